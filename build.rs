@@ -0,0 +1,34 @@
+//! Generates the gNMI and control services, see `src/gnmi.rs` and
+//! `src/api.rs`. The messages are written by hand there, so no protoc is
+//! needed.
+
+fn main(){
+    let method = |module: &str, name: &str, route: &str, input: &str, output: &str| {
+        tonic_build::manual::Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("crate::{}::proto::{}", module, input))
+            .output_type(format!("crate::{}::proto::{}", module, output))
+            .codec_path("tonic::codec::ProstCodec")
+    };
+    let gnmi = |name: &str, route: &str, input: &str, output: &str| method("gnmi", name, route, input, output);
+    let service = tonic_build::manual::Service::builder()
+        .name("gNMI")
+        .package("gnmi")
+        .method(gnmi("capabilities", "Capabilities", "CapabilityRequest", "CapabilityResponse").build())
+        .method(gnmi("get", "Get", "GetRequest", "GetResponse").build())
+        .method(gnmi("subscribe", "Subscribe", "SubscribeRequest", "SubscribeResponse").client_streaming().server_streaming().build())
+        .build();
+    let api = |name: &str, route: &str, input: &str, output: &str| method("api", name, route, input, output).build();
+    let control = tonic_build::manual::Service::builder()
+        .name("Control")
+        .package("routerrs")
+        .method(api("list", "List", "ListRequest", "ListResponse"))
+        .method(api("get", "Get", "GetRequest", "GetResponse"))
+        .method(api("create", "Create", "ApplyRequest", "GetResponse"))
+        .method(api("update", "Update", "ApplyRequest", "GetResponse"))
+        .method(api("destroy", "Destroy", "DestroyRequest", "DestroyResponse"))
+        .method(api("verify", "Verify", "VerifyRequest", "VerifyResponse"))
+        .build();
+    tonic_build::manual::Builder::new().compile(&[service, control]);
+}
@@ -0,0 +1,345 @@
+//! Watermark alerts on the queues of a topology: the root qdisc of every
+//! interface in its namespaces is polled, and an alert is raised when its
+//! backlog, or the rate at which it drops packets or exceeds its rate
+//! limit, crosses a watermark of `AlertSpec`, and cleared once it is back
+//! below, so the congestion points of an experiment are flagged without
+//! watching `tc -s qdisc` by hand.
+//!
+//! Each alert is returned to the caller, appended to `alerts.log` in the
+//! log directory of the topology, which `logs::collect` copies into a run's
+//! artifacts, logged at info level and, with a `webhook`, posted to it as
+//! JSON. Webhooks are plain `http://` URLs, one failing is logged and
+//! doesn't stop the watch.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::{failed, Result, RouterError};
+use crate::logs;
+
+/// Name of the alert log in the log directory of a topology.
+pub const FILE: &str = "alerts.log";
+/// Time a webhook is given to take an alert.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Watermarks applied to the root qdisc of every interface, at least one
+/// of them set.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct AlertSpec{
+    /// bytes queued
+    #[serde(default)]
+    pub backlog: Option<u64>,
+    /// packets queued
+    #[serde(default)]
+    pub backlog_packets: Option<u64>,
+    /// packets dropped per second
+    #[serde(default)]
+    pub drops: Option<f64>,
+    /// packets per second held back by a rate limit
+    #[serde(default)]
+    pub overlimits: Option<f64>,
+    /// `http://` URL every alert is posted to as JSON
+    #[serde(default)]
+    pub webhook: Option<String>,
+}
+
+impl AlertSpec{
+    pub fn check(&self) -> Result<()>{
+        if self.watermarks().is_empty() {
+            return Err(RouterError::Invalid("Alerts need a watermark: backlog, backlog_packets, drops or overlimits".to_string()));
+        }
+        if let Some(url) = &self.webhook{
+            Webhook::parse(url)?;
+        }
+        Ok(())
+    }
+
+    fn watermarks(&self) -> Vec<(Metric, f64)> {
+        [
+            (Metric::Backlog, self.backlog.map(|b| b as f64)),
+            (Metric::BacklogPackets, self.backlog_packets.map(|b| b as f64)),
+            (Metric::Drops, self.drops),
+            (Metric::Overlimits, self.overlimits),
+        ].into_iter().filter_map(|(m, w)| Some((m, w?))).collect()
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric{
+    Backlog,
+    BacklogPackets,
+    Drops,
+    Overlimits,
+}
+
+impl fmt::Display for Metric{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Metric::Backlog => write!(f, "backlog bytes"),
+            Metric::BacklogPackets => write!(f, "backlog packets"),
+            Metric::Drops => write!(f, "drops/s"),
+            Metric::Overlimits => write!(f, "overlimits/s"),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertState{
+    Raised,
+    Cleared,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Alert{
+    /// seconds since the epoch
+    pub time: f64,
+    /// kernel name of the namespace
+    pub netns: String,
+    pub interface: String,
+    pub metric: Metric,
+    pub state: AlertState,
+    pub value: f64,
+    pub watermark: f64,
+}
+
+impl fmt::Display for Alert{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (state, side) = match self.state{
+            AlertState::Raised => ("raised", "above"),
+            AlertState::Cleared => ("cleared", "below"),
+        };
+        write!(f, "{:.6} {} {} {}: {} {:.0} {} {:.0}", self.time, self.netns, self.interface, state, self.metric, self.value, side, self.watermark)
+    }
+}
+
+/// Metric of an interface that crossed its watermark during a watch.
+#[derive(Serialize, Clone, Debug)]
+pub struct Flagged{
+    pub netns: String,
+    pub interface: String,
+    pub metric: Metric,
+    /// highest value seen
+    pub peak: f64,
+    /// times it was raised
+    pub raised: u32,
+}
+
+impl fmt::Display for Flagged{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {} peaked at {:.0}, raised {} times", self.netns, self.interface, self.metric, self.peak, self.raised)
+    }
+}
+
+/// Counters of a root qdisc.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueueStats{
+    pub backlog: u64,
+    pub qlen: u64,
+    pub drops: u64,
+    pub overlimits: u64,
+}
+
+/// Root qdisc counters of every interface in `netns` but the loopback, by
+/// name.
+pub fn read_all(netns: &str) -> Result<BTreeMap<String, QueueStats>>{
+    let qdiscs: serde_json::Value = serde_json::from_str(&cmd::tc(Some(netns), &["-s", "-j", "qdisc", "show"])
+        .map_err(|e| e.context(format!("Failed to read the qdiscs of {}", netns)))?)?;
+    let counter = |q: &serde_json::Value, name: &str| q[name].as_u64().unwrap_or(0);
+    Ok(qdiscs.as_array().cloned().unwrap_or_default().iter()
+        .filter(|q| q["root"].as_bool() == Some(true))
+        .filter_map(|q| {
+            let stats = QueueStats{
+                backlog: counter(q, "backlog"),
+                qlen: counter(q, "qlen"),
+                drops: counter(q, "drops"),
+                overlimits: counter(q, "overlimits"),
+            };
+            Some((q["dev"].as_str()?.to_string(), stats))
+        })
+        .filter(|(name, _)| name != "lo")
+        .collect())
+}
+
+/// Polls the qdiscs of a set of namespaces and turns watermark crossings
+/// into alerts.
+pub struct AlertWatch{
+    pub topology: String,
+    pub spec: AlertSpec,
+    pub namespaces: Vec<String>,
+    previous: BTreeMap<(String, String), (Instant, QueueStats)>,
+    raised: BTreeSet<(String, String, Metric)>,
+    flagged: BTreeMap<(String, String, Metric), (f64, u32)>,
+    log: File,
+}
+
+impl AlertWatch{
+    pub fn new(topology: &str, spec: AlertSpec, namespaces: Vec<String>) -> Result<AlertWatch>{
+        spec.check()?;
+        let dir = std::path::PathBuf::from(logs::LOG_DIR).join(topology);
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(FILE);
+        let log = OpenOptions::new().create(true).append(true).open(&path)
+            .map_err(|e| failed!("Failed to open {}: {}", path.display(), e))?;
+        Ok(AlertWatch{
+            topology: topology.to_string(),
+            spec,
+            namespaces,
+            previous: BTreeMap::new(),
+            raised: BTreeSet::new(),
+            flagged: BTreeMap::new(),
+            log,
+        })
+    }
+
+    /// Reads the qdiscs and returns the alerts raised or cleared since the
+    /// last poll. Rates need a previous poll, so the first one only checks
+    /// the backlog.
+    pub fn poll(&mut self) -> Result<Vec<Alert>>{
+        let mut alerts = Vec::new();
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        let watermarks = self.spec.watermarks();
+        for netns in &self.namespaces{
+            for (interface, stats) in read_all(netns)?{
+                let now = Instant::now();
+                let key = (netns.clone(), interface.clone());
+                let rates = self.previous.get(&key).map(|(then, before)| {
+                    let secs = (now - *then).as_secs_f64().max(f64::EPSILON);
+                    let rate = |n: u64, b: u64| n.saturating_sub(b) as f64 / secs;
+                    (rate(stats.drops, before.drops), rate(stats.overlimits, before.overlimits))
+                });
+                self.previous.insert(key, (now, stats));
+                for (metric, watermark) in &watermarks{
+                    let value = match (metric, rates){
+                        (Metric::Backlog, _) => stats.backlog as f64,
+                        (Metric::BacklogPackets, _) => stats.qlen as f64,
+                        (Metric::Drops, Some((drops, _))) => drops,
+                        (Metric::Overlimits, Some((_, overlimits))) => overlimits,
+                        _ => continue,
+                    };
+                    let key = (netns.clone(), interface.clone(), *metric);
+                    let state = match (value > *watermark, self.raised.contains(&key)){
+                        (true, false) => AlertState::Raised,
+                        (false, true) => AlertState::Cleared,
+                        _ => {
+                            if let Some((peak, _)) = self.flagged.get_mut(&key){
+                                *peak = peak.max(value);
+                            }
+                            continue;
+                        },
+                    };
+                    if state == AlertState::Raised {
+                        let (peak, raised) = self.flagged.entry(key.clone()).or_insert((value, 0));
+                        *peak = peak.max(value);
+                        *raised += 1;
+                        self.raised.insert(key);
+                    } else {
+                        self.raised.remove(&key);
+                    }
+                    alerts.push(Alert{
+                        time,
+                        netns: netns.clone(),
+                        interface: interface.clone(),
+                        metric: *metric,
+                        state,
+                        value,
+                        watermark: *watermark,
+                    });
+                }
+            }
+        }
+        for alert in &alerts{
+            self.publish(alert)?;
+        }
+        Ok(alerts)
+    }
+
+    /// Metrics raised at least once, with their peak.
+    pub fn flagged(&self) -> Vec<Flagged> {
+        self.flagged.iter()
+            .map(|((netns, interface, metric), (peak, raised))| Flagged{
+                netns: netns.clone(),
+                interface: interface.clone(),
+                metric: *metric,
+                peak: *peak,
+                raised: *raised,
+            })
+            .collect()
+    }
+
+    fn publish(&mut self, alert: &Alert) -> Result<()>{
+        writeln!(self.log, "{}", alert)?;
+        tracing::info!(
+            target: "router_rs::alert",
+            topology = self.topology.as_str(),
+            netns = alert.netns.as_str(),
+            interface = alert.interface.as_str(),
+            metric = %alert.metric,
+            state = ?alert.state,
+            value = alert.value,
+            watermark = alert.watermark,
+            "alert",
+        );
+        if let Some(url) = &self.spec.webhook{
+            let body = serde_json::json!({ "topology": self.topology, "alert": alert });
+            if let Err(e) = Webhook::parse(url).and_then(|w| w.post(&body.to_string())) {
+                tracing::warn!(target: "router_rs::alert", webhook = url.as_str(), "failed to post alert: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `http://<host>[:<port>][/<path>]`
+struct Webhook{
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Webhook{
+    fn parse(url: &str) -> Result<Webhook>{
+        let rest = url.strip_prefix("http://")
+            .ok_or_else(|| failed!("Invalid webhook {}, expected http://<host>[:<port>][/<path>]", url))?;
+        let (authority, path) = match rest.find('/'){
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':'){
+            Some((host, port)) if !host.ends_with(':') => (host, port.parse()
+                .map_err(|_| failed!("Invalid port {} of webhook {}", port, url))?),
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(failed!("Webhook {} has no host", url));
+        }
+        Ok(Webhook{ host: host.to_string(), port, path: path.to_string() })
+    }
+
+    fn post(&self, body: &str) -> Result<()>{
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        let addr = (host, self.port).to_socket_addrs()?.next()
+            .ok_or_else(|| failed!("Cannot resolve {}", self.host))?;
+        let mut stream = TcpStream::connect_timeout(&addr, WEBHOOK_TIMEOUT)?;
+        stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+        stream.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+        write!(stream, "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path, self.host, self.port, body.len(), body)?;
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        match status.split_whitespace().nth(1){
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(failed!("Webhook answered {:?}", status)),
+        }
+    }
+}
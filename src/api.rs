@@ -0,0 +1,390 @@
+//! gRPC control API, so external tooling and CI pipelines drive the
+//! emulator without shelling out to the binary. The service
+//! `routerrs.Control` has the methods:
+//!
+//! - `List`: names of the topologies with saved state
+//! - `Get`: saved state of a topology as JSON, and its drift
+//! - `Create`: builds a topology, fails if it exists
+//! - `Update`: reconciles an existing topology with a new description
+//! - `Destroy`: deletes a topology
+//! - `Verify`: runs the checks of a topology, those of the request's
+//!   description or else of the one last created or updated through the API
+//!
+//! Descriptions are sent as text, YAML (or JSON) unless `format` says
+//! `toml`. Changes are made one at a time. The messages are a hand-written
+//! protobuf, the service is generated by `build.rs`.
+//!
+//! With `auth` set, requests need a bearer token and only reach the
+//! topologies it grants, see `auth`; `List` leaves out the others.
+
+// tonic::Status is what every gRPC handler fails with
+#![allow(clippy::result_large_err)]
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tonic::{Request, Response, Status};
+
+use crate::auth::{Access, AuthSpec, TokenSpec};
+use crate::error::{failed, Result};
+use crate::nonblocking::AsyncTopology;
+use crate::parallel::Parallelism;
+use crate::state::{self, State};
+use crate::topology::Topology;
+use crate::verify::{self, VerifyOptions};
+use crate::Config;
+
+pub mod proto{
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListRequest{}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListResponse{
+        #[prost(string, repeated, tag = "1")]
+        pub names: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GetRequest{
+        #[prost(string, tag = "1")]
+        pub name: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GetResponse{
+        #[prost(string, tag = "1")]
+        pub name: String,
+        /// saved state, JSON
+        #[prost(string, tag = "2")]
+        pub state: String,
+        /// differences between the state and the kernel
+        #[prost(string, repeated, tag = "3")]
+        pub drift: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ApplyRequest{
+        /// topology description
+        #[prost(string, tag = "1")]
+        pub topology: String,
+        /// `yaml`, `json` or `toml`, YAML if empty
+        #[prost(string, tag = "2")]
+        pub format: String,
+        /// overrides the name in the description
+        #[prost(string, tag = "3")]
+        pub name: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DestroyRequest{
+        #[prost(string, tag = "1")]
+        pub name: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DestroyResponse{}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct VerifyRequest{
+        #[prost(string, tag = "1")]
+        pub name: String,
+        /// description declaring the checks, the one last applied if empty
+        #[prost(string, tag = "2")]
+        pub topology: String,
+        #[prost(string, tag = "3")]
+        pub format: String,
+        /// pings or connection attempts per check, 3 if 0
+        #[prost(uint32, tag = "4")]
+        pub count: u32,
+        /// milliseconds per reply or connection, 1000 if 0
+        #[prost(uint64, tag = "5")]
+        pub timeout_ms: u64,
+        /// percentage of pings or connections allowed to fail
+        #[prost(double, tag = "6")]
+        pub max_loss: f64,
+        #[prost(bool, tag = "7")]
+        pub mtu: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CheckResult{
+        #[prost(string, tag = "1")]
+        pub from: String,
+        #[prost(string, tag = "2")]
+        pub to: String,
+        #[prost(string, optional, tag = "3")]
+        pub address: Option<String>,
+        #[prost(uint32, optional, tag = "4")]
+        pub port: Option<u32>,
+        #[prost(uint32, optional, tag = "5")]
+        pub mtu: Option<u32>,
+        #[prost(bool, tag = "6")]
+        pub blocked: bool,
+        #[prost(uint32, tag = "7")]
+        pub sent: u32,
+        #[prost(uint32, tag = "8")]
+        pub received: u32,
+        #[prost(double, optional, tag = "9")]
+        pub rtt: Option<f64>,
+        #[prost(double, optional, tag = "10")]
+        pub rtt_max: Option<f64>,
+        #[prost(double, optional, tag = "11")]
+        pub max_rtt: Option<f64>,
+        #[prost(bool, tag = "12")]
+        pub passed: bool,
+        #[prost(string, optional, tag = "13")]
+        pub error: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct VerifyResponse{
+        #[prost(message, repeated, tag = "1")]
+        pub results: Vec<CheckResult>,
+        /// all checks passed
+        #[prost(bool, tag = "2")]
+        pub passed: bool,
+    }
+}
+
+mod service{
+    include!(concat!(env!("OUT_DIR"), "/routerrs.Control.rs"));
+}
+
+pub use service::control_client::ControlClient as Client;
+use service::control_server::{Control, ControlServer};
+
+/// Port the API listens on by default.
+pub const PORT: u16 = 50051;
+
+#[derive(Clone)]
+pub struct ApiServer{
+    pub listen: SocketAddr,
+    /// threads per phase of builds
+    pub parallelism: Parallelism,
+    /// tokens and their permissions, anyone may do anything if not set
+    pub auth: Option<Arc<AuthSpec>>,
+    /// allow builds to change the host outside their namespaces, see
+    /// `preflight::host_changes`
+    pub host_changes: bool,
+    /// descriptions last created or updated, by name, also serializes
+    /// changes
+    applied: Arc<Mutex<HashMap<String, Topology>>>,
+}
+
+impl ApiServer{
+    pub fn new(listen: SocketAddr, parallelism: Parallelism) -> Self {
+        ApiServer{ listen, parallelism, auth: None, host_changes: false, applied: Arc::default() }
+    }
+
+    /// Requires requests to authenticate with a token of `auth`.
+    pub fn with_auth(mut self, auth: AuthSpec) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// Fails unless the token of `request` grants `access` to topology
+    /// `name`.
+    fn authorize<T>(&self, request: &Request<T>, name: &str, access: Access) -> Result<(), Status>{
+        let Some(auth) = &self.auth else {
+            return Ok(());
+        };
+        let holder = authenticate(auth, request)?;
+        if !holder.allows(name, access) {
+            return Err(Status::permission_denied(format!("{} may not {} topology {}", holder.name,
+                if access == Access::Read { "read" } else { "change" }, name)));
+        }
+        Ok(())
+    }
+
+    /// Serves until interrupted.
+    pub fn run(self) -> Result<()>{
+        let listen = self.listen;
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            tonic::transport::Server::builder()
+                .add_service(ControlServer::new(self))
+                .serve(listen)
+                .await
+        }).map_err(|e| failed!("Failed to serve the API on {}: {}", listen, e))
+    }
+
+    /// Builds or reconciles the topology of `request`.
+    async fn apply(&self, request: Request<proto::ApplyRequest>, reconcile: bool) -> Result<proto::GetResponse, Status>{
+        let topology = {
+            let r = request.get_ref();
+            parse(&r.topology, &r.format, &r.name)?
+        };
+        self.authorize(&request, &topology.name, Access::Write)?;
+        let mut applied = self.applied.lock().await;
+        let name = topology.name.clone();
+        let exists = !blocking({
+            let name = name.clone();
+            move || state::namespaces(&name)
+        }).await?.is_empty();
+        match (exists, reconcile){
+            (true, false) => return Err(Status::already_exists(format!("Topology {} already exists", name))),
+            (false, true) => return Err(Status::not_found(format!("Topology {} not found", name))),
+            _ => {},
+        }
+        let mut config = Config::new(name.clone());
+        config.parallelism = self.parallelism;
+        config.host_changes = self.host_changes;
+        let built = match reconcile{
+            true => AsyncTopology::reconcile(topology.clone(), config).await,
+            false => AsyncTopology::apply(topology.clone(), config).await,
+        };
+        built.map_err(|e| Status::internal(e.to_string()))?;
+        applied.insert(name.clone(), topology);
+        let state = blocking({
+            let name = name.clone();
+            move || State::load(&name)
+        }).await?;
+        Ok(proto::GetResponse{ name, state: to_json(state)?, drift: Vec::new() })
+    }
+}
+
+#[tonic::async_trait]
+impl Control for ApiServer{
+    async fn list(&self, request: Request<proto::ListRequest>) -> Result<Response<proto::ListResponse>, Status>{
+        let mut names = blocking(State::list).await?;
+        if let Some(auth) = &self.auth{
+            let holder = authenticate(auth, &request)?;
+            names.retain(|n| holder.allows(n, Access::Read));
+        }
+        Ok(Response::new(proto::ListResponse{ names }))
+    }
+
+    async fn get(&self, request: Request<proto::GetRequest>) -> Result<Response<proto::GetResponse>, Status>{
+        self.authorize(&request, &request.get_ref().name, Access::Read)?;
+        let name = request.into_inner().name;
+        let lookup = name.clone();
+        let (state, drift) = blocking(move || {
+            let Some(state) = State::load(&lookup)? else {
+                return Ok((None, Vec::new()));
+            };
+            let drift = state.drift()?;
+            Ok((Some(state), drift))
+        }).await?;
+        if state.is_none() {
+            return Err(Status::not_found(format!("Topology {} not found", name)));
+        }
+        Ok(Response::new(proto::GetResponse{
+            name,
+            state: to_json(state)?,
+            drift: drift.iter().map(|d| d.to_string()).collect(),
+        }))
+    }
+
+    async fn create(&self, request: Request<proto::ApplyRequest>) -> Result<Response<proto::GetResponse>, Status>{
+        Ok(Response::new(self.apply(request, false).await?))
+    }
+
+    async fn update(&self, request: Request<proto::ApplyRequest>) -> Result<Response<proto::GetResponse>, Status>{
+        Ok(Response::new(self.apply(request, true).await?))
+    }
+
+    async fn destroy(&self, request: Request<proto::DestroyRequest>) -> Result<Response<proto::DestroyResponse>, Status>{
+        self.authorize(&request, &request.get_ref().name, Access::Write)?;
+        let name = request.into_inner().name;
+        let mut applied = self.applied.lock().await;
+        let lookup = name.clone();
+        if blocking(move || state::namespaces(&lookup)).await?.is_empty() {
+            return Err(Status::not_found(format!("Topology {} not found", name)));
+        }
+        let destroyed = name.clone();
+        blocking(move || Topology::destroy(&destroyed)).await?;
+        applied.remove(&name);
+        Ok(Response::new(proto::DestroyResponse{}))
+    }
+
+    async fn verify(&self, request: Request<proto::VerifyRequest>) -> Result<Response<proto::VerifyResponse>, Status>{
+        let topology = if request.get_ref().topology.is_empty() {
+            let name = &request.get_ref().name;
+            self.authorize(&request, name, Access::Read)?;
+            self.applied.lock().await.get(name).cloned()
+                .ok_or_else(|| Status::failed_precondition(format!("No description of {} was applied, send one", name)))?
+        } else {
+            let r = request.get_ref();
+            let topology = parse(&r.topology, &r.format, &r.name)?;
+            self.authorize(&request, &topology.name, Access::Read)?;
+            topology
+        };
+        let request = request.into_inner();
+        if topology.checks.is_empty() {
+            return Err(Status::failed_precondition(format!("Topology {} declares no checks", topology.name)));
+        }
+        let mut options = VerifyOptions{ max_loss: request.max_loss, mtu: request.mtu, ..Default::default() };
+        if request.count > 0 {
+            options.count = request.count;
+        }
+        if request.timeout_ms > 0 {
+            options.timeout = Duration::from_millis(request.timeout_ms);
+        }
+        let results = blocking(move || verify::run(&topology, &options)).await?;
+        let passed = results.iter().all(|r| r.passed);
+        let results = results.into_iter().map(|r| proto::CheckResult{
+            from: r.from,
+            to: r.to,
+            address: r.address.map(|a| a.to_string()),
+            port: r.port.map(u32::from),
+            mtu: r.mtu,
+            blocked: r.blocked,
+            sent: r.sent,
+            received: r.received,
+            rtt: r.rtt,
+            rtt_max: r.rtt_max,
+            max_rtt: r.max_rtt,
+            passed: r.passed,
+            error: r.error,
+        }).collect();
+        Ok(Response::new(proto::VerifyResponse{ results, passed }))
+    }
+}
+
+/// Holder of the bearer token of `request`.
+fn authenticate<'a, T>(auth: &'a AuthSpec, request: &Request<T>) -> Result<&'a TokenSpec, Status>{
+    let token = request.metadata().get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or_else(|| Status::unauthenticated("A bearer token is required"))?;
+    auth.authenticate(token.trim()).ok_or_else(|| Status::unauthenticated("Invalid token"))
+}
+
+/// Topology described by `data` in `format`, named `name` if not empty.
+fn parse(data: &str, format: &str, name: &str) -> Result<Topology, Status>{
+    let topology: Result<Topology, String> = match format{
+        "" | "yaml" | "yml" | "json" => serde_yaml::from_str(data).map_err(|e| e.to_string()),
+        "toml" => toml::from_str(data).map_err(|e| e.to_string()),
+        _ => return Err(Status::invalid_argument(format!("Unsupported topology format {}, expected yaml, json or toml", format))),
+    };
+    let mut topology = topology.map_err(|e| Status::invalid_argument(format!("Failed to parse topology: {}", e)))?;
+    if !name.is_empty() {
+        topology.name = name.to_string();
+    }
+    if topology.name.is_empty() {
+        return Err(Status::invalid_argument("The topology has no name"));
+    }
+    Ok(topology)
+}
+
+fn to_json(state: Option<State>) -> Result<String, Status>{
+    match state{
+        Some(state) => serde_json::to_string(&state).map_err(|e| Status::internal(e.to_string())),
+        None => Ok(String::new()),
+    }
+}
+
+/// Runs `f`, which works on the host, off the runtime.
+async fn blocking<T, F>(f: F) -> Result<T, Status>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(|e| Status::internal(e.to_string()))
+}
@@ -0,0 +1,138 @@
+//! Token authentication and per-topology permissions of the control API,
+//! so teams sharing a lab server only see and change their own
+//! topologies. Clients send `authorization: Bearer <token>` metadata, the
+//! server looks the token up in a file like
+//!
+//! ```yaml
+//! tokens:
+//!   - name: team-a
+//!     token: 9f86d081884c7d65
+//!     role: operator
+//!     topologies: [team-a-*]
+//!   - name: ci
+//!     token: 2c26b46b68ffc68f
+//!     role: viewer
+//! ```
+//!
+//! A viewer lists, reads and verifies the topologies its `topologies`
+//! patterns match, all of them if it has none, an operator creates,
+//! updates and destroys them as well. An admin does everything with every
+//! topology. Patterns match topology names, `*` standing for any run of
+//! characters.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{failed, Result};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AuthSpec{
+    pub tokens: Vec<TokenSpec>,
+}
+
+/// Holder of a token and what it may do.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TokenSpec{
+    /// who holds the token, for messages
+    pub name: String,
+    pub token: String,
+    #[serde(default)]
+    pub role: Role,
+    /// patterns of the topology names granted, all if empty
+    #[serde(default)]
+    pub topologies: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum Role{
+    #[default]
+    Viewer,
+    Operator,
+    Admin,
+}
+
+/// What a request does with a topology.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access{
+    /// list, get and verify
+    Read,
+    /// create, update and destroy
+    Write,
+}
+
+impl AuthSpec{
+    pub fn load(path: &Path) -> Result<AuthSpec>{
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| failed!("Failed to read {}: {}", path.display(), e))?;
+        let spec: AuthSpec = serde_yaml::from_str(&data)
+            .map_err(|e| failed!("Failed to parse {}: {}", path.display(), e))?;
+        spec.check()?;
+        Ok(spec)
+    }
+
+    pub fn check(&self) -> Result<()>{
+        if self.tokens.is_empty() {
+            return Err(failed!("No tokens configured"));
+        }
+        for (n, t) in self.tokens.iter().enumerate(){
+            if t.token.len() < 8 {
+                return Err(failed!("Token of {} is shorter than 8 characters", t.name));
+            }
+            if self.tokens[..n].iter().any(|o| o.token == t.token) {
+                return Err(failed!("Token of {} is given twice", t.name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Holder of `token`, if any.
+    pub fn authenticate(&self, token: &str) -> Option<&TokenSpec> {
+        // compare every token in full, so the time taken doesn't tell how
+        // much of one matched
+        let mut found = None;
+        for t in &self.tokens{
+            if constant_time_eq(t.token.as_bytes(), token.as_bytes()) {
+                found = Some(t);
+            }
+        }
+        found
+    }
+}
+
+impl TokenSpec{
+    /// True if the holder may `access` topology `name`.
+    pub fn allows(&self, name: &str, access: Access) -> bool {
+        match self.role{
+            Role::Admin => true,
+            Role::Viewer if access == Access::Write => false,
+            _ => self.topologies.is_empty() || self.topologies.iter().any(|p| matches(p, name)),
+        }
+    }
+}
+
+/// True if `name` matches `pattern`, `*` matching any run of characters.
+fn matches(pattern: &str, name: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let [first, middle @ .., last] = &parts[..] else {
+        return pattern == name;
+    };
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    for part in middle{
+        match rest.find(part){
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,211 @@
+//! Backup of a running topology to a single `.tar.gz` archive and its
+//! restore, so a lab moves to another machine and is recreated as it was.
+//! The archive holds
+//!
+//! - `topology.yaml`: the description with every allocated subnet and
+//!   loopback address written in, so the restored topology gets the same
+//!   addresses whatever the `ipam` pools would hand out
+//! - `state.json`: the saved state, see `state`
+//! - `ipam.json`: the assigned subnets of links, bridges, VXLAN links and
+//!   tunnels, WireGuard links and the loopback addresses
+//! - `daemons/<netns>/*.conf`: the generated routing daemon configs
+//!
+//! Archives are packed and unpacked with tar. A restore builds the pinned
+//! description and reports where the assignments, interface addresses or
+//! daemon configs come out different from the archived ones.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::daemon::RoutingDaemon;
+use crate::error::{failed, Result, RouterError};
+use crate::loopback;
+use crate::state::{self, State};
+use crate::topology::Topology;
+use crate::trace::Traced;
+use crate::Config;
+
+/// Addresses handed out to a topology.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Assignments{
+    /// link, bridge, VXLAN link or tunnel -> (subnet, IPv6 subnet)
+    pub segments: BTreeMap<String, (String, Option<String>)>,
+    /// namespace -> (IPv4, IPv6) address of its loopback
+    pub loopbacks: BTreeMap<String, (Option<String>, Option<String>)>,
+}
+
+impl Assignments{
+    pub fn from_state(state: &State) -> Assignments {
+        let mut a = Assignments::default();
+        for s in state.links.iter().chain(&state.bridges).chain(&state.vxlans).chain(&state.tunnels).chain(&state.wireguards){
+            a.segments.insert(s.name.clone(), (s.subnet.clone(), s.subnet6.clone()));
+        }
+        for ns in &state.namespaces{
+            if let Some(lo) = state.interfaces.iter().find(|i| i.name == loopback::name(&ns.name)) {
+                a.loopbacks.insert(ns.name.clone(), (lo.ip.clone(), lo.ip6.clone()));
+            }
+        }
+        a
+    }
+}
+
+/// Writes the archive of running topology `topology` to `archive`.
+pub fn backup(topology: &Topology, archive: &Path) -> Result<()>{
+    let Some(state) = State::load(&topology.name)? else {
+        return Err(RouterError::TopologyNotFound(topology.name.to_string()));
+    };
+    let assignments = Assignments::from_state(&state);
+    let staging = staging(&topology.name, "backup")?;
+    let result = (|| {
+        std::fs::write(staging.join("topology.yaml"), serde_yaml::to_string(&pin(topology, &assignments))?)?;
+        std::fs::write(staging.join("state.json"), serde_json::to_string_pretty(&state)?)?;
+        std::fs::write(staging.join("ipam.json"), serde_json::to_string_pretty(&assignments)?)?;
+        for d in RoutingDaemon::list(&topology.name)?{
+            let dir = staging.join("daemons").join(&d.netns);
+            std::fs::create_dir_all(&dir)?;
+            for (name, content) in configs(&d.dir)?{
+                std::fs::write(dir.join(name), content)?;
+            }
+        }
+        tar(&["-czf", &archive.to_string_lossy(), "-C", &staging.to_string_lossy(), "."])
+    })();
+    std::fs::remove_dir_all(&staging)?;
+    result
+}
+
+/// Recreates the topology archived in `archive` with `config`, named as
+/// the archived one, see `name`. Fails if a topology of that name exists.
+/// Returns the differences to the archived assignments, interfaces and
+/// daemon configs.
+pub fn restore(archive: &Path, config: Config) -> Result<Vec<String>>{
+    let staging = staging(&config.name, "restore")?;
+    let result = (|| {
+        tar(&["-xzf", &archive.to_string_lossy(), "-C", &staging.to_string_lossy()])?;
+        let read = |name: &str| std::fs::read_to_string(staging.join(name))
+            .map_err(|e| failed!("Archive {} lacks {}: {}", archive.display(), name, e));
+        let topology: Topology = serde_yaml::from_str(&read("topology.yaml")?)
+            .map_err(|e| failed!("Failed to parse the topology of {}: {}", archive.display(), e))?;
+        let archived: State = serde_json::from_str(&read("state.json")?)
+            .map_err(|e| failed!("Failed to parse the state of {}: {}", archive.display(), e))?;
+        let assignments: Assignments = serde_json::from_str(&read("ipam.json")?)
+            .map_err(|e| failed!("Failed to parse the assignments of {}: {}", archive.display(), e))?;
+        if config.name != topology.name {
+            return Err(failed!("Archive {} holds topology {}, not {}", archive.display(), topology.name, config.name));
+        }
+        if !state::namespaces(&topology.name)?.is_empty() {
+            return Err(failed!("Topology {} exists, destroy it first", topology.name));
+        }
+        topology.apply_with(config)?;
+        let state = State::load(&topology.name)?
+            .ok_or_else(|| failed!("No state saved for {}", topology.name))?;
+        let mut differences = Vec::new();
+        let restored = Assignments::from_state(&state);
+        for (name, subnets) in &assignments.segments{
+            if restored.segments.get(name) != Some(subnets) {
+                differences.push(format!("{}: archived subnets {:?}, restored {:?}", name, subnets, restored.segments.get(name)));
+            }
+        }
+        for (ns, addresses) in &assignments.loopbacks{
+            if restored.loopbacks.get(ns) != Some(addresses) {
+                differences.push(format!("loopback of {}: archived {:?}, restored {:?}", ns, addresses, restored.loopbacks.get(ns)));
+            }
+        }
+        for i in &archived.interfaces{
+            match state.interfaces.iter().find(|r| r.name == i.name){
+                Some(r) if (&r.ip, &r.ip6) != (&i.ip, &i.ip6) =>
+                    differences.push(format!("interface {}: archived {:?} {:?}, restored {:?} {:?}", i.name, i.ip, i.ip6, r.ip, r.ip6)),
+                Some(_) => {},
+                None => differences.push(format!("interface {} was not restored", i.name)),
+            }
+        }
+        let daemons = staging.join("daemons");
+        if daemons.exists() {
+            for entry in std::fs::read_dir(&daemons)?{
+                let dir = entry?.path();
+                let netns = dir.file_name().unwrap_or_default().to_string_lossy().to_string();
+                let current = configs(&RoutingDaemon::dir(&topology.name, &netns))?;
+                for (name, content) in configs(&dir)?{
+                    if !current.iter().any(|(n, c)| *n == name && *c == content) {
+                        differences.push(format!("daemon config {} of {} differs", name, netns));
+                    }
+                }
+            }
+        }
+        Ok(differences)
+    })();
+    std::fs::remove_dir_all(&staging)?;
+    result
+}
+
+/// Name of the topology archived in `archive`.
+pub fn name(archive: &Path) -> Result<String>{
+    let output = Command::new("tar").args(["-xzOf", &archive.to_string_lossy(), "./topology.yaml"]).traced_output()?;
+    if !output.status.success() {
+        return Err(failed!("Failed to read {}: {}", archive.display(), String::from_utf8_lossy(&output.stderr)));
+    }
+    let topology: Topology = serde_yaml::from_slice(&output.stdout)
+        .map_err(|e| failed!("Failed to parse the topology of {}: {}", archive.display(), e))?;
+    Ok(topology.name)
+}
+
+/// `topology` with the subnets and loopback addresses of `assignments`
+/// written in.
+pub fn pin(topology: &Topology, assignments: &Assignments) -> Topology {
+    let mut t = topology.clone();
+    let segments = t.links.iter_mut().map(|l| (&l.name, &mut l.subnet, &mut l.subnet6))
+        .chain(t.bridges.iter_mut().map(|b| (&b.name, &mut b.subnet, &mut b.subnet6)))
+        .chain(t.vxlans.iter_mut().map(|v| (&v.name, &mut v.subnet, &mut v.subnet6)))
+        .chain(t.tunnels.iter_mut().map(|t| (&t.name, &mut t.subnet, &mut t.subnet6)))
+        .chain(t.wireguards.iter_mut().map(|w| (&w.name, &mut w.subnet, &mut w.subnet6)));
+    for (name, subnet, subnet6) in segments{
+        if let Some((s, s6)) = assignments.segments.get(name) {
+            (*subnet, *subnet6) = (s.clone(), s6.clone());
+        }
+    }
+    for ns in &mut t.namespaces{
+        let (Some(lo), Some((ip, ip6))) = (&mut ns.loopback, assignments.loopbacks.get(&ns.name)) else {
+            continue;
+        };
+        let addr = |a: &Option<String>| a.as_ref().and_then(|a| a.split('/').next()).map(|a| a.to_string());
+        (lo.address, lo.address6) = (addr(ip), addr(ip6));
+    }
+    t
+}
+
+/// Config files in `dir` as (file name, content), sorted.
+fn configs(dir: &Path) -> Result<Vec<(String, String)>>{
+    let mut configs = Vec::new();
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Ok(configs);
+    };
+    for entry in entries{
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "conf") {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            configs.push((name, std::fs::read_to_string(&path)?));
+        }
+    }
+    configs.sort();
+    Ok(configs)
+}
+
+fn staging(topology: &str, purpose: &str) -> Result<PathBuf>{
+    let dir = std::env::temp_dir().join(format!("router-rs-{}-{}-{}", purpose, topology, std::process::id()));
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn tar(args: &[&str]) -> Result<()>{
+    let output = Command::new("tar").args(args).traced_output()
+        .map_err(|e| failed!("Failed to run tar: {}", e))?;
+    if !output.status.success() {
+        return Err(failed!("Failed to run tar {}: {}", args.join(" "), String::from_utf8_lossy(&output.stderr)));
+    }
+    Ok(())
+}
@@ -0,0 +1,128 @@
+//! Batched setup of veth links for large topologies. Spawning `ip` costs
+//! far more than the netlink messages it sends, and a link takes about ten
+//! of them. With `Config::batch` the pair, its altnames, the addresses and
+//! MTU, MAC and up state of the ends and the routes of unnumbered ends are
+//! queued instead, one `ip -batch` per namespace, and run when the links
+//! of a pass are attached: the host's batch creating the pairs first, then
+//! those of the namespaces in parallel.
+//!
+//! What a batch creates is recorded in the build's transaction once the
+//! batch ran. A batch failing half-way leaves objects unrecorded, they
+//! live in the namespaces of the topology and go with them.
+//!
+//! Batching is off when reconciling or taking links from the pool, which
+//! look at what exists before changing it.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::process::Command;
+
+use crate::cmd;
+use crate::error::Result;
+use crate::parallel;
+use crate::transaction::Resource;
+use crate::{Config, Veth};
+
+/// `ip` commands queued per namespace, None for the host.
+#[derive(Debug, Default)]
+pub struct Batch{
+    commands: BTreeMap<Option<String>, Vec<String>>,
+    /// recorded in the transaction once the batch of their namespace ran
+    resources: Vec<(Option<String>, Resource)>,
+    /// (netns, name) of the devices the batch creates
+    devices: BTreeSet<(String, String)>,
+}
+
+impl Batch{
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Number of commands queued.
+    pub fn len(&self) -> usize {
+        self.commands.values().map(|c| c.len()).sum()
+    }
+
+    /// Queues `ip <args>` in `netns`.
+    pub(crate) fn queue(&mut self, netns: Option<&str>, args: &[&str]){
+        self.commands.entry(netns.map(|n| n.to_string())).or_default().push(args.join(" "));
+    }
+
+    /// Records `resource` once the batch of `netns` ran.
+    pub(crate) fn record(&mut self, netns: Option<&str>, resource: Resource){
+        self.resources.push((netns.map(|n| n.to_string()), resource));
+    }
+
+    /// Queues the creation of `veth` with the MAC addresses `macs` of its
+    /// ends, set before the ends come up.
+    pub(crate) fn veth(&mut self, veth: &Veth, macs: &[Option<String>; 2]){
+        let queues = veth.queue_args();
+        let mut args = vec!["link", "add", "name", veth.name.as_str()];
+        if let Some(mac) = &macs[0]{
+            args.extend(["address", mac.as_str()]);
+        }
+        args.extend(["netns", veth.namespace.as_str()]);
+        args.extend(queues.iter().map(|a| a.as_str()));
+        args.extend(["type", "veth", "peer", "name", veth.peer.as_str()]);
+        if let Some(mac) = &macs[1]{
+            args.extend(["address", mac.as_str()]);
+        }
+        args.extend(["netns", veth.peer_namespace.as_str()]);
+        args.extend(queues.iter().map(|a| a.as_str()));
+        self.queue(None, &args);
+        self.record(None, Resource::Veth{ name: veth.name.clone(), netns: veth.namespace.clone() });
+        self.devices.insert((veth.namespace.clone(), veth.name.clone()));
+        self.devices.insert((veth.peer_namespace.clone(), veth.peer.clone()));
+    }
+
+    /// Whether the batch creates `name` in `netns`, its settings have to
+    /// be queued as well.
+    pub(crate) fn creates(&self, netns: &str, name: &str) -> bool {
+        self.devices.contains(&(netns.to_string(), name.to_string()))
+    }
+
+    /// Runs the host's batch, then those of the namespaces `parallelism`
+    /// at once, and records what they created in `config`'s transaction.
+    pub(crate) fn run(self, config: &Config) -> Result<()>{
+        let mut batches: Vec<(Option<String>, Vec<String>)> = self.commands.into_iter().collect();
+        let mut ran: Vec<Option<String>> = Vec::new();
+        let mut error = None;
+        // the pairs have to exist before their ends are set up
+        if batches.first().is_some_and(|(netns, _)| netns.is_none()) {
+            let (netns, commands) = batches.remove(0);
+            run(None, &commands)?;
+            ran.push(netns);
+        }
+        let results = parallel::map(&batches, config.parallelism.namespaces, |(netns, commands)| run(netns.as_deref(), commands));
+        for ((netns, _), result) in batches.into_iter().zip(results){
+            match result{
+                Ok(()) => ran.push(netns),
+                Err(e) => {
+                    error.get_or_insert(e);
+                },
+            }
+        }
+        let mut transaction = config.transaction();
+        for (netns, resource) in self.resources{
+            if ran.contains(&netns) {
+                transaction.record(resource);
+            }
+        }
+        match error{
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Feeds `commands` to `ip -batch` in `netns`.
+fn run(netns: Option<&str>, commands: &[String]) -> Result<()>{
+    let mut cmd = Command::new("ip");
+    if let Some(netns) = netns{
+        cmd.args(["-n", netns]);
+    }
+    let mut script = commands.join("\n");
+    script.push('\n');
+    cmd::with_input(cmd.args(["-batch", "-"]), &script)
+        .map_err(|e| e.context(format!("Failed to run batch of {} commands in {}", commands.len(), netns.unwrap_or("the host"))))?;
+    Ok(())
+}
@@ -0,0 +1,125 @@
+//! Scale test of the build: a topology of `namespaces` namespaces and
+//! `links` links between them is created and destroyed once, reporting how
+//! long each phase took, to find where a large lab spends its time and to
+//! compare batched and unbatched builds, see `batch`.
+//!
+//! Link `l<i>` joins `n<i mod N>` to a namespace further along the ring,
+//! the farther the more often the ring was gone around, so namespaces get
+//! links to many others rather than parallel links to one. Links get /30s
+//! of `SUBNET` in order.
+//!
+//! The phases, as recorded by `Topology::apply_with`:
+//!
+//! - `preflight`: checks of the host
+//! - `namespaces`: with their loopbacks and NAT64 devices
+//! - `links`: veth links and bridges
+//! - `interfaces`: host interfaces, tunnels, VXLAN links, groups, VRFs,
+//!   firewalls and forwarders
+//! - `routes`: service addresses and routes
+//! - `daemons`: tuning, policy rules, daemons and processes
+//! - `state`: saving the state
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::error::{failed, Result};
+use crate::parallel::Parallelism;
+use crate::state;
+use crate::topology::Topology;
+use crate::Config;
+
+/// Subnet the links are numbered from.
+pub const SUBNET: &str = "10.0.0.0/8";
+
+/// Time one phase of a build took, see `Config::phases`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Phase{
+    pub name: String,
+    pub took: Duration,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct BenchReport{
+    pub topology: String,
+    pub namespaces: u32,
+    pub links: u32,
+    pub batch: bool,
+    /// phases of the build in order
+    pub phases: Vec<Phase>,
+    pub create: Duration,
+    pub destroy: Duration,
+}
+
+impl fmt::Display for BenchReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |d: &Duration| d.as_secs_f64() * 1000.0;
+        let batch = if self.batch { "batched" } else { "unbatched" };
+        writeln!(f, "{} ({} namespaces, {} links, {})", self.topology, self.namespaces, self.links, batch)?;
+        for p in &self.phases{
+            writeln!(f, "  {:<12} {:>10.1} ms", p.name, ms(&p.took))?;
+        }
+        writeln!(f, "  {:<12} {:>10.1} ms", "create", ms(&self.create))?;
+        write!(f, "  {:<12} {:>10.1} ms", "destroy", ms(&self.destroy))
+    }
+}
+
+/// Creates and destroys a generated topology once, see the module.
+pub struct BenchRun{
+    pub name: String,
+    pub namespaces: u32,
+    pub links: u32,
+    pub batch: bool,
+    pub parallelism: Parallelism,
+}
+
+impl BenchRun{
+    /// The generated topology.
+    pub fn topology(&self) -> Result<Topology>{
+        if self.namespaces < 2 {
+            return Err(failed!("A scale test needs at least 2 namespaces, got {}", self.namespaces));
+        }
+        let subnet: ipnet::Ipv4Net = SUBNET.parse()?;
+        let subnets = subnet.subnets(30)?;
+        if (self.links as u64) > 1u64 << (30 - subnet.prefix_len()) {
+            return Err(failed!("{} links don't fit into {}", self.links, SUBNET));
+        }
+        let mut builder = Topology::builder(&self.name);
+        for n in 0..self.namespaces{
+            builder = builder.namespace(&format!("n{}", n));
+        }
+        let n = self.namespaces;
+        for (i, subnet) in (0..self.links).zip(subnets){
+            let a = i % n;
+            let b = (a + 1 + (i / n) % (n - 1)) % n;
+            builder = builder.link(&format!("l{}", i), &subnet.to_string())
+                .connect(&format!("n{}", a), &format!("n{}", b));
+        }
+        builder.build()
+    }
+
+    pub fn run(&self) -> Result<BenchReport>{
+        if !state::namespaces(&self.name)?.is_empty() {
+            return Err(failed!("Topology {} exists, the scale test needs a name of its own", self.name));
+        }
+        let topology = self.topology()?;
+        let mut config = Config::new(self.name.clone());
+        config.batch = self.batch;
+        config.parallelism = self.parallelism;
+        let start = Instant::now();
+        let config = topology.apply_with(config)?;
+        let create = start.elapsed();
+        let destroyed = Instant::now();
+        Topology::destroy(&self.name)?;
+        Ok(BenchReport{
+            topology: self.name.clone(),
+            namespaces: self.namespaces,
+            links: self.links,
+            batch: self.batch,
+            phases: config.phases.to_vec(),
+            create,
+            destroy: destroyed.elapsed(),
+        })
+    }
+}
@@ -0,0 +1,265 @@
+//! Link aggregation: a link with `bond` joins its two namespaces over
+//! several veth pairs instead of one, enslaved at each end to a bonding
+//! device `<namespace>_<link>` holding the addresses of the link's end. The
+//! members are named `<namespace>_<link>_<n>`, so taking one down, e.g.
+//! with `flap` or `chaos`, fails over within the link while routing keeps
+//! seeing one interface, unlike the paths of ECMP.
+//!
+//! - `active-backup` sends over one member and switches to the next one
+//!   when its carrier goes
+//! - `balance-xor` spreads flows over all members by the hash of
+//!   `xmit_hash_policy`
+//! - `802.3ad` negotiates the aggregate with LACP at the fast rate and
+//!   spreads flows like `balance-xor`
+
+use std::fmt;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::{failed, Result, RouterError};
+use crate::interface;
+use crate::link::Veth;
+use crate::state::State;
+use crate::transaction::Resource;
+use crate::{Config, Interface, Link, Namespace};
+
+/// Members if not set.
+pub const DEFAULT_MEMBERS: u8 = 2;
+/// Milliseconds between carrier checks of the members if not set.
+pub const DEFAULT_MIIMON: u32 = 100;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum BondMode{
+    #[default]
+    #[serde(rename = "active-backup")]
+    ActiveBackup,
+    #[serde(rename = "balance-xor")]
+    BalanceXor,
+    #[serde(rename = "802.3ad")]
+    Lacp,
+}
+
+impl fmt::Display for BondMode{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            BondMode::ActiveBackup => write!(f, "active-backup"),
+            BondMode::BalanceXor => write!(f, "balance-xor"),
+            BondMode::Lacp => write!(f, "802.3ad"),
+        }
+    }
+}
+
+/// Aggregation of a link, see `bond`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct BondSpec{
+    #[serde(default)]
+    pub mode: BondMode,
+    /// veth pairs, `DEFAULT_MEMBERS` if not set
+    #[serde(default)]
+    pub members: Option<u8>,
+    /// `DEFAULT_MIIMON` if not set
+    #[serde(default)]
+    pub miimon: Option<u32>,
+    /// layer2, layer2+3, layer3+4, encap2+3 or encap3+4, the kernel's
+    /// layer2 if not set. Only spreading modes hash.
+    #[serde(default)]
+    pub xmit_hash_policy: Option<String>,
+}
+
+impl BondSpec{
+    pub fn check(&self) -> Result<()>{
+        if self.members == Some(0) {
+            return Err(RouterError::Invalid("Bond needs at least one member".to_string()));
+        }
+        if let Some(policy) = &self.xmit_hash_policy{
+            if !["layer2", "layer2+3", "layer3+4", "encap2+3", "encap3+4"].contains(&policy.as_str()) {
+                return Err(RouterError::Invalid(format!("Unknown transmit hash policy {}", policy)));
+            }
+            if self.mode == BondMode::ActiveBackup {
+                return Err(RouterError::Invalid("Bond in mode active-backup doesn't hash, drop xmit_hash_policy".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Names of the members at the end of `link` in `namespace`.
+    pub fn members(&self, namespace: &str, link: &str) -> Vec<String> {
+        (0..self.members.unwrap_or(DEFAULT_MEMBERS))
+            .map(|n| member(namespace, link, n))
+            .collect()
+    }
+
+    /// `ip link add ... type bond` arguments after the type.
+    pub(crate) fn args(&self) -> Vec<String> {
+        let mut args = vec![
+            "mode".to_string(), self.mode.to_string(),
+            "miimon".to_string(), self.miimon.unwrap_or(DEFAULT_MIIMON).to_string(),
+        ];
+        if let Some(policy) = &self.xmit_hash_policy{
+            args.extend(["xmit_hash_policy".to_string(), policy.clone()]);
+        }
+        if self.mode == BondMode::Lacp {
+            args.extend(["lacp_rate".to_string(), "fast".to_string()]);
+        }
+        args
+    }
+}
+
+/// Name of member `n` of the end of `link` in `namespace`.
+pub fn member(namespace: &str, link: &str, n: u8) -> String {
+    interface::name(namespace, &format!("{}_{}", link, n))
+}
+
+impl Link{
+    /// Like `attach`, joining `ns1` and `ns2` over the members of `spec`
+    /// enslaved to a bonding device at each end.
+    pub fn bond(&self, ns1: Arc<Namespace>, ns2: Arc<Namespace>, spec: &BondSpec, macs: &[Option<String>; 2], config: &Config) -> Result<(Arc<Interface>, Arc<Interface>)>{
+        let (name1, name2) = (interface::name(&ns1.name, &self.name), interface::name(&ns2.name, &self.name));
+        for (ns, name) in [(&ns1, &name1), (&ns2, &name2)]{
+            setup(&ns.netns, name, spec, config)
+                .map_err(|e| failed!("Link {}: {}", self.name, e))?;
+            interface::alias(name, Some(&ns.netns), &format!("{}_{}", ns.name, self.name), config)?;
+        }
+        for (m1, m2) in spec.members(&ns1.name, &self.name).into_iter().zip(spec.members(&ns2.name, &self.name)){
+            let veth = Veth{
+                name: m1.clone(),
+                namespace: ns1.netns.clone(),
+                peer: m2.clone(),
+                peer_namespace: ns2.netns.clone(),
+                numtxqueues: None,
+                numrxqueues: None,
+            };
+            veth.setup(config)?;
+            enslave(&ns1.netns, &m1, &name1)?;
+            enslave(&ns2.netns, &m2, &name2)?;
+        }
+
+        let [(ip1, ip1_6), (ip2, ip2_6)] = self.ends.assign(&self.name, &self.subnet, self.subnet6.as_ref(), &ns1.name, &ns2.name, config)?;
+        // the bond passes its MTU on to the members
+        let i1 = Interface::new(name1, Some(ns1), ip1, ip1_6, Some(3000), config)?;
+        let i2 = Interface::new(name2, Some(ns2), ip2, ip2_6, Some(3000), config)?;
+        for (i, mac) in [&i1, &i2].into_iter().zip(macs){
+            if let Some(mac) = mac{
+                i.set_mac(mac)?;
+            }
+        }
+        self.peer_routes(&i1, &i2, config)?;
+        config.hooks.link_up(&config.name, self, &i1, &i2)?;
+        Ok((i1, i2))
+    }
+}
+
+/// Creates the bonding device `name` in `netns`. When reconciling, one in
+/// the same mode is kept.
+fn setup(netns: &str, name: &str, spec: &BondSpec, config: &Config) -> Result<()>{
+    if config.reconcile {
+        if let Ok(out) = cmd::ip(Some(netns), &["-d", "-j", "link", "show", "dev", name]) {
+            let links: serde_json::Value = serde_json::from_str(&out)?;
+            let info = &links[0]["linkinfo"];
+            if info["info_kind"] == "bond" && info["info_data"]["mode"] == spec.mode.to_string().as_str() {
+                return Ok(());
+            }
+            cmd::ip(Some(netns), &["link", "del", "dev", name])?;
+        }
+    }
+    let mut args = vec!["link".to_string(), "add".to_string(), "name".to_string(), name.to_string(), "type".to_string(), "bond".to_string()];
+    args.extend(spec.args());
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    cmd::ip(Some(netns), &args)
+        .map_err(|e| failed!("Failed to create bond {}: {}", name, e))?;
+    config.transaction().record(Resource::Device{ name: name.to_string(), netns: netns.to_string() });
+    Ok(())
+}
+
+/// Makes `member` a slave of `bond` unless it is already, a member has to
+/// be down to be enslaved.
+fn enslave(netns: &str, member: &str, bond: &str) -> Result<()>{
+    let links: serde_json::Value = serde_json::from_str(&cmd::ip(Some(netns), &["-j", "link", "show", "dev", member])?)?;
+    if links[0]["master"] != bond {
+        cmd::ip(Some(netns), &["link", "set", "dev", member, "down"])?;
+        cmd::ip(Some(netns), &["link", "set", "dev", member, "master", bond])
+            .map_err(|e| failed!("Failed to add {} to bond {}: {}", member, bond, e))?;
+    }
+    cmd::ip(Some(netns), &["link", "set", "dev", member, "up"])?;
+    Ok(())
+}
+
+/// Member of a bond as the kernel sees it.
+#[derive(Serialize, Clone, Debug)]
+pub struct MemberStatus{
+    pub name: String,
+    /// carrier as seen by the MII monitor
+    pub up: bool,
+    /// active or backup
+    pub state: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct BondStatus{
+    pub netns: String,
+    pub device: String,
+    pub mode: String,
+    /// member sending in active-backup mode
+    pub active: Option<String>,
+    pub members: Vec<MemberStatus>,
+}
+
+impl fmt::Display for BondStatus{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in {} ({})", self.device, self.netns, self.mode)?;
+        if let Some(active) = &self.active{
+            write!(f, ", active {}", active)?;
+        }
+        for m in &self.members{
+            write!(f, "\n  {:<16} {:<5} {}", m.name, if m.up { "up" } else { "down" }, m.state)?;
+        }
+        Ok(())
+    }
+}
+
+/// Status of the bonding device `device` in `netns` and its members.
+pub fn status(netns: &str, device: &str) -> Result<BondStatus>{
+    let links: serde_json::Value = serde_json::from_str(&cmd::ip(Some(netns), &["-d", "-j", "link", "show", "dev", device])?)?;
+    let info = &links[0]["linkinfo"];
+    if info["info_kind"] != "bond" {
+        return Err(failed!("{} in {} is no bond", device, netns));
+    }
+    let slaves: serde_json::Value = serde_json::from_str(&cmd::ip(Some(netns), &["-d", "-j", "link", "show", "master", device])?)?;
+    let mut members: Vec<MemberStatus> = slaves.as_array().cloned().unwrap_or_default().iter()
+        .map(|s| {
+            let data = &s["linkinfo"]["info_slave_data"];
+            MemberStatus{
+                name: s["ifname"].as_str().unwrap_or_default().to_string(),
+                up: data["mii_status"] == "UP",
+                state: data["state"].as_str().unwrap_or_default().to_lowercase(),
+            }
+        })
+        .collect();
+    members.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(BondStatus{
+        netns: netns.to_string(),
+        device: device.to_string(),
+        mode: info["info_data"]["mode"].as_str().unwrap_or_default().to_string(),
+        active: info["info_data"]["active_slave"].as_str().map(|a| a.to_string()),
+        members,
+    })
+}
+
+/// Status of the bonds at the ends of `link` of `topology`.
+pub fn link_status(topology: &str, link: &str) -> Result<Vec<BondStatus>>{
+    let state = State::load(topology)?
+        .ok_or_else(|| RouterError::TopologyNotFound(topology.to_string()))?;
+    let mut bonds = Vec::new();
+    for ns in &state.namespaces{
+        let device = interface::name(&ns.name, link);
+        if state.interfaces.iter().any(|i| i.name == device && i.netns.as_deref() == Some(ns.netns.as_str())) {
+            bonds.push(status(&ns.netns, &device)?);
+        }
+    }
+    if bonds.is_empty() {
+        return Err(failed!("Link {} not found in {}", link, topology));
+    }
+    Ok(bonds)
+}
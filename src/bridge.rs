@@ -0,0 +1,125 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::{Result, RouterError};
+use crate::interface;
+use crate::link::host_addr;
+use crate::ovs::Switch;
+use crate::transaction::Resource;
+use crate::{Config, Interface, Namespace, Veth};
+
+/// What switches the frames of a bridge.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BridgeBackend{
+    #[default]
+    Linux,
+    /// Open vSwitch programmable with OpenFlow, see `ovs`
+    Ovs,
+}
+
+/// LAN segment: a Linux or OVS bridge with any number of member
+/// namespaces, each attached by a veth. The member end is named
+/// `<namespace>_<bridge>` and gets host `n + 1` of the subnets for the
+/// `n`-th member, the bridge end is named `<bridge>_<namespace>`.
+pub struct Bridge{
+    pub name: String,
+    pub subnet: String,
+    pub subnet6: Option<String>,
+    /// namespace holding the bridge device
+    pub namespace: Arc<Namespace>,
+    pub backend: BridgeBackend,
+}
+
+impl Bridge{
+    /// Creates the bridge inside `namespace`, or in a namespace of its own
+    /// named like the bridge. Subnets are assigned like those of a `Link`,
+    /// allocated ones large enough for `members` members.
+    pub fn new(name: String, subnet: String, subnet6: Option<String>, namespace: Option<Arc<Namespace>>, backend: BridgeBackend, members: usize, config: &Config) -> Result<Arc<Bridge>>{
+        if config.bridges.contains_key(&name) || config.links.contains_key(&name) {
+            return Err(RouterError::Exists{ kind: "Bridge", name });
+        }
+        interface::check_name(&name).map_err(|e| e.context(format!("Bridge {}", name)))?;
+        let namespace = match namespace{
+            Some(ns) => ns,
+            None => Namespace::new(name.clone(), config)?,
+        };
+        let (subnet, subnet6) = config.ipam().assign_hosts(subnet, subnet6, members as u128)
+            .map_err(|e| e.context(format!("Bridge {}", name)))?;
+        let b = Bridge{
+            name: name.clone(),
+            subnet,
+            subnet6,
+            namespace,
+            backend,
+        };
+        match b.switch(&config.name){
+            Some(switch) => if switch.start()? {
+                config.transaction().record(Resource::Daemon{ dir: switch.dir.clone() });
+            },
+            None => if !(config.reconcile && cmd::ip(Some(&b.namespace.netns), &["link", "show", "dev", b.name.as_str()]).is_ok()) {
+                cmd::ip(Some(&b.namespace.netns), &["link", "add", "name", b.name.as_str(), "type", "bridge"])?;
+            },
+        }
+        cmd::ip(Some(&b.namespace.netns), &["link", "set", "dev", b.name.as_str(), "up"])?;
+        let b = Arc::new(b);
+        config.bridges.insert(name, b.clone());
+        Ok(b)
+    }
+
+    /// Connects `namespaces` to the bridge in order and addresses their ends.
+    pub fn attach(&self, namespaces: &[Arc<Namespace>], config: &Config) -> Result<Vec<Arc<Interface>>>{
+        let mut subnets = Vec::new();
+        for subnet in std::iter::once(&self.subnet).chain(self.subnet6.iter()){
+            let sn: ipnet::IpNet = subnet.parse()
+                .map_err(|e: ipnet::AddrParseError| RouterError::InvalidSubnet{ subnet: subnet.clone(), reason: e.to_string() })?;
+            subnets.push(sn);
+        }
+        let mut interfaces = Vec::new();
+        for (n, ns) in namespaces.iter().enumerate(){
+            if ns.netns == self.namespace.netns {
+                return Err(RouterError::Invalid(format!("Namespace {} holds bridge {} and cannot be a member", ns.name, self.name)));
+            }
+            let port = interface::name(&self.name, &ns.name);
+            let veth = Veth{
+                name: interface::name(&ns.name, &self.name),
+                namespace: ns.netns.clone(),
+                peer: port.clone(),
+                peer_namespace: self.namespace.netns.clone(),
+                numtxqueues: None,
+                numrxqueues: None,
+            };
+            veth.setup(config)?;
+            interface::alias(&veth.name, Some(&ns.netns), &format!("{}_{}", ns.name, self.name), config)?;
+            interface::alias(&port, Some(&self.namespace.netns), &format!("{}_{}", self.name, ns.name), config)?;
+            match self.switch(&config.name){
+                Some(switch) => {
+                    cmd::ip(Some(&self.namespace.netns), &["link", "set", "dev", port.as_str(), "mtu", "3000", "up"])?;
+                    switch.add_port(&port)?;
+                },
+                None => {
+                    cmd::ip(Some(&self.namespace.netns), &["link", "set", "dev", port.as_str(), "master", self.name.as_str(), "mtu", "3000", "up"])?;
+                },
+            }
+
+            let (mut ip, mut ip6) = (None, None);
+            for sn in &subnets{
+                let addr = host_addr(sn, n as u128 + 1)?;
+                if sn.addr().is_ipv6() {
+                    ip6 = Some(addr);
+                } else {
+                    ip = Some(addr);
+                }
+            }
+            interfaces.push(Interface::new(veth.name.clone(), Some(ns.clone()), ip, ip6, Some(3000), config)?);
+        }
+        Ok(interfaces)
+    }
+
+    /// Open vSwitch instance of an OVS bridge of `topology`.
+    pub fn switch(&self, topology: &str) -> Option<Switch> {
+        (self.backend == BridgeBackend::Ovs).then(|| Switch::new(topology, &self.namespace.netns, &self.name))
+    }
+}
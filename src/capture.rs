@@ -0,0 +1,208 @@
+//! Packet captures scoped to the traffic between two namespaces. The BPF
+//! filter is built from the addresses in the saved state, and the
+//! interfaces to capture on are found by following the kernel's route
+//! lookups hop by hop in both directions, so captures land exactly where
+//! the flow passes. Captures are written next to the node logs, see `logs`,
+//! and so end up in a run's artifacts.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::process::{Child, Stdio};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{failed, Result, RouterError};
+use crate::state::State;
+use crate::trace::Traced;
+use crate::{logs, netns, Namespace};
+
+/// Longest path followed before giving up on a routing loop.
+const MAX_HOPS: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol{
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+impl FromStr for Protocol{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<Self>{
+        match s{
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            "icmp" => Ok(Protocol::Icmp),
+            _ => Err(RouterError::Invalid(format!("Unknown protocol {}, expected tcp, udp or icmp", s))),
+        }
+    }
+}
+
+impl fmt::Display for Protocol{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+            Protocol::Icmp => write!(f, "icmp"),
+        }
+    }
+}
+
+/// Traffic between the namespaces `src` and `dst`, optionally narrowed to a
+/// protocol and a port on either side.
+#[derive(Clone, Debug, Default)]
+pub struct Flow{
+    pub src: String,
+    pub dst: String,
+    pub protocol: Option<Protocol>,
+    pub port: Option<u16>,
+}
+
+/// Interface of a namespace the flow passes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hop{
+    pub netns: String,
+    pub interface: String,
+}
+
+impl Flow{
+    /// tcpdump filter expression matching the flow in both directions.
+    pub fn filter(&self, state: &State) -> Result<String>{
+        if self.port.is_some() && !matches!(self.protocol, Some(Protocol::Tcp) | Some(Protocol::Udp)) {
+            return Err(failed!("A port needs protocol tcp or udp"));
+        }
+        let hosts = |namespace: &str| -> Result<String> {
+            let addresses = addresses(state, namespace)?;
+            let hosts: Vec<String> = addresses.iter().map(|a| format!("host {}", a)).collect();
+            Ok(format!("({})", hosts.join(" or ")))
+        };
+        let mut filter = format!("{} and {}", hosts(&self.src)?, hosts(&self.dst)?);
+        match (self.protocol, self.port){
+            (Some(Protocol::Icmp), _) => filter.push_str(" and (icmp or icmp6)"),
+            (Some(protocol), Some(port)) => filter.push_str(&format!(" and {} port {}", protocol, port)),
+            (Some(protocol), None) => filter.push_str(&format!(" and {}", protocol)),
+            (None, _) => {},
+        }
+        Ok(filter)
+    }
+
+    /// Interfaces the flow crosses on the way to `dst` and back, in path
+    /// order, using the first address of `dst` and `src` of the same family.
+    pub fn path(&self, state: &State) -> Result<Vec<Hop>>{
+        let src = addresses(state, &self.src)?;
+        let dst = addresses(state, &self.dst)?;
+        let (to, back) = dst.iter()
+            .find_map(|d| Some((*d, *src.iter().find(|s| s.is_ipv6() == d.is_ipv6())?)))
+            .ok_or_else(|| failed!("{} and {} share no address family", self.src, self.dst))?;
+        let mut hops = trace(state, &Namespace::netns_name(&state.name, &self.src), to)?;
+        for hop in trace(state, &Namespace::netns_name(&state.name, &self.dst), back)?{
+            if !hops.contains(&hop) {
+                hops.push(hop);
+            }
+        }
+        Ok(hops)
+    }
+}
+
+/// Addresses of the interfaces of `namespace`, in interface name order.
+fn addresses(state: &State, namespace: &str) -> Result<Vec<IpAddr>>{
+    let netns = Namespace::netns_name(&state.name, namespace);
+    let mut interfaces: Vec<_> = state.interfaces.iter().filter(|i| i.netns.as_deref() == Some(netns.as_str())).collect();
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    let addresses: Vec<IpAddr> = interfaces.iter()
+        .flat_map(|i| [&i.ip, &i.ip6])
+        .flatten()
+        .filter_map(|ip| ip.split('/').next()?.parse().ok())
+        .collect();
+    if addresses.is_empty() {
+        return Err(failed!("Namespace {} of {} has no addresses", namespace, state.name));
+    }
+    Ok(addresses)
+}
+
+/// Namespace and interface holding `address`.
+fn owner(state: &State, address: IpAddr) -> Option<Hop> {
+    state.interfaces.iter()
+        .find(|i| [&i.ip, &i.ip6].into_iter().flatten().any(|ip| ip.split('/').next() == Some(address.to_string().as_str())))
+        .and_then(|i| Some(Hop{ netns: i.netns.clone()?, interface: i.name.clone() }))
+}
+
+/// Egress and ingress interfaces from `netns` to `address`, following the
+/// routes of every namespace on the way.
+fn trace(state: &State, netns: &str, address: IpAddr) -> Result<Vec<Hop>>{
+    let mut hops = Vec::new();
+    let mut current = netns.to_string();
+    for _ in 0..MAX_HOPS{
+        let out = netns::exec(&current, "ip", &["-j", "route", "get", address.to_string().as_str()])?;
+        if !out.success() {
+            return Err(failed!("No route to {} in {}: {}", address, current, out.stderr.trim()));
+        }
+        let route: serde_json::Value = serde_json::from_str(&out.stdout)?;
+        let route = &route[0];
+        if route["type"] == "local" {
+            return Ok(hops);
+        }
+        let dev = route["dev"].as_str()
+            .ok_or_else(|| failed!("No route to {} in {}", address, current))?;
+        hops.push(Hop{ netns: current.clone(), interface: dev.to_string() });
+        let next = route["gateway"].as_str().and_then(|g| g.parse().ok()).unwrap_or(address);
+        // the path leaves the topology
+        let Some(ingress) = owner(state, next) else {
+            return Ok(hops);
+        };
+        current = ingress.netns.clone();
+        hops.push(ingress);
+    }
+    Err(failed!("Path from {} to {} is longer than {} hops", netns, address, MAX_HOPS))
+}
+
+/// tcpdump processes writing one capture file per interface.
+pub struct Capture{
+    processes: Vec<(Hop, Child, PathBuf)>,
+}
+
+impl Capture{
+    /// Starts tcpdump with `filter` on every hop, writing to
+    /// `<logs>/<netns>/<interface>.pcap` of `topology`.
+    pub fn start(topology: &str, hops: &[Hop], filter: &str) -> Result<Capture>{
+        let mut capture = Capture{ processes: Vec::new() };
+        for hop in hops{
+            std::fs::create_dir_all(logs::dir(topology, &hop.netns))?;
+            let path = logs::dir(topology, &hop.netns).join(format!("{}.pcap", hop.interface));
+            let child = netns::command(&hop.netns, "tcpdump")
+                .args(["-i", hop.interface.as_str(), "-U", "-n", "-w"])
+                .arg(&path)
+                .arg(filter)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .traced_spawn();
+            match child{
+                Ok(child) => capture.processes.push((hop.clone(), child, path)),
+                Err(e) => {
+                    capture.stop()?;
+                    return Err(failed!("Failed to run tcpdump in {}: {}", hop.netns, e));
+                },
+            }
+        }
+        Ok(capture)
+    }
+
+    /// Interrupts tcpdump so it flushes its files and returns them.
+    pub fn stop(self) -> Result<Vec<(Hop, PathBuf)>>{
+        let mut files = Vec::new();
+        for (hop, mut child, path) in self.processes{
+            unsafe { libc::kill(child.id() as i32, libc::SIGINT) };
+            let status = child.wait()?;
+            // tcpdump exits with 0 on SIGINT, anything else means it never
+            // captured, e.g. a missing binary or interface
+            if !status.success() && !path.exists() {
+                return Err(failed!("tcpdump on {} in {} failed: {}", hop.interface, hop.netns, status));
+            }
+            files.push((hop, path));
+        }
+        Ok(files)
+    }
+}
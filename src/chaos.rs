@@ -0,0 +1,542 @@
+//! Chaos testing of a running topology: links are taken down and brought
+//! up again, or drop part of their packets for a while, on a schedule or
+//! at random from a seed, so a run can be repeated. After every change
+//! the routing tables of all namespaces are watched until the next one, so
+//! the timeline shows which namespaces reacted and how long routing took
+//! to converge, including ECMP nexthops being removed and added back.
+//!
+//! A link goes down with both of its ends, as when the cable is pulled.
+//! Loss is injected with a tc drop action on both ends, leaving the
+//! link's own impairments in place, see `inject`.
+//!
+//! Events may overlap, e.g. a link flapping every 100 ms with failures
+//! lasting 150 ms. What a link is left with is kept per link, see
+//! `LinkState`: it is down while any failure lasts, an `up` of the
+//! schedule ends all of them and the end of one failure only that one.
+//! Loss likewise, at the percentage of the latest loss still lasting.
+//! Ordering guarantees:
+//!
+//! - steps run in the order of their time, steps at the same time in the
+//!   order of the schedule, the end of an event after the events starting
+//!   at that time
+//! - steps less than the debounce interval after the first of a batch are
+//!   folded into it and applied together once the last of them is due, the
+//!   link only changes if the batch leaves it in another state, so a link
+//!   flapping faster than the interval shows up as one change or none
+//! - the timeline has one record per link changed, in the order of the
+//!   schedule, records of one batch share its time and convergence
+//!
+//! With `liveness` set, the links of the schedule are probed along the run,
+//! see `liveness`, and every change records how long the prober took to
+//! see the link go down or come up again.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::{failed, Result, RouterError};
+use crate::inject::{Direction, DropInjection, DropMode};
+use crate::interface;
+use crate::liveness::{LivenessEvent, LivenessOptions, LivenessProber};
+use crate::state::State;
+
+/// Time between two looks at the routing tables.
+const POLL: Duration = Duration::from_millis(20);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChaosAction{
+    Down,
+    Up,
+    /// drop `percent` of the packets in both directions
+    Loss,
+    /// stop dropping packets
+    Clear,
+}
+
+impl fmt::Display for ChaosAction{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            ChaosAction::Down => write!(f, "down"),
+            ChaosAction::Up => write!(f, "up"),
+            ChaosAction::Loss => write!(f, "loss"),
+            ChaosAction::Clear => write!(f, "clear"),
+        }
+    }
+}
+
+impl ChaosAction{
+    /// Action undoing this one.
+    fn undo(&self) -> ChaosAction {
+        match self{
+            ChaosAction::Down => ChaosAction::Up,
+            ChaosAction::Up => ChaosAction::Down,
+            ChaosAction::Loss => ChaosAction::Clear,
+            ChaosAction::Clear => ChaosAction::Loss,
+        }
+    }
+}
+
+/// Change to a link at a point of the schedule.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChaosEvent{
+    /// milliseconds since the start of the run
+    pub at: u64,
+    pub link: String,
+    pub action: ChaosAction,
+    /// packets dropped by `loss`, in percent
+    #[serde(default)]
+    pub percent: Option<f64>,
+    /// milliseconds after which `down` and `loss` are undone, they last
+    /// until a later event if not set
+    #[serde(default)]
+    pub duration: Option<u64>,
+}
+
+/// Random link failures and loss, the same for the same seed.
+#[derive(Clone, Debug)]
+pub struct RandomChaos{
+    /// links to pick from
+    pub links: Vec<String>,
+    pub events: u32,
+    /// time between two events
+    pub interval: Duration,
+    /// how long a link stays down or lossy, less than `interval`
+    pub duration: Duration,
+    /// pick loss of this many percent instead of a failure every other
+    /// time on average
+    pub loss: Option<f64>,
+    pub seed: u64,
+}
+
+impl RandomChaos{
+    pub fn schedule(&self) -> Result<Vec<ChaosEvent>>{
+        if self.links.is_empty() {
+            return Err(failed!("No links to pick from"));
+        }
+        if self.duration >= self.interval {
+            return Err(failed!("Events last {} ms, longer than the interval of {} ms", self.duration.as_millis(), self.interval.as_millis()));
+        }
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut events = Vec::new();
+        for n in 0..self.events as u64{
+            let link = self.links[rng.gen_range(0..self.links.len())].clone();
+            let loss = self.loss.filter(|_| rng.gen_bool(0.5));
+            events.push(ChaosEvent{
+                at: n * self.interval.as_millis() as u64,
+                link,
+                action: if loss.is_some() { ChaosAction::Loss } else { ChaosAction::Down },
+                percent: loss,
+                duration: Some(self.duration.as_millis() as u64),
+            });
+        }
+        Ok(events)
+    }
+}
+
+/// What a link is left with by the steps applied so far.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LinkState{
+    /// failures lasting
+    downs: u32,
+    /// percentages of the losses lasting, the latest applies
+    losses: Vec<f64>,
+}
+
+impl LinkState{
+    pub fn down(&self) -> bool {
+        self.downs > 0
+    }
+
+    /// Percentage of the packets dropped, if any.
+    pub fn loss(&self) -> Option<f64> {
+        self.losses.last().copied()
+    }
+
+    /// Applies `action` of the schedule, or the end of an event if `undo`.
+    pub fn apply(&mut self, action: ChaosAction, percent: Option<f64>, undo: bool){
+        match action{
+            ChaosAction::Down => self.downs += 1,
+            ChaosAction::Up if undo => self.downs = self.downs.saturating_sub(1),
+            ChaosAction::Up => self.downs = 0,
+            ChaosAction::Loss => self.losses.push(percent.unwrap_or(100.0)),
+            ChaosAction::Clear if undo => {
+                if let Some(n) = self.losses.iter().position(|p| Some(*p) == percent) {
+                    self.losses.remove(n);
+                }
+            },
+            ChaosAction::Clear => self.losses.clear(),
+        }
+    }
+
+    /// Actions taking a link from `self` to `next`, with the percentage of
+    /// the loss.
+    fn changes(&self, next: &LinkState) -> Vec<(ChaosAction, Option<f64>)> {
+        let mut changes = Vec::new();
+        if self.down() != next.down() {
+            changes.push((if next.down() { ChaosAction::Down } else { ChaosAction::Up }, None));
+        }
+        if self.loss() != next.loss() {
+            match next.loss(){
+                Some(percent) => changes.push((ChaosAction::Loss, Some(percent))),
+                None => changes.push((ChaosAction::Clear, None)),
+            }
+        }
+        changes
+    }
+}
+
+/// Change made during a run and the routing changes following it.
+#[derive(Serialize, Clone, Debug)]
+pub struct ChaosRecord{
+    /// since the start of the run
+    pub at: Duration,
+    pub link: String,
+    pub action: ChaosAction,
+    pub percent: Option<f64>,
+    /// steps of the schedule folded into this change, 1 unless debounced
+    pub steps: usize,
+    /// time from the change to the last routing change seen before the
+    /// next one, None if routes didn't change
+    pub converged: Option<Duration>,
+    /// namespaces whose routes changed
+    pub changed: Vec<String>,
+    /// time from the change to the liveness prober seeing the link go down
+    /// or come up, None if it didn't before the link's next change or no
+    /// prober ran
+    pub detected: Option<Duration>,
+}
+
+impl fmt::Display for ChaosRecord{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>9.3}s {} {}", self.at.as_secs_f64(), self.link, self.action)?;
+        if let Some(percent) = self.percent{
+            write!(f, " {}%", percent)?;
+        }
+        if self.steps > 1 {
+            write!(f, " ({} steps)", self.steps)?;
+        }
+        match self.converged{
+            Some(t) => write!(f, ": converged after {:.1} ms in {}", t.as_secs_f64() * 1000.0, self.changed.join(" "))?,
+            None => write!(f, ": no route changed")?,
+        }
+        if let Some(t) = self.detected{
+            write!(f, ", detected after {:.1} ms", t.as_secs_f64() * 1000.0)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ChaosReport{
+    /// seed of a random run
+    pub seed: Option<u64>,
+    pub timeline: Vec<ChaosRecord>,
+    /// steps of the schedule which left their link as it was, e.g. a
+    /// failure ending within the debounce interval
+    pub absorbed: usize,
+    /// links going down and coming up as the liveness prober saw them,
+    /// since the start of the run
+    pub liveness: Vec<LivenessEvent>,
+}
+
+impl fmt::Display for ChaosReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(seed) = self.seed{
+            writeln!(f, "seed {}", seed)?;
+        }
+        for r in &self.timeline{
+            writeln!(f, "{}", r)?;
+        }
+        if self.absorbed > 0 {
+            writeln!(f, "{} steps left their link as it was", self.absorbed)?;
+        }
+        let times: Vec<Duration> = self.timeline.iter().filter_map(|r| r.converged).collect();
+        if let Some(max) = times.iter().max() {
+            let mean = times.iter().sum::<Duration>() / times.len() as u32;
+            writeln!(f, "{} of {} changes moved routes, converged after avg {:.1} ms, max {:.1} ms",
+                times.len(), self.timeline.len(), mean.as_secs_f64() * 1000.0, max.as_secs_f64() * 1000.0)?;
+        }
+        let detected: Vec<Duration> = self.timeline.iter().filter_map(|r| r.detected).collect();
+        if let Some(max) = detected.iter().max() {
+            let mean = detected.iter().sum::<Duration>() / detected.len() as u32;
+            writeln!(f, "{} of {} changes detected by liveness probes after avg {:.1} ms, max {:.1} ms",
+                detected.len(), self.timeline.len(), mean.as_secs_f64() * 1000.0, max.as_secs_f64() * 1000.0)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct ChaosRun{
+    pub topology: String,
+    pub events: Vec<ChaosEvent>,
+    /// how long routes are watched after the last change
+    pub settle: Duration,
+    /// steps this close to the first of a batch are applied with it
+    pub debounce: Duration,
+    /// probe the links of the schedule along the run
+    pub liveness: Option<LivenessOptions>,
+}
+
+impl ChaosRun{
+    /// Runs the schedule. If a change fails, the links changed so far are
+    /// brought up and cleared again.
+    pub fn run(&self) -> Result<ChaosReport>{
+        let state = State::load(&self.topology)?
+            .ok_or_else(|| RouterError::TopologyNotFound(self.topology.to_string()))?;
+        // each event and the one undoing it, in order
+        let mut steps: Vec<Step> = Vec::new();
+        let mut ends = HashMap::new();
+        for e in &self.events{
+            if e.action == ChaosAction::Loss && !e.percent.is_some_and(|p| p > 0.0 && p <= 100.0) {
+                return Err(failed!("Loss on {} at {} ms needs a percentage above 0 up to 100", e.link, e.at));
+            }
+            if !ends.contains_key(&e.link) {
+                ends.insert(e.link.clone(), link_ends(&state, &e.link)?);
+            }
+            steps.push(Step{ at: e.at, event: e, action: e.action, undo: false });
+            if let Some(duration) = e.duration.filter(|_| matches!(e.action, ChaosAction::Down | ChaosAction::Loss)) {
+                steps.push(Step{ at: e.at + duration, event: e, action: e.action.undo(), undo: true });
+            }
+        }
+        // stable, ends of events after the events starting at their time
+        steps.sort_by_key(|s| (s.at, s.undo));
+        let batches = batches(&steps, self.debounce);
+        let namespaces: Vec<String> = state.namespaces.iter().map(|n| n.netns.clone()).collect();
+        let mut links: HashMap<&str, LinkState> = HashMap::new();
+        let mut report = ChaosReport::default();
+        let prober = match self.liveness{
+            Some(options) => {
+                let mut links: Vec<String> = ends.keys().cloned().collect();
+                links.sort();
+                Some(LivenessProber::start(&state, &links, options)?)
+            },
+            None => None,
+        };
+        let start = Instant::now();
+        let result = (|| -> Result<()>{
+            let mut tables = routes(&namespaces)?;
+            for (n, batch) in batches.iter().enumerate(){
+                let due = start + Duration::from_millis(batch[batch.len() - 1].at);
+                std::thread::sleep(due.saturating_duration_since(Instant::now()));
+                let changed_at = Instant::now();
+                // in the order of the schedule
+                let mut changes: Vec<LinkChange> = Vec::new();
+                let mut touched: Vec<&str> = Vec::new();
+                for step in batch.iter(){
+                    if !touched.contains(&step.event.link.as_str()) {
+                        touched.push(&step.event.link);
+                    }
+                }
+                for link in touched{
+                    let before = links.get(link).cloned().unwrap_or_default();
+                    let mut after = before.clone();
+                    let mut folded = 0;
+                    for step in batch.iter().filter(|s| s.event.link == link){
+                        after.apply(step.action, step.event.percent, step.undo);
+                        folded += 1;
+                    }
+                    let actions = before.changes(&after);
+                    if actions.is_empty() {
+                        report.absorbed += folded;
+                    } else {
+                        changes.push((link, actions, folded));
+                    }
+                    links.insert(link, after);
+                }
+                for (link, actions, _) in &changes{
+                    for (action, percent) in actions{
+                        for (netns, interface) in &ends[*link]{
+                            apply(netns, interface, *action, *percent)
+                                .map_err(|e| failed!("Failed to {} link {}: {}", action, link, e))?;
+                        }
+                    }
+                }
+                let until = match batches.get(n + 1){
+                    Some(next) => start + Duration::from_millis(next[next.len() - 1].at),
+                    None => Instant::now() + self.settle,
+                };
+                let (converged, changed) = watch(&namespaces, &mut tables, changed_at, until)?;
+                for (link, actions, folded) in changes{
+                    for (action, percent) in actions{
+                        report.timeline.push(ChaosRecord{
+                            at: changed_at - start,
+                            link: link.to_string(),
+                            action,
+                            percent,
+                            steps: folded,
+                            converged,
+                            changed: changed.clone(),
+                            detected: None,
+                        });
+                    }
+                }
+            }
+            Ok(())
+        })();
+        if let Err(e) = result {
+            for (netns, interface) in ends.values().flatten(){
+                let _ = apply(netns, interface, ChaosAction::Up, None);
+                let _ = apply(netns, interface, ChaosAction::Clear, None);
+            }
+            return Err(e);
+        }
+        if let Some(prober) = prober {
+            let offset = start - prober.started();
+            report.liveness = prober.stop().events.into_iter()
+                .filter(|e| e.at >= offset)
+                .map(|e| LivenessEvent{ at: e.at - offset, ..e })
+                .collect();
+            detect(&mut report);
+        }
+        Ok(report)
+    }
+}
+
+/// Sets the detection time of every change of the timeline: the first
+/// liveness event of its link taking it where the change did, before the
+/// link changed again.
+fn detect(report: &mut ChaosReport){
+    for n in 0..report.timeline.len(){
+        let record = &report.timeline[n];
+        let next = report.timeline[n + 1..].iter()
+            .find(|r| r.link == record.link && r.at > record.at)
+            .map(|r| r.at);
+        let wanted = match record.action{
+            ChaosAction::Down => Some(false),
+            ChaosAction::Up => Some(true),
+            ChaosAction::Loss | ChaosAction::Clear => None,
+        };
+        let detected = report.liveness.iter()
+            .filter(|e| e.link == record.link && e.at >= record.at && next.is_none_or(|next| e.at < next))
+            .find(|e| wanted.is_none_or(|up| e.up == up))
+            .map(|e| e.at - record.at);
+        report.timeline[n].detected = detected;
+    }
+}
+
+/// Step of a run: an event of the schedule or the end of one.
+struct Step<'a>{
+    /// milliseconds since the start of the run
+    at: u64,
+    event: &'a ChaosEvent,
+    action: ChaosAction,
+    /// ends `event` after its duration
+    undo: bool,
+}
+
+/// Link changed by a batch, the actions taking it there with the
+/// percentage of the loss, and the steps folded in.
+type LinkChange<'a> = (&'a str, Vec<(ChaosAction, Option<f64>)>, usize);
+
+/// `steps` in batches of those less than `debounce` after the first of
+/// each, every step alone if it is zero but for steps at the same time.
+fn batches<'a, 'b>(steps: &'b [Step<'a>], debounce: Duration) -> Vec<&'b [Step<'a>]> {
+    let debounce = debounce.as_millis() as u64;
+    let mut batches = Vec::new();
+    let mut first = 0;
+    for n in 1..=steps.len(){
+        let ends = match steps.get(n){
+            Some(step) => step.at > steps[first].at && step.at - steps[first].at >= debounce,
+            None => true,
+        };
+        if ends {
+            batches.push(&steps[first..n]);
+            first = n;
+        }
+    }
+    batches
+}
+
+/// (namespace, interface) of both ends of the link `name`.
+pub(crate) fn link_ends(state: &State, name: &str) -> Result<Vec<(String, String)>>{
+    if !state.links.iter().any(|l| l.name == name) {
+        return Err(failed!("Link {} not found in {}", name, state.name));
+    }
+    let ends = state.namespaces.iter()
+        .filter_map(|ns| {
+            let interface = interface::name(&ns.name, name);
+            state.interfaces.iter()
+                .any(|i| i.name == interface && i.netns.as_deref() == Some(ns.netns.as_str()))
+                .then(|| (ns.netns.clone(), interface))
+        })
+        .collect();
+    Ok(ends)
+}
+
+pub(crate) fn apply(netns: &str, interface: &str, action: ChaosAction, percent: Option<f64>) -> Result<()>{
+    let drop = DropInjection::new(netns.to_string(), interface.to_string(), Direction::Egress);
+    match action{
+        ChaosAction::Down | ChaosAction::Up => {
+            cmd::ip(Some(netns), &["link", "set", "dev", interface, &action.to_string()])?;
+            Ok(())
+        },
+        ChaosAction::Loss => {
+            let n = (100.0 / percent.unwrap_or(100.0)).round().clamp(1.0, 10000.0) as u32;
+            drop.apply(if n == 1 { DropMode::All } else { DropMode::Random(n) })
+        },
+        // nothing to clear unless loss was injected
+        ChaosAction::Clear => {
+            if drop.counters().is_ok() {
+                drop.remove()?;
+            }
+            Ok(())
+        },
+    }
+}
+
+/// Routes of every namespace, IPv4 and IPv6, without expiry timers.
+pub(crate) fn routes(namespaces: &[String]) -> Result<Vec<Vec<String>>>{
+    let mut tables = Vec::new();
+    for netns in namespaces{
+        let mut table = Vec::new();
+        for family in ["-4", "-6"]{
+            let out = cmd::ip(Some(netns), &["-o", family, "route", "show"])
+                .map_err(|e| e.context(format!("Failed to list the routes of {}", netns)))?;
+            for line in out.lines(){
+                let mut words = line.split_whitespace();
+                let mut route = Vec::new();
+                while let Some(w) = words.next(){
+                    if w == "expires" {
+                        words.next();
+                        continue;
+                    }
+                    route.push(w);
+                }
+                table.push(route.join(" "));
+            }
+        }
+        tables.push(table);
+    }
+    Ok(tables)
+}
+
+/// Watches the routes of `namespaces` until `until`. Returns the time
+/// from `since` to the last change and the namespaces which changed.
+fn watch(namespaces: &[String], tables: &mut Vec<Vec<String>>, since: Instant, until: Instant) -> Result<(Option<Duration>, Vec<String>)>{
+    let mut last = None;
+    let mut changed: Vec<String> = Vec::new();
+    loop{
+        let now = routes(namespaces)?;
+        let seen = Instant::now();
+        for ((netns, old), new) in namespaces.iter().zip(tables.iter()).zip(&now){
+            if old != new {
+                last = Some(seen - since);
+                if !changed.contains(netns) {
+                    changed.push(netns.clone());
+                }
+            }
+        }
+        *tables = now;
+        if seen >= until {
+            changed.sort();
+            return Ok((last, changed));
+        }
+        std::thread::sleep(POLL.min(until - seen));
+    }
+}
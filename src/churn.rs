@@ -0,0 +1,192 @@
+//! Namespace churn: a topology is created and destroyed over and over at a
+//! set rate, and after every teardown the host is compared with how it was
+//! before the first cycle, so leaks of our own cleanup show up at scale:
+//!
+//! - namespaces, or their mounts, left in `/run/netns`
+//! - devices left in the host namespace, by name and ifindex
+//! - host routes, IPv4 and IPv6 of all tables
+//! - saved state and runtime directories under `STATE_DIR`
+//! - file descriptors of this process
+//!
+//! The run stops at the first cycle leaving something behind, later ones
+//! would only pile more on top.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::cmd;
+use crate::error::{failed, Result};
+use crate::parallel::Parallelism;
+use crate::state::{self, STATE_DIR};
+use crate::topology::Topology;
+use crate::Config;
+
+/// What of the host a topology must not leave behind.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot{
+    pub namespaces: BTreeSet<String>,
+    /// mount points under `/run/netns`
+    pub mounts: BTreeSet<String>,
+    /// `<name> (ifindex <n>)` of the host devices
+    pub links: BTreeSet<String>,
+    pub routes: BTreeSet<String>,
+    /// entries in `STATE_DIR`
+    pub state: BTreeSet<String>,
+    pub fds: usize,
+}
+
+impl Snapshot{
+    pub fn take() -> Result<Snapshot>{
+        let mounts = std::fs::read_to_string("/proc/self/mounts")?
+            .lines()
+            .filter_map(|l| l.split_whitespace().nth(1))
+            .filter(|m| m.starts_with("/run/netns/"))
+            .map(|m| m.to_string())
+            .collect();
+        let links = cmd::ip_json(None, &["-j", "link", "show"])?.as_array().cloned().unwrap_or_default().iter()
+            .filter_map(|l| Some(format!("{} (ifindex {})", l["ifname"].as_str()?, l["ifindex"].as_u64()?)))
+            .collect();
+        let mut routes = BTreeSet::new();
+        for family in ["-4", "-6"]{
+            for r in cmd::ip_json(None, &[family, "-j", "route", "show", "table", "all"])?.as_array().cloned().unwrap_or_default(){
+                let field = |name: &str| r[name].as_str().map(|v| format!(" {} {}", name, v)).unwrap_or_default();
+                let table = r["table"].as_str().unwrap_or("main");
+                routes.insert(format!("{}{}{}{} table {}", r["dst"].as_str().unwrap_or("?"), field("gateway"), field("dev"), field("type"), table));
+            }
+        }
+        Ok(Snapshot{
+            namespaces: entries("/run/netns")?,
+            mounts,
+            links,
+            routes,
+            state: entries(STATE_DIR)?,
+            fds: std::fs::read_dir("/proc/self/fd")?.count(),
+        })
+    }
+
+    /// What `after` has that `self` hasn't.
+    pub fn leaks(&self, after: &Snapshot) -> Vec<String> {
+        let mut leaks = Vec::new();
+        let new = |before: &BTreeSet<String>, after: &BTreeSet<String>| -> Vec<String> {
+            after.difference(before).cloned().collect()
+        };
+        leaks.extend(new(&self.namespaces, &after.namespaces).into_iter().map(|n| format!("namespace {}", n)));
+        leaks.extend(new(&self.mounts, &after.mounts).into_iter().map(|m| format!("mount {}", m)));
+        leaks.extend(new(&self.links, &after.links).into_iter().map(|l| format!("host device {}", l)));
+        leaks.extend(new(&self.routes, &after.routes).into_iter().map(|r| format!("host route {}", r)));
+        leaks.extend(new(&self.state, &after.state).into_iter().map(|s| format!("state {}/{}", STATE_DIR, s)));
+        if after.fds > self.fds {
+            leaks.push(format!("{} file descriptors", after.fds - self.fds));
+        }
+        leaks
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Cycle{
+    pub cycle: u32,
+    pub create: Duration,
+    pub destroy: Duration,
+    /// left behind after the teardown
+    pub leaks: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ChurnReport{
+    pub topology: String,
+    pub namespaces: usize,
+    pub links: usize,
+    pub cycles: Vec<Cycle>,
+}
+
+impl ChurnReport{
+    pub fn leaks(&self) -> usize {
+        self.cycles.iter().map(|c| c.leaks.len()).sum()
+    }
+}
+
+impl fmt::Display for ChurnReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let n = self.cycles.len().max(1) as u32;
+        let create: Duration = self.cycles.iter().map(|c| c.create).sum();
+        let destroy: Duration = self.cycles.iter().map(|c| c.destroy).sum();
+        let max_create = self.cycles.iter().map(|c| c.create).max().unwrap_or_default();
+        let max_destroy = self.cycles.iter().map(|c| c.destroy).max().unwrap_or_default();
+        writeln!(f, "{} cycles of {} ({} namespaces, {} links)", self.cycles.len(), self.topology, self.namespaces, self.links)?;
+        writeln!(f, "  create  mean {:>8.1} ms  max {:>8.1} ms", (create / n).as_secs_f64() * 1000.0, max_create.as_secs_f64() * 1000.0)?;
+        write!(f, "  destroy mean {:>8.1} ms  max {:>8.1} ms", (destroy / n).as_secs_f64() * 1000.0, max_destroy.as_secs_f64() * 1000.0)?;
+        for c in self.cycles.iter().filter(|c| !c.leaks.is_empty()){
+            write!(f, "\ncycle {} leaked:", c.cycle)?;
+            for leak in &c.leaks{
+                write!(f, "\n  {}", leak)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Creates and destroys `topology` `cycles` times, starting a cycle at
+/// most `rate` times a second. The host is checked before the first one.
+pub struct ChurnRun{
+    pub topology: Topology,
+    pub cycles: u32,
+    pub rate: f64,
+    pub parallelism: Parallelism,
+}
+
+impl ChurnRun{
+    pub fn run(&self) -> Result<ChurnReport>{
+        let name = &self.topology.name;
+        if !state::namespaces(name)?.is_empty() {
+            return Err(failed!("Topology {} exists, churn needs a name of its own", name));
+        }
+        if self.rate <= 0.0 {
+            return Err(failed!("Invalid rate {}, expected cycles per second above 0", self.rate));
+        }
+        let interval = Duration::from_secs_f64(1.0 / self.rate);
+        let mut report = ChurnReport{
+            topology: name.clone(),
+            namespaces: self.topology.namespaces.len(),
+            links: self.topology.links.len(),
+            cycles: Vec::new(),
+        };
+        let baseline = Snapshot::take()?;
+        for cycle in 1..=self.cycles{
+            let start = Instant::now();
+            let mut config = Config::new(name.clone());
+            config.parallelism = self.parallelism;
+            config.preflight = cycle == 1;
+            self.topology.apply_with(config)
+                .map_err(|e| failed!("Cycle {} failed to create {}: {}", cycle, name, e))?;
+            let create = start.elapsed();
+            let destroyed = Instant::now();
+            Topology::destroy(name)
+                .map_err(|e| failed!("Cycle {} failed to destroy {}: {}", cycle, name, e))?;
+            let destroy = destroyed.elapsed();
+            let leaks = baseline.leaks(&Snapshot::take()?);
+            let leaked = !leaks.is_empty();
+            report.cycles.push(Cycle{ cycle, create, destroy, leaks });
+            if leaked {
+                break;
+            }
+            if let Some(rest) = interval.checked_sub(start.elapsed()){
+                std::thread::sleep(rest);
+            }
+        }
+        Ok(report)
+    }
+}
+
+fn entries(dir: &str) -> Result<BTreeSet<String>>{
+    if !PathBuf::from(dir).exists() {
+        return Ok(BTreeSet::new());
+    }
+    Ok(std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .collect())
+}
@@ -0,0 +1,73 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Result, RouterError};
+use crate::state::STATE_DIR;
+
+/// Clock offsets in seconds for processes started inside a namespace. Time
+/// namespaces only virtualize CLOCK_MONOTONIC and CLOCK_BOOTTIME, the wall
+/// clock (CLOCK_REALTIME) is always shared with the host.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ClockSkew{
+    #[serde(default)]
+    pub monotonic: i64,
+    #[serde(default)]
+    pub boottime: i64,
+}
+
+impl ClockSkew{
+    /// Fails unless the kernel supports time namespaces (Linux 5.6+).
+    pub fn check(&self) -> Result<()>{
+        if !Path::new("/proc/self/ns/time").exists() {
+            return Err(RouterError::Invalid("Clock skew needs time namespace support, which this kernel lacks".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Builds a command running `program` inside `netns` in a fresh time
+    /// namespace carrying the configured offsets.
+    pub fn command(&self, netns: &str, program: &str) -> Command {
+        let mut cmd = Command::new("ip");
+        cmd.arg("netns")
+            .arg("exec")
+            .arg(netns)
+            .arg("unshare")
+            .arg("--time")
+            .arg("--fork")
+            // unshare dying, e.g. a daemon stopped through its pidfile,
+            // stops the program as well
+            .arg("--kill-child=TERM")
+            .arg(format!("--monotonic={}", self.monotonic))
+            .arg(format!("--boottime={}", self.boottime))
+            .arg(program);
+        cmd
+    }
+}
+
+/// Records `skew` as the offsets of programs started in `netns` from now
+/// on, see `netns::command`, or removes them if None.
+pub(crate) fn record(netns: &str, skew: Option<&ClockSkew>) -> Result<()>{
+    let path = path(netns);
+    match skew{
+        Some(skew) => {
+            std::fs::create_dir_all(path.parent().unwrap_or(Path::new(STATE_DIR)))?;
+            std::fs::write(&path, serde_json::to_string(skew)?)?;
+        },
+        None => match std::fs::remove_file(&path){
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {},
+        },
+    }
+    Ok(())
+}
+
+/// Offsets of programs started in `netns`, None if it has none.
+pub fn skew(netns: &str) -> Option<ClockSkew> {
+    serde_json::from_str(&std::fs::read_to_string(path(netns)).ok()?).ok()
+}
+
+fn path(netns: &str) -> PathBuf {
+    Path::new(STATE_DIR).join("clock").join(format!("{}.json", netns))
+}
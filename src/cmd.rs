@@ -0,0 +1,88 @@
+//! The programs the crate drives, `ip`, `tc`, `bridge` and `wg`, run in
+//! the host namespace or, with `netns` set, in a namespace. They are
+//! traced, see `trace`, and a program exiting unsuccessfully fails with
+//! `RouterError::CommandFailed` carrying its command line and stderr.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::error::{Result, RouterError};
+use crate::netns;
+use crate::trace::Traced;
+
+/// Runs `ip` with `args`, returns its output.
+pub fn ip(netns: Option<&str>, args: &[&str]) -> Result<String>{
+    output(&mut in_netns("ip", netns, args))
+}
+
+/// Runs `ip` with `args`, which ask for JSON output with `-j`, returns it
+/// parsed.
+pub fn ip_json(netns: Option<&str>, args: &[&str]) -> Result<serde_json::Value>{
+    Ok(serde_json::from_str(&ip(netns, args)?)?)
+}
+
+pub fn tc(netns: Option<&str>, args: &[&str]) -> Result<String>{
+    output(&mut in_netns("tc", netns, args))
+}
+
+pub fn bridge(netns: Option<&str>, args: &[&str]) -> Result<String>{
+    output(&mut in_netns("bridge", netns, args))
+}
+
+/// Runs `wg` with `args`, `input` on its stdin, e.g. a key, returns its
+/// output trimmed.
+pub fn wg(netns: Option<&str>, args: &[&str], input: &str) -> Result<String>{
+    let mut cmd = match netns{
+        Some(netns) => netns::command(netns, "wg"),
+        None => Command::new("wg"),
+    };
+    cmd.args(args);
+    Ok(with_input(&mut cmd, input)?.trim().to_string())
+}
+
+/// Runs any other `program` with `args` in the host namespace.
+pub fn run(program: &str, args: &[&str]) -> Result<String>{
+    output(Command::new(program).args(args))
+}
+
+/// Runs `program` with `args` inside `netns`, see `netns::command`.
+pub fn exec(netns: &str, program: &str, args: &[&str]) -> Result<String>{
+    output(netns::command(netns, program).args(args))
+}
+
+/// Runs `cmd`, returns its output.
+pub fn output(cmd: &mut Command) -> Result<String>{
+    let output = cmd.traced_output()?;
+    if !output.status.success() {
+        return Err(RouterError::command(cmd, &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Runs `cmd` with `input` on its stdin, returns its output.
+pub fn with_input(cmd: &mut Command, input: &str) -> Result<String>{
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .traced_spawn()?;
+    if let Some(mut stdin) = child.stdin.take(){
+        stdin.write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(RouterError::command(cmd, &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// `program` with `-n <netns>` if `netns` is set, which `ip`, `tc` and
+/// `bridge` all take.
+fn in_netns(program: &str, netns: Option<&str>, args: &[&str]) -> Command {
+    let mut cmd = Command::new(program);
+    if let Some(netns) = netns{
+        cmd.arg("-n").arg(netns);
+    }
+    cmd.args(args);
+    cmd
+}
@@ -0,0 +1,293 @@
+//! Topologies built from modules: a topology file lists other topology
+//! files under `modules`, each with a name, and gets a copy of each, so a
+//! standard pod can be described once and placed several times in a
+//! fabric. Everything a module names is prefixed with the module's name,
+//! e.g. its namespace `leaf` becomes `pod1-leaf` and its link `up` becomes
+//! `pod1-up`, references within the module follow, including interfaces
+//! like `leaf_up`, which becomes `pod1-leaf_pod1-up`. A module's `shift`
+//! moves its addresses as `clone` moves those of copies, see
+//! `Topology::stamp`, so copies of the same file don't overlap.
+//!
+//! A module declares its attachment points as `ports`, names for some of
+//! its namespaces. The including topology reaches the module through
+//! them only, as `<module>.<port>` wherever it names a namespace, e.g. as
+//! link endpoint, and in interface names, e.g. `pod1.uplink_spine`.
+//! Modules may include modules of their own, named after both.
+//!
+//! Only the contents of a module are taken: namespaces, links, bridges,
+//! overlays, routes, services, groups, checks, counters, conntrack
+//! assertions and mirrors. Topology-wide settings, e.g. `ipam`, `daemon`
+//! or `sysctls`, are those of the including topology. Host interfaces
+//! exist once, a module with them can only be used once and without shift.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{failed, Result};
+use crate::topology::Topology;
+
+/// Modules included by modules, deeper is taken for a cycle.
+const MAX_DEPTH: usize = 8;
+
+/// Copy of the topology in `file` under the name `name`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ModuleSpec{
+    pub name: String,
+    /// relative to the including file
+    pub file: PathBuf,
+    /// addresses moved up by this many, IPv6 prefixes by as many /64s
+    #[serde(default)]
+    pub shift: Option<u32>,
+}
+
+/// Replaces the `modules` of `topology`, loaded from `path`, with their
+/// contents.
+pub fn expand(topology: &mut Topology, path: &Path) -> Result<()>{
+    expand_at(topology, path, 0)
+}
+
+fn expand_at(topology: &mut Topology, path: &Path, depth: usize) -> Result<()>{
+    if topology.modules.is_empty() {
+        return Ok(());
+    }
+    if depth >= MAX_DEPTH {
+        return Err(failed!("Modules of {} nested more than {} deep, do they include each other?", path.display(), MAX_DEPTH));
+    }
+    let dir = path.parent().unwrap_or(Path::new("."));
+    // `<module>.<port>` to the namespace it stands for
+    let mut ports: HashMap<String, String> = HashMap::new();
+    for m in std::mem::take(&mut topology.modules){
+        if m.name.is_empty() || m.name.contains(['.', '_', '/']) {
+            return Err(failed!("Module name {:?} of {} must be non-empty and without '.', '_' or '/'", m.name, path.display()));
+        }
+        if ports.keys().any(|p| p.split('.').next() == Some(m.name.as_str())) || topology.namespaces.iter().any(|ns| ns.name == m.name) {
+            return Err(failed!("Module {} of {} is declared twice or named like a namespace", m.name, path.display()));
+        }
+        let file = dir.join(&m.file);
+        let mut module = Topology::from_raw_file(&file)?;
+        expand_at(&mut module, &file, depth + 1)
+            .map_err(|e| failed!("Module {}: {}", m.name, e))?;
+        if let Some(shift) = m.shift{
+            module = module.stamp(1, shift)
+                .map_err(|e| failed!("Module {}: {}", m.name, e))?;
+        }
+        prefix(&mut module, &m.name);
+        for (port, ns) in &module.ports{
+            if !module.namespaces.iter().any(|n| n.name == *ns) {
+                return Err(failed!("Port {} of module {} is no namespace of it", port, m.name));
+            }
+            ports.insert(format!("{}.{}", m.name, port), ns.clone());
+        }
+        merge(topology, module);
+    }
+    let unknown = |name: &str| -> Option<String> {
+        let (module, port) = name.split_once('.')?;
+        ports.keys().any(|p| p.split('.').next() == Some(module))
+            .then(|| format!("Module {} has no port {}", module, port))
+    };
+    let mut errors = Vec::new();
+    rename(topology, &mut |name| match ports.get(name){
+        Some(ns) => Some(ns.clone()),
+        None => {
+            errors.extend(unknown(name));
+            None
+        },
+    }, &mut |_| None);
+    // ports of a module may be those of its own modules
+    for ns in topology.ports.values_mut(){
+        if let Some(n) = ports.get(ns.as_str()) {
+            *ns = n.clone();
+        }
+    }
+    match errors.first(){
+        Some(e) => Err(failed!("{}: {}", path.display(), e)),
+        None => Ok(()),
+    }
+}
+
+/// Prefixes everything `module` names with `name`.
+fn prefix(module: &mut Topology, name: &str){
+    let namespaces: Vec<String> = module.namespaces.iter().map(|n| n.name.clone())
+        .chain(module.bridges.iter().filter(|b| b.namespace.is_none()).map(|b| b.name.clone()))
+        .collect();
+    let items: Vec<String> = module.links.iter().map(|l| l.name.clone())
+        .chain(module.bridges.iter().map(|b| b.name.clone()))
+        .chain(module.vxlans.iter().map(|v| v.name.clone()))
+        .chain(module.tunnels.iter().map(|t| t.name.clone()))
+        .chain(module.wireguards.iter().map(|w| w.name.clone()))
+        .chain(module.services.iter().map(|s| s.name.clone()))
+        .chain(module.groups.iter().map(|g| g.name.clone()))
+        .chain(module.mirrors.iter().map(|m| m.name.clone()))
+        .collect();
+    let prefixed = |known: &[String], n: &str| known.iter().any(|k| k == n).then(|| format!("{}-{}", name, n));
+    rename(module, &mut |n| prefixed(&namespaces, n), &mut |n| prefixed(&items, n));
+    for ns in module.ports.values_mut(){
+        *ns = format!("{}-{}", name, ns);
+    }
+}
+
+/// Contents of `module` added to `topology`.
+fn merge(topology: &mut Topology, module: Topology){
+    topology.namespaces.extend(module.namespaces);
+    topology.links.extend(module.links);
+    topology.bridges.extend(module.bridges);
+    topology.vxlans.extend(module.vxlans);
+    topology.tunnels.extend(module.tunnels);
+    topology.wireguards.extend(module.wireguards);
+    topology.interfaces.extend(module.interfaces);
+    topology.routes.extend(module.routes);
+    topology.services.extend(module.services);
+    topology.checks.extend(module.checks);
+    topology.counters.extend(module.counters);
+    topology.conntrack.extend(module.conntrack);
+    topology.mirrors.extend(module.mirrors);
+    topology.groups.extend(module.groups);
+}
+
+/// Renames the namespaces `ns` maps, and the links, bridges, overlays,
+/// services, groups and mirrors `item` maps, wherever `topology` names
+/// them.
+fn rename(t: &mut Topology, ns: &mut dyn FnMut(&str) -> Option<String>, item: &mut dyn FnMut(&str) -> Option<String>){
+    let mut names = Names{ ns, item };
+    for n in &mut t.namespaces{
+        names.ns(&mut n.name);
+        for peer in n.bgp.iter_mut().flat_map(|b| b.neighbors.iter_mut()).map(|p| &mut p.peer){
+            names.ns(peer);
+        }
+        for vrf in &mut n.vrfs{
+            vrf.interfaces.iter_mut().for_each(|i| names.interface(i));
+        }
+        for rule in &mut n.rules{
+            rule.iif.iter_mut().chain(rule.oif.iter_mut()).for_each(|i| names.interface(i));
+        }
+        if let Some(nat) = &mut n.nat{
+            names.interface(&mut nat.out);
+        }
+        for ports in n.p4.iter_mut().map(|p| &mut p.ports).chain(n.forwarder.iter_mut().map(|f| &mut f.ports)){
+            ports.iter_mut().for_each(|i| names.interface(i));
+        }
+        if let Some(dns) = &mut n.dns{
+            dns.clients.iter_mut().for_each(|c| names.ns(c));
+        }
+    }
+    for l in &mut t.links{
+        names.item(&mut l.name);
+        l.endpoints.iter_mut().for_each(|e| names.ns(e));
+        l.endpoint_qos = std::mem::take(&mut l.endpoint_qos).into_iter().map(|(mut e, q)| { names.ns(&mut e); (e, q) }).collect();
+        l.addresses = std::mem::take(&mut l.addresses).into_iter().map(|(mut e, a)| { names.ns(&mut e); (e, a) }).collect::<BTreeMap<_, _>>();
+        l.group.iter_mut().for_each(|g| names.item(g));
+    }
+    for b in &mut t.bridges{
+        names.item(&mut b.name);
+        b.namespace.iter_mut().for_each(|n| names.ns(n));
+        b.members.iter_mut().for_each(|m| names.ns(m));
+        b.group.iter_mut().for_each(|g| names.item(g));
+    }
+    for v in &mut t.vxlans{
+        names.item(&mut v.name);
+        for e in &mut v.endpoints{
+            names.ns(&mut e.namespace);
+            names.interface(&mut e.local);
+        }
+    }
+    for tun in &mut t.tunnels{
+        names.item(&mut tun.name);
+        for e in &mut tun.endpoints{
+            names.ns(&mut e.namespace);
+            names.interface(&mut e.local);
+        }
+    }
+    for w in &mut t.wireguards{
+        names.item(&mut w.name);
+        for e in &mut w.endpoints{
+            names.ns(&mut e.namespace);
+            names.interface(&mut e.local);
+        }
+    }
+    for i in &mut t.interfaces{
+        i.namespace.iter_mut().for_each(|n| names.ns(n));
+        i.group.iter_mut().for_each(|g| names.item(g));
+    }
+    for r in &mut t.routes{
+        names.ns(&mut r.namespace);
+        r.gateways.iter_mut().for_each(|g| names.interface(g));
+        for nh in &mut r.nexthops{
+            nh.via.iter_mut().chain(nh.dev.iter_mut()).for_each(|i| names.interface(i));
+        }
+    }
+    for s in &mut t.services{
+        names.item(&mut s.name);
+        s.instances.iter_mut().for_each(|i| names.ns(i));
+    }
+    for g in &mut t.groups{
+        names.item(&mut g.name);
+    }
+    for c in &mut t.checks{
+        names.ns(&mut c.from);
+        if c.to.parse::<IpAddr>().is_err() {
+            names.ns(&mut c.to);
+        }
+        c.path.iter_mut().chain(c.return_path.iter_mut()).for_each(|n| names.ns(n));
+    }
+    for c in &mut t.counters{
+        names.ns(&mut c.namespace);
+        c.interfaces.iter_mut().for_each(|i| names.interface(i));
+    }
+    for c in &mut t.conntrack{
+        names.ns(&mut c.namespace);
+        for n in [&mut c.src, &mut c.dst, &mut c.translated].into_iter().flatten(){
+            if n.parse::<IpAddr>().is_err() {
+                names.ns(n);
+            }
+        }
+    }
+    for m in &mut t.mirrors{
+        names.item(&mut m.name);
+        names.ns(&mut m.namespace);
+        m.monitor.iter_mut().for_each(|n| names.ns(n));
+        names.interface(&mut m.interface);
+        m.to.iter_mut().for_each(|i| names.interface(i));
+    }
+}
+
+struct Names<'a>{
+    ns: &'a mut dyn FnMut(&str) -> Option<String>,
+    item: &'a mut dyn FnMut(&str) -> Option<String>,
+}
+
+impl Names<'_>{
+    fn ns(&mut self, name: &mut String){
+        if let Some(n) = (self.ns)(name) {
+            *name = n;
+        }
+    }
+
+    fn item(&mut self, name: &mut String){
+        if let Some(n) = (self.item)(name) {
+            *name = n;
+        }
+    }
+
+    /// Renames an interface given as `<namespace>_<link>`, or by the name
+    /// of a link or bridge alone. Addresses and other names are left as
+    /// they are.
+    fn interface(&mut self, name: &mut String){
+        if name.parse::<IpAddr>().is_ok() {
+            return;
+        }
+        for (n, _) in name.match_indices('_'){
+            if let Some(ns) = (self.ns)(&name[..n]) {
+                let rest = &name[n + 1..];
+                // a trailing `*` of a counter assertion matches any suffix
+                let (link, star) = rest.strip_suffix('*').map_or((rest, ""), |l| (l, "*"));
+                let link = (self.item)(link).unwrap_or_else(|| link.to_string());
+                *name = format!("{}_{}{}", ns, link, star);
+                return;
+            }
+        }
+        self.item(name);
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Instant;
+
+use crate::batch::Batch;
+use crate::bench::Phase;
+use crate::error::Result;
+use crate::hooks::Hooks;
+use crate::interface;
+use crate::ipam::Ipam;
+use crate::mirror::Mirror;
+use crate::parallel::Parallelism;
+use crate::passthrough::MovedNic;
+use crate::policy::PolicyRule;
+use crate::process::Process;
+use crate::topology::RouteSpec;
+use crate::transaction::Transaction;
+use crate::{Bridge, Interface, Link, Namespace, Rib, Route, Tunnel, Vrf, VxlanLink, WireguardLink};
+
+/// Registry of everything created for one topology, keyed by logical name.
+///
+/// The registry is shared rather than owned by the build: every table
+/// locks on its own and hands out clones, `Arc` handles for objects, so
+/// links and interfaces can be created from several threads at once and
+/// queried while the topology is live without holding a lock while they
+/// are used.
+pub struct Config{
+    pub name: String,
+    /// draw namespaces and veth pairs from the pool before creating new ones
+    pub pool: bool,
+    /// adopt objects which already exist and fix up their settings instead
+    /// of failing, see `Topology::reconcile`
+    pub reconcile: bool,
+    /// threads per phase of the build
+    pub parallelism: Parallelism,
+    /// check the host before building, see `preflight`
+    pub preflight: bool,
+    /// kernel modules the preflight checks on top of those the topology
+    /// needs
+    pub modules: Vec<String>,
+    /// allow the build to change the host outside the topology's
+    /// namespaces, see `preflight::host_changes`
+    pub host_changes: bool,
+    /// queue the setup of veth links and run it with one `ip -batch` per
+    /// namespace, see `batch`
+    pub batch: bool,
+    /// time each phase of the build took, in order, see `bench`
+    pub phases: List<Phase>,
+    pub namespaces: Registry<Arc<Namespace>>,
+    pub links: Registry<Arc<Link>>,
+    pub bridges: Registry<Arc<Bridge>>,
+    pub vxlans: Registry<Arc<VxlanLink>>,
+    pub tunnels: Registry<Arc<Tunnel>>,
+    pub wireguards: Registry<Arc<WireguardLink>>,
+    pub interfaces: Registry<Arc<Interface>>,
+    /// host NICs moved into namespaces, by interface name, see
+    /// `passthrough`
+    pub nics: Registry<MovedNic>,
+    /// logical names of the interfaces whose names were shortened to fit
+    /// the kernel's limit, by kernel name, see `interface::shorten`
+    pub aliases: Registry<String>,
+    /// VRF devices, names are unique per namespace only
+    pub vrfs: List<Arc<Vrf>>,
+    /// routes installed, with the namespace they are in
+    pub routes: List<(Arc<Namespace>, Route)>,
+    /// routes each namespace should have, by namespace name, see `Rib`
+    pub ribs: Registry<Arc<Rib>>,
+    /// routes set on the running topology outside its description, kept
+    /// by reconciling, see `rib::set_route`
+    pub set_routes: List<RouteSpec>,
+    /// policy routing rules installed, with their namespace
+    pub rules: List<(Arc<Namespace>, PolicyRule)>,
+    /// supervised programs of the namespaces, see `process`
+    pub processes: List<Arc<Process>>,
+    /// port mirrors, see `mirror`
+    pub mirrors: List<Arc<Mirror>>,
+    /// closures and scripts run at points of the topology's life, see
+    /// `hooks`
+    pub hooks: Hooks,
+    /// subnets in use by links and the pools new ones are allocated from
+    ipam: Mutex<Ipam>,
+    /// objects created by the build in progress, undone if it fails
+    transaction: Mutex<Transaction>,
+    /// commands queued while batching
+    pending: Mutex<Batch>,
+}
+
+
+impl Config{
+    pub fn new(name: String) -> Config {
+        Config{
+            name,
+            pool: false,
+            reconcile: false,
+            parallelism: Parallelism::default(),
+            preflight: true,
+            modules: Vec::new(),
+            host_changes: false,
+            batch: false,
+            phases: List::default(),
+            namespaces: Registry::default(),
+            links: Registry::default(),
+            bridges: Registry::default(),
+            vxlans: Registry::default(),
+            tunnels: Registry::default(),
+            wireguards: Registry::default(),
+            interfaces: Registry::default(),
+            nics: Registry::default(),
+            aliases: Registry::default(),
+            vrfs: List::default(),
+            routes: List::default(),
+            ribs: Registry::default(),
+            set_routes: List::default(),
+            rules: List::default(),
+            processes: List::default(),
+            mirrors: List::default(),
+            hooks: Hooks::default(),
+            ipam: Mutex::default(),
+            transaction: Mutex::default(),
+            pending: Mutex::default(),
+        }
+    }
+
+    /// Interface called `name`, by kernel or logical name.
+    pub fn interface(&self, name: &str) -> Option<Arc<Interface>> {
+        self.interfaces.get(name).or_else(|| self.interfaces.get(&interface::shorten(name)))
+    }
+
+    /// Subnets in use and the pools, locked until the guard is dropped.
+    pub fn ipam(&self) -> MutexGuard<'_, Ipam> {
+        self.ipam.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Journal of the build in progress, locked until the guard is dropped.
+    pub fn transaction(&self) -> MutexGuard<'_, Transaction> {
+        self.transaction.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether the setup of new veth links is queued, see `batch`.
+    pub(crate) fn batching(&self) -> bool {
+        self.batch && !self.reconcile && !self.pool
+    }
+
+    /// Commands queued while batching, locked until the guard is dropped.
+    pub(crate) fn pending(&self) -> MutexGuard<'_, Batch> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Runs the commands queued while batching.
+    pub fn flush(&self) -> Result<()>{
+        let batch = std::mem::take(&mut *self.pending());
+        if batch.is_empty() {
+            return Ok(());
+        }
+        batch.run(self)
+    }
+
+    /// Records the time since `since` as phase `name` and restarts it.
+    pub(crate) fn phase(&self, name: &str, since: &mut Instant){
+        self.phases.push(Phase{ name: name.to_string(), took: since.elapsed() });
+        *since = Instant::now();
+    }
+}
+
+/// Table of one kind of object by name. A thread panicking while holding
+/// the lock leaves the table as it was, so poisoning is ignored.
+pub struct Registry<V>{
+    entries: RwLock<HashMap<String, V>>,
+}
+
+impl<V> Default for Registry<V>{
+    fn default() -> Registry<V> {
+        Registry{ entries: RwLock::new(HashMap::new()) }
+    }
+}
+
+impl<V: Clone> Registry<V>{
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<String, V>> {
+        self.entries.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<String, V>> {
+        self.entries.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, name: &str) -> Option<V> {
+        self.read().get(name).cloned()
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.read().contains_key(name)
+    }
+
+    /// Registers `value` as `name`, returning what it replaced.
+    pub fn insert(&self, name: String, value: V) -> Option<V> {
+        self.write().insert(name, value)
+    }
+
+    /// Registers `value` as `name` unless the name is taken, in which case
+    /// the value already registered is returned. Check and insert are
+    /// atomic, so of two threads creating the same name only one wins.
+    pub fn try_insert(&self, name: String, value: V) -> Result<(), V> {
+        let mut entries = self.write();
+        if let Some(existing) = entries.get(&name) {
+            return Err(existing.clone());
+        }
+        entries.insert(name, value);
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) -> Option<V> {
+        self.write().remove(name)
+    }
+
+    /// Names registered at the time of the call.
+    pub fn keys(&self) -> std::vec::IntoIter<String> {
+        self.read().keys().cloned().collect::<Vec<_>>().into_iter()
+    }
+
+    /// Values registered at the time of the call.
+    pub fn values(&self) -> std::vec::IntoIter<V> {
+        self.read().values().cloned().collect::<Vec<_>>().into_iter()
+    }
+
+    /// (name, value) pairs registered at the time of the call.
+    pub fn iter(&self) -> std::vec::IntoIter<(String, V)> {
+        self.read().iter().map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>().into_iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+}
+
+/// Objects kept in the order they were added, for those without a unique
+/// name. Locks like `Registry`.
+pub struct List<V>{
+    items: RwLock<Vec<V>>,
+}
+
+impl<V> Default for List<V>{
+    fn default() -> List<V> {
+        List{ items: RwLock::new(Vec::new()) }
+    }
+}
+
+impl<V: Clone> List<V>{
+    pub fn push(&self, item: V){
+        self.items.write().unwrap_or_else(PoisonError::into_inner).push(item);
+    }
+
+    /// Items at the time of the call.
+    pub fn iter(&self) -> std::vec::IntoIter<V> {
+        self.to_vec().into_iter()
+    }
+
+    pub fn to_vec(&self) -> Vec<V> {
+        self.items.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.read().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
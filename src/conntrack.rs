@@ -0,0 +1,444 @@
+//! Connection tracking table of a namespace, for labs with NAT or a
+//! stateful firewall: the flows the kernel tracks can be listed, filtered
+//! and flushed, and a topology can assert on the flows it expects, e.g.
+//! that traffic from a host leaves a NAT router translated to its outside
+//! address, checked by `verify` after the connectivity checks made the
+//! traffic.
+//!
+//! The table is read and flushed over ctnetlink, NETLINK_NETFILTER from a
+//! thread in the namespace, so no `conntrack` binary is needed. Packet and
+//! byte counts are only there with `net.netfilter.nf_conntrack_acct` set.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{failed, Result};
+use crate::monitor::{align, attributes, messages, NetlinkSocket, NLMSG_HDRLEN};
+use crate::netns;
+use crate::state::State;
+use crate::Namespace;
+
+const NFNL_SUBSYS_CTNETLINK: u16 = 1;
+const IPCTNL_MSG_CT_GET: u16 = 1;
+const IPCTNL_MSG_CT_DELETE: u16 = 2;
+const IPCTNL_MSG_CT_NEW: u16 = 0;
+const NFGENMSG_LEN: usize = 4;
+/// Time the kernel is given to answer.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const CTA_TUPLE_ORIG: u16 = 1;
+const CTA_TUPLE_REPLY: u16 = 2;
+const CTA_STATUS: u16 = 3;
+const CTA_PROTOINFO: u16 = 4;
+const CTA_TIMEOUT: u16 = 7;
+const CTA_MARK: u16 = 8;
+const CTA_COUNTERS_ORIG: u16 = 9;
+const CTA_COUNTERS_REPLY: u16 = 10;
+const CTA_ZONE: u16 = 18;
+const CTA_TUPLE_IP: u16 = 1;
+const CTA_TUPLE_PROTO: u16 = 2;
+const CTA_PROTO_NUM: u16 = 1;
+const CTA_PROTO_SRC_PORT: u16 = 2;
+const CTA_PROTO_DST_PORT: u16 = 3;
+const CTA_PROTOINFO_TCP: u16 = 1;
+const CTA_PROTOINFO_TCP_STATE: u16 = 1;
+const CTA_COUNTERS_PACKETS: u16 = 1;
+const CTA_COUNTERS_BYTES: u16 = 2;
+/// attributes nested in others carry this flag in their type
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+const IPS_SEEN_REPLY: u32 = 1 << 1;
+const IPS_ASSURED: u32 = 1 << 2;
+const IPS_SRC_NAT: u32 = 1 << 4;
+const IPS_DST_NAT: u32 = 1 << 5;
+
+const TCP_STATES: [&str; 10] = ["NONE", "SYN_SENT", "SYN_RECV", "ESTABLISHED", "FIN_WAIT", "CLOSE_WAIT", "LAST_ACK", "TIME_WAIT", "CLOSE", "SYN_SENT2"];
+
+/// Addresses and ports of one direction of a flow, ports are ICMP ids for
+/// ICMP.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Tuple{
+    pub src: Option<IpAddr>,
+    pub dst: Option<IpAddr>,
+    pub sport: Option<u16>,
+    pub dport: Option<u16>,
+}
+
+impl fmt::Display for Tuple{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addr = |a: Option<IpAddr>| a.map_or("?".to_string(), |a| a.to_string());
+        write!(f, "src={} dst={}", addr(self.src), addr(self.dst))?;
+        if let (Some(sport), Some(dport)) = (self.sport, self.dport) {
+            write!(f, " sport={} dport={}", sport, dport)?;
+        }
+        Ok(())
+    }
+}
+
+/// Entry of the connection tracking table.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Flow{
+    /// tcp, udp, icmp, icmpv6 or the protocol number
+    pub protocol: String,
+    pub original: Tuple,
+    /// what answers look like, differs from `original` reversed where
+    /// NAT translated the flow
+    pub reply: Tuple,
+    /// TCP state, e.g. ESTABLISHED
+    pub state: Option<String>,
+    /// seconds until the entry expires
+    pub timeout: Option<u32>,
+    pub mark: Option<u32>,
+    pub zone: Option<u16>,
+    /// answers were seen
+    pub seen_reply: bool,
+    /// kept when the table is full
+    pub assured: bool,
+    /// source or destination translated
+    pub snat: bool,
+    pub dnat: bool,
+    /// (packets, bytes) in the original and reply direction, with
+    /// accounting enabled
+    pub counters: Option<[(u64, u64); 2]>,
+}
+
+impl Flow{
+    /// Address the source of the flow is translated to, None without SNAT.
+    pub fn translated_source(&self) -> Option<IpAddr> {
+        self.snat.then_some(self.reply.dst).flatten()
+    }
+}
+
+impl fmt::Display for Flow{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<6}", self.protocol)?;
+        if let Some(timeout) = self.timeout{
+            write!(f, " {:>6}", timeout)?;
+        }
+        if let Some(state) = &self.state{
+            write!(f, " {}", state)?;
+        }
+        write!(f, " {}", self.original)?;
+        if !self.seen_reply {
+            write!(f, " [UNREPLIED]")?;
+        }
+        write!(f, " {}", self.reply)?;
+        for (set, flag) in [(self.assured, "ASSURED"), (self.snat, "SNAT"), (self.dnat, "DNAT")]{
+            if set {
+                write!(f, " [{}]", flag)?;
+            }
+        }
+        if let Some(mark) = self.mark.filter(|m| *m != 0) {
+            write!(f, " mark={}", mark)?;
+        }
+        if let Some([(op, ob), (rp, rb)]) = self.counters{
+            write!(f, " packets={}/{} bytes={}/{}", op, rp, ob, rb)?;
+        }
+        Ok(())
+    }
+}
+
+/// Flows of interest, every field set has to match. Addresses match
+/// either direction's tuple as the flow was sent, `original`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FlowFilter{
+    #[serde(default)]
+    pub protocol: Option<String>,
+    #[serde(default)]
+    pub src: Option<IpAddr>,
+    #[serde(default)]
+    pub dst: Option<IpAddr>,
+    #[serde(default)]
+    pub dport: Option<u16>,
+    /// TCP state, e.g. ESTABLISHED
+    #[serde(default)]
+    pub state: Option<String>,
+}
+
+impl FlowFilter{
+    pub fn matches(&self, flow: &Flow) -> bool {
+        self.protocol.as_ref().is_none_or(|p| *p == flow.protocol)
+            && self.src.is_none_or(|a| flow.original.src == Some(a))
+            && self.dst.is_none_or(|a| flow.original.dst == Some(a))
+            && self.dport.is_none_or(|p| flow.original.dport == Some(p))
+            && self.state.as_ref().is_none_or(|s| flow.state.as_ref().is_some_and(|st| st.eq_ignore_ascii_case(s)))
+    }
+}
+
+/// Flows tracked in `netns`.
+pub fn list(netns: &str) -> Result<Vec<Flow>>{
+    netns::run_in(netns, || {
+        let socket = NetlinkSocket::open_protocol(libc::NETLINK_NETFILTER, 0)?;
+        socket.send(&request(IPCTNL_MSG_CT_GET, libc::NLM_F_DUMP as u16))?;
+        let mut flows = Vec::new();
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            let Some(buf) = socket.recv().map_err(|e| failed!("Failed to dump connection tracking table: {}", e))? else {
+                continue;
+            };
+            for (kind, _, payload) in messages(&buf){
+                match kind as libc::c_int{
+                    libc::NLMSG_DONE => return Ok(flows),
+                    libc::NLMSG_ERROR => return Err(failed!("Failed to dump connection tracking table: {}", error(payload))),
+                    _ if kind == (NFNL_SUBSYS_CTNETLINK << 8 | IPCTNL_MSG_CT_NEW) => flows.extend(parse(payload)),
+                    _ => {},
+                }
+            }
+        }
+        Err(failed!("No answer to the dump of the connection tracking table"))
+    })
+    .map_err(|e| failed!("{}: {}", netns, e))
+}
+
+/// Removes every flow tracked in `netns`, so the next packets of
+/// established connections are looked at as new ones, e.g. by a changed
+/// NAT or firewall.
+pub fn flush(netns: &str) -> Result<()>{
+    netns::run_in(netns, || {
+        let socket = NetlinkSocket::open_protocol(libc::NETLINK_NETFILTER, 0)?;
+        socket.send(&request(IPCTNL_MSG_CT_DELETE, libc::NLM_F_ACK as u16))?;
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            let Some(buf) = socket.recv().map_err(|e| failed!("Failed to flush connection tracking table: {}", e))? else {
+                continue;
+            };
+            for (kind, _, payload) in messages(&buf){
+                if kind as libc::c_int == libc::NLMSG_ERROR {
+                    return match error(payload){
+                        e if e.raw_os_error() == Some(0) => Ok(()),
+                        e => Err(failed!("Failed to flush connection tracking table: {}", e)),
+                    };
+                }
+            }
+        }
+        Err(failed!("No answer to the flush of the connection tracking table"))
+    })
+    .map_err(|e| failed!("{}: {}", netns, e))
+}
+
+/// ctnetlink request without attributes for both address families.
+fn request(kind: u16, flags: u16) -> Vec<u8> {
+    let mut request = vec![0u8; NLMSG_HDRLEN + NFGENMSG_LEN];
+    request[0..4].copy_from_slice(&((NLMSG_HDRLEN + NFGENMSG_LEN) as u32).to_ne_bytes());
+    request[4..6].copy_from_slice(&(NFNL_SUBSYS_CTNETLINK << 8 | kind).to_ne_bytes());
+    request[6..8].copy_from_slice(&(libc::NLM_F_REQUEST as u16 | flags).to_ne_bytes());
+    request[8..12].copy_from_slice(&1u32.to_ne_bytes());
+    // nfgenmsg: AF_UNSPEC, NFNETLINK_V0, resource id 0
+    request
+}
+
+fn error(payload: &[u8]) -> std::io::Error {
+    let errno = payload.get(0..4).map(|b| i32::from_ne_bytes([b[0], b[1], b[2], b[3]])).unwrap_or(0);
+    std::io::Error::from_raw_os_error(-errno)
+}
+
+fn parse(payload: &[u8]) -> Option<Flow> {
+    let mut flow = Flow::default();
+    let mut protocol = 0;
+    for (kind, data) in attributes(payload.get(align(NFGENMSG_LEN)..)?){
+        match kind & NLA_TYPE_MASK{
+            CTA_TUPLE_ORIG => (flow.original, protocol) = tuple(data),
+            CTA_TUPLE_REPLY => flow.reply = tuple(data).0,
+            CTA_STATUS => {
+                let status = be32(data);
+                flow.seen_reply = status & IPS_SEEN_REPLY != 0;
+                flow.assured = status & IPS_ASSURED != 0;
+                flow.snat = status & IPS_SRC_NAT != 0;
+                flow.dnat = status & IPS_DST_NAT != 0;
+            },
+            CTA_PROTOINFO => {
+                flow.state = nested(data, CTA_PROTOINFO_TCP)
+                    .and_then(|tcp| nested(tcp, CTA_PROTOINFO_TCP_STATE))
+                    .and_then(|s| s.first())
+                    .map(|s| TCP_STATES.get(*s as usize).map_or(s.to_string(), |s| s.to_string()));
+            },
+            CTA_TIMEOUT => flow.timeout = Some(be32(data)),
+            CTA_MARK => flow.mark = Some(be32(data)),
+            CTA_ZONE => flow.zone = <[u8; 2]>::try_from(data).ok().map(u16::from_be_bytes),
+            CTA_COUNTERS_ORIG | CTA_COUNTERS_REPLY => {
+                let n = (kind & NLA_TYPE_MASK == CTA_COUNTERS_REPLY) as usize;
+                let counters = flow.counters.get_or_insert_default();
+                counters[n] = (nested(data, CTA_COUNTERS_PACKETS).map_or(0, be64), nested(data, CTA_COUNTERS_BYTES).map_or(0, be64));
+            },
+            _ => {},
+        }
+    }
+    flow.protocol = match protocol as libc::c_int{
+        libc::IPPROTO_TCP => "tcp".to_string(),
+        libc::IPPROTO_UDP => "udp".to_string(),
+        libc::IPPROTO_ICMP => "icmp".to_string(),
+        libc::IPPROTO_ICMPV6 => "icmpv6".to_string(),
+        libc::IPPROTO_SCTP => "sctp".to_string(),
+        n => n.to_string(),
+    };
+    Some(flow)
+}
+
+/// Tuple and protocol number of a CTA_TUPLE_* attribute.
+fn tuple(data: &[u8]) -> (Tuple, u8) {
+    let mut t = Tuple::default();
+    let mut protocol = 0;
+    for (kind, data) in attributes(data){
+        match kind & NLA_TYPE_MASK{
+            CTA_TUPLE_IP => {
+                for (kind, a) in attributes(data){
+                    let addr = match a.len(){
+                        4 => <[u8; 4]>::try_from(a).ok().map(IpAddr::from),
+                        16 => <[u8; 16]>::try_from(a).ok().map(IpAddr::from),
+                        _ => None,
+                    };
+                    // V4 and V6 source are 1 and 3, destinations 2 and 4
+                    match kind & NLA_TYPE_MASK{
+                        1 | 3 => t.src = addr,
+                        2 | 4 => t.dst = addr,
+                        _ => {},
+                    }
+                }
+            },
+            CTA_TUPLE_PROTO => {
+                for (kind, p) in attributes(data){
+                    let port = <[u8; 2]>::try_from(p).ok().map(u16::from_be_bytes);
+                    match kind & NLA_TYPE_MASK{
+                        CTA_PROTO_NUM => protocol = p.first().copied().unwrap_or(0),
+                        CTA_PROTO_SRC_PORT => t.sport = port,
+                        CTA_PROTO_DST_PORT => t.dport = port,
+                        _ => {},
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+    (t, protocol)
+}
+
+fn nested(data: &[u8], kind: u16) -> Option<&[u8]> {
+    attributes(data).into_iter().find(|(k, _)| k & NLA_TYPE_MASK == kind).map(|(_, d)| d)
+}
+
+fn be32(data: &[u8]) -> u32 {
+    <[u8; 4]>::try_from(data).map(u32::from_be_bytes).unwrap_or(0)
+}
+
+fn be64(data: &[u8]) -> u64 {
+    <[u8; 8]>::try_from(data).map(u64::from_be_bytes).unwrap_or(0)
+}
+
+/// Flows a namespace of a topology is expected to track, checked by
+/// `verify`. `src` and `dst` are addresses or namespaces, which stand for
+/// any of their addresses.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FlowAssertion{
+    pub namespace: String,
+    #[serde(default)]
+    pub protocol: Option<String>,
+    #[serde(default)]
+    pub src: Option<String>,
+    #[serde(default)]
+    pub dst: Option<String>,
+    #[serde(default)]
+    pub dport: Option<u16>,
+    #[serde(default)]
+    pub state: Option<String>,
+    /// address or namespace the source has to be translated to by SNAT
+    #[serde(default)]
+    pub translated: Option<String>,
+    /// least number of matching flows, 1 if not set, 0 with `max` 0
+    #[serde(default)]
+    pub min: Option<usize>,
+    /// most number of matching flows, e.g. 0 for traffic a firewall
+    /// drops
+    #[serde(default)]
+    pub max: Option<usize>,
+}
+
+impl FlowAssertion{
+    fn least(&self) -> usize {
+        self.min.unwrap_or(self.max.map_or(1, |max| max.min(1)))
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FlowResult{
+    pub assertion: FlowAssertion,
+    /// flows matching the assertion
+    pub flows: Vec<Flow>,
+    pub passed: bool,
+    pub error: Option<String>,
+}
+
+impl fmt::Display for FlowResult{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let a = &self.assertion;
+        write!(f, "{} conntrack {}", if self.passed { "PASS" } else { "FAIL" }, a.namespace)?;
+        for (key, value) in [("protocol", &a.protocol), ("src", &a.src), ("dst", &a.dst), ("state", &a.state), ("translated", &a.translated)]{
+            if let Some(value) = value{
+                write!(f, " {}={}", key, value)?;
+            }
+        }
+        if let Some(dport) = a.dport{
+            write!(f, " dport={}", dport)?;
+        }
+        match (&self.error, a.max){
+            (Some(e), _) => write!(f, ": {}", e),
+            (None, Some(max)) if max == a.least() => write!(f, ": {} flows, expected {}", self.flows.len(), max),
+            (None, Some(max)) => write!(f, ": {} flows, expected {} to {}", self.flows.len(), a.least(), max),
+            (None, None) => write!(f, ": {} flows, expected {} at least", self.flows.len(), a.least()),
+        }
+    }
+}
+
+/// Checks `assertions` against the tables of the namespaces of `state`.
+pub fn assert_flows(state: &State, assertions: &[FlowAssertion]) -> Vec<FlowResult> {
+    assertions.iter().map(|a| {
+        let mut result = FlowResult{ assertion: a.clone(), flows: Vec::new(), passed: false, error: None };
+        match matching(state, a){
+            Ok(flows) => {
+                result.passed = flows.len() >= a.least() && a.max.is_none_or(|max| flows.len() <= max);
+                result.flows = flows;
+            },
+            Err(e) => result.error = Some(e.to_string()),
+        }
+        result
+    }).collect()
+}
+
+fn matching(state: &State, a: &FlowAssertion) -> Result<Vec<Flow>>{
+    let netns = Namespace::netns_name(&state.name, &a.namespace);
+    if !state.namespaces.iter().any(|n| n.netns == netns) {
+        return Err(failed!("Namespace {} not found in {}", a.namespace, state.name));
+    }
+    let src = a.src.as_deref().map(|s| addresses(state, s)).transpose()?;
+    let dst = a.dst.as_deref().map(|s| addresses(state, s)).transpose()?;
+    let translated = a.translated.as_deref().map(|s| addresses(state, s)).transpose()?;
+    let filter = FlowFilter{ protocol: a.protocol.clone(), dport: a.dport, state: a.state.clone(), ..Default::default() };
+    Ok(list(&netns)?.into_iter()
+        .filter(|f| filter.matches(f))
+        .filter(|f| src.as_ref().is_none_or(|s| f.original.src.is_some_and(|a| s.contains(&a))))
+        .filter(|f| dst.as_ref().is_none_or(|d| f.original.dst.is_some_and(|a| d.contains(&a))))
+        .filter(|f| translated.as_ref().is_none_or(|t| f.translated_source().is_some_and(|a| t.contains(&a))))
+        .collect())
+}
+
+/// `name` if it is an address, else the addresses of the namespace it
+/// names.
+fn addresses(state: &State, name: &str) -> Result<Vec<IpAddr>>{
+    if let Ok(address) = name.parse() {
+        return Ok(vec![address]);
+    }
+    let netns = Namespace::netns_name(&state.name, name);
+    let addresses: Vec<IpAddr> = state.interfaces.iter()
+        .filter(|i| i.netns.as_deref() == Some(netns.as_str()))
+        .flat_map(|i| [&i.ip, &i.ip6])
+        .flatten()
+        .filter_map(|ip| ip.split('/').next()?.parse().ok())
+        .collect();
+    if addresses.is_empty() {
+        return Err(failed!("{} is neither an address nor a namespace of {} with addresses", name, state.name));
+    }
+    Ok(addresses)
+}
+
@@ -0,0 +1,84 @@
+//! Container namespaces: a namespace of the topology may be the network
+//! namespace of a running Docker or Podman container, or of any process,
+//! instead of one created for it, so routers are wired straight to
+//! application containers. The container's namespace is attached under the
+//! usual name `<topology>-<namespace>` with `ip netns attach`, so links,
+//! addresses and routes go into it like into any other namespace.
+//!
+//! Attached namespaces keep their own sysctls and run no routing daemon.
+//! Destroying the topology only detaches them; the veths into a container
+//! go with their peers, devices created inside it with the container.
+
+use std::fmt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::{failed, Result};
+use crate::trace::Traced;
+
+/// Runtime asked for the pid of a container.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerRuntime{
+    #[default]
+    Docker,
+    Podman,
+}
+
+impl fmt::Display for ContainerRuntime{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            ContainerRuntime::Docker => write!(f, "docker"),
+            ContainerRuntime::Podman => write!(f, "podman"),
+        }
+    }
+}
+
+impl ContainerRuntime{
+    /// Pid of the running container `name`, its name or id.
+    pub fn pid(&self, name: &str) -> Result<u32>{
+        let output = Command::new(self.to_string())
+            .args(["inspect", "-f", "{{.State.Pid}}", name])
+            .traced_output()
+            .map_err(|e| failed!("Failed to run {}: {}", self, e))?;
+        if !output.status.success() {
+            return Err(failed!("Failed to inspect container {}: {}", name, String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        match stdout.trim().parse(){
+            Ok(0) => Err(failed!("Container {} is not running", name)),
+            Ok(pid) => Ok(pid),
+            Err(e) => Err(failed!("Invalid pid {} of container {}: {}", stdout.trim(), name, e)),
+        }
+    }
+
+    /// Shell command printing the pid of `name`, for scripts.
+    pub fn pid_command(&self, name: &str) -> String {
+        format!("$({} inspect -f '{{{{.State.Pid}}}}' {})", self, name)
+    }
+}
+
+/// Makes the network namespace of `pid` available as `netns`.
+pub(crate) fn attach(netns: &str, pid: u32) -> Result<()>{
+    if !Path::new("/proc").join(pid.to_string()).exists() {
+        return Err(failed!("Process {} not found", pid));
+    }
+    cmd::ip(None, &["netns", "attach", netns, pid.to_string().as_str()])
+        .map_err(|e| e.context(format!("Failed to attach the namespace of process {}", pid)))?;
+    Ok(())
+}
+
+/// Whether `netns` is the network namespace of `pid`, false once the
+/// process was restarted into a new one.
+pub(crate) fn attached(netns: &str, pid: u32) -> bool {
+    let ns = std::fs::metadata(Path::new("/run/netns").join(netns));
+    let own = std::fs::metadata(format!("/proc/{}/ns/net", pid));
+    match (ns, own){
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
@@ -0,0 +1,164 @@
+//! Conversion of topologies to containerlab and Mininet, so labs move
+//! between the tools. Both are derived from the iproute2 script of
+//! `export`: every namespace becomes a node, the veth pairs between them
+//! the links and what the script runs in a namespace the commands of its
+//! node. Commands run on the host, fed a here-document or left running in
+//! the background can't be expressed and are listed as left out.
+//!
+//! Containerlab nodes are `linux` containers of `IMAGE`, their commands
+//! run as `exec`. See `import::containerlab` for the reverse direction.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::export;
+use crate::topology::Topology;
+
+/// Image of the exported nodes, it needs iproute2, tc and sysctl.
+pub const IMAGE: &str = "nicolaka/netshoot:latest";
+
+/// Containerlab topology file.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Lab{
+    pub name: String,
+    pub topology: LabTopology,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LabTopology{
+    #[serde(default)]
+    pub nodes: BTreeMap<String, LabNode>,
+    #[serde(default)]
+    pub links: Vec<LabLink>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LabNode{
+    #[serde(default)]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// commands run in the node once its links exist
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exec: Vec<String>,
+}
+
+/// Two `<node>:<interface>` ends.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LabLink{
+    pub endpoints: Vec<String>,
+}
+
+/// The iproute2 script of a topology split up by namespace.
+#[derive(Debug, Default)]
+struct Nodes{
+    /// node -> commands run in it
+    commands: BTreeMap<String, Vec<String>>,
+    /// veth pairs as (node, interface) ends
+    links: Vec<[(String, String); 2]>,
+    left_out: Vec<String>,
+}
+
+impl Nodes{
+    fn split(topology: &Topology) -> Result<Nodes>{
+        let script = export::iproute2(topology)?;
+        let prefix = format!("{}-", topology.name);
+        let node = |netns: &str| netns.strip_prefix(prefix.as_str()).unwrap_or(netns).to_string();
+        let mut nodes = Nodes::default();
+        let mut heredoc = false;
+        for line in script.lines(){
+            if heredoc {
+                heredoc = line != "EOF";
+                continue;
+            }
+            if line.is_empty() || line.starts_with('#') || line == "set -e" {
+                continue;
+            }
+            if line.contains("<<'EOF'") || line.ends_with(" &") {
+                heredoc = line.contains("<<'EOF'");
+                nodes.left_out.push(line.to_string());
+                continue;
+            }
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            match tokens.as_slice(){
+                ["ip", "netns", "add", netns] | ["ip", "netns", "attach", netns, _] => {
+                    nodes.commands.entry(node(netns)).or_default();
+                },
+                ["ip", "link", "add", "name", a, "netns", n1, "type", "veth", "peer", "name", b, "netns", n2] => {
+                    nodes.links.push([(node(n1), a.to_string()), (node(n2), b.to_string())]);
+                },
+                ["ip", "-n", netns, rest @ ..] => nodes.commands.entry(node(netns)).or_default().push(format!("ip {}", rest.join(" "))),
+                ["bridge", "-n", netns, rest @ ..] => nodes.commands.entry(node(netns)).or_default().push(format!("bridge {}", rest.join(" "))),
+                ["ip", "netns", "exec", netns, rest @ ..] => nodes.commands.entry(node(netns)).or_default().push(rest.join(" ")),
+                _ => nodes.left_out.push(line.to_string()),
+            }
+        }
+        Ok(nodes)
+    }
+}
+
+/// `topology` as containerlab topology file, with what was left out as
+/// comments on top.
+pub fn containerlab(topology: &Topology) -> Result<String>{
+    let nodes = Nodes::split(topology)?;
+    let mut lab = Lab{
+        name: topology.name.clone(),
+        ..Default::default()
+    };
+    for (name, commands) in nodes.commands{
+        lab.topology.nodes.insert(name, LabNode{ kind: "linux".to_string(), image: Some(IMAGE.to_string()), exec: commands });
+    }
+    for [(n1, i1), (n2, i2)] in nodes.links{
+        lab.topology.links.push(LabLink{ endpoints: vec![format!("{}:{}", n1, i1), format!("{}:{}", n2, i2)] });
+    }
+    let mut s = String::new();
+    for l in &nodes.left_out{
+        writeln!(s, "# left out: {}", l)?;
+    }
+    s.push_str(&serde_yaml::to_string(&lab)?);
+    Ok(s)
+}
+
+/// `topology` as Python script building it with Mininet and opening its
+/// CLI, with what was left out as comments on top.
+pub fn mininet(topology: &Topology) -> Result<String>{
+    let nodes = Nodes::split(topology)?;
+    let mut s = String::new();
+    writeln!(s, "#!/usr/bin/env python3")?;
+    writeln!(s, "# topology {}", topology.name)?;
+    for l in &nodes.left_out{
+        writeln!(s, "# left out: {}", l)?;
+    }
+    writeln!(s, "from mininet.net import Mininet")?;
+    writeln!(s, "from mininet.cli import CLI")?;
+    writeln!(s, "\nnet = Mininet(topo=None, build=False, controller=None)")?;
+    writeln!(s, "nodes = {{}}")?;
+    for name in nodes.commands.keys(){
+        writeln!(s, "nodes[{0}] = net.addHost({0}, ip=None)", py(name))?;
+    }
+    for [(n1, i1), (n2, i2)] in &nodes.links{
+        writeln!(s, "net.addLink(nodes[{}], nodes[{}], intfName1={}, intfName2={})", py(n1), py(n2), py(i1), py(i2))?;
+    }
+    writeln!(s, "net.build()")?;
+    // Mininet numbers the first interface of every host, the topology's
+    // addresses replace that
+    writeln!(s, "for node in nodes.values():")?;
+    writeln!(s, "    for intf in node.intfList():")?;
+    writeln!(s, "        node.cmd('ip addr flush dev %s' % intf)")?;
+    for (name, commands) in &nodes.commands{
+        for c in commands{
+            writeln!(s, "nodes[{}].cmd({})", py(name), py(c))?;
+        }
+    }
+    writeln!(s, "CLI(net)")?;
+    writeln!(s, "net.stop()")?;
+    Ok(s)
+}
+
+/// Python string literal of `s`.
+fn py(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
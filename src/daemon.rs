@@ -0,0 +1,604 @@
+//! Routing daemons inside the namespaces of a topology, so routes are
+//! learned over OSPF instead of installed statically. Every namespace with
+//! addressed interfaces gets its own FRR or BIRD instance, speaking OSPFv2
+//! and, where interfaces carry IPv6, OSPFv3 on all of them in the area of
+//! their link.
+//! Namespaces with a `bgp` section additionally run BGP with the declared
+//! neighbors. Config, pid files and control sockets live in
+//! `/run/router-rs/<topology>/<namespace>/`.
+
+use std::fmt;
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::environment;
+use crate::error::{failed, Result, RouterError};
+use crate::logs;
+use crate::netns;
+use crate::state::STATE_DIR;
+use crate::trace::Traced;
+use crate::Namespace;
+
+/// Where Debian and Fedora install the FRR daemons.
+pub const FRR_DIR: &str = "/usr/lib/frr";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DaemonKind{
+    Frr,
+    Bird,
+}
+
+impl fmt::Display for DaemonKind{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            DaemonKind::Frr => write!(f, "frr"),
+            DaemonKind::Bird => write!(f, "bird"),
+        }
+    }
+}
+
+impl FromStr for DaemonKind{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<DaemonKind>{
+        match s{
+            "frr" => Ok(DaemonKind::Frr),
+            "bird" => Ok(DaemonKind::Bird),
+            _ => Err(RouterError::Invalid(format!("Invalid routing daemon {}, expected frr or bird", s))),
+        }
+    }
+}
+
+/// Interface OSPF runs on, `cost` is the link cost of `paths::end_cost`.
+/// Passive interfaces are advertised but send no hellos.
+#[derive(Clone, Debug)]
+pub struct OspfInterface{
+    pub name: String,
+    pub cost: u64,
+    pub area: u32,
+    pub passive: bool,
+    pub v4: bool,
+    pub v6: bool,
+}
+
+/// BGP peer, `name` is the namespace it lives in.
+#[derive(Clone, Debug)]
+pub struct BgpNeighbor{
+    pub name: String,
+    pub address: IpAddr,
+    pub asn: u32,
+}
+
+/// BGP speaker of one namespace. `networks` are announced while the
+/// namespace has a route to them, BGP routes learned from one neighbor are
+/// passed on to the others as usual.
+#[derive(Clone, Debug)]
+pub struct BgpConfig{
+    pub asn: u32,
+    pub neighbors: Vec<BgpNeighbor>,
+    pub networks: Vec<ipnet::IpNet>,
+    /// route flap dampening with the daemon's default parameters
+    pub dampening: bool,
+}
+
+impl BgpConfig{
+    fn ibgp(&self, neighbor: &BgpNeighbor) -> bool {
+        neighbor.asn == self.asn
+    }
+}
+
+/// What the daemon of one namespace is configured from.
+#[derive(Clone, Debug)]
+pub struct DaemonConfig{
+    pub router_id: Ipv4Addr,
+    pub interfaces: Vec<OspfInterface>,
+    pub bgp: Option<BgpConfig>,
+}
+
+impl DaemonConfig{
+    fn v6(&self) -> bool {
+        self.interfaces.iter().any(|i| i.v6)
+            || self.bgp.as_ref().is_some_and(|b| b.neighbors.iter().any(|n| n.address.is_ipv6()))
+    }
+}
+
+/// State of one BGP session as reported by the daemon.
+#[derive(Clone, Debug)]
+pub struct BgpSession{
+    pub address: String,
+    /// namespace of the neighbor, from the session's description
+    pub peer: String,
+    pub state: String,
+}
+
+impl BgpSession{
+    pub fn established(&self) -> bool {
+        self.state == "Established"
+    }
+}
+
+/// Daemon instance of one namespace. Its processes are those with a config
+/// file in `dir`, so a daemon found on disk can be supervised and stopped
+/// without the topology it was configured from. Each process logs to its
+/// node log, see `logs`.
+pub struct RoutingDaemon{
+    pub kind: DaemonKind,
+    pub topology: String,
+    pub netns: String,
+    /// config, pid files and control sockets
+    pub dir: PathBuf,
+}
+
+impl RoutingDaemon{
+    pub fn new(kind: DaemonKind, topology: &str, netns: &str) -> RoutingDaemon {
+        RoutingDaemon{
+            kind,
+            topology: topology.to_string(),
+            netns: netns.to_string(),
+            dir: RoutingDaemon::dir(topology, netns),
+        }
+    }
+
+    /// Runtime directory of the daemon of `netns` in `topology`.
+    pub fn dir(topology: &str, netns: &str) -> PathBuf {
+        PathBuf::from(STATE_DIR).join(topology).join(netns)
+    }
+
+    /// Daemons started for `topology`, sorted by namespace.
+    pub fn list(topology: &str) -> Result<Vec<RoutingDaemon>>{
+        let dir = PathBuf::from(STATE_DIR).join(topology);
+        let mut daemons = Vec::new();
+        if !dir.exists() {
+            return Ok(daemons);
+        }
+        for entry in std::fs::read_dir(&dir)?{
+            let path = entry?.path();
+            let netns = path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string();
+            // other processes of the topology, e.g. Open vSwitch
+            let configs = std::fs::read_dir(&path)?.flatten().any(|f| f.path().extension().is_some_and(|e| e == "conf"));
+            if !configs {
+                continue;
+            }
+            let kind = if path.join("bird.conf").exists() { DaemonKind::Bird } else { DaemonKind::Frr };
+            daemons.push(RoutingDaemon{ kind, topology: topology.to_string(), netns, dir: path });
+        }
+        daemons.sort_by(|a, b| a.netns.cmp(&b.netns));
+        Ok(daemons)
+    }
+
+    /// Config files as (file name, content), one per process.
+    pub fn configs(&self, config: &DaemonConfig) -> Result<Vec<(String, String)>>{
+        match self.kind{
+            DaemonKind::Frr => self.frr(config),
+            DaemonKind::Bird => Ok(vec![("bird.conf".to_string(), self.bird(config)?)]),
+        }
+    }
+
+    fn frr(&self, config: &DaemonConfig) -> Result<Vec<(String, String)>>{
+        let header = |name: &str| format!("hostname {}\nlog file {}\n!\n", self.netns, logs::path(&self.topology, &self.netns, name).display());
+        let mut zebra = header("zebra");
+        let mut ospfd = header("ospfd");
+        let mut ospf6d = header("ospf6d");
+        for i in &config.interfaces{
+            writeln!(zebra, "interface {}\n!", i.name)?;
+            if i.v4 {
+                writeln!(ospfd, "interface {}\n ip ospf area {}\n ip ospf cost {}", i.name, i.area, i.cost)?;
+                writeln!(ospfd, "{}!", if i.passive { " ip ospf passive\n" } else { "" })?;
+            }
+            if i.v6 {
+                writeln!(ospf6d, "interface {}\n ipv6 ospf6 area {}\n ipv6 ospf6 cost {}", i.name, i.area, i.cost)?;
+                writeln!(ospf6d, "{}!", if i.passive { " ipv6 ospf6 passive\n" } else { "" })?;
+            }
+        }
+        writeln!(ospfd, "router ospf\n ospf router-id {}\n!", config.router_id)?;
+        writeln!(ospf6d, "router ospf6\n ospf6 router-id {}\n!", config.router_id)?;
+        let mut configs = vec![("zebra.conf".to_string(), zebra), ("ospfd.conf".to_string(), ospfd)];
+        if config.interfaces.iter().any(|i| i.v6) {
+            configs.push(("ospf6d.conf".to_string(), ospf6d));
+        }
+        if let Some(bgp) = &config.bgp{
+            let mut bgpd = header("bgpd");
+            writeln!(bgpd, "router bgp {}\n bgp router-id {}", bgp.asn, config.router_id)?;
+            // without policies FRR neither accepts nor announces eBGP routes
+            writeln!(bgpd, " no bgp ebgp-requires-policy\n no bgp default ipv4-unicast")?;
+            for n in &bgp.neighbors{
+                writeln!(bgpd, " neighbor {} remote-as {}\n neighbor {} description {}", n.address, n.asn, n.address, n.name)?;
+            }
+            for (family, v6) in [("ipv4", false), ("ipv6", true)]{
+                let neighbors: Vec<&BgpNeighbor> = bgp.neighbors.iter().filter(|n| n.address.is_ipv6() == v6).collect();
+                if neighbors.is_empty() {
+                    continue;
+                }
+                writeln!(bgpd, " address-family {} unicast", family)?;
+                if bgp.dampening {
+                    writeln!(bgpd, "  bgp dampening")?;
+                }
+                for net in bgp.networks.iter().filter(|net| matches!(net, ipnet::IpNet::V6(_)) == v6){
+                    writeln!(bgpd, "  network {}", net)?;
+                }
+                for n in neighbors{
+                    writeln!(bgpd, "  neighbor {} activate", n.address)?;
+                    if bgp.ibgp(n) {
+                        writeln!(bgpd, "  neighbor {} next-hop-self", n.address)?;
+                    }
+                }
+                writeln!(bgpd, " exit-address-family")?;
+            }
+            writeln!(bgpd, "!")?;
+            configs.push(("bgpd.conf".to_string(), bgpd));
+        }
+        Ok(configs)
+    }
+
+    fn bird(&self, config: &DaemonConfig) -> Result<String>{
+        let mut s = String::new();
+        writeln!(s, "log \"{}\" all;", logs::path(&self.topology, &self.netns, "bird").display())?;
+        writeln!(s, "router id {};", config.router_id)?;
+        writeln!(s, "protocol device {{}}")?;
+        let export = if config.bgp.is_some() { "source = RTS_OSPF || source = RTS_BGP" } else { "source = RTS_OSPF" };
+        writeln!(s, "protocol kernel kernel4 {{ ipv4 {{ export where {}; }}; }}", export)?;
+        if config.v6() {
+            writeln!(s, "protocol kernel kernel6 {{ ipv6 {{ export where {}; }}; }}", export)?;
+        }
+        for (version, v6) in [("v2", false), ("v3", true)]{
+            let interfaces: Vec<&OspfInterface> = config.interfaces.iter().filter(|i| if v6 { i.v6 } else { i.v4 }).collect();
+            if interfaces.is_empty() {
+                continue;
+            }
+            writeln!(s, "protocol ospf {} ospf{} {{", version, if v6 { 6 } else { 4 })?;
+            writeln!(s, "  {} {{ import all; export none; }};", if v6 { "ipv6" } else { "ipv4" })?;
+            let mut areas: Vec<u32> = interfaces.iter().map(|i| i.area).collect();
+            areas.sort();
+            areas.dedup();
+            for area in areas{
+                writeln!(s, "  area {} {{", area)?;
+                for i in interfaces.iter().filter(|i| i.area == area){
+                    writeln!(s, "    interface \"{}\" {{ cost {};{} }};", i.name, i.cost, if i.passive { " stub;" } else { "" })?;
+                }
+                writeln!(s, "  }};")?;
+            }
+            writeln!(s, "}}")?;
+        }
+        if let Some(bgp) = &config.bgp{
+            if bgp.dampening {
+                return Err(failed!("BIRD has no route flap dampening, use FRR"));
+            }
+            // connected networks aren't in BIRD's table otherwise
+            writeln!(s, "protocol direct {{ ipv4; ipv6; }}")?;
+            for n in &bgp.neighbors{
+                let v6 = n.address.is_ipv6();
+                let networks: Vec<String> = bgp.networks.iter()
+                    .filter(|net| matches!(net, ipnet::IpNet::V6(_)) == v6)
+                    .map(|net| net.to_string())
+                    .collect();
+                let mut export = "source = RTS_BGP".to_string();
+                if !networks.is_empty() {
+                    write!(export, " || net ~ [ {} ]", networks.join(", "))?;
+                }
+                writeln!(s, "protocol bgp {} {{", bird_protocol(n))?;
+                writeln!(s, "  description \"{}\";\n  local as {};\n  neighbor {} as {};", n.name, bgp.asn, n.address, n.asn)?;
+                writeln!(s, "  {} {{ import all; export where {};{} }};", if v6 { "ipv6" } else { "ipv4" }, export,
+                    if bgp.ibgp(n) { " next hop self;" } else { "" })?;
+                writeln!(s, "}}")?;
+            }
+        }
+        Ok(s)
+    }
+
+    /// Processes, named like their config file.
+    fn processes(&self) -> Result<Vec<String>>{
+        let mut names = Vec::new();
+        if !self.dir.exists() {
+            return Ok(names);
+        }
+        for entry in std::fs::read_dir(&self.dir)?{
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some("conf") {
+                if let Some(name) = path.file_stem().and_then(|n| n.to_str()){
+                    names.push(name.to_string());
+                }
+            }
+        }
+        // zebra has to be up before the protocol daemons connect to it
+        names.sort_by_key(|n| (n != "zebra", n.clone()));
+        Ok(names)
+    }
+
+    fn command(&self, name: &str) -> Vec<String> {
+        let path = |f: &str| self.dir.join(f).to_string_lossy().to_string();
+        match self.kind{
+            DaemonKind::Bird => vec![
+                "bird".to_string(),
+                "-c".to_string(), path("bird.conf"),
+                "-s".to_string(), path("bird.ctl"),
+                "-P".to_string(), path("bird.pid"),
+            ],
+            DaemonKind::Frr => vec![
+                Path::new(FRR_DIR).join(name).to_string_lossy().to_string(),
+                "-d".to_string(),
+                "-u".to_string(), "root".to_string(),
+                "-g".to_string(), "root".to_string(),
+                "-f".to_string(), path(&format!("{}.conf", name)),
+                "-i".to_string(), path(&format!("{}.pid", name)),
+                "-z".to_string(), path("zserv.api"),
+                "--vty_socket".to_string(), self.dir.to_string_lossy().to_string(),
+            ],
+        }
+    }
+
+    /// Writes the configs and starts all processes. Returns once every
+    /// process wrote its pid file.
+    pub fn start(&self, config: &DaemonConfig) -> Result<()>{
+        std::fs::create_dir_all(&self.dir)?;
+        for (name, content) in self.configs(config)?{
+            std::fs::write(self.dir.join(name), content)?;
+        }
+        for name in self.processes()?{
+            self.launch(&name)?;
+        }
+        Ok(())
+    }
+
+    fn launch(&self, name: &str) -> Result<()>{
+        let pidfile = self.dir.join(format!("{}.pid", name));
+        let _ = std::fs::remove_file(&pidfile);
+        let log = logs::open(&self.topology, &self.netns, name)?;
+        let command = self.command(name);
+        let status = netns::command(&self.netns, &command[0])
+            .args(&command[1..])
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .traced_status()
+            .map_err(|e| failed!("Failed to start {} in {}: {}", name, self.netns, e))?;
+        if !status.success() {
+            return Err(failed!("Failed to start {} in {}: {}", name, self.netns,
+                logs::tail(&logs::path(&self.topology, &self.netns, name), 5)));
+        }
+        // both daemonize, the pid file shows up once the child is running
+        for _ in 0..50{
+            if pid(&pidfile).is_some() {
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Err(failed!("{} in {} did not write {}", name, self.netns, pidfile.display()))
+    }
+
+    /// True if the configs on disk match `config` and every process runs.
+    pub fn current(&self, config: &DaemonConfig) -> Result<bool>{
+        let configs = self.configs(config)?;
+        if configs.len() != self.processes()?.len() {
+            return Ok(false);
+        }
+        for (name, content) in configs{
+            if std::fs::read_to_string(self.dir.join(name)).ok().as_deref() != Some(content.as_str()) {
+                return Ok(false);
+            }
+        }
+        Ok(self.dead()?.is_empty())
+    }
+
+    /// Processes which are not running.
+    pub fn dead(&self) -> Result<Vec<String>>{
+        Ok(self.processes()?.into_iter()
+            .filter(|name| !pid(&self.dir.join(format!("{}.pid", name))).is_some_and(alive))
+            .collect())
+    }
+
+    /// Restarts processes which died and returns their names.
+    pub fn supervise(&self) -> Result<Vec<String>>{
+        let dead = self.dead()?;
+        for name in &dead{
+            self.launch(name)?;
+        }
+        Ok(dead)
+    }
+
+    pub fn stop(&self) -> Result<()>{
+        stop_dir(&self.dir)
+    }
+
+    /// BGP sessions as reported by the running daemon, empty if it isn't
+    /// configured for BGP.
+    pub fn bgp_sessions(&self) -> Result<Vec<BgpSession>>{
+        match self.kind{
+            DaemonKind::Frr => {
+                if !self.dir.join("bgpd.conf").exists() {
+                    return Ok(Vec::new());
+                }
+                let dir = self.dir.to_string_lossy().to_string();
+                let output = control("vtysh", &["--vty_socket", &dir, "-d", "bgpd", "-c", "show bgp neighbors json"])?;
+                let neighbors: serde_json::Value = serde_json::from_str(&output)?;
+                Ok(neighbors.as_object().into_iter().flatten()
+                    .map(|(address, n)| BgpSession{
+                        address: address.clone(),
+                        peer: n["nbrDesc"].as_str().unwrap_or_default().to_string(),
+                        state: n["bgpState"].as_str().unwrap_or("unknown").to_string(),
+                    })
+                    .collect())
+            },
+            DaemonKind::Bird => {
+                let ctl = self.dir.join("bird.ctl").to_string_lossy().to_string();
+                let output = control("birdc", &["-s", &ctl, "show", "protocols", "all"])?;
+                let mut sessions: Vec<BgpSession> = Vec::new();
+                let mut bgp = false;
+                for line in output.lines(){
+                    if !line.starts_with(' ') {
+                        // a protocol's first line is `<name> <proto> <table> ...`
+                        bgp = line.split_whitespace().nth(1) == Some("BGP");
+                        if bgp {
+                            sessions.push(BgpSession{ address: String::new(), peer: String::new(), state: "unknown".to_string() });
+                        }
+                        continue;
+                    }
+                    let (Some(session), true) = (sessions.last_mut(), bgp) else {
+                        continue;
+                    };
+                    match line.trim().split_once(':'){
+                        Some(("Description", peer)) => session.peer = peer.trim().to_string(),
+                        Some(("BGP state", state)) => session.state = state.trim().to_string(),
+                        Some(("Neighbor address", address)) => session.address = address.trim().to_string(),
+                        _ => {},
+                    }
+                }
+                Ok(sessions)
+            },
+        }
+    }
+}
+
+/// BGP sessions of all daemons of `topology` with the namespace they belong
+/// to. Waits up to `timeout` for all of them to be established and returns
+/// them as they are then, established or not.
+pub fn wait_bgp(topology: &str, timeout: Duration) -> Result<Vec<(String, BgpSession)>>{
+    let deadline = Instant::now() + timeout;
+    loop{
+        let sessions = RoutingDaemon::list(topology)?.iter()
+            .map(|d| Ok(d.bgp_sessions()?.into_iter().map(|s| (d.netns.clone(), s)).collect::<Vec<_>>()))
+            .collect::<Result<Vec<_>>>()
+            .map(|s| s.concat());
+        match sessions{
+            Ok(sessions) if sessions.iter().all(|(_, s)| s.established()) => return Ok(sessions),
+            // control sockets show up a moment after the pid files
+            Ok(_) | Err(_) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(500)),
+            result => return result,
+        }
+    }
+}
+
+/// BIRD protocol name of the session with `neighbor`.
+fn bird_protocol(neighbor: &BgpNeighbor) -> String {
+    let name: String = neighbor.name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    format!("bgp_{}_{}", name, if neighbor.address.is_ipv6() { "v6" } else { "v4" })
+}
+
+/// Runs a daemon's control client, which talks to it over a socket and so
+/// doesn't need the namespace.
+fn control(client: &str, args: &[&str]) -> Result<String>{
+    let output = Command::new(client).args(args).traced_output()
+        .map_err(|e| failed!("Failed to run {}: {}", client, e))?;
+    if !output.status.success() {
+        return Err(failed!("Failed to run {} {}: {}", client, args.join(" "), String::from_utf8_lossy(&output.stderr)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Routes installed by a routing daemon, FRR's zebra, staticd and isisd as
+/// well as BIRD, or learned from router advertisements rather than by us or
+/// the kernel, as listed by `ip -j route show`: those of any protocol but
+/// `boot`, which we install and `ip` leaves out, and `kernel`.
+pub fn learned(route: &serde_json::Value) -> bool {
+    !matches!(route["protocol"].as_str(), None | Some("boot") | Some("kernel"))
+}
+
+/// Stops every process with a pid file in `dir` and removes `dir`, and the
+/// topology's directory with it once that is empty.
+pub fn stop_dir(dir: &Path) -> Result<()>{
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir)?{
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("pid") {
+            continue;
+        }
+        if let Some(pid) = pid(&path){
+            terminate(pid);
+        }
+    }
+    std::fs::remove_dir_all(dir)?;
+    if let Some(parent) = dir.parent(){
+        let _ = std::fs::remove_dir(parent);
+    }
+    Ok(())
+}
+
+/// Runtime directories of the processes of `topology`, one per namespace,
+/// bridge or other owner.
+pub fn dirs(topology: &str) -> Result<Vec<PathBuf>>{
+    let dir = PathBuf::from(STATE_DIR).join(topology);
+    let mut dirs = Vec::new();
+    if !dir.exists() {
+        return Ok(dirs);
+    }
+    for entry in std::fs::read_dir(&dir)?{
+        let path = entry?.path();
+        if path.is_dir() {
+            dirs.push(path);
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// Stops the daemons of all namespaces of `topology`.
+pub fn stop_topology(topology: &str) -> Result<()>{
+    let dir = PathBuf::from(STATE_DIR).join(topology);
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(&dir)?{
+        stop_dir(&entry?.path())?;
+    }
+    // gone with the last process unless something else was left in it
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+/// Starts `args` inside `netns` in the background, for programs which don't
+/// daemonize themselves. The process logs to the node log `name` and its
+/// pid goes to `<name>.pid` in `dir`, so `stop_dir` stops it. It gets the
+/// variables of its node, see `environment`.
+pub(crate) fn spawn(topology: &str, netns: &str, dir: &Path, name: &str, args: &[String]) -> Result<()>{
+    let Some((program, args)) = args.split_first() else {
+        return Err(failed!("No command to start as {} in {}", name, netns));
+    };
+    let log = logs::open(topology, netns, name)?;
+    let mut cmd = netns::command(netns, program);
+    let namespace = netns.strip_prefix(Namespace::netns_name(topology, "").as_str()).unwrap_or(netns);
+    environment::apply(&mut cmd, topology, namespace)?;
+    let child = cmd
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .traced_spawn()
+        .map_err(|e| failed!("Failed to start {} in {}: {}", name, netns, e))?;
+    // ip netns exec execs the program, so this is its pid
+    std::fs::write(dir.join(format!("{}.pid", name)), child.id().to_string())?;
+    Ok(())
+}
+
+pub(crate) fn pid(pidfile: &Path) -> Option<i32> {
+    std::fs::read_to_string(pidfile).ok()?.trim().parse().ok()
+}
+
+/// Zombies count as dead, they are only waiting for whoever reaps them.
+pub(crate) fn alive(pid: i32) -> bool {
+    if unsafe { libc::kill(pid, 0) } != 0 {
+        return false;
+    }
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+    !stat.rsplit_once(')').is_some_and(|(_, rest)| rest.trim_start().starts_with('Z'))
+}
+
+/// SIGTERM, then SIGKILL if the process is still around after two seconds.
+fn terminate(pid: i32){
+    unsafe { libc::kill(pid, libc::SIGTERM) };
+    for _ in 0..20{
+        if !alive(pid) {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    unsafe { libc::kill(pid, libc::SIGKILL) };
+}
@@ -0,0 +1,816 @@
+//! Software router: IPv4 forwarding between interfaces of a namespace done
+//! by this crate instead of the kernel, what `tap` forwarders without a
+//! command run, see `forwarder`. The loop reaches the ports over one of
+//! two kinds of packet I/O, so the kernel of the namespace never sees the
+//! ports' packets and the loop sees them all:
+//!
+//! - `tap`: each port gets a TAP device `dp<n>`, and tc redirects whatever
+//!   arrives on the port to the TAP device and whatever is written to the
+//!   TAP device out of the port, a frame per read or write
+//! - `af_xdp`: an XDP program on each port redirects its frames to an
+//!   AF_XDP socket, read and written in batches over rings shared with the
+//!   kernel, see `xsk`, built with the `af-xdp` feature only
+//!
+//! The kernel stays the control plane: routes and addresses are read from
+//! its main table every second, so routes installed by the topology or
+//! added later are used as they are, multipath routes hashing flows over
+//! their nexthops. The loop does:
+//!
+//! - ARP: answers requests for the ports' addresses, resolves nexthops,
+//!   holding a few packets for each until it answers
+//! - forwarding by longest prefix match, decrementing the TTL
+//! - ICMP: echo replies for its own addresses, time exceeded and
+//!   unreachable errors
+//!
+//! Anything else addressed to the router, and IPv6, is dropped. A killed
+//! dataplane leaves its redirects or XDP programs behind, dropping the
+//! ports' traffic until it is restarted, one stopped and closed gives the
+//! ports back to the kernel.
+//!
+//! `Benchmark` runs the same traffic through a router of a running
+//! topology forwarded by its kernel and by the dataplane over each kind of
+//! I/O.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::Ipv4Addr;
+use std::os::fd::AsRawFd;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::cmd;
+use crate::error::{failed, Result, RouterError};
+use crate::netns;
+use crate::stats;
+use crate::traffic::{Traffic, TrafficReport};
+
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+const IFF_TAP: libc::c_short = 0x0002;
+const IFF_NO_PI: libc::c_short = 0x1000;
+
+const ETH_IPV4: u16 = 0x0800;
+const ETH_ARP: u16 = 0x0806;
+const BROADCAST: Mac = [0xff; 6];
+const ICMP: u8 = 1;
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+
+/// Packets held for a nexthop while it is being resolved.
+const PENDING: usize = 16;
+/// Time between ARP requests for the same nexthop.
+const ARP_RETRY: Duration = Duration::from_secs(1);
+/// Age after which a resolved nexthop is asked again, still used meanwhile.
+const STALE: Duration = Duration::from_secs(30);
+/// Interval routes and addresses are read again from the kernel.
+pub const REFRESH: Duration = Duration::from_secs(1);
+/// Longest a port waits for frames before looking whether to stop.
+const POLL: Duration = Duration::from_millis(100);
+/// Frames read from a port at once.
+pub const BATCH: usize = 64;
+
+pub type Mac = [u8; 6];
+
+/// How the dataplane reaches its ports.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Io{
+    #[default]
+    Tap,
+    AfXdp,
+}
+
+impl Io{
+    /// Kinds of I/O this build supports.
+    pub fn available() -> Vec<Io> {
+        let mut ios = vec![Io::Tap];
+        if cfg!(feature = "af-xdp") {
+            ios.push(Io::AfXdp);
+        }
+        ios
+    }
+
+    /// Port `n` of the dataplane, interface `name`.
+    fn open(&self, name: &str, n: usize) -> Result<Box<dyn PortIo>>{
+        match self{
+            Io::Tap => Ok(Box::new(TapPort::open(name, n)?)),
+            Io::AfXdp => af_xdp(name),
+        }
+    }
+}
+
+#[cfg(feature = "af-xdp")]
+fn af_xdp(name: &str) -> Result<Box<dyn PortIo>>{
+    Ok(Box::new(crate::xsk::XskPort::open(name)?))
+}
+
+#[cfg(not(feature = "af-xdp"))]
+fn af_xdp(_name: &str) -> Result<Box<dyn PortIo>>{
+    Err(failed!("AF_XDP needs router-rs built with the af-xdp feature"))
+}
+
+impl FromStr for Io{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<Self>{
+        match s{
+            "tap" => Ok(Io::Tap),
+            "af_xdp" => Ok(Io::AfXdp),
+            _ => Err(RouterError::Invalid(format!("Unknown I/O {}, expected tap or af_xdp", s))),
+        }
+    }
+}
+
+impl fmt::Display for Io{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Io::Tap => write!(f, "tap"),
+            Io::AfXdp => write!(f, "af_xdp"),
+        }
+    }
+}
+
+/// Packet I/O of a port.
+pub trait PortIo: Send + Sync{
+    /// Waits up to `timeout` for frames and hands up to `BATCH` of them to
+    /// `handle`, returns how many.
+    fn receive(&self, timeout: Duration, handle: &mut dyn FnMut(&[u8])) -> std::io::Result<usize>;
+    /// Queues `frame`, sent at the latest by the next `flush`.
+    fn send(&self, frame: &[u8]) -> std::io::Result<()>;
+    fn flush(&self) -> std::io::Result<()>;
+    /// Gives the port back to the kernel.
+    fn close(&self) -> Result<()>;
+}
+
+/// Where packets to a prefix go: out of port `port`, to `gateway` or, on
+/// the link, to their destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hop{
+    pub port: usize,
+    pub gateway: Option<Ipv4Addr>,
+}
+
+/// Longest prefix match table, a hash map of prefixes per prefix length.
+/// A prefix without hops rejects its packets.
+#[derive(Clone, Debug)]
+pub struct Table{
+    by_len: Vec<HashMap<u32, Vec<Hop>>>,
+}
+
+impl Default for Table{
+    fn default() -> Table {
+        Table{ by_len: vec![HashMap::new(); 33] }
+    }
+}
+
+impl Table{
+    pub fn insert(&mut self, prefix: Ipv4Addr, len: u8, hops: Vec<Hop>){
+        let len = len.min(32);
+        self.by_len[len as usize].insert(u32::from(prefix) & mask(len), hops);
+    }
+
+    /// Hops of the most specific prefix containing `dst`.
+    pub fn lookup(&self, dst: Ipv4Addr) -> Option<&[Hop]> {
+        let dst = u32::from(dst);
+        self.by_len.iter().enumerate().rev()
+            .filter(|(_, prefixes)| !prefixes.is_empty())
+            .find_map(|(len, prefixes)| prefixes.get(&(dst & mask(len as u8))))
+            .map(|hops| hops.as_slice())
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_len.iter().map(|p| p.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn mask(len: u8) -> u32 {
+    if len == 0 { 0 } else { u32::MAX << (32 - len) }
+}
+
+/// Packets handled since the start.
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+pub struct Counters{
+    pub received: u64,
+    pub forwarded: u64,
+    /// addressed to the router
+    pub local: u64,
+    pub arp: u64,
+    pub no_route: u64,
+    pub ttl_exceeded: u64,
+    /// dropped while their nexthop didn't answer ARP
+    pub unresolved: u64,
+    /// malformed, not IPv4 or not for the router's MACs
+    pub dropped: u64,
+}
+
+impl fmt::Display for Counters{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "received {} forwarded {} local {} arp {} no_route {} ttl_exceeded {} unresolved {} dropped {}",
+            self.received, self.forwarded, self.local, self.arp, self.no_route, self.ttl_exceeded, self.unresolved, self.dropped)
+    }
+}
+
+#[derive(Default)]
+struct Stats{
+    received: AtomicU64,
+    forwarded: AtomicU64,
+    local: AtomicU64,
+    arp: AtomicU64,
+    no_route: AtomicU64,
+    ttl_exceeded: AtomicU64,
+    unresolved: AtomicU64,
+    dropped: AtomicU64,
+}
+
+fn count(counter: &AtomicU64){
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+struct Port{
+    name: String,
+    io: Box<dyn PortIo>,
+    mac: Mac,
+}
+
+/// What is read from the kernel.
+#[derive(Default)]
+struct Fib{
+    table: Table,
+    /// addresses and prefix lengths by port
+    addrs: Vec<Vec<(Ipv4Addr, u8)>>,
+}
+
+#[derive(Default)]
+struct Neighbor{
+    mac: Option<Mac>,
+    learned: Option<Instant>,
+    asked: Option<Instant>,
+    pending: VecDeque<Vec<u8>>,
+}
+
+/// Forwarding loop over `ports`, interfaces of the namespace the process
+/// is in.
+pub struct Dataplane{
+    ports: Vec<Port>,
+    fib: RwLock<Fib>,
+    neighbors: Mutex<HashMap<(usize, Ipv4Addr), Neighbor>>,
+    stats: Stats,
+    stop: AtomicBool,
+}
+
+impl Dataplane{
+    /// Takes over `ports` with `io` and reads the routes.
+    pub fn open(ports: &[String], io: Io) -> Result<Arc<Dataplane>>{
+        if ports.is_empty() {
+            return Err(failed!("Dataplane needs at least one port"));
+        }
+        let mut opened: Vec<Port> = Vec::new();
+        for (n, name) in ports.iter().enumerate(){
+            let link = cmd::ip_json(None, &["-j", "link", "show", "dev", name])?;
+            let mac = link[0]["address"].as_str().and_then(parse_mac)
+                .ok_or_else(|| failed!("Port {} has no MAC address", name));
+            match mac.and_then(|mac| Ok(Port{ name: name.clone(), io: io.open(name, n)?, mac })){
+                Ok(port) => opened.push(port),
+                Err(e) => {
+                    for port in &opened{
+                        let _ = port.io.close();
+                    }
+                    return Err(failed!("Port {}: {}", name, e));
+                },
+            }
+        }
+        let dataplane = Dataplane{
+            ports: opened,
+            fib: RwLock::new(Fib::default()),
+            neighbors: Mutex::new(HashMap::new()),
+            stats: Stats::default(),
+            stop: AtomicBool::new(false),
+        };
+        if let Err(e) = dataplane.refresh() {
+            let _ = dataplane.close();
+            return Err(e);
+        }
+        Ok(Arc::new(dataplane))
+    }
+
+    /// Forwards until `stop` or until reading a port fails, reading the
+    /// routes again every `REFRESH` and handing the counters to `report`
+    /// every `interval`. Runs in the namespace it is called in.
+    pub fn run<F: FnMut(&Counters)>(self: &Arc<Self>, interval: Duration, mut report: F) -> Result<()>{
+        let mut threads = Vec::new();
+        for port in 0..self.ports.len(){
+            let dataplane = self.clone();
+            threads.push(std::thread::spawn(move || dataplane.receive(port)));
+        }
+        let mut reported = Instant::now();
+        let mut refreshed = Instant::now();
+        let result = loop{
+            std::thread::sleep(POLL);
+            if self.stop.load(Ordering::Relaxed) {
+                break Ok(());
+            }
+            if let Some(n) = threads.iter().position(|t| t.is_finished()) {
+                break threads.swap_remove(n).join()
+                    .map_err(|_| failed!("Port {} panicked", self.ports[n].name))
+                    .and_then(|r| r);
+            }
+            if refreshed.elapsed() >= REFRESH {
+                if let Err(e) = self.refresh() {
+                    break Err(e);
+                }
+                refreshed = Instant::now();
+            }
+            if reported.elapsed() >= interval {
+                report(&self.counters());
+                reported = Instant::now();
+            }
+        };
+        self.stop();
+        for thread in threads{
+            let _ = thread.join();
+        }
+        result
+    }
+
+    /// Makes `run` return.
+    pub fn stop(&self){
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// Gives the ports back to the kernel, in the namespace of the ports.
+    pub fn close(&self) -> Result<()>{
+        let errors: Vec<String> = self.ports.iter()
+            .filter_map(|p| p.io.close().err().map(|e| format!("{}: {}", p.name, e)))
+            .collect();
+        if !errors.is_empty() {
+            return Err(failed!("Failed to close ports: {}", errors.join("; ")));
+        }
+        Ok(())
+    }
+
+    pub fn counters(&self) -> Counters {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        Counters{
+            received: get(&self.stats.received),
+            forwarded: get(&self.stats.forwarded),
+            local: get(&self.stats.local),
+            arp: get(&self.stats.arp),
+            no_route: get(&self.stats.no_route),
+            ttl_exceeded: get(&self.stats.ttl_exceeded),
+            unresolved: get(&self.stats.unresolved),
+            dropped: get(&self.stats.dropped),
+        }
+    }
+
+    /// Reads the ports' addresses and the routes over them from the
+    /// kernel.
+    pub fn refresh(&self) -> Result<()>{
+        let port = |dev: &serde_json::Value| self.ports.iter().position(|p| Some(p.name.as_str()) == dev.as_str());
+        let mut fib = Fib{ table: Table::default(), addrs: vec![Vec::new(); self.ports.len()] };
+        for link in cmd::ip_json(None, &["-4", "-j", "addr", "show"])?.as_array().cloned().unwrap_or_default(){
+            let Some(n) = port(&link["ifname"]) else {
+                continue;
+            };
+            for addr in link["addr_info"].as_array().cloned().unwrap_or_default(){
+                if let (Some(local), Some(len)) = (addr["local"].as_str().and_then(|a| a.parse().ok()), addr["prefixlen"].as_u64()) {
+                    fib.addrs[n].push((local, len as u8));
+                }
+            }
+        }
+        for route in cmd::ip_json(None, &["-4", "-j", "route", "show", "table", "main"])?.as_array().cloned().unwrap_or_default(){
+            let (prefix, len) = match route["dst"].as_str(){
+                Some("default") => (Ipv4Addr::UNSPECIFIED, 0),
+                Some(dst) => match dst.split_once('/'){
+                    Some((addr, len)) => (addr.parse()?, len.parse()?),
+                    None => (dst.parse()?, 32),
+                },
+                None => continue,
+            };
+            let hop = |nh: &serde_json::Value| Some(Hop{ port: port(&nh["dev"])?, gateway: nh["gateway"].as_str().and_then(|g| g.parse().ok()) });
+            let hops: Vec<Hop> = match route["nexthops"].as_array(){
+                Some(nexthops) => nexthops.iter().filter_map(hop).collect(),
+                None => hop(&route).into_iter().collect(),
+            };
+            match route["type"].as_str(){
+                Some("unreachable") | Some("prohibit") | Some("blackhole") => fib.table.insert(prefix, len, Vec::new()),
+                _ if !hops.is_empty() => fib.table.insert(prefix, len, hops),
+                _ => {},
+            }
+        }
+        *self.fib.write().unwrap() = fib;
+        Ok(())
+    }
+
+    fn receive(&self, port: usize) -> Result<()>{
+        while !self.stop.load(Ordering::Relaxed){
+            let n = self.ports[port].io.receive(POLL, &mut |frame| {
+                count(&self.stats.received);
+                self.handle(port, frame);
+            }).map_err(|e| failed!("Failed to read {}: {}", self.ports[port].name, e))?;
+            if n > 0 {
+                for p in &self.ports{
+                    if p.io.flush().is_err() {
+                        count(&self.stats.dropped);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&self, port: usize, frame: &[u8]){
+        if frame.len() < 14 || (frame[0..6] != self.ports[port].mac && frame[0..6] != BROADCAST) {
+            return count(&self.stats.dropped);
+        }
+        match u16::from_be_bytes([frame[12], frame[13]]){
+            ETH_ARP => self.arp(port, &frame[14..]),
+            ETH_IPV4 => self.ipv4(port, &frame[14..]),
+            _ => count(&self.stats.dropped),
+        }
+    }
+
+    fn arp(&self, port: usize, arp: &[u8]){
+        if arp.len() < 28 || arp[0..6] != [0, 1, 8, 0, 6, 4] {
+            return count(&self.stats.dropped);
+        }
+        count(&self.stats.arp);
+        let op = u16::from_be_bytes([arp[6], arp[7]]);
+        let sender_mac: Mac = arp[8..14].try_into().unwrap();
+        let sender = Ipv4Addr::new(arp[14], arp[15], arp[16], arp[17]);
+        let target = Ipv4Addr::new(arp[24], arp[25], arp[26], arp[27]);
+        if !sender.is_unspecified() {
+            self.learn(port, sender, sender_mac);
+        }
+        let ours = self.fib.read().unwrap().addrs[port].iter().any(|(a, _)| *a == target);
+        if op == 1 && ours {
+            let reply = arp_packet(2, self.ports[port].mac, target, sender_mac, sender);
+            self.transmit(port, sender_mac, ETH_ARP, &reply);
+        }
+    }
+
+    fn ipv4(&self, port: usize, packet: &[u8]){
+        let Some(header) = header_len(packet) else {
+            return count(&self.stats.dropped);
+        };
+        let total = (u16::from_be_bytes([packet[2], packet[3]]) as usize).min(packet.len());
+        if total < header {
+            return count(&self.stats.dropped);
+        }
+        let packet = &packet[..total];
+        let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+        let local = dst.is_broadcast() || self.fib.read().unwrap().addrs.iter().flatten().any(|(a, _)| *a == dst);
+        if local {
+            count(&self.stats.local);
+            // echo request
+            if packet[9] == ICMP && packet.len() >= header + 8 && packet[header] == 8 && !dst.is_broadcast() {
+                let mut icmp = packet[header..].to_vec();
+                icmp[0] = 0;
+                set_checksum(&mut icmp, 2);
+                let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+                self.originate(&ipv4_packet(dst, src, ICMP, &icmp));
+            }
+            return;
+        }
+        if packet[8] <= 1 {
+            count(&self.stats.ttl_exceeded);
+            return self.icmp_error(port, packet, 11, 0);
+        }
+        let Some(hop) = self.route(packet) else {
+            count(&self.stats.no_route);
+            return self.icmp_error(port, packet, 3, 0);
+        };
+        let mut out = packet.to_vec();
+        out[8] -= 1;
+        set_checksum(&mut out[..header], 10);
+        count(&self.stats.forwarded);
+        self.send(hop.port, hop.gateway.unwrap_or(dst), out);
+    }
+
+    /// Hop for `packet`, flows hashed over the hops of multipath routes.
+    fn route(&self, packet: &[u8]) -> Option<Hop> {
+        let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+        let fib = self.fib.read().unwrap();
+        let hops = fib.table.lookup(dst)?;
+        match hops.len(){
+            0 => None,
+            1 => Some(hops[0]),
+            n => Some(hops[(flow_hash(packet) % n as u64) as usize]),
+        }
+    }
+
+    /// Sends a packet of the router's own.
+    fn originate(&self, packet: &[u8]){
+        let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+        if let Some(hop) = self.route(packet) {
+            self.send(hop.port, hop.gateway.unwrap_or(dst), packet.to_vec());
+        }
+    }
+
+    /// ICMP error about `packet`, received on `port`, to its source, from
+    /// the first address of `port`. None about ICMP errors and fragments
+    /// but the first.
+    fn icmp_error(&self, port: usize, packet: &[u8], kind: u8, code: u8){
+        let header = header_len(packet).unwrap_or(20);
+        let fragment = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff != 0;
+        let error = packet[9] == ICMP && packet.get(header).is_some_and(|t| ![0, 8].contains(t));
+        let Some((from, _)) = self.fib.read().unwrap().addrs[port].first().copied() else {
+            return;
+        };
+        if fragment || error {
+            return;
+        }
+        let mut icmp = vec![kind, code, 0, 0, 0, 0, 0, 0];
+        icmp.extend_from_slice(&packet[..(header + 8).min(packet.len())]);
+        set_checksum(&mut icmp, 2);
+        let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+        self.originate(&ipv4_packet(from, src, ICMP, &icmp));
+    }
+
+    /// Sends `packet` out of `port` to `next`, once it answered ARP.
+    fn send(&self, port: usize, next: Ipv4Addr, packet: Vec<u8>){
+        let mut neighbors = self.neighbors.lock().unwrap();
+        let neighbor = neighbors.entry((port, next)).or_default();
+        let ask = neighbor.asked.is_none_or(|t| t.elapsed() >= ARP_RETRY)
+            && neighbor.learned.is_none_or(|t| t.elapsed() >= STALE);
+        if ask {
+            neighbor.asked = Some(Instant::now());
+        }
+        let mac = neighbor.mac;
+        if mac.is_none() {
+            if neighbor.pending.len() < PENDING {
+                neighbor.pending.push_back(packet.clone());
+            } else {
+                count(&self.stats.unresolved);
+            }
+        }
+        drop(neighbors);
+        if let Some(mac) = mac {
+            self.transmit(port, mac, ETH_IPV4, &packet);
+        }
+        if ask {
+            self.ask(port, next);
+        }
+    }
+
+    fn ask(&self, port: usize, target: Ipv4Addr){
+        let source = {
+            let fib = self.fib.read().unwrap();
+            let addrs = &fib.addrs[port];
+            addrs.iter().find(|(a, len)| u32::from(*a) & mask(*len) == u32::from(target) & mask(*len))
+                .or(addrs.first())
+                .map(|(a, _)| *a)
+        };
+        if let Some(source) = source {
+            let request = arp_packet(1, self.ports[port].mac, source, [0; 6], target);
+            self.transmit(port, BROADCAST, ETH_ARP, &request);
+        }
+    }
+
+    /// Records the MAC of `address` and sends what waited for it.
+    fn learn(&self, port: usize, address: Ipv4Addr, mac: Mac){
+        let mut neighbors = self.neighbors.lock().unwrap();
+        let neighbor = neighbors.entry((port, address)).or_default();
+        neighbor.mac = Some(mac);
+        neighbor.learned = Some(Instant::now());
+        let pending: Vec<Vec<u8>> = neighbor.pending.drain(..).collect();
+        drop(neighbors);
+        for packet in pending{
+            self.transmit(port, mac, ETH_IPV4, &packet);
+        }
+    }
+
+    fn transmit(&self, port: usize, dst: Mac, ethertype: u16, payload: &[u8]){
+        let mut frame = Vec::with_capacity(14 + payload.len());
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(&self.ports[port].mac);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        if self.ports[port].io.send(&frame).is_err() {
+            count(&self.stats.dropped);
+        }
+    }
+}
+
+/// `traffic` through the router forwarded one way.
+#[derive(Serialize, Clone, Debug)]
+pub struct BenchmarkRun{
+    /// `kernel` or the I/O of the dataplane
+    pub path: String,
+    pub traffic: TrafficReport,
+    /// of the dataplane, None for the kernel
+    pub counters: Option<Counters>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct BenchmarkReport{
+    pub router: String,
+    pub runs: Vec<BenchmarkRun>,
+}
+
+impl fmt::Display for BenchmarkReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(kernel) = self.runs.first() else {
+            return Ok(());
+        };
+        write!(f, "{} {} flows through {} for {:.1}s", kernel.traffic.flows.len(), kernel.traffic.protocol, self.router, kernel.traffic.duration.as_secs_f64())?;
+        for run in &self.runs{
+            let share = if kernel.traffic.mbps() > 0.0 { 100.0 * run.traffic.mbps() / kernel.traffic.mbps() } else { 0.0 };
+            write!(f, "\n  {:<8} {:>10.1} Mbit/s {:>6.1}% of kernel", run.path, run.traffic.mbps(), share)?;
+            if let Some(c) = &run.counters {
+                write!(f, "  {} forwarded, {} dropped, {} unresolved", c.forwarded, c.dropped, c.unresolved)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `traffic` through `router`, a namespace of a running topology on its
+/// path, forwarded by its kernel, then by the dataplane over each of `ios`
+/// on `ports`, all interfaces of the router if empty.
+pub struct Benchmark{
+    pub router: String,
+    pub ports: Vec<String>,
+    pub ios: Vec<Io>,
+    pub traffic: Traffic,
+}
+
+impl Benchmark{
+    pub fn run(&self) -> Result<BenchmarkReport>{
+        let ports: Vec<String> = match self.ports.is_empty(){
+            true => stats::read_all(&self.router)?.into_keys().collect(),
+            false => self.ports.clone(),
+        };
+        let kernel = self.traffic.run().map_err(|e| failed!("Kernel path: {}", e))?;
+        let mut runs = vec![BenchmarkRun{ path: "kernel".to_string(), traffic: kernel, counters: None }];
+        for io in &self.ios{
+            let dataplane = netns::run_in(&self.router, || Dataplane::open(&ports, *io))?;
+            let running = {
+                let dataplane = dataplane.clone();
+                netns::spawn_in(&self.router, move || dataplane.run(Duration::MAX, |_| {}))
+            };
+            let traffic = self.traffic.run();
+            dataplane.stop();
+            let ran = running.join().map_err(|_| failed!("Dataplane in {} panicked", self.router));
+            netns::run_in(&self.router, || dataplane.close())?;
+            ran??;
+            let traffic = traffic.map_err(|e| failed!("Dataplane over {}: {}", io, e))?;
+            runs.push(BenchmarkRun{ path: io.to_string(), traffic, counters: Some(dataplane.counters()) });
+        }
+        Ok(BenchmarkReport{ router: self.router.clone(), runs })
+    }
+}
+
+/// Length of the IPv4 header of `packet`, None if it isn't one.
+fn header_len(packet: &[u8]) -> Option<usize> {
+    let header = (*packet.first()? as usize & 0x0f) * 4;
+    (packet.len() >= 20 && packet[0] >> 4 == 4 && header >= 20 && packet.len() >= header).then_some(header)
+}
+
+/// FNV-1a of addresses, protocol and, for unfragmented TCP and UDP, ports.
+fn flow_hash(packet: &[u8]) -> u64 {
+    let mut fields = packet[12..20].to_vec();
+    fields.push(packet[9]);
+    let header = header_len(packet).unwrap_or(20);
+    let fragmented = u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0;
+    if (packet[9] == TCP || packet[9] == UDP) && !fragmented && packet.len() >= header + 4 {
+        fields.extend_from_slice(&packet[header..header + 4]);
+    }
+    fields.iter().fold(0xcbf29ce484222325u64, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Internet checksum of `data` written to `data[at..at + 2]`.
+fn set_checksum(data: &mut [u8], at: usize){
+    data[at] = 0;
+    data[at + 1] = 0;
+    let mut sum: u32 = data.chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    data[at..at + 2].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+fn ipv4_packet(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x45, 0];
+    packet.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0, 64, protocol, 0, 0]);
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+    set_checksum(&mut packet, 10);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn arp_packet(op: u16, sender_mac: Mac, sender: Ipv4Addr, target_mac: Mac, target: Ipv4Addr) -> Vec<u8> {
+    let mut arp = vec![0, 1, 8, 0, 6, 4];
+    arp.extend_from_slice(&op.to_be_bytes());
+    arp.extend_from_slice(&sender_mac);
+    arp.extend_from_slice(&sender.octets());
+    arp.extend_from_slice(&target_mac);
+    arp.extend_from_slice(&target.octets());
+    arp
+}
+
+fn parse_mac(s: &str) -> Option<Mac> {
+    let bytes: Vec<u8> = s.split(':').map(|b| u8::from_str_radix(b, 16).ok()).collect::<Option<_>>()?;
+    bytes.try_into().ok()
+}
+
+#[repr(C)]
+struct IfReqFlags{
+    name: [libc::c_char; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    // rest of the ifreq union
+    _pad: [u8; 22],
+}
+
+/// A port spliced to the TAP device `dp<n>` with tc redirects.
+struct TapPort{
+    port: String,
+    tap: File,
+    buf: Mutex<Vec<u8>>,
+}
+
+impl TapPort{
+    fn open(port: &str, n: usize) -> Result<TapPort>{
+        let name = format!("dp{}", n);
+        let tap = OpenOptions::new().read(true).write(true).open("/dev/net/tun")
+            .map_err(|e| failed!("Failed to open /dev/net/tun: {}", e))?;
+        let mut req = IfReqFlags{ name: [0; libc::IFNAMSIZ], flags: IFF_TAP | IFF_NO_PI, _pad: [0; 22] };
+        for (dst, src) in req.name.iter_mut().zip(name.bytes()){
+            *dst = src as libc::c_char;
+        }
+        if unsafe { libc::ioctl(tap.as_raw_fd(), TUNSETIFF as _, &mut req) } < 0 {
+            return Err(failed!("Failed to create TAP device {}: {}", name, std::io::Error::last_os_error()));
+        }
+        unsafe { libc::fcntl(tap.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+        let mtu = cmd::ip_json(None, &["-j", "link", "show", "dev", port])?[0]["mtu"].as_u64().unwrap_or(1500);
+        cmd::run("ip", &["link", "set", "dev", &name, "mtu", &mtu.to_string(), "up"])?;
+        redirect(port, &name)?;
+        redirect(&name, port)?;
+        Ok(TapPort{ port: port.to_string(), tap, buf: Mutex::new(vec![0; 65536]) })
+    }
+}
+
+impl PortIo for TapPort{
+    fn receive(&self, timeout: Duration, handle: &mut dyn FnMut(&[u8])) -> std::io::Result<usize>{
+        if !wait(self.tap.as_raw_fd(), timeout)? {
+            return Ok(0);
+        }
+        let mut buf = self.buf.lock().unwrap();
+        let mut n = 0;
+        while n < BATCH{
+            match (&self.tap).read(&mut buf){
+                Ok(len) => handle(&buf[..len]),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    fn send(&self, frame: &[u8]) -> std::io::Result<()>{
+        (&self.tap).write(frame).map(|_| ())
+    }
+
+    fn flush(&self) -> std::io::Result<()>{
+        Ok(())
+    }
+
+    fn close(&self) -> Result<()>{
+        // the TAP device goes with its file
+        cmd::tc(None, &["qdisc", "del", "dev", &self.port, "clsact"])?;
+        Ok(())
+    }
+}
+
+/// Waits up to `timeout` for `fd` to become readable.
+pub(crate) fn wait(fd: libc::c_int, timeout: Duration) -> std::io::Result<bool>{
+    let mut pfd = libc::pollfd{ fd, events: libc::POLLIN, revents: 0 };
+    match unsafe { libc::poll(&mut pfd, 1, timeout.as_millis() as libc::c_int) }{
+        n if n < 0 => {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::Interrupted { Ok(false) } else { Err(e) }
+        },
+        n => Ok(n > 0),
+    }
+}
+
+/// Sends everything arriving on `from` out of `to`.
+fn redirect(from: &str, to: &str) -> Result<()>{
+    let _ = cmd::tc(None, &["qdisc", "del", "dev", from, "clsact"]);
+    cmd::tc(None, &["qdisc", "add", "dev", from, "clsact"])?;
+    cmd::tc(None, &["filter", "add", "dev", from, "ingress", "protocol", "all", "u32", "match", "u32", "0", "0",
+        "action", "mirred", "egress", "redirect", "dev", to])?;
+    Ok(())
+}
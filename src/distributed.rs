@@ -0,0 +1,503 @@
+//! Topologies spanning several hosts. `hosts` lists the machines with the
+//! underlay addresses they reach each other on and every namespace names
+//! the `host` it runs on, the first one if it doesn't. Each host runs
+//! `router-rs agent` with the same file and its own name, which builds the
+//! part placed on it: links between its namespaces are veths as usual,
+//! links to namespaces on other hosts a VXLAN or WireGuard device between
+//! the two hosts' addresses. Those are created in the host namespace, where
+//! their sockets stay, and moved into the namespace like a host interface.
+//! `deploy` copies the file to every host and runs the agents over ssh.
+//!
+//! Subnets are assigned over the whole topology, so every host arrives at
+//! the same addresses, and routes, those from `auto_routes`, services and
+//! stubs included, are computed over the whole topology as well and
+//! installed on the host of their namespace, gateways on other hosts by
+//! address. Bridges, VXLAN links, tunnels and WireGuard links of the
+//! topology stay within a host; links between hosts take OSPF's default area and cost.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::{failed, Result};
+use crate::ipam::Ipam;
+use crate::interface;
+use crate::loopback;
+use crate::nat64;
+use crate::owner;
+use crate::paths;
+use crate::qos;
+use crate::queue::QueueSpec;
+use crate::topology::{InterfaceSpec, NexthopSpec, Topology};
+use crate::{Config, Namespace, VxlanLink};
+
+/// VNI of a link between hosts, plus the link's position in the topology.
+pub const VNI_BASE: u32 = 4096;
+
+/// WireGuard port of a link between hosts, plus the link's position in the
+/// topology.
+pub const WIREGUARD_PORT: u16 = 51820;
+
+/// Encapsulation of a link between namespaces on different hosts.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport{
+    #[default]
+    Vxlan,
+    /// encrypted, with the hosts' `wireguard_key`s
+    Wireguard,
+}
+
+impl fmt::Display for Transport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Transport::Vxlan => write!(f, "vxlan"),
+            Transport::Wireguard => write!(f, "wireguard"),
+        }
+    }
+}
+
+/// Machine a part of the topology runs on.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct HostSpec{
+    pub name: String,
+    /// underlay address links to other hosts run between
+    pub address: String,
+    /// ssh destination of `deploy`, `address` if not set
+    #[serde(default)]
+    pub ssh: Option<String>,
+    /// WireGuard private key, base64, for `wireguard` links
+    #[serde(default)]
+    pub wireguard_key: Option<String>,
+}
+
+/// End on this host of a link to a namespace on another host.
+#[derive(Clone, Debug)]
+pub struct RemoteEnd{
+    pub link: String,
+    pub namespace: String,
+    pub netns: String,
+    /// device, `<namespace>_<link>` like the end of a veth
+    pub name: String,
+    pub transport: Transport,
+    /// position of the link in the topology
+    pub index: u32,
+    pub local: IpAddr,
+    pub remote: IpAddr,
+    pub key: Option<String>,
+    pub peer_key: Option<String>,
+}
+
+impl RemoteEnd{
+    pub fn vni(&self) -> u32 {
+        VNI_BASE + self.index
+    }
+
+    pub fn port(&self) -> Result<u16>{
+        u16::try_from(self.index).ok().and_then(|i| WIREGUARD_PORT.checked_add(i))
+            .ok_or_else(|| failed!("Link {} has no WireGuard port left", self.link))
+    }
+
+    /// Creates the device in the host namespace, tagged as `topology`'s,
+    /// unless it exists in the namespace already as wanted. Returns true
+    /// if it was created.
+    fn setup(&self, topology: &str) -> Result<bool>{
+        if let Ok(out) = cmd::ip(Some(&self.netns), &["-d", "-j", "link", "show", "dev", self.name.as_str()]) {
+            let links: serde_json::Value = serde_json::from_str(&out)?;
+            let data = &links[0]["linkinfo"]["info_data"];
+            let same = match self.transport{
+                Transport::Vxlan => links[0]["linkinfo"]["info_kind"] == "vxlan"
+                    && data["id"].as_u64() == Some(self.vni() as u64)
+                    && data["local"].as_str() == Some(self.local.to_string().as_str())
+                    && data["remote"].as_str() == Some(self.remote.to_string().as_str()),
+                Transport::Wireguard => links[0]["linkinfo"]["info_kind"] == "wireguard",
+            };
+            if same {
+                if self.transport == Transport::Wireguard {
+                    self.wireguard(Some(&self.netns))?;
+                }
+                return Ok(false);
+            }
+            cmd::ip(Some(&self.netns), &["link", "del", "dev", self.name.as_str()])?;
+        }
+        // left behind by an agent which failed
+        if cmd::ip(None, &["link", "show", "dev", self.name.as_str()]).is_ok() {
+            cmd::ip(None, &["link", "del", "dev", self.name.as_str()])?;
+        }
+        match self.transport{
+            Transport::Vxlan => {
+                let (vni, local, remote, port) = (self.vni().to_string(), self.local.to_string(), self.remote.to_string(), VxlanLink::PORT.to_string());
+                cmd::ip(None, &["link", "add", "name", self.name.as_str(), "type", "vxlan", "id", vni.as_str(),
+                    "local", local.as_str(), "remote", remote.as_str(), "dstport", port.as_str()])
+                    .map_err(|e| failed!("Link {}: {}", self.link, e))?;
+            },
+            Transport::Wireguard => {
+                cmd::ip(None, &["link", "add", "name", self.name.as_str(), "type", "wireguard"])
+                    .map_err(|e| failed!("Link {}: {}", self.link, e))?;
+                if let Err(e) = self.wireguard(None) {
+                    let _ = cmd::ip(None, &["link", "del", "dev", self.name.as_str()]);
+                    return Err(e);
+                }
+            },
+        }
+        if let Err(e) = owner::tag_device(&self.name, topology) {
+            let _ = cmd::ip(None, &["link", "del", "dev", self.name.as_str()]);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Sets the keys, port and peer of the WireGuard device, found in
+    /// `netns` or the host namespace.
+    fn wireguard(&self, netns: Option<&str>) -> Result<()>{
+        let (Some(key), Some(peer_key)) = (&self.key, &self.peer_key) else {
+            return Err(failed!("WireGuard link {} needs the wireguard_key of both hosts", self.link));
+        };
+        let peer = cmd::wg(None, &["pubkey"], peer_key)?;
+        let port = self.port()?.to_string();
+        let endpoint = std::net::SocketAddr::new(self.remote, self.port()?).to_string();
+        cmd::wg(netns, &["set", self.name.as_str(), "listen-port", port.as_str(), "private-key", "/dev/stdin",
+            "peer", peer.trim(), "endpoint", endpoint.as_str(), "allowed-ips", "0.0.0.0/0,::/0", "persistent-keepalive", "25"], key)?;
+        Ok(())
+    }
+}
+
+/// Host `namespace` of `topology` runs on.
+pub fn host_of<'a>(topology: &'a Topology, namespace: &str) -> Option<&'a str> {
+    let spec = topology.namespaces.iter().find(|n| n.name == namespace);
+    match spec.and_then(|n| n.host.as_deref()){
+        Some(host) => Some(host),
+        None => topology.hosts.first().map(|h| h.name.as_str()),
+    }
+}
+
+/// Part of `topology` running on `host` and the ends of links to other
+/// hosts, which the part holds as host interfaces.
+pub fn slice(topology: &Topology, host: &str) -> Result<(Topology, Vec<RemoteEnd>)>{
+    if !topology.hosts.iter().any(|h| h.name == host) {
+        return Err(failed!("Host {} not found in topology {}", host, topology.name));
+    }
+    for ns in &topology.namespaces{
+        if let Some(h) = &ns.host{
+            if !topology.hosts.iter().any(|o| o.name == *h) {
+                return Err(failed!("Host {} of namespace {} not found", h, ns.name));
+            }
+        }
+    }
+    let on = |ns: &str| host_of(topology, ns);
+    let local = |ns: &str| on(ns) == Some(host);
+    // the same order as Topology::build, which decides allocated subnets
+    let mut full = topology.clone();
+    let mut ipam = Ipam::default();
+    if let Some(spec) = &topology.ipam{
+        ipam.configure(spec)?;
+    }
+    let mut subnets = HashMap::new();
+    for ns in &mut full.namespaces{
+        let Some(lo) = &mut ns.loopback else {
+            continue;
+        };
+        let (ip, ip6) = ipam.assign_loopback(lo.address.clone(), lo.address6.clone())
+            .map_err(|e| failed!("Loopback of {}: {}", ns.name, e))?;
+        let addr = |host: &Option<String>| host.as_ref().and_then(|h| h.split('/').next()).map(|a| a.to_string());
+        (lo.address, lo.address6) = (addr(&ip), addr(&ip6));
+        match (ip, ip6){
+            (Some(ip), ip6) => subnets.insert(loopback::name(&ns.name), (ip, ip6)),
+            (None, Some(ip6)) => subnets.insert(loopback::name(&ns.name), (ip6, None)),
+            (None, None) => None,
+        };
+    }
+    for ns in &full.namespaces{
+        let Some(nat64) = &ns.nat64 else {
+            continue;
+        };
+        subnets.insert(nat64::name(&ns.name), (nat64.pool()?.to_string(), Some(nat64.prefix()?.to_string())));
+    }
+    for auto in [false, true]{
+        for l in full.links.iter_mut().filter(|l| l.subnet.is_empty() == auto && !l.unnumbered){
+            (l.subnet, l.subnet6) = ipam.assign(l.subnet.clone(), l.subnet6.clone())
+                .map_err(|e| failed!("Link {}: {}", l.name, e))?;
+        }
+        for b in full.bridges.iter_mut().filter(|b| b.subnet.is_empty() == auto){
+            (b.subnet, b.subnet6) = ipam.assign_hosts(b.subnet.clone(), b.subnet6.clone(), b.members.len() as u128)
+                .map_err(|e| failed!("Bridge {}: {}", b.name, e))?;
+        }
+        for v in full.vxlans.iter_mut().filter(|v| v.subnet.is_empty() == auto){
+            (v.subnet, v.subnet6) = ipam.assign(v.subnet.clone(), v.subnet6.clone())
+                .map_err(|e| failed!("VXLAN link {}: {}", v.name, e))?;
+        }
+        for t in full.tunnels.iter_mut().filter(|t| t.subnet.is_empty() == auto){
+            (t.subnet, t.subnet6) = ipam.assign(t.subnet.clone(), t.subnet6.clone())
+                .map_err(|e| failed!("Tunnel {}: {}", t.name, e))?;
+        }
+        for w in full.wireguards.iter_mut().filter(|w| w.subnet.is_empty() == auto){
+            (w.subnet, w.subnet6) = ipam.assign(w.subnet.clone(), w.subnet6.clone())
+                .map_err(|e| failed!("WireGuard link {}: {}", w.name, e))?;
+        }
+    }
+    for l in &full.links{
+        subnets.insert(l.name.clone(), (l.subnet.clone(), l.subnet6.clone()));
+    }
+    for b in &full.bridges{
+        subnets.insert(b.name.clone(), (b.subnet.clone(), b.subnet6.clone()));
+    }
+    // namespace and addresses of the link ends
+    let mut addresses: HashMap<String, (String, Option<String>, Option<String>)> = HashMap::new();
+    for l in &full.links{
+        if l.endpoints.len() != 2 {
+            return Err(failed!("Link {} needs exactly two endpoints, got {}", l.name, l.endpoints.len()));
+        }
+        let ends = l.ends()?;
+        if ends.unnumbered {
+            continue;
+        }
+        for subnet in std::iter::once(&l.subnet).chain(l.subnet6.iter()){
+            let sn: ipnet::IpNet = subnet.parse()?;
+            let (a, b) = ends.addrs(&sn).map_err(|e| failed!("Link {}: {}", l.name, e))?;
+            for (ep, addr) in l.endpoints.iter().zip([a, b]){
+                let entry = addresses.entry(interface::name(ep, &l.name)).or_insert((ep.clone(), None, None));
+                if sn.addr().is_ipv6() {
+                    entry.2 = Some(addr);
+                } else {
+                    entry.1 = Some(addr);
+                }
+            }
+        }
+    }
+
+    let mut part = full.clone();
+    part.namespaces.retain(|n| local(&n.name));
+    let mut ends = Vec::new();
+    part.links.clear();
+    for (index, l) in full.links.iter().enumerate(){
+        let hosts = [on(&l.endpoints[0]), on(&l.endpoints[1])];
+        if hosts[0] == hosts[1] {
+            if hosts[0] == Some(host) {
+                part.links.push(l.clone());
+            }
+            continue;
+        }
+        let Some(n) = hosts.iter().position(|h| *h == Some(host)) else {
+            continue;
+        };
+        if l.bond.is_some() {
+            return Err(failed!("Bonded link {} spans hosts, its members have to be veths", l.name));
+        }
+        if l.unnumbered {
+            return Err(failed!("Unnumbered link {} spans hosts, give it a subnet", l.name));
+        }
+        let address = |h: Option<&str>| -> Result<(IpAddr, Option<String>)>{
+            let spec = topology.hosts.iter().find(|o| Some(o.name.as_str()) == h)
+                .ok_or_else(|| failed!("Host of link {} not found", l.name))?;
+            let addr = spec.address.parse()
+                .map_err(|e| failed!("Invalid address {} of host {}: {}", spec.address, spec.name, e))?;
+            Ok((addr, spec.wireguard_key.clone()))
+        };
+        let ((local, key), (remote, peer_key)) = (address(hosts[n])?, address(hosts[1 - n])?);
+        let namespace = l.endpoints[n].clone();
+        let name = interface::name(&namespace, &l.name);
+        let (_, ip, ip6) = addresses.get(&name).cloned().unwrap_or_default();
+        part.interfaces.push(InterfaceSpec{
+            name: name.clone(),
+            namespace: Some(namespace.clone()),
+            ip,
+            ip6,
+            mtu: None,
+            group: l.group.clone(),
+            // the end is no veth, its number of queues is fixed, and qos
+            // brings its own root qdisc
+            queues: Some(QueueSpec{
+                numtxqueues: None,
+                numrxqueues: None,
+                qdisc: topology.link_queues(l).qdisc.filter(|_| l.qos_at(&namespace).is_none()),
+                ..topology.link_queues(l)
+            }).filter(|q| *q != QueueSpec::default()),
+            passthrough: None,
+        });
+        ends.push(RemoteEnd{
+            link: l.name.clone(),
+            netns: Namespace::netns_name(&topology.name, &namespace),
+            namespace,
+            name,
+            transport: l.transport,
+            index: index as u32,
+            local,
+            remote,
+            key,
+            peer_key,
+        });
+    }
+    // segments which don't cross hosts
+    let within = |kind: &str, name: &str, members: Vec<&str>| -> Result<bool>{
+        let hosts: Vec<Option<&str>> = members.iter().map(|m| on(m)).collect();
+        if hosts.iter().any(|h| *h != hosts[0]) {
+            return Err(failed!("{} {} spans several hosts, only links may", kind, name));
+        }
+        Ok(hosts.first() == Some(&Some(host)))
+    };
+    let mut bridges = Vec::new();
+    for b in &full.bridges{
+        let members = b.members.iter().chain(b.namespace.iter()).map(|m| m.as_str()).collect();
+        if within("Bridge", &b.name, members)? {
+            bridges.push(b.clone());
+        }
+    }
+    part.bridges = bridges;
+    let mut vxlans = Vec::new();
+    for v in &full.vxlans{
+        if within("VXLAN link", &v.name, v.endpoints.iter().map(|e| e.namespace.as_str()).collect())? {
+            vxlans.push(v.clone());
+        }
+    }
+    part.vxlans = vxlans;
+    let mut tunnels = Vec::new();
+    for t in &full.tunnels{
+        if within("Tunnel", &t.name, t.endpoints.iter().map(|e| e.namespace.as_str()).collect())? {
+            tunnels.push(t.clone());
+        }
+    }
+    part.tunnels = tunnels;
+    let mut wireguards = Vec::new();
+    for w in &full.wireguards{
+        if within("WireGuard link", &w.name, w.endpoints.iter().map(|e| e.namespace.as_str()).collect())? {
+            wireguards.push(w.clone());
+        }
+    }
+    part.wireguards = wireguards;
+    let first = topology.hosts.first().map(|h| h.name.as_str());
+    part.interfaces.retain(|i| match &i.namespace{
+        Some(ns) => local(ns),
+        None => first == Some(host),
+    });
+    for s in &mut part.services{
+        s.instances.retain(|i| local(i));
+    }
+    part.services.retain(|s| !s.instances.is_empty());
+
+    // routes of the whole topology, generated ones written out, so the
+    // part generates none of its own
+    let mut routes = full.routes.clone();
+    routes.extend(paths::service_routes(&full, &subnets)?);
+    routes.extend(paths::stub_routes(&full, &subnets));
+    if full.auto_routes {
+        routes.extend(paths::static_routes(&full, &subnets)?);
+    }
+    part.auto_routes = false;
+    part.routes = Vec::new();
+    for mut r in routes.into_iter().filter(|r| local(&r.namespace)){
+        let v6 = r.dst.contains(':');
+        // gateways on other hosts by address
+        let remote = |gw: &str| -> Result<Option<String>>{
+            let Some((ns, ip, ip6)) = addresses.get(gw) else {
+                return Ok(None);
+            };
+            if local(ns) {
+                return Ok(None);
+            }
+            let ip = if v6 { ip6 } else { ip };
+            let ip = ip.as_ref()
+                .ok_or_else(|| failed!("Interface {} does not have an {} address", gw, if v6 { "IPv6" } else { "IPv4" }))?;
+            Ok(Some(ip.split('/').next().unwrap_or_default().to_string()))
+        };
+        let mut gateways = Vec::new();
+        let mut nexthops = Vec::new();
+        for gw in &r.gateways{
+            match remote(gw)?{
+                Some(address) => nexthops.push(NexthopSpec{ address: Some(address), ..Default::default() }),
+                None => gateways.push(gw.clone()),
+            }
+        }
+        for n in &r.nexthops{
+            let mut n = n.clone();
+            if let Some(address) = n.via.as_deref().map(remote).transpose()?.flatten() {
+                n.via = None;
+                n.address = Some(address);
+            }
+            nexthops.push(n);
+        }
+        r.gateways = gateways;
+        r.nexthops = nexthops;
+        part.routes.push(r);
+    }
+    Ok((part, ends))
+}
+
+/// Builds the part of `topology` placed on `host`, or brings it in line
+/// with the description if it exists.
+pub fn apply(topology: &Topology, host: &str, config: Config) -> Result<Config>{
+    let (part, ends) = slice(topology, host)?;
+    let mut created = Vec::new();
+    let mut result = Ok(());
+    for end in &ends{
+        match end.setup(&topology.name){
+            Ok(true) => created.push(end.name.clone()),
+            Ok(false) => {},
+            Err(e) => {
+                result = Err(e);
+                break;
+            },
+        }
+    }
+    let result = result.and_then(|_| part.reconcile_with(config));
+    let config = match result{
+        Ok(config) => config,
+        Err(e) => {
+            // devices the namespaces haven't taken
+            for name in created{
+                let _ = cmd::ip(None, &["link", "del", "dev", name.as_str()]);
+            }
+            return Err(e);
+        },
+    };
+    for end in &ends{
+        let link = topology.links.iter().find(|l| l.name == end.link);
+        match link.and_then(|l| l.qos_at(&end.namespace)){
+            Some(q) => q.apply(&end.netns, &end.name)
+                .map_err(|e| failed!("Link {}: {}", end.link, e))?,
+            None => qos::clear(&end.netns, &end.name)?,
+        }
+    }
+    Ok(config)
+}
+
+/// Copies `file` describing `topology` to every host and runs the agent
+/// there, `binary` on the host's path. With `destroy` the hosts' parts are
+/// destroyed instead.
+pub fn deploy(topology: &Topology, file: &Path, binary: &str, destroy: bool, host_changes: bool) -> Result<()>{
+    if topology.hosts.is_empty() {
+        return Err(failed!("Topology {} has no hosts", topology.name));
+    }
+    let ext = file.extension().and_then(|e| e.to_str()).unwrap_or("yaml");
+    let path = format!("/tmp/router-rs-{}.{}", topology.name, ext);
+    let mut failed = Vec::new();
+    for h in &topology.hosts{
+        let dest = h.ssh.as_deref().unwrap_or(h.address.as_str());
+        let result = if destroy {
+            cmd::run("ssh", &[dest, binary, "destroy", topology.name.as_str()])
+        } else {
+            cmd::run("scp", &["-q", &file.to_string_lossy(), &format!("{}:{}", dest, path)])
+                .and_then(|_| {
+                    let mut args = vec![dest, binary, "agent", "-f", path.as_str(), "-n", topology.name.as_str(), "--host", h.name.as_str()];
+                    if host_changes {
+                        args.push("--allow-host-changes");
+                    }
+                    cmd::run("ssh", &args)
+                })
+        };
+        match result{
+            Ok(_) => println!("{}: {}", h.name, if destroy { "destroyed" } else { "applied" }),
+            Err(e) => {
+                eprintln!("{}: {}", h.name, e);
+                failed.push(h.name.clone());
+            },
+        }
+    }
+    if !failed.is_empty() {
+        return Err(failed!("Failed on {}", failed.join(", ")));
+    }
+    Ok(())
+}
@@ -0,0 +1,486 @@
+//! Tiny authoritative DNS server for the names of a topology, so tests can
+//! use stable names while IPAM moves addresses around. `<namespace>.<zone>`
+//! resolves to the addresses of the namespace, `<interface>.<namespace>.<zone>`
+//! to those of one interface. Answers come from the saved state, which is
+//! reread every second, so a reconcile is picked up without a restart.
+//!
+//! The server listens on 127.0.0.53:53 inside every namespace and points
+//! `/etc/netns/<netns>/resolv.conf` at it, which `ip netns exec` bind-mounts
+//! over `/etc/resolv.conf`. With a DNS64 prefix, names without IPv6
+//! addresses get their IPv4 ones mapped into it as well, see `nat64`.
+//!
+//! Instead, namespaces with `dns` in the topology run a server of their
+//! own on port 53 of all their addresses, the builtin one or dnsmasq, and
+//! the resolv.conf of their clients points at an address of the server
+//! they reach directly if there is one, else at its loopback or first
+//! address. Servers run with their files in a runtime directory next to
+//! those of the routing daemons and are stopped with the topology.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::daemon;
+use crate::error::{failed, Result};
+use crate::logs;
+use crate::loopback;
+use crate::nat64;
+use crate::netns;
+use crate::state::{State, STATE_DIR};
+
+pub const LISTEN: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 53)), 53);
+/// Where the server of a namespace with `dns` listens, also for IPv4 if
+/// the namespace allows dual-stack sockets.
+const RESPOND: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 53);
+/// Zone if not set.
+pub const DEFAULT_ZONE: &str = "lab";
+/// Short, addresses change on reconcile.
+pub const TTL: u32 = 5;
+/// First line of the resolv.conf files we write, so only those are removed.
+const MARKER: &str = "# written by router-rs dns";
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_ANY: u16 = 255;
+const NXDOMAIN: u8 = 3;
+const NOTIMP: u8 = 4;
+const REFUSED: u8 = 5;
+
+/// Program answering for a namespace with `dns`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsBackend{
+    /// `router-rs dns respond`, rereading the saved state every second
+    #[default]
+    Builtin,
+    /// dnsmasq with the records as hosts file, restarted when they change
+    Dnsmasq,
+}
+
+impl fmt::Display for DnsBackend{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            DnsBackend::Builtin => write!(f, "builtin"),
+            DnsBackend::Dnsmasq => write!(f, "dnsmasq"),
+        }
+    }
+}
+
+/// DNS server a namespace runs for the names of the topology.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct DnsSpec{
+    /// `DEFAULT_ZONE` if not set
+    #[serde(default)]
+    pub zone: Option<String>,
+    /// NAT64 prefix to synthesize AAAA records in, if any, see `dns64`
+    #[serde(default)]
+    pub dns64: Option<String>,
+    #[serde(default)]
+    pub backend: DnsBackend,
+    /// namespaces resolving with it, all of the topology if empty
+    #[serde(default)]
+    pub clients: Vec<String>,
+}
+
+impl DnsSpec{
+    /// Lower case zone without trailing dot.
+    pub fn zone(&self) -> String {
+        self.zone.as_deref().unwrap_or(DEFAULT_ZONE).trim_matches('.').to_lowercase()
+    }
+
+    pub fn dns64(&self) -> Result<Option<ipnet::Ipv6Net>>{
+        let Some(prefix) = &self.dns64 else {
+            return Ok(None);
+        };
+        let net: ipnet::Ipv6Net = prefix.parse()
+            .map_err(|e| failed!("Invalid DNS64 prefix {}: {}", prefix, e))?;
+        if net.prefix_len() != 96 {
+            return Err(failed!("DNS64 prefix {} is not a /96", prefix));
+        }
+        Ok(Some(net.trunc()))
+    }
+
+    pub fn check(&self) -> Result<()>{
+        let zone = self.zone();
+        if zone.is_empty() || zone.split('.').any(|l| l.is_empty() || l.len() > 63) {
+            return Err(failed!("Invalid DNS zone {:?}", self.zone.as_deref().unwrap_or_default()));
+        }
+        self.dns64()?;
+        Ok(())
+    }
+
+    /// Records served, those of `state` with the DNS64 ones if any.
+    pub fn records(&self, state: &State) -> Result<Records>{
+        let mut records = records(state, &self.zone());
+        if let Some(prefix) = self.dns64()?{
+            dns64(&mut records, prefix);
+        }
+        Ok(records)
+    }
+}
+
+/// Names of the zone mapped to their addresses, names are lower case and
+/// without trailing dot.
+pub type Records = BTreeMap<String, Vec<IpAddr>>;
+
+/// Records of `state` in `zone`.
+pub fn records(state: &State, zone: &str) -> Records {
+    let mut records = Records::new();
+    let mut interfaces: Vec<_> = state.interfaces.iter().collect();
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    for ns in &state.namespaces{
+        let mut all = Vec::new();
+        for i in interfaces.iter().filter(|i| i.netns.as_deref() == Some(ns.netns.as_str())){
+            let addresses: Vec<IpAddr> = [&i.ip, &i.ip6].into_iter().flatten()
+                .filter_map(|ip| ip.split('/').next()?.parse().ok())
+                .collect();
+            all.extend(addresses.iter().copied());
+            records.insert(format!("{}.{}.{}", i.name, ns.name, zone).to_lowercase(), addresses);
+        }
+        records.insert(format!("{}.{}", ns.name, zone).to_lowercase(), all);
+    }
+    records
+}
+
+/// `records` with the IPv4 addresses of names without IPv6 ones mapped
+/// into `prefix` as well, the AAAA records DNS64 synthesizes.
+pub fn dns64(records: &mut Records, prefix: ipnet::Ipv6Net){
+    for addresses in records.values_mut(){
+        if addresses.iter().any(|a| a.is_ipv6()) {
+            continue;
+        }
+        let mapped: Vec<IpAddr> = addresses.iter()
+            .filter_map(|a| match a{
+                IpAddr::V4(a) => Some(IpAddr::V6(nat64::map(prefix, *a))),
+                IpAddr::V6(_) => None,
+            })
+            .collect();
+        addresses.extend(mapped);
+    }
+}
+
+pub struct DnsServer{
+    pub topology: String,
+    pub zone: String,
+    /// NAT64 prefix to synthesize AAAA records in, if any
+    pub dns64: Option<ipnet::Ipv6Net>,
+}
+
+impl DnsServer{
+    /// Serves until the topology is destroyed.
+    pub fn run(&self) -> Result<()>{
+        let zone = self.zone.trim_matches('.').to_lowercase();
+        let shared: Arc<RwLock<Records>> = Arc::new(RwLock::new(Records::new()));
+        let mut serving = BTreeSet::new();
+        loop{
+            let Some(state) = State::load(&self.topology)? else {
+                return Ok(());
+            };
+            *shared.write().map_err(|_| failed!("DNS records poisoned"))? = self.records(&state, &zone);
+            for ns in &state.namespaces{
+                if serving.contains(&ns.netns) {
+                    continue;
+                }
+                let socket = listen(&ns.netns, &zone)?;
+                let (records, zone) = (shared.clone(), zone.clone());
+                std::thread::spawn(move || serve(socket, &zone, &records));
+                serving.insert(ns.netns.clone());
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    /// Serves on port 53 of every address of the namespace it runs in
+    /// until killed, the server of a namespace with `dns`. The state is
+    /// only saved once the topology is built, until then names don't
+    /// resolve.
+    pub fn respond(&self) -> Result<()>{
+        let zone = self.zone.trim_matches('.').to_lowercase();
+        let shared: Arc<RwLock<Records>> = Arc::new(RwLock::new(Records::new()));
+        let socket = UdpSocket::bind(RESPOND)
+            .or_else(|_| UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 53)))
+            .map_err(|e| failed!("Failed to listen on port 53: {}", e))?;
+        let (records, served) = (shared.clone(), zone.clone());
+        std::thread::spawn(move || serve(socket, &served, &records));
+        loop{
+            if let Some(state) = State::load(&self.topology)?{
+                *shared.write().map_err(|_| failed!("DNS records poisoned"))? = self.records(&state, &zone);
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    fn records(&self, state: &State, zone: &str) -> Records {
+        let mut current = records(state, zone);
+        if let Some(prefix) = self.dns64{
+            dns64(&mut current, prefix);
+        }
+        current
+    }
+}
+
+/// DNS server of the namespace `netns`, see `DnsSpec`.
+pub struct Responder{
+    pub topology: String,
+    pub netns: String,
+    /// config, records and pid file
+    pub dir: PathBuf,
+}
+
+impl Responder{
+    pub fn new(topology: &str, namespace: &str, netns: &str) -> Responder {
+        Responder{
+            topology: topology.to_string(),
+            netns: netns.to_string(),
+            dir: Responder::dir(topology, namespace),
+        }
+    }
+
+    /// Runtime directory of the server of `namespace` of `topology`.
+    pub fn dir(topology: &str, namespace: &str) -> PathBuf {
+        PathBuf::from(STATE_DIR).join(topology).join(format!("dns-{}", namespace))
+    }
+
+    /// Namespaces of `topology` with a server directory.
+    pub fn list(topology: &str) -> Result<Vec<String>>{
+        let dir = PathBuf::from(STATE_DIR).join(topology);
+        let mut namespaces = Vec::new();
+        if !dir.exists() {
+            return Ok(namespaces);
+        }
+        for entry in std::fs::read_dir(dir)?{
+            if let Some(ns) = entry?.file_name().to_str().and_then(|n| n.strip_prefix("dns-")){
+                namespaces.push(ns.to_string());
+            }
+        }
+        Ok(namespaces)
+    }
+
+    /// Runs the server of `spec` for the names of `state` unless it runs
+    /// already with the same config and records. Returns true if it was
+    /// started.
+    pub fn start(&self, spec: &DnsSpec, state: &State) -> Result<bool>{
+        let (name, files, args) = match spec.backend{
+            DnsBackend::Builtin => {
+                // reads the records itself, only its arguments count
+                let mut args = vec!["router-rs".to_string(), "dns".to_string(), "respond".to_string(), self.topology.clone(), "--zone".to_string(), spec.zone()];
+                if let Some(prefix) = spec.dns64()?{
+                    args.extend(["--dns64".to_string(), prefix.to_string()]);
+                }
+                ("dns", vec![("dns.cfg", args.join("\n"))], args)
+            },
+            DnsBackend::Dnsmasq => {
+                let (config, hosts) = (self.dir.join("dnsmasq.cfg"), self.dir.join("hosts"));
+                let args = vec![
+                    "dnsmasq".to_string(),
+                    "--keep-in-foreground".to_string(),
+                    format!("--conf-file={}", config.display()),
+                ];
+                ("dnsmasq", vec![("dnsmasq.cfg", dnsmasq_config(&spec.zone(), &hosts)), ("hosts", hosts_file(&spec.records(state)?))], args)
+            },
+        };
+        let pidfile = self.dir.join(format!("{}.pid", name));
+        let running = daemon::pid(&pidfile).is_some_and(daemon::alive);
+        if running && files.iter().all(|(file, content)| std::fs::read_to_string(self.dir.join(file)).ok().as_deref() == Some(content)) {
+            return Ok(false);
+        }
+        daemon::stop_dir(&self.dir)?;
+        std::fs::create_dir_all(&self.dir)?;
+        for (file, content) in &files{
+            std::fs::write(self.dir.join(file), content)?;
+        }
+        daemon::spawn(&self.topology, &self.netns, &self.dir, name, &args)?;
+        std::thread::sleep(Duration::from_millis(200));
+        if !daemon::pid(&pidfile).is_some_and(daemon::alive) {
+            let tail = logs::tail(&logs::path(&self.topology, &self.netns, name), 5);
+            let _ = daemon::stop_dir(&self.dir);
+            return Err(failed!("{} in {} exited: {}", name, self.netns, tail));
+        }
+        Ok(true)
+    }
+
+    pub fn stop(&self) -> Result<()>{
+        daemon::stop_dir(&self.dir)
+    }
+}
+
+/// dnsmasq config answering for `zone` from `hosts` only, never
+/// forwarding.
+fn dnsmasq_config(zone: &str, hosts: &std::path::Path) -> String {
+    let mut s = String::new();
+    let _ = writeln!(s, "no-resolv");
+    let _ = writeln!(s, "no-hosts");
+    let _ = writeln!(s, "addn-hosts={}", hosts.display());
+    // names of the zone not in hosts don't exist
+    let _ = writeln!(s, "local=/{}/", zone);
+    let _ = writeln!(s, "domain={}", zone);
+    let _ = writeln!(s, "local-ttl={}", TTL);
+    let _ = writeln!(s, "user=root");
+    s
+}
+
+/// `records` in the format of /etc/hosts.
+fn hosts_file(records: &Records) -> String {
+    let mut s = String::new();
+    for (name, addresses) in records{
+        for address in addresses{
+            let _ = writeln!(s, "{} {}", address, name);
+        }
+    }
+    s
+}
+
+/// Address of the server in `server` the namespace `client` sends its
+/// queries to: one on a subnet of the client, else the server's loopback
+/// address, else its first address. The client itself asks 127.0.0.1.
+pub fn nameserver(state: &State, server: &str, client: &str) -> Option<IpAddr> {
+    let netns = |name: &str| state.namespaces.iter().find(|n| n.name == name).map(|n| n.netns.clone());
+    let (server_netns, client_netns) = (netns(server)?, netns(client)?);
+    if server_netns == client_netns {
+        return Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+    let mut interfaces: Vec<_> = state.interfaces.iter().collect();
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    let addresses = |netns: &str| -> Vec<(String, ipnet::IpNet)> {
+        interfaces.iter()
+            .filter(|i| i.netns.as_deref() == Some(netns))
+            .flat_map(|i| [&i.ip, &i.ip6].into_iter().flatten().filter_map(|ip| Some((i.name.clone(), ip.parse().ok()?))))
+            .collect()
+    };
+    let (served, subnets) = (addresses(&server_netns), addresses(&client_netns));
+    served.iter().find(|(_, a)| subnets.iter().any(|(_, s)| s.contains(&a.addr())))
+        .or_else(|| served.iter().find(|(name, _)| *name == loopback::name(server)))
+        .or_else(|| served.iter().find(|(_, a)| a.addr().is_ipv4()))
+        .or(served.first())
+        .map(|(_, a)| a.addr())
+}
+
+/// Points the resolv.conf of `netns` at `nameservers`, searching `zones`.
+pub fn configure(netns: &str, nameservers: &[IpAddr], zones: &[String]) -> Result<()>{
+    let dir = PathBuf::from("/etc/netns").join(netns);
+    std::fs::create_dir_all(&dir)?;
+    let mut s = format!("{}\n", MARKER);
+    for nameserver in nameservers{
+        let _ = writeln!(s, "nameserver {}", nameserver);
+    }
+    let _ = writeln!(s, "search {}", zones.join(" "));
+    std::fs::write(dir.join("resolv.conf"), s)?;
+    Ok(())
+}
+
+/// Socket on `LISTEN` in `netns`, with resolv.conf pointed at it.
+fn listen(netns: &str, zone: &str) -> Result<UdpSocket>{
+    cmd::ip(Some(netns), &["link", "set", "dev", "lo", "up"])
+        .map_err(|e| e.context(format!("Failed to bring up lo in {}", netns)))?;
+    let socket = netns::run_in(netns, || UdpSocket::bind(LISTEN)
+        .map_err(|e| failed!("Failed to listen on {} in {}: {}", LISTEN, netns, e)))?;
+    configure(netns, &[LISTEN.ip()], &[zone.to_string()])?;
+    Ok(socket)
+}
+
+fn serve(socket: UdpSocket, zone: &str, records: &RwLock<Records>){
+    let mut buf = [0u8; 512];
+    loop{
+        let Ok((len, peer)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        let answer = match records.read(){
+            Ok(records) => answer(&buf[..len], zone, &records),
+            Err(_) => return,
+        };
+        if let Some(answer) = answer{
+            let _ = socket.send_to(&answer, peer);
+        }
+    }
+}
+
+/// Response to the query in `packet`, None if it isn't worth one.
+pub fn answer(packet: &[u8], zone: &str, records: &Records) -> Option<Vec<u8>> {
+    if packet.len() < 12 || packet[2] & 0x80 != 0 {
+        return None;
+    }
+    let questions = u16::from_be_bytes([packet[4], packet[5]]);
+    let opcode = (packet[2] >> 3) & 0x0f;
+    // header: same id, QR and AA set, RD copied, one question, no answers yet
+    let mut response = vec![packet[0], packet[1], 0x84 | (packet[2] & 0x01), 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    if opcode != 0 || questions != 1 {
+        response[3] = NOTIMP;
+        return Some(response);
+    }
+    let (name, end) = qname(packet)?;
+    if end + 4 > packet.len() {
+        return None;
+    }
+    let qtype = u16::from_be_bytes([packet[end], packet[end + 1]]);
+    response[5] = 1;
+    response.extend_from_slice(&packet[12..end + 4]);
+    if name != zone && !name.ends_with(&format!(".{}", zone)) {
+        response[3] = REFUSED;
+        return Some(response);
+    }
+    let known = name == zone || records.keys().any(|n| *n == name || n.ends_with(&format!(".{}", name)));
+    let Some(addresses) = records.get(&name) else {
+        if !known {
+            response[3] = NXDOMAIN;
+        }
+        return Some(response);
+    };
+    let mut count: u16 = 0;
+    for address in addresses{
+        let (rtype, rdata) = match address{
+            IpAddr::V4(a) => (TYPE_A, a.octets().to_vec()),
+            IpAddr::V6(a) => (TYPE_AAAA, a.octets().to_vec()),
+        };
+        if qtype != rtype && qtype != TYPE_ANY {
+            continue;
+        }
+        // name as pointer to the question
+        response.extend_from_slice(&[0xc0, 0x0c]);
+        response.extend_from_slice(&rtype.to_be_bytes());
+        response.extend_from_slice(&1u16.to_be_bytes());
+        response.extend_from_slice(&TTL.to_be_bytes());
+        response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        response.extend_from_slice(&rdata);
+        count += 1;
+    }
+    response[6..8].copy_from_slice(&count.to_be_bytes());
+    Some(response)
+}
+
+/// Lower case name of the question and the offset right after it.
+fn qname(packet: &[u8]) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut pos = 12;
+    loop{
+        let len = *packet.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // compression never occurs in a question we accept
+        if len > 63 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(packet.get(pos..pos + len)?).to_lowercase());
+        pos += len;
+    }
+    Some((labels.join("."), pos))
+}
+
+/// Removes the resolv.conf written for `netns`, if any.
+pub fn unconfigure(netns: &str) -> Result<()>{
+    let dir = PathBuf::from("/etc/netns").join(netns);
+    let path = dir.join("resolv.conf");
+    if std::fs::read_to_string(&path).is_ok_and(|c| c.starts_with(MARKER)) {
+        std::fs::remove_file(&path)?;
+        // only if nothing else lives there
+        let _ = std::fs::remove_dir(&dir);
+    }
+    Ok(())
+}
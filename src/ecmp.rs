@@ -0,0 +1,185 @@
+//! How a router spread test traffic over the nexthops of its multipath
+//! route: flows from `traffic` are run through it while the counters of
+//! the nexthop interfaces are read before and after, giving the bytes and
+//! packets each path carried and Jain's fairness index over them,
+//!
+//! ```text
+//! J = (x1 + ... + xn)^2 / (n * (x1^2 + ... + xn^2))
+//! ```
+//!
+//! with `xi` the bytes of path `i` divided by its weight. It is 1 when
+//! every path carried its share and 1/n when one path carried it all.
+//!
+//! What to expect depends on the router's `fib_multipath_hash_policy`: 0
+//! hashes on the addresses only, so flows between one pair of hosts all
+//! take the same path, whereas 1 and above hash on the ports too and
+//! should spread them.
+
+use std::fmt;
+use std::net::IpAddr;
+
+use serde::Serialize;
+
+use crate::error::{failed, Result};
+use crate::netns;
+use crate::stats;
+use crate::traffic::{Traffic, TrafficReport};
+
+/// The nexthop taking at least this share of the bytes is the path flows
+/// of an address-hashing router are expected to stick to.
+const STUCK: f64 = 0.9;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Nexthop{
+    pub gateway: Option<IpAddr>,
+    pub interface: String,
+    pub weight: u64,
+    pub bytes: u64,
+    pub packets: u64,
+    /// of the bytes sent over all nexthops
+    pub share: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct EcmpReport{
+    pub router: String,
+    /// the multipath route the traffic took
+    pub route: String,
+    pub hash_policy: u8,
+    pub nexthops: Vec<Nexthop>,
+    pub fairness: f64,
+    /// whether the spread matches the hash policy
+    pub expected: bool,
+    pub traffic: TrafficReport,
+}
+
+impl fmt::Display for EcmpReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} flows over {} via {} (fib_multipath_hash_policy {})", self.traffic.flows.len(), self.route, self.router, self.hash_policy)?;
+        for nexthop in &self.nexthops{
+            let gateway = nexthop.gateway.map(|g| g.to_string()).unwrap_or("-".to_string());
+            writeln!(f, "  {:<16} {:<16} weight {:<3} {:>14} bytes {:>10} packets {:>5.1}%",
+                gateway, nexthop.interface, nexthop.weight, nexthop.bytes, nexthop.packets, 100.0 * nexthop.share)?;
+        }
+        write!(f, "fairness {:.3} of 1.000, {}", self.fairness, if self.expected { "as expected" } else { "NOT as expected" })
+    }
+}
+
+/// Runs `traffic` through `router`, a namespace on its path with a
+/// multipath route to the traffic's target. With a hash policy of 1 and
+/// above the fairness has to reach `min_fairness` to be as expected.
+#[derive(Clone, Debug)]
+pub struct EcmpAnalysis{
+    pub router: String,
+    pub traffic: Traffic,
+    pub min_fairness: f64,
+}
+
+impl EcmpAnalysis{
+    pub fn run(&self) -> Result<EcmpReport>{
+        let target = self.traffic.target.ip();
+        let (route, mut nexthops) = route(&self.router, target)?;
+        let hash_policy = hash_policy(&self.router, target)?;
+        let before = stats::read_all(&self.router)?;
+        let traffic = self.traffic.run()?;
+        let after = stats::read_all(&self.router)?;
+        for nexthop in nexthops.iter_mut(){
+            let sent = match (before.get(&nexthop.interface), after.get(&nexthop.interface)){
+                (Some(before), Some(after)) => *after - *before,
+                _ => return Err(failed!("Interface {} of {} went away during the run", nexthop.interface, self.router)),
+            };
+            nexthop.bytes = sent.tx_bytes;
+            nexthop.packets = sent.tx_packets;
+        }
+        let total: u64 = nexthops.iter().map(|n| n.bytes).sum();
+        if total == 0 {
+            return Err(failed!("No traffic left {} over the nexthops of {}", self.router, route));
+        }
+        for nexthop in nexthops.iter_mut(){
+            nexthop.share = nexthop.bytes as f64 / total as f64;
+        }
+        let fairness = fairness(&nexthops.iter().map(|n| n.bytes as f64 / n.weight.max(1) as f64).collect::<Vec<_>>());
+        let expected = match hash_policy{
+            0 => nexthops.iter().any(|n| n.share >= STUCK),
+            _ => fairness >= self.min_fairness,
+        };
+        Ok(EcmpReport{ router: self.router.clone(), route, hash_policy, nexthops, fairness, expected, traffic })
+    }
+}
+
+/// Jain's fairness index of `values`.
+pub fn fairness(values: &[f64]) -> f64 {
+    let sum: f64 = values.iter().sum();
+    let squares: f64 = values.iter().map(|v| v * v).sum();
+    if squares == 0.0 {
+        return 0.0;
+    }
+    sum * sum / (values.len() as f64 * squares)
+}
+
+/// The most specific route of `netns` to `target`, which has to have more
+/// than one nexthop.
+fn route(netns: &str, target: IpAddr) -> Result<(String, Vec<Nexthop>)>{
+    let family = if target.is_ipv6() { "-6" } else { "-4" };
+    let output = netns::exec(netns, "ip", &[family, "-j", "route", "show", "match", &target.to_string()])?;
+    if !output.success() {
+        return Err(failed!("Failed to read the routes of {}: {}", netns, output.stderr.trim()));
+    }
+    let routes: serde_json::Value = serde_json::from_str(&output.stdout)?;
+    let prefix_len = |r: &serde_json::Value| -> u8 {
+        match r["dst"].as_str(){
+            Some("default") | None => 0,
+            Some(dst) => dst.split_once('/').and_then(|(_, len)| len.parse().ok()).unwrap_or(if target.is_ipv6() { 128 } else { 32 }),
+        }
+    };
+    let best = routes.as_array().cloned().unwrap_or_default().into_iter()
+        .max_by_key(prefix_len)
+        .ok_or_else(|| failed!("{} has no route to {}", netns, target))?;
+    let dst = best["dst"].as_str().unwrap_or("default").to_string();
+    let nexthops: Vec<Nexthop> = best["nexthops"].as_array().cloned().unwrap_or_default().iter()
+        .filter_map(|n| Some(Nexthop{
+            gateway: n["gateway"].as_str().and_then(|g| g.parse().ok()),
+            interface: n["dev"].as_str()?.to_string(),
+            weight: n["weight"].as_u64().unwrap_or(1),
+            bytes: 0,
+            packets: 0,
+            share: 0.0,
+        }))
+        .collect();
+    if nexthops.len() < 2 {
+        return Err(failed!("Route {} of {} to {} has a single path", dst, netns, target));
+    }
+    Ok((dst, nexthops))
+}
+
+fn hash_policy(netns: &str, target: IpAddr) -> Result<u8>{
+    let key = if target.is_ipv6() { "net.ipv6.fib_multipath_hash_policy" } else { "net.ipv4.fib_multipath_hash_policy" };
+    let output = netns::exec(netns, "sysctl", &["-n", key])?;
+    if !output.success() {
+        return Err(failed!("Failed to read {} of {}: {}", key, netns, output.stderr.trim()));
+    }
+    output.stdout.trim().parse()
+        .map_err(|e| failed!("Invalid {} {:?} of {}: {}", key, output.stdout.trim(), netns, e))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn fairness_is_one_for_an_even_spread(){
+        assert_eq!(fairness(&[250.0, 250.0, 250.0, 250.0]), 1.0);
+    }
+
+    #[test]
+    fn fairness_is_one_over_n_when_one_path_carries_everything(){
+        assert_eq!(fairness(&[1000.0, 0.0, 0.0, 0.0]), 0.25);
+        assert_eq!(fairness(&[100.0, 300.0]), 0.8);
+    }
+
+    #[test]
+    fn fairness_of_no_traffic_is_zero(){
+        assert_eq!(fairness(&[0.0, 0.0]), 0.0);
+        assert_eq!(fairness(&[]), 0.0);
+    }
+}
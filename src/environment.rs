@@ -0,0 +1,94 @@
+//! Environment of programs run in a node: `exec`, `clock` and the
+//! processes started for a namespace, like forwarders and P4 switches, get
+//! variables describing the node, so test programs configure themselves
+//! without parsing the topology:
+//!
+//! - `ROUTER_RS_TOPOLOGY`, `ROUTER_RS_NODE`, `ROUTER_RS_NETNS`
+//! - `ROUTER_RS_STATE`: saved state of the topology, JSON, see `State`
+//! - `ROUTER_RS_INTERFACES`: names of the node's interfaces
+//! - `ROUTER_RS_PEERS`: nodes on the other end of its links and bridges
+//!
+//! and for each interface, its name upper-cased with anything but letters
+//! and digits as `_`, e.g. `ROUTER_RS_R1_L1_IP` for `r1_l1`:
+//!
+//! - `ROUTER_RS_<IF>_IP`, `ROUTER_RS_<IF>_IP6`: its addresses with prefix
+//! - `ROUTER_RS_<IF>_PEERS`: nodes on the same link or bridge
+//! - `ROUTER_RS_<IF>_PEER_IP`, `ROUTER_RS_<IF>_PEER_IP6`: their addresses
+//!
+//! Lists are space separated. Interfaces and peers are taken from the
+//! state as saved when the program starts, processes started while a
+//! topology is built see them once it was built before.
+
+use std::process::Command;
+
+use crate::error::Result;
+use crate::interface;
+use crate::state::State;
+use crate::Namespace;
+
+/// Prefix of all variables.
+pub const PREFIX: &str = "ROUTER_RS_";
+
+/// Variables for programs in the namespace `namespace` of `topology`, only
+/// those naming the node if the topology has no saved state.
+pub fn variables(topology: &str, namespace: &str) -> Result<Vec<(String, String)>>{
+    let netns = Namespace::netns_name(topology, namespace);
+    let mut vars = vec![
+        var("TOPOLOGY", topology),
+        var("NODE", namespace),
+        var("NETNS", &netns),
+        var("STATE", &State::path(topology).to_string_lossy()),
+    ];
+    let Some(state) = State::load(topology)? else {
+        return Ok(vars);
+    };
+    // interfaces of other nodes on the segment `segment`
+    let peers = |segment: &str| -> Vec<(&str, Option<&str>, Option<&str>)> {
+        let mut peers = Vec::new();
+        for ns in state.namespaces.iter().filter(|ns| ns.netns != netns){
+            let name = interface::name(&ns.name, segment);
+            for i in state.interfaces.iter().filter(|i| i.name == name && i.netns.as_deref() == Some(ns.netns.as_str())){
+                peers.push((ns.name.as_str(), i.ip.as_deref(), i.ip6.as_deref()));
+            }
+        }
+        peers
+    };
+    let segments: Vec<&str> = state.links.iter()
+        .chain(&state.bridges)
+        .chain(&state.vxlans)
+        .chain(&state.tunnels)
+        .chain(&state.wireguards)
+        .map(|s| s.name.as_str())
+        .collect();
+    let (mut interfaces, mut nodes) = (Vec::new(), Vec::new());
+    for i in state.interfaces.iter().filter(|i| i.netns.as_deref() == Some(netns.as_str())){
+        interfaces.push(i.name.as_str());
+        let key = i.name.to_uppercase().replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+        vars.push(var(&format!("{}_IP", key), i.ip.as_deref().unwrap_or_default()));
+        vars.push(var(&format!("{}_IP6", key), i.ip6.as_deref().unwrap_or_default()));
+        let segment = segments.iter().find(|s| i.name == interface::name(namespace, s));
+        let peers = segment.map(|s| peers(s)).unwrap_or_default();
+        let names: Vec<&str> = peers.iter().map(|p| p.0).collect();
+        vars.push(var(&format!("{}_PEERS", key), &names.join(" ")));
+        vars.push(var(&format!("{}_PEER_IP", key), &peers.iter().filter_map(|p| p.1).collect::<Vec<_>>().join(" ")));
+        vars.push(var(&format!("{}_PEER_IP6", key), &peers.iter().filter_map(|p| p.2).collect::<Vec<_>>().join(" ")));
+        for name in names{
+            if !nodes.contains(&name) {
+                nodes.push(name);
+            }
+        }
+    }
+    vars.push(var("INTERFACES", &interfaces.join(" ")));
+    vars.push(var("PEERS", &nodes.join(" ")));
+    Ok(vars)
+}
+
+/// Sets the variables of `namespace` of `topology` on `cmd`.
+pub fn apply(cmd: &mut Command, topology: &str, namespace: &str) -> Result<()>{
+    cmd.envs(variables(topology, namespace)?);
+    Ok(())
+}
+
+fn var(name: &str, value: &str) -> (String, String) {
+    (format!("{}{}", PREFIX, name), value.to_string())
+}
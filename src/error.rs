@@ -0,0 +1,135 @@
+//! Failures of the library as values to match on rather than messages to
+//! parse. Everything the crate exposes returns `RouterError`: the building
+//! blocks (namespaces, links, interfaces, bridges, VXLAN links, tunnels,
+//! VRFs, loopbacks and the IPAM), the lifecycle of a topology (`apply`,
+//! `build`, `reconcile`, `destroy` and their guards) and the tools running
+//! against a topology.
+//!
+//! Failures without a variant of their own are `Failed` with their
+//! description. Errors of parsing and formatting on the way come out as
+//! `Other`, as does an `anyhow::Error` converted with `RouterError::from`
+//! unless it wraps a `RouterError`, which is recovered.
+//! Context added on the way up wraps the error, `root` looks through it.
+
+use std::fmt::Display;
+use std::process::{Command, Output};
+
+pub type Result<T, E = RouterError> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RouterError{
+    #[error("Topology {0} already exists")]
+    TopologyExists(String),
+    #[error("Topology {0} not found")]
+    TopologyNotFound(String),
+    #[error("Namespace {0} already exists")]
+    NamespaceExists(String),
+    #[error("Namespace {0} not found")]
+    NamespaceNotFound(String),
+    /// link, bridge, VXLAN link, tunnel, interface or VRF `name` exists
+    #[error("{kind} {name} already exists")]
+    Exists{ kind: &'static str, name: String },
+    #[error("{kind} {name} not found")]
+    NotFound{ kind: &'static str, name: String },
+    /// a program run to change the kernel failed
+    #[error("Failed to run {cmd}: {stderr}")]
+    CommandFailed{ cmd: String, stderr: String },
+    /// a program could not be started at all
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// the JSON output of a program could not be parsed
+    #[error("Failed to parse JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid subnet {subnet}: {reason}")]
+    InvalidSubnet{ subnet: String, reason: String },
+    #[error("Invalid address {address}: {reason}")]
+    InvalidAddress{ address: String, reason: String },
+    #[error("Subnet {subnet} overlaps subnet {other} already in use")]
+    SubnetOverlap{ subnet: String, other: String },
+    #[error("Address pool {pool} overlaps pool {other}")]
+    PoolOverlap{ pool: String, other: String },
+    /// `pool` names family and kind, e.g. "IPv4 address"
+    #[error("No {pool} pool configured")]
+    NoPool{ pool: String },
+    #[error("{pool} pools are exhausted")]
+    PoolExhausted{ pool: String },
+    #[error("Interface name {name} is longer than the {max} characters the kernel takes")]
+    NameTooLong{ name: String, max: usize },
+    #[error("Invalid interface name {name:?}: {reason}")]
+    InvalidName{ name: String, reason: String },
+    /// the topology would change the host outside its namespaces without
+    /// being allowed to, see `preflight::host_changes`
+    #[error("Topology {topology} changes the host outside its namespaces, allow it explicitly to:\n  {}", changes.join("\n  "))]
+    HostChanges{ topology: String, changes: Vec<String> },
+    /// subnets, addresses and routes of the topology don't fit together,
+    /// see `validate`
+    #[error("Topology {topology} has {} addressing problems:\n  {}", problems.len(), problems.join("\n  "))]
+    Addressing{ topology: String, problems: Vec<String> },
+    /// a description the kernel was never asked about is inconsistent
+    #[error("{0}")]
+    Invalid(String),
+    /// a build failed and undoing it failed as well
+    #[error("{error} ({rollback})")]
+    RollbackFailed{ error: Box<RouterError>, rollback: String },
+    #[error("{context}: {error}")]
+    Context{ context: String, error: Box<RouterError> },
+    /// any other failure, described
+    #[error("{0}")]
+    Failed(String),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl RouterError{
+    /// `cmd` exited with the unsuccessful `output`.
+    pub(crate) fn command(cmd: &Command, output: &Output) -> RouterError {
+        let args: Vec<_> = cmd.get_args().map(|a| a.to_string_lossy()).collect();
+        RouterError::CommandFailed{
+            cmd: format!("{} {}", cmd.get_program().to_string_lossy(), args.join(" ")),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        }
+    }
+
+    pub(crate) fn context(self, context: impl Display) -> RouterError {
+        RouterError::Context{ context: context.to_string(), error: Box::new(self) }
+    }
+
+    /// The error without the context added on the way up.
+    pub fn root(&self) -> &RouterError {
+        match self{
+            RouterError::Context{ error, .. } => error.root(),
+            e => e,
+        }
+    }
+}
+
+/// Failures of parsing and formatting along the way come out as `Other`.
+macro_rules! other{
+    ($($t:ty),*) => {
+        $(impl From<$t> for RouterError{
+            fn from(e: $t) -> RouterError {
+                RouterError::Other(e.into())
+            }
+        })*
+    };
+}
+
+other!(std::fmt::Error, std::net::AddrParseError, std::num::ParseIntError, std::array::TryFromSliceError,
+    ipnet::AddrParseError, ipnet::PrefixLenError, serde_yaml::Error, toml::ser::Error, toml::de::Error);
+
+impl From<anyhow::Error> for RouterError{
+    fn from(e: anyhow::Error) -> RouterError {
+        match e.downcast::<RouterError>(){
+            Ok(e) => e,
+            Err(e) => RouterError::Other(e),
+        }
+    }
+}
+
+/// `RouterError::Failed` with a message formatted as by `format!`.
+macro_rules! failed{
+    ($($arg:tt)*) => {
+        $crate::error::RouterError::Failed(format!($($arg)*))
+    };
+}
+pub(crate) use failed;
@@ -0,0 +1,252 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{failed, Result};
+
+/// Directory under which every run stores its `result.json`.
+pub const RUNS_DIR: &str = "runs";
+
+/// Outcome of a single experiment run. All maps are keyed by a free-form
+/// identifier (endpoint pair, flow or scenario step) so two runs of the same
+/// experiment line up key by key.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct RunResult{
+    #[serde(default)]
+    pub name: String,
+    /// pass/fail per verification check
+    #[serde(default)]
+    pub verifications: BTreeMap<String, bool>,
+    /// Mbit/s
+    #[serde(default)]
+    pub throughput: BTreeMap<String, f64>,
+    /// packet loss in percent
+    #[serde(default)]
+    pub loss: BTreeMap<String, f64>,
+    /// milliseconds
+    #[serde(default)]
+    pub convergence: BTreeMap<String, f64>,
+}
+
+impl RunResult{
+    /// Loads a run either from an explicit path (a `result.json` file or a
+    /// run directory) or by run name from `RUNS_DIR`.
+    pub fn load(run: &str) -> Result<RunResult>{
+        let path = Self::resolve(run);
+        let data = std::fs::read_to_string(&path)
+            .map_err(|e| failed!("Failed to read run {}: {}", path.display(), e))?;
+        let mut result: RunResult = serde_json::from_str(&data)
+            .map_err(|e| failed!("Failed to parse run {}: {}", path.display(), e))?;
+        if result.name.is_empty(){
+            result.name = run.to_string();
+        }
+        Ok(result)
+    }
+
+    /// Stores the run as `result.json` of run `run` in `RUNS_DIR`.
+    pub fn save(&self, run: &str) -> Result<PathBuf>{
+        let dir = Path::new(RUNS_DIR).join(run);
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| failed!("Failed to create run directory {}: {}", dir.display(), e))?;
+        let path = dir.join("result.json");
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .map_err(|e| failed!("Failed to write run {}: {}", path.display(), e))?;
+        Ok(path)
+    }
+
+    fn resolve(run: &str) -> PathBuf{
+        let path = Path::new(run);
+        if path.is_file(){
+            return path.to_path_buf();
+        }
+        if path.is_dir(){
+            return path.join("result.json");
+        }
+        Path::new(RUNS_DIR).join(run).join("result.json")
+    }
+}
+
+/// Allowed drift before a change counts as a regression.
+pub struct Tolerance{
+    /// relative throughput drop in percent
+    pub throughput: f64,
+    /// absolute loss increase in percentage points
+    pub loss: f64,
+    /// relative convergence time increase in percent
+    pub convergence: f64,
+}
+
+impl Default for Tolerance{
+    fn default() -> Self {
+        Tolerance{
+            throughput: 5.0,
+            loss: 0.5,
+            convergence: 10.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Metric{
+    Verification,
+    Throughput,
+    Loss,
+    Convergence,
+}
+
+impl fmt::Display for Metric{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Metric::Verification => write!(f, "verify"),
+            Metric::Throughput => write!(f, "throughput"),
+            Metric::Loss => write!(f, "loss"),
+            Metric::Convergence => write!(f, "convergence"),
+        }
+    }
+}
+
+pub struct Delta{
+    pub metric: Metric,
+    pub key: String,
+    pub a: Option<String>,
+    pub b: Option<String>,
+    pub regression: bool,
+}
+
+pub struct Comparison{
+    pub a: String,
+    pub b: String,
+    pub deltas: Vec<Delta>,
+}
+
+impl Comparison{
+    pub fn regressions(&self) -> usize {
+        self.deltas.iter().filter(|d| d.regression).count()
+    }
+}
+
+impl fmt::Display for Comparison{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<12} {:<32} {:>14} {:>14}", "METRIC", "KEY", self.a, self.b)?;
+        for d in &self.deltas{
+            writeln!(f, "{:<12} {:<32} {:>14} {:>14}{}",
+                d.metric.to_string(),
+                d.key,
+                d.a.as_deref().unwrap_or("-"),
+                d.b.as_deref().unwrap_or("-"),
+                if d.regression { "  REGRESSION" } else { "" },
+            )?;
+        }
+        write!(f, "{} regression(s)", self.regressions())
+    }
+}
+
+/// Diffs run `b` against baseline run `a`. A metric missing from `b` that was
+/// present in `a` (or a check that used to pass) counts as a regression, a
+/// metric only present in `b` does not.
+pub fn compare(a: &RunResult, b: &RunResult, tolerance: &Tolerance) -> Comparison {
+    let mut deltas = Vec::new();
+
+    for key in keys(&a.verifications, &b.verifications){
+        let va = a.verifications.get(key).copied();
+        let vb = b.verifications.get(key).copied();
+        deltas.push(Delta{
+            metric: Metric::Verification,
+            key: key.clone(),
+            a: va.map(pass_fail),
+            b: vb.map(pass_fail),
+            regression: va == Some(true) && vb != Some(true),
+        });
+    }
+    compare_values(&mut deltas, Metric::Throughput, &a.throughput, &b.throughput, |va, vb| {
+        vb < va * (1.0 - tolerance.throughput / 100.0)
+    });
+    compare_values(&mut deltas, Metric::Loss, &a.loss, &b.loss, |va, vb| {
+        vb > va + tolerance.loss
+    });
+    compare_values(&mut deltas, Metric::Convergence, &a.convergence, &b.convergence, |va, vb| {
+        vb > va * (1.0 + tolerance.convergence / 100.0)
+    });
+
+    Comparison{
+        a: a.name.clone(),
+        b: b.name.clone(),
+        deltas,
+    }
+}
+
+fn compare_values<F>(deltas: &mut Vec<Delta>, metric: Metric, a: &BTreeMap<String, f64>, b: &BTreeMap<String, f64>, regressed: F)
+where F: Fn(f64, f64) -> bool {
+    for key in keys(a, b){
+        let va = a.get(key).copied();
+        let vb = b.get(key).copied();
+        let regression = match (va, vb){
+            (Some(va), Some(vb)) => regressed(va, vb),
+            (Some(_), None) => true,
+            _ => false,
+        };
+        deltas.push(Delta{
+            metric,
+            key: key.clone(),
+            a: va.map(|v| format!("{:.2}", v)),
+            b: vb.map(|v| format!("{:.2}", v)),
+            regression,
+        });
+    }
+}
+
+fn keys<'a, V>(a: &'a BTreeMap<String, V>, b: &'a BTreeMap<String, V>) -> BTreeSet<&'a String> {
+    a.keys().chain(b.keys()).collect()
+}
+
+fn pass_fail(v: bool) -> String {
+    if v { "pass".to_string() } else { "fail".to_string() }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn run(name: &str, passed: bool, throughput: f64, loss: f64, convergence: f64) -> RunResult {
+        RunResult{
+            name: name.to_string(),
+            verifications: BTreeMap::from([("r1-r4".to_string(), passed)]),
+            throughput: BTreeMap::from([("r1-r4".to_string(), throughput)]),
+            loss: BTreeMap::from([("r1-r4".to_string(), loss)]),
+            convergence: BTreeMap::from([("link down".to_string(), convergence)]),
+        }
+    }
+
+    fn regressed(c: &Comparison) -> Vec<Metric> {
+        c.deltas.iter().filter(|d| d.regression).map(|d| d.metric).collect()
+    }
+
+    #[test]
+    fn changes_within_tolerance_are_no_regression(){
+        let c = compare(&run("a", true, 1000.0, 0.1, 200.0), &run("b", true, 960.0, 0.5, 215.0), &Tolerance::default());
+        assert_eq!(c.deltas.len(), 4);
+        assert_eq!(c.regressions(), 0);
+    }
+
+    #[test]
+    fn changes_beyond_tolerance_are_regressions(){
+        let c = compare(&run("a", true, 1000.0, 0.1, 200.0), &run("b", false, 940.0, 0.7, 230.0), &Tolerance::default());
+        assert_eq!(regressed(&c), vec![Metric::Verification, Metric::Throughput, Metric::Loss, Metric::Convergence]);
+        // improvements never are
+        let c = compare(&run("b", false, 940.0, 0.7, 230.0), &run("a", true, 1000.0, 0.1, 200.0), &Tolerance::default());
+        assert_eq!(c.regressions(), 0);
+    }
+
+    #[test]
+    fn metrics_missing_from_the_new_run_are_regressions(){
+        let a = run("a", true, 1000.0, 0.1, 200.0);
+        let mut b = a.clone();
+        b.throughput.clear();
+        b.loss.insert("r2-r3".to_string(), 0.0);
+        let c = compare(&a, &b, &Tolerance::default());
+        assert_eq!(regressed(&c), vec![Metric::Throughput]);
+        let added = c.deltas.iter().find(|d| d.key == "r2-r3").unwrap();
+        assert_eq!((added.a.as_deref(), added.b.as_deref()), (None, Some("0.00")));
+    }
+}
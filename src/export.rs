@@ -0,0 +1,809 @@
+//! Renders a topology as the equivalent sequence of iproute2 commands, for
+//! hosts where the router-rs binary can't run, or as containerlab topology
+//! file or Mininet script, see `containerlab`.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::containerlab;
+use crate::error::{failed, Result, RouterError};
+use crate::firewall;
+use crate::group;
+use crate::interface;
+use crate::ipam::Ipam;
+use crate::link::{host_addr, peer_routes};
+use crate::loopback;
+use crate::nat64::{self, Translator};
+use crate::ovs;
+use crate::p4::{self, P4Switch};
+use crate::passthrough::PassthroughKind;
+use crate::paths;
+use crate::queue::QueueSpec;
+use crate::ra::{self, Advertiser};
+use crate::topology::{NexthopSpec, Topology};
+use crate::vxlan::overlay_addr;
+use crate::{BridgeBackend, Namespace, Nexthop, Route, RouteKind, TunnelKind, Veth, VxlanLink, WireguardLink};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format{
+    Iproute2,
+    Containerlab,
+    Mininet,
+}
+
+impl FromStr for Format{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<Self>{
+        match s{
+            "iproute2" => Ok(Format::Iproute2),
+            "containerlab" | "clab" => Ok(Format::Containerlab),
+            "mininet" => Ok(Format::Mininet),
+            _ => Err(RouterError::Invalid(format!("Unknown export format {}, expected iproute2, containerlab or mininet", s))),
+        }
+    }
+}
+
+pub fn export(topology: &Topology, format: Format) -> Result<String>{
+    match format{
+        Format::Iproute2 => iproute2(topology),
+        Format::Containerlab => containerlab::containerlab(topology),
+        Format::Mininet => containerlab::mininet(topology),
+    }
+}
+
+/// Shell script creating the same namespaces, veths, bonds, bridges, VXLAN links,
+/// tunnels, WireGuard links, addresses and routes as `Topology::build`. WireGuard keys
+/// are generated by the script with `wg`. Clock skew can't be set up ahead of time
+/// and is only noted as a comment.
+pub fn iproute2(topology: &Topology) -> Result<String>{
+    let mut s = String::new();
+    let netns = |ns: &str| Namespace::netns_name(&topology.name, ns);
+    writeln!(s, "#!/bin/sh")?;
+    writeln!(s, "# topology {}", topology.name)?;
+    writeln!(s, "set -e")?;
+
+    if let Some(gc) = &topology.neighbor_gc{
+        let settings: Vec<String> = gc.settings().iter().map(|(k, v)| format!("{}={}", k.replace('/', "."), v)).collect();
+        if !settings.is_empty() {
+            writeln!(s, "\n# neighbor table thresholds, shared with the host")?;
+            writeln!(s, "sysctl -qw {}", settings.join(" "))?;
+        }
+    }
+
+    writeln!(s, "\n# namespaces")?;
+    for ns in &topology.namespaces{
+        let n = netns(&ns.name);
+        match &ns.container{
+            Some(c) => {
+                let pid = match (&c.name, c.pid){
+                    (Some(name), None) => c.runtime.pid_command(name),
+                    (None, Some(pid)) => pid.to_string(),
+                    _ => return Err(failed!("Container of namespace {} needs either a name or a pid", ns.name)),
+                };
+                writeln!(s, "ip netns attach {} {}", n, pid)?;
+            },
+            None => writeln!(s, "ip netns add {}", n)?,
+        }
+        let sysctls = topology.sysctls(ns).map_err(|e| failed!("Namespace {}: {}", ns.name, e))?;
+        if !sysctls.is_empty() {
+            writeln!(s, "ip netns exec {} sysctl -qw {}", n, sysctls.join(" "))?;
+        }
+        // before the interfaces are created, which take the default
+        let nexthops: Vec<&NexthopSpec> = topology.routes.iter().filter(|r| r.namespace == ns.name).flat_map(|r| &r.nexthops).collect();
+        if ns.srv6 || nexthops.iter().any(|n| n.seg6.is_some() || n.seg6local.is_some()) {
+            writeln!(s, "ip netns exec {} sysctl -qw net.ipv6.conf.all.seg6_enabled=1 net.ipv6.conf.default.seg6_enabled=1", n)?;
+        }
+        if nexthops.iter().any(|n| n.seg6local.as_ref().is_some_and(|s| s.vrf())) {
+            writeln!(s, "ip netns exec {} sysctl -qw net.vrf.strict_mode=1", n)?;
+        }
+        if let Some(clock) = &ns.clock{
+            writeln!(s, "# run programs in {} with: ip netns exec {} unshare --time --fork --monotonic={} --boottime={} <program>",
+                ns.name, n, clock.monotonic, clock.boottime)?;
+        }
+    }
+
+    // interface name -> (ipv4, ipv6), needed to resolve gateways
+    let mut interfaces: HashMap<String, (Option<String>, Option<String>)> = HashMap::new();
+    // link or bridge name -> assigned subnets, for generated routes
+    let mut subnets = HashMap::new();
+    let mut ipam = Ipam::default();
+    if let Some(spec) = &topology.ipam{
+        ipam.configure(spec)?;
+    }
+    if topology.namespaces.iter().any(|n| n.loopback.is_some()) {
+        writeln!(s, "\n# loopbacks")?;
+    }
+    for ns in &topology.namespaces{
+        let Some(lo) = &ns.loopback else {
+            continue;
+        };
+        let (ip, ip6) = ipam.assign_loopback(lo.address.clone(), lo.address6.clone())
+            .map_err(|e| failed!("Loopback of {}: {}", ns.name, e))?;
+        let name = loopback::name(&ns.name);
+        writeln!(s, "ip -n {} link add {} type dummy", netns(&ns.name), name)?;
+        altname(&mut s, &netns(&ns.name), &name, &format!("{}_lo", ns.name))?;
+        interface(&mut s, &netns(&ns.name), &name, ip.as_deref(), ip6.as_deref(), None)?;
+        match (&ip, &ip6){
+            (Some(ip), ip6) => subnets.insert(name.clone(), (ip.clone(), ip6.clone())),
+            (None, Some(ip6)) => subnets.insert(name.clone(), (ip6.clone(), None)),
+            (None, None) => None,
+        };
+        interfaces.insert(name, (ip, ip6));
+    }
+    for ns in &topology.namespaces{
+        let Some(nat64) = &ns.nat64 else {
+            continue;
+        };
+        let name = nat64::name(&ns.name);
+        let dir = Translator::dir(&topology.name, &ns.name);
+        let (ip, ip6) = nat64.addresses()?;
+        writeln!(s, "\n# NAT64 of {}", ns.name)?;
+        writeln!(s, "ip -n {} tuntap add dev {} mode tun", netns(&ns.name), name)?;
+        altname(&mut s, &netns(&ns.name), &name, &format!("{}_nat64", ns.name))?;
+        interface(&mut s, &netns(&ns.name), &name, Some(&ip), Some(&ip6), None)?;
+        writeln!(s, "mkdir -p {}", dir.display())?;
+        writeln!(s, "cat > {}/tayga.cfg <<'EOF'\n{}EOF", dir.display(), nat64.tayga_config(&name, &dir)?)?;
+        writeln!(s, "ip netns exec {} tayga --config {}/tayga.cfg --pidfile {}/tayga.pid", netns(&ns.name), dir.display(), dir.display())?;
+        subnets.insert(name.clone(), (nat64.pool()?.to_string(), Some(nat64.prefix()?.to_string())));
+        interfaces.insert(name, (Some(ip), Some(ip6)));
+    }
+    if !topology.links.is_empty() || !topology.bridges.is_empty() {
+        writeln!(s, "\n# links")?;
+    }
+    // VXLAN link or tunnel -> assigned subnets, created once the underlay
+    // exists
+    let mut overlays = HashMap::new();
+    // same order as Topology::build, which decides the allocated subnets
+    for auto in [false, true]{
+        for l in topology.links.iter().filter(|l| l.subnet.is_empty() == auto){
+            if l.endpoints.len() != 2 {
+                return Err(failed!("Link {} needs exactly two endpoints, got {}", l.name, l.endpoints.len()));
+            }
+            let ends = l.ends()?;
+            let (subnet, subnet6) = match ends.unnumbered{
+                true => (String::new(), None),
+                false => ipam.assign(l.subnet.clone(), l.subnet6.clone())
+                    .map_err(|e| failed!("Link {}: {}", l.name, e))?,
+            };
+            subnets.insert(l.name.clone(), (subnet.clone(), subnet6.clone()));
+            let names: Vec<String> = l.endpoints.iter().map(|ns| interface::name(ns, &l.name)).collect();
+            let queues = topology.link_queues(l);
+            match &l.bond{
+                Some(bond) => {
+                    for (name, ns) in names.iter().zip(&l.endpoints){
+                        writeln!(s, "ip -n {} link add name {} type bond {}", netns(ns), name, bond.args().join(" "))?;
+                    }
+                    let members = [bond.members(&l.endpoints[0], &l.name), bond.members(&l.endpoints[1], &l.name)];
+                    for (m1, m2) in members[0].iter().zip(&members[1]){
+                        writeln!(s, "ip link add name {} netns {} type veth peer name {} netns {}",
+                            m1, netns(&l.endpoints[0]), m2, netns(&l.endpoints[1]))?;
+                        for (m, (name, ns)) in [m1, m2].into_iter().zip(names.iter().zip(&l.endpoints)){
+                            writeln!(s, "ip -n {} link set dev {} master {} up", netns(ns), m, name)?;
+                        }
+                    }
+                },
+                None => {
+                    let veth = Veth{
+                        name: names[0].clone(),
+                        namespace: netns(&l.endpoints[0]),
+                        peer: names[1].clone(),
+                        peer_namespace: netns(&l.endpoints[1]),
+                        numtxqueues: queues.numtxqueues,
+                        numrxqueues: queues.numrxqueues,
+                    };
+                    let args = veth.queue_args().iter().map(|a| format!(" {}", a)).collect::<String>();
+                    writeln!(s, "ip link add name {} netns {}{} type veth peer name {} netns {}{}",
+                        veth.name, veth.namespace, args, veth.peer, veth.peer_namespace, args)?;
+                },
+            }
+            for (name, ns) in names.iter().zip(&l.endpoints){
+                altname(&mut s, &netns(ns), name, &format!("{}_{}", ns, l.name))?;
+            }
+            let (mut ips, mut ips6) = ([None, None], [None, None]);
+            for subnet in std::iter::once(&subnet).chain(subnet6.iter()).filter(|_| !ends.unnumbered){
+                let sn: ipnet::IpNet = subnet.parse()?;
+                let (a, b) = ends.addrs(&sn).map_err(|e| failed!("Link {}: {}", l.name, e))?;
+                if sn.addr().is_ipv6() {
+                    ips6 = [Some(a), Some(b)];
+                } else {
+                    ips = [Some(a), Some(b)];
+                }
+            }
+            for (n, ns) in l.endpoints.iter().enumerate().filter(|_| ends.unnumbered){
+                let (ip, ip6) = interfaces.get(&loopback::name(ns))
+                    .ok_or_else(|| failed!("Unnumbered link {} needs a loopback in {}", l.name, ns))?;
+                (ips[n], ips6[n]) = (ip.clone(), ip6.clone());
+            }
+            let macs = topology.link_macs(l)?;
+            for (host, (name, ns)) in names.iter().zip(&l.endpoints).enumerate(){
+                let (ip, ip6) = (ips[host].clone(), ips6[host].clone());
+                if let Some(mac) = &macs[host]{
+                    writeln!(s, "ip -n {} link set dev {} address {}", netns(ns), name, mac)?;
+                }
+                interface(&mut s, &netns(ns), name, ip.as_deref(), ip6.as_deref(), Some(3000))?;
+                if ends.unnumbered {
+                    for args in peer_routes(name, [&ips[host], &ips6[host]], [&ips[1 - host], &ips6[1 - host]]){
+                        writeln!(s, "ip -n {} {}", netns(ns), args.join(" "))?;
+                    }
+                }
+                interfaces.insert(name.clone(), (ip, ip6));
+                match l.qos_at(ns){
+                    Some(qos) => {
+                        for args in qos.commands(name).map_err(|e| failed!("Link {}: {}", l.name, e))?{
+                            writeln!(s, "ip netns exec {} tc {}", netns(ns), args.join(" "))?;
+                        }
+                        queue(&mut s, &netns(ns), name, &QueueSpec{ qdisc: None, ..queues.clone() })?;
+                    },
+                    None => queue(&mut s, &netns(ns), name, &queues)?,
+                }
+            }
+        }
+        for b in topology.bridges.iter().filter(|b| b.subnet.is_empty() == auto){
+            let (subnet, subnet6) = ipam.assign_hosts(b.subnet.clone(), b.subnet6.clone(), b.members.len() as u128)
+                .map_err(|e| failed!("Bridge {}: {}", b.name, e))?;
+            subnets.insert(b.name.clone(), (subnet.clone(), subnet6.clone()));
+            let bridge_ns = match &b.namespace{
+                Some(ns) => netns(ns),
+                None => {
+                    let n = netns(&b.name);
+                    writeln!(s, "ip netns add {}", n)?;
+                    writeln!(s, "ip netns exec {} sysctl -qw net.ipv4.ip_forward=1 net.ipv6.conf.all.forwarding=1", n)?;
+                    n
+                },
+            };
+            let dir = ovs::Switch::dir(&topology.name, &b.name);
+            let env = format!("OVS_RUNDIR={0} OVS_DBDIR={0} OVS_SYSCONFDIR={0}", dir.display());
+            let vsctl = format!("{} ovs-vsctl --db=unix:{}/db.sock", env, dir.display());
+            if b.backend == BridgeBackend::Ovs {
+                writeln!(s, "mkdir -p {}", dir.display())?;
+                writeln!(s, "ovsdb-tool create {}/conf.db {}", dir.display(), ovs::SCHEMA)?;
+                writeln!(s, "{} ip netns exec {} ovsdb-server {2}/conf.db --remote=punix:{2}/db.sock --pidfile={2}/ovsdb-server.pid --detach",
+                    env, bridge_ns, dir.display())?;
+                writeln!(s, "{} --no-wait init", vsctl)?;
+                writeln!(s, "{} ip netns exec {} ovs-vswitchd unix:{}/db.sock --pidfile={2}/ovs-vswitchd.pid --detach", env, bridge_ns, dir.display())?;
+                writeln!(s, "{} add-br {} -- set bridge {1} datapath_type=netdev", vsctl, b.name)?;
+            } else {
+                writeln!(s, "ip -n {} link add name {} type bridge", bridge_ns, b.name)?;
+            }
+            writeln!(s, "ip -n {} link set dev {} up", bridge_ns, b.name)?;
+            let subnets = std::iter::once(&subnet).chain(subnet6.iter())
+                .map(|s| s.parse::<ipnet::IpNet>())
+                .collect::<Result<Vec<_>, _>>()?;
+            for (n, ns) in b.members.iter().enumerate(){
+                let name = interface::name(ns, &b.name);
+                let port = interface::name(&b.name, ns);
+                writeln!(s, "ip link add name {} netns {} type veth peer name {} netns {}", name, netns(ns), port, bridge_ns)?;
+                altname(&mut s, &netns(ns), &name, &format!("{}_{}", ns, b.name))?;
+                altname(&mut s, &bridge_ns, &port, &format!("{}_{}", b.name, ns))?;
+                if b.backend == BridgeBackend::Ovs {
+                    writeln!(s, "ip -n {} link set dev {} mtu 3000 up", bridge_ns, port)?;
+                    writeln!(s, "{} add-port {} {}", vsctl, b.name, port)?;
+                } else {
+                    writeln!(s, "ip -n {} link set dev {} master {} mtu 3000 up", bridge_ns, port, b.name)?;
+                }
+                let (mut ip, mut ip6) = (None, None);
+                for sn in &subnets{
+                    let addr = host_addr(sn, n as u128 + 1)?;
+                    if sn.addr().is_ipv6() {
+                        ip6 = Some(addr);
+                    } else {
+                        ip = Some(addr);
+                    }
+                }
+                interface(&mut s, &netns(ns), &name, ip.as_deref(), ip6.as_deref(), Some(3000))?;
+                interfaces.insert(name, (ip, ip6));
+            }
+            if !b.flows.is_empty() {
+                let ofctl = format!("{} ovs-ofctl", env);
+                let mgmt = format!("unix:{}/{}.mgmt", dir.display(), b.name);
+                writeln!(s, "{} del-flows {}", ofctl, mgmt)?;
+                for flow in &b.flows{
+                    writeln!(s, "{} add-flow {} '{}'", ofctl, mgmt, flow)?;
+                }
+            }
+        }
+        for v in topology.vxlans.iter().filter(|v| v.subnet.is_empty() == auto){
+            let subnets = ipam.assign(v.subnet.clone(), v.subnet6.clone())
+                .map_err(|e| failed!("VXLAN link {}: {}", v.name, e))?;
+            overlays.insert(v.name.clone(), subnets);
+        }
+        for t in topology.tunnels.iter().filter(|t| t.subnet.is_empty() == auto){
+            let subnets = ipam.assign(t.subnet.clone(), t.subnet6.clone())
+                .map_err(|e| failed!("Tunnel {}: {}", t.name, e))?;
+            overlays.insert(t.name.clone(), subnets);
+        }
+        for w in topology.wireguards.iter().filter(|w| w.subnet.is_empty() == auto){
+            let subnets = ipam.assign(w.subnet.clone(), w.subnet6.clone())
+                .map_err(|e| failed!("WireGuard link {}: {}", w.name, e))?;
+            overlays.insert(w.name.clone(), subnets);
+        }
+    }
+
+    if !topology.interfaces.is_empty() {
+        writeln!(s, "\n# host interfaces")?;
+    }
+    for i in &topology.interfaces{
+        let ns = match (&i.namespace, &i.passthrough){
+            (Some(ns), Some(p)) if p.kind != PassthroughKind::Move => {
+                writeln!(s, "ip link add link {} name {} netns {} type {} mode {}", p.nic, i.name, netns(ns), p.kind, p.mode())?;
+                netns(ns)
+            },
+            (Some(ns), _) => {
+                writeln!(s, "ip link set {} netns {}", i.host_name(), netns(ns))?;
+                if i.host_name() != i.name {
+                    writeln!(s, "ip -n {} link set dev {} name {}", netns(ns), i.host_name(), i.name)?;
+                }
+                netns(ns)
+            },
+            (None, _) => String::new(),
+        };
+        interface(&mut s, &ns, &i.name, i.ip.as_deref(), i.ip6.as_deref(), i.mtu)?;
+        if let Some(queues) = &i.queues{
+            queue(&mut s, &ns, &i.name, queues)?;
+        }
+        interfaces.insert(i.name.clone(), (i.ip.clone(), i.ip6.clone()));
+    }
+
+    if !topology.tunnels.is_empty() {
+        writeln!(s, "\n# tunnels")?;
+    }
+    for t in &topology.tunnels{
+        let (subnet, subnet6) = &overlays[&t.name];
+        let subnets = std::iter::once(subnet).chain(subnet6.iter())
+            .map(|s| s.parse::<ipnet::IpNet>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut ends = Vec::new();
+        for e in &t.endpoints{
+            let (local, dev) = underlay(&interfaces, &e.local)
+                .map_err(|err| failed!("Tunnel {}: {}", t.name, err))?;
+            ends.push((e, local, dev));
+        }
+        let locals: Vec<String> = ends.iter().map(|(_, l, _)| l.clone()).chain(t.remote.clone()).collect();
+        if locals.len() != 2 {
+            return Err(failed!("Tunnel {} needs two ends, or one and a remote, got {}", t.name, locals.len()));
+        }
+        let v6 = locals[0].contains(':');
+        let device = t.kind.device(v6)
+            .ok_or_else(|| failed!("{} tunnel {} needs IPv4 endpoints", t.kind, t.name))?;
+        for (n, (e, local, dev)) in ends.iter().enumerate(){
+            let name = interface::name(&e.namespace, &t.name);
+            let mut add = format!("ip -n {} link add name {} type {} local {} remote {}", netns(&e.namespace), name, device, local, locals[1 - n]);
+            if let Some(key) = t.key{
+                write!(add, " key {}", key)?;
+            }
+            if let Some(ttl) = t.ttl{
+                write!(add, " ttl {}", ttl)?;
+            }
+            if let Some(dev) = dev{
+                write!(add, " dev {}", dev)?;
+            }
+            if t.kind == TunnelKind::Sit {
+                add.push_str(" mode any");
+            }
+            writeln!(s, "{}", add)?;
+            altname(&mut s, &netns(&e.namespace), &name, &format!("{}_{}", e.namespace, t.name))?;
+            let (mut ip, mut ip6) = (None, None);
+            for sn in &subnets{
+                let addr = overlay_addr(sn, n, true, e.host)?;
+                if sn.addr().is_ipv6() {
+                    ip6 = Some(addr);
+                } else {
+                    ip = Some(addr);
+                }
+            }
+            interface(&mut s, &netns(&e.namespace), &name, ip.as_deref(), ip6.as_deref(), None)?;
+            interfaces.insert(name, (ip, ip6));
+        }
+    }
+
+    if !topology.wireguards.is_empty() {
+        writeln!(s, "\n# WireGuard links")?;
+    }
+    for (l, w) in topology.wireguards.iter().enumerate(){
+        let (subnet, subnet6) = &overlays[&w.name];
+        let subnets = std::iter::once(subnet).chain(subnet6.iter())
+            .map(|s| s.parse::<ipnet::IpNet>())
+            .collect::<Result<Vec<_>, _>>()?;
+        if w.endpoints.len() != 2 {
+            return Err(failed!("WireGuard link {} needs two ends, got {}", w.name, w.endpoints.len()));
+        }
+        let mut locals = Vec::new();
+        for e in &w.endpoints{
+            let (local, _) = underlay(&interfaces, &e.local)
+                .map_err(|err| failed!("WireGuard link {}: {}", w.name, err))?;
+            let local: std::net::IpAddr = local.parse()
+                .map_err(|err| failed!("WireGuard link {}: invalid address {}: {}", w.name, local, err))?;
+            locals.push(std::net::SocketAddr::new(local, e.port.unwrap_or(WireguardLink::PORT)));
+        }
+        let key = |n: usize| format!("wg{}_{}", l, n);
+        for n in 0..2{
+            writeln!(s, "{}=$(wg genkey)", key(n))?;
+        }
+        for (n, e) in w.endpoints.iter().enumerate(){
+            let name = interface::name(&e.namespace, &w.name);
+            writeln!(s, "ip -n {} link add name {} type wireguard", netns(&e.namespace), name)?;
+            let peer = &w.endpoints[1 - n];
+            let allowed: Vec<String> = subnets.iter().map(|s| s.to_string()).chain(peer.allowed_ips.iter().cloned()).collect();
+            let mut set = format!("echo \"${}\" | ip netns exec {} wg set {} listen-port {} private-key /dev/stdin peer \"$(echo \"${}\" | wg pubkey)\" endpoint {} allowed-ips {}",
+                key(n), netns(&e.namespace), name, locals[n].port(), key(1 - n), locals[1 - n], allowed.join(","));
+            if let Some(keepalive) = w.keepalive{
+                write!(set, " persistent-keepalive {}", keepalive)?;
+            }
+            writeln!(s, "{}", set)?;
+            altname(&mut s, &netns(&e.namespace), &name, &format!("{}_{}", e.namespace, w.name))?;
+            let (mut ip, mut ip6) = (None, None);
+            for sn in &subnets{
+                let addr = overlay_addr(sn, n, true, e.host)?;
+                if sn.addr().is_ipv6() {
+                    ip6 = Some(addr);
+                } else {
+                    ip = Some(addr);
+                }
+            }
+            interface(&mut s, &netns(&e.namespace), &name, ip.as_deref(), ip6.as_deref(), None)?;
+            interfaces.insert(name, (ip, ip6));
+        }
+    }
+
+    if !topology.vxlans.is_empty() {
+        writeln!(s, "\n# VXLAN links")?;
+    }
+    for v in &topology.vxlans{
+        let (subnet, subnet6) = &overlays[&v.name];
+        let subnets = std::iter::once(subnet).chain(subnet6.iter())
+            .map(|s| s.parse::<ipnet::IpNet>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut vteps = Vec::new();
+        for e in &v.endpoints{
+            let (local, dev) = underlay(&interfaces, &e.local)
+                .map_err(|err| failed!("VXLAN link {}: {}", v.name, err))?;
+            vteps.push((e, local, dev));
+        }
+        let pair = vteps.len() == 2 && v.remotes.is_empty();
+        for (n, (e, local, dev)) in vteps.iter().enumerate(){
+            let name = interface::name(&e.namespace, &v.name);
+            let mut add = format!("ip -n {} link add name {} type vxlan id {} local {} dstport {}",
+                netns(&e.namespace), name, v.vni, local, v.port.unwrap_or(VxlanLink::PORT));
+            if let Some(group) = &v.group{
+                write!(add, " group {}", group)?;
+            }
+            if let Some(dev) = dev{
+                write!(add, " dev {}", dev)?;
+            }
+            writeln!(s, "{}", add)?;
+            altname(&mut s, &netns(&e.namespace), &name, &format!("{}_{}", e.namespace, v.name))?;
+            if v.group.is_none() {
+                let others = vteps.iter().map(|(_, l, _)| l).chain(v.remotes.iter()).filter(|o| *o != local);
+                for other in others{
+                    writeln!(s, "bridge -n {} fdb append 00:00:00:00:00:00 dev {} dst {}", netns(&e.namespace), name, other)?;
+                }
+            }
+            let (mut ip, mut ip6) = (None, None);
+            for sn in &subnets{
+                let addr = overlay_addr(sn, n, pair, e.host)?;
+                if sn.addr().is_ipv6() {
+                    ip6 = Some(addr);
+                } else {
+                    ip = Some(addr);
+                }
+            }
+            interface(&mut s, &netns(&e.namespace), &name, ip.as_deref(), ip6.as_deref(), None)?;
+            interfaces.insert(name, (ip, ip6));
+        }
+    }
+
+    if !topology.groups.is_empty() {
+        writeln!(s, "\n# interface groups")?;
+    }
+    for g in &topology.groups{
+        g.check()?;
+        let mut namespaces = Vec::new();
+        for (ns, name) in group::topology_members(topology, &g.name){
+            let ip_cmd = match &ns{
+                Some(ns) => format!("ip -n {}", netns(ns)),
+                None => "ip".to_string(),
+            };
+            writeln!(s, "{} link set dev {} group {}", ip_cmd, name, g.id)?;
+            if !g.sysctls.is_empty() {
+                let sysctl = match &ns{
+                    Some(ns) => format!("ip netns exec {} sysctl", netns(ns)),
+                    None => "sysctl".to_string(),
+                };
+                writeln!(s, "{} -qw {}", sysctl, g.sysctls(&name).join(" "))?;
+            }
+            if !namespaces.contains(&ip_cmd) {
+                namespaces.push(ip_cmd);
+            }
+        }
+        if let Some(mtu) = g.mtu{
+            for ip_cmd in namespaces{
+                writeln!(s, "{} link set group {} mtu {}", ip_cmd, g.id, mtu)?;
+            }
+        }
+    }
+
+    for ns in topology.namespaces.iter().filter(|ns| !ns.vrfs.is_empty()){
+        writeln!(s, "\n# VRFs of {}", ns.name)?;
+        writeln!(s, "ip netns exec {} sysctl -qw net.ipv6.conf.all.keep_addr_on_down=1", netns(&ns.name))?;
+        for v in &ns.vrfs{
+            writeln!(s, "ip -n {} link add name {} type vrf table {}", netns(&ns.name), v.name, v.table)?;
+            writeln!(s, "ip -n {} link set dev {} up", netns(&ns.name), v.name)?;
+            for i in &v.interfaces{
+                writeln!(s, "ip -n {} link set dev {} master {}", netns(&ns.name), i, v.name)?;
+            }
+        }
+    }
+
+    if !topology.services.is_empty() {
+        writeln!(s, "\n# services")?;
+    }
+    for svc in &topology.services{
+        let addr: std::net::IpAddr = svc.address.parse()
+            .map_err(|e| failed!("Invalid address {} of service {}: {}", svc.address, svc.name, e))?;
+        for ns in &svc.instances{
+            writeln!(s, "ip -n {} link set dev lo up", netns(ns))?;
+            writeln!(s, "ip -n {} addr add {} dev lo", netns(ns), ipnet::IpNet::from(addr))?;
+        }
+    }
+
+    for ns in &topology.namespaces{
+        let interfaces = namespace_interfaces(topology, &ns.name);
+        if let Some(settings) = ns.neighbor.as_ref().map(|n| n.sysctls(&interfaces)).filter(|s| !s.is_empty()) {
+            writeln!(s, "\n# neighbor timers of {}", ns.name)?;
+            writeln!(s, "ip netns exec {} sysctl -qw {}", netns(&ns.name), settings.join(" "))?;
+        }
+        if let Some(settings) = ns.icmp.as_ref().map(|i| i.sysctls(&interfaces)).filter(|s| !s.is_empty()) {
+            writeln!(s, "\n# ICMP of {}", ns.name)?;
+            writeln!(s, "ip netns exec {} sysctl -qw {}", netns(&ns.name), settings.join(" "))?;
+        }
+        if let Some(tcp) = &ns.tcp{
+            let settings: Vec<String> = tcp.sysctls(topology.link_bandwidth(&ns.name))?.iter()
+                .map(|s| if s.contains(' ') { format!("'{}'", s) } else { s.clone() })
+                .collect();
+            writeln!(s, "\n# TCP of {}", ns.name)?;
+            if !settings.is_empty() {
+                writeln!(s, "ip netns exec {} sysctl -qw {}", netns(&ns.name), settings.join(" "))?;
+            }
+            if tcp.pacing == Some(true) {
+                let impaired: Vec<String> = topology.links.iter()
+                    .filter(|l| l.endpoints.contains(&ns.name) && l.qos_at(&ns.name).is_some())
+                    .map(|l| interface::name(&ns.name, &l.name))
+                    .collect();
+                for interface in interfaces.iter().filter(|i| !impaired.contains(i)){
+                    writeln!(s, "ip netns exec {} tc qdisc replace dev {} root fq", netns(&ns.name), interface)?;
+                }
+            }
+        }
+        if ns.ra.is_some() {
+            writeln!(s, "\n# router advertisements accepted by {}", ns.name)?;
+            writeln!(s, "ip netns exec {} sysctl -qw {}", netns(&ns.name), ra::host_sysctls(&interfaces).join(" "))?;
+        }
+    }
+
+    let mut routes = topology.routes.clone();
+    routes.extend(paths::service_routes(topology, &subnets)?);
+    routes.extend(paths::stub_routes(topology, &subnets));
+    if topology.auto_routes {
+        routes.extend(paths::static_routes(topology, &subnets)?);
+    }
+    if !routes.is_empty() {
+        writeln!(s, "\n# routes")?;
+    }
+    for r in &routes{
+        let dst: ipnet::IpNet = r.dst.parse()
+            .map_err(|e| failed!("Invalid route destination {}: {}", r.dst, e))?;
+        let v6 = dst.addr().is_ipv6();
+        // gateway interfaces resolved to their addresses
+        let address = |gw: &String| -> Result<std::net::IpAddr> {
+            let (ip, ip6) = interfaces.get(&interface::shorten(gw))
+                .ok_or_else(|| failed!("Gateway interface {} of route {} in {} not found", gw, r.dst, r.namespace))?;
+            let ip = if v6 { ip6 } else { ip };
+            let ip = ip.as_ref()
+                .ok_or_else(|| failed!("Interface {} does not have an {} address", gw, if v6 { "IPv6" } else { "IPv4" }))?;
+            Ok(ip.split('/').next().unwrap_or_default().parse()?)
+        };
+        let mut route = Route{ dst: r.dst.clone(), gateway: Vec::new(), table: topology.table_of(r)?, kind: r.kind };
+        for gw in &r.gateways{
+            route.gateway.push(Nexthop{ address: Some(address(gw)?), ..Default::default() });
+        }
+        for n in &r.nexthops{
+            let gateway = match (&n.via, &n.address){
+                (Some(via), _) => Some(address(via)?),
+                (None, Some(a)) => Some(a.parse().map_err(|e| failed!("Invalid nexthop {} of route {} in {}: {}", a, r.dst, r.namespace, e))?),
+                (None, None) => None,
+            };
+            route.gateway.push(Nexthop{
+                address: gateway,
+                dev: n.dev.clone(),
+                onlink: n.onlink,
+                weight: n.weight,
+                metric: n.metric,
+                seg6: n.seg6.clone(),
+                seg6local: n.seg6local.clone(),
+                ..Default::default()
+            });
+        }
+        if route.kind != RouteKind::Unicast {
+            writeln!(s, "ip -n {} {} route add {}", netns(&r.namespace), if v6 { "-6" } else { "-4" }, route.kind_args()?.join(" "))?;
+            continue;
+        }
+        for (metric, nexthops) in route.by_metric(){
+            let mut line = format!("ip -n {} {} route add {}", netns(&r.namespace), if v6 { "-6" } else { "-4" }, r.dst);
+            if let Some(metric) = metric{
+                write!(line, " metric {}", metric)?;
+            }
+            if let Some(table) = route.table{
+                write!(line, " table {}", table)?;
+            }
+            if let [n] = nexthops.as_slice() {
+                write!(line, " {}", n.args(v6, false)?.join(" "))?;
+            } else {
+                for n in &nexthops{
+                    write!(line, " nexthop {}", n.args(v6, true)?.join(" "))?;
+                }
+            }
+            writeln!(s, "{}", line)?;
+        }
+    }
+    for ns in topology.namespaces.iter().filter(|ns| !ns.rules.is_empty()){
+        writeln!(s, "\n# policy rules of {}", ns.name)?;
+        for rule in &ns.rules{
+            for v6 in rule.families()?{
+                writeln!(s, "ip -n {} {} rule add {}", netns(&ns.name), if v6 { "-6" } else { "-4" }, rule.args()?.join(" "))?;
+            }
+        }
+    }
+    for ns in &topology.namespaces{
+        let flowtable = if ns.flowtable { namespace_interfaces(topology, &ns.name) } else { Vec::new() };
+        let Some(ruleset) = firewall::ruleset(ns.nat.as_ref(), ns.firewall.as_ref(), &flowtable)? else {
+            continue;
+        };
+        writeln!(s, "\n# firewall of {}", ns.name)?;
+        writeln!(s, "ip netns exec {} nft -f - <<'EOF'\n{}EOF", netns(&ns.name), ruleset)?;
+        if let Some(gateway) = ns.nat.as_ref().and_then(|n| n.gateway.as_ref().map(|g| (g, &n.out))){
+            writeln!(s, "ip -n {} route replace default via {} dev {}", netns(&ns.name), gateway.0, gateway.1)?;
+        }
+    }
+    for ns in topology.namespaces.iter(){
+        let Some(p4) = &ns.p4 else {
+            continue;
+        };
+        writeln!(s, "\n# P4 switch of {}", ns.name)?;
+        let mut ports = p4.ports.clone();
+        if ports.is_empty() {
+            ports = namespace_interfaces(topology, &ns.name);
+        }
+        let mut program = p4.program.clone();
+        if !program.ends_with(".json") {
+            let json = P4Switch::dir(&topology.name, &ns.name).join("compiled.json").to_string_lossy().to_string();
+            writeln!(s, "mkdir -p {}", P4Switch::dir(&topology.name, &ns.name).display())?;
+            writeln!(s, "p4c-bm2-ss --p4v 16 -o {} {}", json, program)?;
+            program = json;
+        }
+        writeln!(s, "ip netns exec {} sysctl -qw net.ipv4.ip_forward=0 net.ipv6.conf.all.forwarding=0", netns(&ns.name))?;
+        let ports: Vec<String> = ports.iter().enumerate().map(|(n, p)| format!("-i {}@{}", n, p)).collect();
+        writeln!(s, "ip netns exec {} simple_switch --thrift-port {} {} {} &", netns(&ns.name), p4::THRIFT_PORT, ports.join(" "), program)?;
+        if !p4.commands.is_empty() {
+            writeln!(s, "sleep 2")?;
+            writeln!(s, "ip netns exec {} simple_switch_CLI --thrift-port {} <<'EOF'\n{}\nEOF", netns(&ns.name), p4::THRIFT_PORT, p4.commands.join("\n"))?;
+        }
+    }
+    for ns in topology.namespaces.iter(){
+        let Some(fwd) = &ns.forwarder else {
+            continue;
+        };
+        writeln!(s, "\n# {} forwarder of {}", fwd.kind, ns.name)?;
+        let mut ports = fwd.ports.clone();
+        if ports.is_empty() {
+            ports = namespace_interfaces(topology, &ns.name);
+        }
+        writeln!(s, "ip netns exec {} sysctl -qw net.ipv4.ip_forward=0 net.ipv6.conf.all.forwarding=0", netns(&ns.name))?;
+        writeln!(s, "ip netns exec {} {} &", netns(&ns.name), fwd.kind.expand(&fwd.command, &ports).join(" "))?;
+    }
+    if !topology.mirrors.is_empty() {
+        writeln!(s, "\n# mirrors")?;
+    }
+    for m in &topology.mirrors{
+        m.check()?;
+        let m = m.kernel_names(topology);
+        let ns = netns(&m.namespace);
+        if let (Some((local, remote)), Some(monitor)) = (m.span(), &m.monitor) {
+            writeln!(s, "ip link add name {} netns {} type veth peer name {} netns {}", local, ns, remote, netns(monitor))?;
+            for (n, name) in [(&ns, &local), (&netns(monitor), &remote)]{
+                writeln!(s, "ip netns exec {} sysctl -qw net.ipv6.conf.{}.disable_ipv6=1", n, name)?;
+                writeln!(s, "ip -n {} link set dev {} up", n, name)?;
+            }
+        }
+        writeln!(s, "ip netns exec {} tc qdisc add dev {} clsact", ns, m.interface)?;
+        for hook in m.direction.hooks(){
+            writeln!(s, "ip netns exec {} tc filter add dev {} {} pref {} protocol all u32 match u32 0 0 action mirred egress mirror dev {}",
+                ns, m.interface, hook, m.pref(), m.target())?;
+        }
+    }
+    for ns in topology.namespaces.iter().filter(|n| !n.processes.is_empty()){
+        writeln!(s, "\n# processes of {}, not restarted when run from here", ns.name)?;
+        for p in &ns.processes{
+            writeln!(s, "ip netns exec {} {} &", netns(&ns.name), p.command.join(" "))?;
+        }
+    }
+    for (router, advertisements) in ra::advertisements(topology, &subnets)?{
+        let dir = Advertiser::dir(&topology.name, &router);
+        writeln!(s, "\n# router advertisements of {}", router)?;
+        writeln!(s, "mkdir -p {}", dir.display())?;
+        writeln!(s, "cat > {}/radvd.cfg <<'EOF'\n{}EOF", dir.display(), ra::radvd_config(&advertisements))?;
+        writeln!(s, "ip netns exec {} radvd --config {}/radvd.cfg --pidfile {}/radvd.pid", netns(&router), dir.display(), dir.display())?;
+    }
+    if let Some(kind) = topology.daemon{
+        writeln!(s, "\n# {} routing daemons are not exported, create the topology with router-rs to run them", kind)?;
+    }
+    Ok(s)
+}
+
+/// Altname `logical` of interface `name` of `netns` if it was shortened,
+/// see `interface::shorten`.
+fn altname(s: &mut String, netns: &str, name: &str, logical: &str) -> Result<()>{
+    if name != logical {
+        writeln!(s, "ip -n {} link property add dev {} altname {}", netns, name, logical)?;
+    }
+    Ok(())
+}
+
+/// Transmit queue length and root qdisc of one interface, `netns` empty for
+/// the host.
+fn queue(s: &mut String, netns: &str, name: &str, queues: &QueueSpec) -> Result<()>{
+    if let Some(len) = queues.txqueuelen{
+        let ip_cmd = if netns.is_empty() { "ip".to_string() } else { format!("ip -n {}", netns) };
+        writeln!(s, "{} link set dev {} txqueuelen {}", ip_cmd, name, len)?;
+    }
+    if let Some(qdisc) = queues.qdisc{
+        let tc_cmd = if netns.is_empty() { "tc".to_string() } else { format!("tc -n {}", netns) };
+        writeln!(s, "{} qdisc replace dev {} root {}", tc_cmd, name, qdisc)?;
+    }
+    Ok(())
+}
+
+/// Addresses, mtu and link state of one interface, `netns` empty for the host.
+fn interface(s: &mut String, netns: &str, name: &str, ip: Option<&str>, ip6: Option<&str>, mtu: Option<u32>) -> Result<()>{
+    let ip_cmd = if netns.is_empty() { "ip".to_string() } else { format!("ip -n {}", netns) };
+    if let Some(ip) = ip{
+        writeln!(s, "{} addr add {} dev {}", ip_cmd, ip, name)?;
+    }
+    if let Some(ip6) = ip6{
+        writeln!(s, "{} addr add {} dev {} nodad", ip_cmd, ip6, name)?;
+    }
+    if let Some(mtu) = mtu{
+        writeln!(s, "{} link set dev {} mtu {}", ip_cmd, name, mtu)?;
+    }
+    writeln!(s, "{} link set dev {} up", ip_cmd, name)?;
+    Ok(())
+}
+
+/// Names of the interfaces of namespace `ns`, sorted, as the ports of its
+/// P4 switch or forwarder or the devices of its flowtable.
+fn namespace_interfaces(topology: &Topology, ns: &str) -> Vec<String> {
+    let mut names: Vec<String> = topology.links.iter().filter(|l| l.endpoints.iter().any(|e| e == ns)).map(|l| &l.name)
+        .chain(topology.bridges.iter().filter(|b| b.members.iter().any(|m| m == ns)).map(|b| &b.name))
+        .chain(topology.vxlans.iter().filter(|v| v.endpoints.iter().any(|e| e.namespace == ns)).map(|v| &v.name))
+        .chain(topology.tunnels.iter().filter(|t| t.endpoints.iter().any(|e| e.namespace == ns)).map(|t| &t.name))
+        .chain(topology.wireguards.iter().filter(|w| w.endpoints.iter().any(|e| e.namespace == ns)).map(|w| &w.name))
+        .map(|name| interface::name(ns, name))
+        .chain(topology.interfaces.iter().filter(|i| i.namespace.as_deref() == Some(ns)).map(|i| i.name.clone()))
+        .collect();
+    names.sort();
+    names
+}
+
+/// Tunnel source of `local`: an address, or an interface already created
+/// whose address is used and which then carries the tunnel.
+fn underlay(interfaces: &HashMap<String, (Option<String>, Option<String>)>, local: &str) -> Result<(String, Option<String>)>{
+    let name = interface::shorten(local);
+    match interfaces.get(&name){
+        Some((ip, ip6)) => {
+            let addr = ip.as_ref().or(ip6.as_ref()).and_then(|a| a.split('/').next())
+                .ok_or_else(|| failed!("Interface {} has no address", local))?;
+            Ok((addr.to_string(), Some(name)))
+        },
+        None => Ok((local.to_string(), None)),
+    }
+}
@@ -0,0 +1,142 @@
+//! Software fastpath of router namespaces: with `flowtable` set, the
+//! namespace's nftables table gets a flowtable over all of its interfaces,
+//! and forwarded TCP and UDP connections are added to it once established.
+//! Their packets then skip routing lookup, netfilter hooks and most of the
+//! forwarding path, see `firewall`.
+//!
+//! `compare` measures what that buys: a TCP bulk transfer between two
+//! namespaces, once with the flowtables of the topology removed and once
+//! with them in place. The tables are left as described afterwards.
+
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+use crate::error::{failed, Result, RouterError};
+use crate::firewall;
+use crate::netns;
+use crate::state::State;
+use crate::topology::Topology;
+use crate::Namespace;
+
+/// TCP bulk transfer from `src` to `target`, an address of `dst`, for
+/// `duration`.
+pub struct Throughput{
+    pub src: String,
+    pub dst: String,
+    pub target: SocketAddr,
+    pub duration: Duration,
+}
+
+impl Throughput{
+    /// Mbit/s received by `dst`.
+    pub fn run(&self) -> Result<f64>{
+        let any: IpAddr = if self.target.is_ipv6() { Ipv6Addr::UNSPECIFIED.into() } else { Ipv4Addr::UNSPECIFIED.into() };
+        let bind = SocketAddr::new(any, self.target.port());
+        let listener = netns::run_in(&self.dst, || TcpListener::bind(bind)
+            .map_err(|e| failed!("Failed to listen on {} in {}: {}", bind, self.dst, e)))?;
+        // the socket stays in the namespace it was opened in
+        let receiver = std::thread::spawn(move || receive(listener));
+        let (target, duration) = (self.target, self.duration);
+        let sender = netns::spawn_in(&self.src, move || {
+            let mut stream = TcpStream::connect_timeout(&target, Duration::from_secs(5))
+                .map_err(|e| failed!("Failed to connect to {}: {}", target, e))?;
+            let buf = vec![0u8; 128 * 1024];
+            let start = Instant::now();
+            while start.elapsed() < duration{
+                stream.write_all(&buf)?;
+            }
+            stream.shutdown(std::net::Shutdown::Write)?;
+            Ok(())
+        });
+        let sent = sender.join().map_err(|_| failed!("Sender thread panicked"))?;
+        let received = receiver.join().map_err(|_| failed!("Receiver thread panicked"))?;
+        sent.map_err(|e| failed!("Sending from {}: {}", self.src, e))?;
+        let (bytes, elapsed) = received?;
+        Ok(bytes as f64 * 8.0 / elapsed.as_secs_f64().max(f64::EPSILON) / 1_000_000.0)
+    }
+}
+
+/// Bytes received on the first connection to `listener` and how long
+/// they took, giving up on a sender which doesn't connect.
+fn receive(listener: TcpListener) -> Result<(u64, Duration)>{
+    listener.set_nonblocking(true)?;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut stream = loop{
+        match listener.accept(){
+            Ok((stream, _)) => break stream,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock && Instant::now() < deadline => {
+                std::thread::sleep(Duration::from_millis(10));
+            },
+            Err(e) => return Err(failed!("No connection to the receiver: {}", e)),
+        }
+    };
+    stream.set_nonblocking(false)?;
+    let mut buf = vec![0u8; 128 * 1024];
+    let (mut bytes, start) = (0u64, Instant::now());
+    loop{
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Ok((bytes, start.elapsed()));
+        }
+        bytes += n as u64;
+    }
+}
+
+/// Throughput in Mbit/s through the kernel's forwarding path and through
+/// the flowtables.
+#[derive(Clone, Debug, Default)]
+pub struct Comparison{
+    pub kernel: f64,
+    pub flowtable: f64,
+}
+
+impl Comparison{
+    /// Change from kernel forwarding to the fastpath in percent.
+    pub fn gain(&self) -> f64 {
+        if self.kernel == 0.0 {
+            return 0.0;
+        }
+        (self.flowtable / self.kernel - 1.0) * 100.0
+    }
+}
+
+impl fmt::Display for Comparison{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "kernel {:.1} Mbit/s, flowtable {:.1} Mbit/s ({:+.1}%)", self.kernel, self.flowtable, self.gain())
+    }
+}
+
+/// Runs `probe` across the running `topology` without and with the
+/// flowtables of its namespaces.
+pub fn compare(topology: &Topology, probe: &Throughput) -> Result<Comparison>{
+    let state = State::load(&topology.name)?
+        .ok_or_else(|| RouterError::TopologyNotFound(topology.name.to_string()))?;
+    let routers: Vec<_> = topology.namespaces.iter().filter(|n| n.flowtable).collect();
+    if routers.is_empty() {
+        return Err(failed!("No namespace of {} has a flowtable", topology.name));
+    }
+    let apply = |fastpath: bool| -> Result<()>{
+        for spec in &routers{
+            let netns = Namespace::netns_name(&topology.name, &spec.name);
+            let mut devices: Vec<String> = Vec::new();
+            if fastpath {
+                devices = state.interfaces.iter()
+                    .filter(|i| i.netns.as_deref() == Some(netns.as_str()))
+                    .map(|i| i.name.clone())
+                    .collect();
+                devices.sort();
+            }
+            let ruleset = firewall::ruleset(spec.nat.as_ref(), spec.firewall.as_ref(), &devices)?;
+            firewall::apply(&netns, ruleset.as_deref())
+                .map_err(|e| failed!("Namespace {}: {}", spec.name, e))?;
+        }
+        Ok(())
+    };
+    let kernel = apply(false).and_then(|_| probe.run());
+    // back to the tables as described, whatever the first run did
+    apply(true)?;
+    let kernel = kernel?;
+    Ok(Comparison{ kernel, flowtable: probe.run()? })
+}
@@ -0,0 +1,266 @@
+//! NAT and stateful packet filtering of a namespace, rendered as one
+//! nftables table, `inet router_rs`, which is replaced as a whole in a
+//! single nft transaction. Tables of others are left alone.
+//!
+//! A NAT gateway masquerades what it forwards out of its outside interface,
+//! e.g. a host interface moved into the namespace, so the lab behind it
+//! reaches the world. Filter chains accept established and related traffic
+//! first, then apply the rules in order and finally the chain's policy.
+//! Neighbor discovery, OSPF and BGP are always accepted on input, so a
+//! restrictive policy doesn't cut the routing daemons off.
+//!
+//! With a flowtable, forwarded TCP and UDP connections are added to it
+//! once established and their packets then take the software fastpath
+//! from the ingress hook straight to the outgoing interface, see
+//! `fastpath`.
+
+use std::fmt;
+use std::fmt::Write as _;
+use std::process::Stdio;
+
+use serde::{Deserialize, Serialize};
+
+use crate::capture::Protocol;
+use crate::error::{failed, Result};
+use crate::trace::Traced;
+use crate::{cmd, netns, Nexthop, Route, RouteKind};
+
+pub const TABLE: &str = "router_rs";
+
+/// Flowtable of the table.
+pub const FLOWTABLE: &str = "fastpath";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict{
+    #[default]
+    Accept,
+    Drop,
+    /// drop and tell the sender, not valid as a policy
+    Reject,
+}
+
+impl fmt::Display for Verdict{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Verdict::Accept => write!(f, "accept"),
+            Verdict::Drop => write!(f, "drop"),
+            Verdict::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Chain{
+    /// traffic addressed to the namespace
+    Input,
+    /// traffic routed through the namespace
+    #[default]
+    Forward,
+}
+
+impl fmt::Display for Chain{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Chain::Input => write!(f, "input"),
+            Chain::Forward => write!(f, "forward"),
+        }
+    }
+}
+
+/// Masquerading of forwarded traffic leaving through `out`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct NatSpec{
+    /// outside interface
+    pub out: String,
+    /// only traffic from these prefixes, all by default
+    #[serde(default)]
+    pub sources: Vec<String>,
+    /// address of the upstream router, installed as default route via `out`
+    #[serde(default)]
+    pub gateway: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FirewallSpec{
+    /// policy of the input chain
+    #[serde(default)]
+    pub input: Verdict,
+    /// policy of the forward chain
+    #[serde(default)]
+    pub forward: Verdict,
+    #[serde(default)]
+    pub rules: Vec<RuleSpec>,
+}
+
+/// Rule matching all of the given fields. `port` is the destination port
+/// and needs `protocol` tcp or udp.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RuleSpec{
+    #[serde(default)]
+    pub chain: Chain,
+    #[serde(default)]
+    pub action: Verdict,
+    /// source prefix
+    #[serde(default)]
+    pub from: Option<String>,
+    /// destination prefix
+    #[serde(default)]
+    pub to: Option<String>,
+    #[serde(default)]
+    pub protocol: Option<Protocol>,
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// incoming interface
+    #[serde(default)]
+    pub iif: Option<String>,
+    /// outgoing interface, forward only
+    #[serde(default)]
+    pub oif: Option<String>,
+}
+
+impl RuleSpec{
+    fn render(&self) -> Result<String>{
+        let parse = |prefix: &Option<String>| -> Result<Option<ipnet::IpNet>> {
+            prefix.as_ref()
+                .map(|p| p.parse().map_err(|e| failed!("Invalid prefix {} in firewall rule: {}", p, e)))
+                .transpose()
+        };
+        let (from, to) = (parse(&self.from)?, parse(&self.to)?);
+        if let (Some(from), Some(to)) = (from, to){
+            if from.addr().is_ipv6() != to.addr().is_ipv6() {
+                return Err(failed!("Firewall rule mixes IPv4 and IPv6: {} to {}", from, to));
+            }
+        }
+        if self.oif.is_some() && self.chain == Chain::Input {
+            return Err(failed!("Firewall rule on input can't match an outgoing interface"));
+        }
+        let mut rule = Vec::new();
+        if let Some(iif) = &self.iif{
+            rule.push(format!("iifname \"{}\"", iif));
+        }
+        if let Some(oif) = &self.oif{
+            rule.push(format!("oifname \"{}\"", oif));
+        }
+        let family = |net: &ipnet::IpNet| if net.addr().is_ipv6() { "ip6" } else { "ip" };
+        if let Some(from) = from{
+            rule.push(format!("{} saddr {}", family(&from), from));
+        }
+        if let Some(to) = to{
+            rule.push(format!("{} daddr {}", family(&to), to));
+        }
+        match (self.protocol, self.port){
+            (Some(Protocol::Tcp), Some(port)) => rule.push(format!("tcp dport {}", port)),
+            (Some(Protocol::Udp), Some(port)) => rule.push(format!("udp dport {}", port)),
+            (_, Some(_)) => return Err(failed!("A port in a firewall rule needs protocol tcp or udp")),
+            (Some(Protocol::Icmp), None) => rule.push("meta l4proto { icmp, ipv6-icmp }".to_string()),
+            (Some(protocol), None) => rule.push(format!("meta l4proto {}", protocol)),
+            (None, None) => {},
+        }
+        rule.push(self.action.to_string());
+        Ok(rule.join(" "))
+    }
+}
+
+/// The table for a namespace, None if it needs none. A flowtable over
+/// `flowtable`, the interfaces of the namespace, is added unless it is
+/// empty.
+pub fn ruleset(nat: Option<&NatSpec>, firewall: Option<&FirewallSpec>, flowtable: &[String]) -> Result<Option<String>>{
+    if nat.is_none() && firewall.is_none() && flowtable.is_empty() {
+        return Ok(None);
+    }
+    let mut s = String::new();
+    writeln!(s, "table inet {} {{", TABLE)?;
+    if !flowtable.is_empty() {
+        let devices: Vec<String> = flowtable.iter().map(|d| format!("\"{}\"", d)).collect();
+        writeln!(s, "  flowtable {} {{", FLOWTABLE)?;
+        writeln!(s, "    hook ingress priority filter;")?;
+        writeln!(s, "    devices = {{ {} }};", devices.join(", "))?;
+        writeln!(s, "  }}")?;
+    }
+    let policies = match firewall{
+        Some(fw) => vec![(Chain::Input, fw.input), (Chain::Forward, fw.forward)],
+        None if !flowtable.is_empty() => vec![(Chain::Forward, Verdict::Accept)],
+        None => Vec::new(),
+    };
+    for (chain, policy) in policies{
+        if policy == Verdict::Reject {
+            return Err(failed!("The policy of {} must be accept or drop", chain));
+        }
+        writeln!(s, "  chain {} {{", chain)?;
+        writeln!(s, "    type filter hook {} priority filter; policy {};", chain, policy)?;
+        if chain == Chain::Forward && !flowtable.is_empty() {
+            writeln!(s, "    meta l4proto {{ tcp, udp }} flow add @{}", FLOWTABLE)?;
+        }
+        writeln!(s, "    ct state established,related accept")?;
+        if chain == Chain::Input {
+            writeln!(s, "    iifname \"lo\" accept")?;
+            writeln!(s, "    icmpv6 type {{ nd-neighbor-solicit, nd-neighbor-advert, nd-router-solicit, nd-router-advert }} accept")?;
+            writeln!(s, "    meta l4proto 89 accept")?;
+            writeln!(s, "    tcp dport 179 accept")?;
+        }
+        for rule in firewall.iter().flat_map(|fw| &fw.rules).filter(|r| r.chain == chain){
+            writeln!(s, "    {}", rule.render()?)?;
+        }
+        writeln!(s, "  }}")?;
+    }
+    if let Some(nat) = nat{
+        writeln!(s, "  chain postrouting {{")?;
+        writeln!(s, "    type nat hook postrouting priority srcnat; policy accept;")?;
+        if nat.sources.is_empty() {
+            writeln!(s, "    oifname \"{}\" masquerade", nat.out)?;
+        }
+        for (family, v6) in [("ip", false), ("ip6", true)]{
+            let sources = nat.sources.iter()
+                .map(|p| p.parse::<ipnet::IpNet>().map_err(|e| failed!("Invalid NAT source {}: {}", p, e)))
+                .collect::<Result<Vec<_>>>()?;
+            let sources: Vec<String> = sources.iter().filter(|p| p.addr().is_ipv6() == v6).map(|p| p.to_string()).collect();
+            if !sources.is_empty() {
+                writeln!(s, "    oifname \"{}\" {} saddr {{ {} }} masquerade", nat.out, family, sources.join(", "))?;
+            }
+        }
+        writeln!(s, "  }}")?;
+    }
+    writeln!(s, "}}")?;
+    Ok(Some(s))
+}
+
+/// Replaces the table of `netns` with `ruleset`, or removes it if None.
+pub fn apply(netns: &str, ruleset: Option<&str>) -> Result<()>{
+    // creating the table first makes deleting it safe when it's missing
+    let mut script = format!("table inet {}\ndelete table inet {}\n", TABLE, TABLE);
+    if let Some(ruleset) = ruleset{
+        script.push_str(ruleset);
+    }
+    cmd::with_input(netns::command(netns, "nft").args(["-f", "-"]), &script)
+        .map_err(|e| e.context(format!("Failed to apply firewall of {}", netns)))?;
+    Ok(())
+}
+
+/// True if `netns` has our table. False as well without nft, so topologies
+/// without firewalls don't depend on it.
+pub fn present(netns: &str) -> bool {
+    netns::command(netns, "nft")
+        .args(["list", "table", "inet", TABLE])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .traced_status()
+        .is_ok_and(|s| s.success())
+}
+
+/// Default route of a NAT gateway towards its upstream router, None
+/// without `gateway`.
+pub fn default_route(nat: &NatSpec) -> Result<Option<Route>>{
+    let Some(gateway) = &nat.gateway else {
+        return Ok(None);
+    };
+    let address: std::net::IpAddr = gateway.parse()
+        .map_err(|e| failed!("Invalid NAT gateway {}: {}", gateway, e))?;
+    Ok(Some(Route{
+        dst: if address.is_ipv6() { "::/0" } else { "0.0.0.0/0" }.to_string(),
+        gateway: vec![Nexthop{ address: Some(address), dev: Some(nat.out.clone()), ..Default::default() }],
+        table: None,
+        kind: RouteKind::Unicast,
+    }))
+}
@@ -0,0 +1,185 @@
+//! BGP prefix flaps for control plane stress tests. A namespace announces
+//! its BGP `networks` while it has a connected route to them, so putting an
+//! address of the prefix on its loopback and taking it away again makes its
+//! speaker announce and withdraw the prefix. After every change the kernel
+//! routing tables of the other namespaces are watched to see how long the
+//! change takes to reach them, or whether it never does, e.g. because
+//! dampening suppresses the prefix.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::cmd;
+use crate::error::{failed, Result};
+use crate::state;
+
+/// Time between two looks at the routing tables.
+const POLL: Duration = Duration::from_millis(20);
+
+pub struct FlapScenario{
+    pub topology: String,
+    /// kernel name of the namespace announcing the prefixes
+    pub netns: String,
+    /// prefixes to flap, each must be one of the namespace's BGP networks
+    /// and not reachable through one of its interfaces
+    pub prefixes: Vec<ipnet::IpNet>,
+    /// withdraw/announce cycles
+    pub flaps: u32,
+    /// time between two changes, changes not seen by then count as missed
+    pub interval: Duration,
+    /// wait after the last change before comparing the final state
+    pub settle: Duration,
+}
+
+/// Per observing namespace the time until its routing table followed a
+/// change, None if it didn't within the interval.
+pub type Propagation = Vec<(String, Option<Duration>)>;
+
+/// One announcement or withdrawal of a prefix.
+#[derive(Serialize, Clone, Debug)]
+pub struct FlapEvent{
+    pub prefix: String,
+    pub announced: bool,
+    /// since the start of the scenario
+    pub at: Duration,
+    pub propagation: Propagation,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct FlapReport{
+    pub events: Vec<FlapEvent>,
+    /// namespaces whose routes to a prefix disagree with the origin once
+    /// the flapping stopped and `settle` passed, as (namespace, prefix)
+    pub diverged: Vec<(String, String)>,
+}
+
+impl FlapReport{
+    /// Changes seen by `netns` and the average time they took.
+    pub fn observer(&self, netns: &str) -> (usize, Option<Duration>) {
+        let times: Vec<Duration> = self.events.iter()
+            .flat_map(|e| e.propagation.iter())
+            .filter(|(n, _)| n == netns)
+            .filter_map(|(_, t)| *t)
+            .collect();
+        let mean = if times.is_empty() { None } else { Some(times.iter().sum::<Duration>() / times.len() as u32) };
+        (times.len(), mean)
+    }
+
+    fn observers(&self) -> Vec<String> {
+        self.events.first().map(|e| e.propagation.iter().map(|(n, _)| n.clone()).collect()).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for FlapReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} changes", self.events.len())?;
+        for netns in self.observers(){
+            let (seen, mean) = self.observer(&netns);
+            write!(f, "  {:<24} {}/{} seen", netns, seen, self.events.len())?;
+            if let Some(mean) = mean{
+                write!(f, ", avg {:.1} ms", mean.as_secs_f64() * 1000.0)?;
+            }
+            writeln!(f)?;
+        }
+        for (netns, prefix) in &self.diverged{
+            writeln!(f, "  {} still disagrees on {}", netns, prefix)?;
+        }
+        Ok(())
+    }
+}
+
+impl FlapScenario{
+    /// Flaps the prefixes, starting from and ending in the state they are
+    /// in now.
+    pub fn run(&self) -> Result<FlapReport>{
+        if self.prefixes.is_empty() {
+            return Err(failed!("No prefixes to flap"));
+        }
+        let observers: Vec<String> = state::namespaces(&self.topology)?.into_iter()
+            .filter(|n| *n != self.netns)
+            .collect();
+        if observers.is_empty() {
+            return Err(failed!("Topology {} has no namespace besides {} to observe", self.topology, self.netns));
+        }
+        let mut initial = Vec::new();
+        for prefix in &self.prefixes{
+            initial.push(announced(&self.netns, prefix)?);
+        }
+        let start = Instant::now();
+        let mut report = FlapReport::default();
+        for _ in 0..self.flaps{
+            for first in [true, false]{
+                let changes: Vec<(ipnet::IpNet, bool)> = self.prefixes.iter().zip(&initial)
+                    .map(|(p, up)| (*p, if first { !up } else { *up }))
+                    .collect();
+                let at = start.elapsed();
+                for (prefix, up) in &changes{
+                    set(&self.netns, prefix, *up)?;
+                }
+                let times = watch(&observers, &changes, self.interval)?;
+                for ((prefix, up), propagation) in changes.into_iter().zip(times){
+                    report.events.push(FlapEvent{ prefix: prefix.to_string(), announced: up, at, propagation });
+                }
+            }
+        }
+        std::thread::sleep(self.settle);
+        for (prefix, up) in self.prefixes.iter().zip(&initial){
+            for netns in &observers{
+                if has_route(netns, prefix)? != *up {
+                    report.diverged.push((netns.clone(), prefix.to_string()));
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Per change, per observer the time until its routes matched, watching
+/// for `interval` in total.
+fn watch(observers: &[String], changes: &[(ipnet::IpNet, bool)], interval: Duration) -> Result<Vec<Propagation>>{
+    let start = Instant::now();
+    let mut times: Vec<Propagation> = changes.iter()
+        .map(|_| observers.iter().map(|n| (n.clone(), None)).collect())
+        .collect();
+    loop{
+        for ((prefix, up), times) in changes.iter().zip(times.iter_mut()){
+            for (netns, time) in times.iter_mut().filter(|(_, t)| t.is_none()){
+                if has_route(netns, prefix)? == *up {
+                    *time = Some(start.elapsed());
+                }
+            }
+        }
+        let elapsed = start.elapsed();
+        if elapsed >= interval {
+            return Ok(times);
+        }
+        std::thread::sleep(POLL.min(interval - elapsed));
+    }
+}
+
+/// Address put on the loopback to announce `prefix`: its first host, or
+/// the prefix itself if it's a host route.
+fn loopback_address(prefix: &ipnet::IpNet) -> String {
+    let host = prefix.hosts().next().unwrap_or(prefix.addr());
+    format!("{}/{}", host, prefix.prefix_len())
+}
+
+fn announced(netns: &str, prefix: &ipnet::IpNet) -> Result<bool>{
+    let out = cmd::ip(Some(netns), &["addr", "show", "dev", "lo", "to", loopback_address(prefix).as_str()])?;
+    Ok(!out.trim().is_empty())
+}
+
+fn set(netns: &str, prefix: &ipnet::IpNet, up: bool) -> Result<()>{
+    let address = loopback_address(prefix);
+    cmd::ip(Some(netns), &["link", "set", "dev", "lo", "up"])?;
+    cmd::ip(Some(netns), &["addr", if up { "add" } else { "del" }, address.as_str(), "dev", "lo"])?;
+    Ok(())
+}
+
+fn has_route(netns: &str, prefix: &ipnet::IpNet) -> Result<bool>{
+    let family = if prefix.addr().is_ipv6() { "-6" } else { "-4" };
+    let out = cmd::ip(Some(netns), &[family, "route", "show", "exact", prefix.to_string().as_str()])?;
+    Ok(!out.trim().is_empty())
+}
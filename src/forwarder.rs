@@ -0,0 +1,214 @@
+//! Userspace forwarding: a namespace whose interfaces are handed to a
+//! forwarder process, one built on AF_XDP sockets or on DPDK, or the
+//! software router of `dataplane`, instead of being forwarded by the kernel, for performance experiments beyond
+//! kernel forwarding. Like a P4 switch, the namespace keeps the addresses
+//! of its interfaces, stops forwarding in the kernel and runs no routing
+//! daemon.
+//!
+//! The forwarder is any program which runs in the foreground, its command
+//! line from the description with these arguments replaced:
+//!
+//! - `{ports}`: the ports, comma separated
+//! - `{eal}`: DPDK EAL arguments without PCI devices or hugepages, each port
+//!   a virtual device: `net_af_xdp` for `af_xdp`, `net_af_packet` for `dpdk`
+//!
+//! e.g. `dpdk-testpmd {eal} -- --forward-mode=io --stats-period 10`.
+//! Forwarders without a command run the software router of `dataplane`,
+//! `router-rs dataplane run --io <kind> {ports}`, which has no DPDK. Its pid
+//! file and command line are kept in a runtime directory next to those of
+//! the routing daemons, so it is stopped with the topology, restarted when
+//! the command changes and restarted by `daemon supervise` and the healer
+//! when it dies.
+//!
+//! Native XDP on a veth needs an XDP program or GRO on its peer as well,
+//! forwarders otherwise fall back to generic XDP.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::daemon;
+use crate::error::{failed, Result};
+use crate::logs;
+use crate::namespace;
+use crate::state::STATE_DIR;
+use crate::Namespace;
+
+/// How a forwarder reaches its ports.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwarderKind{
+    /// AF_XDP sockets on the ports
+    #[default]
+    AfXdp,
+    /// DPDK, with the ports as af_packet virtual devices
+    Dpdk,
+    /// TAP devices the ports are redirected to, see `dataplane`
+    Tap,
+}
+
+impl fmt::Display for ForwarderKind{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            ForwarderKind::AfXdp => write!(f, "af_xdp"),
+            ForwarderKind::Dpdk => write!(f, "dpdk"),
+            ForwarderKind::Tap => write!(f, "tap"),
+        }
+    }
+}
+
+impl ForwarderKind{
+    /// Arguments replacing `{eal}`, none for TAP devices.
+    fn eal(&self, ports: &[String]) -> Vec<String> {
+        let driver = match self{
+            ForwarderKind::AfXdp => "net_af_xdp",
+            ForwarderKind::Dpdk => "net_af_packet",
+            ForwarderKind::Tap => return Vec::new(),
+        };
+        let mut args = vec!["--no-pci".to_string(), "--in-memory".to_string(), "--no-huge".to_string()];
+        for (n, port) in ports.iter().enumerate(){
+            args.push(format!("--vdev={}{},iface={}", driver, n, port));
+        }
+        args
+    }
+
+    /// `command` with the placeholders replaced for `ports`.
+    pub fn expand(&self, command: &[String], ports: &[String]) -> Vec<String> {
+        if command.is_empty() && *self != ForwarderKind::Dpdk {
+            let builtin = ["router-rs", "dataplane", "run", "--io", &self.to_string(), "{ports}"];
+            return self.expand(&builtin.map(|a| a.to_string()), ports);
+        }
+        let mut args = Vec::new();
+        for arg in command{
+            if arg == "{eal}" {
+                args.extend(self.eal(ports));
+            } else {
+                args.push(arg.replace("{ports}", &ports.join(",")));
+            }
+        }
+        args
+    }
+}
+
+/// Forwarder process of the namespace `namespace`.
+pub struct Forwarder{
+    pub topology: String,
+    pub namespace: String,
+    pub netns: String,
+    /// pid file and command line
+    pub dir: PathBuf,
+}
+
+impl Forwarder{
+    pub fn new(topology: &str, namespace: &str, netns: &str) -> Forwarder {
+        Forwarder{
+            topology: topology.to_string(),
+            namespace: namespace.to_string(),
+            netns: netns.to_string(),
+            dir: Forwarder::dir(topology, namespace),
+        }
+    }
+
+    /// Runtime directory of the forwarder of `namespace` of `topology`.
+    pub fn dir(topology: &str, namespace: &str) -> PathBuf {
+        PathBuf::from(STATE_DIR).join(topology).join(format!("fwd-{}", namespace))
+    }
+
+    /// Forwarders of `topology` with a runtime directory.
+    pub fn list(topology: &str) -> Result<Vec<Forwarder>>{
+        let dir = PathBuf::from(STATE_DIR).join(topology);
+        let mut forwarders = Vec::new();
+        if !dir.exists() {
+            return Ok(forwarders);
+        }
+        for entry in std::fs::read_dir(dir)?{
+            if let Some(ns) = entry?.file_name().to_str().and_then(|n| n.strip_prefix("fwd-")){
+                forwarders.push(Forwarder::new(topology, ns, &Namespace::netns_name(topology, ns)));
+            }
+        }
+        forwarders.sort_by(|a, b| a.namespace.cmp(&b.namespace));
+        Ok(forwarders)
+    }
+
+    /// Runs `args` unless the forwarder runs already with the same command
+    /// line. Returns true if it was started.
+    pub fn start(&self, kind: ForwarderKind, args: &[String], ports: &[String]) -> Result<bool>{
+        if args.is_empty() {
+            return Err(failed!("Forwarder of {} has no command", self.namespace));
+        }
+        let existed = self.dir.exists();
+        let cmdline = serde_json::to_string(&Cmdline{ kind, args: args.to_vec(), ports: ports.to_vec() })?;
+        if self.running() && std::fs::read_to_string(self.dir.join("cmdline")).ok().as_deref() == Some(cmdline.as_str()) {
+            return Ok(false);
+        }
+        let result = daemon::stop_dir(&self.dir).and_then(|_| {
+            std::fs::create_dir_all(&self.dir)?;
+            std::fs::write(self.dir.join("cmdline"), &cmdline)?;
+            self.launch(kind, args, ports)
+        });
+        if result.is_err() && !existed {
+            let _ = daemon::stop_dir(&self.dir);
+        }
+        result.map(|_| true)
+    }
+
+    fn launch(&self, kind: ForwarderKind, args: &[String], ports: &[String]) -> Result<()>{
+        namespace::sysctl(&self.netns, &["net.ipv4.ip_forward=0", "net.ipv6.conf.all.forwarding=0"])?;
+        // a forwarder killed hard leaves its XDP program behind, which
+        // keeps the next one from binding the port
+        if kind == ForwarderKind::AfXdp {
+            for port in ports{
+                let _ = cmd::ip(Some(&self.netns), &["link", "set", "dev", port.as_str(), "xdp", "off"]);
+            }
+        }
+        daemon::spawn(&self.topology, &self.netns, &self.dir, "forwarder", args)?;
+        // forwarders fail on their ports right away
+        std::thread::sleep(Duration::from_millis(500));
+        if !self.running() {
+            return Err(failed!("Forwarder in {} exited: {}", self.netns,
+                logs::tail(&logs::path(&self.topology, &self.netns, "forwarder"), 5)));
+        }
+        Ok(())
+    }
+
+    pub fn running(&self) -> bool {
+        daemon::pid(&self.dir.join("forwarder.pid")).is_some_and(daemon::alive)
+    }
+
+    /// Kind of the forwarder as started, if it was.
+    pub fn kind(&self) -> Option<ForwarderKind> {
+        self.cmdline().map(|c| c.kind)
+    }
+
+    /// Restarts the forwarder with its last command line if it died.
+    /// Returns true if it was restarted.
+    pub fn supervise(&self) -> Result<bool>{
+        if self.running() {
+            return Ok(false);
+        }
+        let Some(cmdline) = self.cmdline() else {
+            return Err(failed!("Forwarder of {} has no command line in {}", self.namespace, self.dir.display()));
+        };
+        self.launch(cmdline.kind, &cmdline.args, &cmdline.ports)?;
+        Ok(true)
+    }
+
+    pub fn stop(&self) -> Result<()>{
+        daemon::stop_dir(&self.dir)
+    }
+
+    fn cmdline(&self) -> Option<Cmdline> {
+        serde_json::from_str(&std::fs::read_to_string(self.dir.join("cmdline")).ok()?).ok()
+    }
+}
+
+/// What a forwarder was started with, to restart it the same way.
+#[derive(Serialize, Deserialize)]
+struct Cmdline{
+    kind: ForwarderKind,
+    args: Vec<String>,
+    ports: Vec<String>,
+}
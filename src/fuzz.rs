@@ -0,0 +1,494 @@
+//! Fault fuzzing of a running topology: links flap, static routes are
+//! withdrawn and links are impaired with delay and loss, at random from a
+//! seed and at one of three intensities. Unlike `chaos`, which runs a
+//! schedule of link failures and times convergence, a fuzz run looks for
+//! fault sequences routing doesn't recover from.
+//!
+//! The plan, the seed and every fault with its time, target and
+//! parameters, is generated up front and written out before the first
+//! fault, so a run which left routing broken is replayed fault by fault
+//! from the plan, whatever the seed would pick on a changed topology.
+//!
+//! Every fault is undone after its duration: a flapped link comes up
+//! again, a withdrawn route is put back as it was and an impairment is
+//! removed. A target has at most one fault at a time, and only links
+//! without impairments of their own are impaired, as these are removed
+//! with the fault's. Once the last fault ended the routing tables of all
+//! namespaces are watched until they are back to those before the run;
+//! namespaces whose routes aren't within the settle time are failures.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::chaos::{self, ChaosAction};
+use crate::cmd;
+use crate::error::{failed, Result, RouterError};
+use crate::qos::{self, LinkQos};
+use crate::state::State;
+
+/// Time between two looks at the routing tables.
+const POLL: Duration = Duration::from_millis(20);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Intensity{
+    Low,
+    Medium,
+    High,
+}
+
+/// How often faults come and how hard they hit at an intensity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IntensityParams{
+    /// mean milliseconds between two faults, the actual time is picked
+    /// from half to one and a half of it
+    pub interval: u64,
+    /// milliseconds a fault lasts, from..to
+    pub duration: (u64, u64),
+    /// faults lasting at the same time
+    pub concurrent: usize,
+    /// milliseconds of delay of an impairment, from..to
+    pub delay: (u64, u64),
+    /// percent of the packets an impairment drops, from..to
+    pub loss: (f64, f64),
+}
+
+impl Intensity{
+    pub fn params(&self) -> IntensityParams {
+        match self{
+            Intensity::Low => IntensityParams{ interval: 4000, duration: (500, 2000), concurrent: 1, delay: (5, 50), loss: (1.0, 5.0) },
+            Intensity::Medium => IntensityParams{ interval: 2000, duration: (500, 3000), concurrent: 2, delay: (20, 200), loss: (1.0, 20.0) },
+            Intensity::High => IntensityParams{ interval: 500, duration: (200, 3000), concurrent: 4, delay: (50, 500), loss: (5.0, 50.0) },
+        }
+    }
+}
+
+impl fmt::Display for Intensity{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Intensity::Low => write!(f, "low"),
+            Intensity::Medium => write!(f, "medium"),
+            Intensity::High => write!(f, "high"),
+        }
+    }
+}
+
+impl FromStr for Intensity{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<Intensity>{
+        match s{
+            "low" => Ok(Intensity::Low),
+            "medium" => Ok(Intensity::Medium),
+            "high" => Ok(Intensity::High),
+            _ => Err(RouterError::Invalid(format!("Invalid intensity {}, expected low, medium or high", s))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum FaultKind{
+    /// the link goes down with both of its ends
+    Flap{ link: String },
+    /// the static routes to `dst` are deleted from the table
+    Withdraw{
+        /// kernel name of the namespace
+        netns: String,
+        dst: String,
+        /// routing table, main if None
+        #[serde(default)]
+        table: Option<u32>,
+    },
+    /// netem on both ends of the link
+    Impair{ link: String, qos: LinkQos },
+}
+
+/// Fault of a plan.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Fault{
+    /// milliseconds since the start of the run
+    pub at: u64,
+    /// milliseconds after which the fault is undone
+    pub duration: u64,
+    #[serde(flatten)]
+    pub kind: FaultKind,
+}
+
+impl Fault{
+    /// What the fault hits, faults on the same target don't overlap.
+    fn target(&self) -> String {
+        match &self.kind{
+            FaultKind::Flap{ link } | FaultKind::Impair{ link, .. } => format!("link {}", link),
+            FaultKind::Withdraw{ netns, dst, table } => format!("route {} {} {:?}", netns, dst, table),
+        }
+    }
+}
+
+impl fmt::Display for Fault{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind{
+            FaultKind::Flap{ link } => write!(f, "flap {}", link)?,
+            FaultKind::Withdraw{ netns, dst, table } => {
+                write!(f, "withdraw {} in {}", dst, netns)?;
+                if let Some(table) = table{
+                    write!(f, " table {}", table)?;
+                }
+            },
+            FaultKind::Impair{ link, qos } => {
+                write!(f, "impair {}", link)?;
+                if let Some(delay) = qos.delay{
+                    write!(f, " delay {} ms", delay)?;
+                }
+                if let Some(loss) = qos.loss{
+                    write!(f, " loss {}%", loss)?;
+                }
+            },
+        }
+        write!(f, " for {} ms", self.duration)
+    }
+}
+
+/// What faults may hit.
+#[derive(Clone, Debug, Default)]
+pub struct Targets{
+    /// links to flap
+    pub links: Vec<String>,
+    /// links without impairments of their own
+    pub impairable: Vec<String>,
+    /// (netns, dst, table) of the static routes
+    pub routes: Vec<(String, String, Option<u32>)>,
+}
+
+impl Targets{
+    /// Links and static routes of `state`.
+    pub fn of(state: &State) -> Result<Targets>{
+        let mut targets = Targets::default();
+        for link in &state.links{
+            let ends = chaos::link_ends(state, &link.name)?;
+            let mut impaired = false;
+            for (netns, interface) in &ends{
+                let qdiscs = cmd::tc(Some(netns), &["qdisc", "show", "dev", interface, "root"])?;
+                impaired |= qdiscs.contains("netem") || qdiscs.contains("tbf");
+            }
+            targets.links.push(link.name.clone());
+            if !impaired {
+                targets.impairable.push(link.name.clone());
+            }
+        }
+        for r in &state.routes{
+            let route = (r.netns.clone(), r.dst.clone(), r.table);
+            if !targets.routes.contains(&route) {
+                targets.routes.push(route);
+            }
+        }
+        Ok(targets)
+    }
+}
+
+/// Faults of a run and where they came from.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FuzzPlan{
+    pub topology: String,
+    pub seed: u64,
+    pub intensity: Intensity,
+    pub faults: Vec<Fault>,
+}
+
+impl FuzzPlan{
+    /// `count` faults on `targets`, the same ones for the same seed,
+    /// intensity and targets.
+    pub fn generate(topology: &str, targets: &Targets, seed: u64, intensity: Intensity, count: u32) -> Result<FuzzPlan>{
+        if targets.links.is_empty() && targets.routes.is_empty() {
+            return Err(failed!("Topology {} has neither links nor static routes to fuzz", topology));
+        }
+        let p = intensity.params();
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut faults: Vec<Fault> = Vec::new();
+        // (target, end) of the faults lasting at `at`
+        let mut lasting: Vec<(String, u64)> = Vec::new();
+        let mut at = 0;
+        while faults.len() < count as usize {
+            at += rng.gen_range(p.interval / 2..=p.interval * 3 / 2);
+            lasting.retain(|(_, end)| *end > at);
+            loop{
+                let busy = |target: String| lasting.iter().any(|(t, _)| *t == target);
+                let links: Vec<&String> = targets.links.iter().filter(|l| !busy(format!("link {}", l))).collect();
+                let impairable: Vec<&String> = targets.impairable.iter().filter(|l| !busy(format!("link {}", l))).collect();
+                let routes: Vec<_> = targets.routes.iter()
+                    .filter(|(netns, dst, table)| !busy(format!("route {} {} {:?}", netns, dst, table)))
+                    .collect();
+                // flaps weigh 5, impairments 3 and withdrawals 2
+                let weights = [
+                    if links.is_empty() { 0 } else { 5 },
+                    if impairable.is_empty() { 0 } else { 3 },
+                    if routes.is_empty() { 0 } else { 2 },
+                ];
+                let total: u32 = weights.iter().sum();
+                if lasting.len() >= p.concurrent || total == 0 {
+                    // wait for the first lasting fault to end
+                    at = lasting.iter().map(|(_, end)| *end).min().unwrap_or(at);
+                    lasting.retain(|(_, end)| *end > at);
+                    continue;
+                }
+                let mut pick = rng.gen_range(0..total);
+                let kind = weights.iter().position(|w| {
+                    let found = pick < *w;
+                    pick = pick.saturating_sub(*w);
+                    found
+                }).unwrap_or(0);
+                let duration = rng.gen_range(p.duration.0..=p.duration.1);
+                let kind = match kind{
+                    0 => FaultKind::Flap{ link: links[rng.gen_range(0..links.len())].clone() },
+                    1 => {
+                        let link = impairable[rng.gen_range(0..impairable.len())].clone();
+                        let delay = rng.gen_range(p.delay.0..=p.delay.1) as f64;
+                        let loss = (rng.gen_range(p.loss.0..=p.loss.1) * 10.0).round() / 10.0;
+                        FaultKind::Impair{ link, qos: LinkQos{ delay: Some(delay), loss: Some(loss), ..Default::default() } }
+                    },
+                    _ => {
+                        let (netns, dst, table) = routes[rng.gen_range(0..routes.len())].clone();
+                        FaultKind::Withdraw{ netns, dst, table }
+                    },
+                };
+                let fault = Fault{ at, duration, kind };
+                lasting.push((fault.target(), at + duration));
+                faults.push(fault);
+                break;
+            }
+        }
+        Ok(FuzzPlan{ topology: topology.to_string(), seed, intensity, faults })
+    }
+
+    pub fn load(path: &std::path::Path) -> Result<FuzzPlan>{
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| failed!("Failed to read plan {}: {}", path.display(), e))?;
+        serde_yaml::from_str(&data)
+            .map_err(|e| failed!("Failed to parse plan {}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &std::path::Path) -> Result<()>{
+        std::fs::write(path, serde_yaml::to_string(self)?)
+            .map_err(|e| failed!("Failed to write plan {}: {}", path.display(), e))
+    }
+
+    /// Faults hitting the same target at the same time.
+    pub fn check(&self) -> Result<()>{
+        for (n, f) in self.faults.iter().enumerate(){
+            let overlapping = self.faults[..n].iter()
+                .find(|o| o.target() == f.target() && o.at < f.at + f.duration && f.at < o.at + o.duration);
+            if let Some(o) = overlapping {
+                return Err(RouterError::Invalid(format!("Faults {} and {} overlap", o, f)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Start or end of a fault during a run.
+#[derive(Serialize, Clone, Debug)]
+pub struct FuzzRecord{
+    /// since the start of the run
+    pub at: Duration,
+    /// index of the fault in the plan
+    pub fault: usize,
+    /// the fault was undone
+    pub end: bool,
+    pub description: String,
+}
+
+impl fmt::Display for FuzzRecord{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>9.3}s #{} ", self.at.as_secs_f64(), self.fault)?;
+        if self.end {
+            write!(f, "end of ")?;
+        }
+        write!(f, "{}", self.description)
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FuzzReport{
+    pub seed: u64,
+    pub intensity: Intensity,
+    pub timeline: Vec<FuzzRecord>,
+    /// time from the end of the last fault until the routing tables were
+    /// as before the run, None if they weren't within the settle time
+    pub recovered: Option<Duration>,
+    /// namespaces whose routes differ from those before the run
+    pub failures: Vec<String>,
+}
+
+impl fmt::Display for FuzzReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "seed {} intensity {}", self.seed, self.intensity)?;
+        for r in &self.timeline{
+            writeln!(f, "{}", r)?;
+        }
+        match self.recovered{
+            Some(t) => writeln!(f, "routing recovered {:.1} ms after the last fault", t.as_secs_f64() * 1000.0),
+            None => writeln!(f, "routing did not recover in {}", self.failures.join(" ")),
+        }
+    }
+}
+
+pub struct FuzzRun{
+    pub plan: FuzzPlan,
+    /// how long routing may take to recover after the last fault
+    pub settle: Duration,
+}
+
+impl FuzzRun{
+    /// Runs the plan. If a fault fails to be made or undone, the faults
+    /// lasting are undone and the run fails.
+    pub fn run(&self) -> Result<FuzzReport>{
+        self.plan.check()?;
+        let state = State::load(&self.plan.topology)?
+            .ok_or_else(|| RouterError::TopologyNotFound(self.plan.topology.to_string()))?;
+        // (at, end, fault), ends before the starts at their time
+        let mut steps: Vec<(u64, bool, usize)> = Vec::new();
+        for (n, fault) in self.plan.faults.iter().enumerate(){
+            steps.push((fault.at, false, n));
+            steps.push((fault.at + fault.duration, true, n));
+        }
+        steps.sort_by_key(|(at, end, n)| (*at, !*end, *n));
+        let namespaces: Vec<String> = state.namespaces.iter().map(|n| n.netns.clone()).collect();
+        let before = sorted(chaos::routes(&namespaces)?);
+        let mut report = FuzzReport{
+            seed: self.plan.seed,
+            intensity: self.plan.intensity,
+            timeline: Vec::new(),
+            recovered: None,
+            failures: Vec::new(),
+        };
+        // withdrawn routes per fault, to put back
+        let mut withdrawn: Vec<Vec<String>> = vec![Vec::new(); self.plan.faults.len()];
+        let mut lasting: Vec<usize> = Vec::new();
+        let start = Instant::now();
+        let result = (|| -> Result<()>{
+            for (at, end, n) in &steps{
+                let fault = &self.plan.faults[*n];
+                std::thread::sleep((start + Duration::from_millis(*at)).saturating_duration_since(Instant::now()));
+                let applied = start.elapsed();
+                if *end {
+                    undo(&state, fault, &withdrawn[*n])?;
+                    lasting.retain(|l| l != n);
+                } else {
+                    withdrawn[*n] = make(&state, fault)?;
+                    lasting.push(*n);
+                }
+                tracing::info!(fault = n, end, description = %fault, "fault");
+                report.timeline.push(FuzzRecord{ at: applied, fault: *n, end: *end, description: fault.to_string() });
+            }
+            Ok(())
+        })();
+        if let Err(e) = result {
+            for n in lasting{
+                let _ = undo(&state, &self.plan.faults[n], &withdrawn[n]);
+            }
+            return Err(e);
+        }
+        let ended = Instant::now();
+        loop{
+            let now = sorted(chaos::routes(&namespaces)?);
+            if now == before {
+                report.recovered = Some(ended.elapsed());
+                break;
+            }
+            if ended.elapsed() >= self.settle {
+                report.failures = namespaces.iter().zip(now.iter().zip(&before))
+                    .filter(|(_, (now, before))| now != before)
+                    .map(|(netns, _)| netns.clone())
+                    .collect();
+                break;
+            }
+            std::thread::sleep(POLL);
+        }
+        Ok(report)
+    }
+}
+
+fn sorted(mut tables: Vec<Vec<String>>) -> Vec<Vec<String>> {
+    for table in &mut tables{
+        table.sort();
+    }
+    tables
+}
+
+/// Makes `fault`. Returns the routes withdrawn, to be put back.
+fn make(state: &State, fault: &Fault) -> Result<Vec<String>>{
+    match &fault.kind{
+        FaultKind::Flap{ link } => {
+            for (netns, interface) in chaos::link_ends(state, link)?{
+                chaos::apply(&netns, &interface, ChaosAction::Down, None)
+                    .map_err(|e| failed!("Failed to take link {} down: {}", link, e))?;
+            }
+            Ok(Vec::new())
+        },
+        FaultKind::Impair{ link, qos } => {
+            for (netns, interface) in chaos::link_ends(state, link)?{
+                qos.apply(&netns, &interface)?;
+            }
+            Ok(Vec::new())
+        },
+        FaultKind::Withdraw{ netns, dst, table } => {
+            let table = table.map(|t| t.to_string()).unwrap_or_else(|| "main".to_string());
+            let family = if dst.contains(':') { "-6" } else { "-4" };
+            let routes = routes(netns, family, dst, &table)?;
+            cmd::ip(Some(netns), &[family, "route", "flush", "exact", dst.as_str(), "table", table.as_str()])?;
+            Ok(routes)
+        },
+    }
+}
+
+/// Undoes `fault`, putting back the routes `withdrawn`.
+fn undo(state: &State, fault: &Fault, withdrawn: &[String]) -> Result<()>{
+    match &fault.kind{
+        FaultKind::Flap{ link } => {
+            for (netns, interface) in chaos::link_ends(state, link)?{
+                chaos::apply(&netns, &interface, ChaosAction::Up, None)
+                    .map_err(|e| failed!("Failed to bring link {} up: {}", link, e))?;
+            }
+        },
+        FaultKind::Impair{ link, .. } => {
+            for (netns, interface) in chaos::link_ends(state, link)?{
+                qos::clear(&netns, &interface)?;
+            }
+        },
+        FaultKind::Withdraw{ netns, dst, table } => {
+            let table = table.map(|t| t.to_string()).unwrap_or_else(|| "main".to_string());
+            let family = if dst.contains(':') { "-6" } else { "-4" };
+            for route in withdrawn{
+                let mut args = vec![family, "route", "replace"];
+                args.extend(route.split_whitespace());
+                args.extend(["table", table.as_str()]);
+                cmd::ip(Some(netns), &args)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Routes to exactly `dst` in `table`, one line each with the nexthops of
+/// multipath routes joined in and flags `ip route` doesn't take left out.
+fn routes(netns: &str, family: &str, dst: &str, table: &str) -> Result<Vec<String>>{
+    let output = cmd::ip(Some(netns), &[family, "route", "show", "exact", dst, "table", table])?;
+    let mut routes: Vec<String> = Vec::new();
+    for line in output.lines(){
+        let words: Vec<&str> = line.split_whitespace()
+            .filter(|w| !matches!(*w, "linkdown" | "dead" | "offload" | "trap" | "rt_offload" | "rt_trap"))
+            .collect();
+        match routes.last_mut(){
+            Some(route) if line.starts_with(char::is_whitespace) => {
+                route.push(' ');
+                route.push_str(&words.join(" "));
+            },
+            _ => routes.push(words.join(" ")),
+        }
+    }
+    Ok(routes)
+}
@@ -0,0 +1,108 @@
+//! Generators of common topologies, so a fabric of dozens of routers is a
+//! call instead of a hand-written description. Each returns a builder
+//! holding the namespaces and links of the shape, to be extended before
+//! `build`, e.g. with a `daemon`:
+//!
+//! ```no_run
+//! let topology = router_rs::generators::clos("fabric", 2, 4, 1)?
+//!     .ipam("10.10.0.0/16", 31)
+//!     .build()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Links are left without subnet and take theirs from the `ipam` pool,
+//! `DEFAULT_POOL` unless another one is added. Routers hash ECMP and
+//! `auto_routes` is set, so every namespace gets routes to every subnet
+//! over all equally cheap paths. Names are kept short for the interface
+//! names `<namespace>_<link>` to stay within the 15 characters of the
+//! kernel.
+
+use crate::error::{failed, Result};
+use crate::topology::{Topology, TopologyBuilder};
+
+/// IPv4 pool links are allocated /31 subnets from.
+pub const DEFAULT_POOL: &str = "10.0.0.0/16";
+
+/// 3-stage Clos: `spines` spine routers `spine1..`, each linked to all of
+/// the `leaves` leaf routers `leaf1..`, the links named `s<spine>l<leaf>`.
+/// Each leaf gets `hosts` stub namespaces `host1..`, numbered across
+/// leaves, on links `l<leaf>h<host>`.
+pub fn clos(name: &str, spines: u32, leaves: u32, hosts: u32) -> Result<TopologyBuilder>{
+    if spines == 0 || leaves == 0 {
+        return Err(failed!("A Clos topology needs at least one spine and one leaf"));
+    }
+    let mut b = start(name);
+    for s in 1..=spines{
+        b = b.namespace(&format!("spine{}", s)).ecmp();
+    }
+    for l in 1..=leaves{
+        b = b.namespace(&format!("leaf{}", l)).ecmp();
+    }
+    for s in 1..=spines{
+        for l in 1..=leaves{
+            b = b.link(&format!("s{}l{}", s, l), "").connect(&format!("spine{}", s), &format!("leaf{}", l));
+        }
+    }
+    let mut host = 0;
+    for l in 1..=leaves{
+        for _ in 0..hosts{
+            host += 1;
+            b = b.namespace(&format!("host{}", host)).stub()
+                .link(&format!("l{}h{}", l, host), "").connect(&format!("leaf{}", l), &format!("host{}", host));
+        }
+    }
+    Ok(b)
+}
+
+/// Ring of `nodes` routers `r1..`, each linked to the next and the last to
+/// the first, the links named `r<a>r<b>`.
+pub fn ring(name: &str, nodes: u32) -> Result<TopologyBuilder>{
+    if nodes < 3 {
+        return Err(failed!("A ring needs at least 3 nodes, got {}", nodes));
+    }
+    let mut b = routers(start(name), nodes);
+    for n in 1..=nodes{
+        let next = n % nodes + 1;
+        b = b.link(&format!("r{}r{}", n, next), "").connect(&format!("r{}", n), &format!("r{}", next));
+    }
+    Ok(b)
+}
+
+/// Full mesh of `nodes` routers `r1..`, every pair linked, the links named
+/// `r<a>r<b>` with `a < b`.
+pub fn mesh(name: &str, nodes: u32) -> Result<TopologyBuilder>{
+    if nodes < 2 {
+        return Err(failed!("A mesh needs at least 2 nodes, got {}", nodes));
+    }
+    let mut b = routers(start(name), nodes);
+    for x in 1..=nodes{
+        for y in x + 1..=nodes{
+            b = b.link(&format!("r{}r{}", x, y), "").connect(&format!("r{}", x), &format!("r{}", y));
+        }
+    }
+    Ok(b)
+}
+
+/// Star of `spokes` routers `r1..` around the router `hub`, the links named
+/// `hub<spoke>`.
+pub fn star(name: &str, spokes: u32) -> Result<TopologyBuilder>{
+    if spokes == 0 {
+        return Err(failed!("A star needs at least one spoke"));
+    }
+    let mut b = routers(start(name).namespace("hub").ecmp(), spokes);
+    for n in 1..=spokes{
+        b = b.link(&format!("hub{}", n), "").connect("hub", &format!("r{}", n));
+    }
+    Ok(b)
+}
+
+fn start(name: &str) -> TopologyBuilder {
+    Topology::builder(name).ipam(DEFAULT_POOL, 31).auto_routes()
+}
+
+fn routers(mut b: TopologyBuilder, count: u32) -> TopologyBuilder {
+    for n in 1..=count{
+        b = b.namespace(&format!("r{}", n)).ecmp();
+    }
+    b
+}
@@ -0,0 +1,653 @@
+//! Minimal gNMI server presenting the namespaces of a running topology as
+//! devices, so telemetry collectors like gnmic or telegraf monitor the
+//! simulated routers as they would real ones. The `target` of a request
+//! names the namespace. Served is a read-only subset of OpenConfig state:
+//!
+//! - `/interfaces/interface[name]/state`: name, admin-status, oper-status,
+//!   mtu and counters
+//! - `/interfaces/interface[name]/subinterfaces/subinterface[index=0]/ipv4/addresses/address[ip]/state`,
+//!   likewise for ipv6: ip and prefix-length
+//! - `/network-instances/network-instance[name=default]/afts/ipv4-unicast/ipv4-entry[prefix]/state`,
+//!   likewise for ipv6: prefix and origin-protocol
+//!
+//! Capabilities, Get and Subscribe in the modes ONCE, POLL and STREAM are
+//! implemented, Set is not. Values are scalars with the PROTO encoding and
+//! JSON with JSON and JSON_IETF. Paths match everything below them, `*`
+//! matches any element name or key value and a key left out any value.
+//!
+//! The messages are a hand-written subset of gnmi.proto, unknown fields of
+//! requests are skipped. The service is generated by `build.rs`.
+
+// tonic::Status is what every gRPC handler fails with
+#![allow(clippy::result_large_err)]
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::cmd;
+use crate::error::{failed, Result};
+use crate::stats::InterfaceStats;
+use crate::{state, Namespace};
+
+use proto::{Encoding, Notification, Path, PathElem, SubscribeResponse, SubscriptionList, SubscriptionMode, TypedValue};
+
+pub mod proto{
+    use std::collections::BTreeMap;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Encoding{
+        Json = 0,
+        Bytes = 1,
+        Proto = 2,
+        Ascii = 3,
+        JsonIetf = 4,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PathElem{
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(btree_map = "string, string", tag = "2")]
+        pub key: BTreeMap<String, String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Path{
+        #[prost(string, tag = "2")]
+        pub origin: String,
+        #[prost(message, repeated, tag = "3")]
+        pub elem: Vec<PathElem>,
+        #[prost(string, tag = "4")]
+        pub target: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TypedValue{
+        #[prost(oneof = "typed_value::Value", tags = "1, 2, 3, 4, 5, 10, 11, 12, 14")]
+        pub value: Option<typed_value::Value>,
+    }
+
+    pub mod typed_value{
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Value{
+            #[prost(string, tag = "1")]
+            StringVal(String),
+            #[prost(int64, tag = "2")]
+            IntVal(i64),
+            #[prost(uint64, tag = "3")]
+            UintVal(u64),
+            #[prost(bool, tag = "4")]
+            BoolVal(bool),
+            #[prost(bytes, tag = "5")]
+            BytesVal(Vec<u8>),
+            #[prost(bytes, tag = "10")]
+            JsonVal(Vec<u8>),
+            #[prost(bytes, tag = "11")]
+            JsonIetfVal(Vec<u8>),
+            #[prost(string, tag = "12")]
+            AsciiVal(String),
+            #[prost(double, tag = "14")]
+            DoubleVal(f64),
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Update{
+        #[prost(message, optional, tag = "1")]
+        pub path: Option<Path>,
+        #[prost(message, optional, tag = "3")]
+        pub val: Option<TypedValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Notification{
+        /// nanoseconds since the epoch
+        #[prost(int64, tag = "1")]
+        pub timestamp: i64,
+        #[prost(message, optional, tag = "2")]
+        pub prefix: Option<Path>,
+        #[prost(message, repeated, tag = "4")]
+        pub update: Vec<Update>,
+        #[prost(message, repeated, tag = "5")]
+        pub delete: Vec<Path>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ModelData{
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub organization: String,
+        #[prost(string, tag = "3")]
+        pub version: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CapabilityRequest{}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CapabilityResponse{
+        #[prost(message, repeated, tag = "1")]
+        pub supported_models: Vec<ModelData>,
+        #[prost(enumeration = "Encoding", repeated, tag = "2")]
+        pub supported_encodings: Vec<i32>,
+        #[prost(string, tag = "3")]
+        pub gnmi_version: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GetRequest{
+        #[prost(message, optional, tag = "1")]
+        pub prefix: Option<Path>,
+        #[prost(message, repeated, tag = "2")]
+        pub path: Vec<Path>,
+        #[prost(enumeration = "Encoding", tag = "5")]
+        pub encoding: i32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GetResponse{
+        #[prost(message, repeated, tag = "1")]
+        pub notification: Vec<Notification>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum SubscriptionMode{
+        TargetDefined = 0,
+        OnChange = 1,
+        Sample = 2,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Subscription{
+        #[prost(message, optional, tag = "1")]
+        pub path: Option<Path>,
+        #[prost(enumeration = "SubscriptionMode", tag = "2")]
+        pub mode: i32,
+        /// nanoseconds
+        #[prost(uint64, tag = "3")]
+        pub sample_interval: u64,
+        #[prost(bool, tag = "4")]
+        pub suppress_redundant: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SubscriptionList{
+        #[prost(message, optional, tag = "1")]
+        pub prefix: Option<Path>,
+        #[prost(message, repeated, tag = "2")]
+        pub subscription: Vec<Subscription>,
+        #[prost(enumeration = "subscription_list::Mode", tag = "5")]
+        pub mode: i32,
+        #[prost(enumeration = "Encoding", tag = "8")]
+        pub encoding: i32,
+        #[prost(bool, tag = "9")]
+        pub updates_only: bool,
+    }
+
+    pub mod subscription_list{
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+        #[repr(i32)]
+        pub enum Mode{
+            Stream = 0,
+            Once = 1,
+            Poll = 2,
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Poll{}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SubscribeRequest{
+        #[prost(oneof = "subscribe_request::Request", tags = "1, 3")]
+        pub request: Option<subscribe_request::Request>,
+    }
+
+    pub mod subscribe_request{
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Request{
+            #[prost(message, tag = "1")]
+            Subscribe(super::SubscriptionList),
+            #[prost(message, tag = "3")]
+            Poll(super::Poll),
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SubscribeResponse{
+        #[prost(oneof = "subscribe_response::Response", tags = "1, 3")]
+        pub response: Option<subscribe_response::Response>,
+    }
+
+    pub mod subscribe_response{
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Response{
+            #[prost(message, tag = "1")]
+            Update(super::Notification),
+            #[prost(bool, tag = "3")]
+            SyncResponse(bool),
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
+mod service{
+    include!(concat!(env!("OUT_DIR"), "/gnmi.gNMI.rs"));
+}
+
+pub use service::g_n_m_i_client::gNMIClient as Client;
+use service::g_n_m_i_server::{gNMI, gNMIServer};
+
+pub const VERSION: &str = "0.10.0";
+/// Port assigned to gNMI by IANA.
+pub const PORT: u16 = 9339;
+/// Sample interval of STREAM subscriptions which don't set one.
+const DEFAULT_SAMPLE: Duration = Duration::from_secs(10);
+/// Shortest sample interval, also how often on change subscriptions look
+/// for changes.
+const MIN_SAMPLE: Duration = Duration::from_secs(1);
+
+/// Value at a path of the state of a namespace.
+#[derive(Clone, Debug)]
+pub struct Leaf{
+    pub path: Vec<PathElem>,
+    pub value: serde_json::Value,
+}
+
+fn elem(name: &str, key: &[(&str, &str)]) -> PathElem {
+    PathElem{
+        name: name.to_string(),
+        key: key.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+    }
+}
+
+fn push(leaves: &mut Vec<Leaf>, base: &[PathElem], leaf: &[&str], value: serde_json::Value){
+    let mut path = base.to_vec();
+    path.extend(leaf.iter().map(|name| elem(name, &[])));
+    leaves.push(Leaf{ path, value });
+}
+
+/// Readable form of a path, e.g. `/interfaces/interface[name=r_l1]/state/mtu`.
+pub fn path_string(path: &[PathElem]) -> String {
+    let mut s = String::new();
+    for e in path{
+        s.push('/');
+        s.push_str(&e.name);
+        for (k, v) in &e.key{
+            s.push_str(&format!("[{}={}]", k, v));
+        }
+    }
+    if s.is_empty() {
+        s.push('/');
+    }
+    s
+}
+
+/// True if `path` is at or below `pattern`.
+fn matches(pattern: &[PathElem], path: &[PathElem]) -> bool {
+    pattern.len() <= path.len() && pattern.iter().zip(path).all(|(p, e)| {
+        (p.name == "*" || p.name == e.name)
+            && p.key.iter().all(|(k, v)| v == "*" || e.key.get(k) == Some(v))
+    })
+}
+
+/// OpenConfig state of `netns`.
+pub fn leaves(netns: &str) -> Result<Vec<Leaf>>{
+    let mut leaves = Vec::new();
+    let links = cmd::ip_json(Some(netns), &["-s", "-j", "link", "show"])?;
+    for l in links.as_array().cloned().unwrap_or_default(){
+        let Some(name) = l["ifname"].as_str() else {
+            continue;
+        };
+        let base = [elem("interfaces", &[]), elem("interface", &[("name", name)]), elem("state", &[])];
+        let up = l["flags"].as_array().is_some_and(|f| f.iter().any(|f| f == "UP"));
+        let oper = match l["operstate"].as_str(){
+            Some("UP") => "UP",
+            Some("DOWN") => "DOWN",
+            Some("LOWERLAYERDOWN") => "LOWER_LAYER_DOWN",
+            Some("DORMANT") => "DORMANT",
+            Some("NOTPRESENT") => "NOT_PRESENT",
+            Some("TESTING") => "TESTING",
+            _ => "UNKNOWN",
+        };
+        push(&mut leaves, &base, &["name"], name.into());
+        push(&mut leaves, &base, &["admin-status"], if up { "UP" } else { "DOWN" }.into());
+        push(&mut leaves, &base, &["oper-status"], oper.into());
+        if let Some(mtu) = l["mtu"].as_u64(){
+            push(&mut leaves, &base, &["mtu"], mtu.into());
+        }
+        let stats = InterfaceStats::from_json(&l);
+        for (counter, value) in [
+            ("in-octets", stats.rx_bytes),
+            ("in-pkts", stats.rx_packets),
+            ("in-discards", stats.rx_dropped),
+            ("in-errors", stats.rx_errors),
+            ("out-octets", stats.tx_bytes),
+            ("out-pkts", stats.tx_packets),
+            ("out-discards", stats.tx_dropped),
+            ("out-errors", stats.tx_errors),
+        ]{
+            push(&mut leaves, &base, &["counters", counter], value.into());
+        }
+    }
+    let addresses = cmd::ip_json(Some(netns), &["-j", "addr", "show"])?;
+    for a in addresses.as_array().cloned().unwrap_or_default(){
+        let Some(name) = a["ifname"].as_str() else {
+            continue;
+        };
+        for info in a["addr_info"].as_array().cloned().unwrap_or_default(){
+            let (Some(local), Some(len)) = (info["local"].as_str(), info["prefixlen"].as_u64()) else {
+                continue;
+            };
+            let family = if info["family"] == "inet6" { "ipv6" } else { "ipv4" };
+            let base = [
+                elem("interfaces", &[]),
+                elem("interface", &[("name", name)]),
+                elem("subinterfaces", &[]),
+                elem("subinterface", &[("index", "0")]),
+                elem(family, &[]),
+                elem("addresses", &[]),
+                elem("address", &[("ip", local)]),
+                elem("state", &[]),
+            ];
+            push(&mut leaves, &base, &["ip"], local.into());
+            push(&mut leaves, &base, &["prefix-length"], len.into());
+        }
+    }
+    for (family, v6) in [("-4", false), ("-6", true)]{
+        let routes = cmd::ip_json(Some(netns), &[family, "-j", "route", "show"])?;
+        let mut seen = Vec::new();
+        for r in routes.as_array().cloned().unwrap_or_default(){
+            let Some(dst) = r["dst"].as_str() else {
+                continue;
+            };
+            let prefix = match (dst, v6){
+                ("default", false) => "0.0.0.0/0".to_string(),
+                ("default", true) => "::/0".to_string(),
+                (dst, false) if !dst.contains('/') => format!("{}/32", dst),
+                (dst, true) if !dst.contains('/') => format!("{}/128", dst),
+                (dst, _) => dst.to_string(),
+            };
+            // routes to the same prefix with different metrics
+            if seen.contains(&prefix) {
+                continue;
+            }
+            seen.push(prefix.clone());
+            let (afi, entry) = if v6 { ("ipv6-unicast", "ipv6-entry") } else { ("ipv4-unicast", "ipv4-entry") };
+            let base = [
+                elem("network-instances", &[]),
+                elem("network-instance", &[("name", "default")]),
+                elem("afts", &[]),
+                elem(afi, &[]),
+                elem(entry, &[("prefix", prefix.as_str())]),
+                elem("state", &[]),
+            ];
+            push(&mut leaves, &base, &["prefix"], prefix.as_str().into());
+            let origin = match r["protocol"].as_str(){
+                Some("kernel") => Some("DIRECTLY_CONNECTED"),
+                Some("static") | Some("boot") => Some("STATIC"),
+                Some("bgp") => Some("BGP"),
+                Some("ospf") => Some("OSPF"),
+                _ => None,
+            };
+            if let Some(origin) = origin{
+                push(&mut leaves, &base, &["origin-protocol"], origin.into());
+            }
+        }
+    }
+    Ok(leaves)
+}
+
+fn typed(value: &serde_json::Value, encoding: Encoding) -> Result<TypedValue, Status>{
+    use proto::typed_value::Value;
+    let value = match (encoding, value){
+        (Encoding::Proto, serde_json::Value::String(s)) => Value::StringVal(s.clone()),
+        (Encoding::Proto, serde_json::Value::Bool(b)) => Value::BoolVal(*b),
+        (Encoding::Proto, serde_json::Value::Number(n)) if n.is_u64() => Value::UintVal(n.as_u64().unwrap_or_default()),
+        (Encoding::Proto, value) | (Encoding::Json, value) => Value::JsonVal(value.to_string().into_bytes()),
+        (Encoding::JsonIetf, value) => Value::JsonIetfVal(value.to_string().into_bytes()),
+        (encoding, _) => return Err(Status::unimplemented(format!("Encoding {:?} is not supported", encoding))),
+    };
+    Ok(TypedValue{ value: Some(value) })
+}
+
+fn encoding(encoding: i32) -> Result<Encoding, Status>{
+    Encoding::try_from(encoding).map_err(|_| Status::invalid_argument(format!("Unknown encoding {}", encoding)))
+}
+
+fn notification(target: &str, leaves: &[Leaf], encoding: Encoding) -> Result<Notification, Status>{
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as i64;
+    let mut update = Vec::new();
+    for leaf in leaves{
+        update.push(proto::Update{
+            path: Some(Path{ elem: leaf.path.clone(), ..Default::default() }),
+            val: Some(typed(&leaf.value, encoding)?),
+        });
+    }
+    Ok(Notification{
+        timestamp,
+        prefix: Some(Path{ target: target.to_string(), ..Default::default() }),
+        update,
+        delete: Vec::new(),
+    })
+}
+
+fn update(notification: Notification) -> SubscribeResponse {
+    SubscribeResponse{ response: Some(proto::subscribe_response::Response::Update(notification)) }
+}
+
+fn sync() -> SubscribeResponse {
+    SubscribeResponse{ response: Some(proto::subscribe_response::Response::SyncResponse(true)) }
+}
+
+/// Subscription of a STREAM subscription list.
+struct Stream{
+    target: String,
+    pattern: Vec<PathElem>,
+    interval: Duration,
+    /// only send leaves whose value changed
+    changes: bool,
+    next: Instant,
+    last: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Clone)]
+pub struct GnmiServer{
+    pub topology: String,
+    pub listen: SocketAddr,
+}
+
+impl GnmiServer{
+    /// Serves until interrupted.
+    pub fn run(self) -> Result<()>{
+        let listen = self.listen;
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            tonic::transport::Server::builder()
+                .add_service(gNMIServer::new(self))
+                .serve(listen)
+                .await
+        }).map_err(|e| failed!("Failed to serve gNMI on {}: {}", listen, e))
+    }
+
+    /// Namespace and path pattern of `path` below `prefix`.
+    fn resolve(&self, prefix: Option<&Path>, path: Option<&Path>) -> Result<(String, Vec<PathElem>), Status>{
+        let target = [prefix, path].into_iter().flatten()
+            .map(|p| p.target.as_str())
+            .find(|t| !t.is_empty())
+            .ok_or_else(|| Status::invalid_argument(format!("No target, expected a namespace of {}", self.topology)))?;
+        let namespaces = state::namespaces(&self.topology).map_err(|e| Status::internal(e.to_string()))?;
+        if !namespaces.contains(&Namespace::netns_name(&self.topology, target)) {
+            return Err(Status::not_found(format!("Namespace {} not found in {}", target, self.topology)));
+        }
+        let pattern = [prefix, path].into_iter().flatten().flat_map(|p| p.elem.iter().cloned()).collect();
+        Ok((target.to_string(), pattern))
+    }
+
+    /// Leaves of `target` at or below `pattern`.
+    async fn collect(&self, target: &str, pattern: &[PathElem]) -> Result<Vec<Leaf>, Status>{
+        let netns = Namespace::netns_name(&self.topology, target);
+        let leaves = tokio::task::spawn_blocking(move || leaves(&netns))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(leaves.into_iter().filter(|l| matches(pattern, &l.path)).collect())
+    }
+
+    async fn send(&self, list: &SubscriptionList, tx: &mpsc::Sender<Result<SubscribeResponse, Status>>) -> Result<bool, Status>{
+        let encoding = encoding(list.encoding)?;
+        for s in &list.subscription{
+            let (target, pattern) = self.resolve(list.prefix.as_ref(), s.path.as_ref())?;
+            let leaves = self.collect(&target, &pattern).await?;
+            if tx.send(Ok(update(notification(&target, &leaves, encoding)?))).await.is_err() {
+                return Ok(false);
+            }
+        }
+        Ok(tx.send(Ok(sync())).await.is_ok())
+    }
+
+    /// Answers a subscription list until the client goes away.
+    async fn subscription(&self, list: SubscriptionList, mut requests: Streaming<proto::SubscribeRequest>, tx: &mpsc::Sender<Result<SubscribeResponse, Status>>) -> Result<(), Status>{
+        use proto::subscription_list::Mode;
+        if list.subscription.is_empty() {
+            return Err(Status::invalid_argument("Empty subscription list"));
+        }
+        let mode = Mode::try_from(list.mode).map_err(|_| Status::invalid_argument(format!("Unknown mode {}", list.mode)))?;
+        match mode{
+            Mode::Once => {
+                self.send(&list, tx).await?;
+                Ok(())
+            },
+            Mode::Poll => {
+                if !self.send(&list, tx).await? {
+                    return Ok(());
+                }
+                while let Some(request) = requests.message().await?{
+                    match request.request{
+                        Some(proto::subscribe_request::Request::Poll(_)) => {
+                            if !self.send(&list, tx).await? {
+                                return Ok(());
+                            }
+                        },
+                        _ => return Err(Status::invalid_argument("Expected a poll")),
+                    }
+                }
+                Ok(())
+            },
+            Mode::Stream => self.stream(&list, tx).await,
+        }
+    }
+
+    async fn stream(&self, list: &SubscriptionList, tx: &mpsc::Sender<Result<SubscribeResponse, Status>>) -> Result<(), Status>{
+        let encoding = encoding(list.encoding)?;
+        let mut streams = Vec::new();
+        for s in &list.subscription{
+            let (target, pattern) = self.resolve(list.prefix.as_ref(), s.path.as_ref())?;
+            let mode = SubscriptionMode::try_from(s.mode).map_err(|_| Status::invalid_argument(format!("Unknown subscription mode {}", s.mode)))?;
+            let (interval, changes) = match mode{
+                SubscriptionMode::Sample if s.sample_interval == 0 => (DEFAULT_SAMPLE, s.suppress_redundant),
+                SubscriptionMode::Sample => (Duration::from_nanos(s.sample_interval).max(MIN_SAMPLE), s.suppress_redundant),
+                SubscriptionMode::OnChange | SubscriptionMode::TargetDefined => (MIN_SAMPLE, true),
+            };
+            streams.push(Stream{ target, pattern, interval, changes, next: Instant::now(), last: HashMap::new() });
+        }
+        let mut first = true;
+        loop{
+            let now = Instant::now();
+            for s in streams.iter_mut().filter(|s| s.next <= now){
+                s.next = now + s.interval;
+                let mut leaves = self.collect(&s.target, &s.pattern).await?;
+                if s.changes {
+                    leaves.retain(|l| s.last.get(&path_string(&l.path)) != Some(&l.value));
+                }
+                for l in &leaves{
+                    s.last.insert(path_string(&l.path), l.value.clone());
+                }
+                if leaves.is_empty() || (first && list.updates_only) {
+                    continue;
+                }
+                if tx.send(Ok(update(notification(&s.target, &leaves, encoding)?))).await.is_err() {
+                    return Ok(());
+                }
+            }
+            if first {
+                first = false;
+                if tx.send(Ok(sync())).await.is_err() {
+                    return Ok(());
+                }
+            }
+            if tx.is_closed() {
+                return Ok(());
+            }
+            let next = streams.iter().map(|s| s.next).min().unwrap_or(now + MIN_SAMPLE);
+            tokio::time::sleep_until(next).await;
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl gNMI for GnmiServer{
+    async fn capabilities(&self, _request: Request<proto::CapabilityRequest>) -> Result<Response<proto::CapabilityResponse>, Status>{
+        let model = |name: &str, version: &str| proto::ModelData{
+            name: name.to_string(),
+            organization: "OpenConfig working group".to_string(),
+            version: version.to_string(),
+        };
+        Ok(Response::new(proto::CapabilityResponse{
+            supported_models: vec![
+                model("openconfig-interfaces", "3.0.0"),
+                model("openconfig-if-ip", "3.0.0"),
+                model("openconfig-network-instance", "4.0.0"),
+                model("openconfig-aft", "2.0.0"),
+            ],
+            supported_encodings: vec![Encoding::Json as i32, Encoding::Proto as i32, Encoding::JsonIetf as i32],
+            gnmi_version: VERSION.to_string(),
+        }))
+    }
+
+    async fn get(&self, request: Request<proto::GetRequest>) -> Result<Response<proto::GetResponse>, Status>{
+        let request = request.into_inner();
+        let encoding = encoding(request.encoding)?;
+        let root = [Path::default()];
+        let paths = if request.path.is_empty() { &root[..] } else { &request.path[..] };
+        let mut notifications = Vec::new();
+        for path in paths{
+            let (target, pattern) = self.resolve(request.prefix.as_ref(), Some(path))?;
+            let leaves = self.collect(&target, &pattern).await?;
+            if leaves.is_empty() {
+                return Err(Status::not_found(format!("Nothing at {} in {}", path_string(&pattern), target)));
+            }
+            notifications.push(notification(&target, &leaves, encoding)?);
+        }
+        Ok(Response::new(proto::GetResponse{ notification: notifications }))
+    }
+
+    type SubscribeStream = ReceiverStream<Result<SubscribeResponse, Status>>;
+
+    async fn subscribe(&self, request: Request<Streaming<proto::SubscribeRequest>>) -> Result<Response<Self::SubscribeStream>, Status>{
+        let mut requests = request.into_inner();
+        let first = requests.message().await?
+            .and_then(|r| r.request)
+            .ok_or_else(|| Status::invalid_argument("No subscription list"))?;
+        let proto::subscribe_request::Request::Subscribe(list) = first else {
+            return Err(Status::invalid_argument("The first request must be a subscription list"));
+        };
+        let (tx, rx) = mpsc::channel(16);
+        let server = self.clone();
+        tokio::spawn(async move {
+            if let Err(status) = server.subscription(list, requests, &tx).await {
+                let _ = tx.send(Err(status)).await;
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
@@ -0,0 +1,208 @@
+//! Pictures of a topology: namespaces are nodes, links are edges labelled
+//! with their subnets and the interface names at either end, bridges are
+//! nodes of their own joined to every member. VXLAN links are drawn dashed,
+//! like a link between two endpoints and like a bridge between more.
+//! Rendered as Graphviz DOT or as a Mermaid flowchart for Markdown.
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::error::{Result, RouterError};
+use crate::topology::{LinkSpec, Topology};
+use crate::BridgeBackend;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GraphFormat{
+    Dot,
+    Mermaid,
+}
+
+impl FromStr for GraphFormat{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<Self>{
+        match s{
+            "dot" => Ok(GraphFormat::Dot),
+            "mermaid" => Ok(GraphFormat::Mermaid),
+            _ => Err(RouterError::Invalid(format!("Unknown graph format {}, expected dot or mermaid", s))),
+        }
+    }
+}
+
+pub fn render(topology: &Topology, format: GraphFormat) -> Result<String>{
+    match format{
+        GraphFormat::Dot => dot(topology),
+        GraphFormat::Mermaid => mermaid(topology),
+    }
+}
+
+/// Subnets of a link or bridge, allocated ones are only known once created.
+fn subnets(subnet: &str, subnet6: &Option<String>) -> String {
+    let mut s = if subnet.is_empty() { "ipam".to_string() } else { subnet.to_string() };
+    if let Some(subnet6) = subnet6{
+        s.push(' ');
+        s.push_str(subnet6);
+    }
+    s
+}
+
+fn endpoints(link: &LinkSpec) -> Option<(&str, &str)> {
+    match link.endpoints.as_slice(){
+        [a, b] => Some((a, b)),
+        _ => None,
+    }
+}
+
+pub fn dot(topology: &Topology) -> Result<String>{
+    let mut s = String::new();
+    writeln!(s, "graph \"{}\" {{", topology.name)?;
+    writeln!(s, "  node [shape=box, style=rounded];")?;
+    for ns in &topology.namespaces{
+        let shape = if ns.stub { " shape=ellipse" } else { "" };
+        let mut label = ns.name.clone();
+        if let Some(bgp) = &ns.bgp{
+            write!(label, "\\nAS {}", bgp.asn)?;
+        }
+        if ns.p4.is_some() {
+            label.push_str("\\nP4");
+        }
+        if let Some(fwd) = &ns.forwarder{
+            write!(label, "\\n{}", fwd.kind)?;
+        }
+        if let Some(c) = &ns.container{
+            match (&c.name, c.pid){
+                (Some(name), _) => write!(label, "\\n{} {}", c.runtime, name)?,
+                (None, Some(pid)) => write!(label, "\\npid {}", pid)?,
+                (None, None) => {},
+            }
+        }
+        writeln!(s, "  \"{}\" [label=\"{}\"{}];", ns.name, label, shape)?;
+    }
+    for b in &topology.bridges{
+        let name = if b.backend == BridgeBackend::Ovs { format!("{} (OVS)", b.name) } else { b.name.clone() };
+        writeln!(s, "  \"bridge:{}\" [label=\"{}\\n{}\", shape=diamond, style=\"\"];", b.name, name, subnets(&b.subnet, &b.subnet6))?;
+        for m in &b.members{
+            writeln!(s, "  \"{}\" -- \"bridge:{}\" [taillabel=\"{}_{}\"];", m, b.name, m, b.name)?;
+        }
+    }
+    for l in &topology.links{
+        let Some((a, b)) = endpoints(l) else {
+            continue;
+        };
+        writeln!(s, "  \"{}\" -- \"{}\" [label=\"{}\\n{}\", taillabel=\"{}_{}\", headlabel=\"{}_{}\"];",
+            a, b, l.name, subnets(&l.subnet, &l.subnet6), a, l.name, b, l.name)?;
+    }
+    for v in &topology.vxlans{
+        let label = format!("{} VNI {}\\n{}", v.name, v.vni, subnets(&v.subnet, &v.subnet6));
+        match v.endpoints.as_slice(){
+            [a, b] => writeln!(s, "  \"{}\" -- \"{}\" [label=\"{}\", taillabel=\"{}_{}\", headlabel=\"{}_{}\", style=dashed];",
+                a.namespace, b.namespace, label, a.namespace, v.name, b.namespace, v.name)?,
+            endpoints => {
+                writeln!(s, "  \"vxlan:{}\" [label=\"{}\", shape=diamond, style=dashed];", v.name, label)?;
+                for e in endpoints{
+                    writeln!(s, "  \"{}\" -- \"vxlan:{}\" [taillabel=\"{}_{}\", style=dashed];", e.namespace, v.name, e.namespace, v.name)?;
+                }
+            },
+        }
+    }
+    for t in &topology.tunnels{
+        let label = format!("{} ({})\\n{}", t.name, t.kind, subnets(&t.subnet, &t.subnet6));
+        match (t.endpoints.as_slice(), &t.remote){
+            ([a, b], _) => writeln!(s, "  \"{}\" -- \"{}\" [label=\"{}\", taillabel=\"{}_{}\", headlabel=\"{}_{}\", style=dotted];",
+                a.namespace, b.namespace, label, a.namespace, t.name, b.namespace, t.name)?,
+            ([a], Some(remote)) => {
+                writeln!(s, "  \"remote:{}\" [label=\"{}\", shape=plaintext];", remote, remote)?;
+                writeln!(s, "  \"{}\" -- \"remote:{}\" [label=\"{}\", taillabel=\"{}_{}\", style=dotted];",
+                    a.namespace, remote, label, a.namespace, t.name)?;
+            },
+            _ => {},
+        }
+    }
+    for w in &topology.wireguards{
+        if let [a, b] = w.endpoints.as_slice() {
+            let label = format!("{} (wireguard)\\n{}", w.name, subnets(&w.subnet, &w.subnet6));
+            writeln!(s, "  \"{}\" -- \"{}\" [label=\"{}\", taillabel=\"{}_{}\", headlabel=\"{}_{}\", style=dotted];",
+                a.namespace, b.namespace, label, a.namespace, w.name, b.namespace, w.name)?;
+        }
+    }
+    writeln!(s, "}}")?;
+    Ok(s)
+}
+
+/// Mermaid node ids only allow a few characters, labels carry the names.
+fn mermaid_id(prefix: &str, name: &str) -> String {
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    format!("{}_{}", prefix, name)
+}
+
+pub fn mermaid(topology: &Topology) -> Result<String>{
+    let mut s = String::new();
+    writeln!(s, "flowchart LR")?;
+    for ns in &topology.namespaces{
+        let mut label = ns.name.clone();
+        if let Some(bgp) = &ns.bgp{
+            write!(label, "<br/>AS {}", bgp.asn)?;
+        }
+        if ns.p4.is_some() {
+            label.push_str("<br/>P4");
+        }
+        if let Some(fwd) = &ns.forwarder{
+            write!(label, "<br/>{}", fwd.kind)?;
+        }
+        let id = mermaid_id("ns", &ns.name);
+        if ns.stub {
+            writeln!(s, "  {}([\"{}\"])", id, label)?;
+        } else {
+            writeln!(s, "  {}[\"{}\"]", id, label)?;
+        }
+    }
+    for b in &topology.bridges{
+        let id = mermaid_id("br", &b.name);
+        let name = if b.backend == BridgeBackend::Ovs { format!("{} (OVS)", b.name) } else { b.name.clone() };
+        writeln!(s, "  {}{{\"{}<br/>{}\"}}", id, name, subnets(&b.subnet, &b.subnet6))?;
+        for m in &b.members{
+            writeln!(s, "  {} ---|\"{}_{}\"| {}", mermaid_id("ns", m), m, b.name, id)?;
+        }
+    }
+    for l in &topology.links{
+        let Some((a, b)) = endpoints(l) else {
+            continue;
+        };
+        writeln!(s, "  {} ---|\"{}<br/>{}<br/>{}_{} - {}_{}\"| {}",
+            mermaid_id("ns", a), l.name, subnets(&l.subnet, &l.subnet6), a, l.name, b, l.name, mermaid_id("ns", b))?;
+    }
+    for v in &topology.vxlans{
+        let label = format!("{} VNI {}<br/>{}", v.name, v.vni, subnets(&v.subnet, &v.subnet6));
+        match v.endpoints.as_slice(){
+            [a, b] => writeln!(s, "  {} -.-|\"{}<br/>{}_{} - {}_{}\"| {}",
+                mermaid_id("ns", &a.namespace), label, a.namespace, v.name, b.namespace, v.name, mermaid_id("ns", &b.namespace))?,
+            endpoints => {
+                let id = mermaid_id("vx", &v.name);
+                writeln!(s, "  {}{{\"{}\"}}", id, label)?;
+                for e in endpoints{
+                    writeln!(s, "  {} -.-|\"{}_{}\"| {}", mermaid_id("ns", &e.namespace), e.namespace, v.name, id)?;
+                }
+            },
+        }
+    }
+    for t in &topology.tunnels{
+        let label = format!("{} ({})<br/>{}", t.name, t.kind, subnets(&t.subnet, &t.subnet6));
+        match (t.endpoints.as_slice(), &t.remote){
+            ([a, b], _) => writeln!(s, "  {} -.-|\"{}<br/>{}_{} - {}_{}\"| {}",
+                mermaid_id("ns", &a.namespace), label, a.namespace, t.name, b.namespace, t.name, mermaid_id("ns", &b.namespace))?,
+            ([a], Some(remote)) => {
+                let id = mermaid_id("remote", remote);
+                writeln!(s, "  {}[/\"{}\"/]", id, remote)?;
+                writeln!(s, "  {} -.-|\"{}<br/>{}_{}\"| {}", mermaid_id("ns", &a.namespace), label, a.namespace, t.name, id)?;
+            },
+            _ => {},
+        }
+    }
+    for w in &topology.wireguards{
+        if let [a, b] = w.endpoints.as_slice() {
+            let label = format!("{} (wireguard)<br/>{}", w.name, subnets(&w.subnet, &w.subnet6));
+            writeln!(s, "  {} -.-|\"{}<br/>{}_{} - {}_{}\"| {}",
+                mermaid_id("ns", &a.namespace), label, a.namespace, w.name, b.namespace, w.name, mermaid_id("ns", &b.namespace))?;
+        }
+    }
+    Ok(s)
+}
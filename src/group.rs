@@ -0,0 +1,115 @@
+//! Interface groups: links, bridges and interfaces of a topology name a
+//! group, e.g. `fabric` or `edge`, and their interfaces join the kernel
+//! interface group (`ip link set group`) with the group's id. Settings of
+//! the group are then applied per namespace to all of its members at once,
+//! the mtu with a single `ip link set group <id> mtu`, sysctls per member.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::{Result, RouterError};
+use crate::interface;
+use crate::namespace;
+use crate::topology::Topology;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct GroupSpec{
+    pub name: String,
+    /// kernel group id, 0 is the default group every interface starts in
+    pub id: u32,
+    #[serde(default)]
+    pub mtu: Option<u32>,
+    /// per-interface sysctls as `ipv4.<setting>` or `ipv6.<setting>`, set
+    /// as `net.ipv4.conf.<interface>.<setting>` on every member, e.g.
+    /// `ipv4.rp_filter: 0`
+    #[serde(default)]
+    pub sysctls: BTreeMap<String, String>,
+}
+
+impl GroupSpec{
+    pub fn check(&self) -> Result<()>{
+        if self.id == 0 {
+            return Err(RouterError::Invalid(format!("Group {} needs an id other than 0, the default group", self.name)));
+        }
+        for key in self.sysctls.keys(){
+            if !(key.starts_with("ipv4.") || key.starts_with("ipv6.")) || key.split('.').count() != 2 {
+                return Err(RouterError::Invalid(format!("Invalid sysctl {} of group {}, expected ipv4.<setting> or ipv6.<setting>", key, self.name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sysctl assignments for member `interface`.
+    pub fn sysctls(&self, interface: &str) -> Vec<String> {
+        self.sysctls.iter()
+            .map(|(key, value)| {
+                let (family, setting) = key.split_once('.').unwrap_or_default();
+                format!("net.{}.conf.{}.{}={}", family, interface, setting, value)
+            })
+            .collect()
+    }
+
+    /// Applies the settings to every interface of the kernel group in
+    /// `netns`, None for the host, including members added by hand.
+    pub fn apply(&self, netns: Option<&str>) -> Result<()>{
+        let id = self.id.to_string();
+        if let Some(mtu) = self.mtu{
+            cmd::ip(netns, &["link", "set", "group", id.as_str(), "mtu", mtu.to_string().as_str()])?;
+        }
+        if self.sysctls.is_empty() {
+            return Ok(());
+        }
+        for interface in members(netns, self.id)?{
+            let settings = self.sysctls(&interface);
+            match netns{
+                Some(netns) => namespace::sysctl(netns, &settings),
+                None => {
+                    let mut args = vec!["-qw"];
+                    args.extend(settings.iter().map(|s| s.as_str()));
+                    cmd::run("sysctl", &args).map(|_| ())
+                },
+            }.map_err(|e| e.context(format!("Failed to apply sysctls of group {} to {}", self.name, interface)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Puts `interface` into kernel group `id`.
+pub fn join(netns: Option<&str>, interface: &str, id: u32) -> Result<()>{
+    cmd::ip(netns, &["link", "set", "dev", interface, "group", id.to_string().as_str()])?;
+    Ok(())
+}
+
+/// Interfaces in kernel group `id` of `netns`.
+pub fn members(netns: Option<&str>, id: u32) -> Result<Vec<String>>{
+    let out = cmd::ip(netns, &["-j", "link", "show", "group", id.to_string().as_str()])?;
+    let links: serde_json::Value = serde_json::from_str(&out)?;
+    Ok(links.as_array().cloned().unwrap_or_default().iter()
+        .filter_map(|l| Some(l["ifname"].as_str()?.to_string()))
+        .collect())
+}
+
+/// Interfaces the topology puts into `group` as (namespace, interface):
+/// both ends of its links, member ends and bridge ports of its bridges and
+/// its host interfaces, whose namespace may be None.
+pub fn topology_members(topology: &Topology, group: &str) -> Vec<(Option<String>, String)> {
+    let mut members = Vec::new();
+    for l in topology.links.iter().filter(|l| l.group.as_deref() == Some(group)){
+        for ns in &l.endpoints{
+            members.push((Some(ns.clone()), interface::name(ns, &l.name)));
+        }
+    }
+    for b in topology.bridges.iter().filter(|b| b.group.as_deref() == Some(group)){
+        let bridge_ns = b.namespace.clone().unwrap_or_else(|| b.name.clone());
+        for m in &b.members{
+            members.push((Some(m.clone()), interface::name(m, &b.name)));
+            members.push((Some(bridge_ns.clone()), interface::name(&b.name, m)));
+        }
+    }
+    for i in topology.interfaces.iter().filter(|i| i.group.as_deref() == Some(group)){
+        members.push((i.namespace.clone(), i.name.clone()));
+    }
+    members
+}
@@ -0,0 +1,131 @@
+//! Guards tearing down what they hold when dropped, so tests using the
+//! library get their topologies and kernel objects removed at the end of
+//! the scope, also when an assertion panics or `?` returns early:
+//!
+//! ```no_run
+//! use router_rs::Topology;
+//!
+//! let lab = Topology::builder("lab")
+//!     .namespace("a")
+//!     .namespace("b")
+//!     .link("l1", "10.0.0.0/30").connect("a", "b")
+//!     .build()?
+//!     .guard()?;
+//! let a = lab.namespace("a").unwrap();
+//! assert!(a.exec("ip", &["route"])?.success());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Errors while dropping can't be returned and are logged instead, call
+//! `destroy` to see them. `keep` disarms a guard, leaving everything in
+//! place.
+
+use std::sync::Arc;
+
+use crate::error::Result;
+use crate::topology::Topology;
+use crate::transaction::{self, Resource};
+use crate::{Config, Namespace};
+
+/// A created topology, destroyed with its state when dropped.
+pub struct TopologyGuard{
+    /// None once destroyed or kept
+    config: Option<Config>,
+}
+
+impl TopologyGuard{
+    /// Creates `topology` on the host starting from `config`, see
+    /// `Topology::apply_with`.
+    pub fn apply(topology: &Topology, config: Config) -> Result<TopologyGuard>{
+        Ok(TopologyGuard{ config: Some(topology.apply_with(config)?) })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config().name
+    }
+
+    /// Registry of everything created for the topology.
+    pub fn config(&self) -> &Config {
+        self.config.as_ref().expect("guard holds its topology until dropped")
+    }
+
+    /// Namespace by its name in the topology.
+    pub fn namespace(&self, name: &str) -> Option<Arc<Namespace>> {
+        self.config().namespaces.get(name)
+    }
+
+    /// Destroys the topology now, returning what went wrong.
+    pub fn destroy(mut self) -> Result<()>{
+        match self.config.take(){
+            Some(config) => {
+                config.hooks.teardown(&config.name);
+                Topology::destroy(&config.name)
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Leaves the topology in place and returns its registry.
+    pub fn keep(mut self) -> Config {
+        self.config.take().expect("guard holds its topology until dropped")
+    }
+}
+
+impl Drop for TopologyGuard{
+    fn drop(&mut self){
+        if let Some(config) = self.config.take(){
+            config.hooks.teardown(&config.name);
+            if let Err(e) = Topology::destroy(&config.name) {
+                tracing::warn!(target: "router_rs::guard", topology = config.name.as_str(), "failed to destroy topology: {}", e);
+            }
+        }
+    }
+}
+
+/// Kernel objects created one by one, e.g. with `Namespace::new` and
+/// `Link::attach`, undone in `transaction::teardown` order when dropped.
+pub struct ResourceGuard{
+    resources: Vec<Resource>,
+}
+
+impl ResourceGuard{
+    pub fn new(resources: Vec<Resource>) -> ResourceGuard {
+        ResourceGuard{ resources }
+    }
+
+    /// Takes over what `config` recorded since its last commit, which its
+    /// transaction then no longer rolls back.
+    pub fn take(config: &Config) -> ResourceGuard {
+        ResourceGuard::new(config.transaction().take())
+    }
+
+    /// Adds resources created later.
+    pub fn push(&mut self, resource: Resource){
+        self.resources.push(resource);
+    }
+
+    pub fn resources(&self) -> &[Resource] {
+        &self.resources
+    }
+
+    /// Undoes the resources now, returning what went wrong.
+    pub fn destroy(mut self) -> Result<()>{
+        transaction::undo_all(std::mem::take(&mut self.resources))
+    }
+
+    /// Leaves the resources in place and returns them.
+    pub fn keep(mut self) -> Vec<Resource> {
+        std::mem::take(&mut self.resources)
+    }
+}
+
+impl Drop for ResourceGuard{
+    fn drop(&mut self){
+        if self.resources.is_empty() {
+            return;
+        }
+        if let Err(e) = transaction::undo_all(std::mem::take(&mut self.resources)) {
+            tracing::warn!(target: "router_rs::guard", "failed to undo resources: {}", e);
+        }
+    }
+}
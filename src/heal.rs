@@ -0,0 +1,164 @@
+//! Watches a running topology for nodes that died behind our back: a
+//! namespace removed with `ip netns del`, an interface deleted inside one or
+//! a routing daemon, forwarder or process supervisor that exited. Failures are reported
+//! and, when healing, dead processes are restarted and anything else is
+//! rebuilt from the topology by reconciling it.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+use crate::daemon::{self, RoutingDaemon};
+use crate::error::{Result, RouterError};
+use crate::forwarder::Forwarder;
+use crate::process::Process;
+use crate::state::State;
+use crate::topology::Topology;
+use crate::trace::Traced;
+use crate::Config;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Failure{
+    Namespace{ netns: String },
+    Interface{ netns: Option<String>, name: String },
+    Process{ netns: String, name: String },
+}
+
+impl fmt::Display for Failure{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Failure::Namespace{ netns } => write!(f, "namespace {} is gone", netns),
+            Failure::Interface{ netns: Some(netns), name } => write!(f, "interface {} in {} is gone", name, netns),
+            Failure::Interface{ netns: None, name } => write!(f, "interface {} is gone", name),
+            Failure::Process{ netns, name } => write!(f, "{} in {} is not running", name, netns),
+        }
+    }
+}
+
+pub struct Healer{
+    pub topology: Topology,
+    /// time between two checks
+    pub interval: Duration,
+    /// repair failures instead of only reporting them
+    pub heal: bool,
+}
+
+impl Healer{
+    /// Checks the topology against its saved state, and repairs what it
+    /// found when healing. Returns the failures found.
+    pub fn check(&self) -> Result<Vec<Failure>>{
+        let state = State::load(&self.topology.name)?
+            .ok_or_else(|| RouterError::TopologyNotFound(self.topology.name.to_string()))?;
+        let failures = failures(&state)?;
+        if self.heal && !failures.is_empty() {
+            self.repair(&failures)?;
+        }
+        Ok(failures)
+    }
+
+    /// Checks every `interval` until the topology is destroyed, passing
+    /// failures to `report`. Without healing a failure is reported once,
+    /// not again in every round it persists.
+    pub fn run(&self, mut report: impl FnMut(&[Failure])) -> Result<()>{
+        let mut known = Vec::new();
+        loop{
+            if State::load(&self.topology.name)?.is_none() {
+                return Ok(());
+            }
+            let failures = self.check()?;
+            let new: Vec<Failure> = failures.iter().filter(|f| !known.contains(*f)).cloned().collect();
+            if !new.is_empty() {
+                report(&new);
+            }
+            known = failures;
+            std::thread::sleep(self.interval);
+        }
+    }
+
+    fn repair(&self, failures: &[Failure]) -> Result<()>{
+        let mut rebuild = false;
+        for f in failures{
+            match f{
+                // daemons keep running in the namespace they were started
+                // in even after it lost its name, so they have to go first
+                Failure::Namespace{ netns } => {
+                    daemon::stop_dir(&RoutingDaemon::dir(&self.topology.name, netns))?;
+                    for fwd in Forwarder::list(&self.topology.name)?.iter().filter(|f| &f.netns == netns){
+                        fwd.stop()?;
+                    }
+                    for p in Process::list(&self.topology.name)?.iter().filter(|p| &p.netns == netns){
+                        p.stop()?;
+                    }
+                    rebuild = true;
+                },
+                Failure::Interface{ .. } => rebuild = true,
+                Failure::Process{ .. } => {},
+            }
+        }
+        if rebuild {
+            // the host changes were allowed when the topology was created
+            let mut config = Config::new(self.topology.name.clone());
+            config.host_changes = true;
+            self.topology.reconcile_with(config)?;
+        }
+        // reconciling leaves daemons with an unchanged config alone
+        for d in RoutingDaemon::list(&self.topology.name)?{
+            d.supervise()?;
+        }
+        for fwd in Forwarder::list(&self.topology.name)?{
+            fwd.supervise()?;
+        }
+        for p in Process::list(&self.topology.name)?{
+            p.supervise()?;
+        }
+        Ok(())
+    }
+}
+
+/// What the kernel lost compared to `state`. Interfaces and processes of a
+/// missing namespace are not listed separately.
+pub fn failures(state: &State) -> Result<Vec<Failure>>{
+    let mut failures = Vec::new();
+    let mut gone = Vec::new();
+    for ns in &state.namespaces{
+        if !PathBuf::from("/run/netns").join(&ns.netns).exists() {
+            failures.push(Failure::Namespace{ netns: ns.netns.clone() });
+            gone.push(ns.netns.clone());
+        }
+    }
+    let devices = state.interfaces.iter().map(|i| (&i.netns, &i.name))
+        .chain(state.bridges.iter().map(|b| (&b.netns, &b.name)));
+    for (netns, name) in devices{
+        if netns.as_ref().is_some_and(|n| gone.contains(n)) {
+            continue;
+        }
+        let mut cmd = Command::new("ip");
+        if let Some(netns) = netns{
+            cmd.arg("-n").arg(netns);
+        }
+        if !cmd.args(["link", "show", "dev", name.as_str()]).traced_output()?.status.success() {
+            failures.push(Failure::Interface{ netns: netns.clone(), name: name.clone() });
+        }
+    }
+    for d in RoutingDaemon::list(&state.name)?{
+        if gone.contains(&d.netns) {
+            continue;
+        }
+        for name in d.dead()?{
+            failures.push(Failure::Process{ netns: d.netns.clone(), name });
+        }
+    }
+    for fwd in Forwarder::list(&state.name)?{
+        if !gone.contains(&fwd.netns) && !fwd.running() {
+            failures.push(Failure::Process{ netns: fwd.netns.clone(), name: "forwarder".to_string() });
+        }
+    }
+    // programs which exited for good are done, not failed
+    for p in Process::list(&state.name)?{
+        if !gone.contains(&p.netns) && !p.running() && p.exited().is_none() {
+            failures.push(Failure::Process{ netns: p.netns.clone(), name: p.spec.name.clone() });
+        }
+    }
+    Ok(failures)
+}
@@ -0,0 +1,195 @@
+//! Code run at points of a topology's life, so users start a custom daemon
+//! right after its namespace exists or clean up after it without forking
+//! the crate. Rust closures are registered on `Config::hooks`, external
+//! scripts in the description as `hooks` or with `Hooks::script`.
+//!
+//! - `namespace_created`: a namespace was created or taken from the pool,
+//!   not when it was adopted by `reconcile`
+//! - `link_up`: both ends of a veth or bonded link exist and are numbered
+//! - `teardown`: the topology is about to be destroyed
+//!
+//! A closure or script failing while the topology is built fails the
+//! build, which undoes it. Failures at teardown are logged and the
+//! topology destroyed anyway.
+//!
+//! Scripts run on the host, with `ROUTER_RS_TOPOLOGY` and `ROUTER_RS_EVENT`
+//! set, `ROUTER_RS_NODE` and `ROUTER_RS_NETNS` for namespace events and
+//! `ROUTER_RS_LINK` and `ROUTER_RS_NODES`, the two namespaces, for link
+//! events. Those of the description are kept in its state, so `destroy`
+//! runs their teardown from another process too.
+
+use std::fmt;
+use std::process::Command;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::environment::PREFIX;
+use crate::error::{Result, RouterError};
+use crate::trace::Traced;
+use crate::{Interface, Link, Namespace};
+
+/// Point of a topology's life hooks run at.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent{
+    NamespaceCreated,
+    LinkUp,
+    Teardown,
+}
+
+impl fmt::Display for HookEvent{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            HookEvent::NamespaceCreated => write!(f, "namespace_created"),
+            HookEvent::LinkUp => write!(f, "link_up"),
+            HookEvent::Teardown => write!(f, "teardown"),
+        }
+    }
+}
+
+/// External script run on `on`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HookSpec{
+    pub on: HookEvent,
+    /// program and arguments, run on the host
+    pub command: Vec<String>,
+}
+
+impl HookSpec{
+    pub fn check(&self) -> Result<()>{
+        if self.command.is_empty() {
+            return Err(RouterError::Invalid(format!("Hook on {} has no command", self.on)));
+        }
+        Ok(())
+    }
+
+    fn run(&self, vars: &[(String, String)]) -> Result<()>{
+        let mut cmd = Command::new(&self.command[0]);
+        cmd.args(&self.command[1..]);
+        cmd.env(format!("{}EVENT", PREFIX), self.on.to_string());
+        cmd.envs(vars.iter().map(|(k, v)| (k, v)));
+        let output = cmd.traced_output()?;
+        if !output.status.success() {
+            return Err(RouterError::command(&cmd, &output).context(format!("Hook on {} failed", self.on)));
+        }
+        Ok(())
+    }
+}
+
+type NamespaceHook = Arc<dyn Fn(&Namespace) -> Result<()> + Send + Sync>;
+type LinkHook = Arc<dyn Fn(&Link, &Interface, &Interface) -> Result<()> + Send + Sync>;
+type TeardownHook = Arc<dyn Fn(&str) -> Result<()> + Send + Sync>;
+
+/// Closures and scripts registered for a topology.
+#[derive(Default)]
+pub struct Hooks{
+    namespace_created: RwLock<Vec<NamespaceHook>>,
+    link_up: RwLock<Vec<LinkHook>>,
+    teardown: RwLock<Vec<TeardownHook>>,
+    scripts: RwLock<Vec<HookSpec>>,
+}
+
+impl Hooks{
+    /// Runs `f` with every namespace created.
+    pub fn on_namespace_created(&self, f: impl Fn(&Namespace) -> Result<()> + Send + Sync + 'static){
+        self.namespace_created.write().unwrap_or_else(PoisonError::into_inner).push(Arc::new(f));
+    }
+
+    /// Runs `f` with every veth link and its two ends once they are up.
+    pub fn on_link_up(&self, f: impl Fn(&Link, &Interface, &Interface) -> Result<()> + Send + Sync + 'static){
+        self.link_up.write().unwrap_or_else(PoisonError::into_inner).push(Arc::new(f));
+    }
+
+    /// Runs `f` with the topology's name before it is destroyed by its
+    /// `TopologyGuard`, `Topology::destroy` alone only runs the scripts.
+    pub fn on_teardown(&self, f: impl Fn(&str) -> Result<()> + Send + Sync + 'static){
+        self.teardown.write().unwrap_or_else(PoisonError::into_inner).push(Arc::new(f));
+    }
+
+    /// Runs `spec` on its event.
+    pub fn script(&self, spec: HookSpec){
+        self.scripts.write().unwrap_or_else(PoisonError::into_inner).push(spec);
+    }
+
+    /// Scripts registered, in order.
+    pub fn scripts(&self) -> Vec<HookSpec> {
+        self.scripts.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Whether any closure or script runs on `on`.
+    pub fn wants(&self, on: HookEvent) -> bool {
+        let closures = match on{
+            HookEvent::NamespaceCreated => !self.namespace_created.read().unwrap_or_else(PoisonError::into_inner).is_empty(),
+            HookEvent::LinkUp => !self.link_up.read().unwrap_or_else(PoisonError::into_inner).is_empty(),
+            HookEvent::Teardown => !self.teardown.read().unwrap_or_else(PoisonError::into_inner).is_empty(),
+        };
+        closures || !self.scripts_on(on).is_empty()
+    }
+
+    fn scripts_on(&self, on: HookEvent) -> Vec<HookSpec> {
+        self.scripts().into_iter().filter(|s| s.on == on).collect()
+    }
+
+    pub(crate) fn namespace_created(&self, topology: &str, ns: &Namespace) -> Result<()>{
+        let hooks = self.namespace_created.read().unwrap_or_else(PoisonError::into_inner).clone();
+        for f in hooks{
+            f(ns).map_err(|e| e.context(format!("Hook on namespace_created of {} failed", ns.name)))?;
+        }
+        let vars = [
+            var("TOPOLOGY", topology),
+            var("NODE", &ns.name),
+            var("NETNS", &ns.netns),
+        ];
+        for s in self.scripts_on(HookEvent::NamespaceCreated){
+            s.run(&vars)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn link_up(&self, topology: &str, link: &Link, i1: &Interface, i2: &Interface) -> Result<()>{
+        let hooks = self.link_up.read().unwrap_or_else(PoisonError::into_inner).clone();
+        for f in hooks{
+            f(link, i1, i2).map_err(|e| e.context(format!("Hook on link_up of {} failed", link.name)))?;
+        }
+        let nodes: Vec<&str> = [i1, i2].iter()
+            .filter_map(|i| i.namespace.as_ref())
+            .map(|ns| ns.name.as_str())
+            .collect();
+        let vars = [
+            var("TOPOLOGY", topology),
+            var("LINK", &link.name),
+            var("NODES", &nodes.join(" ")),
+        ];
+        for s in self.scripts_on(HookEvent::LinkUp){
+            s.run(&vars)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the closures on teardown, logging failures. The scripts are
+    /// run by `Topology::destroy` from the saved state.
+    pub(crate) fn teardown(&self, topology: &str){
+        let hooks = self.teardown.read().unwrap_or_else(PoisonError::into_inner).clone();
+        for f in hooks{
+            if let Err(e) = f(topology) {
+                tracing::warn!(target: "router_rs::hooks", topology, "hook on teardown failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Runs the teardown scripts among `scripts` of `topology`, logging
+/// failures.
+pub(crate) fn teardown(topology: &str, scripts: &[HookSpec]){
+    let vars = [var("TOPOLOGY", topology)];
+    for s in scripts.iter().filter(|s| s.on == HookEvent::Teardown){
+        if let Err(e) = s.run(&vars) {
+            tracing::warn!(target: "router_rs::hooks", topology, "{}", e);
+        }
+    }
+}
+
+fn var(name: &str, value: &str) -> (String, String) {
+    (format!("{}{}", PREFIX, name), value.to_string())
+}
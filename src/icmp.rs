@@ -0,0 +1,44 @@
+//! ICMP behavior of a namespace: the rate its errors are limited to and
+//! whether it sends and follows redirects. A router forwarding a packet
+//! out of the interface it came in on, as between parallel links, sends
+//! the source a redirect by default, and a source following it silently
+//! takes another path than the one under test.
+
+use serde::{Deserialize, Serialize};
+
+/// ICMP settings of a namespace, the kernel's own where not set.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct IcmpSpec{
+    /// minimum milliseconds between ICMP errors to the same destination,
+    /// for IPv4 and IPv6, 0 to disable the limit
+    #[serde(default)]
+    pub ratelimit: Option<u32>,
+    /// change routes as redirects received say, IPv4 and IPv6
+    #[serde(default)]
+    pub accept_redirects: Option<bool>,
+    /// send redirects, IPv6 routers always do
+    #[serde(default)]
+    pub send_redirects: Option<bool>,
+}
+
+impl IcmpSpec{
+    /// sysctl settings for the namespace, `default` and `interfaces`.
+    pub fn sysctls(&self, interfaces: &[String]) -> Vec<String> {
+        let mut settings = Vec::new();
+        if let Some(ms) = self.ratelimit{
+            settings.push(format!("net.ipv4.icmp_ratelimit={}", ms));
+            settings.push(format!("net.ipv6.icmp.ratelimit={}", ms));
+        }
+        let devices: Vec<&str> = ["all", "default"].into_iter().chain(interfaces.iter().map(|i| i.as_str())).collect();
+        for dev in &devices{
+            if let Some(accept) = self.accept_redirects{
+                settings.push(format!("net.ipv4.conf.{}.accept_redirects={}", dev, accept as u8));
+                settings.push(format!("net.ipv6.conf.{}.accept_redirects={}", dev, accept as u8));
+            }
+            if let Some(send) = self.send_redirects{
+                settings.push(format!("net.ipv4.conf.{}.send_redirects={}", dev, send as u8));
+            }
+        }
+        settings
+    }
+}
@@ -0,0 +1,526 @@
+//! Converts hand-built namespaces into a `Topology` by parsing the JSON
+//! output of `ip -d -j link`, `ip -j addr` and `ip -j route`.
+//!
+//! veth pairs spanning two of the given namespaces become links, every other
+//! interface becomes a host interface. Links are described by subnet only,
+//! so re-creating an imported topology numbers the ends `.1`/`.2` (or both
+//! addresses of a /31) even if the original used other host addresses.
+//!
+//! Containerlab topology files are imported the same way, see
+//! `containerlab`: their links are taken as veth pairs and the addresses
+//! and routes from the `ip` commands of the nodes' `exec`.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::cmd;
+use crate::containerlab::Lab;
+use crate::error::{failed, Result};
+use crate::topology::{InterfaceSpec, LinkSpec, NamespaceSpec, RouteSpec, Topology};
+use crate::RouteKind;
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct LinkInfo{
+    pub ifindex: u32,
+    pub ifname: String,
+    #[serde(default)]
+    pub link_index: Option<u32>,
+    #[serde(default)]
+    pub mtu: Option<u32>,
+    #[serde(default)]
+    pub linkinfo: Option<LinkKind>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct LinkKind{
+    #[serde(default)]
+    pub info_kind: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct AddrInfo{
+    pub ifname: String,
+    #[serde(default)]
+    pub addr_info: Vec<Addr>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Addr{
+    pub family: String,
+    pub local: String,
+    pub prefixlen: u8,
+    #[serde(default)]
+    pub scope: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct RouteInfo{
+    pub dst: String,
+    #[serde(default)]
+    pub gateway: Option<String>,
+    #[serde(default)]
+    pub protocol: Option<String>,
+    #[serde(default)]
+    pub nexthops: Vec<Nexthop>,
+    /// blackhole, unreachable or prohibit, unicast if not set
+    #[serde(default, rename = "type")]
+    pub kind: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Nexthop{
+    #[serde(default)]
+    pub gateway: Option<String>,
+}
+
+pub fn parse_links(json: &str) -> Result<Vec<LinkInfo>>{
+    serde_json::from_str(json).map_err(|e| failed!("Failed to parse ip link output: {}", e))
+}
+
+pub fn parse_addrs(json: &str) -> Result<Vec<AddrInfo>>{
+    serde_json::from_str(json).map_err(|e| failed!("Failed to parse ip addr output: {}", e))
+}
+
+pub fn parse_routes(json: &str) -> Result<Vec<RouteInfo>>{
+    serde_json::from_str(json).map_err(|e| failed!("Failed to parse ip route output: {}", e))
+}
+
+/// Result of an import. `warnings` lists what could not be expressed in
+/// the topology and was left out.
+pub struct Imported{
+    pub topology: Topology,
+    pub warnings: Vec<String>,
+}
+
+/// Everything found in one namespace.
+struct Dump{
+    /// logical name in the imported topology
+    name: String,
+    links: Vec<LinkInfo>,
+    addrs: HashMap<String, Vec<Addr>>,
+    routes: Vec<RouteInfo>,
+    ecmp: bool,
+}
+
+impl Dump{
+    fn read(netns: &str, name: String) -> Result<Dump>{
+        let links = parse_links(&cmd::ip(Some(netns), &["-d", "-j", "link", "show"])?)?;
+        let addrs = parse_addrs(&cmd::ip(Some(netns), &["-j", "addr", "show"])?)?
+            .into_iter()
+            .map(|a| {
+                let global = a.addr_info.into_iter()
+                    .filter(|i| i.scope.as_deref() != Some("link") && i.scope.as_deref() != Some("host"))
+                    .collect();
+                (a.ifname, global)
+            })
+            .collect();
+        let mut routes = parse_routes(&cmd::ip(Some(netns), &["-4", "-j", "route", "show"])?)?;
+        routes.extend(parse_routes(&cmd::ip(Some(netns), &["-6", "-j", "route", "show"])?)?);
+        let ecmp = cmd::exec(netns, "sysctl", &["-n", "net.ipv4.fib_multipath_hash_policy"])?.trim() == "1";
+        Ok(Dump{ name, links, addrs, routes, ecmp })
+    }
+
+    fn addrs(&self, ifname: &str) -> &[Addr]{
+        self.addrs.get(ifname).map(|a| a.as_slice()).unwrap_or_default()
+    }
+}
+
+/// Reads the namespaces `netns` (kernel names) and describes them as
+/// topology `name`. Namespaces called `<name>-<ns>` are imported as `<ns>`.
+pub fn import(name: &str, netns: &[String]) -> Result<Imported>{
+    let prefix = format!("{}-", name);
+    let mut dumps = Vec::new();
+    for n in netns{
+        let logical = n.strip_prefix(prefix.as_str()).unwrap_or(n).to_string();
+        dumps.push(Dump::read(n, logical)?);
+    }
+    Ok(describe(name, &dumps))
+}
+
+/// Describes the containerlab topology file `data` as topology `name`.
+/// Links to anything but the file's nodes, e.g. `host:` or `macvlan:`
+/// ends, are left out.
+pub fn containerlab(name: &str, data: &str) -> Result<Imported>{
+    let lab: Lab = serde_yaml::from_str(data)
+        .map_err(|e| failed!("Failed to parse containerlab topology: {}", e))?;
+    let mut dumps: Vec<Dump> = lab.topology.nodes.keys()
+        .map(|n| Dump{ name: n.clone(), links: Vec::new(), addrs: HashMap::new(), routes: Vec::new(), ecmp: false })
+        .collect();
+    let mut warnings = Vec::new();
+    // ifindexes are unique across the whole file, so every end has exactly
+    // one peer pointing back at it
+    let mut ifindex = 0;
+    for l in &lab.topology.links{
+        let ends: Option<Vec<(usize, &str)>> = l.endpoints.iter()
+            .map(|e| {
+                let (node, ifname) = e.split_once(':')?;
+                Some((dumps.iter().position(|d| d.name == node)?, ifname))
+            })
+            .collect();
+        let Some(ends) = ends.filter(|e| e.len() == 2) else {
+            warnings.push(format!("link {} is not between two nodes and was left out", l.endpoints.join(" ")));
+            continue;
+        };
+        for (n, (i, ifname)) in ends.iter().enumerate(){
+            dumps[*i].links.push(LinkInfo{
+                ifindex: ifindex + n as u32 + 1,
+                ifname: ifname.to_string(),
+                link_index: Some(ifindex + (1 - n) as u32 + 1),
+                mtu: None,
+                linkinfo: Some(LinkKind{ info_kind: Some("veth".to_string()) }),
+            });
+        }
+        ifindex += 2;
+    }
+    for (d, node) in dumps.iter_mut().zip(lab.topology.nodes.values()){
+        for command in &node.exec{
+            exec(d, command);
+        }
+    }
+    let mut imported = describe(name, &dumps);
+    warnings.append(&mut imported.warnings);
+    imported.warnings = warnings;
+    Ok(imported)
+}
+
+/// Takes the addresses, routes and ECMP hashing `command` configures into
+/// `d`, other commands are ignored.
+fn exec(d: &mut Dump, command: &str){
+    let mut tokens: Vec<&str> = command.split_whitespace().collect();
+    if tokens.first() == Some(&"sysctl") {
+        d.ecmp |= tokens.contains(&"net.ipv4.fib_multipath_hash_policy=1");
+        return;
+    }
+    if tokens.first() != Some(&"ip") {
+        return;
+    }
+    tokens.retain(|t| *t != "-4" && *t != "-6");
+    match tokens.as_slice(){
+        ["ip", "addr" | "address" | "a", "add", addr, "dev", ifname, ..] => {
+            let Some((local, prefixlen)) = addr.split_once('/').and_then(|(a, p)| Some((a, p.parse().ok()?))) else {
+                return;
+            };
+            let family = if local.contains(':') { "inet6" } else { "inet" };
+            d.addrs.entry(ifname.to_string()).or_default()
+                .push(Addr{ family: family.to_string(), local: local.to_string(), prefixlen, scope: None });
+        },
+        ["ip", "route" | "r", "add" | "replace", rest @ ..] => {
+            let (kind, rest) = match rest{
+                [kind @ ("blackhole" | "unreachable" | "prohibit"), rest @ ..] => (Some(kind.to_string()), rest),
+                rest => (None, rest),
+            };
+            let Some((dst, rest)) = rest.split_first() else {
+                return;
+            };
+            let mut route = RouteInfo{ dst: dst.to_string(), kind, ..Default::default() };
+            let mut nexthop = false;
+            for pair in rest.windows(2){
+                match pair{
+                    ["nexthop", _] => nexthop = true,
+                    ["via", gateway] if nexthop => route.nexthops.push(Nexthop{ gateway: Some(gateway.to_string()) }),
+                    ["via", gateway] => route.gateway = Some(gateway.to_string()),
+                    _ => {},
+                }
+            }
+            d.routes.push(route);
+        },
+        _ => {},
+    }
+}
+
+fn describe(name: &str, dumps: &[Dump]) -> Imported{
+    let mut topology = Topology{
+        name: name.to_string(),
+        ..Default::default()
+    };
+    let mut warnings = Vec::new();
+    for d in dumps{
+        topology.namespaces.push(NamespaceSpec{
+            name: d.name.clone(),
+            ecmp: d.ecmp,
+            ..Default::default()
+        });
+    }
+
+    // (namespace index, ifname) -> name of the interface in the topology
+    let mut names: HashMap<(usize, String), String> = HashMap::new();
+    for (i, d) in dumps.iter().enumerate(){
+        for l in &d.links{
+            if l.ifname == "lo" || names.contains_key(&(i, l.ifname.clone())) {
+                continue;
+            }
+            let veth = l.linkinfo.as_ref().and_then(|k| k.info_kind.as_deref()) == Some("veth");
+            if veth {
+                if let Some((j, peer)) = find_peer(dumps, i, l){
+                    let mut link = link_name(&d.name, &l.ifname);
+                    if topology.links.iter().any(|s| s.name == link) {
+                        link = format!("{}-{}", link, topology.links.len());
+                    }
+                    let spec = link_spec(&link, d, l, &dumps[j], peer, &mut warnings);
+                    names.insert((i, l.ifname.clone()), format!("{}_{}", d.name, link));
+                    names.insert((j, peer.ifname.clone()), format!("{}_{}", dumps[j].name, link));
+                    topology.links.push(spec);
+                    continue;
+                }
+            }
+            let mut spec = InterfaceSpec{
+                name: l.ifname.clone(),
+                namespace: Some(d.name.clone()),
+                mtu: l.mtu,
+                ..Default::default()
+            };
+            for a in d.addrs(&l.ifname){
+                let addr = format!("{}/{}", a.local, a.prefixlen);
+                match a.family.as_str(){
+                    "inet" if spec.ip.is_none() => spec.ip = Some(addr),
+                    "inet6" if spec.ip6.is_none() => spec.ip6 = Some(addr),
+                    _ => warnings.push(format!("{}: extra address {} on {} left out", d.name, addr, l.ifname)),
+                }
+            }
+            names.insert((i, l.ifname.clone()), l.ifname.clone());
+            topology.interfaces.push(spec);
+        }
+    }
+
+    // nexthop address -> topology interface owning it
+    let mut owners: HashMap<String, String> = HashMap::new();
+    for (i, d) in dumps.iter().enumerate(){
+        for (ifname, addrs) in &d.addrs{
+            if let Some(n) = names.get(&(i, ifname.clone())){
+                for a in addrs{
+                    owners.insert(a.local.clone(), n.clone());
+                }
+            }
+        }
+    }
+    for d in dumps{
+        for r in &d.routes{
+            if r.protocol.as_deref() == Some("kernel") || r.dst.starts_with("fe80:") || r.dst.starts_with("ff00:") {
+                continue;
+            }
+            if let Some(kind) = r.kind.as_deref().and_then(|k| k.parse::<RouteKind>().ok()).filter(|k| *k != RouteKind::Unicast) {
+                // the family of a default route isn't in the output
+                if r.dst == "default" {
+                    warnings.push(format!("{}: {} default route was left out", d.name, kind));
+                } else {
+                    topology.routes.push(RouteSpec{ namespace: d.name.clone(), dst: r.dst.clone(), kind, ..Default::default() });
+                }
+                continue;
+            }
+            let gateways: Vec<&String> = r.gateway.iter()
+                .chain(r.nexthops.iter().filter_map(|n| n.gateway.as_ref()))
+                .collect();
+            if gateways.is_empty() {
+                warnings.push(format!("{}: route {} has no gateway and was left out", d.name, r.dst));
+                continue;
+            }
+            let resolved: Option<Vec<String>> = gateways.iter().map(|g| owners.get(g.as_str()).cloned()).collect();
+            let dst = if r.dst == "default" {
+                if gateways[0].contains(':') { "::/0".to_string() } else { "0.0.0.0/0".to_string() }
+            } else {
+                r.dst.clone()
+            };
+            match resolved{
+                Some(gateways) => topology.routes.push(RouteSpec{
+                    namespace: d.name.clone(),
+                    dst,
+                    gateways,
+                    ..Default::default()
+                }),
+                None => warnings.push(format!("{}: route {} uses a gateway outside the imported namespaces and was left out", d.name, r.dst)),
+            }
+        }
+    }
+    Imported{ topology, warnings }
+}
+
+/// The other end of veth `l` of namespace `i`. ifindexes are only unique
+/// per namespace, so among the interfaces pointing back at `l` the one
+/// sharing a subnet (or failing that, the link name) with it wins.
+fn find_peer<'a>(dumps: &'a [Dump], i: usize, l: &LinkInfo) -> Option<(usize, &'a LinkInfo)>{
+    let link_index = l.link_index?;
+    let mut candidates: Vec<(usize, &LinkInfo)> = Vec::new();
+    for (j, d) in dumps.iter().enumerate(){
+        if j == i {
+            continue;
+        }
+        for p in &d.links{
+            if p.ifindex == link_index && p.link_index == Some(l.ifindex) {
+                candidates.push((j, p));
+            }
+        }
+    }
+    if candidates.len() > 1 {
+        let nets = |d: &Dump, ifname: &str| -> Vec<ipnet::IpNet>{
+            d.addrs(ifname).iter()
+                .filter_map(|a| format!("{}/{}", a.local, a.prefixlen).parse::<ipnet::IpNet>().ok())
+                .map(|n| n.trunc())
+                .collect()
+        };
+        let own = nets(&dumps[i], &l.ifname);
+        let link = link_name(&dumps[i].name, &l.ifname);
+        if let Some(c) = candidates.iter().find(|(j, p)| nets(&dumps[*j], &p.ifname).iter().any(|n| own.contains(n))){
+            return Some(*c);
+        }
+        if let Some(c) = candidates.iter().find(|(j, p)| link_name(&dumps[*j].name, &p.ifname) == link){
+            return Some(*c);
+        }
+        return None;
+    }
+    candidates.pop()
+}
+
+/// router-rs names veth ends `<namespace>_<link>`, other names are taken
+/// as the link name as they are.
+fn link_name(namespace: &str, ifname: &str) -> String {
+    ifname.strip_prefix(format!("{}_", namespace).as_str()).unwrap_or(ifname).to_string()
+}
+
+fn link_spec(link: &str, d: &Dump, l: &LinkInfo, peer_dump: &Dump, peer: &LinkInfo, warnings: &mut Vec<String>) -> LinkSpec{
+    let mut spec = LinkSpec{
+        name: link.to_string(),
+        endpoints: vec![d.name.clone(), peer_dump.name.clone()],
+        ..Default::default()
+    };
+    for a in d.addrs(&l.ifname){
+        let net = match format!("{}/{}", a.local, a.prefixlen).parse::<ipnet::IpNet>(){
+            Ok(net) => net.trunc().to_string(),
+            Err(_) => continue,
+        };
+        match a.family.as_str(){
+            "inet" if spec.subnet.is_empty() => spec.subnet = net,
+            "inet6" if spec.subnet6.is_none() => spec.subnet6 = Some(net),
+            _ => warnings.push(format!("{}: extra address {}/{} on {} left out", d.name, a.local, a.prefixlen, l.ifname)),
+        }
+    }
+    // an IPv6-only link carries its subnet in `subnet`
+    if spec.subnet.is_empty() {
+        if let Some(subnet6) = spec.subnet6.take(){
+            spec.subnet = subnet6;
+        }
+    }
+    // `.1` goes to the first endpoint, keep the lower address first
+    let first = |dump: &Dump, ifname: &str| dump.addrs(ifname).first().and_then(|a| a.local.parse::<std::net::IpAddr>().ok());
+    if let (Some(a), Some(b)) = (first(d, &l.ifname), first(peer_dump, &peer.ifname)){
+        if b < a {
+            spec.endpoints.reverse();
+        }
+    }
+    spec
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn dump(name: &str, links: &str, addrs: &str, routes: &str) -> Dump {
+        Dump{
+            name: name.to_string(),
+            links: parse_links(links).unwrap(),
+            addrs: parse_addrs(addrs).unwrap().into_iter().map(|a| (a.ifname, a.addr_info)).collect(),
+            routes: parse_routes(routes).unwrap(),
+            ecmp: false,
+        }
+    }
+
+    fn pair() -> Vec<Dump> {
+        vec![
+            dump("r1",
+                r#"[{"ifindex":1,"ifname":"lo","mtu":65536},
+                    {"ifindex":2,"link_index":3,"ifname":"r1_ab","mtu":1500,"linkinfo":{"info_kind":"veth"}},
+                    {"ifindex":4,"ifname":"eth0","mtu":9000}]"#,
+                r#"[{"ifname":"r1_ab","addr_info":[{"family":"inet","local":"10.0.0.1","prefixlen":30,"scope":"global"},
+                                                   {"family":"inet6","local":"fd00::1","prefixlen":64,"scope":"global"}]},
+                    {"ifname":"eth0","addr_info":[{"family":"inet","local":"192.168.1.5","prefixlen":24,"scope":"global"}]}]"#,
+                r#"[{"dst":"10.0.0.0/30","dev":"r1_ab","protocol":"kernel","scope":"link","prefsrc":"10.0.0.1"},
+                    {"dst":"default","gateway":"10.0.0.2","dev":"r1_ab"},
+                    {"dst":"10.9.0.0/24","gateway":"192.168.1.1","dev":"eth0"},
+                    {"type":"blackhole","dst":"10.8.0.0/24"}]"#),
+            dump("r2",
+                r#"[{"ifindex":3,"link_index":2,"ifname":"r2_ab","mtu":1500,"linkinfo":{"info_kind":"veth"}}]"#,
+                r#"[{"ifname":"r2_ab","addr_info":[{"family":"inet","local":"10.0.0.2","prefixlen":30,"scope":"global"}]}]"#,
+                r#"[{"dst":"192.168.1.0/24","nexthops":[{"gateway":"10.0.0.1","dev":"r2_ab","weight":1}]}]"#),
+        ]
+    }
+
+    #[test]
+    fn veth_pairs_become_links(){
+        let imported = describe("lab", &pair());
+        let t = &imported.topology;
+        assert_eq!(t.namespaces.iter().map(|n| n.name.as_str()).collect::<Vec<_>>(), ["r1", "r2"]);
+        assert_eq!(t.links.len(), 1);
+        let l = &t.links[0];
+        assert_eq!((l.name.as_str(), l.subnet.as_str(), l.subnet6.as_deref()), ("ab", "10.0.0.0/30", Some("fd00::/64")));
+        assert_eq!(l.endpoints, ["r1", "r2"]);
+        // anything else stays an interface of its namespace
+        assert_eq!(t.interfaces.len(), 1);
+        let i = &t.interfaces[0];
+        assert_eq!((i.name.as_str(), i.namespace.as_deref(), i.ip.as_deref(), i.mtu), ("eth0", Some("r1"), Some("192.168.1.5/24"), Some(9000)));
+    }
+
+    #[test]
+    fn routes_resolve_gateways_to_interfaces(){
+        let imported = describe("lab", &pair());
+        let routes: Vec<(&str, &str, Vec<String>, RouteKind)> = imported.topology.routes.iter()
+            .map(|r| (r.namespace.as_str(), r.dst.as_str(), r.gateways.clone(), r.kind))
+            .collect();
+        assert_eq!(routes, vec![
+            ("r1", "0.0.0.0/0", vec!["r2_ab".to_string()], RouteKind::Unicast),
+            ("r1", "10.8.0.0/24", Vec::new(), RouteKind::Blackhole),
+            ("r2", "192.168.1.0/24", vec!["r1_ab".to_string()], RouteKind::Unicast),
+        ]);
+        assert_eq!(imported.warnings, ["r1: route 10.9.0.0/24 uses a gateway outside the imported namespaces and was left out"]);
+    }
+
+    #[test]
+    fn containerlab_links_and_exec_commands_are_imported(){
+        let lab = "
+name: lab
+topology:
+  nodes:
+    r1:
+      kind: linux
+      exec:
+      - ip addr add 10.0.0.1/30 dev eth1
+      - ip -6 addr add fd00::1/64 dev eth1
+      - ip addr add 10.0.0.5/30 dev eth2
+      - sysctl -w net.ipv4.fib_multipath_hash_policy=1
+      - ip route add 10.9.0.0/24 nexthop via 10.0.0.2 nexthop via 10.0.0.6
+      - ip route add unreachable 10.8.0.0/24
+    r2:
+      kind: linux
+      exec: [ip addr add 10.0.0.2/30 dev eth1]
+    r3:
+      kind: linux
+      exec: [ip addr add 10.0.0.6/30 dev eth1]
+  links:
+  - endpoints: [r1:eth1, r2:eth1]
+  - endpoints: [r3:eth1, r1:eth2]
+  - endpoints: [r2:eth2, host:r2-eth2]
+";
+        let imported = containerlab("lab", lab).unwrap();
+        let t = &imported.topology;
+        assert!(t.namespaces.iter().find(|n| n.name == "r1").unwrap().ecmp);
+        let links: Vec<(&str, &str, Option<&str>, &[String])> = t.links.iter()
+            .map(|l| (l.name.as_str(), l.subnet.as_str(), l.subnet6.as_deref(), l.endpoints.as_slice()))
+            .collect();
+        assert_eq!(links, [
+            ("eth1", "10.0.0.0/30", Some("fd00::/64"), &["r1".to_string(), "r2".to_string()][..]),
+            ("eth2", "10.0.0.4/30", None, &["r1".to_string(), "r3".to_string()][..]),
+        ]);
+        assert!(t.interfaces.is_empty());
+        let routes: Vec<(&str, Vec<String>, RouteKind)> = t.routes.iter().map(|r| (r.dst.as_str(), r.gateways.clone(), r.kind)).collect();
+        assert_eq!(routes, vec![
+            ("10.9.0.0/24", vec!["r2_eth1".to_string(), "r3_eth2".to_string()], RouteKind::Unicast),
+            ("10.8.0.0/24", Vec::new(), RouteKind::Unreachable),
+        ]);
+        assert_eq!(imported.warnings, ["link r2:eth2 host:r2-eth2 is not between two nodes and was left out"]);
+    }
+
+    #[test]
+    fn invalid_output_fails_to_parse(){
+        assert!(parse_links("[{\"ifname\":\"lo\"}]").is_err());
+        assert!(parse_routes("not json").is_err());
+    }
+}
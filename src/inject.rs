@@ -0,0 +1,200 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::cmd;
+use crate::error::{failed, Result, RouterError};
+
+/// tc filter preference used for injected drops, so they can be removed
+/// without touching other filters on the interface.
+const DROP_PREF: &str = "100";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Direction{
+    Ingress,
+    Egress,
+}
+
+impl fmt::Display for Direction{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Direction::Ingress => write!(f, "ingress"),
+            Direction::Egress => write!(f, "egress"),
+        }
+    }
+}
+
+impl FromStr for Direction{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<Direction>{
+        match s{
+            "ingress" => Ok(Direction::Ingress),
+            "egress" => Ok(Direction::Egress),
+            _ => Err(RouterError::Invalid(format!("Invalid direction {}, expected ingress or egress", s))),
+        }
+    }
+}
+
+/// Which packets get dropped. `Random(n)` drops with probability 1/n,
+/// `Every(n)` drops exactly every n-th packet.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DropMode{
+    All,
+    Random(u32),
+    Every(u32),
+}
+
+impl fmt::Display for DropMode{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            DropMode::All => write!(f, "all"),
+            DropMode::Random(n) => write!(f, "random:{}", n),
+            DropMode::Every(n) => write!(f, "every:{}", n),
+        }
+    }
+}
+
+impl FromStr for DropMode{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<DropMode>{
+        if s == "all" {
+            return Ok(DropMode::All);
+        }
+        let (kind, n) = s.split_once(':')
+            .ok_or_else(|| RouterError::Invalid(format!("Invalid drop mode {}, expected all, random:<n> or every:<n>", s)))?;
+        let n: u32 = n.parse()?;
+        if !(1..=10000).contains(&n) {
+            return Err(RouterError::Invalid(format!("Invalid drop mode {}, n must be between 1 and 10000", s)));
+        }
+        match kind{
+            "random" => Ok(DropMode::Random(n)),
+            "every" => Ok(DropMode::Every(n)),
+            _ => Err(RouterError::Invalid(format!("Invalid drop mode {}, expected all, random:<n> or every:<n>", s))),
+        }
+    }
+}
+
+/// Packets seen and dropped by an injected drop action, as counted by the
+/// kernel. Only drops done by the framework show up here, so they can be
+/// subtracted from the loss measured end to end.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DropCounters{
+    pub packets: u64,
+    pub bytes: u64,
+    pub dropped: u64,
+}
+
+/// Drops packets on an interface inside a namespace using a tc matchall
+/// filter with a gact action on the clsact qdisc.
+pub struct DropInjection{
+    pub namespace: String,
+    pub interface: String,
+    pub direction: Direction,
+}
+
+impl DropInjection{
+    pub fn new(namespace: String, interface: String, direction: Direction) -> DropInjection {
+        DropInjection{
+            namespace,
+            interface,
+            direction,
+        }
+    }
+
+    pub fn apply(&self, mode: DropMode) -> Result<()>{
+        let qdiscs = cmd::tc(Some(&self.namespace), &["qdisc", "show", "dev", self.interface.as_str()])?;
+        if !qdiscs.contains("clsact") {
+            cmd::tc(Some(&self.namespace), &["qdisc", "add", "dev", self.interface.as_str(), "clsact"])?;
+        }
+        let direction = self.direction.to_string();
+        let mut args = vec![
+            "filter", "replace", "dev", self.interface.as_str(), direction.as_str(),
+            "pref", DROP_PREF, "matchall", "action", "gact",
+        ];
+        let n = match mode{
+            DropMode::Random(n) | DropMode::Every(n) => n.to_string(),
+            DropMode::All => String::new(),
+        };
+        match mode{
+            DropMode::All => args.push("drop"),
+            DropMode::Random(_) => args.extend(["ok", "random", "netrand", "drop", n.as_str()]),
+            DropMode::Every(_) => args.extend(["ok", "random", "determ", "drop", n.as_str()]),
+        }
+        cmd::tc(Some(&self.namespace), &args)?;
+        Ok(())
+    }
+
+    pub fn remove(&self) -> Result<()>{
+        let direction = self.direction.to_string();
+        cmd::tc(Some(&self.namespace), &["filter", "del", "dev", self.interface.as_str(), direction.as_str(), "pref", DROP_PREF])?;
+        Ok(())
+    }
+
+    pub fn counters(&self) -> Result<DropCounters>{
+        let direction = self.direction.to_string();
+        let out = cmd::tc(Some(&self.namespace), &["-s", "-j", "filter", "show", "dev", self.interface.as_str(), direction.as_str(), "pref", DROP_PREF])?;
+        let filters: serde_json::Value = serde_json::from_str(&out)?;
+        let filters = filters.as_array().cloned().unwrap_or_default();
+        for filter in filters{
+            let actions = match filter["options"]["actions"].as_array(){
+                Some(actions) => actions.clone(),
+                None => continue,
+            };
+            for action in actions{
+                if action["kind"] != "gact" {
+                    continue;
+                }
+                let stats = &action["stats"];
+                return Ok(DropCounters{
+                    packets: stats["packets"].as_u64().unwrap_or(0),
+                    bytes: stats["bytes"].as_u64().unwrap_or(0),
+                    dropped: stats["drops"].as_u64().unwrap_or(0),
+                });
+            }
+        }
+        Err(failed!("No drop injection on {} {} in {}", self.interface, self.direction, self.namespace))
+    }
+}
+
+/// Corrupts a fraction of the frames leaving an interface with a netem root
+/// qdisc. netem flips a random bit after any pending checksum offload has
+/// been resolved, so receivers see frames with bad L3/L4 checksums. netem
+/// only shapes egress, corrupt both ends of a link to affect both directions.
+pub struct CorruptInjection{
+    pub namespace: String,
+    pub interface: String,
+}
+
+impl CorruptInjection{
+    pub fn new(namespace: String, interface: String) -> CorruptInjection {
+        CorruptInjection{
+            namespace,
+            interface,
+        }
+    }
+
+    /// Corrupts `percent` of the frames, `correlation` (percent) makes a
+    /// corrupted frame more likely to follow another one.
+    pub fn apply(&self, percent: f64, correlation: f64) -> Result<()>{
+        for (name, value) in [("corruption", percent), ("correlation", correlation)]{
+            if !(0.0..=100.0).contains(&value) {
+                return Err(failed!("Invalid {} {}, expected a percentage between 0 and 100", name, value));
+            }
+        }
+        let percent = format!("{}%", percent);
+        let correlation = format!("{}%", correlation);
+        cmd::tc(Some(&self.namespace), &[
+            "qdisc", "replace", "dev", self.interface.as_str(), "root",
+            "netem", "corrupt", percent.as_str(), correlation.as_str(),
+        ])?;
+        Ok(())
+    }
+
+    pub fn remove(&self) -> Result<()>{
+        let qdiscs = cmd::tc(Some(&self.namespace), &["qdisc", "show", "dev", self.interface.as_str(), "root"])?;
+        if !qdiscs.contains("netem") {
+            return Err(failed!("No corruption injection on {} in {}", self.interface, self.namespace));
+        }
+        cmd::tc(Some(&self.namespace), &["qdisc", "del", "dev", self.interface.as_str(), "root"])?;
+        Ok(())
+    }
+}
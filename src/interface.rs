@@ -0,0 +1,310 @@
+use std::process::Command;
+use std::sync::Arc;
+
+use crate::cmd;
+use crate::error::{Result, RouterError};
+use crate::passthrough;
+use crate::queue::{Qdisc, QueueSpec};
+use crate::stats::{self, InterfaceStats};
+use crate::trace::Traced;
+use crate::transaction::Resource;
+use crate::{Config, Namespace};
+
+/// `ip` holds the IPv4 and `ip6` the IPv6 address in prefix notation.
+pub struct Interface{
+    pub name: String,
+    pub ip: Option<String>,
+    pub ip6: Option<String>,
+    pub namespace: Option<Arc<Namespace>>,
+    pub mtu: Option<u32>,
+}
+
+impl Interface {
+    pub fn new(name: String, namespace: Option<Arc<Namespace>>, ip: Option<String>, ip6: Option<String>, mtu: Option<u32>, config: &Config) -> Result<Arc<Interface>> {
+        if let Some(r) = config.interfaces.get(&name){
+            return Err(RouterError::Exists{ kind: "Interface", name: r.name.clone() });
+        }
+        let mut i = Interface{
+            name: name.clone(),
+            ip: None,
+            ip6: None,
+            namespace,
+            mtu,
+        };
+        // a veth end created by the pending batch, set up by it as well
+        let queued = i.namespace.as_ref().is_some_and(|ns| config.pending().creates(&ns.netns, &i.name));
+        if let Some(namespace) = i.namespace.clone().filter(|_| !queued){
+            if !namespace.has_link(&i.name)? {
+                let nic = passthrough::take(&i.name, &i.name, &namespace.netns)?;
+                config.transaction().record(Resource::Moved(nic.clone()));
+                config.nics.insert(i.name.clone(), nic);
+            }
+        }
+        let existing = if config.reconcile { i.addresses()? } else { Vec::new() };
+        let wanted: Vec<ipnet::IpNet> = [&ip, &ip6].into_iter().flatten()
+            .filter_map(|ip| ip.parse().ok())
+            .collect();
+        for stale in existing.iter().filter(|a| !wanted.contains(a)){
+            i.ip(&["addr", "del", stale.to_string().as_str(), "dev", i.name.as_str()])?;
+        }
+        for ip in [ip, ip6].into_iter().flatten(){
+            if ip.parse().is_ok_and(|a: ipnet::IpNet| existing.contains(&a)) {
+                if ip.contains(':') {
+                    i.ip6 = Some(ip);
+                } else {
+                    i.ip = Some(ip);
+                }
+                continue;
+            }
+            if queued {
+                i.queue_ip(ip, config)?;
+                continue;
+            }
+            i.set_ip(ip.clone())?;
+            config.transaction().record(Resource::Address{
+                name: i.name.clone(),
+                netns: i.namespace.as_ref().map(|n| n.netns.clone()),
+                address: ip,
+            });
+        }
+        if queued {
+            i.queue_up(config);
+        } else {
+            if let Some(mtu) = i.mtu{
+                i.set_mtu(mtu)?;
+            }
+            i.set_up()?;
+        }
+        let r = Arc::new(i);
+        config.interfaces.try_insert(name, r.clone())
+            .map_err(|other| RouterError::Exists{ kind: "Interface", name: other.name.clone() })?;
+        Ok(r)
+    }
+    /// Adds an IPv4 or IPv6 address. IPv6 addresses skip duplicate address
+    /// detection so they are usable right away.
+    fn set_ip(&mut self, ip: String) -> Result<()>{
+        let v6 = ip.parse::<ipnet::IpNet>()
+            .map_err(|e| RouterError::InvalidAddress{ address: ip.clone(), reason: e.to_string() })?
+            .addr()
+            .is_ipv6();
+        let mut args = vec!["addr", "add", ip.as_str(), "dev", self.name.as_str()];
+        if v6 {
+            args.push("nodad");
+        }
+        self.ip(&args).map_err(|e| e.context("Failed to set ip"))?;
+
+        if v6 {
+            self.ip6 = Some(ip);
+        } else {
+            self.ip = Some(ip);
+        }
+        Ok(())
+    }
+    /// Like `set_ip`, but queued in the pending batch, see `batch`.
+    fn queue_ip(&mut self, ip: String, config: &Config) -> Result<()>{
+        let v6 = ip.parse::<ipnet::IpNet>()
+            .map_err(|e| RouterError::InvalidAddress{ address: ip.clone(), reason: e.to_string() })?
+            .addr()
+            .is_ipv6();
+        let netns = self.namespace.as_ref().map(|n| n.netns.as_str());
+        let mut args = vec!["addr", "add", ip.as_str(), "dev", self.name.as_str()];
+        if v6 {
+            args.push("nodad");
+        }
+        let mut batch = config.pending();
+        batch.queue(netns, &args);
+        batch.record(netns, Resource::Address{
+            name: self.name.clone(),
+            netns: netns.map(|n| n.to_string()),
+            address: ip.clone(),
+        });
+        if v6 {
+            self.ip6 = Some(ip);
+        } else {
+            self.ip = Some(ip);
+        }
+        Ok(())
+    }
+    /// MTU and up state in one command of the pending batch.
+    fn queue_up(&self, config: &Config){
+        let mtu = self.mtu.map(|m| m.to_string());
+        let mut args = vec!["link", "set", "dev", self.name.as_str()];
+        if let Some(mtu) = &mtu{
+            args.extend(["mtu", mtu.as_str()]);
+        }
+        args.push("up");
+        config.pending().queue(self.namespace.as_ref().map(|n| n.netns.as_str()), &args);
+    }
+    /// Global IPv4 and IPv6 addresses currently configured.
+    fn addresses(&self) -> Result<Vec<ipnet::IpNet>>{
+        let out = self.ip(&["-j", "addr", "show", "dev", self.name.as_str()])?;
+        let links: serde_json::Value = serde_json::from_str(&out)?;
+        let mut addresses = Vec::new();
+        for l in links.as_array().cloned().unwrap_or_default(){
+            for a in l["addr_info"].as_array().cloned().unwrap_or_default(){
+                if a["scope"] != "global" {
+                    continue;
+                }
+                let addr = format!("{}/{}", a["local"].as_str().unwrap_or_default(), a["prefixlen"]);
+                if let Ok(addr) = addr.parse(){
+                    addresses.push(addr);
+                }
+            }
+        }
+        Ok(addresses)
+    }
+
+    /// Sets the MAC address, see `derived_mac`.
+    pub fn set_mac(&self, mac: &str) -> Result<()>{
+        self.ip(&["link", "set", "dev", self.name.as_str(), "address", mac])
+            .map_err(|e| e.context(format!("Failed to set MAC address of {}", self.name)))?;
+        Ok(())
+    }
+
+    /// Sets the packets the transmit queue holds.
+    pub fn set_txqueuelen(&self, len: u32) -> Result<()>{
+        self.ip(&["link", "set", "dev", self.name.as_str(), "txqueuelen", len.to_string().as_str()])
+            .map_err(|e| e.context(format!("Failed to set txqueuelen of {}", self.name)))?;
+        Ok(())
+    }
+
+    /// Replaces the root qdisc.
+    pub fn set_qdisc(&self, qdisc: Qdisc) -> Result<()>{
+        cmd::tc(self.namespace.as_ref().map(|n| n.netns.as_str()), &["qdisc", "replace", "dev", self.name.as_str(), "root", qdisc.to_string().as_str()])
+            .map_err(|e| e.context(format!("Failed to set qdisc of {}", self.name)))?;
+        Ok(())
+    }
+
+    /// Sets the transmit queue length and root qdisc of `queues`, the
+    /// number of queues is only taken when a veth pair is created.
+    pub fn set_queues(&self, queues: &QueueSpec) -> Result<()>{
+        if let Some(len) = queues.txqueuelen{
+            self.set_txqueuelen(len)?;
+        }
+        if let Some(qdisc) = queues.qdisc{
+            self.set_qdisc(qdisc)?;
+        }
+        Ok(())
+    }
+
+    /// Current rx/tx counters, see `stats::Poller` for deltas over time.
+    pub fn stats(&self) -> Result<InterfaceStats>{
+        stats::read(self.namespace.as_ref().map(|n| n.netns.as_str()), &self.name)
+    }
+
+    /// Runs ip in the interface's namespace.
+    pub(crate) fn ip(&self, args: &[&str]) -> Result<String>{
+        cmd::ip(self.namespace.as_ref().map(|n| n.netns.as_str()), args)
+    }
+
+    fn set_mtu(&mut self, mtu: u32) -> Result<()>{
+        self.ip(&["link", "set", "dev", self.name.as_str(), "mtu", mtu.to_string().as_str()])
+            .map_err(|e| e.context("Failed to set mtu"))?;
+        self.mtu = Some(mtu);
+        Ok(())
+    }
+    fn set_up(&mut self) -> Result<()>{
+        self.ip(&["link", "set", "dev", self.name.as_str(), "up"])
+            .map_err(|e| e.context("Failed to set up"))?;
+        Ok(())
+    }
+}
+
+/// Longest interface name the kernel takes, IFNAMSIZ less the NUL.
+pub const MAX_NAME: usize = 15;
+
+/// Kernel name of the interface of `namespace` on the link, bridge, VXLAN
+/// link or tunnel `segment`: `<namespace>_<segment>`, see `shorten`.
+pub fn name(namespace: &str, segment: &str) -> String {
+    shorten(&format!("{}_{}", namespace, segment))
+}
+
+/// `logical` if the kernel takes it as interface name, else its first 10
+/// bytes followed by 5 hex digits hashed from all of it, the same on every
+/// run and host. The interface keeps `logical` as altname, see `alias`.
+pub fn shorten(logical: &str) -> String {
+    if logical.len() <= MAX_NAME {
+        return logical.to_string();
+    }
+    let mut end = MAX_NAME - 5;
+    while !logical.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{:05x}", &logical[..end], fnv1a(logical.bytes()) & 0xfffff)
+}
+
+/// Fails unless the kernel takes `name` as interface name.
+pub fn check_name(name: &str) -> Result<()>{
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(RouterError::InvalidName{ name: name.to_string(), reason: "reserved".to_string() });
+    }
+    if name.len() > MAX_NAME {
+        return Err(RouterError::NameTooLong{ name: name.to_string(), max: MAX_NAME });
+    }
+    if name.contains(|c: char| c == '/' || c == ':' || c.is_whitespace()) {
+        return Err(RouterError::InvalidName{ name: name.to_string(), reason: "contains '/', ':' or whitespace".to_string() });
+    }
+    Ok(())
+}
+
+/// Gives interface `name` in `netns` the logical name it was shortened
+/// from as altname, so `ip link show <logical>` finds it, and records the
+/// alias in `config`. Nothing to do for names that weren't shortened.
+pub fn alias(name: &str, netns: Option<&str>, logical: &str, config: &Config) -> Result<()>{
+    if name == logical {
+        return Ok(());
+    }
+    if let Some(other) = config.aliases.get(name).filter(|other| *other != logical) {
+        return Err(RouterError::Invalid(format!("Interface names {} and {} both shorten to {}, rename one", other, logical, name)));
+    }
+    if let Some(netns) = netns.filter(|netns| config.pending().creates(netns, name)) {
+        config.pending().queue(Some(netns), &["link", "property", "add", "dev", name, "altname", logical]);
+        config.aliases.insert(name.to_string(), logical.to_string());
+        return Ok(());
+    }
+    let mut cmd = Command::new("ip");
+    if let Some(netns) = netns{
+        cmd.args(["-n", netns]);
+    }
+    cmd.args(["link", "property", "add", "dev", name, "altname", logical]);
+    let output = cmd.traced_output()?;
+    // kept from before when reconciling
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() && !stderr.contains("exists") {
+        return Err(RouterError::command(&cmd, &output).context(format!("Failed to give {} the altname {}", name, logical)));
+    }
+    config.aliases.insert(name.to_string(), logical.to_string());
+    Ok(())
+}
+
+// FNV-1a, stable unlike the std hasher
+pub(crate) fn fnv1a(bytes: impl Iterator<Item = u8>) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes{
+        hash = (hash ^ b as u64).wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Locally administered unicast MAC address hashed from the topology and
+/// interface name, the same on every run and host.
+pub fn derived_mac(topology: &str, name: &str) -> String {
+    let hash = fnv1a(topology.bytes().chain([0]).chain(name.bytes()));
+    let b = hash.to_be_bytes();
+    format!("02:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", b[0], b[1], b[2], b[3], b[4])
+}
+
+/// Fails unless `mac` is a unicast MAC address like `02:00:00:00:00:01`.
+pub fn check_mac(mac: &str) -> Result<()>{
+    let bytes: Vec<u8> = mac.split(':')
+        .map(|b| if b.len() == 2 { u8::from_str_radix(b, 16).ok() } else { None })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| RouterError::InvalidAddress{ address: mac.to_string(), reason: "not a MAC address".to_string() })?;
+    if bytes.len() != 6 {
+        return Err(RouterError::InvalidAddress{ address: mac.to_string(), reason: "not a MAC address".to_string() });
+    }
+    if bytes[0] & 1 == 1 {
+        return Err(RouterError::InvalidAddress{ address: mac.to_string(), reason: "multicast".to_string() });
+    }
+    Ok(())
+}
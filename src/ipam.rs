@@ -0,0 +1,368 @@
+//! Address management for link subnets. Subnets are carved out of
+//! configured pools in order, every subnet in use (allocated or assigned by
+//! hand) is tracked so overlaps are caught before anything is created.
+
+use std::collections::BTreeSet;
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Result, RouterError};
+
+/// Pool configuration of a topology, e.g. `/31`s out of `10.0.0.0/16`.
+/// Loopback addresses come from pools of their own, see `loopback`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IpamSpec{
+    #[serde(default)]
+    pub pool: Option<String>,
+    #[serde(default = "default_prefix")]
+    pub prefix: u8,
+    #[serde(default)]
+    pub pool6: Option<String>,
+    #[serde(default = "default_prefix6")]
+    pub prefix6: u8,
+    /// pool of IPv4 loopback addresses and router ids
+    #[serde(default)]
+    pub loopbacks: Option<String>,
+    /// pool of IPv6 loopback addresses
+    #[serde(default)]
+    pub loopbacks6: Option<String>,
+}
+
+impl Default for IpamSpec{
+    fn default() -> Self {
+        IpamSpec{
+            pool: None,
+            prefix: default_prefix(),
+            pool6: None,
+            prefix6: default_prefix6(),
+            loopbacks: None,
+            loopbacks6: None,
+        }
+    }
+}
+
+fn default_prefix() -> u8 {
+    31
+}
+
+fn default_prefix6() -> u8 {
+    64
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Ipam{
+    /// pool and the prefix length of the subnets carved out of it
+    pools: Vec<(IpNet, u8)>,
+    /// pools of loopback addresses
+    loopbacks: Vec<IpNet>,
+    used: BTreeSet<IpNet>,
+}
+
+impl Ipam{
+    /// Adds the pools of `spec`, pools already present are kept.
+    pub fn configure(&mut self, spec: &IpamSpec) -> Result<()>{
+        if let Some(pool) = &spec.pool{
+            self.add_pool(pool, spec.prefix)?;
+        }
+        if let Some(pool) = &spec.pool6{
+            self.add_pool(pool, spec.prefix6)?;
+        }
+        for pool in spec.loopbacks.iter().chain(spec.loopbacks6.iter()){
+            self.add_loopback_pool(pool)?;
+        }
+        Ok(())
+    }
+
+    pub fn add_pool(&mut self, pool: &str, prefix: u8) -> Result<()>{
+        let net: IpNet = pool.parse()
+            .map_err(|e: ipnet::AddrParseError| RouterError::InvalidSubnet{ subnet: pool.to_string(), reason: e.to_string() })?;
+        let net = net.trunc();
+        if prefix < net.prefix_len() || prefix > net.max_prefix_len() {
+            return Err(RouterError::InvalidSubnet{ subnet: net.to_string(), reason: format!("cannot carve /{} subnets out of it", prefix) });
+        }
+        if self.pools.iter().any(|(p, l)| *p == net && *l == prefix) {
+            return Ok(());
+        }
+        if let Some(p) = self.pools.iter().map(|(p, _)| p).chain(self.loopbacks.iter()).find(|p| overlaps(p, &net)){
+            return Err(RouterError::PoolOverlap{ pool: net.to_string(), other: p.to_string() });
+        }
+        self.pools.push((net, prefix));
+        Ok(())
+    }
+
+    /// Adds a pool loopback addresses are allocated from, one address
+    /// each.
+    pub fn add_loopback_pool(&mut self, pool: &str) -> Result<()>{
+        let net: IpNet = pool.parse()
+            .map_err(|e: ipnet::AddrParseError| RouterError::InvalidSubnet{ subnet: pool.to_string(), reason: e.to_string() })?;
+        let net = net.trunc();
+        if self.loopbacks.contains(&net) {
+            return Ok(());
+        }
+        if let Some(p) = self.pools.iter().map(|(p, _)| p).chain(self.loopbacks.iter()).find(|p| overlaps(p, &net)){
+            return Err(RouterError::PoolOverlap{ pool: net.to_string(), other: p.to_string() });
+        }
+        self.loopbacks.push(net);
+        Ok(())
+    }
+
+    /// Subnets of a link: an empty `subnet` is allocated from the IPv4 pool,
+    /// plus a `subnet6` if an IPv6 pool exists as well (or only an IPv6
+    /// subnet without IPv4 pool). Hand-assigned subnets are reserved.
+    pub fn assign(&mut self, subnet: String, subnet6: Option<String>) -> Result<(String, Option<String>)>{
+        self.assign_hosts(subnet, subnet6, 0)
+    }
+
+    /// Like `assign`, for a segment whose members get hosts 1 to `hosts`,
+    /// see `allocate_hosts`.
+    pub fn assign_hosts(&mut self, subnet: String, subnet6: Option<String>, hosts: u128) -> Result<(String, Option<String>)>{
+        if subnet.is_empty() {
+            if subnet6.is_some() {
+                return Err(RouterError::Invalid("subnet6 given without subnet".to_string()));
+            }
+            return match self.allocate_hosts(false, hosts){
+                Ok(v4) => {
+                    let v6 = self.allocate_hosts(true, hosts).ok();
+                    Ok((v4.to_string(), v6.map(|v6| v6.to_string())))
+                },
+                Err(e) => match self.allocate_hosts(true, hosts){
+                    Ok(v6) => Ok((v6.to_string(), None)),
+                    Err(_) => Err(e),
+                },
+            };
+        }
+        if let Some(subnet6) = &subnet6{
+            let v4: IpNet = subnet.parse()
+                .map_err(|e: ipnet::AddrParseError| RouterError::InvalidSubnet{ subnet: subnet.clone(), reason: e.to_string() })?;
+            let v6: IpNet = subnet6.parse()
+                .map_err(|e: ipnet::AddrParseError| RouterError::InvalidSubnet{ subnet: subnet6.clone(), reason: e.to_string() })?;
+            if !v4.addr().is_ipv4() || !v6.addr().is_ipv6() {
+                return Err(RouterError::Invalid("dual-stack needs an IPv4 subnet and an IPv6 subnet6".to_string()));
+            }
+        }
+        self.reserve(&subnet)?;
+        if let Some(subnet6) = &subnet6{
+            if let Err(e) = self.reserve(subnet6){
+                self.release(&subnet)?;
+                return Err(e);
+            }
+        }
+        Ok((subnet, subnet6))
+    }
+
+    /// Addresses of a loopback as host prefixes: declared ones are
+    /// reserved, without any an IPv4 address is allocated, plus an IPv6
+    /// one if an IPv6 loopback pool exists as well (or only an IPv6
+    /// address without IPv4 pool).
+    pub fn assign_loopback(&mut self, address: Option<String>, address6: Option<String>) -> Result<(Option<String>, Option<String>)>{
+        if address.is_none() && address6.is_none() {
+            return match self.allocate_loopback(false){
+                Ok(v4) => {
+                    let v6 = self.allocate_loopback(true).ok();
+                    Ok((Some(v4.to_string()), v6.map(|v6| v6.to_string())))
+                },
+                Err(e) => match self.allocate_loopback(true){
+                    Ok(v6) => Ok((None, Some(v6.to_string()))),
+                    Err(_) => Err(e),
+                },
+            };
+        }
+        let mut hosts = Vec::new();
+        for (declared, v6) in [(&address, false), (&address6, true)]{
+            let host = match declared{
+                Some(declared) => {
+                    let addr: std::net::IpAddr = declared.parse()
+                        .map_err(|e: std::net::AddrParseError| RouterError::InvalidAddress{ address: declared.clone(), reason: e.to_string() })?;
+                    if addr.is_ipv6() != v6 {
+                        return Err(RouterError::InvalidAddress{ address: declared.clone(), reason: "wrong family for a loopback".to_string() });
+                    }
+                    Some(IpNet::from(addr).to_string())
+                },
+                None => None,
+            };
+            hosts.push(host);
+        }
+        let (v4, v6) = (hosts[0].clone(), hosts[1].clone());
+        if let Some(v4) = &v4{
+            self.reserve(v4)?;
+        }
+        if let Some(v6) = &v6{
+            if let Err(e) = self.reserve(v6){
+                if let Some(v4) = &v4{
+                    self.release(v4)?;
+                }
+                return Err(e);
+            }
+        }
+        Ok((v4, v6))
+    }
+
+    /// Returns the first free subnet of the first pool of the requested
+    /// address family.
+    pub fn allocate(&mut self, v6: bool) -> Result<IpNet>{
+        self.allocate_hosts(v6, 0)
+    }
+
+    /// Like `allocate`, with a subnet holding hosts 1 to `hosts` and, for
+    /// IPv4, the broadcast address, even if that takes a shorter prefix
+    /// than that of the pool. Pools too small for it are skipped.
+    pub fn allocate_hosts(&mut self, v6: bool, hosts: u128) -> Result<IpNet>{
+        let family = if v6 { "IPv6" } else { "IPv4" };
+        let mut pools = self.pools.iter().filter(|(p, _)| p.addr().is_ipv6() == v6).peekable();
+        if pools.peek().is_none() {
+            return Err(RouterError::NoPool{ pool: format!("{} address", family) });
+        }
+        for (pool, prefix) in pools{
+            // the network address is never a host
+            let addresses = hosts + 1 + if v6 { 0 } else { 1 };
+            let prefix = (*prefix).min(pool.max_prefix_len() - addresses.next_power_of_two().trailing_zeros() as u8);
+            if prefix < pool.prefix_len() {
+                continue;
+            }
+            let subnets = pool.subnets(prefix)
+                .map_err(|e| RouterError::InvalidSubnet{ subnet: pool.to_string(), reason: e.to_string() })?;
+            if let Some(subnet) = subnets.into_iter().find(|s| !self.used.iter().any(|u| overlaps(u, s))){
+                self.used.insert(subnet);
+                return Ok(subnet);
+            }
+        }
+        Err(RouterError::PoolExhausted{ pool: format!("{} address", family) })
+    }
+
+    /// Returns the first free host address of the first loopback pool of
+    /// the requested address family as host prefix.
+    pub fn allocate_loopback(&mut self, v6: bool) -> Result<IpNet>{
+        let family = if v6 { "IPv6" } else { "IPv4" };
+        let mut pools = self.loopbacks.iter().filter(|p| p.addr().is_ipv6() == v6).peekable();
+        if pools.peek().is_none() {
+            return Err(RouterError::NoPool{ pool: format!("{} loopback", family) });
+        }
+        for pool in pools{
+            if let Some(host) = pool.hosts().map(IpNet::from).find(|h| !self.used.iter().any(|u| overlaps(u, h))){
+                self.used.insert(host);
+                return Ok(host);
+            }
+        }
+        Err(RouterError::PoolExhausted{ pool: format!("{} loopback", family) })
+    }
+
+    /// Marks a hand-assigned subnet as used. Fails if it overlaps a subnet
+    /// already in use.
+    pub fn reserve(&mut self, subnet: &str) -> Result<IpNet>{
+        let net: IpNet = subnet.parse()
+            .map_err(|e: ipnet::AddrParseError| RouterError::InvalidSubnet{ subnet: subnet.to_string(), reason: e.to_string() })?;
+        let net = net.trunc();
+        if let Some(u) = self.used.iter().find(|u| overlaps(u, &net)){
+            return Err(RouterError::SubnetOverlap{ subnet: subnet.to_string(), other: u.to_string() });
+        }
+        self.used.insert(net);
+        Ok(net)
+    }
+
+    pub fn release(&mut self, subnet: &str) -> Result<()>{
+        let net: IpNet = subnet.parse()
+            .map_err(|e: ipnet::AddrParseError| RouterError::InvalidSubnet{ subnet: subnet.to_string(), reason: e.to_string() })?;
+        self.used.remove(&net.trunc());
+        Ok(())
+    }
+
+    /// Subnets currently in use, sorted.
+    pub fn used(&self) -> impl Iterator<Item = &IpNet>{
+        self.used.iter()
+    }
+}
+
+fn overlaps(a: &IpNet, b: &IpNet) -> bool {
+    a.contains(&b.network()) || b.contains(&a.network())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn allocates_subnets_in_order_until_exhausted(){
+        let mut ipam = Ipam::default();
+        ipam.add_pool("10.0.0.0/30", 31).unwrap();
+        assert_eq!(ipam.allocate(false).unwrap().to_string(), "10.0.0.0/31");
+        assert_eq!(ipam.allocate(false).unwrap().to_string(), "10.0.0.2/31");
+        assert!(matches!(ipam.allocate(false), Err(RouterError::PoolExhausted{ .. })));
+        // released subnets are handed out again
+        ipam.release("10.0.0.0/31").unwrap();
+        assert_eq!(ipam.allocate(false).unwrap().to_string(), "10.0.0.0/31");
+    }
+
+    #[test]
+    fn allocation_moves_on_to_the_next_pool(){
+        let mut ipam = Ipam::default();
+        ipam.add_pool("10.0.0.0/31", 31).unwrap();
+        ipam.add_pool("10.1.0.0/30", 30).unwrap();
+        assert_eq!(ipam.allocate(false).unwrap().to_string(), "10.0.0.0/31");
+        assert_eq!(ipam.allocate(false).unwrap().to_string(), "10.1.0.0/30");
+        assert!(matches!(ipam.allocate(true), Err(RouterError::NoPool{ .. })));
+    }
+
+    #[test]
+    fn assigns_both_families_when_both_pools_exist(){
+        let mut ipam = Ipam::default();
+        ipam.configure(&IpamSpec{ pool: Some("10.0.0.0/24".to_string()), pool6: Some("fd00::/48".to_string()), ..Default::default() }).unwrap();
+        assert_eq!(ipam.assign(String::new(), None).unwrap(), ("10.0.0.0/31".to_string(), Some("fd00::/64".to_string())));
+        assert_eq!(ipam.assign(String::new(), None).unwrap(), ("10.0.0.2/31".to_string(), Some("fd00:0:0:1::/64".to_string())));
+    }
+
+    #[test]
+    fn allocation_skips_subnets_reserved_by_hand(){
+        let mut ipam = Ipam::default();
+        ipam.add_pool("10.0.0.0/29", 31).unwrap();
+        ipam.assign("10.0.0.0/30".to_string(), None).unwrap();
+        assert_eq!(ipam.allocate(false).unwrap().to_string(), "10.0.0.4/31");
+    }
+
+    #[test]
+    fn overlapping_subnets_and_pools_are_refused(){
+        let mut ipam = Ipam::default();
+        ipam.reserve("10.0.0.0/24").unwrap();
+        assert!(matches!(ipam.reserve("10.0.0.128/25"), Err(RouterError::SubnetOverlap{ .. })));
+        assert!(matches!(ipam.reserve("10.0.0.0/16"), Err(RouterError::SubnetOverlap{ .. })));
+        ipam.add_pool("10.1.0.0/16", 31).unwrap();
+        assert!(matches!(ipam.add_pool("10.1.2.0/24", 30), Err(RouterError::PoolOverlap{ .. })));
+        assert!(matches!(ipam.add_loopback_pool("10.1.255.0/24"), Err(RouterError::PoolOverlap{ .. })));
+        assert!(matches!(ipam.add_pool("10.2.0.0/24", 16), Err(RouterError::InvalidSubnet{ .. })));
+    }
+
+    #[test]
+    fn loopbacks_are_host_prefixes(){
+        let mut ipam = Ipam::default();
+        ipam.add_loopback_pool("10.255.0.0/30").unwrap();
+        assert_eq!(ipam.assign_loopback(None, None).unwrap(), (Some("10.255.0.1/32".to_string()), None));
+        assert_eq!(ipam.allocate_loopback(false).unwrap().to_string(), "10.255.0.2/32");
+        assert!(matches!(ipam.allocate_loopback(false), Err(RouterError::PoolExhausted{ .. })));
+        assert!(matches!(ipam.assign_loopback(Some("fd00::1".to_string()), None), Err(RouterError::InvalidAddress{ .. })));
+    }
+
+    #[test]
+    fn bridge_subnet_holds_all_members(){
+        let mut ipam = Ipam::default();
+        ipam.add_pool("10.0.0.0/24", 31).unwrap();
+        let (subnet, subnet6) = ipam.assign_hosts(String::new(), None, 3).unwrap();
+        assert_eq!(subnet, "10.0.0.0/29");
+        assert_eq!(subnet6, None);
+        let net: IpNet = subnet.parse().unwrap();
+        for host in 1..=3{
+            crate::link::host_addr(&net, host).unwrap();
+        }
+        // links keep the pool's prefix, next to the bridge's subnet
+        assert_eq!(ipam.assign(String::new(), None).unwrap().0, "10.0.0.8/31");
+        assert_eq!(ipam.assign_hosts(String::new(), None, 2).unwrap().0, "10.0.0.12/30");
+    }
+
+    #[test]
+    fn bridge_subnet_skips_pools_too_small(){
+        let mut ipam = Ipam::default();
+        ipam.add_pool("10.0.0.0/30", 31).unwrap();
+        ipam.add_pool("10.1.0.0/24", 31).unwrap();
+        assert_eq!(ipam.allocate_hosts(false, 4).unwrap().to_string(), "10.1.0.0/29");
+        assert!(matches!(ipam.allocate_hosts(true, 4), Err(RouterError::NoPool{ .. })));
+    }
+}
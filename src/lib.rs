@@ -0,0 +1,115 @@
+pub mod alert;
+pub mod api;
+pub mod auth;
+pub mod backup;
+pub mod batch;
+pub mod bench;
+pub mod bond;
+mod bridge;
+pub mod capture;
+pub mod chaos;
+pub mod churn;
+pub mod clock;
+pub mod cmd;
+pub mod compose;
+pub mod conntrack;
+pub mod container;
+pub mod containerlab;
+mod config;
+pub mod daemon;
+pub mod dataplane;
+pub mod distributed;
+pub mod dns;
+pub mod ecmp;
+pub mod environment;
+pub mod error;
+pub mod experiment;
+pub mod export;
+pub mod fastpath;
+pub mod firewall;
+pub mod flap;
+pub mod forwarder;
+pub mod fuzz;
+pub mod generators;
+pub mod gnmi;
+pub mod graph;
+pub mod group;
+pub mod guard;
+pub mod heal;
+pub mod hooks;
+pub mod icmp;
+pub mod import;
+pub mod inject;
+mod interface;
+pub mod ipam;
+pub mod liveness;
+mod link;
+pub mod logs;
+pub mod loopback;
+pub mod matrix;
+pub mod mirror;
+pub mod monitor;
+mod namespace;
+pub mod nat64;
+pub mod neighbor;
+pub mod netns;
+pub mod nftables;
+pub mod nonblocking;
+pub mod offload;
+pub mod ovs;
+pub mod owd;
+pub mod owner;
+pub mod p4;
+pub mod parallel;
+pub mod passthrough;
+pub mod persona;
+pub mod paths;
+pub mod plan;
+pub mod policy;
+pub mod pool;
+pub mod preflight;
+pub mod process;
+pub mod qos;
+pub mod queue;
+pub mod ra;
+pub mod restart;
+mod rib;
+mod route;
+pub mod scale;
+pub mod scenario;
+pub mod shell;
+pub mod show;
+pub mod snapshot;
+pub mod snmp;
+pub mod state;
+pub mod stats;
+pub mod stress;
+pub mod syslog;
+pub mod tcp;
+pub mod topology;
+pub mod trace;
+pub mod traffic;
+pub mod transaction;
+mod tunnel;
+pub mod validate;
+pub mod verify;
+#[cfg(feature = "af-xdp")]
+pub mod xsk;
+mod vrf;
+mod vxlan;
+mod wireguard;
+
+pub use bridge::{Bridge, BridgeBackend};
+pub use config::{Config, List, Registry};
+pub use error::RouterError;
+pub use interface::Interface;
+pub use link::{Ends, Link};
+pub(crate) use link::Veth;
+pub use namespace::Namespace;
+pub use rib::{Rib, RibChange};
+pub use route::{Nexthop, Route, RouteKind, Seg6, Seg6Local, Seg6Mode};
+pub use topology::{Topology, TopologyBuilder};
+pub use tunnel::{Tunnel, TunnelEnd, TunnelKind};
+pub use vrf::Vrf;
+pub use vxlan::{Vtep, VxlanLink};
+pub use wireguard::{Keypair, WireguardEnd, WireguardLink};
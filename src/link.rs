@@ -0,0 +1,334 @@
+use std::process::Command;
+use std::sync::Arc;
+
+use crate::cmd;
+use crate::error::{Result, RouterError};
+use crate::hooks::HookEvent;
+use crate::interface;
+use crate::loopback;
+use crate::queue::QueueSpec;
+use crate::transaction::Resource;
+use crate::{pool, Config, Interface, Namespace};
+
+/// `subnet` may be IPv4 or IPv6, `subnet6` adds an IPv6 subnet to an IPv4
+/// link to make it dual-stack. Unnumbered links have neither.
+pub struct Link{
+    pub name: String,
+    pub subnet: String,
+    pub subnet6: Option<String>,
+    pub ends: Ends,
+}
+
+/// How the two ends of a link are numbered.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Ends{
+    /// host numbers of the ends within every subnet, see `endpoint_addrs`
+    /// for the default
+    pub hosts: Option<[u128; 2]>,
+    /// exact addresses of each end, replacing the one of their family
+    pub addresses: [Vec<std::net::IpAddr>; 2],
+    /// the ends take the loopback addresses of their namespaces as host
+    /// prefixes, with a peer route to the other end's, instead of
+    /// addresses from a subnet
+    pub unnumbered: bool,
+}
+
+impl Ends{
+    /// Addresses of the two ends in `subnet`.
+    pub fn addrs(&self, subnet: &ipnet::IpNet) -> Result<(String, String)>{
+        let (mut a, mut b) = match self.hosts{
+            Some([a, b]) => (host_addr(subnet, a)?, host_addr(subnet, b)?),
+            None => endpoint_addrs(subnet)?,
+        };
+        for (end, addr) in self.addresses.iter().zip([&mut a, &mut b]){
+            if let Some(exact) = end.iter().find(|e| e.is_ipv6() == subnet.addr().is_ipv6()) {
+                if !subnet.contains(exact) {
+                    return Err(RouterError::InvalidAddress{ address: exact.to_string(), reason: format!("outside of subnet {}", subnet) });
+                }
+                *addr = format!("{}/{}", exact, subnet.prefix_len());
+            }
+        }
+        if a == b {
+            return Err(RouterError::Invalid(format!("Both ends get address {}", a)));
+        }
+        Ok((a, b))
+    }
+
+    /// (IPv4, IPv6) addresses of the ends of `link` joining `ns1` and `ns2`
+    /// numbered from `subnet` and `subnet6`, or the loopback addresses of
+    /// both namespaces if unnumbered.
+    pub(crate) fn assign(&self, link: &str, subnet: &str, subnet6: Option<&String>, ns1: &str, ns2: &str, config: &Config) -> Result<[(Option<String>, Option<String>); 2]>{
+        let mut ips: [(Option<String>, Option<String>); 2] = Default::default();
+        if self.unnumbered {
+            for (ns, ip) in [ns1, ns2].into_iter().zip(ips.iter_mut()){
+                let lo = config.interfaces.get(&loopback::name(ns))
+                    .ok_or_else(|| RouterError::Invalid(format!("Unnumbered link {} needs a loopback in {}", link, ns)))?;
+                *ip = (lo.ip.clone(), lo.ip6.clone());
+            }
+            return Ok(ips);
+        }
+        for subnet in std::iter::once(subnet).chain(subnet6.map(|s| s.as_str())){
+            let sn: ipnet::IpNet = subnet.parse()
+                .map_err(|e: ipnet::AddrParseError| RouterError::InvalidSubnet{ subnet: subnet.to_string(), reason: e.to_string() })?;
+            let (a, b) = self.addrs(&sn).map_err(|e| e.context(format!("Link {}", link)))?;
+            if sn.addr().is_ipv6() {
+                (ips[0].1, ips[1].1) = (Some(a), Some(b));
+            } else {
+                (ips[0].0, ips[1].0) = (Some(a), Some(b));
+            }
+        }
+        Ok(ips)
+    }
+}
+
+impl Link {
+    /// An empty `subnet` is allocated from the IPv4 pool of `config.ipam`,
+    /// plus an IPv6 `subnet6` if an IPv6 pool is configured as well (or only
+    /// an IPv6 subnet without IPv4 pool). Hand-assigned subnets must not
+    /// overlap any subnet already in use. Unnumbered links take no subnet.
+    pub fn new(name: String, subnet: String, subnet6: Option<String>, ends: Ends, config: &Config) -> Result<Arc<Link>> {
+        if let Some(r) = config.links.get(&name){
+            return Err(RouterError::Exists{ kind: "RouterLink", name: r.name.clone() });
+        }
+        if config.bridges.contains_key(&name) {
+            return Err(RouterError::Exists{ kind: "Bridge", name });
+        }
+        let (subnet, subnet6) = match ends.unnumbered{
+            true if !subnet.is_empty() || subnet6.is_some() => return Err(RouterError::Invalid(format!("Unnumbered link {} takes no subnet", name))),
+            true => (subnet, subnet6),
+            false => config.ipam().assign(subnet, subnet6).map_err(|e| e.context(format!("Link {}", name)))?,
+        };
+        let r = Arc::new(Link{
+            name: name.clone(),
+            subnet,
+            subnet6,
+            ends,
+        });
+        // another thread may have created the link in the meantime
+        if let Err(other) = config.links.try_insert(name, r.clone()) {
+            if !r.ends.unnumbered {
+                config.ipam().release(&r.subnet)?;
+            }
+            if let Some(subnet6) = &r.subnet6{
+                config.ipam().release(subnet6)?;
+            }
+            return Err(RouterError::Exists{ kind: "RouterLink", name: other.name.clone() });
+        }
+        Ok(r)
+    }
+    /// Connects `ns1` and `ns2`, giving the ends the MAC addresses of
+    /// `macs` where set instead of random ones and the number of queues of
+    /// `queues`. The rest of `queues` is set once the ends exist, see
+    /// `Interface::set_queues`.
+    pub fn attach(&self, ns1: Arc<Namespace>, ns2: Arc<Namespace>, macs: &[Option<String>; 2], queues: &QueueSpec, config: &Config) -> Result<(Arc<Interface>,Arc<Interface>)>{
+        let name1 = interface::name(&ns1.name, &self.name);
+        let name2 = interface::name(&ns2.name, &self.name);
+        let veth = Veth{
+            name: name1.clone(),
+            namespace: ns1.netns.clone(),
+            peer: name2.clone(),
+            peer_namespace: ns2.netns.clone(),
+            numtxqueues: queues.numtxqueues,
+            numrxqueues: queues.numrxqueues,
+        };
+        let batched = config.batching();
+        match batched{
+            true => config.pending().veth(&veth, macs),
+            false => veth.setup(config)?,
+        }
+        interface::alias(&name1, Some(&ns1.netns), &format!("{}_{}", ns1.name, self.name), config)?;
+        interface::alias(&name2, Some(&ns2.netns), &format!("{}_{}", ns2.name, self.name), config)?;
+
+        let [(ip1, ip1_6), (ip2, ip2_6)] = self.ends.assign(&self.name, &self.subnet, self.subnet6.as_ref(), &ns1.name, &ns2.name, config)?;
+        let i1 = Interface::new(name1.clone(), Some(ns1.clone()), ip1, ip1_6, Some(3000), config)?;
+        let i2 = Interface::new(name2.clone(), Some(ns2.clone()), ip2, ip2_6, Some(3000), config)?;
+        // batched pairs are created with their MAC addresses
+        for (i, mac) in [&i1, &i2].into_iter().zip(macs).filter(|_| !batched){
+            if let Some(mac) = mac{
+                i.set_mac(mac)?;
+            }
+        }
+        self.peer_routes(&i1, &i2, config)?;
+        // hooks expect the link to be up
+        if batched && config.hooks.wants(HookEvent::LinkUp) {
+            config.flush()?;
+        }
+        config.hooks.link_up(&config.name, self, &i1, &i2)?;
+
+        Ok((i1,i2))
+    }
+
+    /// Routes of unnumbered ends to the address of the other end, which the
+    /// kernel would add for an address with peer. They are marked as kernel
+    /// routes, as they come and go with the ends.
+    pub(crate) fn peer_routes(&self, i1: &Interface, i2: &Interface, config: &Config) -> Result<()>{
+        if !self.ends.unnumbered {
+            return Ok(());
+        }
+        for (local, peer) in [(i1, i2), (i2, i1)]{
+            let netns = local.namespace.as_ref().map(|n| n.netns.as_str());
+            let queued = netns.is_some_and(|netns| config.pending().creates(netns, &local.name));
+            for args in peer_routes(&local.name, [&local.ip, &local.ip6], [&peer.ip, &peer.ip6]){
+                let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+                match queued{
+                    true => config.pending().queue(netns, &args),
+                    false => {
+                        local.ip(&args)?;
+                    },
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the veth pair of the link and returns its subnets to
+    /// `config.ipam`.
+    pub fn delete(&self, config: &Config) -> Result<()>{
+        let ends: Vec<String> = config.namespaces.keys()
+            .map(|ns| interface::name(&ns, &self.name))
+            .filter(|i| config.interfaces.contains_key(i))
+            .collect();
+        // deleting one end of a veth removes the peer as well
+        if let Some(intf) = ends.first().and_then(|i| config.interfaces.get(i)){
+            if let Some(ns) = &intf.namespace{
+                delete_link(&ns.netns, &intf.name)
+                    .map_err(|e| e.context("Failed to delete veth"))?;
+            }
+        }
+        for i in ends{
+            config.interfaces.remove(&i);
+            config.aliases.remove(&i);
+        }
+        if !self.ends.unnumbered {
+            config.ipam().release(&self.subnet)?;
+        }
+        if let Some(subnet6) = &self.subnet6{
+            config.ipam().release(subnet6)?;
+        }
+        config.links.remove(&self.name);
+        Ok(())
+    }
+}
+
+/// `ip` arguments of the routes of unnumbered end `name` with (IPv4,
+/// IPv6) addresses `local` to the addresses `peer` of the other end.
+pub(crate) fn peer_routes(name: &str, local: [&Option<String>; 2], peer: [&Option<String>; 2]) -> Vec<Vec<String>>{
+    let host = |a: &Option<String>| a.as_ref().and_then(|a| a.split('/').next()).map(|a| a.to_string());
+    local.into_iter().zip(peer)
+        .filter_map(|(l, p)| Some((host(l)?, host(p)?)))
+        .map(|(l, p)| ["route", "replace", &p, "dev", name, "src", &l, "proto", "kernel", "scope", "link"].iter().map(|a| a.to_string()).collect())
+        .collect()
+}
+
+/// Addresses of the two ends of a point-to-point subnet: the first two
+/// hosts, or both addresses of a /31 (/127).
+pub(crate) fn endpoint_addrs(subnet: &ipnet::IpNet) -> Result<(String, String)>{
+    if subnet.prefix_len() + 1 == subnet.max_prefix_len() {
+        return Ok((host_addr(subnet, 0)?, host_addr(subnet, 1)?));
+    }
+    Ok((host_addr(subnet, 1)?, host_addr(subnet, 2)?))
+}
+
+/// Returns the `host`-th address of `subnet` with the subnet's prefix length.
+pub(crate) fn host_addr(subnet: &ipnet::IpNet, host: u128) -> Result<String>{
+    let addr = match subnet{
+        ipnet::IpNet::V4(n) => {
+            let a = u32::from(n.network()) as u128 + host;
+            let a = u32::try_from(a)
+                .map_err(|_| RouterError::InvalidSubnet{ subnet: subnet.to_string(), reason: format!("has no host {}", host) })?;
+            std::net::IpAddr::V4(std::net::Ipv4Addr::from(a))
+        },
+        ipnet::IpNet::V6(n) => {
+            let a = u128::from(n.network()).checked_add(host)
+                .ok_or_else(|| RouterError::InvalidSubnet{ subnet: subnet.to_string(), reason: format!("has no host {}", host) })?;
+            std::net::IpAddr::V6(std::net::Ipv6Addr::from(a))
+        },
+    };
+    if !subnet.contains(&addr) {
+        return Err(RouterError::InvalidSubnet{ subnet: subnet.to_string(), reason: format!("has no host {}", host) });
+    }
+    Ok(format!("{}/{}", addr, subnet.prefix_len()))
+}
+
+/// veth pair created directly inside the namespaces of both ends, so equally
+/// named pairs of different topologies never clash in the root namespace.
+pub(crate) struct Veth{
+    pub(crate) name: String,
+    pub(crate) namespace: String,
+    pub(crate) peer: String,
+    pub(crate) peer_namespace: String,
+    /// queues of both ends, the kernel's single pair if None
+    pub(crate) numtxqueues: Option<u32>,
+    pub(crate) numrxqueues: Option<u32>,
+}
+
+impl Veth{
+    /// Creates the pair unless it is taken from the pool or, when
+    /// reconciling, both ends already exist with the queues asked for.
+    /// Pooled pairs have a single queue pair, so multiqueue pairs are
+    /// always created.
+    pub(crate) fn setup(&self, config: &Config) -> Result<()>{
+        if config.reconcile {
+            let ends = [(&self.namespace, &self.name), (&self.peer_namespace, &self.peer)];
+            let found = ends.iter().map(|(ns, name)| queues(ns, name)).collect::<Result<Vec<_>>>()?;
+            if found.iter().all(|f| f.is_some_and(|q| self.has_queues(q))) {
+                return Ok(());
+            }
+            // half a pair is left over or the queues changed, which only
+            // creating the pair sets. Deleting an end of a whole pair
+            // removes its peer, so each end is looked up again.
+            for (ns, name) in ends{
+                if queues(ns, name)?.is_some() {
+                    delete_link(ns, name)?;
+                }
+            }
+        }
+        let multiqueue = self.numtxqueues.is_some() || self.numrxqueues.is_some();
+        if !(config.pool && !multiqueue && pool::take_veth(self)?) {
+            self.create()?;
+            config.transaction().record(Resource::Veth{ name: self.name.clone(), netns: self.namespace.clone() });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn create(&self) -> Result<()>{
+        let queues = self.queue_args();
+        let mut cmd = Command::new("ip");
+        cmd.args(["link", "add", "name", self.name.as_str(), "netns", self.namespace.as_str()]).args(&queues).args(["type", "veth"])
+            .args(["peer", "name", self.peer.as_str(), "netns", self.peer_namespace.as_str()]).args(&queues);
+        cmd::output(&mut cmd).map_err(|e| e.context("Failed to create veth"))?;
+        Ok(())
+    }
+
+    /// `ip link` arguments setting the queues of an end.
+    pub(crate) fn queue_args(&self) -> Vec<String>{
+        let mut args = Vec::new();
+        for (arg, n) in [("numtxqueues", self.numtxqueues), ("numrxqueues", self.numrxqueues)]{
+            if let Some(n) = n{
+                args.extend([arg.to_string(), n.to_string()]);
+            }
+        }
+        args
+    }
+
+    /// Whether an end with (tx, rx) `queues` has those asked for.
+    fn has_queues(&self, queues: (u32, u32)) -> bool {
+        queues == (self.numtxqueues.unwrap_or(1), self.numrxqueues.unwrap_or(1))
+    }
+}
+
+/// (tx, rx) queues of `name` in `netns`, None if it does not exist. Fails
+/// if `netns` can't be read.
+fn queues(netns: &str, name: &str) -> Result<Option<(u32, u32)>>{
+    let links = cmd::ip_json(Some(netns), &["-d", "-j", "link", "show"])?;
+    let Some(link) = links.as_array().and_then(|l| l.iter().find(|l| l["ifname"] == name)) else {
+        return Ok(None);
+    };
+    let count = |key: &str| link[key].as_u64().map_or(1, |n| n as u32);
+    Ok(Some((count("num_tx_queues"), count("num_rx_queues"))))
+}
+
+fn delete_link(netns: &str, name: &str) -> Result<()>{
+    cmd::ip(Some(netns), &["link", "del", "dev", name])?;
+    Ok(())
+}
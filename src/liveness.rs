@@ -0,0 +1,264 @@
+//! BFD-style liveness of the links of a running topology. Both ends of a
+//! link send a UDP probe to each other every `interval`, telling the peer
+//! whether they heard from it within the detection time, `multiplier`
+//! intervals. An end is up while it hears its peer and its peer hears it,
+//! a link while both of its ends are, so a link dropping packets in one
+//! direction only goes down as well. Failures are detected within the
+//! detection time, well below a second at the default 50 ms times 3,
+//! without a routing daemon running BFD.
+//!
+//! One thread serves the probes of all links. Links start in the state
+//! they are found in after the first detection time, only changes after
+//! that are reported, see `LivenessEvent`. `chaos` runs the prober along
+//! a run to tell how long links took to be detected down and up again.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::chaos;
+use crate::error::{failed, Result};
+use crate::netns;
+use crate::state::State;
+
+/// Marks the probes, followed by whether the sender hears its peer.
+const MAGIC: &[u8; 4] = b"RRLV";
+
+#[derive(Clone, Copy, Debug)]
+pub struct LivenessOptions{
+    /// time between two probes of an end
+    pub interval: Duration,
+    /// intervals without a probe after which an end goes down
+    pub multiplier: u32,
+}
+
+impl Default for LivenessOptions{
+    fn default() -> Self {
+        LivenessOptions{
+            interval: Duration::from_millis(50),
+            multiplier: 3,
+        }
+    }
+}
+
+impl LivenessOptions{
+    /// Time without a probe after which an end goes down.
+    pub fn detection(&self) -> Duration {
+        self.interval * self.multiplier.max(1)
+    }
+}
+
+/// Link going down or coming up.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct LivenessEvent{
+    /// since the prober started
+    pub at: Duration,
+    pub link: String,
+    pub up: bool,
+}
+
+impl fmt::Display for LivenessEvent{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>9.3}s {} {}", self.at.as_secs_f64(), self.link, if self.up { "up" } else { "down" })
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct LivenessReport{
+    /// links probed and whether they were up once the prober started
+    pub links: Vec<(String, bool)>,
+    pub events: Vec<LivenessEvent>,
+}
+
+impl fmt::Display for LivenessReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let down: Vec<&str> = self.links.iter().filter(|(_, up)| !up).map(|(l, _)| l.as_str()).collect();
+        write!(f, "{} links probed", self.links.len())?;
+        if !down.is_empty() {
+            write!(f, ", down at start: {}", down.join(" "))?;
+        }
+        for e in &self.events{
+            write!(f, "\n{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+/// Session of one end of a link.
+struct End{
+    socket: UdpSocket,
+    peer: SocketAddr,
+    /// when the last probe of the peer arrived
+    heard: Option<Instant>,
+    /// whether the peer said it hears this end
+    heard_back: bool,
+}
+
+impl End{
+    fn hears(&self, now: Instant, detection: Duration) -> bool {
+        self.heard.is_some_and(|t| now - t < detection)
+    }
+
+    fn up(&self, now: Instant, detection: Duration) -> bool {
+        self.hears(now, detection) && self.heard_back
+    }
+}
+
+struct Session{
+    link: String,
+    ends: [End; 2],
+    up: bool,
+}
+
+/// Probes links until stopped.
+pub struct LivenessProber{
+    start: Instant,
+    links: Vec<(String, bool)>,
+    events: Arc<Mutex<Vec<LivenessEvent>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LivenessProber{
+    /// Starts probing `links` of the topology in `state`, all of them if
+    /// empty, and returns once the first detection time passed. Links
+    /// without two numbered ends are left out.
+    pub fn start(state: &State, links: &[String], options: LivenessOptions) -> Result<LivenessProber>{
+        if options.interval.is_zero() {
+            return Err(failed!("Liveness probes need an interval above 0"));
+        }
+        let mut sessions = Vec::new();
+        for link in &state.links{
+            if !links.is_empty() && !links.contains(&link.name) {
+                continue;
+            }
+            if let Some(ends) = session(state, &link.name)? {
+                sessions.push(Session{ link: link.name.clone(), ends, up: false });
+            }
+        }
+        if let Some(missing) = links.iter().find(|l| !sessions.iter().any(|s| s.link == **l)) {
+            return Err(failed!("Link {} of {} has no two numbered ends to probe", missing, state.name));
+        }
+        let detection = options.detection();
+        let start = Instant::now();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (ready_tx, ready) = std::sync::mpsc::channel();
+        let thread = {
+            let (events, stop) = (events.clone(), stop.clone());
+            std::thread::spawn(move || {
+                let mut ready_tx = Some(ready_tx);
+                let mut next = Instant::now();
+                while !stop.load(Ordering::Relaxed) {
+                    let now = Instant::now();
+                    for s in &mut sessions{
+                        for end in &mut s.ends{
+                            receive(end, now);
+                        }
+                        for end in &s.ends{
+                            let probe = [MAGIC[0], MAGIC[1], MAGIC[2], MAGIC[3], end.hears(now, detection) as u8];
+                            let _ = end.socket.send_to(&probe, end.peer);
+                        }
+                        let up = s.ends.iter().all(|e| e.up(now, detection));
+                        if ready_tx.is_none() && up != s.up {
+                            events.lock().unwrap_or_else(PoisonError::into_inner)
+                                .push(LivenessEvent{ at: now - start, link: s.link.clone(), up });
+                        }
+                        s.up = up;
+                    }
+                    // a session takes three rounds to come up
+                    if now - start >= detection.max(options.interval * 3) {
+                        if let Some(tx) = ready_tx.take() {
+                            let _ = tx.send(sessions.iter().map(|s| (s.link.clone(), s.up)).collect::<Vec<_>>());
+                        }
+                    }
+                    next += options.interval;
+                    std::thread::sleep(next.saturating_duration_since(Instant::now()));
+                }
+            })
+        };
+        let links = ready.recv().map_err(|_| failed!("Liveness prober of {} stopped", state.name))?;
+        Ok(LivenessProber{ start, links, events, stop, thread: Some(thread) })
+    }
+
+    /// When the prober started, the time events are counted from.
+    pub fn started(&self) -> Instant {
+        self.start
+    }
+
+    /// Links probed and whether they were up once the prober started.
+    pub fn links(&self) -> &[(String, bool)] {
+        &self.links
+    }
+
+    /// Changes seen so far.
+    pub fn events(&self) -> Vec<LivenessEvent> {
+        self.events.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Stops probing and reports what was seen.
+    pub fn stop(mut self) -> LivenessReport {
+        self.halt();
+        LivenessReport{ links: std::mem::take(&mut self.links), events: self.events() }
+    }
+
+    fn halt(&mut self){
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for LivenessProber{
+    fn drop(&mut self){
+        self.halt();
+    }
+}
+
+/// Sockets of both ends of link `name`, bound to their addresses inside
+/// their namespaces, None unless it has two ends with an address of the
+/// same family.
+fn session(state: &State, name: &str) -> Result<Option<[End; 2]>>{
+    let ends = chaos::link_ends(state, name)?;
+    if ends.len() != 2 {
+        return Ok(None);
+    }
+    let addresses: Vec<(Option<IpAddr>, Option<IpAddr>)> = ends.iter().map(|(netns, interface)| {
+        let i = state.interfaces.iter().find(|i| i.name == *interface && i.netns.as_deref() == Some(netns.as_str()));
+        let address = |ip: Option<&String>| ip.and_then(|ip| ip.split('/').next()?.parse().ok());
+        (address(i.and_then(|i| i.ip.as_ref())), address(i.and_then(|i| i.ip6.as_ref())))
+    }).collect();
+    let pair = match (addresses[0], addresses[1]){
+        ((Some(a), _), (Some(b), _)) | ((_, Some(a)), (_, Some(b))) => (a, b),
+        _ => return Ok(None),
+    };
+    let mut sockets = Vec::new();
+    for ((netns, _), address) in ends.iter().zip([pair.0, pair.1]){
+        let socket = netns::run_in(netns, || Ok(UdpSocket::bind(SocketAddr::new(address, 0))?))
+            .map_err(|e| failed!("Failed to open liveness socket on {} of link {}: {}", address, name, e))?;
+        socket.set_nonblocking(true)?;
+        sockets.push(socket);
+    }
+    let b = sockets.pop().unwrap();
+    let a = sockets.pop().unwrap();
+    let (to_b, to_a) = (b.local_addr()?, a.local_addr()?);
+    let end = |socket, peer| End{ socket, peer, heard: None, heard_back: false };
+    Ok(Some([end(a, to_b), end(b, to_a)]))
+}
+
+/// Takes the probes of the peer queued on `end`.
+fn receive(end: &mut End, now: Instant){
+    let mut buf = [0u8; 16];
+    while let Ok((len, from)) = end.socket.recv_from(&mut buf) {
+        if from == end.peer && len == MAGIC.len() + 1 && buf[..MAGIC.len()] == MAGIC[..] {
+            end.heard = Some(now);
+            end.heard_back = buf[MAGIC.len()] != 0;
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Output of processes launched in namespaces, one log file per process
+//! under `/var/log/router-rs/<topology>/<netns>/<name>.log`. Logs outlive
+//! the namespaces, `collect` copies them into a run's artifact directory.
+//! Daemons detach from us, so files are rotated when a process is (re)started
+//! rather than while it writes.
+
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use crate::error::{failed, Result};
+
+pub const LOG_DIR: &str = "/var/log/router-rs";
+/// Size from which a log is rotated on the next start.
+pub const MAX_SIZE: u64 = 1 << 20;
+/// Rotated files kept, `<name>.log.1` being the newest.
+pub const KEEP: usize = 3;
+
+/// Log directory of `netns` in `topology`.
+pub fn dir(topology: &str, netns: &str) -> PathBuf {
+    PathBuf::from(LOG_DIR).join(topology).join(netns)
+}
+
+pub fn path(topology: &str, netns: &str, name: &str) -> PathBuf {
+    dir(topology, netns).join(format!("{}.log", name))
+}
+
+/// Opens the log of process `name` for appending, rotating it first if it
+/// grew past `MAX_SIZE`. Hand the file to a `Command` as stdout and, via
+/// `try_clone`, stderr.
+pub fn open(topology: &str, netns: &str, name: &str) -> Result<File>{
+    let path = path(topology, netns, name);
+    std::fs::create_dir_all(dir(topology, netns))?;
+    if std::fs::metadata(&path).is_ok_and(|m| m.len() > MAX_SIZE) {
+        rotate(&path)?;
+    }
+    OpenOptions::new().create(true).append(true).open(&path)
+        .map_err(|e| failed!("Failed to open log {}: {}", path.display(), e))
+}
+
+fn rotate(path: &Path) -> Result<()>{
+    let rotated = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+    let _ = std::fs::remove_file(rotated(KEEP));
+    for n in (1..KEEP).rev(){
+        if rotated(n).exists() {
+            std::fs::rename(rotated(n), rotated(n + 1))?;
+        }
+    }
+    std::fs::rename(path, rotated(1))?;
+    Ok(())
+}
+
+/// Last `lines` lines of a log, for error messages.
+pub fn tail(path: &Path, lines: usize) -> String {
+    let content = std::fs::read_to_string(path).unwrap_or_default();
+    let all: Vec<&str> = content.lines().collect();
+    all[all.len().saturating_sub(lines)..].join("\n")
+}
+
+/// Copies all logs of `topology`, rotated ones included, to
+/// `<dest>/logs/<netns>/`, and those of the whole topology such as the
+/// `syslog` collector file to `<dest>/logs/`. Returns the copied files.
+pub fn collect(topology: &str, dest: &Path) -> Result<Vec<PathBuf>>{
+    let src = PathBuf::from(LOG_DIR).join(topology);
+    if !src.exists() {
+        return Err(failed!("No logs of topology {} in {}", topology, LOG_DIR));
+    }
+    let mut files = Vec::new();
+    for ns in std::fs::read_dir(&src)?{
+        let ns = ns?.path();
+        if ns.is_file() {
+            std::fs::create_dir_all(dest.join("logs"))?;
+            let copy = dest.join("logs").join(ns.file_name().unwrap_or_default());
+            std::fs::copy(&ns, &copy)?;
+            files.push(copy);
+            continue;
+        }
+        let target = dest.join("logs").join(ns.file_name().unwrap_or_default());
+        std::fs::create_dir_all(&target)?;
+        for log in std::fs::read_dir(&ns)?{
+            let log = log?.path();
+            let copy = target.join(log.file_name().unwrap_or_default());
+            std::fs::copy(&log, &copy)?;
+            files.push(copy);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Deletes all logs of `topology`.
+pub fn remove(topology: &str) -> Result<()>{
+    let dir = PathBuf::from(LOG_DIR).join(topology);
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
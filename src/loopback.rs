@@ -0,0 +1,52 @@
+//! Loopback of a namespace: a dummy device `<namespace>_lo` with a /32 and
+//! or /128 address that stays reachable whichever links are up. Addresses
+//! are declared or allocated from the `loopbacks` pools of `ipam`.
+//!
+//! The IPv4 address is the router id of the namespace's routing daemon and
+//! is announced passively in OSPF, `auto_routes` add routes to the
+//! loopbacks like to any link subnet.
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::Result;
+use crate::interface;
+use crate::transaction::Resource;
+use crate::{Config, Interface, Namespace};
+
+/// Loopback addresses of a namespace, allocated if neither is set.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct LoopbackSpec{
+    /// IPv4 address, e.g. `10.255.0.1`
+    #[serde(default)]
+    pub address: Option<String>,
+    /// IPv6 address, e.g. `fd00:ff::1`
+    #[serde(default)]
+    pub address6: Option<String>,
+}
+
+/// Name of the loopback device of `namespace`.
+pub fn name(namespace: &str) -> String {
+    interface::name(namespace, "lo")
+}
+
+/// Creates the loopback device of `ns` and addresses it. When
+/// reconciling, an existing device is kept.
+pub fn create(ns: Arc<Namespace>, ip: Option<String>, ip6: Option<String>, config: &Config) -> Result<Arc<Interface>>{
+    let name = name(&ns.name);
+    if !(config.reconcile && ns.has_link(&name)?) {
+        cmd::ip(Some(&ns.netns), &["link", "add", name.as_str(), "type", "dummy"])
+            .map_err(|e| e.context(format!("Failed to create loopback {}", name)))?;
+        config.transaction().record(Resource::Device{ name: name.clone(), netns: ns.netns.clone() });
+    }
+    interface::alias(&name, Some(&ns.netns), &format!("{}_lo", ns.name), config)?;
+    Interface::new(name, Some(ns), ip, ip6, None, config)
+}
+
+/// IPv4 address of the loopback of `namespace`, if it has one.
+pub fn router_id(config: &Config, namespace: &str) -> Option<std::net::Ipv4Addr> {
+    let intf = config.interfaces.get(&name(namespace))?;
+    intf.ip.as_ref()?.split('/').next()?.parse().ok()
+}
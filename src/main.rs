@@ -1,477 +1,2887 @@
-use std::collections::HashMap;
 use anyhow::Error;
-use std::sync::Arc;
 use std::process::Command;
+use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand};
 
-struct Config{
-    namespaces: HashMap<String,Box<Arc<Namespace>>>,
-    links: HashMap<String,Box<Arc<Link>>>,
-    interfaces: HashMap<String,Box<Arc<Interface>>>,
+use router_rs::{alert, api, auth, backup, bench, bond, capture, chaos, churn, clock, cmd, conntrack, daemon, dataplane, distributed, dns, ecmp, environment, experiment, export, fastpath, flap, forwarder, fuzz, generators, gnmi, graph, heal, import, inject, liveness, logs, matrix, mirror, monitor, netns, nftables, offload, ovs, owd, owner, parallel, persona, plan, pool, preflight, process, restart, scale, scenario, shell, show, snapshot, snmp, state, stats, stress, syslog, topology, trace, traffic, transaction, validate, verify, Config, Namespace};
+use router_rs::trace::Traced;
+use router_rs::transaction::Resource;
+
+#[derive(Parser)]
+#[command(name = "router-rs", about = "Build routed network namespace labs")]
+struct Cli{
+    #[command(subcommand)]
+    command: Commands,
+    /// Least severe events logged to stderr, e.g. debug to trace every
+    /// command run; RUST_LOG takes precedence
+    #[arg(long, global = true, default_value = "warn")]
+    log_level: String,
+    /// Log events as one JSON object per line
+    #[arg(long, global = true)]
+    log_json: bool,
+}
+
+#[derive(Subcommand)]
+enum Commands{
+    /// Create a topology from a YAML or TOML file
+    Create{
+        #[arg(short, long)]
+        file: PathBuf,
+        /// Topology name, defaults to the name in the file or its file stem
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Take namespaces and veth pairs from the pool where available
+        #[arg(long)]
+        pool: bool,
+        /// Update an existing topology in place: keep what exists, create
+        /// what is missing and remove what is no longer described
+        #[arg(long)]
+        reconcile: bool,
+        /// Allow changes to the host outside the topology's namespaces:
+        /// kernel modules loaded, host sysctls and host interfaces
+        #[arg(long)]
+        allow_host_changes: bool,
+        /// Skip checking the host before building
+        #[arg(long)]
+        no_preflight: bool,
+        /// Kernel module the host must have on top of those the topology
+        /// needs, e.g. mpls_router
+        #[arg(long)]
+        module: Vec<String>,
+        #[command(flatten)]
+        parallelism: ParallelismArgs,
+    },
+    /// Compare a topology file with the running topology and print what
+    /// applying it would add, remove and change
+    Diff{
+        #[arg(short, long)]
+        file: PathBuf,
+        /// Topology name, defaults to the name in the file or its file stem
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Print the plan as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the plan of a topology file like diff, then update the running
+    /// topology in place, changing only what differs
+    Apply{
+        #[arg(short, long)]
+        file: PathBuf,
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Allow changes to the host outside the topology's namespaces:
+        /// kernel modules loaded, host sysctls and host interfaces
+        #[arg(long)]
+        allow_host_changes: bool,
+        /// Skip checking the host before building
+        #[arg(long)]
+        no_preflight: bool,
+        #[arg(long)]
+        module: Vec<String>,
+        #[command(flatten)]
+        parallelism: ParallelismArgs,
+    },
+    /// Check that the host can build a topology and report every problem
+    /// found: addressing, privileges, kernel support, sysctls and
+    /// conflicting namespaces and interfaces
+    Preflight{
+        #[arg(short, long)]
+        file: PathBuf,
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Check for updating an existing topology in place
+        #[arg(long)]
+        reconcile: bool,
+        #[arg(long)]
+        module: Vec<String>,
+    },
+    /// Create several isolated copies of a topology in parallel, named
+    /// <name>-0 .. <name>-<count-1>
+    Clone{
+        #[arg(short, long)]
+        file: PathBuf,
+        #[arg(short, long)]
+        name: Option<String>,
+        #[arg(short, long)]
+        count: u32,
+        /// Number of addresses every copy is moved up from the previous one
+        #[arg(long, default_value_t = 65536)]
+        shift: u32,
+        /// Copies created at once, defaults to --parallelism
+        #[arg(long)]
+        parallel_copies: Option<usize>,
+        /// Allow changes to the host outside the topology's namespaces:
+        /// kernel modules loaded, host sysctls and host interfaces
+        #[arg(long)]
+        allow_host_changes: bool,
+        #[command(flatten)]
+        parallelism: ParallelismArgs,
+    },
+    /// Scale out a running topology with namespaces wired like an existing
+    /// one or more parallel links, then save the extended description
+    Scale{
+        #[arg(short, long)]
+        file: PathBuf,
+        #[arg(short, long)]
+        name: Option<String>,
+        /// File to save the extended description to, defaults to --file
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        #[command(subcommand)]
+        command: ScaleCommand,
+        /// Allow changes to the host outside the topology's namespaces:
+        /// kernel modules loaded, host sysctls and host interfaces
+        #[arg(long)]
+        allow_host_changes: bool,
+        #[command(flatten)]
+        parallelism: ParallelismArgs,
+    },
+    /// Build or update the part of a multi-host topology placed on this host
+    Agent{
+        #[arg(short, long)]
+        file: PathBuf,
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Name of this host among the topology's hosts
+        #[arg(long)]
+        host: String,
+        /// Allow changes to the host outside the topology's namespaces:
+        /// kernel modules loaded, host sysctls and host interfaces
+        #[arg(long)]
+        allow_host_changes: bool,
+        #[command(flatten)]
+        parallelism: ParallelismArgs,
+    },
+    /// Run the agent on every host of a multi-host topology over ssh
+    Deploy{
+        #[arg(short, long)]
+        file: PathBuf,
+        #[arg(short, long)]
+        name: Option<String>,
+        /// router-rs on the hosts
+        #[arg(long, default_value = "router-rs")]
+        binary: String,
+        /// Destroy the topology on every host instead
+        #[arg(long)]
+        destroy: bool,
+        /// Allow changes to the host outside the topology's namespaces:
+        /// kernel modules loaded, host sysctls and host interfaces
+        #[arg(long)]
+        allow_host_changes: bool,
+    },
+    /// Explore and modify a topology interactively, every change is applied
+    /// at once
+    Shell{
+        name: String,
+        /// Start from this description instead of the running topology
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+        /// Allow changes to the host outside the topology's namespaces:
+        /// kernel modules loaded, host sysctls and host interfaces
+        #[arg(long)]
+        allow_host_changes: bool,
+        #[command(flatten)]
+        parallelism: ParallelismArgs,
+    },
+    /// Delete all namespaces of a topology
+    Destroy{
+        #[arg(required_unless_present = "orphans")]
+        name: Option<String>,
+        /// Return namespaces and pool veth pairs to the pool instead of deleting them
+        #[arg(long, conflicts_with = "orphans")]
+        pool: bool,
+        /// Remove the namespaces and host devices left behind by runs which
+        /// crashed, of the topology given or of all
+        #[arg(long)]
+        orphans: bool,
+        /// With --orphans, print what would be removed only
+        #[arg(long, requires = "orphans")]
+        dry_run: bool,
+    },
+    /// Archive a running topology with its state, assigned addresses and
+    /// daemon configs, to recreate it with restore
+    Backup{
+        #[arg(short, long)]
+        file: PathBuf,
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Archive to write, a .tar.gz
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Dump the namespaces, interfaces, addresses, routes and qdiscs of a
+    /// running topology into a JSON file, to recreate it with restore
+    Snapshot{
+        #[arg(short, long)]
+        file: PathBuf,
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Snapshot file to write
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Recreate a topology from a backup archive or a snapshot, reporting
+    /// what came out different
+    Restore{
+        archive: PathBuf,
+        /// Allow changes to the host outside the topology's namespaces:
+        /// kernel modules loaded, host sysctls and host interfaces
+        #[arg(long)]
+        allow_host_changes: bool,
+        #[command(flatten)]
+        parallelism: ParallelismArgs,
+    },
+    /// Print a topology in another format, e.g. as iproute2 shell script
+    Export{
+        #[arg(short, long)]
+        file: PathBuf,
+        #[arg(short, long)]
+        name: Option<String>,
+        /// iproute2, containerlab or mininet
+        #[arg(long, default_value = "iproute2")]
+        format: export::Format,
+    },
+    /// Draw a topology as Graphviz DOT or Mermaid flowchart
+    Graph{
+        #[arg(short, long)]
+        file: PathBuf,
+        #[arg(short, long)]
+        name: Option<String>,
+        /// dot or mermaid
+        #[arg(long, default_value = "dot")]
+        format: graph::GraphFormat,
+        /// File to write, stdout if not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Describe existing namespaces or a containerlab topology file as a
+    /// topology file
+    Import{
+        /// Name of the topology, namespaces called <name>-<ns> are imported as <ns>
+        name: String,
+        /// Kernel names of the namespaces to import
+        #[arg(required_unless_present = "containerlab")]
+        namespaces: Vec<String>,
+        /// Import this containerlab topology file instead of namespaces
+        #[arg(long, conflicts_with = "namespaces")]
+        containerlab: Option<PathBuf>,
+        /// Write to a .yaml or .toml file instead of printing YAML
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Describe a Clos, ring, mesh or star topology with addressing and
+    /// ECMP routes
+    Generate{
+        /// Name of the topology
+        name: String,
+        #[command(subcommand)]
+        shape: GenerateCommand,
+        /// IPv4 pool the links are allocated subnets from
+        #[arg(long, default_value = generators::DEFAULT_POOL)]
+        pool: String,
+        /// Prefix length of the allocated IPv4 subnets
+        #[arg(long, default_value_t = 31)]
+        prefix: u8,
+        /// IPv6 pool the links are allocated /64 subnets from as well
+        #[arg(long)]
+        pool6: Option<String>,
+        /// Write to a .yaml or .toml file instead of printing YAML
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Manage the pool of pre-provisioned namespaces and veth pairs
+    Pool{
+        #[command(subcommand)]
+        command: PoolCommand,
+    },
+    /// Summarize the namespaces and interfaces of a topology and report
+    /// where the kernel drifted from the state saved at create time
+    Status{
+        name: String,
+    },
+    /// Print the interfaces, addresses and routes of every namespace of a
+    /// topology as the kernel has them, as tables or JSON
+    Show{
+        name: String,
+        /// Print the namespaces as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the interface counters of a topology, with an interval keep
+    /// printing how much they grew in between
+    Stats{
+        name: String,
+        /// Only this namespace
+        #[arg(short, long)]
+        namespace: Option<String>,
+        /// Seconds between polls
+        #[arg(short, long)]
+        interval: Option<u64>,
+        /// Stop after this many polls, default forever with an interval
+        #[arg(short, long)]
+        count: Option<u32>,
+        /// Print every poll as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check the counter assertions of a topology file against how much the
+    /// counters grew while a command ran, e.g. test traffic, or during a
+    /// number of seconds
+    AssertCounters{
+        #[arg(short, long)]
+        file: PathBuf,
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Seconds to wait without a command
+        #[arg(short, long, default_value_t = 10)]
+        duration: u64,
+        /// Print the results as JSON
+        #[arg(long)]
+        json: bool,
+        /// Command run on the host between the two polls
+        #[arg(last = true)]
+        command: Vec<String>,
+    },
+    /// Watch the queues of a topology while a command runs, e.g. test
+    /// traffic, or during a number of seconds, and flag interfaces whose
+    /// backlog, drops or overlimits cross the watermarks of its `alerts`
+    Alerts{
+        #[arg(short, long)]
+        file: PathBuf,
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Milliseconds between polls
+        #[arg(short, long, default_value_t = 1000)]
+        interval: u64,
+        /// Seconds to watch without a command
+        #[arg(short, long, default_value_t = 10)]
+        duration: u64,
+        /// Post alerts to this http:// URL, overriding the topology's
+        #[arg(long)]
+        webhook: Option<String>,
+        /// Print alerts and the summary as JSON, one object per line
+        #[arg(long)]
+        json: bool,
+        /// Command run on the host while the queues are watched
+        #[arg(last = true)]
+        command: Vec<String>,
+    },
+    /// Run a program in a namespace, e.g. exec lab r1 -- ping 10.0.0.2, with
+    /// ROUTER_RS_* variables describing the node
+    Exec{
+        topology: String,
+        namespace: String,
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Run a program in a namespace with skewed monotonic/boottime clocks
+    Clock{
+        topology: String,
+        namespace: String,
+        /// CLOCK_MONOTONIC offset in seconds
+        #[arg(long, default_value_t = 0, allow_hyphen_values = true)]
+        monotonic: i64,
+        /// CLOCK_BOOTTIME offset in seconds
+        #[arg(long, default_value_t = 0, allow_hyphen_values = true)]
+        boottime: i64,
+        /// Write the output to the node log of this name instead of the terminal
+        #[arg(long)]
+        log: Option<String>,
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// List or flush the connection tracking table of a namespace of a
+    /// running topology
+    Conntrack{
+        #[command(subcommand)]
+        command: ConntrackCommand,
+    },
+    /// Manage the OpenFlow rules of an OVS bridge of a running topology
+    Flows{
+        #[command(subcommand)]
+        command: FlowCommand,
+    },
+    /// Turn segmentation, receive and checksum offloads of an interface on
+    /// or off, without either print which are on
+    Offload{
+        topology: String,
+        namespace: String,
+        interface: String,
+        /// tso, gso, gro, tx-checksum or rx-checksum, repeated
+        #[arg(long)]
+        on: Vec<offload::Offload>,
+        #[arg(long)]
+        off: Vec<offload::Offload>,
+    },
+    /// Print which members of the bonds at both ends of a bonded link are
+    /// up and which one is active
+    Bond{
+        topology: String,
+        link: String,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Inject packet drops on an interface
+    Drop{
+        #[command(subcommand)]
+        command: DropCommand,
+    },
+    /// Corrupt a fraction of the frames leaving an interface
+    Corrupt{
+        #[command(subcommand)]
+        command: CorruptCommand,
+    },
+    /// Apply duplication and reordering stress profiles to interfaces
+    Stress{
+        #[command(subcommand)]
+        command: StressCommand,
+    },
+    /// Restart the routing daemon of a namespace while sending traffic
+    /// from src to dst across it and report the impact
+    Restart{
+        topology: String,
+        namespace: String,
+        /// Shell command stopping the daemon
+        #[arg(long)]
+        stop: String,
+        /// Shell command starting the daemon
+        #[arg(long)]
+        start: String,
+        src: String,
+        dst: String,
+        /// Address of dst the probes are sent to
+        address: std::net::IpAddr,
+        /// Milliseconds of traffic before the stop
+        #[arg(long, default_value_t = 1000)]
+        delay: u64,
+        /// Milliseconds between stop and start
+        #[arg(long, default_value_t = 2000)]
+        downtime: u64,
+        /// Let routes withdrawn by the stopping daemon disappear instead of
+        /// keeping them as graceful restart would
+        #[arg(long)]
+        no_preserve: bool,
+        /// Fail if more probes than this are lost
+        #[arg(long, default_value_t = 0)]
+        max_lost: u32,
+        #[arg(long, default_value_t = 9002)]
+        port: u16,
+        #[arg(short, long, default_value_t = 5000)]
+        count: u32,
+        /// Interval between probes in milliseconds
+        #[arg(short, long, default_value_t = 1)]
+        interval: u64,
+    },
+    /// Measure TCP throughput from src to dst through the kernel's
+    /// forwarding path and through the flowtables of the topology
+    Fastpath{
+        #[arg(short, long)]
+        file: PathBuf,
+        #[arg(short, long)]
+        name: Option<String>,
+        src: String,
+        dst: String,
+        /// Address of dst the transfer goes to
+        address: std::net::IpAddr,
+        #[arg(long, default_value_t = 9003)]
+        port: u16,
+        /// Seconds of each transfer
+        #[arg(short, long, default_value_t = 5)]
+        seconds: u64,
+    },
+    /// Send test load of parallel flows from src to dst, e.g. to spread it
+    /// over ECMP paths, and report what each flow delivered and which
+    /// interfaces of src it left through
+    Load{
+        topology: String,
+        src: String,
+        dst: String,
+        /// Address of dst the flows go to
+        address: std::net::IpAddr,
+        #[arg(long, default_value_t = 5201)]
+        port: u16,
+        /// tcp or udp
+        #[arg(short, long, default_value = "tcp")]
+        protocol: traffic::Protocol,
+        #[arg(short, long, default_value_t = 6)]
+        flows: u32,
+        #[arg(short, long, default_value_t = 10)]
+        seconds: u64,
+        /// Mbit/s per UDP flow, as fast as possible if not set
+        #[arg(short, long)]
+        rate: Option<f64>,
+        /// builtin or iperf3
+        #[arg(short, long, default_value = "builtin")]
+        generator: traffic::Generator,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Run flows through a router and report how they spread over the
+    /// nexthops of its multipath route, failing if the spread doesn't
+    /// match its fib_multipath_hash_policy
+    Ecmp{
+        topology: String,
+        /// Namespace with the multipath route
+        router: String,
+        src: String,
+        dst: String,
+        /// Address of dst the flows go to
+        address: std::net::IpAddr,
+        #[arg(long, default_value_t = 5201)]
+        port: u16,
+        /// tcp or udp
+        #[arg(short, long, default_value = "tcp")]
+        protocol: traffic::Protocol,
+        #[arg(short, long, default_value_t = 16)]
+        flows: u32,
+        #[arg(short, long, default_value_t = 5)]
+        seconds: u64,
+        /// Mbit/s per UDP flow, as fast as possible if not set
+        #[arg(short, long)]
+        rate: Option<f64>,
+        /// builtin or iperf3
+        #[arg(short, long, default_value = "builtin")]
+        generator: traffic::Generator,
+        /// Least Jain's fairness index of the nexthops expected from a
+        /// router hashing on ports
+        #[arg(long, default_value_t = 0.9)]
+        min_fairness: f64,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Capture the traffic between two namespaces on every interface it
+    /// passes, into pcap files next to the node logs
+    Capture{
+        topology: String,
+        src: String,
+        dst: String,
+        /// tcp, udp or icmp
+        #[arg(short, long)]
+        protocol: Option<capture::Protocol>,
+        #[arg(long)]
+        port: Option<u16>,
+        /// Seconds to capture, until interrupted by default
+        #[arg(short, long)]
+        duration: Option<u64>,
+        /// Only print the filter and the interfaces on the path
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Mirror an interface of a running topology to another interface of
+    /// its namespace or to a span port in a monitor namespace, removed
+    /// again when done
+    Mirror{
+        topology: String,
+        namespace: String,
+        interface: String,
+        /// Interface of the namespace to send the copies out of
+        #[arg(long, conflicts_with = "monitor", required_unless_present = "monitor")]
+        to: Option<String>,
+        /// Namespace to create the span port in
+        #[arg(short, long)]
+        monitor: Option<String>,
+        /// ingress, egress or both
+        #[arg(long, default_value = "both")]
+        direction: mirror::Direction,
+        /// Seconds to mirror, until interrupted by default
+        #[arg(short, long)]
+        duration: Option<u64>,
+    },
+    /// Withdraw and announce BGP networks of a namespace repeatedly and
+    /// report how fast the other namespaces follow
+    Flap{
+        topology: String,
+        namespace: String,
+        /// Prefixes to flap, each one of the namespace's BGP networks
+        #[arg(required = true)]
+        prefixes: Vec<ipnet::IpNet>,
+        /// Withdraw/announce cycles
+        #[arg(short, long, default_value_t = 5)]
+        count: u32,
+        /// Milliseconds between two changes
+        #[arg(short, long, default_value_t = 2000)]
+        interval: u64,
+        /// Seconds to wait after the last change before comparing routes
+        #[arg(long, default_value_t = 5)]
+        settle: u64,
+        /// Print every change as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Run the timed steps of a scenario file against its topology and
+    /// report how each went
+    Scenario{
+        /// YAML with the topology file and the steps: at (ms) and action,
+        /// up, destroy, fail, restore, loss, clear, check or exec
+        file: PathBuf,
+        /// Store the results of the checks as this run, see experiment
+        #[arg(long)]
+        run: Option<String>,
+        /// Allow up to change the host outside the topology's namespaces
+        #[arg(long)]
+        allow_host_changes: bool,
+        /// Pings or connection attempts per check
+        #[arg(short, long, default_value_t = 3)]
+        count: u32,
+        /// Milliseconds to wait for each reply or connection
+        #[arg(short, long, default_value_t = 1000)]
+        timeout: u64,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Take links down or make them drop packets on a schedule or at
+    /// random and report how routing converges after every change
+    Chaos{
+        #[command(subcommand)]
+        command: ChaosCommand,
+    },
+    /// Probe the links of a topology BFD-style and print them going down
+    /// and coming up
+    Liveness{
+        topology: String,
+        /// Links to probe, all numbered links of the topology by default
+        #[arg(short, long)]
+        link: Vec<String>,
+        /// Milliseconds between two probes
+        #[arg(short, long, default_value_t = 50)]
+        interval: u64,
+        /// Probes missed before a link goes down
+        #[arg(short, long, default_value_t = 3)]
+        multiplier: u32,
+        /// Seconds to probe for, until interrupted if not set
+        #[arg(short, long)]
+        duration: Option<u64>,
+        /// Print the report as JSON at the end instead of events as they
+        /// are seen
+        #[arg(long)]
+        json: bool,
+    },
+    /// Flap links, withdraw static routes and impair links at random from
+    /// a seed and check that routing recovers, or replay a logged run
+    Fuzz{
+        #[command(subcommand)]
+        command: FuzzCommand,
+    },
+    /// Print routes appearing in and disappearing from the namespaces of a
+    /// topology, or wait for a route in one of them
+    Routes{
+        topology: String,
+        /// Namespaces to watch, all of the topology by default
+        #[arg(short, long)]
+        namespace: Vec<String>,
+        /// Wait until the namespace has a route to this prefix and print
+        /// how long it took
+        #[arg(long)]
+        wait: Option<ipnet::IpNet>,
+        /// Wait until the route to --wait is gone instead
+        #[arg(long, requires = "wait")]
+        withdrawn: bool,
+        /// Seconds to wait
+        #[arg(long, default_value_t = 30)]
+        timeout: u64,
+    },
+    /// Keep checking a topology for namespaces, interfaces and daemon
+    /// processes that disappeared until it is destroyed
+    Watch{
+        #[arg(short, long)]
+        file: PathBuf,
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Seconds between checks
+        #[arg(short, long, default_value_t = 5)]
+        interval: u64,
+        /// Rebuild what disappeared from the topology file instead of
+        /// only reporting it
+        #[arg(long)]
+        heal: bool,
+    },
+    /// Check on the routing daemons of a topology
+    Daemon{
+        #[command(subcommand)]
+        command: DaemonCommand,
+    },
+    /// Forward IPv4 in userspace with the software router
+    Dataplane{
+        #[command(subcommand)]
+        command: DataplaneCommand,
+    },
+    /// Serve the names of a topology over DNS inside its namespaces
+    Dns{
+        #[command(subcommand)]
+        command: DnsCommand,
+    },
+    /// Check on the supervised processes of the namespaces of a topology
+    Process{
+        #[command(subcommand)]
+        command: ProcessCommand,
+    },
+    /// Serve the interfaces and routes of every namespace of a topology as
+    /// OpenConfig state over gNMI, the namespace is the target
+    Gnmi{
+        topology: String,
+        #[arg(short, long, default_value_t = std::net::SocketAddr::from(([127, 0, 0, 1], gnmi::PORT)))]
+        listen: std::net::SocketAddr,
+    },
+    /// Serve a gRPC API to list, create, update, destroy and verify
+    /// topologies until interrupted
+    Api{
+        #[arg(short, long, default_value_t = std::net::SocketAddr::from(([127, 0, 0, 1], api::PORT)))]
+        listen: std::net::SocketAddr,
+        /// YAML file of the tokens clients authenticate with and the
+        /// topologies each may see or change, open to anyone if not given
+        #[arg(long)]
+        auth: Option<PathBuf>,
+        /// Allow changes to the host outside the topology's namespaces:
+        /// kernel modules loaded, host sysctls and host interfaces
+        #[arg(long)]
+        allow_host_changes: bool,
+        #[command(flatten)]
+        parallelism: ParallelismArgs,
+    },
+    /// Answer SNMP v1 and v2c requests for the ifTable and ipRouteTable on
+    /// port 161 in every namespace of a topology until it is destroyed
+    Snmp{
+        topology: String,
+        #[arg(short, long, default_value = "public")]
+        community: String,
+        /// only serve these namespaces
+        #[arg(short, long)]
+        namespace: Vec<String>,
+    },
+    /// Gather syslog messages sent to 127.0.0.1:514 in any namespace and
+    /// the node logs of a topology into one file until it is destroyed,
+    /// waiting for it to be created
+    Syslog{
+        topology: String,
+        /// Collector file, <log dir>/<topology>/syslog.log by default
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Work with the logs of processes run in a topology
+    Logs{
+        #[command(subcommand)]
+        command: LogsCommand,
+    },
+    /// Save and restore the nftables rulesets of a topology
+    Nft{
+        #[command(subcommand)]
+        command: NftCommand,
+    },
+    /// Measure path characteristics between namespaces
+    Measure{
+        #[command(subcommand)]
+        command: MeasureCommand,
+    },
+    /// Run traffic personas from a host namespace against a server in
+    /// another one: web browsing, bulk transfers and voip calls
+    Traffic{
+        topology: String,
+        src: String,
+        dst: String,
+        /// Address of dst the traffic goes to
+        address: std::net::IpAddr,
+        /// web, bulk or voip, repeat to run several at once
+        #[arg(short, long, default_value = "web")]
+        persona: Vec<persona::Persona>,
+        /// Sessions of every persona instead of their own
+        #[arg(long)]
+        sessions: Option<u32>,
+        /// DSCP of every persona instead of their own
+        #[arg(long)]
+        dscp: Option<u8>,
+        /// Seconds to run
+        #[arg(short, long, default_value_t = 10)]
+        duration: u64,
+        #[arg(long, default_value_t = 9100)]
+        port: u16,
+        /// Seed of object sizes and think times
+        #[arg(long, default_value_t = 1)]
+        seed: u64,
+        /// Print the reports as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Work with experiment results
+    Experiment{
+        #[command(subcommand)]
+        command: ExperimentCommand,
+    },
+    /// Run the connectivity checks of a topology file against the running
+    /// topology, fails if any check fails
+    Verify{
+        #[arg(short, long)]
+        file: PathBuf,
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Pings or connection attempts per check
+        #[arg(short, long, default_value_t = 3)]
+        count: u32,
+        /// Milliseconds to wait for each reply or connection
+        #[arg(short, long, default_value_t = 1000)]
+        timeout: u64,
+        /// Percentage of pings or connections allowed to fail
+        #[arg(long, default_value_t = 0.0)]
+        max_loss: f64,
+        /// Send unfragmentable packets as large as the MTU of the source's
+        /// outgoing interface instead of pinging
+        #[arg(long)]
+        mtu: bool,
+        /// Search the path MTU to every target instead of pinging and fail
+        /// where it is below the MTU of the source's outgoing interface
+        #[arg(long)]
+        pmtu: bool,
+        /// Print the results as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum GenerateCommand{
+    /// Spines each linked to every leaf, hosts as stubs of the leaves
+    Clos{
+        #[arg(long, default_value_t = 2)]
+        spines: u32,
+        #[arg(long, default_value_t = 4)]
+        leaves: u32,
+        /// Hosts per leaf
+        #[arg(long, default_value_t = 0)]
+        hosts: u32,
+    },
+    /// Routers each linked to the next one
+    Ring{
+        nodes: u32,
+    },
+    /// Routers linked to each other
+    Mesh{
+        nodes: u32,
+    },
+    /// Routers linked to a hub router
+    Star{
+        spokes: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum ScaleCommand{
+    /// Add namespaces copied from an existing one, with copies of its links
+    Namespaces{
+        like: String,
+        #[arg(short, long, default_value_t = 1)]
+        count: u32,
+    },
+    /// Add links parallel to those between two namespaces
+    Links{
+        a: String,
+        b: String,
+        #[arg(short, long, default_value_t = 1)]
+        count: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum PoolCommand{
+    /// Top the pool up to the given number of free namespaces and veth pairs
+    Fill{
+        #[arg(long, default_value_t = 0)]
+        namespaces: usize,
+        #[arg(long, default_value_t = 0)]
+        veths: usize,
+    },
+    /// Print the number of free namespaces and veth pairs
+    Status,
+    /// Delete everything left in the pool
+    Drain,
+}
+
+#[derive(Subcommand)]
+enum ConntrackCommand{
+    /// Print the tracked flows, those matching all filters given
+    List{
+        topology: String,
+        namespace: String,
+        /// tcp, udp, icmp, ...
+        #[arg(long)]
+        protocol: Option<String>,
+        /// Source address of the flow as sent
+        #[arg(long)]
+        src: Option<std::net::IpAddr>,
+        /// Destination address of the flow as sent
+        #[arg(long)]
+        dst: Option<std::net::IpAddr>,
+        #[arg(long)]
+        dport: Option<u16>,
+        /// TCP state, e.g. ESTABLISHED
+        #[arg(long)]
+        state: Option<String>,
+        /// Print the flows as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Remove every tracked flow
+    Flush{
+        topology: String,
+        namespace: String,
+    },
 }
 
+#[derive(Subcommand)]
+enum FlowCommand{
+    /// Add flows in ovs-ofctl add-flow syntax
+    Add{
+        topology: String,
+        bridge: String,
+        #[arg(required = true)]
+        flows: Vec<String>,
+    },
+    /// Delete the flows matching a filter, all without one
+    Del{
+        topology: String,
+        bridge: String,
+        filter: Option<String>,
+    },
+    /// Print the flows with their counters
+    Dump{
+        topology: String,
+        bridge: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DropCommand{
+    /// Start dropping packets: all, random:<n> (1 in n) or every:<n>
+    Add{
+        topology: String,
+        namespace: String,
+        interface: String,
+        direction: inject::Direction,
+        mode: inject::DropMode,
+    },
+    /// Print the packets seen and dropped by the injection
+    Stats{
+        topology: String,
+        namespace: String,
+        interface: String,
+        direction: inject::Direction,
+    },
+    /// Stop dropping packets
+    Del{
+        topology: String,
+        namespace: String,
+        interface: String,
+        direction: inject::Direction,
+    },
+}
+
+#[derive(Subcommand)]
+enum ChaosCommand{
+    /// Run the events of a YAML schedule
+    Run{
+        topology: String,
+        /// List of events: at (ms), link, action (down, up, loss or clear),
+        /// percent for loss and duration (ms) after which it is undone
+        #[arg(short, long)]
+        file: PathBuf,
+        /// Milliseconds routes are watched after the last change
+        #[arg(long, default_value_t = 5000)]
+        settle: u64,
+        /// Apply changes less than this many milliseconds apart together,
+        /// links only change if they end up in another state
+        #[arg(long, default_value_t = 0)]
+        debounce: u64,
+        /// Probe the links of the schedule every this many milliseconds
+        /// and time how long failures take to be detected
+        #[arg(long)]
+        liveness: Option<u64>,
+        /// Probes missed before a link counts as down
+        #[arg(long, default_value_t = 3, requires = "liveness")]
+        multiplier: u32,
+        /// Print the timeline as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Fail random links, the same ones again for the same seed
+    Random{
+        topology: String,
+        /// Links to pick from, all links of the topology by default
+        #[arg(short, long)]
+        link: Vec<String>,
+        #[arg(short, long, default_value_t = 10)]
+        count: u32,
+        /// Milliseconds between two events
+        #[arg(short, long, default_value_t = 5000)]
+        interval: u64,
+        /// Milliseconds a link stays down or lossy
+        #[arg(short, long, default_value_t = 2000)]
+        duration: u64,
+        /// Make every other event on average drop this many percent of the
+        /// packets instead of taking the link down
+        #[arg(long)]
+        loss: Option<f64>,
+        /// Seed of the random choices, random by default
+        #[arg(long)]
+        seed: Option<u64>,
+        #[arg(long, default_value_t = 5000)]
+        settle: u64,
+        #[arg(long, default_value_t = 0)]
+        debounce: u64,
+        #[arg(long)]
+        liveness: Option<u64>,
+        #[arg(long, default_value_t = 3, requires = "liveness")]
+        multiplier: u32,
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum FuzzCommand{
+    /// Generate faults from a seed, log them and run them
+    Run{
+        topology: String,
+        /// Seed of the random choices, random by default
+        #[arg(long)]
+        seed: Option<u64>,
+        /// How often and how hard faults hit: low, medium or high
+        #[arg(short, long, default_value = "medium")]
+        intensity: fuzz::Intensity,
+        #[arg(short, long, default_value_t = 20)]
+        count: u32,
+        /// File the faults are logged to before the first one, defaults to
+        /// fuzz-<topology>-<seed>.yaml
+        #[arg(long)]
+        log: Option<PathBuf>,
+        /// Milliseconds routing may take to recover after the last fault
+        #[arg(long, default_value_t = 10000)]
+        settle: u64,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Run the faults logged by a previous run again
+    Replay{
+        log: PathBuf,
+        #[arg(long, default_value_t = 10000)]
+        settle: u64,
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum CorruptCommand{
+    /// Start corrupting frames
+    Add{
+        topology: String,
+        namespace: String,
+        interface: String,
+        /// Percentage of frames to corrupt
+        percent: f64,
+        /// Percentage by which a corruption depends on the previous one
+        #[arg(long, default_value_t = 0.0)]
+        correlation: f64,
+    },
+    /// Stop corrupting frames
+    Del{
+        topology: String,
+        namespace: String,
+        interface: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum StressCommand{
+    /// Apply a profile (light, heavy, extreme) to the egress of <namespace>:<interface>s
+    Apply{
+        topology: String,
+        profile: stress::Profile,
+        #[arg(required = true)]
+        interfaces: Vec<String>,
+    },
+    /// Remove stress profiles from <namespace>:<interface>s
+    Clear{
+        topology: String,
+        #[arg(required = true)]
+        interfaces: Vec<String>,
+    },
+    /// Send numbered datagrams and check the duplicates and reordering the
+    /// receiving application sees
+    Verify{
+        topology: String,
+        src: String,
+        dst: String,
+        /// Address of dst the datagrams are sent to
+        address: std::net::IpAddr,
+        /// Fail unless the effects of this profile are visible
+        #[arg(long)]
+        profile: Option<stress::Profile>,
+        #[arg(long, default_value_t = 9001)]
+        port: u16,
+        #[arg(short, long, default_value_t = 1000)]
+        count: u32,
+        /// Interval between datagrams in milliseconds
+        #[arg(short, long, default_value_t = 1)]
+        interval: u64,
+    },
+    /// Create and destroy a topology over and over and check that every
+    /// teardown leaves no namespaces, devices, routes or state behind
+    NsChurn{
+        /// Topology to churn, a ring of --nodes routers if not given
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+        /// Name the topology is created under
+        #[arg(short, long, default_value = "churn")]
+        name: String,
+        #[arg(long, default_value_t = 4)]
+        nodes: u32,
+        #[arg(short, long, default_value_t = 10)]
+        cycles: u32,
+        /// Cycles started per second at most
+        #[arg(short, long, default_value_t = 1.0)]
+        rate: f64,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+        #[command(flatten)]
+        parallelism: ParallelismArgs,
+    },
+    /// Create and destroy a topology of --namespaces namespaces and --links
+    /// links and report how long each phase of the build took
+    Bench{
+        /// Name the topology is created under
+        #[arg(short, long, default_value = "bench")]
+        name: String,
+        #[arg(long, default_value_t = 100)]
+        namespaces: u32,
+        #[arg(long, default_value_t = 1000)]
+        links: u32,
+        /// Set up every link with its own ip commands instead of one batch
+        /// per namespace
+        #[arg(long)]
+        no_batch: bool,
+        /// Fail if creating the topology took longer, in seconds
+        #[arg(long)]
+        max_create: Option<f64>,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+        #[command(flatten)]
+        parallelism: ParallelismArgs,
+    },
+}
 
-impl Config{
-    fn new() -> Config {
-        Config{
-            namespaces: HashMap::new(),
-            links: HashMap::new(),
-            interfaces: HashMap::new(),
+#[derive(Subcommand)]
+enum DaemonCommand{
+    /// List the daemon, forwarder and supervised processes of every
+    /// namespace and whether they run
+    Status{
+        topology: String,
+    },
+    /// Restart daemon, forwarder and process supervisors which died
+    Supervise{
+        topology: String,
+    },
+    /// List the BGP sessions of every namespace, fails unless all of them
+    /// are established
+    Bgp{
+        topology: String,
+        /// Seconds to wait for sessions to come up
+        #[arg(short, long, default_value_t = 0)]
+        wait: u64,
+    },
+}
+
+#[derive(Subcommand)]
+enum DataplaneCommand{
+    /// Forward between interfaces of the namespace it runs in, what
+    /// forwarders without a command run
+    Run{
+        /// Interfaces, comma separated
+        ports: String,
+        /// tap or af_xdp
+        #[arg(long, default_value = "tap")]
+        io: dataplane::Io,
+        /// Seconds between counter reports
+        #[arg(short, long, default_value_t = 10)]
+        interval: u64,
+    },
+    /// Run flows through a router of a running topology forwarded by its
+    /// kernel, then by the software router over each kind of I/O
+    Bench{
+        topology: String,
+        router: String,
+        src: String,
+        dst: String,
+        /// Address of dst the flows go to
+        address: std::net::IpAddr,
+        #[arg(long, default_value_t = 5201)]
+        port: u16,
+        /// tcp or udp
+        #[arg(short, long, default_value = "tcp")]
+        protocol: traffic::Protocol,
+        #[arg(short, long, default_value_t = 4)]
+        flows: u32,
+        #[arg(short, long, default_value_t = 5)]
+        seconds: u64,
+        /// Mbit/s per UDP flow, as fast as possible if not set
+        #[arg(short, long)]
+        rate: Option<f64>,
+        /// Kinds of I/O to compare, all this build has by default
+        #[arg(long, value_delimiter = ',')]
+        io: Vec<dataplane::Io>,
+        /// Interfaces of the router the software router takes over, all
+        /// by default
+        #[arg(long, value_delimiter = ',')]
+        ports: Vec<String>,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum DnsCommand{
+    /// Answer for <namespace>.<zone> and <interface>.<namespace>.<zone> on
+    /// 127.0.0.53 in every namespace until the topology is destroyed
+    Serve{
+        topology: String,
+        #[arg(long, default_value = "lab")]
+        zone: String,
+        /// Answer AAAA queries for names with only IPv4 addresses with
+        /// these mapped into this NAT64 prefix
+        #[arg(long)]
+        dns64: Option<ipnet::Ipv6Net>,
+    },
+    /// Answer on port 53 of the namespace it runs in until killed, the
+    /// server run in namespaces with `dns`
+    Respond{
+        topology: String,
+        #[arg(long, default_value = "lab")]
+        zone: String,
+        #[arg(long)]
+        dns64: Option<ipnet::Ipv6Net>,
+    },
+    /// Print the names served for a topology
+    Records{
+        topology: String,
+        #[arg(long, default_value = "lab")]
+        zone: String,
+        #[arg(long)]
+        dns64: Option<ipnet::Ipv6Net>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ProcessCommand{
+    /// List the processes of a topology with their pids, restarts and how
+    /// they exited
+    List{
+        topology: String,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Run a program until it exits for good under the restart policy,
+    /// the supervisor of processes of the description
+    Run{
+        /// Runtime directory for the pid file and exit status
+        #[arg(long)]
+        dir: PathBuf,
+        /// never, on-failure or always
+        #[arg(long, default_value = "never")]
+        restart: process::RestartPolicy,
+        name: String,
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum LogsCommand{
+    /// Copy all logs of a topology to runs/<run>/logs/
+    Collect{
+        topology: String,
+        run: String,
+        /// Delete the logs once copied
+        #[arg(long)]
+        remove: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum NftCommand{
+    /// Write the ruleset of every namespace to <dir>/<namespace>.nft
+    Save{
+        topology: String,
+        dir: PathBuf,
+    },
+    /// Replace the rulesets of the namespaces with those saved in <dir>
+    Restore{
+        topology: String,
+        dir: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum MeasureCommand{
+    /// One-way delay and delay variation from kernel timestamps
+    Owd{
+        topology: String,
+        src: String,
+        dst: String,
+        /// Address of dst the probes are sent to
+        address: std::net::IpAddr,
+        #[arg(long, default_value_t = 9000)]
+        port: u16,
+        #[arg(short, long, default_value_t = 100)]
+        count: u32,
+        /// Interval between probes in milliseconds
+        #[arg(short, long, default_value_t = 10)]
+        interval: u64,
+    },
+    /// One-way delay and delay variation between every two namespaces
+    Matrix{
+        topology: String,
+        /// Namespaces to probe between, all by default
+        namespaces: Vec<String>,
+        #[arg(long, default_value_t = 9000)]
+        port: u16,
+        /// Probes per pair
+        #[arg(short, long, default_value_t = 20)]
+        count: u32,
+        /// Interval between probes in milliseconds
+        #[arg(short, long, default_value_t = 10)]
+        interval: u64,
+        /// text, json or csv
+        #[arg(short, long, default_value = "text")]
+        format: matrix::MatrixFormat,
+    },
+}
+
+/// Threads used by the phases of a build, see `parallel`.
+#[derive(Args)]
+struct ParallelismArgs{
+    /// Threads per phase, defaults to the number of CPUs
+    #[arg(long)]
+    parallelism: Option<usize>,
+    /// Namespaces set up at once, defaults to --parallelism
+    #[arg(long)]
+    parallel_namespaces: Option<usize>,
+    /// Routes installed at once, defaults to --parallelism
+    #[arg(long)]
+    parallel_routes: Option<usize>,
+}
+
+impl ParallelismArgs{
+    fn parallelism(&self) -> parallel::Parallelism {
+        let mut p = match self.parallelism{
+            Some(n) => parallel::Parallelism::new(n),
+            None => parallel::Parallelism::default(),
+        };
+        if let Some(n) = self.parallel_namespaces{
+            p.namespaces = n.max(1);
+        }
+        if let Some(n) = self.parallel_routes{
+            p.routes = n.max(1);
         }
+        p
     }
 }
 
-struct Link{
-    name: String,
-    subnet: String,
+#[derive(Subcommand)]
+enum ExperimentCommand{
+    /// Diff two runs and report regressions of run_b against run_a
+    Compare{
+        run_a: String,
+        run_b: String,
+    },
 }
 
-impl Link {
-    fn new(name: String, subnet: String, config: &mut Config) -> anyhow::Result<Arc<Link>> {
-        if let Some(r) = config.links.get(&name){
-            return Err(anyhow::anyhow!("RouterLink {} already exists", r.name));
-        }
-        let r = Arc::new(Link{
-            name: name.clone(),
-            subnet,
-        });
-        config.links.insert(name, Box::new(r.clone()));
-        Ok(r.clone())
-    }
-    fn attach(&self, ns1: Arc<Namespace>, ns2: Arc<Namespace>, config: &mut Config) -> anyhow::Result<(Arc<Interface>,Arc<Interface>)>{
-        let name1 = format!("{}_{}", ns1.name.clone(), self.name);
-        let name2 = format!("{}_{}", ns2.name.clone(), self.name);
-        let veth = Veth{
-            name: name1.clone(),
-            peer: name2.clone(),
-        };
-        veth.create()?;
+/// Builds the topology in `file`, checking the host first with the extra
+/// kernel modules in `preflight` unless None.
+fn create(file: PathBuf, name: Option<String>, pool: bool, reconcile: bool, host_changes: bool, preflight: Option<Vec<String>>, parallelism: parallel::Parallelism) -> Result<(), Error>{
+    let mut topology = topology::Topology::from_file(&file)?;
+    if let Some(name) = name{
+        topology.name = name;
+    }
+    let mut config = Config::new(topology.name.clone());
+    config.pool = pool;
+    config.parallelism = parallelism;
+    config.host_changes = host_changes;
+    config.preflight = preflight.is_some();
+    config.modules = preflight.unwrap_or_default();
+    if reconcile {
+        topology.reconcile_with(config)?;
+    } else {
+        topology.apply_with(config)?;
+    }
+    for m in verify::mtu_mismatches(&topology)?{
+        eprintln!("warning: {}", m);
+    }
+    Ok(())
+}
 
+fn diff(file: PathBuf, name: Option<String>, json: bool) -> Result<(), Error>{
+    let mut topology = topology::Topology::from_file(&file)?;
+    if let Some(name) = name{
+        topology.name = name;
+    }
+    let plan = plan::Plan::new(&topology)?;
+    match json{
+        true => println!("{}", serde_json::to_string_pretty(&plan)?),
+        false => println!("{}", plan),
+    }
+    Ok(())
+}
 
-        let sn: ipnet::IpNet = self.subnet.parse()?;
-        let pl = sn.prefix_len();
-        let sn_v4: std::net::Ipv4Addr = sn.addr().to_string().parse()?;
-        let sn_v4_octets = u32::from_be_bytes(sn_v4.octets());
-        let ip1 = sn_v4_octets + 1;
-        let ip2 = sn_v4_octets + 2;
-        let ip1 = format!("{}/{}", std::net::Ipv4Addr::from(ip1.to_be_bytes()).to_string(), pl);
-        let ip2 = format!("{}/{}", std::net::Ipv4Addr::from(ip2.to_be_bytes()).to_string(), pl);
-        let i1 = Interface::new(name1.clone(), Some(ns1.clone()), Some(ip1.clone()), Some(3000), config)?;
-        let i2 = Interface::new(name2.clone(), Some(ns2.clone()), Some(ip2.clone()), Some(3000), config)?;
+fn apply(file: PathBuf, name: Option<String>, host_changes: bool, preflight: Option<Vec<String>>, parallelism: parallel::Parallelism) -> Result<(), Error>{
+    let mut topology = topology::Topology::from_file(&file)?;
+    if let Some(name) = name{
+        topology.name = name;
+    }
+    let plan = plan::Plan::new(&topology)?;
+    println!("{}", plan);
+    if plan.exists && plan.is_empty() {
+        return Ok(());
+    }
+    let mut config = Config::new(topology.name.clone());
+    config.parallelism = parallelism;
+    config.host_changes = host_changes;
+    config.preflight = preflight.is_some();
+    config.modules = preflight.unwrap_or_default();
+    topology.reconcile_with(config)?;
+    for m in verify::mtu_mismatches(&topology)?{
+        eprintln!("warning: {}", m);
+    }
+    Ok(())
+}
 
-        Ok((i1,i2))
+fn check_host(file: PathBuf, name: Option<String>, reconcile: bool, modules: &[String]) -> Result<(), Error>{
+    let mut topology = topology::Topology::from_file(&file)?;
+    if let Some(name) = name{
+        topology.name = name;
+    }
+    validate::run(&topology)?;
+    let changes = preflight::host_changes(&topology, reconcile);
+    if !changes.is_empty() {
+        println!("{} changes the host outside its namespaces, needs --allow-host-changes to:", topology.name);
+        for c in changes{
+            println!("  {}", c);
+        }
     }
-    
+    preflight::run(&topology, reconcile, modules)?;
+    println!("{} can be built", topology.name);
+    Ok(())
 }
 
+fn clone(file: PathBuf, name: Option<String>, count: u32, shift: u32, host_changes: bool, parallelism: parallel::Parallelism) -> Result<(), Error>{
+    let mut base = topology::Topology::from_file(&file)?;
+    if let Some(name) = name{
+        base.name = name;
+    }
+    let mut copies = Vec::new();
+    for index in 0..count{
+        let copy = base.stamp(index, shift)?;
+        if !Namespace::list(&copy.name)?.is_empty() {
+            return Err(anyhow::anyhow!("Topology {} already exists", copy.name));
+        }
+        copies.push(copy);
+    }
+    let results = parallel::map(&copies, parallelism.copies, |copy| {
+        let mut config = Config::new(copy.name.clone());
+        config.parallelism = parallelism;
+        config.host_changes = host_changes;
+        copy.apply_with(config).map(|_| ()).map_err(|e| anyhow::anyhow!("{}: {}", copy.name, e))
+    });
+    let errors: Vec<String> = results.into_iter().filter_map(|r| r.err()).map(|e| e.to_string()).collect();
+    if !errors.is_empty() {
+        return Err(anyhow::anyhow!("Failed to clone {}: {}", base.name, errors.join("; ")));
+    }
+    Ok(())
+}
 
-struct Route{
-    dst: String,
-    gateway: Vec<Arc<Interface>>,
+fn agent(file: PathBuf, name: Option<String>, host: &str, host_changes: bool, parallelism: parallel::Parallelism) -> Result<(), Error>{
+    let mut topology = topology::Topology::from_file(&file)?;
+    if let Some(name) = name{
+        topology.name = name;
+    }
+    let mut config = Config::new(topology.name.clone());
+    config.parallelism = parallelism;
+    config.host_changes = host_changes;
+    distributed::apply(&topology, host, config)?;
+    Ok(())
 }
 
-struct Interface{
-    name: String,
-    ip: Option<String>,
-    namespace: Option<Arc<Namespace>>,
-    mtu: Option<u32>,
+fn deploy(file: PathBuf, name: Option<String>, binary: &str, destroy: bool, host_changes: bool) -> Result<(), Error>{
+    let mut topology = topology::Topology::from_file(&file)?;
+    if let Some(name) = name{
+        topology.name = name;
+    }
+    Ok(distributed::deploy(&topology, &file, binary, destroy, host_changes)?)
 }
 
-impl Interface {
-    fn new(name: String, namespace: Option<Arc<Namespace>>, ip: Option<String>, mtu: Option<u32>, config: &mut Config) -> anyhow::Result<Arc<Interface>> {
-        if let Some(r) = config.interfaces.get(&name){
-            return Err(anyhow::anyhow!("Interface {} already exists", r.name));
+fn destroy(name: &str, pool: bool) -> Result<(), Error>{
+    if !pool {
+        return Ok(topology::Topology::destroy(name)?);
+    }
+    let namespaces = state::namespaces(name)?;
+    if namespaces.is_empty() {
+        return Err(anyhow::anyhow!("Topology {} not found", name));
+    }
+    let mut resources: Vec<Resource> = daemon::dirs(name)?.into_iter()
+        .map(|dir| Resource::Daemon{ dir })
+        .collect();
+    resources.extend(state::State::load(name)?.into_iter().flat_map(|s| s.nics).map(Resource::Moved));
+    for ns in namespaces{
+        dns::unconfigure(&ns)?;
+        resources.push(Resource::Namespace{ netns: ns, pooled: true });
+    }
+    transaction::undo_all(resources)?;
+    daemon::stop_topology(name)?;
+    Ok(state::State::remove(name)?)
+}
+
+fn destroy_orphans(name: Option<&str>, dry_run: bool) -> Result<(), Error>{
+    let orphans = owner::orphans(name)?;
+    if dry_run {
+        for o in &orphans{
+            println!("{}", o);
         }
-        let mut i = Interface{
-            name: name.clone(),
-            ip,
-            namespace,
-            mtu,
-        };
-        if let Some(namespace) = i.namespace.clone(){
-            i.attach(namespace)?;
+        return Ok(());
+    }
+    let failed = owner::remove(&orphans);
+    for o in orphans.iter().filter(|o| !failed.iter().any(|(f, _)| f.to_string() == o.to_string())){
+        println!("removed {}", o);
+    }
+    for (o, e) in &failed{
+        eprintln!("failed to remove {}: {}", o, e);
+    }
+    if !failed.is_empty() {
+        return Err(anyhow::anyhow!("{} of {} orphans not removed", failed.len(), orphans.len()));
+    }
+    Ok(())
+}
+
+fn interactive(name: String, file: Option<PathBuf>, host_changes: bool, parallelism: parallel::Parallelism) -> Result<(), Error>{
+    let topology = match file{
+        Some(file) => {
+            let mut topology = topology::Topology::from_file(&file)?;
+            topology.name = name;
+            topology
+        },
+        None => {
+            let namespaces = state::namespaces(&name)?;
+            if namespaces.is_empty() {
+                topology::Topology{ name, ..Default::default() }
+            } else {
+                let imported = import::import(&name, &namespaces)?;
+                for w in &imported.warnings{
+                    eprintln!("warning: {}", w);
+                }
+                imported.topology
+            }
+        },
+    };
+    let mut shell = shell::Shell::new(topology, parallelism);
+    shell.host_changes = host_changes;
+    Ok(shell.run()?)
+}
+
+fn backup(file: PathBuf, name: Option<String>, output: PathBuf) -> Result<(), Error>{
+    let mut topology = topology::Topology::from_file(&file)?;
+    if let Some(name) = name{
+        topology.name = name;
+    }
+    backup::backup(&topology, &output)?;
+    println!("Archived {} to {}", topology.name, output.display());
+    Ok(())
+}
+
+fn snapshot(file: PathBuf, name: Option<String>, output: PathBuf) -> Result<(), Error>{
+    let mut topology = topology::Topology::from_file(&file)?;
+    if let Some(name) = name{
+        topology.name = name;
+    }
+    snapshot::snapshot(&topology, &output)?;
+    println!("Saved snapshot of {} to {}", topology.name, output.display());
+    Ok(())
+}
+
+fn restore(archive: PathBuf, host_changes: bool, parallelism: parallel::Parallelism) -> Result<(), Error>{
+    let snapshot = if snapshot::is_snapshot(&archive) { Some(snapshot::load(&archive)?) } else { None };
+    let name = match &snapshot{
+        Some(s) => s.topology.name.clone(),
+        None => backup::name(&archive)?,
+    };
+    let mut config = Config::new(name.clone());
+    config.parallelism = parallelism;
+    config.host_changes = host_changes;
+    let differences = match &snapshot{
+        Some(s) => snapshot::restore(s, config)?,
+        None => backup::restore(&archive, config)?,
+    };
+    for d in &differences{
+        eprintln!("warning: {}", d);
+    }
+    println!("Restored {} from {}", name, archive.display());
+    Ok(())
+}
+
+fn export(file: PathBuf, name: Option<String>, format: export::Format) -> Result<(), Error>{
+    let mut topology = topology::Topology::from_file(&file)?;
+    if let Some(name) = name{
+        topology.name = name;
+    }
+    print!("{}", export::export(&topology, format)?);
+    Ok(())
+}
+
+fn draw(file: PathBuf, name: Option<String>, format: graph::GraphFormat, output: Option<PathBuf>) -> Result<(), Error>{
+    let mut topology = topology::Topology::from_file(&file)?;
+    if let Some(name) = name{
+        topology.name = name;
+    }
+    let rendered = graph::render(&topology, format)?;
+    match output{
+        Some(path) => std::fs::write(&path, rendered)
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))?,
+        None => print!("{}", rendered),
+    }
+    Ok(())
+}
+
+fn scale_out(file: PathBuf, name: Option<String>, output: Option<PathBuf>, command: ScaleCommand, host_changes: bool, parallelism: parallel::Parallelism) -> Result<(), Error>{
+    let mut topology = topology::Topology::from_file(&file)?;
+    let added = match command{
+        ScaleCommand::Namespaces{ like, count } => scale::add_namespaces(&mut topology, &like, count)?,
+        ScaleCommand::Links{ a, b, count } => scale::add_links(&mut topology, &a, &b, count)?,
+    };
+    let mut live = topology.clone();
+    if let Some(name) = name{
+        live.name = name;
+    }
+    if Namespace::list(&live.name)?.is_empty() {
+        return Err(anyhow::anyhow!("Topology {} does not exist", live.name));
+    }
+    let mut config = Config::new(live.name.clone());
+    config.parallelism = parallelism;
+    config.host_changes = host_changes;
+    live.reconcile_with(config)?;
+    let path = output.unwrap_or(file);
+    let data = match path.extension().and_then(|e| e.to_str()){
+        Some("toml") => toml::to_string(&topology)?,
+        _ => serde_yaml::to_string(&topology)?,
+    };
+    std::fs::write(&path, data)
+        .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))?;
+    println!("added {}", added.join(", "));
+    Ok(())
+}
+
+fn import(name: &str, namespaces: &[String], containerlab: Option<PathBuf>, output: Option<PathBuf>) -> Result<(), Error>{
+    let imported = match containerlab{
+        Some(path) => {
+            let data = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+            import::containerlab(name, &data)?
+        },
+        None => import::import(name, namespaces)?,
+    };
+    for w in &imported.warnings{
+        eprintln!("warning: {}", w);
+    }
+    match output{
+        Some(path) => {
+            let data = match path.extension().and_then(|e| e.to_str()){
+                Some("toml") => toml::to_string(&imported.topology)?,
+                _ => serde_yaml::to_string(&imported.topology)?,
+            };
+            std::fs::write(&path, data)?;
+        },
+        None => print!("{}", serde_yaml::to_string(&imported.topology)?),
+    }
+    Ok(())
+}
+
+fn generate(name: &str, shape: GenerateCommand, pool: &str, prefix: u8, pool6: Option<String>, output: Option<PathBuf>) -> Result<(), Error>{
+    let mut builder = match shape{
+        GenerateCommand::Clos{ spines, leaves, hosts } => generators::clos(name, spines, leaves, hosts)?,
+        GenerateCommand::Ring{ nodes } => generators::ring(name, nodes)?,
+        GenerateCommand::Mesh{ nodes } => generators::mesh(name, nodes)?,
+        GenerateCommand::Star{ spokes } => generators::star(name, spokes)?,
+    };
+    builder = builder.ipam(pool, prefix);
+    if let Some(pool6) = pool6{
+        builder = builder.ipam(&pool6, 64);
+    }
+    let topology = builder.build()?;
+    match output{
+        Some(path) => {
+            let data = match path.extension().and_then(|e| e.to_str()){
+                Some("toml") => toml::to_string(&topology)?,
+                _ => serde_yaml::to_string(&topology)?,
+            };
+            std::fs::write(&path, data)?;
+        },
+        None => print!("{}", serde_yaml::to_string(&topology)?),
+    }
+    Ok(())
+}
+
+fn pool(command: PoolCommand) -> Result<(), Error>{
+    match command{
+        PoolCommand::Fill{ namespaces, veths } => Ok(pool::fill(namespaces, veths)?),
+        PoolCommand::Status => {
+            let status = pool::status()?;
+            println!("namespaces {} veths {}", status.namespaces, status.veths);
+            Ok(())
+        },
+        PoolCommand::Drain => Ok(pool::drain()?),
+    }
+}
+
+fn status(name: &str) -> Result<(), Error>{
+    let saved = state::State::load(name)?;
+    let namespaces = state::namespaces(name)?;
+    if namespaces.is_empty() && saved.is_none() {
+        return Err(anyhow::anyhow!("Topology {} not found", name));
+    }
+    println!("{:<24} {:>10} {:>6}", "NAMESPACE", "INTERFACES", "UP");
+    for ns in namespaces{
+        let links = cmd::ip_json(Some(&ns), &["-j", "link", "show"])?;
+        let links = links.as_array().cloned().unwrap_or_default();
+        let links: Vec<_> = links.iter().filter(|l| l["ifname"] != "lo").collect();
+        let up = links.iter().filter(|l| l["operstate"] == "UP").count();
+        println!("{:<24} {:>10} {:>6}", ns, links.len(), up);
+    }
+    let saved = match saved{
+        Some(saved) => saved,
+        None => {
+            println!("no saved state, drift not checked");
+            return Ok(());
+        },
+    };
+    let drift = saved.drift()?;
+    for d in &drift{
+        println!("drift: {}", d);
+    }
+    if !drift.is_empty() {
+        return Err(anyhow::anyhow!("Topology {} drifted from its saved state in {} places", name, drift.len()));
+    }
+    Ok(())
+}
+
+fn show(name: &str, json: bool) -> Result<(), Error>{
+    let view = show::TopologyView::read(name)?;
+    match json{
+        true => println!("{}", serde_json::to_string_pretty(&view)?),
+        false => println!("{}", view),
+    }
+    Ok(())
+}
+
+fn interface_stats(name: &str, namespace: Option<String>, interval: Option<u64>, count: Option<u32>, json: bool) -> Result<(), Error>{
+    let mut namespaces = state::namespaces(name)?;
+    if namespaces.is_empty() {
+        return Err(anyhow::anyhow!("Topology {} not found", name));
+    }
+    if let Some(namespace) = namespace{
+        let netns = Namespace::netns_name(name, &namespace);
+        if !namespaces.contains(&netns) {
+            return Err(anyhow::anyhow!("Namespace {} not found in {}", namespace, name));
         }
-        if let Some(ip) = i.ip.clone(){
-            i.set_ip(ip)?;
+        namespaces = vec![netns];
+    }
+    let mut poller = stats::Poller::new(namespaces);
+    let polls = match (interval, count){
+        (None, _) => 1,
+        // the first poll only sets the baseline
+        (Some(_), Some(count)) => count.saturating_add(1),
+        (Some(_), None) => u32::MAX,
+    };
+    for n in 0..polls{
+        if n > 0 {
+            std::thread::sleep(std::time::Duration::from_secs(interval.unwrap_or(0)));
         }
-        if let Some(mtu) = i.mtu.clone(){
-            i.set_mtu(mtu)?;
+        let samples = poller.poll()?;
+        if interval.is_some() && n == 0 {
+            continue;
         }
-        i.set_up()?;
-        let r = Arc::new(i);
-        config.interfaces.insert(name, Box::new(r.clone()));
-        Ok(r.clone())
-    }
-    fn attach(&self, namespace: Arc<Namespace>) -> anyhow::Result<()>{
-            let output = Command::new("ip")
-            .arg("link")
-            .arg("set")
-            .arg(self.name.as_str())
-            .arg("netns")
-            .arg(namespace.name.as_str())
-            .output()?;
-        if !output.status.success() {
-            return Err(anyhow::anyhow!("Failed to attach interface to namespace: {}", String::from_utf8_lossy(&output.stderr)));
+        if json {
+            println!("{}", serde_json::to_string(&samples)?);
+            continue;
         }
-        Ok(())
+        print_stats(&samples);
     }
-    fn set_ip(&mut self, ip: String) -> anyhow::Result<()>{
-        match &self.namespace{
-            Some(namespace) => {
-                let output = Command::new("ip")
-                    .arg("netns")
-                    .arg("exec")
-                    .arg(namespace.name.as_str())
-                    .arg("ip")
-                    .arg("addr")
-                    .arg("add")
-                    .arg(ip.as_str())
-                    .arg("dev")
-                    .arg(self.name.as_str())
-                    .output()?;
-                if !output.status.success() {
-                    return Err(anyhow::anyhow!("Failed to set ip: {}", String::from_utf8_lossy(&output.stderr)));
-                }
-            },
-            None => {
-                let output = Command::new("ip")
-                    .arg("addr")
-                    .arg("add")
-                    .arg(ip.as_str())
-                    .arg("dev")
-                    .arg(self.name.as_str())
-                    .output()?;
-                if !output.status.success() {
-                    return Err(anyhow::anyhow!("Failed to set ip: {}", String::from_utf8_lossy(&output.stderr)));
-                }
+    Ok(())
+}
+
+/// Counters per namespace, deltas once there was a previous poll. The share
+/// of sent packets among the interfaces of a namespace shows how ECMP
+/// spreads the traffic.
+fn print_stats(samples: &[stats::Sample]){
+    let mut netns = None;
+    for s in samples{
+        if netns != Some(&s.netns) {
+            netns = Some(&s.netns);
+            match s.interval{
+                Some(interval) => println!("{} (last {:.1}s):", s.netns, interval.as_secs_f64()),
+                None => println!("{}:", s.netns),
             }
         }
+        let sent: u64 = samples.iter().filter(|o| o.netns == s.netns).map(|o| o.delta.tx_packets).sum();
+        let share = if sent > 0 { 100.0 * s.delta.tx_packets as f64 / sent as f64 } else { 0.0 };
+        println!("  {:<16} rx {:>10} pkts {:>14} bytes {:>6} drop {:>6} err  tx {:>10} pkts {:>14} bytes {:>6} drop {:>6} err  {:>5.1}%",
+            s.interface,
+            s.delta.rx_packets, s.delta.rx_bytes, s.delta.rx_dropped, s.delta.rx_errors,
+            s.delta.tx_packets, s.delta.tx_bytes, s.delta.tx_dropped, s.delta.tx_errors,
+            share,
+        );
+    }
+}
 
-        self.ip = Some(ip);
-        Ok(())
+/// Checks the counter assertions of a topology against a command or a wait.
+fn assert_counters(file: PathBuf, name: Option<String>, duration: u64, json: bool, command: &[String]) -> Result<(), Error>{
+    let mut topology = topology::Topology::from_file(&file)?;
+    if let Some(name) = name{
+        topology.name = name;
     }
-    fn set_mtu(&mut self, mtu: u32) -> anyhow::Result<()>{
-        match &self.namespace{
-            Some(namespace) => {
-                let output = Command::new("ip")
-                    .arg("netns")
-                    .arg("exec")
-                    .arg(namespace.name.as_str())
-                    .arg("ip")
-                    .arg("link")
-                    .arg("set")
-                    .arg("dev")
-                    .arg(self.name.as_str())
-                    .arg("mtu")
-                    .arg(mtu.to_string().as_str())
-                    .output()?;
-                if !output.status.success() {
-                    return Err(anyhow::anyhow!("Failed to set mtu: {}", String::from_utf8_lossy(&output.stderr)));
-                }
-            },
-            None => {
-                let output = Command::new("ip")
-                    .arg("link")
-                    .arg("set")
-                    .arg("dev")
-                    .arg(self.name.as_str())
-                    .arg("mtu")
-                    .arg(mtu.to_string().as_str())
-                    .output()?;
-                if !output.status.success() {
-                    return Err(anyhow::anyhow!("Failed to set mtu: {}", String::from_utf8_lossy(&output.stderr)));
-                }
-            }   
+    if topology.counters.is_empty() {
+        return Err(anyhow::anyhow!("Topology {} declares no counter assertions", topology.name));
+    }
+    for a in &topology.counters{
+        a.check()?;
+    }
+    let namespaces: Vec<String> = topology.counters.iter().map(|a| Namespace::netns_name(&topology.name, &a.namespace)).collect();
+    let mut poller = stats::Poller::new(namespaces);
+    poller.poll()?;
+    match command.split_first(){
+        Some((program, args)) => {
+            let status = Command::new(program).args(args).traced_status()
+                .map_err(|e| anyhow::anyhow!("Failed to run {}: {}", program, e))?;
+            if !status.success() {
+                return Err(anyhow::anyhow!("{} exited with {}", program, status));
+            }
+        },
+        None => std::thread::sleep(std::time::Duration::from_secs(duration)),
+    }
+    let samples = poller.poll()?;
+    let mut results = Vec::new();
+    for a in &topology.counters{
+        results.extend(a.evaluate(&topology.name, &samples)?);
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    } else {
+        for r in &results{
+            println!("{}", r);
         }
-        self.mtu = Some(mtu);
-        Ok(())
     }
-    fn set_up(&mut self) -> anyhow::Result<()>{
-        match &self.namespace{
-            Some(namespace) => {
-                let output = Command::new("ip")
-                    .arg("netns")
-                    .arg("exec")
-                    .arg(namespace.name.as_str())
-                    .arg("ip")
-                    .arg("link")
-                    .arg("set")
-                    .arg("dev")
-                    .arg(self.name.as_str())
-                    .arg("up")
-                    .output()?;
-                if !output.status.success() {
-                    return Err(anyhow::anyhow!("Failed to set up: {}", String::from_utf8_lossy(&output.stderr)));
-                }
-            },
-            None => {
-                let output = Command::new("ip")
-                    .arg("link")
-                    .arg("set")
-                    .arg("dev")
-                    .arg(self.name.as_str())
-                    .arg("up")
-                    .output()?;
-                if !output.status.success() {
-                    return Err(anyhow::anyhow!("Failed to set up: {}", String::from_utf8_lossy(&output.stderr)));
-                }
+    let failed = results.iter().filter(|r| !r.passed()).count();
+    if failed > 0 {
+        return Err(anyhow::anyhow!("{} of {} counter assertions failed", failed, results.len()));
+    }
+    Ok(())
+}
+
+fn alerts(file: PathBuf, name: Option<String>, interval: u64, duration: u64, webhook: Option<String>, json: bool, command: &[String]) -> Result<(), Error>{
+    let mut topology = topology::Topology::from_file(&file)?;
+    if let Some(name) = name{
+        topology.name = name;
+    }
+    let mut spec = topology.alerts.clone()
+        .ok_or_else(|| anyhow::anyhow!("Topology {} declares no alerts", topology.name))?;
+    if webhook.is_some() {
+        spec.webhook = webhook;
+    }
+    let namespaces = state::namespaces(&topology.name)?;
+    if namespaces.is_empty() {
+        return Err(anyhow::anyhow!("Topology {} not found", topology.name));
+    }
+    let mut watch = alert::AlertWatch::new(&topology.name, spec, namespaces)?;
+    let print = |alerts: Vec<alert::Alert>| -> Result<(), Error>{
+        for a in alerts{
+            match json{
+                true => println!("{}", serde_json::to_string(&a)?),
+                false => println!("{}", a),
             }
         }
         Ok(())
+    };
+    print(watch.poll()?)?;
+    let mut child = match command.split_first(){
+        Some((program, args)) => Some(Command::new(program).args(args).traced_spawn()
+            .map_err(|e| anyhow::anyhow!("Failed to run {}: {}", program, e))?),
+        None => None,
+    };
+    let end = std::time::Instant::now() + std::time::Duration::from_secs(duration);
+    let status = loop{
+        std::thread::sleep(std::time::Duration::from_millis(interval));
+        print(watch.poll()?)?;
+        match &mut child{
+            Some(c) => if let Some(status) = c.try_wait()? {
+                break Some(status);
+            },
+            None => if std::time::Instant::now() >= end {
+                break None;
+            },
+        }
+    };
+    let flagged = watch.flagged();
+    if json {
+        println!("{}", serde_json::to_string(&flagged)?);
+    } else {
+        for f in &flagged{
+            println!("{}", f);
+        }
+    }
+    if let (Some(status), Some(program)) = (status, command.first()) {
+        if !status.success() {
+            return Err(anyhow::anyhow!("{} exited with {}", program, status));
+        }
     }
+    if !flagged.is_empty() {
+        return Err(anyhow::anyhow!("{} congestion points flagged", flagged.len()));
+    }
+    Ok(())
 }
 
+/// Runs the program with the terminal attached and exits with its code.
+fn exec(topology: &str, namespace: &str, command: &[String]) -> Result<(), Error>{
+    let netns = Namespace::netns_name(topology, namespace);
+    if !std::path::Path::new("/run/netns").join(&netns).exists() {
+        return Err(anyhow::anyhow!("Namespace {} not found in {}", namespace, topology));
+    }
+    let mut cmd = netns::command(&netns, &command[0]);
+    environment::apply(&mut cmd, topology, namespace)?;
+    let status = cmd.args(&command[1..]).traced_status()
+        .map_err(|e| anyhow::anyhow!("Failed to run {} in {}: {}", command[0], netns, e))?;
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(1));
+    }
+    Ok(())
+}
 
+fn clock(topology: &str, namespace: &str, skew: clock::ClockSkew, log: Option<String>, command: &[String]) -> Result<(), Error>{
+    skew.check()?;
+    let netns = Namespace::netns_name(topology, namespace);
+    let mut cmd = skew.command(&netns, &command[0]);
+    environment::apply(&mut cmd, topology, namespace)?;
+    cmd.args(&command[1..]);
+    if let Some(name) = log{
+        let log = logs::open(topology, &netns, &name)?;
+        cmd.stdout(log.try_clone()?).stderr(log);
+    }
+    let status = cmd.traced_status()?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("{} exited with {}", command[0], status));
+    }
+    Ok(())
+}
 
-struct Veth{
-    name: String,
-    peer: String,
+fn conntrack(command: ConntrackCommand) -> Result<(), Error>{
+    match command{
+        ConntrackCommand::List{ topology, namespace, protocol, src, dst, dport, state, json } => {
+            let filter = conntrack::FlowFilter{ protocol, src, dst, dport, state };
+            let flows: Vec<conntrack::Flow> = conntrack::list(&Namespace::netns_name(&topology, &namespace))?
+                .into_iter()
+                .filter(|f| filter.matches(f))
+                .collect();
+            match json{
+                true => println!("{}", serde_json::to_string_pretty(&flows)?),
+                false => {
+                    for f in &flows{
+                        println!("{}", f);
+                    }
+                },
+            }
+            Ok(())
+        },
+        ConntrackCommand::Flush{ topology, namespace } => Ok(conntrack::flush(&Namespace::netns_name(&topology, &namespace))?),
+    }
 }
 
-impl Veth{
-    fn create(&self) -> anyhow::Result<()>{
-        let output = Command::new("ip")
-            .arg("link")
-            .arg("add")
-            .arg(self.name.as_str())
-            .arg("type")
-            .arg("veth")
-            .arg("peer")
-            .arg("name")
-            .arg(self.peer.as_str())
-            .output()?;
-        if !output.status.success() {
-            return Err(anyhow::anyhow!("Failed to create veth: {}", String::from_utf8_lossy(&output.stderr)));
-        }
-        Ok(())
+fn flows(command: FlowCommand) -> Result<(), Error>{
+    match command{
+        FlowCommand::Add{ topology, bridge, flows } => Ok(ovs::Switch::find(&topology, &bridge)?.add_flows(&flows)?),
+        FlowCommand::Del{ topology, bridge, filter } => Ok(ovs::Switch::find(&topology, &bridge)?.del_flows(filter.as_deref())?),
+        FlowCommand::Dump{ topology, bridge } => {
+            print!("{}", ovs::Switch::find(&topology, &bridge)?.dump_flows()?);
+            Ok(())
+        },
     }
 }
 
-struct Namespace{
-    name: String,
+fn drop_injection(command: DropCommand) -> Result<(), Error>{
+    match command{
+        DropCommand::Add{ topology, namespace, interface, direction, mode } => {
+            let injection = inject::DropInjection::new(Namespace::netns_name(&topology, &namespace), interface, direction);
+            Ok(injection.apply(mode)?)
+        },
+        DropCommand::Stats{ topology, namespace, interface, direction } => {
+            let injection = inject::DropInjection::new(Namespace::netns_name(&topology, &namespace), interface, direction);
+            let c = injection.counters()?;
+            println!("packets {} bytes {} dropped {}", c.packets, c.bytes, c.dropped);
+            Ok(())
+        },
+        DropCommand::Del{ topology, namespace, interface, direction } => {
+            let injection = inject::DropInjection::new(Namespace::netns_name(&topology, &namespace), interface, direction);
+            Ok(injection.remove()?)
+        },
+    }
 }
 
-impl Namespace {
-    fn new(name: String, ecmp: bool, config: &mut Config) -> anyhow::Result<Arc<Namespace>> {
-        if let Some(r) = config.namespaces.get(&name){
-            return Err(anyhow::anyhow!("Namespace {} already exists", r.name));
+fn offloads(topology: &str, namespace: &str, interface: &str, on: &[offload::Offload], off: &[offload::Offload]) -> Result<(), Error>{
+    let netns = Namespace::netns_name(topology, namespace);
+    if !std::path::Path::new("/run/netns").join(&netns).exists() {
+        return Err(anyhow::anyhow!("Namespace {} not found in {}", namespace, topology));
+    }
+    if on.is_empty() && off.is_empty() {
+        for (offload, on) in offload::get(&netns, interface)?{
+            println!("{:<12} {}", offload.to_string(), if on { "on" } else { "off" });
         }
-        let n= Namespace{
-            name: name.clone(),
-        };
-        let n = Arc::new(n);
-        if let Err(e) = n.create(){
-            return Err(anyhow::anyhow!("Failed to create network namespace: {}", e));
+        return Ok(());
+    }
+    let settings: Vec<_> = off.iter().map(|o| (*o, false)).chain(on.iter().map(|o| (*o, true))).collect();
+    Ok(offload::set(&netns, interface, &settings)?)
+}
+
+fn bonds(topology: &str, link: &str, json: bool) -> Result<(), Error>{
+    let bonds = bond::link_status(topology, link)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&bonds)?);
+    } else {
+        for b in &bonds{
+            println!("{}", b);
         }
-        n.enable_routing()?;
-        if ecmp {
-            n.enable_ecmp()?;
+    }
+    Ok(())
+}
+
+fn corrupt(command: CorruptCommand) -> Result<(), Error>{
+    match command{
+        CorruptCommand::Add{ topology, namespace, interface, percent, correlation } => {
+            let injection = inject::CorruptInjection::new(Namespace::netns_name(&topology, &namespace), interface);
+            Ok(injection.apply(percent, correlation)?)
+        },
+        CorruptCommand::Del{ topology, namespace, interface } => {
+            let injection = inject::CorruptInjection::new(Namespace::netns_name(&topology, &namespace), interface);
+            Ok(injection.remove()?)
+        },
+    }
+}
+
+/// Splits `<namespace>:<interface>`.
+fn ns_interface(s: &str) -> Result<(&str, &str), Error>{
+    s.split_once(':').ok_or_else(|| anyhow::anyhow!("Invalid interface {}, expected <namespace>:<interface>", s))
+}
+
+fn stress(command: StressCommand) -> Result<(), Error>{
+    match command{
+        StressCommand::Apply{ topology, profile, interfaces } => {
+            for i in &interfaces{
+                let (ns, intf) = ns_interface(i)?;
+                profile.apply(&Namespace::netns_name(&topology, ns), intf)?;
+            }
+            Ok(())
+        },
+        StressCommand::Clear{ topology, interfaces } => {
+            for i in &interfaces{
+                let (ns, intf) = ns_interface(i)?;
+                stress::clear(&Namespace::netns_name(&topology, ns), intf)?;
+            }
+            Ok(())
+        },
+        StressCommand::Verify{ topology, src, dst, address, profile, port, count, interval } => {
+            let probe = stress::SequenceProbe{
+                src: Namespace::netns_name(&topology, &src),
+                dst: Namespace::netns_name(&topology, &dst),
+                target: std::net::SocketAddr::new(address, port),
+                count,
+                interval: std::time::Duration::from_millis(interval),
+            };
+            let report = probe.run()?;
+            println!("{}", report);
+            if let Some(profile) = profile{
+                profile.verify(&report)?;
+            }
+            Ok(())
+        },
+        StressCommand::NsChurn{ file, name, nodes, cycles, rate, json, parallelism } => {
+            let mut topology = match file{
+                Some(file) => topology::Topology::from_file(&file)?,
+                None => generators::ring(&name, nodes)?.build()?,
+            };
+            topology.name = name;
+            let report = churn::ChurnRun{ topology, cycles, rate, parallelism: parallelism.parallelism() }.run()?;
+            match json{
+                true => println!("{}", serde_json::to_string_pretty(&report)?),
+                false => println!("{}", report),
+            }
+            if report.leaks() > 0 {
+                return Err(anyhow::anyhow!("{} leaks after {} cycles", report.leaks(), report.cycles.len()));
+            }
+            Ok(())
+        },
+        StressCommand::Bench{ name, namespaces, links, no_batch, max_create, json, parallelism } => {
+            let report = bench::BenchRun{ name, namespaces, links, batch: !no_batch, parallelism: parallelism.parallelism() }.run()?;
+            match json{
+                true => println!("{}", serde_json::to_string_pretty(&report)?),
+                false => println!("{}", report),
+            }
+            if let Some(max) = max_create{
+                if report.create.as_secs_f64() > max {
+                    return Err(anyhow::anyhow!("Creating {} took {:.1}s, more than {}s", report.topology, report.create.as_secs_f64(), max));
+                }
+            }
+            Ok(())
+        },
+    }
+}
+
+fn restart(gr: restart::GracefulRestart, probe: stress::SequenceProbe, max_lost: u32) -> Result<(), Error>{
+    let report = gr.run(probe)?;
+    println!("{}", report);
+    Ok(report.verify(max_lost)?)
+}
+
+fn fastpath(file: PathBuf, name: Option<String>, src: &str, dst: &str, target: std::net::SocketAddr, seconds: u64) -> Result<(), Error>{
+    let mut topology = topology::Topology::from_file(&file)?;
+    if let Some(name) = name{
+        topology.name = name;
+    }
+    let probe = fastpath::Throughput{
+        src: Namespace::netns_name(&topology.name, src),
+        dst: Namespace::netns_name(&topology.name, dst),
+        target,
+        duration: std::time::Duration::from_secs(seconds),
+    };
+    println!("{}", fastpath::compare(&topology, &probe)?);
+    Ok(())
+}
+
+fn watch(file: PathBuf, name: Option<String>, interval: u64, heal: bool) -> Result<(), Error>{
+    let mut topology = topology::Topology::from_file(&file)?;
+    if let Some(name) = name{
+        topology.name = name;
+    }
+    let healer = heal::Healer{
+        topology,
+        interval: std::time::Duration::from_secs(interval),
+        heal,
+    };
+    healer.run(|failures| {
+        for f in failures{
+            eprintln!("{}", f);
         }
-        config.namespaces.insert(name.clone(), Box::new(n.clone()));
-        Ok(n.clone())
-    }
-    fn enable_ecmp(&self) -> anyhow::Result<()>{
-        let output = Command::new("ip")
-            .arg("netns")
-            .arg("exec")
-            .arg(self.name.as_str())
-            .arg("sysctl")
-            .arg("-w")
-            .arg("net.ipv4.fib_multipath_hash_policy=1")
-        .output()?;
-        if !output.status.success() {
-            return Err(anyhow::anyhow!("Failed to enable ecmp: {}", String::from_utf8_lossy(&output.stderr)));
+        if heal {
+            eprintln!("repaired {}", healer.topology.name);
         }
-        Ok(())
+    })?;
+    Ok(())
+}
+
+fn capture(topology: &str, flow: capture::Flow, duration: Option<u64>, dry_run: bool) -> Result<(), Error>{
+    let state = state::State::load(topology)?
+        .ok_or_else(|| anyhow::anyhow!("Topology {} not found", topology))?;
+    let filter = flow.filter(&state)?;
+    let hops = flow.path(&state)?;
+    println!("filter: {}", filter);
+    for hop in &hops{
+        println!("  {} {}", hop.netns, hop.interface);
+    }
+    if dry_run {
+        return Ok(());
+    }
+    let capture = capture::Capture::start(topology, &hops, &filter)?;
+    match duration{
+        Some(secs) => std::thread::sleep(std::time::Duration::from_secs(secs)),
+        // Ctrl-C reaches tcpdump as well, which then flushes its files
+        None => loop{
+            std::thread::sleep(std::time::Duration::from_secs(3600));
+        },
     }
+    for (_, path) in capture.stop()?{
+        println!("{}", path.display());
+    }
+    Ok(())
+}
 
-    fn enable_routing(&self) -> anyhow::Result<()>{
-        let output = Command::new("ip")
-            .arg("netns")
-            .arg("exec")
-            .arg(self.name.as_str())
-            .arg("sysctl")
-            .arg("-w")
-            .arg("net.ipv4.ip_forward=1")
-        .output()?;
-        if !output.status.success() {
-            return Err(anyhow::anyhow!("Failed to enable routing: {}", String::from_utf8_lossy(&output.stderr)));
+fn mirror(topology: &str, spec: mirror::MirrorSpec, duration: Option<u64>) -> Result<(), Error>{
+    let state = state::State::load(topology)?
+        .ok_or_else(|| anyhow::anyhow!("Topology {} not found", topology))?;
+    for ns in std::iter::once(&spec.namespace).chain(spec.monitor.iter()){
+        if !state.namespaces.iter().any(|n| n.name == *ns) {
+            return Err(anyhow::anyhow!("Namespace {} not found in topology {}", ns, topology));
         }
-        Ok(())
     }
+    let m = mirror::Mirror::new(topology, spec)?;
+    // the filters outlive the process, so wait for the signal instead of
+    // dying of it
+    let signals = unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        set
+    };
+    if let Err(e) = m.start() {
+        let _ = m.stop();
+        return Err(e.into());
+    }
+    match (&m.spec.monitor, m.spec.span()){
+        (Some(monitor), Some((_, remote))) => println!("mirroring {} of {} to {} in {}", m.spec.interface, m.spec.namespace, remote, monitor),
+        _ => println!("mirroring {} of {} to {}", m.spec.interface, m.spec.namespace, m.spec.target()),
+    }
+    match duration{
+        Some(secs) => {
+            let timeout = libc::timespec{ tv_sec: secs as libc::time_t, tv_nsec: 0 };
+            unsafe { libc::sigtimedwait(&signals, std::ptr::null_mut(), &timeout) };
+        },
+        None => {
+            let mut signal = 0;
+            unsafe { libc::sigwait(&signals, &mut signal) };
+        },
+    }
+    m.stop()?;
+    Ok(())
+}
 
-    fn create(&self) -> anyhow::Result<()>{
-        let output = Command::new("ip")
-            .arg("netns")
-            .arg("add")
-            .arg(self.name.as_str())
-            .output()?;
-        if !output.status.success() {
-            return Err(anyhow::anyhow!("Failed to create namespace: {}", String::from_utf8_lossy(&output.stderr)));
+fn flap(scenario: flap::FlapScenario, json: bool) -> Result<(), Error>{
+    let report = scenario.run()?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report);
+    }
+    Ok(())
+}
+
+fn run_chaos(command: ChaosCommand) -> Result<(), Error>{
+    let (run, seed, json) = match command{
+        ChaosCommand::Run{ topology, file, settle, debounce, liveness, multiplier, json } => {
+            let data = std::fs::read_to_string(&file)
+                .map_err(|e| anyhow::anyhow!("Failed to read schedule {}: {}", file.display(), e))?;
+            let events = serde_yaml::from_str(&data)
+                .map_err(|e| anyhow::anyhow!("Failed to parse schedule {}: {}", file.display(), e))?;
+            (chaos::ChaosRun{
+                topology,
+                events,
+                settle: std::time::Duration::from_millis(settle),
+                debounce: std::time::Duration::from_millis(debounce),
+                liveness: liveness_options(liveness, multiplier),
+            }, None, json)
+        },
+        ChaosCommand::Random{ topology, link, count, interval, duration, loss, seed, settle, debounce, liveness, multiplier, json } => {
+            let links = if link.is_empty() {
+                state::State::load(&topology)?
+                    .ok_or_else(|| anyhow::anyhow!("Topology {} not found", topology))?
+                    .links.into_iter().map(|l| l.name).collect()
+            } else {
+                link
+            };
+            let seed = seed.unwrap_or_else(rand::random);
+            let random = chaos::RandomChaos{
+                links,
+                events: count,
+                interval: std::time::Duration::from_millis(interval),
+                duration: std::time::Duration::from_millis(duration),
+                loss,
+                seed,
+            };
+            (chaos::ChaosRun{
+                topology,
+                events: random.schedule()?,
+                settle: std::time::Duration::from_millis(settle),
+                debounce: std::time::Duration::from_millis(debounce),
+                liveness: liveness_options(liveness, multiplier),
+            }, Some(seed), json)
+        },
+    };
+    let mut report = run.run()?;
+    report.seed = seed;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report);
+    }
+    Ok(())
+}
+
+fn liveness_options(interval: Option<u64>, multiplier: u32) -> Option<liveness::LivenessOptions> {
+    interval.map(|ms| liveness::LivenessOptions{
+        interval: std::time::Duration::from_millis(ms),
+        multiplier,
+    })
+}
+
+fn run_scenario(file: PathBuf, run: Option<String>, host_changes: bool, options: verify::VerifyOptions, json: bool) -> Result<(), Error>{
+    let scenario = scenario::Scenario::from_file(&file)?;
+    let report = scenario::ScenarioRun{ scenario, host_changes, options }.run()?;
+    match json{
+        true => println!("{}", serde_json::to_string_pretty(&report)?),
+        false => println!("{}", report),
+    }
+    if let Some(run) = run{
+        let path = report.result().save(&run)?;
+        eprintln!("saved {}", path.display());
+    }
+    if !report.passed() {
+        return Err(anyhow::anyhow!("Scenario {} failed", report.scenario));
+    }
+    Ok(())
+}
+
+fn probe_liveness(topology: String, links: Vec<String>, options: liveness::LivenessOptions, duration: Option<u64>, json: bool) -> Result<(), Error>{
+    let state = state::State::load(&topology)?
+        .ok_or_else(|| anyhow::anyhow!("Topology {} not found", topology))?;
+    let prober = liveness::LivenessProber::start(&state, &links, options)?;
+    let until = duration.map(|s| std::time::Instant::now() + std::time::Duration::from_secs(s));
+    if !json {
+        let report = liveness::LivenessReport{ links: prober.links().to_vec(), events: Vec::new() };
+        println!("{}", report);
+    }
+    let mut printed = 0;
+    while until.is_none_or(|until| std::time::Instant::now() < until) {
+        std::thread::sleep(options.interval);
+        if !json {
+            let events = prober.events();
+            for e in &events[printed..]{
+                println!("{}", e);
+            }
+            printed = events.len();
+        }
+    }
+    let report = prober.stop();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for e in &report.events[printed..]{
+            println!("{}", e);
         }
-        Ok(())
     }
-    fn add_route(&self, route: Route) -> anyhow::Result<()>{
-        {
-            let mut args = vec![
-                "netns",
-                "exec",
-                self.name.as_str(),
-                "ip",
-                "route",
-                "add",
-                route.dst.as_str(),
-            ];
-            for intf in &route.gateway{
-                let ip = if let Some(ip) = &intf.ip{
-                    let ip_vec: Vec<&str> = ip.split("/").collect();
-                    ip_vec[0]
+    Ok(())
+}
+
+fn run_fuzz(command: FuzzCommand) -> Result<(), Error>{
+    let (plan, log, settle, json) = match command{
+        FuzzCommand::Run{ topology, seed, intensity, count, log, settle, json } => {
+            let state = state::State::load(&topology)?
+                .ok_or_else(|| anyhow::anyhow!("Topology {} not found", topology))?;
+            let seed = seed.unwrap_or_else(rand::random);
+            let plan = fuzz::FuzzPlan::generate(&topology, &fuzz::Targets::of(&state)?, seed, intensity, count)?;
+            let log = log.unwrap_or_else(|| PathBuf::from(format!("fuzz-{}-{}.yaml", topology, seed)));
+            plan.save(&log)?;
+            (plan, log, settle, json)
+        },
+        FuzzCommand::Replay{ log, settle, json } => (fuzz::FuzzPlan::load(&log)?, log, settle, json),
+    };
+    let report = fuzz::FuzzRun{ plan, settle: std::time::Duration::from_millis(settle) }.run()?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report);
+    }
+    if !report.failures.is_empty() {
+        return Err(anyhow::anyhow!("Routing did not recover, replay with: router-rs fuzz replay {}", log.display()));
+    }
+    Ok(())
+}
+
+fn monitor_routes(topology: String, namespace: Vec<String>, wait: Option<ipnet::IpNet>, withdrawn: bool, timeout: u64) -> Result<(), Error>{
+    let namespaces = if namespace.is_empty() {
+        state::namespaces(&topology)?
+    } else {
+        namespace.iter().map(|n| Namespace::netns_name(&topology, n)).collect()
+    };
+    if namespaces.is_empty() {
+        return Err(anyhow::anyhow!("Topology {} not found", topology));
+    }
+    let mut monitor = monitor::RouteMonitor::start(&namespaces)?;
+    let start = std::time::Instant::now();
+    let timeout = std::time::Duration::from_secs(timeout);
+    if let Some(prefix) = wait {
+        let [netns] = namespaces.as_slice() else {
+            return Err(anyhow::anyhow!("--wait needs exactly one namespace"));
+        };
+        let at = if withdrawn {
+            monitor.wait_for_withdrawal(netns, prefix, timeout)?
+        } else {
+            monitor.wait_for_route(netns, prefix, timeout)?
+        };
+        println!("{} {} after {:.1} ms", prefix, if withdrawn { "withdrawn" } else { "present" },
+            at.saturating_duration_since(start).as_secs_f64() * 1000.0);
+        return Ok(());
+    }
+    loop{
+        if let Some(event) = monitor.next(timeout)? {
+            println!("{:>9.3}s {}", event.at.saturating_duration_since(start).as_secs_f64(), event);
+        }
+    }
+}
+
+fn run_dataplane(command: DataplaneCommand) -> Result<(), Error>{
+    match command{
+        DataplaneCommand::Run{ ports, io, interval } => {
+            let ports: Vec<String> = ports.split(',').filter(|p| !p.is_empty()).map(|p| p.to_string()).collect();
+            Ok(dataplane::Dataplane::open(&ports, io)?.run(std::time::Duration::from_secs(interval), |counters| println!("{}", counters))?)
+        },
+        DataplaneCommand::Bench{ topology, router, src, dst, address, port, protocol, flows, seconds, rate, io, ports, json } => {
+            let benchmark = dataplane::Benchmark{
+                router: Namespace::netns_name(&topology, &router),
+                ports,
+                ios: if io.is_empty() { dataplane::Io::available() } else { io },
+                traffic: traffic::Traffic{
+                    src: Namespace::netns_name(&topology, &src),
+                    dst: Namespace::netns_name(&topology, &dst),
+                    target: std::net::SocketAddr::new(address, port),
+                    protocol,
+                    flows,
+                    duration: std::time::Duration::from_secs(seconds),
+                    rate,
+                    generator: traffic::Generator::Builtin,
+                },
+            };
+            let report = benchmark.run()?;
+            match json{
+                true => println!("{}", serde_json::to_string_pretty(&report)?),
+                false => println!("{}", report),
+            }
+            Ok(())
+        },
+    }
+}
+
+fn routing_daemon(command: DaemonCommand) -> Result<(), Error>{
+    match command{
+        DaemonCommand::Status{ topology } => {
+            let mut down = 0;
+            for d in daemon::RoutingDaemon::list(&topology)?{
+                let dead = d.dead()?;
+                down += dead.len();
+                if dead.is_empty() {
+                    println!("{:<24} {:<6} running", d.netns, d.kind.to_string());
                 } else {
-                    return Err(anyhow::anyhow!("Interface {} does not have an IP address", intf.name));
-                };
-                args.push("nexthop");
-                args.push("via");
-                args.push(ip);
-                if route.gateway.len() > 1 {
-                    args.push("weight");
-                    args.push("1");
+                    println!("{:<24} {:<6} dead: {}", d.netns, d.kind.to_string(), dead.join(" "));
                 }
             }
-            Command::new("ip").args(args).output()?;
-        }
-        Ok(())
+            for fwd in forwarder::Forwarder::list(&topology)?{
+                let kind = fwd.kind().map(|k| k.to_string()).unwrap_or_default();
+                if fwd.running() {
+                    println!("{:<24} {:<6} running", fwd.netns, kind);
+                } else {
+                    down += 1;
+                    println!("{:<24} {:<6} dead: forwarder", fwd.netns, kind);
+                }
+            }
+            for p in process::Process::list(&topology)?{
+                match p.exited(){
+                    Some(exited) => println!("{:<24} {:<6} {}: {}", p.netns, "proc", p.spec.name, exited),
+                    None if p.running() => println!("{:<24} {:<6} running: {}", p.netns, "proc", p.spec.name),
+                    None => {
+                        down += 1;
+                        println!("{:<24} {:<6} dead: {}", p.netns, "proc", p.spec.name);
+                    },
+                }
+            }
+            if down > 0 {
+                return Err(anyhow::anyhow!("{} daemon processes of {} are not running", down, topology));
+            }
+            Ok(())
+        },
+        DaemonCommand::Supervise{ topology } => {
+            for d in daemon::RoutingDaemon::list(&topology)?{
+                for name in d.supervise()?{
+                    println!("restarted {} in {}", name, d.netns);
+                }
+            }
+            for fwd in forwarder::Forwarder::list(&topology)?{
+                if fwd.supervise()? {
+                    println!("restarted forwarder in {}", fwd.netns);
+                }
+            }
+            for p in process::Process::list(&topology)?{
+                if p.supervise()? {
+                    println!("restarted {} in {}", p.spec.name, p.netns);
+                }
+            }
+            Ok(())
+        },
+        DaemonCommand::Bgp{ topology, wait } => {
+            let sessions = daemon::wait_bgp(&topology, std::time::Duration::from_secs(wait))?;
+            for (netns, s) in &sessions{
+                println!("{:<24} {:<16} {:<40} {}", netns, s.peer, s.address, s.state);
+            }
+            let down = sessions.iter().filter(|(_, s)| !s.established()).count();
+            if down > 0 {
+                return Err(anyhow::anyhow!("{} of {} BGP sessions of {} are not established", down, sessions.len(), topology));
+            }
+            Ok(())
+        },
     }
 }
 
-fn main() -> Result<(), Error>{
-    let mut config = Config::new();
-
-    let r1 = Namespace::new("r1".to_string(), true, &mut config)?;
-    let r2 = Namespace::new("r2".to_string(), true, &mut config)?;
-
-    let link1 = Link::new(
-        "link1".to_string(),
-        "10.0.0.0/24".to_string(),
-        &mut config,
-    )?;
-    let (link1_intf1, link1_intf2) = link1.attach(r1.clone(), r2.clone(), &mut config)?;
-
-    let link2 = Link::new(
-        "link2".to_string(),
-        "10.0.1.0/24".to_string(),
-        &mut config,
-    )?;
-    let (link2_intf1, link2_intf2) = link2.attach(r1.clone(), r2.clone(), &mut config)?;
-
-    let link3 = Link::new(
-        "link3".to_string(),
-        "10.0.2.0/24".to_string(),
-        &mut config,
-    )?;
-    let (link3_intf1, link3_intf2) = link3.attach(r1.clone(), r2.clone(), &mut config)?;
-
-    let link4 = Link::new(
-        "link4".to_string(),
-        "10.0.3.0/24".to_string(),
-        &mut config,
-    )?;
-    let (link4_intf1, link4_intf2) = link4.attach(r1.clone(), r2.clone(), &mut config)?;
-
-    let link5 = Link::new(
-        "link5".to_string(),
-        "10.0.4.0/24".to_string(),
-        &mut config,
-    )?;
-    let (link5_intf1, link5_intf2) = link5.attach(r1.clone(), r2.clone(), &mut config)?;
-
-    let link6 = Link::new(
-        "link6".to_string(),
-        "10.0.5.0/24".to_string(),
-        &mut config,
-    )?;
-    let (link6_intf1, link6_intf2) = link6.attach(r1.clone(), r2.clone(), &mut config)?;
-
-    let p1 = Namespace::new("p1".to_string(), false, &mut config)?;
-    let plink1 = Link::new(
-        "plink1".to_string(),
-        "10.1.2.0/24".to_string(),
-         &mut config
-    )?;
-    let (p1_intf1,p1_intf2) = plink1.attach(p1.clone(), r1.clone(), &mut config)?;
-
-    let p2 = Namespace::new("p2".to_string(), false, &mut config)?;
-    let plink2 = Link::new(
-        "plink2".to_string(),
-        "10.1.3.0/24".to_string(),
-        &mut config
-    )?;
-    let (p2_intf1,p2_intf2) = plink2.attach(p2.clone(), r2.clone(), &mut config)?;
-
-    Interface::new(
-        "en0".to_string(),
-        Some(p1.clone()),
-        Some("192.168.0.1/24".to_string()),
-        Some(3000),
-        &mut config,
-    )?;
-
-    Interface::new(
-        "en1".to_string(),
-        Some(p2.clone()),
-        Some("192.168.1.1/24".to_string()),
-        Some(3000),
-        &mut config,
-    )?;
-
-    r1.add_route(Route {
-        dst: "192.168.1.0/24".to_string(), 
-        gateway: vec![
-            link1_intf2.clone(),
-            link2_intf2.clone(),
-            link3_intf2.clone(),
-            link4_intf2.clone(),
-            link5_intf2.clone(),
-            link6_intf2.clone(),
-        ],
-    })?;
+fn run_checks(file: PathBuf, name: Option<String>, options: &verify::VerifyOptions, json: bool) -> Result<(), Error>{
+    let mut topology = topology::Topology::from_file(&file)?;
+    if let Some(name) = name{
+        topology.name = name;
+    }
+    if topology.checks.is_empty() && topology.conntrack.is_empty() {
+        return Err(anyhow::anyhow!("Topology {} declares no checks", topology.name));
+    }
+    for m in verify::mtu_mismatches(&topology)?{
+        eprintln!("warning: {}", m);
+    }
+    let results = verify::run(&topology, options)?;
+    // after the checks, which may have made the flows
+    let flows = match topology.conntrack.is_empty(){
+        true => Vec::new(),
+        false => {
+            let state = state::State::load(&topology.name)?
+                .ok_or_else(|| anyhow::anyhow!("Topology {} not found", topology.name))?;
+            conntrack::assert_flows(&state, &topology.conntrack)
+        },
+    };
+    match (json, flows.is_empty()){
+        (true, true) => println!("{}", serde_json::to_string_pretty(&results)?),
+        (true, false) => println!("{}", serde_json::to_string_pretty(&serde_json::json!({ "checks": results, "conntrack": flows }))?),
+        (false, _) => {
+            for r in &results{
+                println!("{}", r);
+            }
+            for f in &flows{
+                println!("{}", f);
+            }
+        },
+    }
+    let failed = results.iter().filter(|r| !r.passed).count();
+    let failed_flows = flows.iter().filter(|f| !f.passed).count();
+    if failed > 0 || failed_flows > 0 {
+        return Err(anyhow::anyhow!("{} of {} checks and {} of {} conntrack assertions failed", failed, results.len(), failed_flows, flows.len()));
+    }
+    Ok(())
+}
 
-    r1.add_route(Route {
-        dst: "192.168.0.0/24".to_string(), 
-        gateway: vec![
-            p1_intf1.clone(),
-        ],
-    })?;
+fn processes(command: ProcessCommand) -> Result<(), Error>{
+    match command{
+        ProcessCommand::List{ topology, json } => {
+            if state::State::load(&topology)?.is_none() {
+                return Err(anyhow::anyhow!("Topology {} not found", topology));
+            }
+            let processes: Vec<process::ProcessStatus> = process::Process::list(&topology)?.iter().map(|p| p.status()).collect();
+            match json{
+                true => println!("{}", serde_json::to_string_pretty(&processes)?),
+                false => {
+                    for p in &processes{
+                        println!("{}", p);
+                    }
+                },
+            }
+            Ok(())
+        },
+        ProcessCommand::Run{ dir, restart, name, command } => Ok(process::run(&dir, &name, restart, &command)?),
+    }
+}
 
-    p1.add_route(Route {
-        dst: "192.168.1.0/24".to_string(), 
-        gateway: vec![
-            p1_intf2.clone(),
-        ],
-    })?;
+fn serve_dns(command: DnsCommand) -> Result<(), Error>{
+    match command{
+        DnsCommand::Serve{ topology, zone, dns64 } => Ok(dns::DnsServer{ topology, zone, dns64 }.run()?),
+        DnsCommand::Respond{ topology, zone, dns64 } => Ok(dns::DnsServer{ topology, zone, dns64 }.respond()?),
+        DnsCommand::Records{ topology, zone, dns64 } => {
+            let state = state::State::load(&topology)?
+                .ok_or_else(|| anyhow::anyhow!("Topology {} not found", topology))?;
+            let mut records = dns::records(&state, zone.trim_matches('.'));
+            if let Some(prefix) = dns64{
+                dns::dns64(&mut records, prefix);
+            }
+            for (name, addresses) in records{
+                let addresses: Vec<String> = addresses.iter().map(|a| a.to_string()).collect();
+                println!("{:<40} {}", name, addresses.join(" "));
+            }
+            Ok(())
+        },
+    }
+}
 
-    r2.add_route(Route {
-        dst: "192.168.0.0/24".to_string(), 
-        gateway: vec![
-            link1_intf1.clone(),
-            link2_intf1.clone(),
-            link3_intf1.clone(),
-            link4_intf1.clone(),
-            link5_intf1.clone(),
-            link6_intf1.clone(),
-        ],
-    })?;
+fn collect_logs(command: LogsCommand) -> Result<(), Error>{
+    match command{
+        LogsCommand::Collect{ topology, run, remove } => {
+            let dest = std::path::Path::new(experiment::RUNS_DIR).join(&run);
+            for path in logs::collect(&topology, &dest)?{
+                println!("{}", path.display());
+            }
+            if remove {
+                logs::remove(&topology)?;
+            }
+            Ok(())
+        },
+    }
+}
 
-    r2.add_route(Route {
-        dst: "192.168.1.0/24".to_string(), 
-        gateway: vec![
-            p2_intf1.clone(),
-        ],
-    })?;
+fn nft(command: NftCommand) -> Result<(), Error>{
+    match command{
+        NftCommand::Save{ topology, dir } => {
+            for path in nftables::save_topology(&topology, &dir)?{
+                println!("{}", path.display());
+            }
+            Ok(())
+        },
+        NftCommand::Restore{ topology, dir } => Ok(nftables::restore_topology(&topology, &dir)?),
+    }
+}
 
-    p2.add_route(Route {
-        dst: "192.168.0.0/24".to_string(), 
-        gateway: vec![
-            p2_intf2.clone(),
-        ],
-    })?;
-    Ok(())
-}
\ No newline at end of file
+fn measure(command: MeasureCommand) -> Result<(), Error>{
+    match command{
+        MeasureCommand::Owd{ topology, src, dst, address, port, count, interval } => {
+            let probe = owd::OwdProbe{
+                src: Namespace::netns_name(&topology, &src),
+                dst: Namespace::netns_name(&topology, &dst),
+                target: std::net::SocketAddr::new(address, port),
+                count,
+                interval: std::time::Duration::from_millis(interval),
+            };
+            println!("{}", probe.run()?);
+            Ok(())
+        },
+        MeasureCommand::Matrix{ topology, namespaces, port, count, interval, format } => {
+            let run = matrix::LatencyMatrix{ topology, namespaces, port, count, interval: std::time::Duration::from_millis(interval) };
+            let matrix = run.run()?;
+            println!("{}", matrix.render(format)?.trim_end());
+            Ok(())
+        },
+    }
+}
+
+fn experiment(command: ExperimentCommand) -> Result<(), Error>{
+    match command{
+        ExperimentCommand::Compare{ run_a, run_b } => {
+            let a = experiment::RunResult::load(&run_a)?;
+            let b = experiment::RunResult::load(&run_b)?;
+            let comparison = experiment::compare(&a, &b, &experiment::Tolerance::default());
+            println!("{}", comparison);
+            if comparison.regressions() > 0 {
+                return Err(anyhow::anyhow!("{} regression(s) between {} and {}", comparison.regressions(), a.name, b.name));
+            }
+            Ok(())
+        },
+    }
+}
+
+fn main() -> Result<(), Error>{
+    let cli = Cli::parse();
+    trace::init(&cli.log_level, cli.log_json)?;
+    match cli.command{
+        Commands::Create{ file, name, pool, reconcile, allow_host_changes, no_preflight, module, parallelism } => {
+            let preflight = if no_preflight { None } else { Some(module) };
+            create(file, name, pool, reconcile, allow_host_changes, preflight, parallelism.parallelism())
+        },
+        Commands::Preflight{ file, name, reconcile, module } => check_host(file, name, reconcile, &module),
+        Commands::Clone{ file, name, count, shift, parallel_copies, allow_host_changes, parallelism } => {
+            let mut parallelism = parallelism.parallelism();
+            if let Some(n) = parallel_copies{
+                parallelism.copies = n.max(1);
+            }
+            clone(file, name, count, shift, allow_host_changes, parallelism)
+        },
+        Commands::Scale{ file, name, output, command, allow_host_changes, parallelism } => {
+            scale_out(file, name, output, command, allow_host_changes, parallelism.parallelism())
+        },
+        Commands::Agent{ file, name, host, allow_host_changes, parallelism } => agent(file, name, &host, allow_host_changes, parallelism.parallelism()),
+        Commands::Deploy{ file, name, binary, destroy, allow_host_changes } => deploy(file, name, &binary, destroy, allow_host_changes),
+        Commands::Shell{ name, file, allow_host_changes, parallelism } => interactive(name, file, allow_host_changes, parallelism.parallelism()),
+        Commands::Diff{ file, name, json } => diff(file, name, json),
+        Commands::Apply{ file, name, allow_host_changes, no_preflight, module, parallelism } => {
+            let preflight = (!no_preflight).then_some(module);
+            apply(file, name, allow_host_changes, preflight, parallelism.parallelism())
+        },
+        Commands::Destroy{ name, orphans: true, dry_run, .. } => destroy_orphans(name.as_deref(), dry_run),
+        Commands::Destroy{ name, pool, .. } => destroy(&name.unwrap_or_default(), pool),
+        Commands::Backup{ file, name, output } => backup(file, name, output),
+        Commands::Snapshot{ file, name, output } => snapshot(file, name, output),
+        Commands::Restore{ archive, allow_host_changes, parallelism } => restore(archive, allow_host_changes, parallelism.parallelism()),
+        Commands::Export{ file, name, format } => export(file, name, format),
+        Commands::Graph{ file, name, format, output } => draw(file, name, format, output),
+        Commands::Import{ name, namespaces, containerlab, output } => import(&name, &namespaces, containerlab, output),
+        Commands::Generate{ name, shape, pool, prefix, pool6, output } => generate(&name, shape, &pool, prefix, pool6, output),
+        Commands::Pool{ command } => pool(command),
+        Commands::Status{ name } => status(&name),
+        Commands::Show{ name, json } => show(&name, json),
+        Commands::Stats{ name, namespace, interval, count, json } => interface_stats(&name, namespace, interval, count, json),
+        Commands::AssertCounters{ file, name, duration, json, command } => assert_counters(file, name, duration, json, &command),
+        Commands::Alerts{ file, name, interval, duration, webhook, json, command } => {
+            alerts(file, name, interval, duration, webhook, json, &command)
+        },
+        Commands::Exec{ topology, namespace, command } => exec(&topology, &namespace, &command),
+        Commands::Clock{ topology, namespace, monotonic, boottime, log, command } => {
+            clock(&topology, &namespace, clock::ClockSkew{ monotonic, boottime }, log, &command)
+        },
+        Commands::Conntrack{ command } => conntrack(command),
+        Commands::Flows{ command } => flows(command),
+        Commands::Offload{ topology, namespace, interface, on, off } => offloads(&topology, &namespace, &interface, &on, &off),
+        Commands::Bond{ topology, link, json } => bonds(&topology, &link, json),
+        Commands::Drop{ command } => drop_injection(command),
+        Commands::Corrupt{ command } => corrupt(command),
+        Commands::Stress{ command } => stress(command),
+        Commands::Restart{ topology, namespace, stop, start, src, dst, address, delay, downtime, no_preserve, max_lost, port, count, interval } => {
+            let gr = restart::GracefulRestart{
+                topology: topology.clone(),
+                netns: Namespace::netns_name(&topology, &namespace),
+                stop,
+                start,
+                delay: std::time::Duration::from_millis(delay),
+                downtime: std::time::Duration::from_millis(downtime),
+                preserve: !no_preserve,
+            };
+            let probe = stress::SequenceProbe{
+                src: Namespace::netns_name(&topology, &src),
+                dst: Namespace::netns_name(&topology, &dst),
+                target: std::net::SocketAddr::new(address, port),
+                count,
+                interval: std::time::Duration::from_millis(interval),
+            };
+            restart(gr, probe, max_lost)
+        },
+        Commands::Fastpath{ file, name, src, dst, address, port, seconds } => {
+            fastpath(file, name, &src, &dst, std::net::SocketAddr::new(address, port), seconds)
+        },
+        Commands::Load{ topology, src, dst, address, port, protocol, flows, seconds, rate, generator, json } => {
+            let traffic = traffic::Traffic{
+                src: Namespace::netns_name(&topology, &src),
+                dst: Namespace::netns_name(&topology, &dst),
+                target: std::net::SocketAddr::new(address, port),
+                protocol,
+                flows,
+                duration: std::time::Duration::from_secs(seconds),
+                rate,
+                generator,
+            };
+            let report = traffic.run()?;
+            match json{
+                true => println!("{}", serde_json::to_string_pretty(&report)?),
+                false => println!("{}", report),
+            }
+            Ok(())
+        },
+        Commands::Ecmp{ topology, router, src, dst, address, port, protocol, flows, seconds, rate, generator, min_fairness, json } => {
+            let analysis = ecmp::EcmpAnalysis{
+                router: Namespace::netns_name(&topology, &router),
+                traffic: traffic::Traffic{
+                    src: Namespace::netns_name(&topology, &src),
+                    dst: Namespace::netns_name(&topology, &dst),
+                    target: std::net::SocketAddr::new(address, port),
+                    protocol,
+                    flows,
+                    duration: std::time::Duration::from_secs(seconds),
+                    rate,
+                    generator,
+                },
+                min_fairness,
+            };
+            let report = analysis.run()?;
+            match json{
+                true => println!("{}", serde_json::to_string_pretty(&report)?),
+                false => println!("{}", report),
+            }
+            if !report.expected {
+                return Err(anyhow::anyhow!("Flows over {} not spread as fib_multipath_hash_policy {} should", router, report.hash_policy));
+            }
+            Ok(())
+        },
+        Commands::Capture{ topology, src, dst, protocol, port, duration, dry_run } => {
+            capture(&topology, capture::Flow{ src, dst, protocol, port }, duration, dry_run)
+        },
+        Commands::Mirror{ topology, namespace, interface, to, monitor, direction, duration } => {
+            let spec = mirror::MirrorSpec{ name: "mirror".to_string(), namespace, interface, direction, to, monitor };
+            mirror(&topology, spec, duration)
+        },
+        Commands::Flap{ topology, namespace, prefixes, count, interval, settle, json } => {
+            let scenario = flap::FlapScenario{
+                netns: Namespace::netns_name(&topology, &namespace),
+                topology,
+                prefixes,
+                flaps: count,
+                interval: std::time::Duration::from_millis(interval),
+                settle: std::time::Duration::from_secs(settle),
+            };
+            flap(scenario, json)
+        },
+        Commands::Scenario{ file, run, allow_host_changes, count, timeout, json } => {
+            let options = verify::VerifyOptions{ count, timeout: std::time::Duration::from_millis(timeout), ..Default::default() };
+            run_scenario(file, run, allow_host_changes, options, json)
+        },
+        Commands::Chaos{ command } => run_chaos(command),
+        Commands::Liveness{ topology, link, interval, multiplier, duration, json } => {
+            let options = liveness::LivenessOptions{ interval: std::time::Duration::from_millis(interval), multiplier };
+            probe_liveness(topology, link, options, duration, json)
+        },
+        Commands::Fuzz{ command } => run_fuzz(command),
+        Commands::Routes{ topology, namespace, wait, withdrawn, timeout } => monitor_routes(topology, namespace, wait, withdrawn, timeout),
+        Commands::Watch{ file, name, interval, heal } => watch(file, name, interval, heal),
+        Commands::Daemon{ command } => routing_daemon(command),
+        Commands::Dataplane{ command } => run_dataplane(command),
+        Commands::Dns{ command } => serve_dns(command),
+        Commands::Process{ command } => processes(command),
+        Commands::Gnmi{ topology, listen } => {
+            if state::namespaces(&topology)?.is_empty() {
+                return Err(anyhow::anyhow!("Topology {} not found", topology));
+            }
+            Ok(gnmi::GnmiServer{ topology, listen }.run()?)
+        },
+        Commands::Api{ listen, auth, allow_host_changes, parallelism } => {
+            let mut server = api::ApiServer::new(listen, parallelism.parallelism());
+            server.host_changes = allow_host_changes;
+            if let Some(auth) = auth{
+                server = server.with_auth(auth::AuthSpec::load(&auth)?);
+            }
+            Ok(server.run()?)
+        },
+        Commands::Snmp{ topology, community, namespace } => {
+            if state::namespaces(&topology)?.is_empty() {
+                return Err(anyhow::anyhow!("Topology {} not found", topology));
+            }
+            Ok(snmp::SnmpAgent{ topology, community, namespaces: namespace }.run()?)
+        },
+        Commands::Syslog{ topology, output } => {
+            let output = output.unwrap_or_else(|| syslog::path(&topology));
+            Ok(syslog::Collector{ topology, output }.run()?)
+        },
+        Commands::Logs{ command } => collect_logs(command),
+        Commands::Nft{ command } => nft(command),
+        Commands::Measure{ command } => measure(command),
+        Commands::Traffic{ topology, src, dst, address, persona, sessions, dscp, duration, port, seed, json } => {
+            let run = persona::PersonaRun{
+                src: Namespace::netns_name(&topology, &src),
+                dst: Namespace::netns_name(&topology, &dst),
+                target: std::net::SocketAddr::new(address, port),
+                personas: persona,
+                sessions,
+                dscp,
+                duration: std::time::Duration::from_secs(duration),
+                seed,
+            };
+            let reports = run.run()?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&reports)?);
+            } else {
+                for r in &reports{
+                    println!("{}", r);
+                }
+            }
+            Ok(())
+        },
+        Commands::Experiment{ command } => experiment(command),
+        Commands::Verify{ file, name, count, timeout, max_loss, mtu, pmtu, json } => {
+            let options = verify::VerifyOptions{
+                count,
+                timeout: std::time::Duration::from_millis(timeout),
+                max_loss,
+                mtu,
+                pmtu,
+            };
+            run_checks(file, name, &options, json)
+        },
+    }
+}
@@ -0,0 +1,199 @@
+//! Latency matrix of a running topology: one-way delay probes, see `owd`,
+//! from every namespace to every other one, or between those selected,
+//! so the delays of the links' netem impairments can be checked to add
+//! up along multi-hop paths, in each direction on its own. A probe goes to
+//! the first IPv4 address of its target (IPv6 if it has none), as a check
+//! naming a namespace does.
+//!
+//! Pairs are probed one after the other, so probes of one pair don't queue
+//! behind those of another on links both paths share. A pair whose probes
+//! don't come through gets its error in the matrix instead of delays.
+
+use std::fmt::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::error::{failed, Result, RouterError};
+use crate::owd::OwdProbe;
+use crate::state::State;
+use crate::verify;
+use crate::Namespace;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MatrixFormat{
+    Text,
+    Json,
+    Csv,
+}
+
+impl FromStr for MatrixFormat{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<Self>{
+        match s{
+            "text" => Ok(MatrixFormat::Text),
+            "json" => Ok(MatrixFormat::Json),
+            "csv" => Ok(MatrixFormat::Csv),
+            _ => Err(RouterError::Invalid(format!("Unknown matrix format {}, expected text, json or csv", s))),
+        }
+    }
+}
+
+/// Probes of a matrix run.
+pub struct LatencyMatrix{
+    pub topology: String,
+    /// namespaces probed, all of the topology if empty
+    pub namespaces: Vec<String>,
+    pub port: u16,
+    /// probes per pair
+    pub count: u32,
+    pub interval: Duration,
+}
+
+/// One-way delays from `src` to `dst` in milliseconds.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Cell{
+    pub src: String,
+    pub dst: String,
+    pub address: Option<IpAddr>,
+    pub sent: u32,
+    pub received: u32,
+    pub min: Option<f64>,
+    pub mean: Option<f64>,
+    pub max: Option<f64>,
+    /// mean difference between the delays of consecutive probes
+    pub jitter: Option<f64>,
+    /// why the pair couldn't be measured
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Matrix{
+    pub topology: String,
+    /// rows and columns, in this order
+    pub namespaces: Vec<String>,
+    /// every pair of different namespaces, by row
+    pub cells: Vec<Cell>,
+}
+
+impl LatencyMatrix{
+    pub fn run(&self) -> Result<Matrix>{
+        let state = State::load(&self.topology)?
+            .ok_or_else(|| RouterError::TopologyNotFound(self.topology.to_string()))?;
+        let namespaces: Vec<String> = match self.namespaces.is_empty(){
+            true => state.namespaces.iter().map(|n| n.name.clone()).collect(),
+            false => self.namespaces.clone(),
+        };
+        for ns in &namespaces{
+            if !state.namespaces.iter().any(|n| n.name == *ns) {
+                return Err(failed!("Namespace {} not found in topology {}", ns, self.topology));
+            }
+        }
+        if namespaces.len() < 2 {
+            return Err(failed!("A matrix needs two namespaces at least"));
+        }
+        let mut cells = Vec::new();
+        for src in &namespaces{
+            for dst in namespaces.iter().filter(|dst| *dst != src){
+                cells.push(self.probe(&state, src, dst));
+            }
+        }
+        Ok(Matrix{ topology: self.topology.clone(), namespaces, cells })
+    }
+
+    fn probe(&self, state: &State, src: &str, dst: &str) -> Cell {
+        let mut cell = Cell{ src: src.to_string(), dst: dst.to_string(), sent: self.count, ..Default::default() };
+        let address = match verify::resolve(state, dst){
+            Ok(address) => address,
+            Err(e) => {
+                cell.error = Some(e.to_string());
+                return cell;
+            },
+        };
+        cell.address = Some(address);
+        let probe = OwdProbe{
+            src: Namespace::netns_name(&self.topology, src),
+            dst: Namespace::netns_name(&self.topology, dst),
+            target: SocketAddr::new(address, self.port),
+            count: self.count,
+            interval: self.interval,
+        };
+        match probe.run(){
+            Ok(r) => {
+                cell.received = r.received;
+                cell.min = Some(r.min / 1000.0);
+                cell.mean = Some(r.mean / 1000.0);
+                cell.max = Some(r.max / 1000.0);
+                cell.jitter = Some(r.jitter / 1000.0);
+            },
+            Err(e) => cell.error = Some(e.to_string()),
+        }
+        cell
+    }
+}
+
+impl Matrix{
+    pub fn cell(&self, src: &str, dst: &str) -> Option<&Cell> {
+        self.cells.iter().find(|c| c.src == src && c.dst == dst)
+    }
+
+    pub fn render(&self, format: MatrixFormat) -> Result<String>{
+        match format{
+            MatrixFormat::Text => Ok(self.to_string()),
+            MatrixFormat::Json => Ok(serde_json::to_string_pretty(self)?),
+            MatrixFormat::Csv => self.csv(),
+        }
+    }
+
+    /// One row per pair.
+    fn csv(&self) -> Result<String>{
+        let mut s = String::new();
+        writeln!(s, "src,dst,address,sent,received,min_ms,mean_ms,max_ms,jitter_ms,error")?;
+        let ms = |v: Option<f64>| v.map(|v| format!("{:.3}", v)).unwrap_or_default();
+        for c in &self.cells{
+            writeln!(s, "{},{},{},{},{},{},{},{},{},{}", c.src, c.dst,
+                c.address.map(|a| a.to_string()).unwrap_or_default(),
+                c.sent, c.received, ms(c.min), ms(c.mean), ms(c.max), ms(c.jitter),
+                // quoted, errors may contain commas
+                c.error.as_ref().map(|e| format!("\"{}\"", e.replace('"', "\"\""))).unwrap_or_default())?;
+        }
+        Ok(s)
+    }
+}
+
+/// Mean one-way delay and jitter in milliseconds, rows are sources and
+/// columns destinations, followed by the errors.
+impl fmt::Display for Matrix{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = |c: Option<&Cell>| match c{
+            None => "-".to_string(),
+            Some(Cell{ mean: Some(mean), jitter: Some(jitter), .. }) => format!("{:.3}±{:.3}", mean, jitter),
+            Some(_) => "error".to_string(),
+        };
+        let rows: Vec<Vec<String>> = self.namespaces.iter()
+            .map(|src| self.namespaces.iter().map(|dst| text(self.cell(src, dst))).collect())
+            .collect();
+        let first = self.namespaces.iter().map(|n| n.chars().count()).max().unwrap_or(0).max(3);
+        let widths: Vec<usize> = self.namespaces.iter().enumerate()
+            .map(|(n, dst)| rows.iter().map(|r| r[n].chars().count()).chain([dst.chars().count()]).max().unwrap_or(0))
+            .collect();
+        write!(f, "{:<first$}", "ms")?;
+        for (dst, width) in self.namespaces.iter().zip(&widths){
+            write!(f, "  {:>width$}", dst)?;
+        }
+        for (src, row) in self.namespaces.iter().zip(&rows){
+            write!(f, "\n{:<first$}", src)?;
+            for (cell, width) in row.iter().zip(&widths){
+                write!(f, "  {:>width$}", cell)?;
+            }
+        }
+        for c in &self.cells{
+            if let Some(e) = &c.error{
+                write!(f, "\n{} -> {}: {}", c.src, c.dst, e)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Port mirroring: a copy of every frame an interface receives, sends or
+//! both goes out of another interface, as a switch's SPAN port does, for
+//! analysis tools to watch a link without being on its path. tc mirred
+//! copies the frames, from u32 filters matching everything on the clsact
+//! qdisc of the mirrored interface, which leaves its root qdisc, e.g. the
+//! netem of `qos`, alone.
+//!
+//! mirred only sends out of interfaces of the same namespace. A mirror to
+//! a `monitor` namespace creates a veth pair into it, the span port, named
+//! like the ends of a link called after the mirror: the copies come out
+//! of `<monitor>_<mirror>` there, e.g. for `tcpdump -i`. Span ports carry
+//! no addresses and no IPv6, so nothing but the copies shows up on them.
+//!
+//! The filters of a mirror have a preference hashed from its name, so
+//! they can be found again and replaced without touching those of other
+//! mirrors or of the dataplane.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::{failed, Result, RouterError};
+use crate::topology::Topology;
+use crate::{interface, Namespace};
+
+/// First preference of the filters of mirrors, 4096 follow.
+const PREF: u32 = 49152;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction{
+    /// frames the interface receives
+    Ingress,
+    /// frames the interface sends
+    Egress,
+    #[default]
+    Both,
+}
+
+impl Direction{
+    /// clsact hooks the direction takes.
+    pub fn hooks(&self) -> &'static [&'static str] {
+        match self{
+            Direction::Ingress => &["ingress"],
+            Direction::Egress => &["egress"],
+            Direction::Both => &["ingress", "egress"],
+        }
+    }
+}
+
+impl fmt::Display for Direction{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Direction::Ingress => write!(f, "ingress"),
+            Direction::Egress => write!(f, "egress"),
+            Direction::Both => write!(f, "both"),
+        }
+    }
+}
+
+impl FromStr for Direction{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<Self>{
+        match s{
+            "ingress" => Ok(Direction::Ingress),
+            "egress" => Ok(Direction::Egress),
+            "both" => Ok(Direction::Both),
+            _ => Err(RouterError::Invalid(format!("Unknown direction {}, expected ingress, egress or both", s))),
+        }
+    }
+}
+
+/// Mirror of `interface` in `namespace`, to another interface of the
+/// namespace, `to`, or to a span port in the `monitor` namespace.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct MirrorSpec{
+    pub name: String,
+    pub namespace: String,
+    pub interface: String,
+    #[serde(default)]
+    pub direction: Direction,
+    #[serde(default)]
+    pub to: Option<String>,
+    #[serde(default)]
+    pub monitor: Option<String>,
+}
+
+impl MirrorSpec{
+    pub fn check(&self) -> Result<()>{
+        match (&self.to, &self.monitor){
+            (Some(_), Some(_)) | (None, None) => Err(RouterError::Invalid(format!("Mirror {} needs either to or monitor", self.name))),
+            (Some(to), None) if *to == self.interface => Err(RouterError::Invalid(format!("Mirror {} mirrors {} to itself", self.name, to))),
+            (None, Some(monitor)) if *monitor == self.namespace => Err(RouterError::Invalid(format!("Mirror {} monitors its own namespace {}, use to", self.name, monitor))),
+            _ => Ok(()),
+        }
+    }
+
+    /// (kernel name of the span port end in `namespace`, kernel name of
+    /// the end in the monitor namespace), None for a mirror to an
+    /// interface.
+    pub fn span(&self) -> Option<(String, String)> {
+        let monitor = self.monitor.as_ref()?;
+        Some((interface::name(&self.namespace, &self.name), interface::name(monitor, &self.name)))
+    }
+
+    /// Kernel name of the interface the copies leave `namespace` by.
+    pub fn target(&self) -> String {
+        match (&self.to, self.span()){
+            (Some(to), _) => to.clone(),
+            (None, Some((local, _))) => local,
+            (None, None) => String::new(),
+        }
+    }
+
+    /// The spec with the kernel's names of the interfaces, which may be
+    /// given by the link or bridge they belong to, as `topology` builds it.
+    pub fn kernel_names(&self, topology: &Topology) -> MirrorSpec {
+        let kernel = |name: &str| match topology.links.iter().map(|l| (&l.name, &l.endpoints))
+            .chain(topology.bridges.iter().map(|b| (&b.name, &b.members)))
+            .any(|(n, ends)| n == name && ends.contains(&self.namespace)){
+            true => interface::name(&self.namespace, name),
+            false => interface::shorten(name),
+        };
+        MirrorSpec{ interface: kernel(&self.interface), to: self.to.as_deref().map(kernel), ..self.clone() }
+    }
+
+    /// Preference of the mirror's filters.
+    pub fn pref(&self) -> u32 {
+        PREF + (interface::fnv1a(self.name.bytes()) % 4096) as u32
+    }
+}
+
+/// Mirror installed in a namespace of topology `topology`.
+#[derive(Clone, Debug)]
+pub struct Mirror{
+    pub spec: MirrorSpec,
+    pub netns: String,
+    /// namespace of the span port's far end
+    pub monitor: Option<String>,
+}
+
+impl Mirror{
+    pub fn new(topology: &str, spec: MirrorSpec) -> Result<Mirror>{
+        spec.check()?;
+        Ok(Mirror{
+            netns: Namespace::netns_name(topology, &spec.namespace),
+            monitor: spec.monitor.as_ref().map(|m| Namespace::netns_name(topology, m)),
+            spec,
+        })
+    }
+
+    /// Creates the span port unless it exists and installs the filters,
+    /// replacing those installed before. Returns whether it created the
+    /// span port.
+    pub fn start(&self) -> Result<bool>{
+        let mut created = false;
+        if let (Some((local, remote)), Some(monitor)) = (self.spec.span(), &self.monitor) {
+            if !exists(&self.netns, &local) {
+                cmd::ip(None, &["link", "add", "name", local.as_str(), "netns", self.netns.as_str(), "type", "veth",
+                    "peer", "name", remote.as_str(), "netns", monitor.as_str()])
+                    .map_err(|e| failed!("Mirror {}: failed to create the span port: {}", self.spec.name, e))?;
+                created = true;
+            }
+            for (netns, name) in [(&self.netns, &local), (monitor, &remote)]{
+                cmd::exec(netns, "sysctl", &["-qw", &format!("net.ipv6.conf.{}.disable_ipv6=1", name)])?;
+                cmd::ip(None, &["-n", netns.as_str(), "link", "set", "dev", name.as_str(), "up"])?;
+            }
+        }
+        let dev = self.spec.interface.as_str();
+        let qdiscs = cmd::exec(&self.netns, "tc", &["qdisc", "show", "dev", dev])?;
+        if !qdiscs.contains("clsact") {
+            cmd::exec(&self.netns, "tc", &["qdisc", "add", "dev", dev, "clsact"])
+                .map_err(|e| failed!("Mirror {}: {}", self.spec.name, e))?;
+        }
+        self.remove_filters();
+        let pref = self.spec.pref().to_string();
+        let target = self.spec.target();
+        for hook in self.spec.direction.hooks(){
+            cmd::exec(&self.netns, "tc", &["filter", "add", "dev", dev, hook, "pref", pref.as_str(), "protocol", "all",
+                "u32", "match", "u32", "0", "0", "action", "mirred", "egress", "mirror", "dev", target.as_str()])
+                .map_err(|e| failed!("Mirror {}: {}", self.spec.name, e))?;
+        }
+        Ok(created)
+    }
+
+    /// Removes the filters and the span port.
+    pub fn stop(&self) -> Result<()>{
+        self.remove_filters();
+        if let Some((local, _)) = self.spec.span().filter(|(local, _)| exists(&self.netns, local)) {
+            // deleting one end of a veth removes the peer as well
+            cmd::ip(None, &["-n", self.netns.as_str(), "link", "del", "dev", local.as_str()])?;
+        }
+        Ok(())
+    }
+
+    /// Removes the filters of either direction, leaving the span port. The
+    /// interface may be gone or mirrored in one direction only.
+    pub fn remove_filters(&self){
+        let pref = self.spec.pref().to_string();
+        for hook in Direction::Both.hooks(){
+            let _ = cmd::exec(&self.netns, "tc", &["filter", "del", "dev", self.spec.interface.as_str(), hook, "pref", pref.as_str()]);
+        }
+    }
+}
+
+fn exists(netns: &str, name: &str) -> bool {
+    cmd::ip(None, &["-n", netns, "link", "show", "dev", name]).is_ok()
+}
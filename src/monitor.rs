@@ -0,0 +1,546 @@
+//! Route monitor: listens for the kernel's RTNETLINK route notifications in
+//! any number of namespaces and keeps their routing tables up to date, so
+//! a caller can wait for a route to appear or disappear and see when it
+//! did, e.g. to measure how long routing took to converge after a link
+//! failed or a daemon restarted.
+//!
+//! Each namespace gets a thread with a netlink socket joined to the route
+//! groups, the current routes are dumped only after it joined, so nothing
+//! happens unseen between the two. IPv4 flushes routes over an interface
+//! going down without a notification, so link changes dump the routes of
+//! the namespace again and the difference is reported as changes.
+//!
+//! Multipath routes are kept as one entry per nexthop, so ECMP nexthops
+//! being removed and added back show up as changes of their own.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use ipnet::IpNet;
+
+use crate::error::{failed, Result};
+use crate::netns;
+
+/// How often the listening threads check whether the monitor was dropped.
+const POLL: Duration = Duration::from_millis(100);
+
+/// Time the namespaces are given to start listening.
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) const NLMSG_HDRLEN: usize = 16;
+const RTMSG_LEN: usize = 12;
+const RTNEXTHOP_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouteChange{
+    Added,
+    Removed,
+}
+
+impl fmt::Display for RouteChange{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            RouteChange::Added => write!(f, "added"),
+            RouteChange::Removed => write!(f, "removed"),
+        }
+    }
+}
+
+/// Route of a namespace, multipath routes have one per nexthop.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RouteEntry{
+    pub prefix: IpNet,
+    pub table: u32,
+    pub metric: u32,
+    pub gateway: Option<IpAddr>,
+    /// outgoing interface
+    pub dev: Option<String>,
+}
+
+impl fmt::Display for RouteEntry{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.prefix)?;
+        if let Some(gateway) = self.gateway{
+            write!(f, " via {}", gateway)?;
+        }
+        if let Some(dev) = &self.dev{
+            write!(f, " dev {}", dev)?;
+        }
+        if self.metric != 0 {
+            write!(f, " metric {}", self.metric)?;
+        }
+        if self.table != libc::RT_TABLE_MAIN as u32 {
+            write!(f, " table {}", self.table)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RouteEvent{
+    pub netns: String,
+    pub change: RouteChange,
+    pub route: RouteEntry,
+    /// when the notification was received
+    pub at: Instant,
+}
+
+impl fmt::Display for RouteEvent{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.netns, self.change, self.route)
+    }
+}
+
+/// What the listening thread of a namespace saw.
+enum Update{
+    /// all routes, after joining the route groups or a link change
+    Dump(String, Vec<RouteEntry>, Instant),
+    /// routes of one notification, `replace` if they replace the routes to
+    /// the same prefix, table and metric
+    Routes(String, RouteChange, Vec<RouteEntry>, bool, Instant),
+    Failed(String, String),
+}
+
+pub struct RouteMonitor{
+    updates: mpsc::Receiver<Update>,
+    /// routes of every namespace and when they appeared
+    tables: HashMap<String, HashMap<RouteEntry, Instant>>,
+    /// changes not yet returned by `next`
+    pending: VecDeque<RouteEvent>,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl RouteMonitor{
+    /// Starts listening in `namespaces` and returns once all of them
+    /// listen and their routes are known.
+    pub fn start(namespaces: &[String]) -> Result<Self>{
+        let (tx, updates) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut monitor = RouteMonitor{
+            updates,
+            tables: HashMap::new(),
+            pending: VecDeque::new(),
+            stop: stop.clone(),
+            threads: Vec::new(),
+        };
+        for n in namespaces{
+            let (n, tx, stop) = (n.clone(), tx.clone(), stop.clone());
+            monitor.threads.push(std::thread::spawn(move || {
+                if let Err(e) = netns::enter(&n).and_then(|_| listen(&n, &tx, &stop)) {
+                    let _ = tx.send(Update::Failed(n, e.to_string()));
+                }
+            }));
+        }
+        let deadline = Instant::now() + START_TIMEOUT;
+        while monitor.tables.len() < namespaces.len() {
+            let update = monitor.updates.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .map_err(|_| failed!("Namespaces didn't start listening for routes within {} s", START_TIMEOUT.as_secs()))?;
+            monitor.apply(update)?;
+        }
+        monitor.pending.clear();
+        Ok(monitor)
+    }
+
+    /// Routes of `netns` as last seen.
+    pub fn routes(&self, netns: &str) -> Vec<&RouteEntry> {
+        let mut routes: Vec<&RouteEntry> = self.tables.get(netns).map(|t| t.keys().collect()).unwrap_or_default();
+        routes.sort_by_key(|r| (r.table, r.prefix, r.metric));
+        routes
+    }
+
+    /// Next route change in any of the namespaces, None if nothing changed
+    /// within `timeout`.
+    pub fn next(&mut self, timeout: Duration) -> Result<Option<RouteEvent>>{
+        let deadline = Instant::now() + timeout;
+        loop{
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(Some(event));
+            }
+            match self.updates.recv_timeout(deadline.saturating_duration_since(Instant::now())){
+                Ok(update) => self.apply(update)?,
+                Err(mpsc::RecvTimeoutError::Timeout) => return Ok(None),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Err(failed!("Route monitor stopped")),
+            }
+        }
+    }
+
+    /// Waits until `netns` has a route to `prefix` outside of the local
+    /// table. Returns when it appeared, which is before the call if it
+    /// already was there. Changes seen while waiting are not returned by
+    /// `next` anymore.
+    pub fn wait_for_route(&mut self, netns: &str, prefix: IpNet, timeout: Duration) -> Result<Instant>{
+        let deadline = Instant::now() + timeout;
+        loop{
+            let table = self.tables.get(netns)
+                .ok_or_else(|| failed!("Namespace {} isn't monitored", netns))?;
+            if let Some(at) = table.iter().filter(|(r, _)| matches(r, prefix)).map(|(_, at)| *at).min() {
+                return Ok(at);
+            }
+            self.wait(deadline)
+                .map_err(|e| failed!("No route to {} in {} after {} ms: {}", prefix, netns, timeout.as_millis(), e))?;
+        }
+    }
+
+    /// Waits until `netns` has no route to `prefix` outside of the local
+    /// table anymore. Returns when the last one disappeared, or the time
+    /// of the call if there was none.
+    pub fn wait_for_withdrawal(&mut self, netns: &str, prefix: IpNet, timeout: Duration) -> Result<Instant>{
+        let deadline = Instant::now() + timeout;
+        let mut removed = Instant::now();
+        loop{
+            let table = self.tables.get(netns)
+                .ok_or_else(|| failed!("Namespace {} isn't monitored", netns))?;
+            if !table.keys().any(|r| matches(r, prefix)) {
+                return Ok(removed);
+            }
+            let event = self.wait(deadline)
+                .map_err(|e| failed!("Route to {} still in {} after {} ms: {}", prefix, netns, timeout.as_millis(), e))?;
+            if event.netns == netns && event.change == RouteChange::Removed && matches(&event.route, prefix) {
+                removed = event.at;
+            }
+        }
+    }
+
+    /// Takes the next change, failing at `deadline`.
+    fn wait(&mut self, deadline: Instant) -> Result<RouteEvent>{
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if timeout.is_zero() {
+            return Err(failed!("timed out"));
+        }
+        self.next(timeout)?.ok_or_else(|| failed!("timed out"))
+    }
+
+    fn apply(&mut self, update: Update) -> Result<()>{
+        match update{
+            Update::Failed(netns, e) => Err(failed!("Failed to monitor routes of {}: {}", netns, e)),
+            Update::Dump(netns, routes, at) => {
+                let table = self.tables.entry(netns.clone()).or_default();
+                let gone: Vec<RouteEntry> = table.keys().filter(|r| !routes.contains(r)).cloned().collect();
+                for route in gone{
+                    table.remove(&route);
+                    self.pending.push_back(RouteEvent{ netns: netns.clone(), change: RouteChange::Removed, route, at });
+                }
+                for route in routes{
+                    if !table.contains_key(&route) {
+                        table.insert(route.clone(), at);
+                        self.pending.push_back(RouteEvent{ netns: netns.clone(), change: RouteChange::Added, route, at });
+                    }
+                }
+                Ok(())
+            },
+            Update::Routes(netns, change, routes, replace, at) => {
+                let table = self.tables.entry(netns.clone()).or_default();
+                if replace {
+                    let replaced: Vec<RouteEntry> = table.keys()
+                        .filter(|r| routes.iter().any(|n| n.prefix == r.prefix && n.table == r.table && n.metric == r.metric) && !routes.contains(r))
+                        .cloned()
+                        .collect();
+                    for route in replaced{
+                        table.remove(&route);
+                        self.pending.push_back(RouteEvent{ netns: netns.clone(), change: RouteChange::Removed, route, at });
+                    }
+                }
+                for route in routes{
+                    let changed = match change{
+                        RouteChange::Added => !table.contains_key(&route) && table.insert(route.clone(), at).is_none(),
+                        RouteChange::Removed => table.remove(&route).is_some(),
+                    };
+                    if changed {
+                        self.pending.push_back(RouteEvent{ netns: netns.clone(), change, route, at });
+                    }
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl Drop for RouteMonitor{
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        for t in self.threads.drain(..){
+            let _ = t.join();
+        }
+    }
+}
+
+fn matches(route: &RouteEntry, prefix: IpNet) -> bool {
+    route.prefix == prefix && route.table != libc::RT_TABLE_LOCAL as u32
+}
+
+/// Listens for route and link notifications of the namespace the thread is
+/// in until `stop` is set.
+fn listen(netns: &str, updates: &mpsc::Sender<Update>, stop: &AtomicBool) -> Result<()>{
+    let groups = libc::RTMGRP_IPV4_ROUTE | libc::RTMGRP_IPV6_ROUTE | libc::RTMGRP_LINK;
+    let events = NetlinkSocket::open(groups as u32)?;
+    let mut names = HashMap::new();
+    let dumped = dump(&mut names)?;
+    let _ = updates.send(Update::Dump(netns.to_string(), dumped, Instant::now()));
+    while !stop.load(Ordering::SeqCst) {
+        let buf = match events.recv(){
+            Ok(Some(buf)) => buf,
+            Ok(None) => continue,
+            // whatever was lost is found by dumping again
+            Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => Vec::new(),
+            Err(e) => return Err(failed!("Failed to receive route notifications: {}", e)),
+        };
+        let at = Instant::now();
+        let mut links = buf.is_empty();
+        for (kind, flags, payload) in messages(&buf){
+            let change = match kind{
+                libc::RTM_NEWROUTE => RouteChange::Added,
+                libc::RTM_DELROUTE => RouteChange::Removed,
+                libc::RTM_NEWLINK | libc::RTM_DELLINK => {
+                    links = true;
+                    continue;
+                },
+                _ => continue,
+            };
+            let routes = parse_route(payload, &mut names);
+            if !routes.is_empty() {
+                let replace = flags & libc::NLM_F_REPLACE as u16 != 0;
+                let _ = updates.send(Update::Routes(netns.to_string(), change, routes, replace, at));
+            }
+        }
+        if links {
+            // indexes of deleted interfaces may be reused
+            names.clear();
+            let dumped = dump(&mut names)?;
+            let _ = updates.send(Update::Dump(netns.to_string(), dumped, Instant::now()));
+        }
+    }
+    Ok(())
+}
+
+/// Routes of all tables of the namespace the thread is in.
+fn dump(names: &mut HashMap<u32, String>) -> Result<Vec<RouteEntry>>{
+    let socket = NetlinkSocket::open(0)?;
+    let mut request = [0u8; NLMSG_HDRLEN + RTMSG_LEN];
+    request[0..4].copy_from_slice(&((NLMSG_HDRLEN + RTMSG_LEN) as u32).to_ne_bytes());
+    request[4..6].copy_from_slice(&libc::RTM_GETROUTE.to_ne_bytes());
+    request[6..8].copy_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+    request[8..12].copy_from_slice(&1u32.to_ne_bytes());
+    socket.send(&request)?;
+    let mut routes = Vec::new();
+    loop{
+        let Some(buf) = socket.recv().map_err(|e| failed!("Failed to dump routes: {}", e))? else {
+            continue;
+        };
+        for (kind, _, payload) in messages(&buf){
+            match kind as libc::c_int{
+                libc::NLMSG_DONE => return Ok(routes),
+                libc::NLMSG_ERROR => {
+                    let errno = payload.get(0..4).map(|b| i32::from_ne_bytes([b[0], b[1], b[2], b[3]])).unwrap_or(0);
+                    return Err(failed!("Failed to dump routes: {}", std::io::Error::from_raw_os_error(-errno)));
+                },
+                _ if kind == libc::RTM_NEWROUTE => routes.extend(parse_route(payload, names)),
+                _ => {},
+            }
+        }
+    }
+}
+
+/// (type, flags, payload) of the netlink messages in `buf`.
+pub(crate) fn messages(buf: &[u8]) -> Vec<(u16, u16, &[u8])> {
+    let mut messages = Vec::new();
+    let mut offset = 0;
+    while offset + NLMSG_HDRLEN <= buf.len() {
+        let len = u32::from_ne_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]]) as usize;
+        if len < NLMSG_HDRLEN || offset + len > buf.len() {
+            break;
+        }
+        let kind = u16::from_ne_bytes([buf[offset + 4], buf[offset + 5]]);
+        let flags = u16::from_ne_bytes([buf[offset + 6], buf[offset + 7]]);
+        messages.push((kind, flags, &buf[offset + NLMSG_HDRLEN..offset + len]));
+        offset += align(len);
+    }
+    messages
+}
+
+/// (type, payload) of the netlink attributes in `buf`.
+pub(crate) fn attributes(buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attributes = Vec::new();
+    let mut offset = 0;
+    while offset + 4 <= buf.len() {
+        let len = u16::from_ne_bytes([buf[offset], buf[offset + 1]]) as usize;
+        if len < 4 || offset + len > buf.len() {
+            break;
+        }
+        let kind = u16::from_ne_bytes([buf[offset + 2], buf[offset + 3]]);
+        attributes.push((kind, &buf[offset + 4..offset + len]));
+        offset += align(len);
+    }
+    attributes
+}
+
+pub(crate) fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Entries of a route message, none for cached routes and families other
+/// than IPv4 and IPv6.
+fn parse_route(payload: &[u8], names: &mut HashMap<u32, String>) -> Vec<RouteEntry> {
+    if payload.len() < RTMSG_LEN {
+        return Vec::new();
+    }
+    let family = payload[0] as libc::c_int;
+    let flags = u32::from_ne_bytes([payload[8], payload[9], payload[10], payload[11]]);
+    if flags & libc::RTM_F_CLONED != 0 || !matches!(family, libc::AF_INET | libc::AF_INET6) {
+        return Vec::new();
+    }
+    let mut destination = None;
+    let mut table = payload[4] as u32;
+    let mut metric = 0;
+    let mut gateway = None;
+    let mut oif = None;
+    let mut nexthops = Vec::new();
+    for (kind, data) in attributes(&payload[RTMSG_LEN..]){
+        match kind{
+            libc::RTA_DST => destination = address(family, data),
+            libc::RTA_TABLE => table = number(data),
+            libc::RTA_PRIORITY => metric = number(data),
+            libc::RTA_GATEWAY => gateway = address(family, data),
+            libc::RTA_OIF => oif = Some(number(data)),
+            libc::RTA_MULTIPATH => nexthops = multipath(family, data),
+            _ => {},
+        }
+    }
+    let destination = destination.unwrap_or(match family{
+        libc::AF_INET => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        _ => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    });
+    let Ok(prefix) = IpNet::new(destination, payload[1]) else {
+        return Vec::new();
+    };
+    if nexthops.is_empty() {
+        nexthops.push((gateway, oif));
+    }
+    nexthops.into_iter()
+        .map(|(gateway, oif)| RouteEntry{ prefix, table, metric, gateway, dev: oif.map(|i| name(i, names)) })
+        .collect()
+}
+
+/// (gateway, interface index) of the nexthops of a multipath attribute.
+fn multipath(family: libc::c_int, mut data: &[u8]) -> Vec<(Option<IpAddr>, Option<u32>)> {
+    let mut nexthops = Vec::new();
+    while data.len() >= RTNEXTHOP_LEN {
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        if len < RTNEXTHOP_LEN || len > data.len() {
+            break;
+        }
+        let oif = number(&data[4..8]);
+        let gateway = attributes(&data[RTNEXTHOP_LEN..len]).into_iter()
+            .find(|(kind, _)| *kind == libc::RTA_GATEWAY)
+            .and_then(|(_, a)| address(family, a));
+        nexthops.push((gateway, Some(oif)));
+        data = &data[align(len).min(data.len())..];
+    }
+    nexthops
+}
+
+fn address(family: libc::c_int, data: &[u8]) -> Option<IpAddr> {
+    match family{
+        libc::AF_INET => <[u8; 4]>::try_from(data).ok().map(|a| IpAddr::V4(a.into())),
+        _ => <[u8; 16]>::try_from(data).ok().map(|a| IpAddr::V6(a.into())),
+    }
+}
+
+fn number(data: &[u8]) -> u32 {
+    <[u8; 4]>::try_from(data).map(u32::from_ne_bytes).unwrap_or(0)
+}
+
+/// Name of the interface with `index`, remembered in `names` so routes of
+/// interfaces deleted meanwhile are still recognized.
+fn name(index: u32, names: &mut HashMap<u32, String>) -> String {
+    names.entry(index)
+        .or_insert_with(|| {
+            let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+            let name = unsafe { libc::if_indextoname(index, buf.as_mut_ptr()) };
+            if name.is_null() {
+                return format!("if{}", index);
+            }
+            unsafe { std::ffi::CStr::from_ptr(name) }.to_string_lossy().to_string()
+        })
+        .clone()
+}
+
+pub(crate) struct NetlinkSocket(OwnedFd);
+
+impl NetlinkSocket{
+    /// NETLINK_ROUTE socket of the calling thread's namespace joined to
+    /// `groups`, receiving with a timeout of `POLL`.
+    fn open(groups: u32) -> Result<Self>{
+        Self::open_protocol(libc::NETLINK_ROUTE, groups)
+    }
+
+    /// Like `open`, for another netlink protocol.
+    pub(crate) fn open_protocol(protocol: libc::c_int, groups: u32) -> Result<Self>{
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, protocol) };
+        if fd < 0 {
+            return Err(failed!("Failed to open netlink socket: {}", std::io::Error::last_os_error()));
+        }
+        let socket = NetlinkSocket(unsafe { OwnedFd::from_raw_fd(fd) });
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = groups;
+        let rc = unsafe {
+            libc::bind(fd, &addr as *const libc::sockaddr_nl as *const libc::sockaddr, mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+        };
+        if rc != 0 {
+            return Err(failed!("Failed to bind netlink socket: {}", std::io::Error::last_os_error()));
+        }
+        // room for bursts of notifications, e.g. a full table withdrawn
+        socket.set_option(libc::SO_RCVBUF, &(4 * 1024 * 1024 as libc::c_int))?;
+        let timeout = libc::timeval{ tv_sec: 0, tv_usec: POLL.as_micros() as libc::suseconds_t };
+        socket.set_option(libc::SO_RCVTIMEO, &timeout)?;
+        Ok(socket)
+    }
+
+    fn set_option<T>(&self, option: libc::c_int, value: &T) -> Result<()>{
+        let rc = unsafe {
+            libc::setsockopt(
+                self.0.as_raw_fd(),
+                libc::SOL_SOCKET,
+                option,
+                value as *const T as *const libc::c_void,
+                mem::size_of::<T>() as libc::socklen_t,
+            )
+        };
+        if rc != 0 {
+            return Err(failed!("Failed to set netlink socket option {}: {}", option, std::io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn send(&self, buf: &[u8]) -> Result<()>{
+        let n = unsafe { libc::send(self.0.as_raw_fd(), buf.as_ptr() as *const libc::c_void, buf.len(), 0) };
+        if n < 0 {
+            return Err(failed!("Failed to send netlink request: {}", std::io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Next datagram, None on timeout. ENOBUFS means notifications were
+    /// lost because the socket's buffer was full.
+    pub(crate) fn recv(&self) -> std::io::Result<Option<Vec<u8>>>{
+        let mut buf = vec![0u8; 64 * 1024];
+        let n = unsafe { libc::recv(self.0.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        if n < 0 {
+            let err = std::io::Error::last_os_error();
+            return match err.kind(){
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut | std::io::ErrorKind::Interrupted => Ok(None),
+                _ => Err(err),
+            };
+        }
+        buf.truncate(n as usize);
+        Ok(Some(buf))
+    }
+}
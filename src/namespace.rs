@@ -0,0 +1,463 @@
+use std::net::{Ipv6Addr, SocketAddr};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock;
+use crate::cmd;
+use crate::error::{failed, Result, RouterError};
+use crate::group::GroupSpec;
+use crate::icmp::IcmpSpec;
+use crate::neighbor::NeighborSpec;
+use crate::state::State;
+use crate::tcp::{self, TcpSpec};
+use crate::trace::Traced;
+use crate::transaction::Resource;
+use crate::{container, daemon, netns, owner, parallel, policy, pool, ra, rib, Config, Nexthop, Route, RouteKind, Seg6, Seg6Local, Seg6Mode};
+
+/// Sysctls forwarding in the namespaces created, routers or not.
+pub const ROUTING: [(&str, &str); 2] = [("net.ipv4.ip_forward", "1"), ("net.ipv6.conf.all.forwarding", "1")];
+/// Sysctls hashing multipath routes on the ports too, see
+/// `NamespaceSpec::ecmp`.
+pub const ECMP: [(&str, &str); 2] = [("net.ipv4.fib_multipath_hash_policy", "1"), ("net.ipv6.fib_multipath_hash_policy", "1")];
+
+pub struct Namespace{
+    pub name: String,
+    /// kernel name, `<topology>-<name>`
+    pub netns: String,
+}
+
+impl Namespace {
+    /// Creates a namespace forwarding packets.
+    pub fn new(name: String, config: &Config) -> Result<Arc<Namespace>> {
+        let sysctls = ROUTING.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        Ok(Namespace::new_all(&[(name, sysctls, None)], config)?.remove(0))
+    }
+
+    /// Creates several namespaces, given as (name, sysctl assignments,
+    /// container pid), see `Topology::sysctls`. Those with a pid are the
+    /// namespace of that process, attached rather than created, see
+    /// `container`. The kernel side is set up on up to
+    /// `config.parallelism.namespaces` threads at once.
+    pub fn new_all(specs: &[(String, Vec<String>, Option<u32>)], config: &Config) -> Result<Vec<Arc<Namespace>>> {
+        // (namespace, sysctls, create it, taken from the pool, container pid)
+        type Setup<'a> = (Arc<Namespace>, &'a [String], bool, bool, Option<u32>);
+        let mut setups: Vec<Setup> = Vec::new();
+        for (name, sysctls, pid) in specs{
+            if let Some(r) = config.namespaces.get(name){
+                return Err(RouterError::NamespaceExists(r.name.clone()));
+            }
+            if setups.iter().any(|(n, _, _, _, _)| n.name == *name) {
+                return Err(RouterError::NamespaceExists(name.clone()));
+            }
+            let n = Arc::new(Namespace{
+                name: name.clone(),
+                netns: Namespace::netns_name(&config.name, name),
+            });
+            if config.reconcile && n.exists() {
+                match pid{
+                    // the container was restarted into a new namespace
+                    Some(pid) if !container::attached(&n.netns, *pid) => Namespace::delete(&n.netns)?,
+                    _ => {
+                        setups.push((n, sysctls, false, false, *pid));
+                        continue;
+                    },
+                }
+            }
+            let pooled = pid.is_none() && config.pool && pool::take_namespace(&n.netns)?;
+            setups.push((n, sysctls, !pooled, pooled, *pid));
+        }
+        let results = parallel::map(&setups, config.parallelism.namespaces, |(n, sysctls, create, pooled, pid)| {
+            if *create {
+                let created = match pid{
+                    Some(pid) => container::attach(&n.netns, *pid),
+                    None => n.create(),
+                };
+                if let Err(e) = created{
+                    return Err(e.context("Failed to create network namespace"));
+                }
+            }
+            // containers' namespaces are theirs
+            if (*create || *pooled) && pid.is_none() {
+                owner::tag_namespace(&n.netns, &config.name)
+                    .map_err(|e| e.context("Failed to tag network namespace"))?;
+            }
+            n.sysctl(sysctls)
+                .map_err(|e| e.context("Failed to set sysctls"))
+        });
+        // record everything that exists now, so a failure undoes all of it
+        let mut error = None;
+        for ((n, _, create, pooled, _), result) in setups.iter().zip(results){
+            let created = *create && n.exists();
+            if created || *pooled {
+                config.transaction().record(Resource::Namespace{ netns: n.netns.clone(), pooled: *pooled });
+            }
+            match result{
+                Ok(()) => {
+                    config.namespaces.insert(n.name.clone(), n.clone());
+                },
+                Err(e) => {
+                    error.get_or_insert(e);
+                },
+            }
+        }
+        if let Some(e) = error{
+            return Err(e);
+        }
+        for (n, _, create, pooled, _) in &setups{
+            if *create || *pooled {
+                config.hooks.namespace_created(&config.name, n)?;
+            }
+        }
+        Ok(setups.into_iter().map(|(n, _, _, _, _)| n).collect())
+    }
+    pub fn netns_name(topology: &str, name: &str) -> String {
+        format!("{}-{}", topology, name)
+    }
+
+    /// Returns the kernel names of all namespaces belonging to `topology`,
+    /// those its saved state records and those tagged as created for it,
+    /// see `owner`. The name alone doesn't tell, `lab-0-r1` is `r1` of
+    /// topology `lab-0` as well as `0-r1` of `lab`.
+    pub fn list(topology: &str) -> Result<Vec<String>>{
+        let recorded: Vec<String> = State::load(topology)?.iter()
+            .flat_map(|s| s.namespaces.iter().map(|n| n.netns.clone()))
+            .collect();
+        let out = cmd::ip(None, &["netns", "list"])
+            .map_err(|e| e.context("Failed to list namespaces"))?;
+        let prefix = Namespace::netns_name(topology, "");
+        let mut namespaces: Vec<String> = out
+            .lines()
+            .filter_map(|l| l.split_whitespace().next())
+            .filter(|n| n.starts_with(prefix.as_str()))
+            .filter(|n| recorded.iter().any(|r| r == n) || owner::namespace_owner(n).is_some_and(|o| o.topology == topology))
+            .map(|n| n.to_string())
+            .collect();
+        namespaces.sort();
+        Ok(namespaces)
+    }
+
+    pub fn delete(netns: &str) -> Result<()>{
+        cmd::ip(None, &["netns", "del", netns])
+            .map_err(|e| e.context("Failed to delete namespace"))?;
+        clock::record(netns, None)
+    }
+
+    pub(crate) fn exists(&self) -> bool {
+        std::path::Path::new("/run/netns").join(&self.netns).exists()
+    }
+
+    pub(crate) fn has_link(&self, name: &str) -> Result<bool>{
+        let output = Command::new("ip")
+            .arg("-n")
+            .arg(self.netns.as_str())
+            .arg("link")
+            .arg("show")
+            .arg("dev")
+            .arg(name)
+            .traced_output()?;
+        Ok(output.status.success())
+    }
+
+    /// Enables processing of segment routing headers on `interfaces` and on
+    /// those created later, with `vrf_strict` also VRF strict mode, which
+    /// SRv6 decapsulation into a VRF needs.
+    pub fn enable_srv6(&self, interfaces: &[String], vrf_strict: bool) -> Result<()>{
+        let mut settings: Vec<String> = ["all", "default"].into_iter().chain(interfaces.iter().map(|i| i.as_str()))
+            .map(|i| format!("net.ipv6.conf.{}.seg6_enabled=1", i))
+            .collect();
+        if vrf_strict {
+            settings.push("net.vrf.strict_mode=1".to_string());
+        }
+        self.sysctl(&settings)
+            .map_err(|e| e.context("Failed to enable SRv6"))
+    }
+
+    /// Applies the neighbor timers of `neighbor` to `interfaces`.
+    pub fn tune_neighbors(&self, neighbor: &NeighborSpec, interfaces: &[String]) -> Result<()>{
+        self.sysctl(&neighbor.sysctls(interfaces))
+            .map_err(|e| e.context("Failed to set neighbor timers"))
+    }
+
+    /// Applies the ICMP settings of `icmp` to the namespace and to
+    /// `interfaces`.
+    pub fn configure_icmp(&self, icmp: &IcmpSpec, interfaces: &[String]) -> Result<()>{
+        self.sysctl(&icmp.sysctls(interfaces))
+            .map_err(|e| e.context("Failed to configure ICMP"))
+    }
+
+    /// Applies the TCP settings of `tcp`, buffers sized with the fastest
+    /// `link_bandwidth` if it needs one, and paces `interfaces`.
+    pub fn tune_tcp(&self, tcp: &TcpSpec, link_bandwidth: Option<u64>, interfaces: &[String]) -> Result<()>{
+        self.sysctl(&tcp.sysctls(link_bandwidth)?)
+            .map_err(|e| match tcp.congestion.is_some(){
+                true => e.context(format!("Failed to tune TCP, available congestion controls: {}", tcp::available().join(" "))),
+                false => e.context("Failed to tune TCP"),
+            })?;
+        for interface in interfaces{
+            tcp::pace(&self.netns, interface, tcp.pacing == Some(true))?;
+        }
+        Ok(())
+    }
+
+    /// Lets `interfaces` learn routes and addresses from router
+    /// advertisements.
+    pub fn accept_ra(&self, interfaces: &[String]) -> Result<()>{
+        self.sysctl(&ra::host_sysctls(interfaces))
+            .map_err(|e| e.context("Failed to accept router advertisements"))
+    }
+
+    fn sysctl(&self, settings: &[String]) -> Result<()>{
+        sysctl(&self.netns, settings)
+    }
+
+    fn create(&self) -> Result<()>{
+        cmd::ip(None, &["netns", "add", self.netns.as_str()])
+            .map_err(|e| e.context("Failed to create namespace"))?;
+        Ok(())
+    }
+    /// Puts a service address on the loopback as a host prefix, so the
+    /// namespace answers for it. Brings the loopback up as well.
+    pub fn add_service_address(&self, address: &str, config: &Config) -> Result<()>{
+        let addr: std::net::IpAddr = address.parse()
+            .map_err(|e: std::net::AddrParseError| RouterError::InvalidAddress{ address: address.to_string(), reason: e.to_string() })?;
+        let prefix = ipnet::IpNet::from(addr).to_string();
+        cmd::ip(Some(&self.netns), &["link", "set", "dev", "lo", "up"])?;
+        let present = cmd::ip(Some(&self.netns), &["addr", "show", "dev", "lo", "to", prefix.as_str()])?;
+        if !present.trim().is_empty() {
+            return Ok(());
+        }
+        cmd::ip(Some(&self.netns), &["addr", "add", prefix.as_str(), "dev", "lo"])?;
+        config.transaction().record(Resource::Address{ name: "lo".to_string(), netns: Some(self.netns.clone()), address: prefix });
+        Ok(())
+    }
+
+    /// Applies the settings of `group` to all interfaces of its kernel
+    /// group in this namespace.
+    pub fn apply_group(&self, group: &GroupSpec) -> Result<()>{
+        group.apply(Some(&self.netns))
+    }
+
+    /// Runs `f` inside the namespace, e.g. to open sockets there, see
+    /// `netns::run_in`.
+    pub fn run<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send,
+        T: Send,
+    {
+        netns::run_in(&self.netns, f)
+    }
+
+    /// Runs `program` inside the namespace and captures its exit code and
+    /// output, see `netns::exec`.
+    pub fn exec(&self, program: &str, args: &[&str]) -> Result<netns::ExecOutput>{
+        netns::exec(&self.netns, program, args)
+    }
+
+    /// UDP socket bound to `addr` inside the namespace. A socket keeps the
+    /// namespace it was created in, so it can be used from any thread.
+    pub fn udp_socket(&self, addr: SocketAddr) -> Result<std::net::UdpSocket>{
+        self.run(|| std::net::UdpSocket::bind(addr)
+            .map_err(|e| failed!("Failed to bind udp {} in {}: {}", addr, self.name, e)))
+    }
+
+    pub fn tcp_listener(&self, addr: SocketAddr) -> Result<std::net::TcpListener>{
+        self.run(|| std::net::TcpListener::bind(addr)
+            .map_err(|e| failed!("Failed to bind tcp {} in {}: {}", addr, self.name, e)))
+    }
+
+    /// TCP connection to `addr` opened from inside the namespace.
+    pub fn tcp_connect(&self, addr: SocketAddr, timeout: Duration) -> Result<std::net::TcpStream>{
+        self.run(|| std::net::TcpStream::connect_timeout(&addr, timeout)
+            .map_err(|e| failed!("Failed to connect to {} from {}: {}", addr, self.name, e)))
+    }
+
+    /// Like `udp_socket`, for use with tokio. Must be called within a tokio
+    /// runtime.
+    pub fn tokio_udp_socket(&self, addr: SocketAddr) -> Result<tokio::net::UdpSocket>{
+        let socket = self.udp_socket(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(tokio::net::UdpSocket::from_std(socket)?)
+    }
+
+    /// Like `tcp_listener`, for use with tokio. Must be called within a
+    /// tokio runtime.
+    pub fn tokio_tcp_listener(&self, addr: SocketAddr) -> Result<tokio::net::TcpListener>{
+        let listener = self.tcp_listener(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(tokio::net::TcpListener::from_std(listener)?)
+    }
+
+    /// Installs `route`, failing if there is a route to its destination
+    /// already. Like the other routes the namespace owns it is kept by the
+    /// topology holding the namespace, see `rib::set_route`.
+    pub fn add_route(&self, route: Route) -> Result<()>{
+        rib::add_route(&self.netns, route)?;
+        Ok(())
+    }
+
+    /// Like `add_route`, but replaces an existing route to the same
+    /// destination.
+    pub fn replace_route(&self, route: Route) -> Result<()>{
+        rib::set_route(&self.netns, route)?;
+        Ok(())
+    }
+
+    /// Removes the route to `dst` from `table`, main if None, whatever its
+    /// nexthops.
+    pub fn del_route(&self, dst: &str, table: Option<u32>) -> Result<()>{
+        rib::remove_route(&self.netns, dst, table)?;
+        Ok(())
+    }
+
+    /// Routes in the main table and numbered tables, IPv4 first, with the
+    /// kernel routes of several metrics to one destination merged into one
+    /// route. Gateways are resolved to the interfaces of `config` holding
+    /// their address. Connected routes are left out.
+    pub fn list_routes(&self, config: &Config) -> Result<Vec<Route>>{
+        self.routes(config, true)
+    }
+
+    /// Like `list_routes`, without the routes learned by routing daemons
+    /// and from router advertisements, those a `Rib` owns.
+    pub(crate) fn static_routes(&self, config: &Config) -> Result<Vec<Route>>{
+        self.routes(config, false)
+    }
+
+    fn routes(&self, config: &Config, learned: bool) -> Result<Vec<Route>>{
+        let mut routes: Vec<Route> = Vec::new();
+        for (family, v6) in [("-4", false), ("-6", true)]{
+            let installed: serde_json::Value = serde_json::from_str(&cmd::ip(Some(&self.netns), &[family, "-j", "route", "show", "table", "all"])?)?;
+            for r in installed.as_array().cloned().unwrap_or_default(){
+                if r["protocol"] == "kernel" || (!learned && daemon::learned(&r)) {
+                    continue;
+                }
+                let Some(table) = policy::route_table(&r) else {
+                    continue;
+                };
+                let dst = match (r["dst"].as_str(), v6){
+                    (Some("default"), false) => "0.0.0.0/0".to_string(),
+                    (Some("default"), true) => "::/0".to_string(),
+                    (Some(dst), _) if dst.contains('/') => dst.to_string(),
+                    (Some(dst), false) => format!("{}/32", dst),
+                    (Some(dst), true) => format!("{}/128", dst),
+                    (None, _) => continue,
+                };
+                let kind: RouteKind = r["type"].as_str().unwrap_or("unicast").parse().unwrap_or_default();
+                let multipath = r["nexthops"].as_array();
+                let nexthops = match kind{
+                    RouteKind::Unicast => multipath.cloned().unwrap_or_else(|| vec![r.clone()]),
+                    _ => Vec::new(),
+                };
+                let metric = r["metric"].as_u64().map(|m| m as u32);
+                let gateway = nexthops.iter().map(|n| nexthop(config, n, v6, metric, multipath.is_some())).collect::<Vec<Nexthop>>();
+                match routes.iter_mut().find(|o| o.dst == dst && o.table == table){
+                    Some(route) => route.gateway.extend(gateway),
+                    None => routes.push(Route{ dst, gateway, table, kind }),
+                }
+            }
+        }
+        Ok(routes)
+    }
+
+    /// Installs, or with `verb` del deletes, one kernel route per metric of
+    /// the nexthops.
+    pub(crate) fn route(&self, verb: &str, route: Route) -> Result<()>{
+        let dst: ipnet::IpNet = route.dst.parse()
+            .map_err(|e: ipnet::AddrParseError| RouterError::InvalidSubnet{ subnet: route.dst.clone(), reason: e.to_string() })?;
+        let v6 = dst.addr().is_ipv6();
+        if route.kind != RouteKind::Unicast {
+            let mut args: Vec<String> = vec![if v6 { "-6" } else { "-4" }.to_string(), "route".to_string(), verb.to_string()];
+            args.extend(route.kind_args()?);
+            let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+            cmd::ip(Some(&self.netns), &args)
+                .map_err(|e| e.context(format!("Failed to {} route to {} in {}", verb, route.dst, self.netns)))?;
+            return Ok(());
+        }
+        if route.gateway.is_empty() {
+            return Err(RouterError::Invalid(format!("Route to {} has no nexthop", route.dst)));
+        }
+        for (metric, nexthops) in route.by_metric(){
+            let mut args: Vec<String> = vec![
+                if v6 { "-6" } else { "-4" }.to_string(),
+                "route".to_string(),
+                verb.to_string(),
+                route.dst.clone(),
+            ];
+            if let Some(metric) = metric{
+                args.push("metric".to_string());
+                args.push(metric.to_string());
+            }
+            if let Some(table) = route.table{
+                args.push("table".to_string());
+                args.push(table.to_string());
+            }
+            // IPv6 takes routes without gateway, those of local SRv6
+            // segments included, only outside the multipath syntax
+            if let [n] = nexthops.as_slice() {
+                args.extend(n.args(v6, false)?);
+            } else {
+                for n in &nexthops{
+                    args.push("nexthop".to_string());
+                    args.extend(n.args(v6, true)?);
+                }
+            }
+            let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+            cmd::ip(Some(&self.netns), &args)
+                .map_err(|e| e.context(format!("Failed to {} route to {} in {}", verb, route.dst, self.netns)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Applies sysctl `settings`, `<key>=<value>`, inside `netns`. The values
+/// they replace in a namespace taken from the pool are kept for its
+/// release, see `pool::remember_sysctls`.
+pub(crate) fn sysctl<S: AsRef<str>>(netns: &str, settings: &[S]) -> Result<()>{
+    if settings.is_empty() {
+        return Ok(());
+    }
+    pool::remember_sysctls(netns, settings)?;
+    let mut args = vec!["-w"];
+    args.extend(settings.iter().map(|s| s.as_ref()));
+    cmd::exec(netns, "sysctl", &args)?;
+    Ok(())
+}
+
+/// Nexthop of a kernel route or of one of its `nexthops`, the gateway
+/// resolved to the interface of `config` holding it if there is one.
+fn nexthop(config: &Config, n: &serde_json::Value, v6: bool, metric: Option<u32>, multipath: bool) -> Nexthop {
+    // an IPv6 gateway of an IPv4 route comes as `via`
+    let address = n["gateway"].as_str().or(n["via"]["host"].as_str());
+    let via = address.and_then(|address| config.interfaces.values().find(|i| {
+        let ip = if v6 { &i.ip6 } else { &i.ip };
+        ip.as_deref().and_then(|ip| ip.split('/').next()) == Some(address)
+    }));
+    Nexthop{
+        via: via.clone(),
+        address: if via.is_some() { None } else { address.and_then(|a| a.parse().ok()) },
+        dev: n["dev"].as_str().map(|d| d.to_string()),
+        onlink: n["flags"].as_array().is_some_and(|f| f.iter().any(|f| f == "onlink")),
+        weight: if multipath { n["weight"].as_u64().map(|w| w as u32) } else { None },
+        metric,
+        seg6: (n["encap"] == "seg6").then(|| Seg6{
+            mode: if n["mode"] == "inline" { Seg6Mode::Inline } else { Seg6Mode::Encap },
+            // inline mode lists the original destination as unspecified
+            // last segment
+            segs: segs(&n["segs"]).into_iter().filter(|s| !s.is_unspecified()).collect(),
+        }),
+        seg6local: (n["encap"] == "seg6local").then(|| Seg6Local{
+            action: n["action"].as_str().unwrap_or_default().to_string(),
+            nh4: n["nh4"].as_str().and_then(|a| a.parse().ok()),
+            nh6: n["nh6"].as_str().and_then(|a| a.parse().ok()),
+            table: n["table"].as_str().or(n["vrftable"].as_str()).and_then(|t| t.parse().ok()),
+            segs: segs(&n["srh"]["segs"]).into_iter().filter(|s| !s.is_unspecified()).collect(),
+        }),
+    }
+}
+
+fn segs(segs: &serde_json::Value) -> Vec<Ipv6Addr> {
+    segs.as_array().cloned().unwrap_or_default().iter()
+        .filter_map(|s| s.as_str()?.parse().ok())
+        .collect()
+}
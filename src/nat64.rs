@@ -0,0 +1,199 @@
+//! Stateless IPv6 to IPv4 translation (NAT64) in a namespace, so IPv6-only
+//! hosts reach IPv4-only ones and transition setups are tested end to end.
+//!
+//! The namespace runs `tayga` on a tun device `<namespace>_nat64`. IPv6
+//! packets to an address in the `/96` prefix leave the translator towards
+//! the IPv4 address in its last 32 bits, sourced from an address of the
+//! IPv4 pool tayga maps the IPv6 host to. The device holds the last
+//! address of the pool and the matching address of the prefix, so both
+//! are connected routes, announced in OSPF like the loopback and reached
+//! by `auto_routes` like any link subnet.
+//!
+//! With `dns serve --dns64 <prefix>` the DNS server answers AAAA queries
+//! for names with only IPv4 addresses with these mapped into the prefix
+//! (DNS64), so an IPv6-only host resolves IPv4-only ones.
+//!
+//! tayga runs with its config in a runtime directory next to those of the
+//! routing daemons and is stopped with the topology.
+
+use std::fmt::Write;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use ipnet::{Ipv4Net, Ipv6Net};
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::daemon;
+use crate::error::{failed, Result};
+use crate::interface;
+use crate::logs;
+use crate::state::STATE_DIR;
+use crate::transaction::Resource;
+use crate::{Config, Interface, Namespace};
+
+/// Prefix if not set, from the local-use `64:ff9b:1::/48` as tayga
+/// doesn't translate private IPv4 addresses with the well-known one.
+pub const DEFAULT_PREFIX: &str = "64:ff9b:1::/96";
+/// Pool if not set.
+pub const DEFAULT_POOL: &str = "192.168.255.0/24";
+
+/// NAT64 translator of a namespace.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Nat64Spec{
+    /// `/96` IPv6 prefix IPv4 addresses are mapped into, `DEFAULT_PREFIX`
+    /// if not set
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /// IPv4 addresses translated IPv6 hosts are given, `DEFAULT_POOL` if
+    /// not set
+    #[serde(default)]
+    pub pool: Option<String>,
+}
+
+impl Nat64Spec{
+    pub fn prefix(&self) -> Result<Ipv6Net>{
+        let prefix = self.prefix.as_deref().unwrap_or(DEFAULT_PREFIX);
+        let net: Ipv6Net = prefix.parse()
+            .map_err(|e| failed!("Invalid NAT64 prefix {}: {}", prefix, e))?;
+        if net.prefix_len() != 96 {
+            return Err(failed!("NAT64 prefix {} is not a /96", prefix));
+        }
+        Ok(net.trunc())
+    }
+
+    pub fn pool(&self) -> Result<Ipv4Net>{
+        let pool = self.pool.as_deref().unwrap_or(DEFAULT_POOL);
+        let net: Ipv4Net = pool.parse()
+            .map_err(|e| failed!("Invalid NAT64 pool {}: {}", pool, e))?;
+        if net.prefix_len() > 29 {
+            return Err(failed!("NAT64 pool {} is smaller than a /29", pool));
+        }
+        Ok(net.trunc())
+    }
+
+    pub fn check(&self) -> Result<()>{
+        self.prefix()?;
+        self.pool()?;
+        Ok(())
+    }
+
+    /// (IPv4, IPv6) address of the tun device: the last address of the
+    /// pool and its mapping into the prefix.
+    pub fn addresses(&self) -> Result<(String, String)>{
+        let (prefix, pool) = (self.prefix()?, self.pool()?);
+        let ip = Ipv4Addr::from(u32::from(pool.broadcast()) - 1);
+        Ok((format!("{}/{}", ip, pool.prefix_len()), format!("{}/{}", map(prefix, ip), prefix.prefix_len())))
+    }
+
+    /// tayga config translating on `device`, keeping its mappings in `dir`.
+    pub fn tayga_config(&self, device: &str, dir: &std::path::Path) -> Result<String>{
+        let (prefix, pool) = (self.prefix()?, self.pool()?);
+        let mut s = String::new();
+        let _ = writeln!(s, "tun-device {}", device);
+        // the address tayga answers ICMP from, never handed out
+        let _ = writeln!(s, "ipv4-addr {}", Ipv4Addr::from(u32::from(pool.network()) + 1));
+        let _ = writeln!(s, "prefix {}", prefix);
+        let _ = writeln!(s, "dynamic-pool {}", pool);
+        let _ = writeln!(s, "data-dir {}", dir.display());
+        Ok(s)
+    }
+}
+
+/// `ip` mapped into `prefix`, in its last 32 bits.
+pub fn map(prefix: Ipv6Net, ip: Ipv4Addr) -> Ipv6Addr {
+    Ipv6Addr::from(u128::from(prefix.network()) | u32::from(ip) as u128)
+}
+
+/// Name of the tun device of `namespace`.
+pub fn name(namespace: &str) -> String {
+    interface::name(namespace, "nat64")
+}
+
+/// Creates the tun device of `ns` and addresses it. When reconciling, an
+/// existing device is kept.
+pub fn create(ns: Arc<Namespace>, spec: &Nat64Spec, config: &Config) -> Result<Arc<Interface>>{
+    let name = name(&ns.name);
+    let (ip, ip6) = spec.addresses()?;
+    if !(config.reconcile && ns.has_link(&name)?) {
+        cmd::ip(Some(&ns.netns), &["tuntap", "add", "dev", name.as_str(), "mode", "tun"])
+            .map_err(|e| e.context(format!("Failed to create NAT64 device {}", name)))?;
+        config.transaction().record(Resource::Device{ name: name.clone(), netns: ns.netns.clone() });
+    }
+    interface::alias(&name, Some(&ns.netns), &format!("{}_nat64", ns.name), config)?;
+    Interface::new(name, Some(ns), Some(ip), Some(ip6), None, config)
+}
+
+/// tayga of the namespace `netns`.
+pub struct Translator{
+    pub topology: String,
+    pub netns: String,
+    /// config, pid file and mappings
+    pub dir: PathBuf,
+}
+
+impl Translator{
+    pub fn new(topology: &str, namespace: &str, netns: &str) -> Translator {
+        Translator{
+            topology: topology.to_string(),
+            netns: netns.to_string(),
+            dir: Translator::dir(topology, namespace),
+        }
+    }
+
+    /// Runtime directory of the tayga of `namespace` of `topology`.
+    pub fn dir(topology: &str, namespace: &str) -> PathBuf {
+        PathBuf::from(STATE_DIR).join(topology).join(format!("nat64-{}", namespace))
+    }
+
+    /// Namespaces of `topology` with a tayga directory.
+    pub fn list(topology: &str) -> Result<Vec<String>>{
+        let dir = PathBuf::from(STATE_DIR).join(topology);
+        let mut namespaces = Vec::new();
+        if !dir.exists() {
+            return Ok(namespaces);
+        }
+        for entry in std::fs::read_dir(dir)?{
+            if let Some(ns) = entry?.file_name().to_str().and_then(|n| n.strip_prefix("nat64-")){
+                namespaces.push(ns.to_string());
+            }
+        }
+        Ok(namespaces)
+    }
+
+    /// Runs tayga with `config` unless it runs already with the same one.
+    /// Returns true if it was started.
+    pub fn start(&self, config: &str) -> Result<bool>{
+        // not .conf, which marks the directory of a routing daemon
+        let path = self.dir.join("tayga.cfg");
+        if self.running() && std::fs::read_to_string(&path).ok().as_deref() == Some(config) {
+            return Ok(false);
+        }
+        daemon::stop_dir(&self.dir)?;
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(&path, config)?;
+        let args: Vec<String> = vec![
+            "tayga".to_string(),
+            "--nodetach".to_string(),
+            "--config".to_string(),
+            path.to_string_lossy().to_string(),
+        ];
+        daemon::spawn(&self.topology, &self.netns, &self.dir, "tayga", &args)?;
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        if !self.running() {
+            let tail = logs::tail(&logs::path(&self.topology, &self.netns, "tayga"), 5);
+            let _ = daemon::stop_dir(&self.dir);
+            return Err(failed!("tayga in {} exited: {}", self.netns, tail));
+        }
+        Ok(true)
+    }
+
+    pub fn stop(&self) -> Result<()>{
+        daemon::stop_dir(&self.dir)
+    }
+
+    fn running(&self) -> bool {
+        daemon::pid(&self.dir.join("tayga.pid")).is_some_and(daemon::alive)
+    }
+}
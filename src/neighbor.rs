@@ -0,0 +1,148 @@
+//! Neighbor cache tuning, for ARP and NDP alike, so neighbor cache
+//! exhaustion and churn are reproduced on purpose.
+//!
+//! Reachability timers are set per namespace, on each of its interfaces.
+//! The garbage collection thresholds belong to the kernel's neighbor
+//! tables, which all namespaces of the host share, and exist only in the
+//! host namespace: they are set for the whole topology, the host's values
+//! kept next to its state and put back when it is destroyed.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{failed, Result};
+use crate::state::STATE_DIR;
+
+/// Neighbor timers of a namespace, the kernel's own where not set.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct NeighborSpec{
+    /// an entry stays REACHABLE for a random half to one and a half times
+    /// this after it was confirmed
+    #[serde(default)]
+    pub base_reachable_time_ms: Option<u32>,
+    /// seconds after which an unused STALE entry may be collected
+    #[serde(default)]
+    pub gc_stale_time: Option<u32>,
+    /// seconds a STALE entry in use waits before it is probed
+    #[serde(default)]
+    pub delay_first_probe_time: Option<u32>,
+    /// packets queued per unresolved neighbor
+    #[serde(default)]
+    pub unres_qlen: Option<u32>,
+}
+
+impl NeighborSpec{
+    /// sysctl settings for IPv4 and IPv6 on `interfaces`.
+    pub fn sysctls(&self, interfaces: &[String]) -> Vec<String> {
+        let timers = [
+            ("base_reachable_time_ms", self.base_reachable_time_ms),
+            ("gc_stale_time", self.gc_stale_time),
+            ("delay_first_probe_time", self.delay_first_probe_time),
+            ("unres_qlen", self.unres_qlen),
+        ];
+        let mut settings = Vec::new();
+        for family in ["ipv4", "ipv6"]{
+            for dev in interfaces{
+                for (key, value) in timers{
+                    if let Some(v) = value{
+                        settings.push(format!("net.{}.neigh.{}.{}={}", family, dev, key, v));
+                    }
+                }
+            }
+        }
+        settings
+    }
+}
+
+/// Garbage collection thresholds of the host's neighbor tables, counting
+/// the entries of all namespaces.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct NeighborGc{
+    /// entries below which garbage collection leaves the table alone
+    #[serde(default)]
+    pub gc_thresh1: Option<u32>,
+    /// soft limit, exceeded for at most 5 seconds
+    #[serde(default)]
+    pub gc_thresh2: Option<u32>,
+    /// hard limit, neighbors beyond it aren't resolved
+    #[serde(default)]
+    pub gc_thresh3: Option<u32>,
+}
+
+impl NeighborGc{
+    /// (sysctl path, value) of the thresholds set.
+    pub fn settings(&self) -> Vec<(String, u32)> {
+        let mut settings = Vec::new();
+        for family in ["ipv4", "ipv6"]{
+            for (key, value) in [("gc_thresh1", self.gc_thresh1), ("gc_thresh2", self.gc_thresh2), ("gc_thresh3", self.gc_thresh3)]{
+                if let Some(v) = value{
+                    settings.push((format!("net/{}/neigh/default/{}", family, key), v));
+                }
+            }
+        }
+        settings
+    }
+
+    /// Sets the thresholds on the host for `topology`, keeping the host's
+    /// values unless they were kept before.
+    pub fn apply(&self, topology: &str) -> Result<()>{
+        let settings = self.settings();
+        let saved = saved_path(topology);
+        if !saved.exists() {
+            // all of them, a later reconcile may set others
+            let mut host = Vec::new();
+            for family in ["ipv4", "ipv6"]{
+                for key in ["gc_thresh1", "gc_thresh2", "gc_thresh3"]{
+                    let key = format!("net/{}/neigh/default/{}", family, key);
+                    host.push((key.clone(), read(&key)?));
+                }
+            }
+            std::fs::create_dir_all(STATE_DIR)?;
+            std::fs::write(&saved, serde_json::to_string(&host)?)?;
+        }
+        for (key, value) in &settings{
+            write(key, *value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Puts back the host's thresholds kept by `NeighborGc::apply` for
+/// `topology`, if any.
+pub fn restore(topology: &str) -> Result<()>{
+    let saved = saved_path(topology);
+    let Ok(data) = std::fs::read_to_string(&saved) else {
+        return Ok(());
+    };
+    let host: Vec<(String, u32)> = serde_json::from_str(&data)
+        .map_err(|e| failed!("Failed to parse {}: {}", saved.display(), e))?;
+    for (key, value) in host{
+        write(&key, value)?;
+    }
+    std::fs::remove_file(saved)?;
+    Ok(())
+}
+
+/// Whether the host's thresholds are kept for `topology`, i.e. it has set
+/// its own.
+pub fn saved(topology: &str) -> bool {
+    saved_path(topology).exists()
+}
+
+fn saved_path(topology: &str) -> PathBuf {
+    PathBuf::from(STATE_DIR).join(format!("{}.neighbor", topology))
+}
+
+fn read(key: &str) -> Result<u32>{
+    let path = PathBuf::from("/proc/sys").join(key);
+    let value = std::fs::read_to_string(&path)
+        .map_err(|e| failed!("Failed to read {}: {}", path.display(), e))?;
+    value.trim().parse().map_err(|e| failed!("Invalid value {} of {}: {}", value.trim(), path.display(), e))
+}
+
+fn write(key: &str, value: u32) -> Result<()>{
+    let path = PathBuf::from("/proc/sys").join(key);
+    std::fs::write(&path, value.to_string())
+        .map_err(|e| failed!("Failed to set {}: {}", path.display(), e))
+}
@@ -0,0 +1,123 @@
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::clock;
+use crate::error::{failed, Result};
+use crate::trace::Traced;
+
+/// Moves the calling thread into the named network namespace. Only the
+/// calling thread is affected, sockets it opens afterwards live in `netns`.
+pub fn enter(netns: &str) -> Result<()>{
+    let path = format!("/run/netns/{}", netns);
+    let file = File::open(&path)
+        .map_err(|e| failed!("Failed to open namespace {}: {}", path, e))?;
+    if unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+        return Err(failed!("Failed to enter namespace {}: {}", netns, std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Runs `f` on a new thread which has joined `netns` before `f` starts.
+pub fn spawn_in<F, T>(netns: &str, f: F) -> JoinHandle<Result<T>>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let netns = netns.to_string();
+    std::thread::spawn(move || {
+        enter(&netns)?;
+        f()
+    })
+}
+
+/// Runs `f` inside `netns` and returns its result. `f` runs on a scoped
+/// thread, so unlike with `spawn_in` it may borrow from the caller, and the
+/// caller's own namespace is never touched.
+pub fn run_in<F, T>(netns: &str, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send,
+    T: Send,
+{
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            enter(netns)?;
+            f()
+        }).join().map_err(|_| failed!("Closure in namespace {} panicked", netns))?
+    })
+}
+
+/// Runs `send` in `src` against `receive` in `dst`, e.g. a probe and its
+/// receiver. `receive` signals `ready` once it listens, `send` only starts
+/// then, and returns when `done` is set, which happens `linger` after
+/// `send` returned so packets still in flight arrive.
+pub fn send_receive<S, R, T, U>(src: &str, dst: &str, linger: Duration, send: S, receive: R) -> Result<(T, U)>
+where
+    S: FnOnce() -> Result<T> + Send + 'static,
+    R: FnOnce(Arc<AtomicBool>, mpsc::Sender<()>) -> Result<U> + Send + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let done = Arc::new(AtomicBool::new(false));
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let receiver = {
+        let done = done.clone();
+        spawn_in(dst, move || receive(done, ready_tx))
+    };
+    if ready_rx.recv_timeout(Duration::from_secs(5)).is_err() {
+        done.store(true, Ordering::SeqCst);
+        return match receiver.join(){
+            Ok(Err(e)) => Err(e),
+            _ => Err(failed!("Receiver in {} did not start", dst)),
+        };
+    }
+    let sent = spawn_in(src, send).join().map_err(|_| failed!("Sender thread panicked"));
+    std::thread::sleep(linger);
+    done.store(true, Ordering::SeqCst);
+    let received = receiver.join().map_err(|_| failed!("Receiver thread panicked"))??;
+    Ok((sent??, received))
+}
+
+/// `program` prepared to run inside `netns` with `ip netns exec`, which
+/// also bind-mounts the files in `/etc/netns/<netns>`. In a namespace with
+/// clock offsets it runs in a time namespace carrying them, see `clock`.
+pub fn command(netns: &str, program: &str) -> Command {
+    if let Some(skew) = clock::skew(netns) {
+        return skew.command(netns, program);
+    }
+    let mut cmd = Command::new("ip");
+    cmd.arg("netns").arg("exec").arg(netns).arg(program);
+    cmd
+}
+
+/// Captured result of a program run with `exec`.
+#[derive(Clone, Debug, Default)]
+pub struct ExecOutput{
+    /// exit code, None if the program was killed by a signal
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl ExecOutput{
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// Runs `program` with `args` inside `netns`, waits for it and returns its
+/// exit code and output. A program that ran and failed is not an error,
+/// one that couldn't be started is.
+pub fn exec(netns: &str, program: &str, args: &[&str]) -> Result<ExecOutput>{
+    let output = command(netns, program).args(args).traced_output()
+        .map_err(|e| failed!("Failed to run {} in {}: {}", program, netns, e))?;
+    Ok(ExecOutput{
+        code: output.status.code(),
+        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+        stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+    })
+}
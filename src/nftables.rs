@@ -0,0 +1,66 @@
+//! Snapshot and restore of the nftables ruleset of namespaces. A restore
+//! flushes and loads the ruleset in a single nft transaction, so a namespace
+//! never runs with half a ruleset.
+
+use std::path::{Path, PathBuf};
+
+use crate::{cmd, netns, state, Namespace};
+use crate::error::{failed, Result, RouterError};
+
+/// Returns the full ruleset of `netns` in nft syntax.
+pub fn save(netns: &str) -> Result<String>{
+    cmd::exec(netns, "nft", &["list", "ruleset"])
+        .map_err(|e| e.context(format!("Failed to save nftables ruleset of {}", netns)))
+}
+
+/// Replaces the ruleset of `netns` with `ruleset`.
+pub fn restore(netns: &str, ruleset: &str) -> Result<()>{
+    cmd::with_input(netns::command(netns, "nft").args(["-f", "-"]), &format!("flush ruleset\n{}", ruleset))
+        .map_err(|e| e.context(format!("Failed to restore nftables ruleset of {}", netns)))?;
+    Ok(())
+}
+
+/// Writes the ruleset of every namespace of `topology` to `<dir>/<ns>.nft`,
+/// named by the namespace's name within the topology so the snapshot can
+/// be restored into a copy under another name.
+pub fn save_topology(topology: &str, dir: &Path) -> Result<Vec<PathBuf>>{
+    let namespaces = state::namespaces(topology)?;
+    if namespaces.is_empty() {
+        return Err(RouterError::TopologyNotFound(topology.to_string()));
+    }
+    std::fs::create_dir_all(dir)?;
+    let prefix = Namespace::netns_name(topology, "");
+    let mut files = Vec::new();
+    for netns in namespaces{
+        let ns = netns.strip_prefix(prefix.as_str()).unwrap_or(&netns);
+        let path = dir.join(format!("{}.nft", ns));
+        std::fs::write(&path, save(&netns)?)?;
+        files.push(path);
+    }
+    Ok(files)
+}
+
+/// Loads every `<dir>/<ns>.nft` into namespace `ns` of `topology`.
+pub fn restore_topology(topology: &str, dir: &Path) -> Result<()>{
+    let mut found = false;
+    for entry in std::fs::read_dir(dir)?{
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("nft") {
+            continue;
+        }
+        let ns = match path.file_stem().and_then(|s| s.to_str()){
+            Some(ns) => ns,
+            None => continue,
+        };
+        let netns = Namespace::netns_name(topology, ns);
+        if !Path::new("/run/netns").join(&netns).exists() {
+            return Err(failed!("Namespace {} of snapshot {} not found", netns, path.display()));
+        }
+        restore(&netns, &std::fs::read_to_string(&path)?)?;
+        found = true;
+    }
+    if !found {
+        return Err(failed!("No rulesets found in {}", dir.display()));
+    }
+    Ok(())
+}
@@ -0,0 +1,282 @@
+//! Async API for programs running a tokio runtime, e.g. a controller
+//! driving several topologies while it serves gRPC, watches routes and
+//! probes paths, so none of that waits on the others.
+//!
+//! Operations on a running topology, links going down or up and programs
+//! run in a namespace, run their `ip` commands as tokio processes and take
+//! no thread while they wait. Building, reconciling and destroying a
+//! topology, the connectivity checks of `verify` and route changes, which
+//! go through the RIB of the namespace and the saved state, see
+//! `rib::set_route`, use the blocking API on tokio's blocking threads, one
+//! each, so checks run concurrently. Route changes come as a stream fed
+//! by a `RouteMonitor`, see `monitor`.
+//!
+//! ```no_run
+//! use futures::StreamExt;
+//! use router_rs::nonblocking::AsyncTopology;
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let lab = AsyncTopology::open("lab").await?;
+//! let mut events = lab.route_events(&["r1", "r2"]).await?;
+//! lab.set_link("r1r2", false).await?;
+//! while let Some(event) = events.next().await {
+//!     println!("{}", event?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use futures::future::join_all;
+use ipnet::IpNet;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::error::{failed, Result, RouterError};
+use crate::monitor::{RouteEntry, RouteEvent, RouteMonitor};
+use crate::netns::{self, ExecOutput};
+use crate::rib;
+use crate::state::State;
+use crate::topology::Topology;
+use crate::trace::TracedAsync;
+use crate::verify::{self, CheckResult, CheckSpec, VerifyOptions};
+use crate::{chaos, Config, Namespace, Nexthop, Route, RouteKind};
+
+/// How often the thread feeding a route stream checks whether the stream
+/// was dropped.
+const POLL: Duration = Duration::from_millis(100);
+
+/// Route changes, ending with an error if the monitor failed.
+pub type RouteEvents = ReceiverStream<Result<RouteEvent>>;
+
+/// Running topology.
+#[derive(Clone, Debug)]
+pub struct AsyncTopology{
+    name: String,
+}
+
+impl AsyncTopology{
+    /// Creates `topology` starting from `config`, see
+    /// `Topology::apply_with`.
+    pub async fn apply(topology: Topology, config: Config) -> Result<AsyncTopology>{
+        let name = topology.name.clone();
+        blocking(move || topology.apply_with(config).map(|_| ())).await?;
+        Ok(AsyncTopology{ name })
+    }
+
+    /// Brings the running topology in line with `topology`, see
+    /// `Topology::reconcile_with`.
+    pub async fn reconcile(topology: Topology, config: Config) -> Result<AsyncTopology>{
+        let name = topology.name.clone();
+        blocking(move || topology.reconcile_with(config).map(|_| ())).await?;
+        Ok(AsyncTopology{ name })
+    }
+
+    /// Topology `name` as it runs.
+    pub async fn open(name: &str) -> Result<AsyncTopology>{
+        let topology = AsyncTopology{ name: name.to_string() };
+        topology.state().await?;
+        Ok(topology)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub async fn state(&self) -> Result<State>{
+        let name = self.name.clone();
+        blocking(move || State::load(&name)?.ok_or_else(|| RouterError::TopologyNotFound(name.to_string()))).await
+    }
+
+    pub async fn destroy(self) -> Result<()>{
+        blocking(move || Topology::destroy(&self.name)).await
+    }
+
+    /// Namespace by its name in the topology.
+    pub fn namespace(&self, name: &str) -> AsyncNamespace {
+        AsyncNamespace{ netns: Namespace::netns_name(&self.name, name) }
+    }
+
+    /// Takes both ends of `link` down, or brings them up.
+    pub async fn set_link(&self, link: &str, up: bool) -> Result<()>{
+        let ends: Vec<(AsyncNamespace, String)> = chaos::link_ends(&self.state().await?, link)?.into_iter()
+            .map(|(netns, interface)| (AsyncNamespace{ netns }, interface))
+            .collect();
+        let results = join_all(ends.iter().map(|(ns, interface)| ns.set_interface(interface, up))).await;
+        results.into_iter().collect::<Result<Vec<()>>>()
+            .map_err(|e| failed!("Link {}: {}", link, e))?;
+        Ok(())
+    }
+
+    /// Runs `checks` against the topology, all at once.
+    pub async fn verify(&self, checks: &[CheckSpec], options: &VerifyOptions) -> Result<Vec<CheckResult>>{
+        let state = self.state().await?;
+        let results = join_all(checks.iter().map(|c| {
+            let (state, spec, options) = (state.clone(), c.clone(), options.clone());
+            blocking(move || Ok(verify::check(&state, &spec, &options)))
+        })).await;
+        results.into_iter().collect()
+    }
+
+    /// Route changes in `namespaces`, once all of them are watched. The
+    /// monitor stops when the stream is dropped.
+    pub async fn route_events(&self, namespaces: &[&str]) -> Result<RouteEvents>{
+        let netns: Vec<String> = namespaces.iter().map(|n| Namespace::netns_name(&self.name, n)).collect();
+        route_events(netns).await
+    }
+}
+
+/// Route changes in the namespaces `netns`, kernel names, see
+/// `AsyncTopology::route_events`.
+pub async fn route_events(netns: Vec<String>) -> Result<RouteEvents>{
+    let (tx, rx) = mpsc::channel(1024);
+    let (started_tx, started) = tokio::sync::oneshot::channel();
+    // the monitor's threads block on their sockets, it is fed from a
+    // thread of its own rather than one of the runtime's
+    std::thread::spawn(move || {
+        let mut monitor = match RouteMonitor::start(&netns){
+            Ok(monitor) => {
+                let _ = started_tx.send(Ok(()));
+                monitor
+            },
+            Err(e) => {
+                let _ = started_tx.send(Err(e));
+                return;
+            },
+        };
+        while !tx.is_closed() {
+            match monitor.next(POLL){
+                Ok(Some(event)) => {
+                    if tx.blocking_send(Ok(event)).is_err() {
+                        return;
+                    }
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    let _ = tx.blocking_send(Err(e));
+                    return;
+                },
+            }
+        }
+    });
+    started.await.map_err(|_| failed!("Route monitor ended before it started"))??;
+    Ok(ReceiverStream::new(rx))
+}
+
+/// Namespace of a running topology.
+#[derive(Clone, Debug)]
+pub struct AsyncNamespace{
+    /// kernel name, `<topology>-<name>`
+    pub netns: String,
+}
+
+impl AsyncNamespace{
+    /// Runs `program` with `args` inside the namespace, see `netns::exec`.
+    pub async fn exec(&self, program: &str, args: &[&str]) -> Result<ExecOutput>{
+        let mut cmd = tokio::process::Command::from(netns::command(&self.netns, program));
+        let output = cmd.args(args).kill_on_drop(true).traced_output().await
+            .map_err(|e| failed!("Failed to run {} in {}: {}", program, self.netns, e))?;
+        Ok(ExecOutput{
+            code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        })
+    }
+
+    /// Runs `ip -n <netns>` with `args`, returns its output.
+    pub async fn ip(&self, args: &[&str]) -> Result<String>{
+        let mut cmd = tokio::process::Command::new("ip");
+        cmd.arg("-n").arg(&self.netns).args(args).kill_on_drop(true);
+        let output = cmd.traced_output().await?;
+        if !output.status.success() {
+            return Err(RouterError::command(cmd.as_std(), &output));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    pub async fn set_interface(&self, interface: &str, up: bool) -> Result<()>{
+        self.ip(&["link", "set", "dev", interface, if up { "up" } else { "down" }]).await?;
+        Ok(())
+    }
+
+    /// Routes of all tables, multipath routes with one entry per nexthop
+    /// as `RouteMonitor` has them.
+    pub async fn routes(&self) -> Result<Vec<RouteEntry>>{
+        let mut routes = Vec::new();
+        for family in ["-4", "-6"]{
+            let out = self.ip(&[family, "-j", "route", "show", "table", "all"]).await?;
+            let entries: Vec<serde_json::Value> = serde_json::from_str(&out)?;
+            routes.extend(entries.iter().flat_map(|r| route(r, family == "-6")));
+        }
+        Ok(routes)
+    }
+
+    /// Adds or replaces the route to `prefix` over `via`, `dev` or both,
+    /// kept by reconciling like the routes of the description.
+    pub async fn replace_route(&self, prefix: IpNet, via: Option<IpAddr>, dev: Option<&str>) -> Result<()>{
+        if via.is_none() && dev.is_none() {
+            return Err(failed!("Route to {} needs a gateway or a device", prefix));
+        }
+        let route = Route{
+            dst: prefix.to_string(),
+            gateway: vec![Nexthop{ address: via, dev: dev.map(|d| d.to_string()), ..Default::default() }],
+            table: None,
+            kind: RouteKind::Unicast,
+        };
+        let netns = self.netns.clone();
+        blocking(move || rib::set_route(&netns, route)).await?;
+        Ok(())
+    }
+
+    pub async fn delete_route(&self, prefix: IpNet) -> Result<()>{
+        let netns = self.netns.clone();
+        blocking(move || rib::remove_route(&netns, &prefix.to_string(), None)).await?;
+        Ok(())
+    }
+}
+
+/// Entries of a route of `ip -j route`, none for routes to other than a
+/// prefix, e.g. unreachable ones, which have no nexthop.
+fn route(r: &serde_json::Value, v6: bool) -> Vec<RouteEntry> {
+    let prefix = match r["dst"].as_str(){
+        Some("default") | None => Some(IpNet::new(if v6 { IpAddr::from([0u16; 8]) } else { IpAddr::from([0u8; 4]) }, 0).expect("prefix length 0 is valid")),
+        Some(dst) => dst.parse().ok().or_else(|| dst.parse::<IpAddr>().ok().map(IpNet::from)),
+    };
+    let Some(prefix) = prefix else {
+        return Vec::new();
+    };
+    let table = match &r["table"]{
+        serde_json::Value::Null => libc::RT_TABLE_MAIN as u32,
+        serde_json::Value::String(t) => match t.as_str(){
+            "main" => libc::RT_TABLE_MAIN as u32,
+            "local" => libc::RT_TABLE_LOCAL as u32,
+            "default" => libc::RT_TABLE_DEFAULT as u32,
+            t => t.parse().unwrap_or(0),
+        },
+        t => t.as_u64().unwrap_or(0) as u32,
+    };
+    let metric = r["metric"].as_u64().unwrap_or(0) as u32;
+    let entry = |nh: &serde_json::Value| RouteEntry{
+        prefix,
+        table,
+        metric,
+        gateway: nh["gateway"].as_str().and_then(|g| g.parse().ok()),
+        dev: nh["dev"].as_str().map(|d| d.to_string()),
+    };
+    match r["nexthops"].as_array(){
+        Some(nexthops) => nexthops.iter().map(entry).collect(),
+        None => vec![entry(r)],
+    }
+}
+
+/// Runs `f`, which blocks on the host, on a blocking thread of the
+/// runtime.
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await.map_err(|e| failed!("{}", e))?
+}
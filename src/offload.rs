@@ -0,0 +1,211 @@
+//! Segmentation, receive and checksum offloads of interfaces. veths
+//! offload them all by default, so the stack hands them 64 KB super-frames
+//! which netem and tbf delay, drop and rate-limit as one packet, and
+//! captures show frames longer than the mtu with checksums never filled
+//! in. Turning them off makes shaping and captures see the packets a real
+//! wire would carry.
+//!
+//! Settings are made with the ethtool ioctls from a thread inside the
+//! namespace, no `ethtool` binary needed. Turning off a checksum offload
+//! also turns off TSO, which depends on it.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{failed, Result, RouterError};
+use crate::netns;
+
+const SIOCETHTOOL: libc::c_ulong = 0x8946;
+const ETHTOOL_GRXCSUM: u32 = 0x14;
+const ETHTOOL_SRXCSUM: u32 = 0x15;
+const ETHTOOL_GTXCSUM: u32 = 0x16;
+const ETHTOOL_STXCSUM: u32 = 0x17;
+const ETHTOOL_GTSO: u32 = 0x1e;
+const ETHTOOL_STSO: u32 = 0x1f;
+const ETHTOOL_GGSO: u32 = 0x23;
+const ETHTOOL_SGSO: u32 = 0x24;
+const ETHTOOL_GGRO: u32 = 0x2b;
+const ETHTOOL_SGRO: u32 = 0x2c;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Offload{
+    /// TCP segmentation offload
+    Tso,
+    /// generic segmentation offload
+    Gso,
+    /// generic receive offload
+    Gro,
+    TxChecksum,
+    RxChecksum,
+}
+
+impl Offload{
+    pub const ALL: [Offload; 5] = [Offload::Tso, Offload::Gso, Offload::Gro, Offload::TxChecksum, Offload::RxChecksum];
+
+    /// (get, set) ethtool commands
+    fn commands(&self) -> (u32, u32) {
+        match self{
+            Offload::Tso => (ETHTOOL_GTSO, ETHTOOL_STSO),
+            Offload::Gso => (ETHTOOL_GGSO, ETHTOOL_SGSO),
+            Offload::Gro => (ETHTOOL_GGRO, ETHTOOL_SGRO),
+            Offload::TxChecksum => (ETHTOOL_GTXCSUM, ETHTOOL_STXCSUM),
+            Offload::RxChecksum => (ETHTOOL_GRXCSUM, ETHTOOL_SRXCSUM),
+        }
+    }
+}
+
+impl FromStr for Offload{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<Self>{
+        match s{
+            "tso" => Ok(Offload::Tso),
+            "gso" => Ok(Offload::Gso),
+            "gro" => Ok(Offload::Gro),
+            "tx-checksum" => Ok(Offload::TxChecksum),
+            "rx-checksum" => Ok(Offload::RxChecksum),
+            _ => Err(RouterError::Invalid(format!("Unknown offload {}, expected tso, gso, gro, tx-checksum or rx-checksum", s))),
+        }
+    }
+}
+
+impl fmt::Display for Offload{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Offload::Tso => write!(f, "tso"),
+            Offload::Gso => write!(f, "gso"),
+            Offload::Gro => write!(f, "gro"),
+            Offload::TxChecksum => write!(f, "tx-checksum"),
+            Offload::RxChecksum => write!(f, "rx-checksum"),
+        }
+    }
+}
+
+/// Offloads of an interface, unset ones are left alone.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct OffloadSpec{
+    #[serde(default)]
+    pub tso: Option<bool>,
+    #[serde(default)]
+    pub gso: Option<bool>,
+    #[serde(default)]
+    pub gro: Option<bool>,
+    #[serde(default)]
+    pub tx_checksum: Option<bool>,
+    #[serde(default)]
+    pub rx_checksum: Option<bool>,
+}
+
+impl OffloadSpec{
+    /// Every offload turned off.
+    pub fn off() -> OffloadSpec {
+        OffloadSpec{ tso: Some(false), gso: Some(false), gro: Some(false), tx_checksum: Some(false), rx_checksum: Some(false) }
+    }
+
+    /// `self` with every field set in `over` replaced.
+    pub fn merge(&self, over: &OffloadSpec) -> OffloadSpec {
+        OffloadSpec{
+            tso: over.tso.or(self.tso),
+            gso: over.gso.or(self.gso),
+            gro: over.gro.or(self.gro),
+            tx_checksum: over.tx_checksum.or(self.tx_checksum),
+            rx_checksum: over.rx_checksum.or(self.rx_checksum),
+        }
+    }
+
+    /// Offloads set, checksums first so TSO turned on after them sticks.
+    pub fn settings(&self) -> Vec<(Offload, bool)> {
+        [
+            (Offload::TxChecksum, self.tx_checksum),
+            (Offload::RxChecksum, self.rx_checksum),
+            (Offload::Tso, self.tso),
+            (Offload::Gso, self.gso),
+            (Offload::Gro, self.gro),
+        ].into_iter().filter_map(|(o, on)| Some((o, on?))).collect()
+    }
+
+    /// Applies the settings to `interface` in `netns`.
+    pub fn apply(&self, netns: &str, interface: &str) -> Result<()>{
+        set(netns, interface, &self.settings())
+    }
+}
+
+/// Turns each offload of `interface` in `netns` on or off.
+pub fn set(netns: &str, interface: &str, settings: &[(Offload, bool)]) -> Result<()>{
+    if settings.is_empty() {
+        return Ok(());
+    }
+    netns::run_in(netns, || {
+        let socket = Socket::new()?;
+        for (offload, on) in settings{
+            socket.ethtool(interface, offload.commands().1, *on as u32)
+                .map_err(|e| failed!("Failed to turn {} {} on {}: {}", offload, if *on { "on" } else { "off" }, interface, e))?;
+        }
+        Ok(())
+    })
+}
+
+/// Whether each offload of `interface` in `netns` is on.
+pub fn get(netns: &str, interface: &str) -> Result<Vec<(Offload, bool)>>{
+    netns::run_in(netns, || {
+        let socket = Socket::new()?;
+        Offload::ALL.iter()
+            .map(|offload| {
+                let on = socket.ethtool(interface, offload.commands().0, 0)
+                    .map_err(|e| failed!("Failed to read {} of {}: {}", offload, interface, e))?;
+                Ok((*offload, on != 0))
+            })
+            .collect()
+    })
+}
+
+#[repr(C)]
+struct EthtoolValue{
+    cmd: u32,
+    data: u32,
+}
+
+#[repr(C)]
+struct IfReq{
+    name: [libc::c_char; libc::IFNAMSIZ],
+    data: *mut libc::c_void,
+    // rest of the ifreq union
+    _pad: [u8; 16],
+}
+
+struct Socket(libc::c_int);
+
+impl Socket{
+    fn new() -> Result<Socket>{
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(failed!("Failed to open socket: {}", std::io::Error::last_os_error()));
+        }
+        Ok(Socket(fd))
+    }
+
+    /// Runs ethtool command `cmd` with `data` on `interface`, returns the
+    /// data the kernel put back.
+    fn ethtool(&self, interface: &str, cmd: u32, data: u32) -> std::io::Result<u32>{
+        if interface.len() >= libc::IFNAMSIZ {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "interface name too long"));
+        }
+        let mut value = EthtoolValue{ cmd, data };
+        let mut req = IfReq{ name: [0; libc::IFNAMSIZ], data: &mut value as *mut EthtoolValue as *mut libc::c_void, _pad: [0; 16] };
+        for (dst, src) in req.name.iter_mut().zip(interface.bytes()){
+            *dst = src as libc::c_char;
+        }
+        if unsafe { libc::ioctl(self.0, SIOCETHTOOL as _, &mut req) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(value.data)
+    }
+}
+
+impl Drop for Socket{
+    fn drop(&mut self){
+        unsafe { libc::close(self.0) };
+    }
+}
@@ -0,0 +1,197 @@
+//! Open vSwitch as backend of a bridge, for SDN-style forwarding by
+//! OpenFlow rules next to the namespace routers. Every OVS bridge gets an
+//! ovsdb-server and ovs-vswitchd of its own, started inside the bridge's
+//! namespace with their database, sockets and pid files in a runtime
+//! directory next to those of the routing daemons, so they are stopped
+//! with the topology. The bridge uses the userspace (`netdev`) datapath,
+//! which sees only the ports of its namespace and needs no kernel module.
+//!
+//! Without flows the bridge switches like a learning bridge (the `NORMAL`
+//! action). Flows in the description replace all flows of the bridge, so
+//! they have to include a `NORMAL` rule where that is still wanted.
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::daemon;
+use crate::error::{failed, Result, RouterError};
+use crate::logs;
+use crate::netns;
+use crate::state::{State, STATE_DIR};
+use crate::trace::Traced;
+
+/// Database schema shipped with Open vSwitch.
+pub const SCHEMA: &str = "/usr/share/openvswitch/vswitch.ovsschema";
+
+/// Datapath port OVS creates in the namespace of a `netdev` bridge.
+pub const DATAPATH_PORT: &str = "ovs-netdev";
+
+/// Open vSwitch instance switching the bridge `bridge` in `netns`.
+pub struct Switch{
+    pub topology: String,
+    pub netns: String,
+    pub bridge: String,
+    /// database, sockets and pid files
+    pub dir: PathBuf,
+}
+
+impl Switch{
+    pub fn new(topology: &str, netns: &str, bridge: &str) -> Switch {
+        Switch{
+            topology: topology.to_string(),
+            netns: netns.to_string(),
+            bridge: bridge.to_string(),
+            dir: Switch::dir(topology, bridge),
+        }
+    }
+
+    /// Runtime directory of the OVS bridge `bridge` of `topology`.
+    pub fn dir(topology: &str, bridge: &str) -> PathBuf {
+        PathBuf::from(STATE_DIR).join(topology).join(format!("ovs-{}", bridge))
+    }
+
+    /// Switch of the OVS bridge `bridge` of a running topology.
+    pub fn find(topology: &str, bridge: &str) -> Result<Switch>{
+        let state = State::load(topology)?
+            .ok_or_else(|| RouterError::TopologyNotFound(topology.to_string()))?;
+        let b = state.bridges.iter().find(|b| b.name == bridge)
+            .ok_or_else(|| failed!("Bridge {} not found in {}", bridge, topology))?;
+        match (&b.netns, b.ovs){
+            (Some(netns), true) => Ok(Switch::new(topology, netns, bridge)),
+            _ => Err(failed!("Bridge {} of {} is not an OVS bridge", bridge, topology)),
+        }
+    }
+
+    /// Starts ovsdb-server and ovs-vswitchd unless they run already and
+    /// adds the bridge. Returns true if the daemons were started.
+    pub fn start(&self) -> Result<bool>{
+        let running = ["ovsdb-server", "ovs-vswitchd"].iter().all(|name| {
+            std::fs::read_to_string(self.dir.join(format!("{}.pid", name))).ok()
+                .and_then(|p| p.trim().parse::<i32>().ok())
+                .is_some_and(|pid| unsafe { libc::kill(pid, 0) } == 0)
+        });
+        if !running {
+            if let Err(e) = self.launch_all() {
+                let _ = daemon::stop_dir(&self.dir);
+                return Err(e);
+            }
+        }
+        self.vsctl(&["--may-exist", "add-br", self.bridge.as_str(), "--", "set", "bridge", self.bridge.as_str(), "datapath_type=netdev"])?;
+        Ok(!running)
+    }
+
+    fn launch_all(&self) -> Result<()>{
+        std::fs::create_dir_all(&self.dir)?;
+        let db = self.dir.join("conf.db");
+        if !db.exists() {
+            let output = Command::new("ovsdb-tool").arg("create").arg(&db).arg(SCHEMA).traced_output()
+                .map_err(|e| failed!("Failed to run ovsdb-tool, is Open vSwitch installed? {}", e))?;
+            if !output.status.success() {
+                return Err(failed!("Failed to create OVS database of {}: {}", self.bridge, String::from_utf8_lossy(&output.stderr)));
+            }
+        }
+        let remote = format!("--remote=punix:{}", self.socket());
+        self.launch("ovsdb-server", &[db.to_string_lossy().as_ref(), remote.as_str()])?;
+        self.vsctl(&["--no-wait", "init"])?;
+        let socket = format!("unix:{}", self.socket());
+        self.launch("ovs-vswitchd", &[socket.as_str()])
+    }
+
+    /// Runs `name` detached inside the namespace, logging to its node log.
+    fn launch(&self, name: &str, args: &[&str]) -> Result<()>{
+        let pidfile = self.dir.join(format!("{}.pid", name));
+        let log = logs::path(&self.topology, &self.netns, name);
+        logs::open(&self.topology, &self.netns, name)?;
+        let status = self.command(netns::command(&self.netns, name))
+            .args(args)
+            .arg(format!("--pidfile={}", pidfile.display()))
+            .arg(format!("--unixctl={}", self.dir.join(format!("{}.ctl", name)).display()))
+            .arg(format!("--log-file={}", log.display()))
+            .arg("--detach")
+            .stdin(Stdio::null())
+            .traced_status()
+            .map_err(|e| failed!("Failed to start {} in {}: {}", name, self.netns, e))?;
+        if !status.success() {
+            return Err(failed!("Failed to start {} in {}: {}", name, self.netns, logs::tail(&log, 5)));
+        }
+        for _ in 0..50{
+            if pidfile.exists() {
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Err(failed!("{} in {} did not write {}", name, self.netns, pidfile.display()))
+    }
+
+    /// Adds `port`, an interface of the namespace, to the bridge.
+    pub fn add_port(&self, port: &str) -> Result<()>{
+        self.vsctl(&["--may-exist", "add-port", self.bridge.as_str(), port])
+            .map(|_| ())
+            .map_err(|e| failed!("Failed to add {} to OVS bridge {}: {}", port, self.bridge, e))
+    }
+
+    pub fn add_flows(&self, flows: &[String]) -> Result<()>{
+        for flow in flows{
+            self.ofctl(&["add-flow", flow.as_str()])
+                .map_err(|e| failed!("Failed to add flow to OVS bridge {}: {}", self.bridge, e))?;
+        }
+        Ok(())
+    }
+
+    /// Makes `flows` the only flows of the bridge, those already installed
+    /// stay untouched.
+    pub fn replace_flows(&self, flows: &[String]) -> Result<()>{
+        let file = self.dir.join(format!("{}.flows", self.bridge));
+        std::fs::write(&file, flows.iter().map(|f| format!("{}\n", f)).collect::<String>())?;
+        self.ofctl(&["replace-flows", file.to_string_lossy().as_ref()])
+            .map(|_| ())
+            .map_err(|e| failed!("Failed to install flows on OVS bridge {}: {}", self.bridge, e))
+    }
+
+    /// Deletes the flows matching `filter`, all without one.
+    pub fn del_flows(&self, filter: Option<&str>) -> Result<()>{
+        let mut args = vec!["del-flows"];
+        args.extend(filter);
+        self.ofctl(&args).map(|_| ())
+    }
+
+    /// Flows with their counters as printed by `ovs-ofctl dump-flows`.
+    pub fn dump_flows(&self) -> Result<String>{
+        self.ofctl(&["dump-flows"])
+    }
+
+    fn socket(&self) -> String {
+        self.dir.join("db.sock").to_string_lossy().to_string()
+    }
+
+    /// `cmd` with the OVS directories pointed at the runtime directory,
+    /// the bridge's management socket ends up there as well.
+    fn command(&self, mut cmd: Command) -> Command {
+        for var in ["OVS_RUNDIR", "OVS_DBDIR", "OVS_SYSCONFDIR"]{
+            cmd.env(var, &self.dir);
+        }
+        cmd
+    }
+
+    fn vsctl(&self, args: &[&str]) -> Result<String>{
+        let db = format!("--db=unix:{}", self.socket());
+        self.run("ovs-vsctl", std::iter::once(db.as_str()).chain(args.iter().copied()).collect())
+    }
+
+    fn ofctl(&self, args: &[&str]) -> Result<String>{
+        let mgmt = format!("unix:{}", self.dir.join(format!("{}.mgmt", self.bridge)).display());
+        let mut args = args.to_vec();
+        args.insert(1, mgmt.as_str());
+        self.run("ovs-ofctl", args)
+    }
+
+    fn run(&self, program: &str, args: Vec<&str>) -> Result<String>{
+        let output = self.command(Command::new(program)).args(&args).traced_output()
+            .map_err(|e| failed!("Failed to run {}, is Open vSwitch installed? {}", program, e))?;
+        if !output.status.success() {
+            return Err(failed!("Failed to run {} {}: {}", program, args.join(" "), String::from_utf8_lossy(&output.stderr)));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use crate::error::{failed, Result};
+use crate::netns;
+
+/// One-way delay probe between two namespaces. The sender takes its tx
+/// timestamp from the kernel error queue and the receiver its rx timestamp
+/// from the SCM_TIMESTAMPING control message, so both ends are stamped by the
+/// kernel (or the NIC) rather than by user space. All namespaces share the
+/// host clock, so rx - tx is the actual one-way delay.
+pub struct OwdProbe{
+    pub src: String,
+    pub dst: String,
+    pub target: SocketAddr,
+    pub count: u32,
+    pub interval: Duration,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TimestampSource{
+    Hardware,
+    Software,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+struct Stamp{
+    sw: Option<i128>,
+    hw: Option<i128>,
+}
+
+/// Delays are in microseconds, `jitter` is the mean absolute difference
+/// between the delays of consecutive packets (RFC 3393 IPDV).
+#[derive(Clone, Debug)]
+pub struct OwdReport{
+    pub sent: u32,
+    pub received: u32,
+    pub source: TimestampSource,
+    pub min: f64,
+    pub mean: f64,
+    pub max: f64,
+    pub jitter: f64,
+}
+
+impl fmt::Display for OwdReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} sent, {} received, {:?} timestamps, delay min/mean/max {:.1}/{:.1}/{:.1} us, jitter {:.1} us",
+            self.sent, self.received, self.source, self.min, self.mean, self.max, self.jitter)
+    }
+}
+
+impl OwdProbe{
+    pub fn run(&self) -> Result<OwdReport>{
+        let (port, v6, count) = (self.target.port(), self.target.is_ipv6(), self.count);
+        let (target, interval) = (self.target, self.interval);
+        // give packets still in flight a moment before stopping the receiver
+        let (tx, rx) = netns::send_receive(&self.src, &self.dst, Duration::from_millis(500),
+            move || send(target, count, interval),
+            move |done, ready| receive(port, v6, count, done, ready))?;
+        report(&tx, &rx, count)
+    }
+}
+
+fn report(tx: &BTreeMap<u32, Stamp>, rx: &BTreeMap<u32, Stamp>, sent: u32) -> Result<OwdReport>{
+    let hardware = !rx.is_empty() && rx.iter().all(|(seq, r)| {
+        r.hw.is_some() && tx.get(seq).is_some_and(|t| t.hw.is_some())
+    });
+    let mut delays = Vec::new();
+    for (seq, r) in rx{
+        let t = match tx.get(seq){
+            Some(t) => t,
+            None => continue,
+        };
+        let d = if hardware {
+            r.hw.zip(t.hw).map(|(r, t)| r - t)
+        } else {
+            r.sw.zip(t.sw).map(|(r, t)| r - t)
+        };
+        if let Some(d) = d{
+            delays.push(d as f64 / 1000.0);
+        }
+    }
+    if delays.is_empty() {
+        return Err(failed!("No timestamped probes received ({} sent)", sent));
+    }
+    let ipdv: Vec<f64> = delays.windows(2).map(|w| (w[1] - w[0]).abs()).collect();
+    Ok(OwdReport{
+        sent,
+        received: rx.len() as u32,
+        source: if hardware { TimestampSource::Hardware } else { TimestampSource::Software },
+        min: delays.iter().cloned().fold(f64::MAX, f64::min),
+        mean: delays.iter().sum::<f64>() / delays.len() as f64,
+        max: delays.iter().cloned().fold(f64::MIN, f64::max),
+        jitter: if ipdv.is_empty() { 0.0 } else { ipdv.iter().sum::<f64>() / ipdv.len() as f64 },
+    })
+}
+
+fn send(target: SocketAddr, count: u32, interval: Duration) -> Result<BTreeMap<u32, Stamp>>{
+    let socket = UdpSocket::bind(if target.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" })?;
+    set_timestamping(&socket, libc::SOF_TIMESTAMPING_TX_SOFTWARE
+        | libc::SOF_TIMESTAMPING_TX_HARDWARE
+        | libc::SOF_TIMESTAMPING_SOFTWARE
+        | libc::SOF_TIMESTAMPING_RAW_HARDWARE
+        | libc::SOF_TIMESTAMPING_OPT_ID
+        | libc::SOF_TIMESTAMPING_OPT_TSONLY)?;
+    let mut stamps: BTreeMap<u32, Stamp> = BTreeMap::new();
+    for seq in 0..count{
+        socket.send_to(&seq.to_be_bytes(), target)?;
+        // tx timestamps are queued asynchronously, software and hardware
+        // stamps may arrive as separate messages
+        let deadline = Instant::now() + Duration::from_millis(100);
+        while Instant::now() < deadline {
+            match recv(&socket, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT)?{
+                Some(Message{ id: Some(id), stamp, .. }) => {
+                    let entry = stamps.entry(id).or_default();
+                    entry.sw = entry.sw.or(stamp.sw);
+                    entry.hw = entry.hw.or(stamp.hw);
+                    if id == seq && entry.sw.is_some() {
+                        break;
+                    }
+                },
+                _ => std::thread::sleep(Duration::from_micros(50)),
+            }
+        }
+        std::thread::sleep(interval);
+    }
+    while let Some(Message{ id: Some(id), stamp, .. }) = recv(&socket, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT)?{
+        let entry = stamps.entry(id).or_default();
+        entry.sw = entry.sw.or(stamp.sw);
+        entry.hw = entry.hw.or(stamp.hw);
+    }
+    Ok(stamps)
+}
+
+fn receive(port: u16, v6: bool, count: u32, done: Arc<AtomicBool>, ready: mpsc::Sender<()>) -> Result<BTreeMap<u32, Stamp>>{
+    let socket = UdpSocket::bind(if v6 { format!("[::]:{}", port) } else { format!("0.0.0.0:{}", port) })?;
+    set_timestamping(&socket, libc::SOF_TIMESTAMPING_RX_SOFTWARE
+        | libc::SOF_TIMESTAMPING_RX_HARDWARE
+        | libc::SOF_TIMESTAMPING_SOFTWARE
+        | libc::SOF_TIMESTAMPING_RAW_HARDWARE)?;
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    let _ = ready.send(());
+    let mut stamps = BTreeMap::new();
+    while (stamps.len() as u32) < count {
+        match recv(&socket, 0)?{
+            Some(m) => {
+                if m.payload.len() >= 4 {
+                    let seq = u32::from_be_bytes([m.payload[0], m.payload[1], m.payload[2], m.payload[3]]);
+                    stamps.insert(seq, m.stamp);
+                }
+            },
+            None => {
+                if done.load(Ordering::SeqCst) {
+                    break;
+                }
+            },
+        }
+    }
+    Ok(stamps)
+}
+
+fn set_timestamping(socket: &UdpSocket, flags: libc::c_uint) -> Result<()>{
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPING,
+            &flags as *const libc::c_uint as *const libc::c_void,
+            mem::size_of::<libc::c_uint>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(failed!("Failed to enable SO_TIMESTAMPING: {}", std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+struct Message{
+    payload: Vec<u8>,
+    /// timestamp id, only set for error queue messages
+    id: Option<u32>,
+    stamp: Stamp,
+}
+
+/// Receives one message with its control data. Returns None when nothing is
+/// available (timeout or empty error queue).
+fn recv(socket: &UdpSocket, flags: libc::c_int) -> Result<Option<Message>>{
+    let mut payload = [0u8; 64];
+    let mut control = [0u64; 64];
+    let mut iov = libc::iovec{
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, flags) };
+    if n < 0 {
+        let err = std::io::Error::last_os_error();
+        return match err.kind(){
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => Ok(None),
+            _ => Err(failed!("Failed to receive: {}", err)),
+        };
+    }
+
+    let mut stamp = Stamp::default();
+    let mut id = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let (level, kind) = ((*cmsg).cmsg_level, (*cmsg).cmsg_type);
+            if level == libc::SOL_SOCKET && kind == libc::SCM_TIMESTAMPING {
+                let ts = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const [libc::timespec; 3]);
+                stamp.sw = nanos(&ts[0]);
+                stamp.hw = nanos(&ts[2]);
+            } else if (level == libc::SOL_IP && kind == libc::IP_RECVERR) || (level == libc::SOL_IPV6 && kind == libc::IPV6_RECVERR) {
+                let err = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err);
+                if err.ee_errno == libc::ENOMSG as u32 && err.ee_origin == libc::SO_EE_ORIGIN_TIMESTAMPING {
+                    id = Some(err.ee_data);
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok(Some(Message{
+        payload: payload[..n as usize].to_vec(),
+        id,
+        stamp,
+    }))
+}
+
+fn nanos(ts: &libc::timespec) -> Option<i128>{
+    if ts.tv_sec == 0 && ts.tv_nsec == 0 {
+        return None;
+    }
+    Some(ts.tv_sec as i128 * 1_000_000_000 + ts.tv_nsec as i128)
+}
@@ -0,0 +1,183 @@
+//! Ownership tags on kernel objects, so leftovers of runs which crashed
+//! before they recorded their state, or before `destroy` ran, can be found
+//! and removed without touching namespaces and interfaces of other tools
+//! on the same host, e.g. `destroy --orphans`.
+//!
+//! Namespaces can't carry labels, the interface alias of their `lo` does
+//! instead: `router-rs:<topology>:<pid>`, pid of the process which created
+//! it. Devices made in the host namespace to be moved into a namespace
+//! later carry the same alias themselves. Interfaces in the namespaces go
+//! with them and aren't tagged, pool veths keep their pool name as alias.
+//!
+//! A tagged namespace is an orphan once the process which created it is
+//! gone and the state of its topology, if there is one, doesn't list it.
+//! While the process runs, e.g. still building the topology, it isn't.
+
+use std::fmt;
+use std::path::Path;
+
+use crate::cmd;
+use crate::error::{Result, RouterError};
+use crate::state::State;
+use crate::{daemon, dns, Namespace};
+
+const TAG: &str = "router-rs";
+
+/// Alias of objects created for `topology` by this process.
+pub fn label(topology: &str) -> String {
+    format!("{}:{}:{}", TAG, topology, std::process::id())
+}
+
+/// Topology and creating process of a tagged object.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Owner{
+    pub topology: String,
+    pub pid: u32,
+}
+
+impl Owner{
+    /// Owner of an object with interface alias `alias`, None for objects
+    /// not created by router-rs.
+    pub fn parse(alias: &str) -> Option<Owner> {
+        let rest = alias.strip_prefix(TAG)?.strip_prefix(':')?;
+        // topology names may contain ':', pids don't
+        let (topology, pid) = rest.rsplit_once(':')?;
+        Some(Owner{ topology: topology.to_string(), pid: pid.parse().ok()? })
+    }
+
+    /// True while the creating process runs. A pid reused meanwhile keeps
+    /// the object, which is the safe side.
+    pub fn alive(&self) -> bool {
+        Path::new("/proc").join(self.pid.to_string()).exists()
+    }
+}
+
+/// Tags namespace `netns` as created for `topology`.
+pub(crate) fn tag_namespace(netns: &str, topology: &str) -> Result<()>{
+    cmd::ip(Some(netns), &["link", "set", "dev", "lo", "alias", label(topology).as_str()])?;
+    Ok(())
+}
+
+/// Removes the tag of `netns`, e.g. when it goes back to the pool.
+pub(crate) fn untag_namespace(netns: &str) -> Result<()>{
+    cmd::ip(Some(netns), &["link", "set", "dev", "lo", "alias", ""])?;
+    Ok(())
+}
+
+/// Tags device `name` of the host namespace as created for `topology`.
+pub(crate) fn tag_device(name: &str, topology: &str) -> Result<()>{
+    cmd::ip(None, &["link", "set", "dev", name, "alias", label(topology).as_str()])?;
+    Ok(())
+}
+
+/// Owner of namespace `netns` by the tag of its `lo`, None if untagged
+/// or gone.
+pub(crate) fn namespace_owner(netns: &str) -> Option<Owner> {
+    let out = cmd::ip(Some(netns), &["-j", "link", "show", "dev", "lo"]).ok()?;
+    Owner::parse(&alias(&out)?)
+}
+
+#[derive(Clone, Debug)]
+pub enum Orphan{
+    Namespace{ netns: String, owner: Owner },
+    /// device left in the host namespace
+    Device{ name: String, owner: Owner },
+}
+
+impl Orphan{
+    pub fn owner(&self) -> &Owner {
+        match self{
+            Orphan::Namespace{ owner, .. } | Orphan::Device{ owner, .. } => owner,
+        }
+    }
+
+    /// Deletes the namespace, stopping what still runs in it, or the
+    /// device.
+    pub fn remove(&self) -> Result<()>{
+        match self{
+            Orphan::Namespace{ netns, .. } => {
+                // processes keep a deleted namespace alive
+                for pid in cmd::ip(None, &["netns", "pids", netns.as_str()])?.split_whitespace(){
+                    if let Ok(pid) = pid.parse::<libc::pid_t>() {
+                        unsafe { libc::kill(pid, libc::SIGKILL) };
+                    }
+                }
+                dns::unconfigure(netns)?;
+                Namespace::delete(netns)?;
+            },
+            Orphan::Device{ name, .. } => {
+                cmd::ip(None, &["link", "del", "dev", name.as_str()])?;
+            },
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Orphan{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (kind, name, owner) = match self{
+            Orphan::Namespace{ netns, owner } => ("namespace", netns, owner),
+            Orphan::Device{ name, owner } => ("device", name, owner),
+        };
+        write!(f, "{} {} of topology {}, created by pid {}", kind, name, owner.topology, owner.pid)
+    }
+}
+
+/// Tagged namespaces and host devices left behind, those of `topology`
+/// only if set.
+pub fn orphans(topology: Option<&str>) -> Result<Vec<Orphan>>{
+    let wanted = |o: &Owner| topology.is_none_or(|t| o.topology == t) && !o.alive();
+    let mut orphans = Vec::new();
+    for netns in cmd::ip(None, &["netns", "list"])?.lines().filter_map(|l| l.split_whitespace().next()){
+        // gone meanwhile or not readable, not ours to judge
+        let Some(owner) = namespace_owner(netns) else {
+            continue;
+        };
+        if !wanted(&owner) || !netns.starts_with(Namespace::netns_name(&owner.topology, "").as_str()) {
+            continue;
+        }
+        let recorded = State::load(&owner.topology).ok().flatten()
+            .is_some_and(|s| s.namespaces.iter().any(|n| n.netns == netns));
+        if !recorded {
+            orphans.push(Orphan::Namespace{ netns: netns.to_string(), owner });
+        }
+    }
+    let links: serde_json::Value = serde_json::from_str(&cmd::ip(None, &["-j", "link", "show"])?)?;
+    for l in links.as_array().cloned().unwrap_or_default(){
+        let (Some(name), Some(owner)) = (l["ifname"].as_str(), l["ifalias"].as_str().and_then(Owner::parse)) else {
+            continue;
+        };
+        if wanted(&owner) {
+            orphans.push(Orphan::Device{ name: name.to_string(), owner });
+        }
+    }
+    Ok(orphans)
+}
+
+/// Removes `orphans`, and the daemon directories of their topologies
+/// which have no state. Returns the orphans which couldn't be removed
+/// with the reason.
+pub fn remove(orphans: &[Orphan]) -> Vec<(Orphan, RouterError)> {
+    let mut failed = Vec::new();
+    for o in orphans{
+        if let Err(e) = o.remove() {
+            failed.push((o.clone(), e));
+        }
+    }
+    let mut topologies: Vec<&str> = orphans.iter().map(|o| o.owner().topology.as_str()).collect();
+    topologies.sort();
+    topologies.dedup();
+    for t in topologies{
+        if matches!(State::load(t), Ok(None)) {
+            if let Err(e) = daemon::stop_topology(t) {
+                tracing::warn!(target: "router_rs::owner", topology = t, "failed to stop daemons: {}", e);
+            }
+        }
+    }
+    failed
+}
+
+fn alias(out: &str) -> Option<String> {
+    let links: serde_json::Value = serde_json::from_str(out).ok()?;
+    links[0]["ifalias"].as_str().map(|a| a.to_string())
+}
@@ -0,0 +1,183 @@
+//! P4 programmable dataplane: a namespace whose ports are switched by a
+//! bmv2 `simple_switch` running a P4 program instead of by the kernel, so
+//! P4 experiments get their veths, addresses and checks from the topology
+//! like any router. The kernel of such a namespace stops forwarding and
+//! runs no routing daemon; its interfaces keep their addresses, which
+//! neighbors resolve and the program may use as router addresses.
+//!
+//! The switch runs with its compiled program, pid file and thrift port in
+//! a runtime directory next to those of the routing daemons and is stopped
+//! with the topology. A `.p4` program is compiled with `p4c-bm2-ss` first.
+//! Table entries are given as `simple_switch_CLI` commands and applied
+//! after a `reset_state` whenever the topology is built or reconciled.
+//!
+//! bmv2 forwards frames as it gets them, so checksums left to the offload
+//! of the sending veth stay unfilled; hosts exchanging TCP through a
+//! switch need `ethtool -K <interface> tx off`.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::daemon;
+use crate::error::{failed, Result};
+use crate::logs;
+use crate::namespace;
+use crate::netns;
+use crate::state::STATE_DIR;
+use crate::trace::Traced;
+
+/// Thrift port of the switch's runtime API, free in its own namespace.
+pub const THRIFT_PORT: u16 = 9090;
+
+/// bmv2 switch of the namespace `netns`.
+pub struct P4Switch{
+    pub topology: String,
+    pub netns: String,
+    /// compiled program, pid file and command line
+    pub dir: PathBuf,
+}
+
+impl P4Switch{
+    pub fn new(topology: &str, namespace: &str, netns: &str) -> P4Switch {
+        P4Switch{
+            topology: topology.to_string(),
+            netns: netns.to_string(),
+            dir: P4Switch::dir(topology, namespace),
+        }
+    }
+
+    /// Runtime directory of the switch of `namespace` of `topology`.
+    pub fn dir(topology: &str, namespace: &str) -> PathBuf {
+        PathBuf::from(STATE_DIR).join(topology).join(format!("p4-{}", namespace))
+    }
+
+    /// Namespaces of `topology` with a switch directory.
+    pub fn list(topology: &str) -> Result<Vec<String>>{
+        let dir = PathBuf::from(STATE_DIR).join(topology);
+        let mut namespaces = Vec::new();
+        if !dir.exists() {
+            return Ok(namespaces);
+        }
+        for entry in std::fs::read_dir(dir)?{
+            if let Some(ns) = entry?.file_name().to_str().and_then(|n| n.strip_prefix("p4-")){
+                namespaces.push(ns.to_string());
+            }
+        }
+        Ok(namespaces)
+    }
+
+    /// Runs `program` with `ports` as ports 0, 1, ... unless the switch
+    /// runs already with the same program and ports, and fills its tables
+    /// with `commands`. Returns true if the switch was started.
+    pub fn start(&self, program: &Path, ports: &[String], commands: &[String]) -> Result<bool>{
+        let existed = self.dir.exists();
+        let result = self.run(program, ports, commands);
+        if result.is_err() && !existed {
+            let _ = daemon::stop_dir(&self.dir);
+        }
+        result
+    }
+
+    fn run(&self, program: &Path, ports: &[String], commands: &[String]) -> Result<bool>{
+        std::fs::create_dir_all(&self.dir)?;
+        let json = self.compile(program)?;
+        let args = self.args(ports);
+        let running = self.running()
+            && std::fs::read(self.dir.join("program.json")).ok() == Some(json.clone())
+            && std::fs::read_to_string(self.dir.join("cmdline")).ok().as_deref() == Some(args.join(" ").as_str());
+        if !running {
+            daemon::stop_dir(&self.dir)?;
+            std::fs::create_dir_all(&self.dir)?;
+            std::fs::write(self.dir.join("program.json"), &json)?;
+            std::fs::write(self.dir.join("cmdline"), args.join(" "))?;
+            self.sysctl(&["net.ipv4.ip_forward=0", "net.ipv6.conf.all.forwarding=0"])?;
+            daemon::spawn(&self.topology, &self.netns, &self.dir, "simple_switch", &args)?;
+        }
+        self.configure(commands)?;
+        Ok(!running)
+    }
+
+    /// JSON of `program`, compiled into the runtime directory if it is P4
+    /// source.
+    fn compile(&self, program: &Path) -> Result<Vec<u8>>{
+        if program.extension().is_some_and(|e| e == "json") {
+            return std::fs::read(program)
+                .map_err(|e| failed!("Failed to read P4 program {}: {}", program.display(), e));
+        }
+        let out = self.dir.join("compiled.json");
+        let output = Command::new("p4c-bm2-ss").arg("--p4v").arg("16").arg("-o").arg(&out).arg(program).traced_output()
+            .map_err(|e| failed!("Failed to run p4c-bm2-ss, is the P4 compiler installed? {}", e))?;
+        if !output.status.success() {
+            return Err(failed!("Failed to compile {}: {}", program.display(), String::from_utf8_lossy(&output.stderr)));
+        }
+        Ok(std::fs::read(out)?)
+    }
+
+    fn args(&self, ports: &[String]) -> Vec<String> {
+        let mut args = vec!["simple_switch".to_string(), "--thrift-port".to_string(), THRIFT_PORT.to_string()];
+        for (n, port) in ports.iter().enumerate(){
+            args.push("-i".to_string());
+            args.push(format!("{}@{}", n, port));
+        }
+        args.push(self.dir.join("program.json").to_string_lossy().to_string());
+        args
+    }
+
+    /// Clears the tables and runs `commands`, retrying until the thrift
+    /// server of a freshly started switch is up.
+    fn configure(&self, commands: &[String]) -> Result<()>{
+        let script: String = std::iter::once("reset_state").chain(commands.iter().map(|c| c.as_str()))
+            .map(|c| format!("{}\n", c))
+            .collect();
+        let mut result = Ok(String::new());
+        for _ in 0..50{
+            result = self.cli(&script);
+            match &result{
+                Err(e) if e.to_string().contains("Could not connect") => std::thread::sleep(Duration::from_millis(100)),
+                _ => break,
+            }
+        }
+        if result.is_err() && !self.running() {
+            return Err(failed!("simple_switch in {} exited: {}", self.netns,
+                logs::tail(&logs::path(&self.topology, &self.netns, "simple_switch"), 5)));
+        }
+        let output = result?;
+        // the CLI exits successfully whatever the commands did
+        if let Some(error) = output.lines().find(|l| l.contains("Error") || l.starts_with("Invalid")) {
+            return Err(failed!("Failed to configure the P4 switch in {}: {}", self.netns, error.trim()));
+        }
+        Ok(())
+    }
+
+    /// Runs `simple_switch_CLI` with `script` as input and returns its
+    /// output.
+    pub fn cli(&self, script: &str) -> Result<String>{
+        let mut child = netns::command(&self.netns, "simple_switch_CLI")
+            .arg("--thrift-port")
+            .arg(THRIFT_PORT.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .traced_spawn()
+            .map_err(|e| failed!("Failed to run simple_switch_CLI in {}: {}", self.netns, e))?;
+        if let Some(mut stdin) = child.stdin.take(){
+            stdin.write_all(script.as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        if !output.status.success() {
+            return Err(failed!("Failed to run simple_switch_CLI in {}: {}{}", self.netns, stdout, String::from_utf8_lossy(&output.stderr)));
+        }
+        Ok(stdout)
+    }
+
+    fn running(&self) -> bool {
+        daemon::pid(&self.dir.join("simple_switch.pid")).is_some_and(daemon::alive)
+    }
+
+    fn sysctl(&self, settings: &[&str]) -> Result<()>{
+        namespace::sysctl(&self.netns, settings)
+    }
+}
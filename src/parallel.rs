@@ -0,0 +1,67 @@
+//! Bounded concurrency for the phases of applying a topology. Creating
+//! namespaces and installing routes are independent per item and mostly
+//! wait on `ip` processes, so they run on several threads; how many is
+//! worth tuning between a small VM and a big lab server.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Threads used per phase, 1 runs a phase sequentially.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Parallelism{
+    /// topology copies created at once by `clone`
+    pub copies: usize,
+    /// namespaces set up at once
+    pub namespaces: usize,
+    /// routes installed at once
+    pub routes: usize,
+}
+
+impl Parallelism{
+    /// The same limit for every phase.
+    pub fn new(limit: usize) -> Parallelism {
+        Parallelism{
+            copies: limit.max(1),
+            namespaces: limit.max(1),
+            routes: limit.max(1),
+        }
+    }
+}
+
+impl Default for Parallelism{
+    /// One thread per CPU.
+    fn default() -> Self {
+        Parallelism::new(std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1))
+    }
+}
+
+/// Applies `f` to every item with at most `limit` calls running at once and
+/// returns the results in the order of `items`.
+pub fn map<T, R, F>(items: &[T], limit: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    if limit <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+    std::thread::scope(|s| {
+        for _ in 0..limit.min(items.len()){
+            s.spawn(|| loop{
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(i) else {
+                    break;
+                };
+                let result = f(item);
+                if let Ok(mut results) = results.lock() {
+                    results[i] = Some(result);
+                }
+            });
+        }
+    });
+    // a panicking worker fails the scope above, so every slot is filled
+    results.into_inner().unwrap_or_else(|e| e.into_inner()).into_iter().flatten().collect()
+}
@@ -0,0 +1,198 @@
+//! Host NICs in the namespaces of a topology. An interface either is a NIC
+//! moved from the host into its namespace, optionally under another name,
+//! or a macvlan or ipvlan child created on a NIC which stays on the host,
+//! so a lab reaches the physical network through its own MAC or IP
+//! address without taking the NIC away from the host.
+//!
+//! A moved NIC is recorded with its host name, MTU and whether it was up,
+//! see `MovedNic`, and `restore` gives it back that way when the topology
+//! is destroyed or the interface dropped from it. Deleting its namespace
+//! alone would let the kernel hand it back down and under whatever name it
+//! had inside. Addresses the NIC had on the host are flushed by the move
+//! and not restored. Children are deleted with their namespace.
+
+use std::fmt;
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::{Result, RouterError};
+use crate::trace::Traced;
+use crate::transaction::Resource;
+use crate::{Config, Namespace};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PassthroughKind{
+    /// the NIC itself is moved
+    #[default]
+    Move,
+    /// child with a MAC address of its own
+    Macvlan,
+    /// child sharing the NIC's MAC address
+    Ipvlan,
+}
+
+impl fmt::Display for PassthroughKind{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            PassthroughKind::Move => write!(f, "move"),
+            PassthroughKind::Macvlan => write!(f, "macvlan"),
+            PassthroughKind::Ipvlan => write!(f, "ipvlan"),
+        }
+    }
+}
+
+/// Host NIC an interface comes from.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Passthrough{
+    /// name of the NIC on the host
+    pub nic: String,
+    #[serde(default)]
+    pub kind: PassthroughKind,
+    /// macvlan mode, bridge, private, vepa, passthru or source, or ipvlan
+    /// mode, l2, l3 or l3s. bridge and l2 if not set.
+    #[serde(default)]
+    pub mode: Option<String>,
+}
+
+/// Host NIC moved into a namespace.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct MovedNic{
+    /// name inside the namespace
+    pub name: String,
+    pub netns: String,
+    /// name on the host
+    pub host_name: String,
+    /// MTU on the host
+    pub mtu: Option<u32>,
+    /// whether it was up on the host
+    #[serde(default)]
+    pub up: bool,
+}
+
+impl Passthrough{
+    pub fn check(&self) -> Result<()>{
+        let modes: &[&str] = match self.kind{
+            PassthroughKind::Move => &[],
+            PassthroughKind::Macvlan => &["bridge", "private", "vepa", "passthru", "source"],
+            PassthroughKind::Ipvlan => &["l2", "l3", "l3s"],
+        };
+        match &self.mode{
+            Some(mode) if !modes.contains(&mode.as_str()) => Err(RouterError::Invalid(match self.kind{
+                PassthroughKind::Move => format!("Host interface {} is moved and takes no mode", self.nic),
+                kind => format!("Unknown {} mode {}, expected {}", kind, mode, modes.join(", ")),
+            })),
+            _ => Ok(()),
+        }
+    }
+
+    /// Mode of the child, the kernel's default if not set.
+    pub fn mode(&self) -> &str {
+        match (&self.mode, self.kind){
+            (Some(mode), _) => mode,
+            (None, PassthroughKind::Ipvlan) => "l2",
+            (None, _) => "bridge",
+        }
+    }
+
+    /// Brings the NIC into `namespace` as interface `name`, moved or as a
+    /// child, unless it is there already. NICs moved are registered in
+    /// `config.nics`, from `saved` if moved by an earlier build, an
+    /// interface found without a record is left alone.
+    pub fn setup(&self, name: &str, namespace: &Namespace, saved: &[MovedNic], config: &Config) -> Result<()>{
+        self.check()?;
+        let netns = namespace.netns.as_str();
+        let found = link(Some(netns), name)?;
+        if self.kind == PassthroughKind::Move {
+            let nic = match (found, saved.iter().find(|n| n.name == name && n.netns == netns)){
+                (Some(_), Some(nic)) => nic.clone(),
+                // created in the namespace by other means, or moved by hand
+                (Some(_), None) => return Ok(()),
+                (None, _) => {
+                    let nic = take(&self.nic, name, netns)?;
+                    config.transaction().record(Resource::Moved(nic.clone()));
+                    nic
+                },
+            };
+            config.nics.insert(name.to_string(), nic);
+            return Ok(());
+        }
+        let kind = self.kind.to_string();
+        if let Some(l) = found {
+            if l["linkinfo"]["info_kind"] == kind.as_str() && l["linkinfo"]["info_data"]["mode"] == self.mode() {
+                return Ok(());
+            }
+            // kind or mode changed, which only creating the child sets
+            cmd::ip(Some(netns), &["link", "del", "dev", name])?;
+        }
+        cmd::ip(None, &["link", "add", "link", self.nic.as_str(), "name", name, "netns", netns, "type", kind.as_str(), "mode", self.mode()])
+            .map_err(|e| e.context(format!("Failed to create {} child {} of host interface {}", kind, name, self.nic)))?;
+        config.transaction().record(Resource::Device{ name: name.to_string(), netns: netns.to_string() });
+        Ok(())
+    }
+}
+
+/// Moves host NIC `host_name` into `netns` as `name`, returns how to give
+/// it back.
+pub fn take(host_name: &str, name: &str, netns: &str) -> Result<MovedNic>{
+    let l = link(None, host_name)?
+        .ok_or_else(|| RouterError::Invalid(format!("Host interface {} not found", host_name)))?;
+    let nic = MovedNic{
+        name: name.to_string(),
+        netns: netns.to_string(),
+        host_name: host_name.to_string(),
+        mtu: l["mtu"].as_u64().map(|m| m as u32),
+        up: l["flags"].as_array().is_some_and(|f| f.iter().any(|f| f == "UP")),
+    };
+    cmd::ip(None, &["link", "set", "dev", host_name, "netns", netns])
+        .map_err(|e| e.context("Failed to attach interface to namespace"))?;
+    if name != host_name {
+        cmd::ip(Some(netns), &["link", "set", "dev", host_name, "down", "name", name])
+            .map_err(|e| e.context(format!("Failed to rename host interface {} to {}", host_name, name)))?;
+    }
+    Ok(nic)
+}
+
+/// Moves `nic` back to the host under its host name, with the MTU and
+/// state it had there. A NIC gone from its namespace is left alone.
+pub fn restore(nic: &MovedNic) -> Result<()>{
+    if link(Some(&nic.netns), &nic.name)?.is_none() {
+        return Ok(());
+    }
+    let mut args = vec!["link", "set", "dev", nic.name.as_str(), "down"];
+    if nic.name != nic.host_name {
+        args.extend(["name", nic.host_name.as_str()]);
+    }
+    cmd::ip(Some(&nic.netns), &args)?;
+    cmd::ip(Some(&nic.netns), &["link", "set", "dev", nic.host_name.as_str(), "netns", "1"])
+        .map_err(|e| e.context(format!("Failed to give host interface {} back", nic.host_name)))?;
+    let mtu = nic.mtu.map(|m| m.to_string());
+    let mut args = vec!["link", "set", "dev", nic.host_name.as_str()];
+    if let Some(mtu) = &mtu{
+        args.extend(["mtu", mtu.as_str()]);
+    }
+    if nic.up {
+        args.push("up");
+    }
+    if args.len() > 4 {
+        cmd::ip(None, &args)?;
+    }
+    Ok(())
+}
+
+/// `ip -d -j link show` of `name`, None if it doesn't exist.
+fn link(netns: Option<&str>, name: &str) -> Result<Option<serde_json::Value>>{
+    let mut cmd = Command::new("ip");
+    if let Some(netns) = netns{
+        cmd.args(["-n", netns]);
+    }
+    let output = cmd.args(["-d", "-j", "link", "show", "dev", name]).traced_output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let links: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| RouterError::Invalid(format!("Unreadable link {}: {}", name, e)))?;
+    Ok(links.get(0).cloned())
+}
@@ -0,0 +1,361 @@
+//! Shortest-path static routes. Every link and bridge weighs its cost, so
+//! path selection follows the bandwidth and latency the topology declares
+//! instead of hop count alone. Equal-cost paths become ECMP routes. Costs
+//! are per direction, so routes over asymmetric links may return a
+//! different way than they went.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::error::{failed, Result};
+use crate::interface;
+use crate::loopback;
+use crate::nat64;
+use crate::qos::LinkQos;
+use crate::topology::{LinkSpec, RouteSpec, Topology};
+
+/// Bandwidth in Mbit/s costing 1, as OSPF's reference bandwidth (100 Gbit/s).
+pub const REFERENCE_BANDWIDTH: u64 = 100_000;
+
+/// Cost of a link: its explicit `cost`, otherwise
+/// `REFERENCE_BANDWIDTH / bandwidth` (at least 1, 1 without bandwidth) plus
+/// the latency in whole milliseconds. Bandwidth and latency default to the
+/// rate and delay of the link's `qos`. Links without attributes cost 1.
+pub fn link_cost(link: &LinkSpec) -> u64 {
+    cost(link, None)
+}
+
+/// Cost of sending over `link` from its end in `namespace`: like
+/// `link_cost`, with the rate and delay of the end's `endpoint_qos` taking
+/// precedence, so an asymmetric link costs differently each way.
+pub fn end_cost(link: &LinkSpec, namespace: &str) -> u64 {
+    cost(link, link.endpoint_qos.get(namespace))
+}
+
+fn cost(link: &LinkSpec, end: Option<&LinkQos>) -> u64 {
+    if let Some(cost) = link.cost{
+        return cost.max(1);
+    }
+    let qos = link.qos.clone().unwrap_or_default();
+    let end = end.cloned().unwrap_or_default();
+    let bandwidth = match end.rate.or(link.bandwidth).or(qos.rate){
+        Some(bw) if bw > 0 => (REFERENCE_BANDWIDTH / bw).max(1),
+        _ => 1,
+    };
+    bandwidth + end.delay.or(link.latency).or(qos.delay).unwrap_or_default().max(0.0).round() as u64
+}
+
+/// One segment (link or bridge) of the graph.
+struct Segment{
+    name: String,
+    namespaces: Vec<String>,
+    /// by the namespace sending over the segment
+    costs: HashMap<String, u64>,
+    /// the loopback addresses of the ends of an unnumbered link, which
+    /// stand for the families it carries but aren't attached to it
+    subnets: Vec<ipnet::IpNet>,
+    unnumbered: bool,
+}
+
+/// Routes from every namespace to every subnet it isn't attached to, over
+/// the cheapest paths. `subnets` maps link and bridge names to their
+/// subnets, which for allocated links are only known once assigned,
+/// loopback devices to their addresses and NAT64 devices to their pool
+/// and prefix.
+/// Destinations already routed by hand in a namespace are left alone.
+pub fn static_routes(topology: &Topology, subnets: &HashMap<String, (String, Option<String>)>) -> Result<Vec<RouteSpec>>{
+    let segments = segments(topology, subnets)?;
+    let mut destinations = Vec::new();
+    for s in segments.iter().filter(|s| !s.unnumbered){
+        for net in &s.subnets{
+            destinations.push((s.namespaces.clone(), *net));
+        }
+    }
+    for ns in &topology.namespaces{
+        let devices = ns.loopback.as_ref().map(|_| loopback::name(&ns.name)).into_iter()
+            .chain(ns.nat64.as_ref().map(|_| nat64::name(&ns.name)));
+        for name in devices{
+            let Some((ip, ip6)) = subnets.get(&name) else {
+                continue;
+            };
+            for ip in std::iter::once(ip).chain(ip6.iter()){
+                let net: ipnet::IpNet = ip.parse()
+                    .map_err(|e| failed!("Invalid address {} of {}: {}", ip, name, e))?;
+                destinations.push((vec![ns.name.clone()], net));
+            }
+        }
+    }
+    // subnets of host interfaces moved into a namespace
+    for i in &topology.interfaces{
+        if let Some(ns) = &i.namespace{
+            for ip in i.ip.iter().chain(i.ip6.iter()){
+                let net: ipnet::IpNet = ip.parse()
+                    .map_err(|e| failed!("Invalid address {} of {}: {}", ip, i.name, e))?;
+                destinations.push((vec![ns.clone()], net.trunc()));
+            }
+        }
+    }
+    // ends of unnumbered links reach each other's loopback over their
+    // peer route already
+    let mut peers = BTreeSet::new();
+    for l in topology.links.iter().filter(|l| l.unnumbered){
+        for (ns, other) in l.endpoints.iter().zip(l.endpoints.iter().rev()){
+            if let Some((ip, ip6)) = subnets.get(&loopback::name(other)){
+                for ip in std::iter::once(ip).chain(ip6.iter()){
+                    if let Ok(net) = ip.parse::<ipnet::IpNet>() {
+                        peers.insert((ns.clone(), net.to_string()));
+                    }
+                }
+            }
+        }
+    }
+    let mut routes = cheapest_routes(topology, &segments, &destinations);
+    routes.retain(|r| !peers.contains(&(r.namespace.clone(), r.dst.clone())));
+    Ok(routes)
+}
+
+/// Default routes of stub namespaces with a single link via its other end,
+/// in each family the link carries. Stubs with routes of their own are
+/// left alone, stubs learning from router advertisements get no IPv6
+/// default route.
+pub fn stub_routes(topology: &Topology, subnets: &HashMap<String, (String, Option<String>)>) -> Vec<RouteSpec> {
+    let mut routes = Vec::new();
+    for ns in topology.namespaces.iter().filter(|n| n.stub){
+        let links: Vec<&LinkSpec> = topology.links.iter().filter(|l| l.endpoints.contains(&ns.name)).collect();
+        let [link] = links[..] else {
+            continue;
+        };
+        if topology.routes.iter().any(|r| r.namespace == ns.name) {
+            continue;
+        }
+        let Some(peer) = link.endpoints.iter().find(|e| **e != ns.name) else {
+            continue;
+        };
+        // an unnumbered link carries the families of the loopbacks
+        let segment = if link.unnumbered { loopback::name(peer) } else { link.name.clone() };
+        let (subnet, subnet6) = subnets.get(&segment).cloned().unwrap_or_default();
+        let mut defaults = Vec::new();
+        if !subnet.contains(':') {
+            defaults.push("0.0.0.0/0");
+        }
+        // learned from router advertisements instead
+        if (subnet.contains(':') || subnet6.is_some()) && ns.ra.is_none() {
+            defaults.push("::/0");
+        }
+        for dst in defaults{
+            routes.push(RouteSpec{
+                namespace: ns.name.clone(),
+                dst: dst.to_string(),
+                gateways: vec![interface::name(peer, &link.name)],
+                ..Default::default()
+            });
+        }
+    }
+    routes
+}
+
+/// Host routes to the address of every service from all namespaces not
+/// running an instance of it. Like an anycast VIP announced by several
+/// load balancers, a service reached over equally cheap paths through
+/// different instances gets an ECMP route.
+pub fn service_routes(topology: &Topology, subnets: &HashMap<String, (String, Option<String>)>) -> Result<Vec<RouteSpec>>{
+    let segments = segments(topology, subnets)?;
+    let mut destinations = Vec::new();
+    for s in &topology.services{
+        let addr: std::net::IpAddr = s.address.parse()
+            .map_err(|e| failed!("Invalid address {} of service {}: {}", s.address, s.name, e))?;
+        destinations.push((s.instances.clone(), ipnet::IpNet::from(addr)));
+    }
+    Ok(cheapest_routes(topology, &segments, &destinations))
+}
+
+fn segments(topology: &Topology, subnets: &HashMap<String, (String, Option<String>)>) -> Result<Vec<Segment>>{
+    let mut segments = Vec::new();
+    let parse = |name: &str| -> Result<Vec<ipnet::IpNet>>{
+        let mut nets = Vec::new();
+        if let Some((subnet, subnet6)) = subnets.get(name){
+            for s in std::iter::once(subnet).chain(subnet6.iter()).filter(|s| !s.is_empty()){
+                let net: ipnet::IpNet = s.parse()
+                    .map_err(|e| failed!("Invalid subnet {} of {}: {}", s, name, e))?;
+                nets.push(net.trunc());
+            }
+        }
+        Ok(nets)
+    };
+    for l in &topology.links{
+        let subnets = match l.unnumbered{
+            true => l.endpoints.iter().map(|e| parse(&loopback::name(e))).collect::<Result<Vec<_>>>()?.concat(),
+            false => parse(&l.name)?,
+        };
+        segments.push(Segment{
+            name: l.name.clone(),
+            namespaces: l.endpoints.clone(),
+            costs: l.endpoints.iter().map(|e| (e.clone(), end_cost(l, e))).collect(),
+            subnets,
+            unnumbered: l.unnumbered,
+        });
+    }
+    for b in &topology.bridges{
+        segments.push(Segment{
+            name: b.name.clone(),
+            namespaces: b.members.clone(),
+            costs: b.members.iter().map(|m| (m.clone(), 1)).collect(),
+            subnets: parse(&b.name)?,
+            unnumbered: false,
+        });
+    }
+    Ok(segments)
+}
+
+/// Routes from every namespace to each destination prefix over the
+/// cheapest paths to whichever of the namespaces it is attached to is
+/// nearest. Namespaces attached themselves get no route.
+fn cheapest_routes(topology: &Topology, segments: &[Segment], destinations: &[(Vec<String>, ipnet::IpNet)]) -> Vec<RouteSpec> {
+    let manual: BTreeSet<(String, String)> = topology.routes.iter()
+        .map(|r| (r.namespace.clone(), r.dst.clone()))
+        .collect();
+    let mut routes = Vec::new();
+    for v6 in [false, true]{
+        let family = |n: &ipnet::IpNet| n.addr().is_ipv6() == v6;
+        // only segments carrying this family can forward it
+        let usable: Vec<&Segment> = segments.iter().filter(|s| s.subnets.iter().any(family)).collect();
+        for src in &topology.namespaces{
+            let (dist, first) = shortest_paths(&src.name, &usable);
+            for (attached, net) in destinations.iter().filter(|(_, n)| family(n)){
+                if attached.contains(&src.name) || manual.contains(&(src.name.clone(), net.to_string())) {
+                    continue;
+                }
+                let best = attached.iter().filter_map(|n| dist.get(n)).min();
+                let best = match best{
+                    Some(best) => *best,
+                    None => continue,
+                };
+                let gateways: BTreeSet<String> = attached.iter()
+                    .filter(|n| dist.get(*n) == Some(&best))
+                    .flat_map(|n| first[n].iter().cloned())
+                    .collect();
+                routes.push(RouteSpec{
+                    namespace: src.name.clone(),
+                    dst: net.to_string(),
+                    gateways: gateways.into_iter().collect(),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    routes
+}
+
+/// Dijkstra from `src`. Returns the distance to every reachable namespace
+/// and the gateway interfaces `src` reaches it through on all cheapest paths.
+fn shortest_paths(src: &str, segments: &[&Segment]) -> (HashMap<String, u64>, HashMap<String, BTreeSet<String>>){
+    let mut dist: HashMap<String, u64> = HashMap::new();
+    let mut first: HashMap<String, BTreeSet<String>> = HashMap::new();
+    let mut done: BTreeSet<String> = BTreeSet::new();
+    let mut queue: BTreeSet<(u64, String)> = BTreeSet::new();
+    dist.insert(src.to_string(), 0);
+    first.insert(src.to_string(), BTreeSet::new());
+    queue.insert((0, src.to_string()));
+    while let Some((d, node)) = queue.pop_first(){
+        if !done.insert(node.clone()) {
+            continue;
+        }
+        for s in segments.iter().filter(|s| s.namespaces.contains(&node)){
+            for next in s.namespaces.iter().filter(|n| **n != node){
+                let nd = d + s.costs[&node];
+                let hops: BTreeSet<String> = if node == src {
+                    BTreeSet::from([interface::name(next, &s.name)])
+                } else {
+                    first[&node].clone()
+                };
+                match dist.get(next){
+                    Some(old) if *old < nd => {},
+                    Some(old) if *old == nd => {
+                        first.entry(next.clone()).or_default().extend(hops);
+                    },
+                    _ => {
+                        dist.insert(next.clone(), nd);
+                        first.insert(next.clone(), hops);
+                        queue.insert((nd, next.clone()));
+                    },
+                }
+            }
+        }
+    }
+    dist.remove(src);
+    (dist, first)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// r1 reaches r4 over r2 and over r3, h hangs off r4.
+    fn diamond(extra: &str) -> (Topology, HashMap<String, (String, Option<String>)>){
+        let yaml = format!("
+name: diamond
+namespaces: [{{name: r1}}, {{name: r2}}, {{name: r3}}, {{name: r4}}, {{name: h}}]
+links:
+- {{name: a, subnet: 10.0.0.0/30, endpoints: [r1, r2]}}
+- {{name: b, subnet: 10.0.0.4/30, endpoints: [r1, r3]}}
+- {{name: c, subnet: 10.0.0.8/30, endpoints: [r2, r4]}}
+- {{name: d, subnet: 10.0.0.12/30, endpoints: [r3, r4]}}
+- {{name: e, subnet: 10.0.1.0/24, endpoints: [r4, h]}}
+{}", extra);
+        let topology: Topology = serde_yaml::from_str(&yaml).unwrap();
+        let subnets = topology.links.iter().map(|l| (l.name.clone(), (l.subnet.clone(), None))).collect();
+        (topology, subnets)
+    }
+
+    fn gateways(routes: &[RouteSpec], namespace: &str, dst: &str) -> Option<Vec<String>> {
+        routes.iter().find(|r| r.namespace == namespace && r.dst == dst).map(|r| r.gateways.clone())
+    }
+
+    #[test]
+    fn equal_cost_paths_become_ecmp_routes(){
+        let (topology, subnets) = diamond("");
+        let routes = static_routes(&topology, &subnets).unwrap();
+        assert_eq!(gateways(&routes, "r1", "10.0.1.0/24"), Some(vec!["r2_a".to_string(), "r3_b".to_string()]));
+        assert_eq!(gateways(&routes, "h", "10.0.0.0/30"), Some(vec!["r4_e".to_string()]));
+        // attached namespaces get no route
+        assert_eq!(gateways(&routes, "r4", "10.0.1.0/24"), None);
+    }
+
+    #[test]
+    fn cheaper_path_wins(){
+        let (mut topology, subnets) = diamond("");
+        topology.links[1].cost = Some(10);
+        let routes = static_routes(&topology, &subnets).unwrap();
+        assert_eq!(gateways(&routes, "r1", "10.0.1.0/24"), Some(vec!["r2_a".to_string()]));
+        // the subnet of b is still reached directly by r3
+        assert_eq!(gateways(&routes, "r4", "10.0.0.4/30"), Some(vec!["r3_d".to_string()]));
+    }
+
+    #[test]
+    fn costs_are_per_direction(){
+        let (mut topology, subnets) = diamond("");
+        topology.links[0].endpoint_qos.insert("r1".to_string(), LinkQos{ delay: Some(50.0), ..Default::default() });
+        let routes = static_routes(&topology, &subnets).unwrap();
+        assert_eq!(gateways(&routes, "r1", "10.0.1.0/24"), Some(vec!["r3_b".to_string()]));
+        assert_eq!(gateways(&routes, "r2", "10.0.0.4/30"), Some(vec!["r1_a".to_string()]));
+    }
+
+    #[test]
+    fn routes_set_by_hand_are_left_alone(){
+        let (topology, subnets) = diamond("routes: [{namespace: r1, dst: 10.0.1.0/24, gateways: [r2_a]}]");
+        let routes = static_routes(&topology, &subnets).unwrap();
+        assert_eq!(gateways(&routes, "r1", "10.0.1.0/24"), None);
+        assert!(gateways(&routes, "r2", "10.0.1.0/24").is_some());
+    }
+
+    #[test]
+    fn link_cost_follows_bandwidth_and_latency(){
+        let (topology, _) = diamond("");
+        let mut link = topology.links[0].clone();
+        assert_eq!(link_cost(&link), 1);
+        link.bandwidth = Some(1000);
+        link.latency = Some(4.6);
+        assert_eq!(link_cost(&link), REFERENCE_BANDWIDTH / 1000 + 5);
+        link.cost = Some(0);
+        assert_eq!(link_cost(&link), 1);
+    }
+}
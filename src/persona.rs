@@ -0,0 +1,441 @@
+//! Traffic personas: reusable profiles of the traffic users generate, run
+//! from a host namespace against a server in another one, so QoS and ECMP
+//! experiments get realistic mixed traffic without external generators.
+//!
+//! - `web`: users loading pages, one short TCP connection per object with
+//!   sizes from 1 KiB to 1 MiB and think times between pages
+//! - `bulk`: long TCP transfers as fast as the path allows
+//! - `voip`: calls sending a small UDP datagram every 20 ms, echoed back
+//!   by the server so loss, round trip time and jitter are measured
+//!
+//! Each persona marks its packets with its own DSCP, web best effort, bulk
+//! CS1 and voip EF. The server answers TCP and UDP on the same port. Every
+//! connection comes from a new source port, so ECMP spreads them.
+
+use std::fmt;
+use std::io::{Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{failed, Result, RouterError};
+use crate::netns;
+
+/// Bytes of a web request.
+const REQUEST: usize = 400;
+
+/// Bytes of a voip datagram, 20 ms of G.711 with its RTP header.
+const VOICE: usize = 172;
+
+/// Time between two voip datagrams.
+const PACKETIZATION: Duration = Duration::from_millis(20);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Persona{
+    Web,
+    Bulk,
+    Voip,
+}
+
+/// Settings of a persona.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PersonaParams{
+    /// users, transfers or calls at once
+    pub sessions: u32,
+    pub dscp: u8,
+}
+
+impl Persona{
+    pub fn params(&self) -> PersonaParams {
+        match self{
+            Persona::Web => PersonaParams{ sessions: 4, dscp: 0 },
+            Persona::Bulk => PersonaParams{ sessions: 1, dscp: 8 },
+            Persona::Voip => PersonaParams{ sessions: 2, dscp: 46 },
+        }
+    }
+}
+
+impl fmt::Display for Persona{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Persona::Web => write!(f, "web"),
+            Persona::Bulk => write!(f, "bulk"),
+            Persona::Voip => write!(f, "voip"),
+        }
+    }
+}
+
+impl FromStr for Persona{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<Persona>{
+        match s{
+            "web" => Ok(Persona::Web),
+            "bulk" => Ok(Persona::Bulk),
+            "voip" => Ok(Persona::Voip),
+            _ => Err(RouterError::Invalid(format!("Invalid persona {}, expected web, bulk or voip", s))),
+        }
+    }
+}
+
+/// Runs `personas` at once from `src` to a server on `target` in `dst`.
+pub struct PersonaRun{
+    pub src: String,
+    pub dst: String,
+    pub target: SocketAddr,
+    pub personas: Vec<Persona>,
+    /// sessions of every persona, their own if not set
+    pub sessions: Option<u32>,
+    /// DSCP of every persona, their own if not set
+    pub dscp: Option<u8>,
+    pub duration: Duration,
+    /// sizes and think times are the same for the same seed
+    pub seed: u64,
+}
+
+/// What one persona did during a run. `completed` and `failed` count
+/// objects for web, transfers for bulk and calls for voip. `bytes` is the
+/// payload sent and received by the users.
+#[derive(Serialize, Clone, Debug)]
+pub struct PersonaReport{
+    pub persona: Persona,
+    pub sessions: u32,
+    pub dscp: u8,
+    pub completed: u32,
+    pub failed: u32,
+    pub bytes: u64,
+    /// Mbit/s
+    pub throughput: f64,
+    /// milliseconds per object for web, round trip time for voip
+    pub latency: Option<f64>,
+    /// voip datagrams not echoed, in percent
+    pub loss: Option<f64>,
+    /// mean difference between consecutive round trip times of voip, in
+    /// milliseconds
+    pub jitter: Option<f64>,
+}
+
+impl fmt::Display for PersonaReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} sessions, DSCP {}, {} completed, {} failed, {:.2} Mbit/s",
+            self.persona, self.sessions, self.dscp, self.completed, self.failed, self.throughput)?;
+        if let Some(latency) = self.latency{
+            write!(f, ", latency {:.2} ms", latency)?;
+        }
+        if let Some(loss) = self.loss{
+            write!(f, ", loss {:.1}%", loss)?;
+        }
+        if let Some(jitter) = self.jitter{
+            write!(f, ", jitter {:.2} ms", jitter)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counters of one session.
+#[derive(Default)]
+struct Session{
+    completed: u32,
+    failed: u32,
+    bytes: u64,
+    /// milliseconds
+    latencies: Vec<f64>,
+    sent: u32,
+}
+
+impl PersonaRun{
+    pub fn run(&self) -> Result<Vec<PersonaReport>>{
+        if self.personas.is_empty() {
+            return Err(failed!("No personas to run"));
+        }
+        if self.dscp.is_some_and(|d| d > 63) {
+            return Err(failed!("Invalid DSCP {:?}, expected 0 to 63", self.dscp));
+        }
+        let done = Arc::new(AtomicBool::new(false));
+        let server = serve(&self.dst, self.target, done.clone())?;
+        let deadline = Instant::now() + self.duration;
+        let mut sessions = Vec::new();
+        for (p, persona) in self.personas.iter().enumerate(){
+            let params = persona.params();
+            let dscp = self.dscp.unwrap_or(params.dscp);
+            let mut threads = Vec::new();
+            for n in 0..self.sessions.unwrap_or(params.sessions){
+                let (persona, target) = (*persona, self.target);
+                let seed = self.seed.wrapping_add((p as u64) << 32 | n as u64);
+                threads.push(netns::spawn_in(&self.src, move || match persona{
+                    Persona::Web => web(target, dscp, deadline, seed),
+                    Persona::Bulk => bulk(target, dscp, deadline),
+                    Persona::Voip => voip(target, dscp, deadline),
+                }));
+            }
+            sessions.push((*persona, dscp, threads));
+        }
+        let mut reports = Vec::new();
+        let mut errors = Vec::new();
+        for (persona, dscp, threads) in sessions{
+            let mut finished = Vec::new();
+            for t in threads{
+                match t.join().map_err(|_| failed!("Session thread panicked")).and_then(|r| r){
+                    Ok(s) => finished.push(s),
+                    Err(e) => errors.push(format!("{} from {}: {}", persona, self.src, e)),
+                }
+            }
+            reports.push(report(persona, dscp, &finished, self.duration));
+        }
+        done.store(true, Ordering::SeqCst);
+        for t in server{
+            t.join().map_err(|_| failed!("Server thread panicked"))??;
+        }
+        if !errors.is_empty() {
+            return Err(failed!("{}", errors.join(", ")));
+        }
+        Ok(reports)
+    }
+}
+
+fn report(persona: Persona, dscp: u8, sessions: &[Session], duration: Duration) -> PersonaReport {
+    let bytes = sessions.iter().map(|s| s.bytes).sum::<u64>();
+    let latencies: Vec<f64> = sessions.iter().flat_map(|s| s.latencies.iter().cloned()).collect();
+    let mean = |v: &[f64]| if v.is_empty() { None } else { Some(v.iter().sum::<f64>() / v.len() as f64) };
+    let mut r = PersonaReport{
+        persona,
+        sessions: sessions.len() as u32,
+        dscp,
+        completed: sessions.iter().map(|s| s.completed).sum(),
+        failed: sessions.iter().map(|s| s.failed).sum(),
+        bytes,
+        throughput: bytes as f64 * 8.0 / duration.as_secs_f64().max(f64::EPSILON) / 1_000_000.0,
+        latency: mean(&latencies),
+        loss: None,
+        jitter: None,
+    };
+    if persona == Persona::Voip {
+        let sent = sessions.iter().map(|s| s.sent).sum::<u32>();
+        if sent > 0 {
+            r.loss = Some(sent.saturating_sub(latencies.len() as u32) as f64 * 100.0 / sent as f64);
+        }
+        let ipdv: Vec<f64> = sessions.iter()
+            .flat_map(|s| s.latencies.windows(2).map(|w| (w[1] - w[0]).abs()))
+            .collect();
+        r.jitter = mean(&ipdv);
+    }
+    r
+}
+
+/// Listens for TCP and UDP on `target` in `dst` until `done`.
+fn serve(dst: &str, target: SocketAddr, done: Arc<AtomicBool>) -> Result<Vec<std::thread::JoinHandle<Result<()>>>>{
+    let (listener, socket) = netns::run_in(dst, || {
+        let listener = TcpListener::bind(target)
+            .map_err(|e| failed!("Failed to listen on {} in {}: {}", target, dst, e))?;
+        // echoes come from the address the calls go to, not the one of the
+        // interface they leave by
+        let socket = UdpSocket::bind(target)
+            .map_err(|e| failed!("Failed to listen on {} in {}: {}", target, dst, e))?;
+        Ok((listener, socket))
+    })?;
+    // the sockets stay in the namespace they were opened in
+    let tcp = {
+        let done = done.clone();
+        std::thread::spawn(move || {
+            listener.set_nonblocking(true)?;
+            while !done.load(Ordering::SeqCst) {
+                match listener.accept(){
+                    Ok((stream, _)) => {
+                        std::thread::spawn(move || answer(stream));
+                    },
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => std::thread::sleep(Duration::from_millis(5)),
+                    Err(e) => return Err(failed!("Failed to accept: {}", e)),
+                }
+            }
+            Ok(())
+        })
+    };
+    let udp = std::thread::spawn(move || {
+        socket.set_read_timeout(Some(Duration::from_millis(50)))?;
+        let mut buf = [0u8; 1500];
+        while !done.load(Ordering::SeqCst) {
+            match socket.recv_from(&mut buf){
+                Ok((n, from)) => {
+                    let _ = socket.send_to(&buf[..n], from);
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {},
+                Err(e) => return Err(failed!("Failed to receive: {}", e)),
+            }
+        }
+        Ok(())
+    });
+    Ok(vec![tcp, udp])
+}
+
+/// Reads a request, the size of the response first, until the client is
+/// done sending and answers with that many bytes.
+fn answer(mut stream: TcpStream) -> Result<()>{
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut size = [0u8; 4];
+    stream.read_exact(&mut size)?;
+    std::io::copy(&mut stream, &mut std::io::sink())?;
+    let mut left = u32::from_be_bytes(size) as usize;
+    let buf = vec![0u8; 64 * 1024];
+    while left > 0 {
+        let n = left.min(buf.len());
+        stream.write_all(&buf[..n])?;
+        left -= n;
+    }
+    Ok(())
+}
+
+/// Connects to `target` with `dscp`. The mark is set once connected, the
+/// handshake goes out unmarked.
+fn connect(target: SocketAddr, dscp: u8) -> Result<TcpStream>{
+    let stream = TcpStream::connect_timeout(&target, Duration::from_secs(2))?;
+    set_dscp(&stream, target.is_ipv6(), dscp)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    Ok(stream)
+}
+
+/// Fetches an object of `size` bytes, returns the bytes moved.
+fn fetch(target: SocketAddr, dscp: u8, size: u32) -> Result<u64>{
+    let mut stream = connect(target, dscp)?;
+    let mut request = vec![0u8; REQUEST];
+    request[..4].copy_from_slice(&size.to_be_bytes());
+    stream.write_all(&request)?;
+    stream.shutdown(Shutdown::Write)?;
+    let received = std::io::copy(&mut stream, &mut std::io::sink())?;
+    if received != size as u64 {
+        return Err(failed!("Received {} of {} bytes", received, size));
+    }
+    Ok(REQUEST as u64 + received)
+}
+
+fn web(target: SocketAddr, dscp: u8, deadline: Instant, seed: u64) -> Result<Session>{
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut s = Session::default();
+    while Instant::now() < deadline {
+        for _ in 0..rng.gen_range(1..=8){
+            if Instant::now() >= deadline {
+                break;
+            }
+            // log-uniform, most objects are small
+            let size = (1024.0 * 1024f64.powf(rng.gen::<f64>())) as u32;
+            let start = Instant::now();
+            match fetch(target, dscp, size){
+                Ok(bytes) => {
+                    s.completed += 1;
+                    s.bytes += bytes;
+                    s.latencies.push(start.elapsed().as_secs_f64() * 1000.0);
+                },
+                Err(_) => s.failed += 1,
+            }
+        }
+        let think = Duration::from_millis(rng.gen_range(200..2000));
+        std::thread::sleep(think.min(deadline.saturating_duration_since(Instant::now())));
+    }
+    Ok(s)
+}
+
+fn bulk(target: SocketAddr, dscp: u8, deadline: Instant) -> Result<Session>{
+    let mut s = Session::default();
+    let buf = vec![0u8; 128 * 1024];
+    while Instant::now() < deadline {
+        let transfer = || -> Result<u64>{
+            let mut stream = connect(target, dscp)?;
+            stream.write_all(&0u32.to_be_bytes())?;
+            let mut sent = 4;
+            while Instant::now() < deadline {
+                stream.write_all(&buf)?;
+                sent += buf.len() as u64;
+            }
+            stream.shutdown(Shutdown::Write)?;
+            std::io::copy(&mut stream, &mut std::io::sink())?;
+            Ok(sent)
+        };
+        match transfer(){
+            Ok(bytes) => {
+                s.completed += 1;
+                s.bytes += bytes;
+            },
+            Err(_) => {
+                s.failed += 1;
+                std::thread::sleep(Duration::from_millis(100));
+            },
+        }
+    }
+    Ok(s)
+}
+
+/// A call until `deadline`, every datagram carries its sequence number
+/// and when it was sent.
+fn voip(target: SocketAddr, dscp: u8, deadline: Instant) -> Result<Session>{
+    let socket = UdpSocket::bind(if target.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" })?;
+    socket.connect(target)?;
+    set_dscp(&socket, target.is_ipv6(), dscp)?;
+    socket.set_nonblocking(true)?;
+    let start = Instant::now();
+    let mut s = Session::default();
+    let mut datagram = [0u8; VOICE];
+    let mut buf = [0u8; 1500];
+    let mut next = start;
+    // echoes of the last datagrams get a moment after the call ends
+    let end = deadline + Duration::from_millis(500);
+    loop{
+        let now = Instant::now();
+        if now >= next && now < deadline {
+            datagram[..4].copy_from_slice(&s.sent.to_be_bytes());
+            datagram[4..12].copy_from_slice(&((now - start).as_nanos() as u64).to_be_bytes());
+            // no route while routing changes, counts as lost
+            let _ = socket.send(&datagram);
+            s.sent += 1;
+            next += PACKETIZATION;
+        }
+        loop{
+            match socket.recv(&mut buf){
+                Ok(n) if n == VOICE => {
+                    let sent = u64::from_be_bytes(buf[4..12].try_into()?);
+                    let rtt = start.elapsed().as_nanos() as u64 - sent;
+                    s.latencies.push(rtt as f64 / 1_000_000.0);
+                    s.bytes += 2 * VOICE as u64;
+                },
+                Ok(_) => {},
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                // an ICMP error for an earlier datagram
+                Err(_) => break,
+            }
+        }
+        if now >= end || (now >= deadline && s.latencies.len() as u32 == s.sent) {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    if s.sent > 0 {
+        s.completed = 1;
+    }
+    Ok(s)
+}
+
+/// Marks what `socket` sends with `dscp`.
+fn set_dscp<S: AsRawFd>(socket: &S, v6: bool, dscp: u8) -> Result<()>{
+    let tos = (dscp as libc::c_int) << 2;
+    let (level, option) = if v6 { (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) } else { (libc::IPPROTO_IP, libc::IP_TOS) };
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            &tos as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(failed!("Failed to set DSCP {}: {}", dscp, std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
@@ -0,0 +1,502 @@
+//! Plan of an update: what applying a changed description to a running
+//! topology adds, removes and changes, computed from the saved state
+//! without touching the kernel. `diff` prints it, `apply` prints it and
+//! then brings the topology in line with `Topology::reconcile`, which
+//! creates only what is missing and removes only what is stale.
+//!
+//! Compared are namespaces, links and bridges with their subnets and ends,
+//! VXLAN links, tunnels and WireGuard links, host interfaces with their
+//! namespace, addresses and MTU, routes with their nexthops, VRFs, policy
+//! rules, processes and mirrors. Subnets and addresses left to IPAM are
+//! only known once assigned, a plan compares those it knows and routes
+//! derived from them, e.g. by `auto_routes`, may show up as added once the
+//! topology is applied.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::error::Result;
+use crate::interface;
+use crate::loopback;
+use crate::mirror::MirrorSpec;
+use crate::nat64;
+use crate::state::{InterfaceState, SegmentState, State};
+use crate::topology::{RouteSpec, Topology};
+
+/// (namespace, table, destination) of a route
+type RouteKey = (String, Option<u32>, String);
+/// (kind, gateway addresses if known) of a route
+type RouteValue = (String, Option<BTreeSet<String>>);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action{
+    Add,
+    Remove,
+    Change,
+}
+
+/// One change, e.g. adding link `ab` or the MTU of an interface.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Step{
+    pub action: Action,
+    /// kind and name, e.g. `link ab` or `route 10.0.1.0/24 in r1`
+    pub object: String,
+    /// what is added, or what changes from what to what
+    pub detail: String,
+}
+
+impl fmt::Display for Step{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (sign, verb) = match self.action{
+            Action::Add => ('+', "add"),
+            Action::Remove => ('-', "remove"),
+            Action::Change => ('~', "change"),
+        };
+        write!(f, "{} {} {}", sign, verb, self.object)?;
+        if !self.detail.is_empty() {
+            write!(f, ": {}", self.detail)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Plan{
+    pub topology: String,
+    /// whether the topology runs, without it everything is added
+    pub exists: bool,
+    pub steps: Vec<Step>,
+}
+
+impl Plan{
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Plan of applying `topology` to the running topology of its name,
+    /// see the module.
+    pub fn new(topology: &Topology) -> Result<Plan>{
+        let state = State::load(&topology.name)?;
+        Plan::between(state.as_ref(), topology)
+    }
+
+    /// Plan of going from `state`, empty if None, to `topology`.
+    pub fn between(state: Option<&State>, topology: &Topology) -> Result<Plan>{
+        let empty = State::default();
+        let exists = state.is_some();
+        let state = state.unwrap_or(&empty);
+        let mut plan = Plan{ topology: topology.name.clone(), exists, steps: Vec::new() };
+        plan.namespaces(state, topology);
+        plan.links(state, topology);
+        plan.bridges(state, topology);
+        plan.segments("VXLAN link", &state.vxlans, topology.vxlans.iter().map(|v| (&v.name, &v.subnet, &v.subnet6)));
+        plan.segments("tunnel", &state.tunnels, topology.tunnels.iter().map(|t| (&t.name, &t.subnet, &t.subnet6)));
+        plan.segments("WireGuard link", &state.wireguards, topology.wireguards.iter().map(|w| (&w.name, &w.subnet, &w.subnet6)));
+        plan.interfaces(state, topology);
+        plan.routes(state, topology)?;
+        plan.vrfs(state, topology);
+        plan.rules(state, topology)?;
+        plan.processes(state, topology);
+        plan.mirrors(state, topology);
+        Ok(plan)
+    }
+
+    fn add(&mut self, object: String, detail: String){
+        self.steps.push(Step{ action: Action::Add, object, detail });
+    }
+
+    fn remove(&mut self, object: String){
+        self.steps.push(Step{ action: Action::Remove, object, detail: String::new() });
+    }
+
+    fn change(&mut self, object: String, what: &str, from: impl fmt::Display, to: impl fmt::Display){
+        self.steps.push(Step{ action: Action::Change, object, detail: format!("{} {} -> {}", what, from, to) });
+    }
+
+    fn namespaces(&mut self, state: &State, topology: &Topology){
+        for ns in &topology.namespaces{
+            if !state.namespaces.iter().any(|s| s.name == ns.name) {
+                self.add(format!("namespace {}", ns.name), String::new());
+            }
+        }
+        for ns in &state.namespaces{
+            if !topology.namespaces.iter().any(|n| n.name == ns.name) {
+                self.remove(format!("namespace {}", ns.name));
+            }
+        }
+    }
+
+    fn links(&mut self, state: &State, topology: &Topology){
+        for l in &topology.links{
+            let object = format!("link {}", l.name);
+            let mut ends = l.endpoints.clone();
+            ends.sort();
+            let Some(s) = state.links.iter().find(|s| s.name == l.name) else {
+                self.add(object, format!("{} between {}", subnets(&l.subnet, &l.subnet6), ends.join(" and ")));
+                continue;
+            };
+            self.subnets(&object, s, &l.subnet, &l.subnet6);
+            let was = ends_of(state, &l.name);
+            if was != ends {
+                self.change(object, "ends", was.join(" and "), ends.join(" and "));
+            }
+        }
+        for s in &state.links{
+            if !topology.links.iter().any(|l| l.name == s.name) {
+                self.remove(format!("link {}", s.name));
+            }
+        }
+    }
+
+    fn bridges(&mut self, state: &State, topology: &Topology){
+        for b in &topology.bridges{
+            let object = format!("bridge {}", b.name);
+            let mut members = b.members.clone();
+            members.sort();
+            let Some(s) = state.bridges.iter().find(|s| s.name == b.name) else {
+                self.add(object, format!("{} with {}", subnets(&b.subnet, &b.subnet6), members.join(", ")));
+                continue;
+            };
+            self.subnets(&object, s, &b.subnet, &b.subnet6);
+            let was = ends_of(state, &b.name);
+            if was != members {
+                self.change(object, "members", was.join(", "), members.join(", "));
+            }
+        }
+        for s in &state.bridges{
+            if !topology.bridges.iter().any(|b| b.name == s.name) {
+                self.remove(format!("bridge {}", s.name));
+            }
+        }
+    }
+
+    /// Segments without ends of their own in the state, compared by name
+    /// and subnets.
+    fn segments<'a>(&mut self, kind: &str, saved: &[SegmentState], described: impl Iterator<Item = (&'a String, &'a String, &'a Option<String>)>){
+        let mut names = BTreeSet::new();
+        for (name, subnet, subnet6) in described{
+            names.insert(name.clone());
+            let object = format!("{} {}", kind, name);
+            match saved.iter().find(|s| s.name == *name){
+                Some(s) => self.subnets(&object, s, subnet, subnet6),
+                None => self.add(object, subnets(subnet, subnet6)),
+            }
+        }
+        for s in saved.iter().filter(|s| !names.contains(&s.name)){
+            self.remove(format!("{} {}", kind, s.name));
+        }
+    }
+
+    /// Changed hand-assigned subnets of a segment, allocated ones are kept.
+    fn subnets(&mut self, object: &str, saved: &SegmentState, subnet: &str, subnet6: &Option<String>){
+        if !subnet.is_empty() && saved.subnet != subnet {
+            self.change(object.to_string(), "subnet", &saved.subnet, subnet);
+        }
+        if subnet6.is_some() && saved.subnet6 != *subnet6 {
+            self.change(object.to_string(), "IPv6 subnet", saved.subnet6.as_deref().unwrap_or("none"), subnet6.as_deref().unwrap_or("none"));
+        }
+    }
+
+    fn interfaces(&mut self, state: &State, topology: &Topology){
+        let netns = |ns: &Option<String>| ns.as_ref().map(|ns| crate::Namespace::netns_name(&topology.name, ns));
+        for i in &topology.interfaces{
+            let at = i.namespace.as_deref().map(|ns| format!(" in {}", ns)).unwrap_or_default();
+            let object = format!("interface {}{}", i.name, at);
+            let Some(s) = state.interfaces.iter().find(|s| s.name == i.name || s.name == interface::shorten(&i.name)) else {
+                let addresses: Vec<&str> = [&i.ip, &i.ip6].into_iter().flatten().map(|a| a.as_str()).collect();
+                self.add(object, addresses.join(" "));
+                continue;
+            };
+            let moved = netns(&i.namespace);
+            if s.netns != moved {
+                let name = |n: &Option<String>| n.as_ref()
+                    .map(|n| n.strip_prefix(&format!("{}-", topology.name)).unwrap_or(n).to_string())
+                    .unwrap_or("host".to_string());
+                self.change(object.clone(), "namespace", name(&s.netns), name(&moved));
+            }
+            for (what, was, wanted) in [("address", &s.ip, &i.ip), ("IPv6 address", &s.ip6, &i.ip6)]{
+                if wanted.is_some() && was != wanted {
+                    self.change(object.clone(), what, was.as_deref().unwrap_or("none"), wanted.as_deref().unwrap_or("none"));
+                }
+            }
+            if i.mtu.is_some() && s.mtu != i.mtu {
+                let mtu = |m: Option<u32>| m.map(|m| m.to_string()).unwrap_or("default".to_string());
+                self.change(object, "MTU", mtu(s.mtu), mtu(i.mtu));
+            }
+        }
+        for s in host_interfaces(state){
+            if !topology.interfaces.iter().any(|i| s.name == i.name || s.name == interface::shorten(&i.name)) {
+                self.remove(format!("interface {}", s.name));
+            }
+        }
+    }
+
+    fn routes(&mut self, state: &State, topology: &Topology) -> Result<()>{
+        let name_of: HashMap<&str, &str> = state.namespaces.iter().map(|n| (n.netns.as_str(), n.name.as_str())).collect();
+        let mut saved: BTreeMap<RouteKey, RouteValue> = BTreeMap::new();
+        for r in &state.routes{
+            let ns = name_of.get(r.netns.as_str()).copied().unwrap_or(&r.netns);
+            saved.insert((ns.to_string(), r.table, dst(&r.dst)), (r.kind.to_string(), Some(r.via.iter().cloned().collect())));
+        }
+        // ends of renumbered segments, whose new addresses aren't known
+        let mut renumbered = BTreeSet::new();
+        let described = topology.links.iter().map(|l| (&l.name, &l.subnet))
+            .chain(topology.bridges.iter().map(|b| (&b.name, &b.subnet)));
+        for (name, subnet) in described.filter(|(_, subnet)| !subnet.is_empty()){
+            if state.links.iter().chain(&state.bridges).any(|s| s.name == *name && s.subnet != *subnet) {
+                renumbered.extend(state.namespaces.iter().map(|ns| interface::name(&ns.name, name)));
+            }
+        }
+        let mut wanted = BTreeMap::new();
+        for r in topology.route_specs_with(&known_subnets(state, topology))?{
+            let table = topology.table_of(&r)?;
+            wanted.insert((r.namespace.clone(), table, dst(&r.dst)), (r.kind.to_string(), gateways(state, &renumbered, &r)));
+        }
+        let object = |(ns, table, dst): &RouteKey| match table{
+            Some(table) => format!("route {} in {} table {}", dst, ns, table),
+            None => format!("route {} in {}", dst, ns),
+        };
+        let via = |v: &BTreeSet<String>| match v.is_empty(){
+            true => "none".to_string(),
+            false => v.iter().cloned().collect::<Vec<_>>().join(" "),
+        };
+        for (key, (kind, gateways)) in &wanted{
+            let Some((was_kind, was)) = saved.get(key) else {
+                let detail = match gateways{
+                    Some(g) if !g.is_empty() => format!("via {}", via(g)),
+                    _ if kind != "unicast" => kind.clone(),
+                    _ => String::new(),
+                };
+                self.add(object(key), detail);
+                continue;
+            };
+            if kind != was_kind {
+                self.change(object(key), "kind", was_kind, kind);
+            }
+            // gateways on interfaces without known addresses can't be
+            // compared
+            if let (Some(gateways), Some(was)) = (gateways, was) {
+                if gateways != was {
+                    self.change(object(key), "nexthops", via(was), via(gateways));
+                }
+            }
+        }
+        for key in saved.keys().filter(|k| !wanted.contains_key(*k)){
+            self.remove(object(key));
+        }
+        Ok(())
+    }
+
+    fn vrfs(&mut self, state: &State, topology: &Topology){
+        let mut described = BTreeSet::new();
+        for ns in &topology.namespaces{
+            let netns = crate::Namespace::netns_name(&topology.name, &ns.name);
+            for v in &ns.vrfs{
+                described.insert((netns.clone(), v.name.clone()));
+                let object = format!("VRF {} in {}", v.name, ns.name);
+                match state.vrfs.iter().find(|s| s.netns == netns && s.name == v.name){
+                    Some(s) if s.table != v.table => self.change(object, "table", s.table, v.table),
+                    Some(s) if s.interfaces != v.interfaces => self.change(object, "interfaces", s.interfaces.join(", "), v.interfaces.join(", ")),
+                    Some(_) => {},
+                    None => self.add(object, format!("table {}", v.table)),
+                }
+            }
+        }
+        for s in state.vrfs.iter().filter(|s| !described.contains(&(s.netns.clone(), s.name.clone()))){
+            self.remove(format!("VRF {} in {}", s.name, namespace_name(state, &s.netns)));
+        }
+    }
+
+    fn rules(&mut self, state: &State, topology: &Topology) -> Result<()>{
+        let describe = |r: &crate::policy::PolicyRule| -> Result<String> { Ok(r.args()?.join(" ")) };
+        for ns in &topology.namespaces{
+            let netns = crate::Namespace::netns_name(&topology.name, &ns.name);
+            let saved: Vec<_> = state.rules.iter().filter(|r| r.netns == netns).map(|r| &r.rule).collect();
+            for r in ns.rules.iter().filter(|r| !saved.contains(r)){
+                self.add(format!("rule in {}", ns.name), describe(r)?);
+            }
+            for r in saved.iter().filter(|r| !ns.rules.contains(r)){
+                self.remove(format!("rule in {}: {}", ns.name, describe(r)?));
+            }
+        }
+        for r in state.rules.iter().filter(|r| !topology.namespaces.iter().any(|n| crate::Namespace::netns_name(&topology.name, &n.name) == r.netns)){
+            self.remove(format!("rule in {}: {}", namespace_name(state, &r.netns), describe(&r.rule)?));
+        }
+        Ok(())
+    }
+
+    fn processes(&mut self, state: &State, topology: &Topology){
+        for ns in &topology.namespaces{
+            let netns = crate::Namespace::netns_name(&topology.name, &ns.name);
+            for p in &ns.processes{
+                let object = format!("process {} in {}", p.name, ns.name);
+                match state.processes.iter().find(|s| s.netns == netns && s.name == p.name){
+                    Some(s) if s.command != p.command => self.change(object, "command", s.command.join(" "), p.command.join(" ")),
+                    Some(s) if s.restart != p.restart => self.change(object, "restart", s.restart, p.restart),
+                    Some(_) => {},
+                    None => self.add(object, p.command.join(" ")),
+                }
+            }
+        }
+        for s in &state.processes{
+            let described = topology.namespaces.iter()
+                .any(|n| crate::Namespace::netns_name(&topology.name, &n.name) == s.netns && n.processes.iter().any(|p| p.name == s.name));
+            if !described {
+                self.remove(format!("process {} in {}", s.name, namespace_name(state, &s.netns)));
+            }
+        }
+    }
+
+    fn mirrors(&mut self, state: &State, topology: &Topology){
+        for m in &topology.mirrors{
+            // saved with the kernel's names of the interfaces
+            let m = &m.kernel_names(topology);
+            let object = format!("mirror {}", m.name);
+            let to = |m: &MirrorSpec| match (&m.to, &m.monitor){
+                (Some(to), _) => format!("{} {} of {} to {}", m.direction, m.interface, m.namespace, to),
+                (None, monitor) => format!("{} {} of {} to namespace {}", m.direction, m.interface, m.namespace, monitor.as_deref().unwrap_or_default()),
+            };
+            match state.mirrors.iter().find(|s| s.name == m.name){
+                Some(s) if s != m => self.change(object, "source and target", to(s), to(m)),
+                Some(_) => {},
+                None => self.add(object, to(m)),
+            }
+        }
+        for s in state.mirrors.iter().filter(|s| !topology.mirrors.iter().any(|m| m.name == s.name)){
+            self.remove(format!("mirror {}", s.name));
+        }
+    }
+}
+
+impl fmt::Display for Plan{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.steps.is_empty() {
+            return write!(f, "{} is up to date", self.topology);
+        }
+        match self.exists{
+            true => write!(f, "{} changes:", self.topology)?,
+            false => write!(f, "{} does not run, applying creates:", self.topology)?,
+        }
+        for s in &self.steps{
+            write!(f, "\n  {}", s)?;
+        }
+        Ok(())
+    }
+}
+
+fn subnets(subnet: &str, subnet6: &Option<String>) -> String {
+    let mut s: Vec<&str> = Vec::new();
+    if !subnet.is_empty() {
+        s.push(subnet);
+    }
+    if let Some(subnet6) = subnet6{
+        s.push(subnet6);
+    }
+    if s.is_empty() {
+        return "subnet from IPAM".to_string();
+    }
+    s.join(" ")
+}
+
+/// Namespaces holding an end of segment `segment` in `state`, sorted.
+fn ends_of(state: &State, segment: &str) -> Vec<String> {
+    let mut ends: Vec<String> = state.namespaces.iter()
+        .filter(|ns| {
+            let name = interface::name(&ns.name, segment);
+            state.interfaces.iter().any(|i| i.name == name && i.netns.as_deref() == Some(ns.netns.as_str()))
+        })
+        .map(|ns| ns.name.clone())
+        .collect();
+    ends.sort();
+    ends
+}
+
+/// Interfaces of `state` which are neither ends of a segment, bridge
+/// ports, loopbacks nor NAT64 devices.
+fn host_interfaces(state: &State) -> Vec<&InterfaceState> {
+    let mut derived = BTreeSet::new();
+    let segments: Vec<&str> = state.links.iter()
+        .chain(&state.bridges)
+        .chain(&state.vxlans)
+        .chain(&state.tunnels)
+        .chain(&state.wireguards)
+        .map(|s| s.name.as_str())
+        .collect();
+    for ns in &state.namespaces{
+        for s in &segments{
+            derived.insert(interface::name(&ns.name, s));
+        }
+        for b in &state.bridges{
+            derived.insert(interface::name(&b.name, &ns.name));
+        }
+        derived.insert(loopback::name(&ns.name));
+        derived.insert(nat64::name(&ns.name));
+    }
+    state.interfaces.iter().filter(|i| !derived.contains(&i.name)).collect()
+}
+
+/// Subnets of the segments, loopbacks and NAT64 devices as far as known:
+/// hand-assigned in `topology`, or assigned when `state` was saved.
+fn known_subnets(state: &State, topology: &Topology) -> HashMap<String, (String, Option<String>)> {
+    let mut subnets = HashMap::new();
+    for s in state.links.iter().chain(&state.bridges){
+        subnets.insert(s.name.clone(), (s.subnet.clone(), s.subnet6.clone()));
+    }
+    let described = topology.links.iter().map(|l| (&l.name, &l.subnet, &l.subnet6))
+        .chain(topology.bridges.iter().map(|b| (&b.name, &b.subnet, &b.subnet6)));
+    for (name, subnet, subnet6) in described.filter(|(_, subnet, _)| !subnet.is_empty()){
+        subnets.insert(name.clone(), (subnet.clone(), subnet6.clone()));
+    }
+    for ns in &state.namespaces{
+        for name in [loopback::name(&ns.name), nat64::name(&ns.name)]{
+            let Some(i) = state.interfaces.iter().find(|i| i.name == name) else {
+                continue;
+            };
+            let net = |ip: &String| ip.parse::<ipnet::IpNet>().map(|n| n.trunc().to_string()).unwrap_or(ip.clone());
+            match (&i.ip, &i.ip6){
+                (Some(ip), ip6) => subnets.insert(name, (net(ip), ip6.as_ref().map(net))),
+                (None, Some(ip6)) => subnets.insert(name, (net(ip6), None)),
+                (None, None) => None,
+            };
+        }
+    }
+    subnets
+}
+
+/// Gateway addresses of `route`, None if an interface it names has no
+/// known address or is one of `renumbered`.
+fn gateways(state: &State, renumbered: &BTreeSet<String>, route: &RouteSpec) -> Option<BTreeSet<String>> {
+    let v6 = route.dst.contains(':');
+    let address = |name: &String| -> Option<String> {
+        let i = state.interfaces.iter().find(|i| i.name == *name || i.name == interface::shorten(name))?;
+        if renumbered.contains(&i.name) {
+            return None;
+        }
+        let ip = if v6 { i.ip6.as_ref() } else { i.ip.as_ref() }?;
+        Some(ip.split('/').next().unwrap_or(ip).to_string())
+    };
+    let mut gateways = BTreeSet::new();
+    for gw in &route.gateways{
+        gateways.insert(address(gw)?);
+    }
+    for n in &route.nexthops{
+        match (&n.address, &n.via){
+            (Some(a), _) => gateways.insert(a.clone()),
+            (None, Some(via)) => gateways.insert(address(via)?),
+            (None, None) => false,
+        };
+    }
+    Some(gateways)
+}
+
+/// Destination as the kernel keeps it, host bits cleared.
+fn dst(dst: &str) -> String {
+    dst.parse::<ipnet::IpNet>().map(|n| n.trunc().to_string()).unwrap_or(dst.to_string())
+}
+
+fn namespace_name<'a>(state: &'a State, netns: &'a str) -> &'a str {
+    state.namespaces.iter().find(|n| n.netns == netns).map(|n| n.name.as_str()).unwrap_or(netns)
+}
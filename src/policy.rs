@@ -0,0 +1,198 @@
+//! Policy routing: `ip rule` entries selecting the routing table a packet
+//! is looked up in by source, destination, firewall mark or interface.
+//! Together with routes in tables other than main, see `RouteSpec::table`,
+//! this emulates policy-based routing and VRF-lite, e.g. one table per
+//! customer selected by the incoming interface.
+//!
+//! The kernel's own rules, lookup in local, main and default, are never
+//! touched.
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::{failed, Result};
+
+/// Kernel ids of the tables `ip` knows by name.
+pub const TABLE_DEFAULT: u32 = 253;
+pub const TABLE_MAIN: u32 = 254;
+pub const TABLE_LOCAL: u32 = 255;
+
+/// Rule looking up `table` for packets matching all of the given
+/// selectors. A rule with `from` or `to` is of their family, one without
+/// is installed for IPv4 and IPv6.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PolicyRule{
+    /// assigned by the kernel, below the previous rule, if not set
+    #[serde(default)]
+    pub priority: Option<u32>,
+    /// source prefix
+    #[serde(default)]
+    pub from: Option<String>,
+    /// destination prefix
+    #[serde(default)]
+    pub to: Option<String>,
+    #[serde(default)]
+    pub fwmark: Option<u32>,
+    /// bits of the mark compared with `fwmark`, all by default
+    #[serde(default)]
+    pub fwmask: Option<u32>,
+    /// incoming interface, `lo` for locally generated traffic
+    #[serde(default)]
+    pub iif: Option<String>,
+    /// outgoing interface, for sockets bound to it
+    #[serde(default)]
+    pub oif: Option<String>,
+    pub table: u32,
+}
+
+impl PolicyRule{
+    /// Families the rule is installed for, IPv6 as true.
+    pub fn families(&self) -> Result<Vec<bool>>{
+        let (from, to) = (prefix(&self.from)?, prefix(&self.to)?);
+        match (from, to){
+            (Some(from), Some(to)) if from.addr().is_ipv6() != to.addr().is_ipv6() =>
+                Err(failed!("Policy rule mixes IPv4 and IPv6: {} to {}", from, to)),
+            (Some(net), _) | (None, Some(net)) => Ok(vec![net.addr().is_ipv6()]),
+            (None, None) => Ok(vec![false, true]),
+        }
+    }
+
+    /// `ip rule` arguments following `add` or `del`.
+    pub fn args(&self) -> Result<Vec<String>>{
+        if self.fwmask.is_some() && self.fwmark.is_none() {
+            return Err(failed!("Policy rule has fwmask without fwmark"));
+        }
+        let mut args = Vec::new();
+        if let Some(priority) = self.priority{
+            args.extend(["priority".to_string(), priority.to_string()]);
+        }
+        if let Some(from) = prefix(&self.from)?{
+            args.extend(["from".to_string(), from.to_string()]);
+        }
+        if let Some(to) = prefix(&self.to)?{
+            args.extend(["to".to_string(), to.to_string()]);
+        }
+        match (self.fwmark, self.fwmask){
+            (Some(mark), Some(mask)) => args.extend(["fwmark".to_string(), format!("{:#x}/{:#x}", mark, mask)]),
+            (Some(mark), None) => args.extend(["fwmark".to_string(), format!("{:#x}", mark)]),
+            _ => {},
+        }
+        if let Some(iif) = &self.iif{
+            args.extend(["iif".to_string(), iif.clone()]);
+        }
+        if let Some(oif) = &self.oif{
+            args.extend(["oif".to_string(), oif.clone()]);
+        }
+        args.extend(["table".to_string(), self.table.to_string()]);
+        Ok(args)
+    }
+
+    /// True if `installed` is this rule, the priority is compared only if
+    /// this rule sets one.
+    pub fn matches(&self, installed: &PolicyRule) -> bool {
+        let same = |a: &Option<String>, b: &Option<String>| prefix(a).ok().flatten() == prefix(b).ok().flatten();
+        (self.priority.is_none() || self.priority == installed.priority)
+            && same(&self.from, &installed.from)
+            && same(&self.to, &installed.to)
+            && self.fwmark == installed.fwmark
+            && self.fwmark.map(|_| self.fwmask.unwrap_or(u32::MAX)) == installed.fwmark.map(|_| installed.fwmask.unwrap_or(u32::MAX))
+            && self.iif == installed.iif
+            && self.oif == installed.oif
+            && self.table == installed.table
+    }
+
+    /// Rule as listed by `ip -j rule show`, None for the kernel's own and
+    /// for rules this module can't express.
+    fn from_json(r: &serde_json::Value) -> Option<PolicyRule> {
+        let hex = |v: &serde_json::Value| v.as_str().and_then(|s| u32::from_str_radix(s.trim_start_matches("0x"), 16).ok());
+        let prefix = |addr: &serde_json::Value, len: &serde_json::Value| match (addr.as_str(), len.as_u64()){
+            (Some("all"), _) | (None, _) => None,
+            (Some(addr), Some(len)) => Some(format!("{}/{}", addr, len)),
+            (Some(addr), None) => Some(addr.to_string()),
+        };
+        let table = match r["table"].as_str()?{
+            "local" => TABLE_LOCAL,
+            "main" => TABLE_MAIN,
+            "default" => TABLE_DEFAULT,
+            t => t.parse().ok()?,
+        };
+        let rule = PolicyRule{
+            priority: r["priority"].as_u64().map(|p| p as u32),
+            from: prefix(&r["src"], &r["srclen"]),
+            to: prefix(&r["dst"], &r["dstlen"]),
+            fwmark: hex(&r["fwmark"]),
+            fwmask: hex(&r["fwmask"]).filter(|m| *m != u32::MAX),
+            iif: r["iif"].as_str().map(|i| i.to_string()),
+            oif: r["oif"].as_str().map(|o| o.to_string()),
+            table,
+        };
+        let kernel = [(0, TABLE_LOCAL), (32766, TABLE_MAIN), (32767, TABLE_DEFAULT)].into_iter().any(|(priority, table)| PolicyRule{
+            priority: Some(priority),
+            table,
+            ..Default::default()
+        } == rule);
+        if kernel {
+            return None;
+        }
+        Some(rule)
+    }
+}
+
+/// Table of a route listed with `ip route show table all`, `Some(None)` for
+/// main. None for routes of the local and default tables, which aren't
+/// ours.
+pub(crate) fn route_table(r: &serde_json::Value) -> Option<Option<u32>> {
+    match r["table"].as_str(){
+        None | Some("main") => Some(None),
+        Some(t) => t.parse().ok().map(Some),
+    }
+}
+
+fn prefix(prefix: &Option<String>) -> Result<Option<ipnet::IpNet>>{
+    prefix.as_ref()
+        .map(|p| p.parse::<ipnet::IpNet>()
+            .map(|n| n.trunc())
+            .map_err(|e| failed!("Invalid prefix {} in policy rule: {}", p, e)))
+        .transpose()
+}
+
+/// Rules of `netns` other than the kernel's, with their family.
+pub fn installed(netns: &str) -> Result<Vec<(bool, PolicyRule)>>{
+    let mut rules = Vec::new();
+    for (family, v6) in [("-4", false), ("-6", true)]{
+        let listed: serde_json::Value = serde_json::from_str(&cmd::ip(Some(netns), &[family, "-j", "rule", "show"])?)?;
+        for r in listed.as_array().cloned().unwrap_or_default(){
+            if let Some(rule) = PolicyRule::from_json(&r){
+                rules.push((v6, rule));
+            }
+        }
+    }
+    Ok(rules)
+}
+
+/// Adds `rules` to `netns`. When reconciling, rules already installed are
+/// kept and the others not in `rules` removed.
+pub fn apply(netns: &str, rules: &[PolicyRule], reconcile: bool) -> Result<()>{
+    let installed = if reconcile { installed(netns)? } else { Vec::new() };
+    for (v6, old) in &installed{
+        if !rules.iter().any(|r| r.families().is_ok_and(|f| f.contains(v6)) && r.matches(old)) {
+            let mut args = vec![if *v6 { "-6" } else { "-4" }.to_string(), "rule".to_string(), "del".to_string()];
+            args.extend(old.args()?);
+            let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+            cmd::ip(Some(netns), &args)?;
+        }
+    }
+    for rule in rules{
+        for v6 in rule.families()?{
+            if installed.iter().any(|(f, old)| *f == v6 && rule.matches(old)) {
+                continue;
+            }
+            let mut args = vec![if v6 { "-6" } else { "-4" }.to_string(), "rule".to_string(), "add".to_string()];
+            args.extend(rule.args()?);
+            let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+            cmd::ip(Some(netns), &args)
+                .map_err(|e| failed!("Failed to add policy rule in {}: {}", netns, e))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,296 @@
+//! Pre-provisioned namespaces and veth pairs. Free ones are told apart by
+//! their names in the kernel, so taking from and returning to the pool
+//! works across runs. Namespaces are scrubbed on return: interfaces are
+//! removed and what router-rs changed while they were lent out, kept in a
+//! journal, is put back. Settings applied by hand are not undone.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+use crate::cmd;
+use crate::error::{failed, Result};
+use crate::state::STATE_DIR;
+use crate::{owner, Namespace, Veth};
+
+/// Free pool namespaces are named `rrs-pool-<n>`.
+const NS_POOL: &str = "rrs-pool";
+/// Free veth pairs live in the root namespace as `rrsp<n>a`/`rrsp<n>b`. While
+/// lent out, each end keeps its pool name as interface alias.
+const VETH_PREFIX: &str = "rrsp";
+
+/// What a namespace lent out of the pool had before router-rs changed it.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Journal{
+    /// original value of every sysctl set
+    sysctls: BTreeMap<String, String>,
+    /// txqueuelen of the pool veth ends moved in, by pool name. Their root
+    /// qdisc is the kernel's default one, as `fill` created them
+    veths: BTreeMap<String, u32>,
+}
+
+impl Journal{
+    fn path(netns: &str) -> PathBuf {
+        PathBuf::from(STATE_DIR).join("pool").join(format!("{}.json", netns))
+    }
+
+    /// Journal of `netns`, None unless it was taken from the pool.
+    fn load(netns: &str) -> Result<Option<Journal>>{
+        match std::fs::read_to_string(Journal::path(netns)){
+            Ok(data) => Ok(Some(serde_json::from_str(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, netns: &str) -> Result<()>{
+        let path = Journal::path(netns);
+        std::fs::create_dir_all(path.parent().unwrap_or(STATE_DIR.as_ref()))?;
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+pub struct PoolStatus{
+    pub namespaces: usize,
+    pub veths: usize,
+}
+
+pub fn status() -> Result<PoolStatus>{
+    Ok(PoolStatus{
+        namespaces: free_namespaces()?.len(),
+        veths: free_veths()?.len(),
+    })
+}
+
+/// Tops the pool up to `namespaces` free namespaces and `veths` free pairs.
+pub fn fill(namespaces: usize, veths: usize) -> Result<()>{
+    let free = free_namespaces()?;
+    let mut used: BTreeSet<usize> = free.iter().filter_map(|n| pool_index(n)).collect();
+    for _ in free.len()..namespaces{
+        let index = next_index(&used);
+        used.insert(index);
+        cmd::ip(None, &["netns", "add", format!("{}-{}", NS_POOL, index).as_str()])?;
+    }
+
+    let free = free_veths()?;
+    let mut used = lent_veths()?;
+    used.extend(free.iter().cloned());
+    for _ in free.len()..veths{
+        let index = next_index(&used);
+        used.insert(index);
+        let (a, b) = veth_names(index);
+        cmd::ip(None, &["link", "add", "name", a.as_str(), "type", "veth", "peer", "name", b.as_str()])?;
+    }
+    Ok(())
+}
+
+/// Deletes all free namespaces and veth pairs.
+pub fn drain() -> Result<()>{
+    for ns in free_namespaces()?{
+        Namespace::delete(&ns)?;
+    }
+    for index in free_veths()?{
+        let (a, _) = veth_names(index);
+        cmd::ip(None, &["link", "del", a.as_str()])?;
+    }
+    Ok(())
+}
+
+/// Renames a free pool namespace to `netns`. Returns false if the pool is
+/// empty, in which case the caller creates the namespace itself.
+pub fn take_namespace(netns: &str) -> Result<bool>{
+    let free = free_namespaces()?;
+    let ns = match free.first(){
+        Some(ns) => ns,
+        None => return Ok(false),
+    };
+    rename_netns(ns, netns)?;
+    Journal::default().save(netns)?;
+    Ok(true)
+}
+
+/// Keeps the values `settings`, `<key>=<value>` sysctl assignments, are
+/// about to replace in `netns` if it was taken from the pool, so releasing
+/// it puts them back. Keys kept before keep their first value.
+pub(crate) fn remember_sysctls<S: AsRef<str>>(netns: &str, settings: &[S]) -> Result<()>{
+    let Some(mut journal) = Journal::load(netns)? else {
+        return Ok(());
+    };
+    let mut args = vec!["-e"];
+    args.extend(settings.iter()
+        .filter_map(|s| s.as_ref().split_once('=').map(|(k, _)| k))
+        .filter(|k| !journal.sysctls.contains_key(*k)));
+    if args.len() == 1 {
+        return Ok(());
+    }
+    for line in cmd::exec(netns, "sysctl", &args)?.lines(){
+        if let Some((key, value)) = line.split_once(" = ") {
+            // tcp_rmem and the like are printed tab separated
+            journal.sysctls.insert(key.to_string(), value.split_whitespace().collect::<Vec<_>>().join(" "));
+        }
+    }
+    journal.save(netns)
+}
+
+/// Moves a free veth pair into the namespaces of `veth` and renames both
+/// ends. Returns false if the pool has no free pair.
+pub(crate) fn take_veth(veth: &Veth) -> Result<bool>{
+    let index = match free_veths()?.into_iter().next(){
+        Some(index) => index,
+        None => return Ok(false),
+    };
+    let (a, b) = veth_names(index);
+    for (end, netns, name) in [(&a, &veth.namespace, &veth.name), (&b, &veth.peer_namespace, &veth.peer)]{
+        if let Some(mut journal) = Journal::load(netns)? {
+            let link = cmd::ip_json(None, &["-j", "link", "show", "dev", end.as_str()])?;
+            let txqueuelen = link[0]["txqlen"].as_u64().unwrap_or(1000) as u32;
+            journal.veths.insert(end.clone(), txqueuelen);
+            journal.save(netns)?;
+        }
+        cmd::ip(None, &["link", "set", "dev", end.as_str(), "netns", netns.as_str()])?;
+        cmd::ip(Some(netns.as_str()), &["link", "set", "dev", end.as_str(), "name", name.as_str(), "alias", end.as_str()])?;
+    }
+    Ok(true)
+}
+
+/// Returns a namespace and the pool veths inside it to the pool, putting
+/// back the sysctls and veth queues its journal kept. Other virtual
+/// interfaces are deleted, physical ones moved back to the host.
+pub fn release_namespace(netns: &str) -> Result<()>{
+    let journal = Journal::load(netns)?.unwrap_or_default();
+    // while the interfaces of per-interface sysctls still exist, those of
+    // interfaces gone since are skipped
+    if !journal.sysctls.is_empty() {
+        let settings: Vec<String> = journal.sysctls.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        let mut args = vec!["-q", "-e", "-w"];
+        args.extend(settings.iter().map(|s| s.as_str()));
+        cmd::exec(netns, "sysctl", &args)?;
+    }
+    let out = cmd::ip(Some(netns), &["-d", "-j", "link", "show"])?;
+    let links: serde_json::Value = serde_json::from_str(&out)?;
+    for l in links.as_array().cloned().unwrap_or_default(){
+        let name = l["ifname"].as_str().unwrap_or_default();
+        if name == "lo" || name.is_empty() {
+            continue;
+        }
+        let alias = l["ifalias"].as_str().unwrap_or_default();
+        if alias.starts_with(VETH_PREFIX) {
+            if let Some(txqueuelen) = journal.veths.get(alias) {
+                cmd::ip(Some(netns), &["link", "set", "dev", name, "txqueuelen", txqueuelen.to_string().as_str()])?;
+                // deleting a root qdisc set up brings back the default one
+                if !default_qdisc(netns, name)? {
+                    cmd::tc(Some(netns), &["qdisc", "del", "dev", name, "root"])?;
+                }
+            }
+            cmd::ip(Some(netns), &["link", "set", "dev", name, "down"])?;
+            cmd::ip(Some(netns), &["addr", "flush", "dev", name])?;
+            cmd::ip(Some(netns), &["link", "set", "dev", name, "mtu", "1500", "name", alias])?;
+            cmd::ip(Some(netns), &["link", "set", "dev", alias, "netns", "1"])?;
+        } else if l["linkinfo"]["info_kind"].is_string() {
+            // deleting one end of a veth removes the peer as well
+            let _ = cmd::ip(Some(netns), &["link", "del", "dev", name]);
+        } else {
+            cmd::ip(Some(netns), &["link", "set", "dev", name, "netns", "1"])?;
+        }
+    }
+    owner::untag_namespace(netns)?;
+    clock::record(netns, None)?;
+    let used: BTreeSet<usize> = free_namespaces()?.iter().filter_map(|n| pool_index(n)).collect();
+    rename_netns(netns, &format!("{}-{}", NS_POOL, next_index(&used)))?;
+    match std::fs::remove_file(Journal::path(netns)){
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// True if the root qdisc of `dev` in `netns` is the one the kernel gave
+/// it, which has no handle.
+fn default_qdisc(netns: &str, dev: &str) -> Result<bool>{
+    let qdiscs: serde_json::Value = serde_json::from_str(&cmd::tc(Some(netns), &["-j", "qdisc", "show", "dev", dev, "root"])?)?;
+    Ok(qdiscs[0]["handle"].as_str().is_none_or(|h| h == "0:"))
+}
+
+/// Named namespaces are bind mounts of the nsfs inode under /run/netns, so a
+/// rename is a new bind mount plus removal of the old one.
+fn rename_netns(from: &str, to: &str) -> Result<()>{
+    let from = format!("/run/netns/{}", from);
+    let to = format!("/run/netns/{}", to);
+    if std::path::Path::new(&to).exists() {
+        return Err(failed!("Namespace {} already exists", to));
+    }
+    std::fs::File::create(&to)?;
+    if let Err(e) = cmd::run("mount", &["--bind", from.as_str(), to.as_str()]) {
+        let _ = std::fs::remove_file(&to);
+        return Err(e.context(format!("Failed to rename namespace {}", from)));
+    }
+    cmd::run("umount", &[from.as_str()])
+        .map_err(|e| e.context(format!("Failed to rename namespace {}", from)))?;
+    std::fs::remove_file(&from)?;
+    Ok(())
+}
+
+fn free_veths() -> Result<BTreeSet<usize>>{
+    let out = cmd::ip(None, &["-j", "link", "show", "type", "veth"])?;
+    let links: serde_json::Value = serde_json::from_str(&out)?;
+    let names: BTreeSet<String> = links.as_array().cloned().unwrap_or_default().iter()
+        .filter_map(|l| l["ifname"].as_str().map(|n| n.to_string()))
+        .collect();
+    Ok(names.iter()
+        .filter_map(|n| veth_index(n))
+        .filter(|i| {
+            let (a, b) = veth_names(*i);
+            names.contains(&a) && names.contains(&b)
+        })
+        .collect())
+}
+
+/// Indices of pool veths currently lent out to some namespace.
+fn lent_veths() -> Result<BTreeSet<usize>>{
+    let mut used = BTreeSet::new();
+    let out = cmd::ip(None, &["netns", "list"])?;
+    for ns in out.lines().filter_map(|l| l.split_whitespace().next()){
+        let out = match cmd::ip(Some(ns), &["-j", "link", "show", "type", "veth"]){
+            Ok(out) => out,
+            Err(_) => continue,
+        };
+        let links: serde_json::Value = serde_json::from_str(&out)?;
+        for l in links.as_array().cloned().unwrap_or_default(){
+            if let Some(index) = l["ifalias"].as_str().and_then(veth_index){
+                used.insert(index);
+            }
+        }
+    }
+    Ok(used)
+}
+
+/// Free pool namespaces, those named `rrs-pool-<n>`.
+fn free_namespaces() -> Result<Vec<String>>{
+    let mut free: Vec<String> = cmd::ip(None, &["netns", "list"])?.lines()
+        .filter_map(|l| l.split_whitespace().next())
+        .filter(|n| pool_index(n).is_some())
+        .map(|n| n.to_string())
+        .collect();
+    free.sort();
+    Ok(free)
+}
+
+fn veth_names(index: usize) -> (String, String){
+    (format!("{}{}a", VETH_PREFIX, index), format!("{}{}b", VETH_PREFIX, index))
+}
+
+fn veth_index(name: &str) -> Option<usize>{
+    let n = name.strip_prefix(VETH_PREFIX)?;
+    let n = n.strip_suffix('a').or_else(|| n.strip_suffix('b'))?;
+    n.parse().ok()
+}
+
+fn pool_index(netns: &str) -> Option<usize>{
+    netns.strip_prefix(NS_POOL)?.strip_prefix('-')?.parse().ok()
+}
+
+fn next_index(used: &BTreeSet<usize>) -> usize {
+    (0..).find(|i| !used.contains(i)).unwrap_or_default()
+}
@@ -0,0 +1,348 @@
+//! Checks of the host run before a topology is built, so a build doesn't
+//! fail halfway through on something that was wrong from the start, and
+//! every problem is reported at once rather than one per attempt:
+//!
+//! - the capabilities: CAP_NET_ADMIN for links and routes, CAP_SYS_ADMIN
+//!   for the namespace mounts, both held by root
+//! - the kernel support for the devices the topology creates: veth,
+//!   bridge, bond, vxlan, vrf, the tunnel types, wireguard, tun, macvlan
+//!   and ipvlan, plus modules asked for, e.g. `mpls_router` for programs
+//!   run in the lab
+//! - the `wg` tool keying WireGuard links
+//! - that the sysctls of the namespaces, and those of the groups, can be
+//!   written
+//! - namespaces of the topology which exist already and host interfaces
+//!   moved into it or given children in it which don't
+//!
+//! Device types are tried in a scratch namespace deleted again. Other
+//! modules count as available if loaded or known to `modprobe`.
+//!
+//! Building a topology changes the host itself in a few places only, see
+//! `host_changes`: kernel modules of the devices and features it uses are
+//! loaded if they aren't yet, the garbage collection thresholds of the
+//! neighbor tables are host sysctls, and host interfaces are moved into
+//! namespaces, given macvlan or ipvlan children there, or configured where
+//! they are, with the sysctls of their
+//! group. Such a topology is only built if `Config::host_changes` allows
+//! it, everything else stays inside the namespaces created. Device types
+//! whose module isn't loaded yet aren't tried, as that would load it.
+
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::cmd;
+use crate::error::{failed, Result};
+use crate::forwarder::ForwarderKind;
+use crate::passthrough::PassthroughKind;
+use crate::topology::Topology;
+use crate::trace::Traced;
+use crate::{BridgeBackend, Namespace};
+
+/// Effective capabilities needed, (bit, name).
+const CAPABILITIES: [(u32, &str); 2] = [(12, "CAP_NET_ADMIN"), (21, "CAP_SYS_ADMIN")];
+
+/// Scratch namespaces created by this process, copies of a topology are
+/// checked in parallel.
+static SCRATCH: AtomicU32 = AtomicU32::new(0);
+
+/// Problems found on the host for building `topology`, none if it can
+/// be built. When reconciling, namespaces of the topology and moved host
+/// interfaces are expected to exist already. `modules` are checked on top
+/// of those the topology needs.
+pub fn check(topology: &Topology, reconcile: bool, modules: &[String]) -> Vec<String> {
+    let mut problems = Vec::new();
+    capabilities(&mut problems);
+    let scratch = format!("router-rs-preflight-{}-{}", std::process::id(), SCRATCH.fetch_add(1, Ordering::Relaxed));
+    match cmd::ip(None, &["netns", "add", scratch.as_str()]){
+        Ok(_) => {
+            devices(topology, &scratch, &mut problems);
+            sysctls(topology, &scratch, &mut problems);
+            if let Err(e) = cmd::ip(None, &["netns", "del", scratch.as_str()]) {
+                problems.push(format!("Failed to delete the scratch namespace {}: {}", scratch, e));
+            }
+        },
+        Err(e) => problems.push(format!("Cannot create namespaces: {}", e)),
+    }
+    if !topology.wireguards.is_empty() && Command::new("wg").arg("--version").traced_output().is_err() {
+        problems.push("WireGuard links need the wg tool, which is not installed".to_string());
+    }
+    for m in modules{
+        if !module(m) {
+            problems.push(format!("Kernel module {} is neither loaded nor known to modprobe", m));
+        }
+    }
+    if !reconcile {
+        conflicts(topology, &mut problems);
+    }
+    problems
+}
+
+/// Fails with all problems `check` found.
+pub fn run(topology: &Topology, reconcile: bool, modules: &[String]) -> Result<()>{
+    let problems = check(topology, reconcile, modules);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(failed!("Preflight of {} found {} problems:\n  {}", topology.name, problems.len(), problems.join("\n  ")))
+}
+
+fn capabilities(problems: &mut Vec<String>){
+    let status = match std::fs::read_to_string("/proc/self/status"){
+        Ok(status) => status,
+        Err(e) => return problems.push(format!("Cannot read the capabilities: {}", e)),
+    };
+    let effective = status.lines()
+        .find_map(|l| l.strip_prefix("CapEff:"))
+        .and_then(|c| u64::from_str_radix(c.trim(), 16).ok())
+        .unwrap_or_default();
+    for (bit, name) in CAPABILITIES{
+        if effective & (1 << bit) == 0 {
+            problems.push(format!("Missing {}, run as root", name));
+        }
+    }
+}
+
+/// Creates a device of every type the topology needs in `scratch`.
+fn devices(topology: &Topology, scratch: &str, problems: &mut Vec<String>){
+    let mut kinds: Vec<(&str, Vec<&str>)> = Vec::new();
+    if !topology.links.is_empty() || !topology.bridges.is_empty() {
+        kinds.push(("veth", vec!["peer", "name", "preflight1"]));
+    }
+    if topology.bridges.iter().any(|b| b.backend == BridgeBackend::Linux) {
+        kinds.push(("bridge", vec![]));
+    }
+    if topology.links.iter().any(|l| l.bond.is_some()) {
+        kinds.push(("bond", vec![]));
+    }
+    if !topology.vxlans.is_empty() {
+        kinds.push(("vxlan", vec!["id", "1", "dstport", "4789"]));
+    }
+    if topology.namespaces.iter().any(|ns| !ns.vrfs.is_empty()) {
+        kinds.push(("vrf", vec!["table", "10"]));
+    }
+    let tunnels: Vec<String> = topology.tunnels.iter().map(|t| t.kind.to_string()).collect();
+    for kind in &tunnels{
+        if !kinds.iter().any(|(k, _)| k == kind) {
+            kinds.push((kind, vec!["remote", "192.0.2.1", "local", "192.0.2.2"]));
+        }
+    }
+    if !topology.wireguards.is_empty() {
+        kinds.push(("wireguard", vec![]));
+    }
+    for (n, (kind, args)) in kinds.iter().enumerate(){
+        if loads(module_of(kind)) {
+            continue;
+        }
+        let name = format!("preflight{}", n + 2);
+        let mut cmd = vec!["link", "add", name.as_str(), "type", kind];
+        cmd.extend(args);
+        if let Err(e) = cmd::ip(Some(scratch), &cmd) {
+            problems.push(format!("Kernel lacks {} devices: {}", kind, e));
+        }
+    }
+    if topology.namespaces.iter().any(|ns| ns.nat64.is_some()) && !Path::new("/dev/net/tun").exists() {
+        problems.push("Kernel lacks tun devices for NAT64: /dev/net/tun not found".to_string());
+    }
+}
+
+/// Writes the sysctls of the namespaces, and those of the groups on the
+/// defaults for new interfaces, in `scratch`.
+fn sysctls(topology: &Topology, scratch: &str, problems: &mut Vec<String>){
+    let mut settings = Vec::new();
+    for ns in &topology.namespaces{
+        match topology.sysctls(ns){
+            Ok(s) => settings.extend(s),
+            Err(e) => problems.push(format!("Namespace {}: {}", ns.name, e)),
+        }
+    }
+    for g in &topology.groups{
+        settings.extend(g.sysctls("default"));
+    }
+    settings.sort();
+    settings.dedup();
+    for setting in &settings{
+        let output = Command::new("ip")
+            .args(["netns", "exec", scratch, "sysctl", "-qw", setting.as_str()])
+            .traced_output();
+        match output{
+            Ok(o) if o.status.success() => {},
+            Ok(o) => problems.push(format!("Cannot write sysctl {}: {}", setting, String::from_utf8_lossy(&o.stderr).trim())),
+            Err(e) => problems.push(format!("Cannot run sysctl: {}", e)),
+        }
+    }
+}
+
+fn conflicts(topology: &Topology, problems: &mut Vec<String>){
+    let existing = match cmd::ip(None, &["netns", "list"]){
+        Ok(list) => list,
+        Err(e) => return problems.push(format!("Cannot list namespaces: {}", e)),
+    };
+    let existing: Vec<&str> = existing.lines().filter_map(|l| l.split_whitespace().next()).collect();
+    let names = topology.namespaces.iter().map(|ns| &ns.name)
+        .chain(topology.bridges.iter().filter(|b| b.namespace.is_none()).map(|b| &b.name));
+    for name in names{
+        let netns = Namespace::netns_name(&topology.name, name);
+        if existing.contains(&netns.as_str()) {
+            problems.push(format!("Namespace {} exists already", netns));
+        }
+    }
+    for i in &topology.interfaces{
+        if cmd::ip(None, &["link", "show", "dev", i.host_name()]).is_err() {
+            problems.push(format!("Host interface {} not found", i.host_name()));
+        }
+        if let Some(Err(e)) = i.passthrough.as_ref().map(|p| p.check()) {
+            problems.push(e.to_string());
+        }
+    }
+}
+
+/// Changes `topology` makes to the host outside the namespaces it
+/// creates, none if it keeps to them. When reconciling, host interfaces
+/// are expected to be moved already.
+pub fn host_changes(topology: &Topology, reconcile: bool) -> Vec<String> {
+    let mut changes = Vec::new();
+    let mut loaded = Vec::new();
+    for (module, user) in modules(topology){
+        if !loaded.contains(&module) && loads(module) {
+            changes.push(format!("load kernel module {} for {}", module, user));
+            loaded.push(module);
+        }
+    }
+    if let Some(gc) = &topology.neighbor_gc{
+        for (key, value) in gc.settings(){
+            changes.push(format!("set host sysctl {} to {}, put back on destroy", key.replace('/', "."), value));
+        }
+    }
+    for i in &topology.interfaces{
+        match (&i.namespace, i.passthrough.as_ref().map(|p| p.kind)){
+            (Some(ns), Some(kind @ (PassthroughKind::Macvlan | PassthroughKind::Ipvlan))) if !reconcile => {
+                changes.push(format!("create {} child of host interface {} in namespace {}", kind, i.host_name(), Namespace::netns_name(&topology.name, ns)));
+            },
+            (Some(ns), _) if !reconcile => changes.push(format!("move host interface {} into namespace {}, moved back on destroy", i.host_name(), Namespace::netns_name(&topology.name, ns))),
+            (Some(_), _) => {},
+            (None, _) => changes.push(format!("configure host interface {}", i.name)),
+        }
+        let Some(group) = i.group.as_ref().and_then(|g| topology.groups.iter().find(|o| o.name == *g)) else {
+            continue;
+        };
+        if i.namespace.is_none() {
+            for setting in group.sysctls(&i.name){
+                changes.push(format!("set host sysctl {} of group {}", setting, group.name));
+            }
+        }
+    }
+    changes
+}
+
+/// Kernel modules of the devices and features `topology` uses, with what
+/// needs them.
+fn modules(topology: &Topology) -> Vec<(&'static str, String)> {
+    let mut modules = Vec::new();
+    if !topology.links.is_empty() || !topology.bridges.is_empty() {
+        modules.push(("veth", "links".to_string()));
+    }
+    for l in topology.links.iter().filter(|l| l.bond.is_some()){
+        modules.push(("bonding", format!("bonded link {}", l.name)));
+    }
+    for b in &topology.bridges{
+        let module = if b.backend == BridgeBackend::Ovs { "openvswitch" } else { "bridge" };
+        modules.push((module, format!("bridge {}", b.name)));
+    }
+    if let Some(v) = topology.vxlans.first() {
+        modules.push(("vxlan", format!("VXLAN link {}", v.name)));
+    }
+    for t in &topology.tunnels{
+        modules.push((module_of(&t.kind.to_string()), format!("tunnel {}", t.name)));
+    }
+    if let Some(w) = topology.wireguards.first() {
+        modules.push(("wireguard", format!("WireGuard link {}", w.name)));
+    }
+    for l in &topology.links{
+        for qos in l.qos.iter().chain(l.endpoint_qos.values()){
+            if qos.delay.is_some() || qos.jitter.is_some() || qos.loss.is_some() || qos.reorder.is_some() {
+                modules.push(("sch_netem", format!("impairments of link {}", l.name)));
+            }
+            if qos.rate.is_some() {
+                modules.push(("sch_tbf", format!("rate limit of link {}", l.name)));
+            }
+        }
+    }
+    if let Some(m) = topology.mirrors.first() {
+        for module in ["sch_ingress", "cls_u32", "act_mirred"]{
+            modules.push((module, format!("mirror {}", m.name)));
+        }
+    }
+    for i in &topology.interfaces{
+        match i.passthrough.as_ref().map(|p| p.kind){
+            Some(PassthroughKind::Macvlan) => modules.push(("macvlan", format!("interface {}", i.name))),
+            Some(PassthroughKind::Ipvlan) => modules.push(("ipvlan", format!("interface {}", i.name))),
+            _ => {},
+        }
+    }
+    let qdiscs = topology.links.iter().map(|l| topology.link_queues(l).qdisc)
+        .chain(topology.interfaces.iter().map(|i| i.queues.as_ref().and_then(|q| q.qdisc)));
+    for qdisc in qdiscs.flatten(){
+        if let Some(module) = qdisc.module() {
+            modules.push((module, format!("{} qdisc", qdisc)));
+        }
+    }
+    for ns in &topology.namespaces{
+        if !ns.vrfs.is_empty() {
+            modules.push(("vrf", format!("VRFs of {}", ns.name)));
+        }
+        if ns.nat.is_some() || ns.firewall.is_some() || ns.flowtable {
+            modules.push(("nf_tables", format!("nftables of {}", ns.name)));
+        }
+        if ns.nat64.is_some() {
+            modules.push(("tun", format!("NAT64 of {}", ns.name)));
+        }
+        if ns.forwarder.as_ref().is_some_and(|f| f.kind == ForwarderKind::Tap) {
+            modules.push(("tun", format!("dataplane of {}", ns.name)));
+            modules.push(("cls_u32", format!("dataplane of {}", ns.name)));
+            modules.push(("act_mirred", format!("dataplane of {}", ns.name)));
+        }
+    }
+    modules
+}
+
+/// Module of devices of type `kind`.
+fn module_of(kind: &str) -> &'static str {
+    match kind{
+        "veth" => "veth",
+        "bridge" => "bridge",
+        "bond" => "bonding",
+        "vxlan" => "vxlan",
+        "vrf" => "vrf",
+        "gre" | "gretap" => "ip_gre",
+        "ipip" => "ipip",
+        "wireguard" => "wireguard",
+        _ => "sit",
+    }
+}
+
+/// True if module `name` isn't loaded but would be, built as a module of
+/// the running kernel.
+fn loads(name: &str) -> bool {
+    if Path::new("/sys/module").join(name).exists() {
+        return false;
+    }
+    let Ok(release) = std::fs::read_to_string("/proc/sys/kernel/osrelease") else {
+        return false;
+    };
+    let Ok(dep) = std::fs::read_to_string(Path::new("/lib/modules").join(release.trim()).join("modules.dep")) else {
+        return false;
+    };
+    dep.lines()
+        .filter_map(|l| l.split(':').next())
+        .filter_map(|path| Path::new(path).file_name()?.to_str()?.split('.').next().map(|s| s.replace('-', "_")))
+        .any(|m| m == name)
+}
+
+/// True if module `name` is loaded or built in, or modprobe knows it.
+fn module(name: &str) -> bool {
+    if Path::new("/sys/module").join(name).exists() {
+        return true;
+    }
+    Command::new("modprobe").args(["-n", "-q", name]).traced_status().is_ok_and(|s| s.success())
+}
@@ -0,0 +1,323 @@
+//! Long-running programs of the description, e.g. traffic sinks or custom
+//! daemons, started in their namespace once the topology is up. Each runs
+//! under a small supervisor, `router-rs process run`, which waits for it,
+//! records how it exited and restarts it as its `restart` policy asks, one
+//! second after it exited. The program gets `SIGTERM` when its supervisor
+//! goes, so stopping the supervisor like any other daemon stops both.
+//!
+//! Pid files, the spec started and the exit status live in
+//! `/run/router-rs/<topology>/proc-<namespace>-<name>/`, the pid of the
+//! supervisor is kept in the topology's state too. Both write to the node
+//! log named after the process. A supervisor killed behind our back is
+//! restarted by `daemon supervise` and the healer.
+
+use std::fmt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::daemon;
+use crate::error::{failed, Result, RouterError};
+use crate::logs;
+use crate::state::STATE_DIR;
+
+/// Time between a program exiting and its restart.
+pub const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// When a program which exited is started again.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy{
+    #[default]
+    Never,
+    /// unless it exited with status 0
+    OnFailure,
+    Always,
+}
+
+impl fmt::Display for RestartPolicy{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            RestartPolicy::Never => write!(f, "never"),
+            RestartPolicy::OnFailure => write!(f, "on-failure"),
+            RestartPolicy::Always => write!(f, "always"),
+        }
+    }
+}
+
+impl FromStr for RestartPolicy{
+    type Err = RouterError;
+
+    fn from_str(s: &str) -> Result<Self>{
+        match s{
+            "never" => Ok(RestartPolicy::Never),
+            "on-failure" => Ok(RestartPolicy::OnFailure),
+            "always" => Ok(RestartPolicy::Always),
+            _ => Err(failed!("Unknown restart policy {}, expected never, on-failure or always", s)),
+        }
+    }
+}
+
+/// Program run in a namespace, see `process`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ProcessSpec{
+    /// unique in the namespace, also the name of its log
+    pub name: String,
+    /// program and arguments
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+}
+
+impl ProcessSpec{
+    pub fn check(&self) -> Result<()>{
+        // child.pid is the program's
+        if self.name.is_empty() || self.name.contains('/') || self.name == "child" {
+            return Err(RouterError::Invalid(format!("Invalid process name {:?}", self.name)));
+        }
+        if self.command.is_empty() {
+            return Err(RouterError::Invalid(format!("Process {} has no command", self.name)));
+        }
+        Ok(())
+    }
+}
+
+/// A supervised program of `namespace`.
+#[derive(Clone, Debug)]
+pub struct Process{
+    pub topology: String,
+    pub namespace: String,
+    pub netns: String,
+    pub spec: ProcessSpec,
+    /// spec, pid files and exit status
+    pub dir: PathBuf,
+}
+
+/// What a process was started as, to find it again.
+#[derive(Serialize, Deserialize)]
+struct Started{
+    namespace: String,
+    netns: String,
+    spec: ProcessSpec,
+}
+
+impl Process{
+    pub fn new(topology: &str, namespace: &str, netns: &str, spec: &ProcessSpec) -> Process {
+        Process{
+            topology: topology.to_string(),
+            namespace: namespace.to_string(),
+            netns: netns.to_string(),
+            spec: spec.clone(),
+            dir: Process::dir(topology, namespace, &spec.name),
+        }
+    }
+
+    /// Runtime directory of process `name` of `namespace` of `topology`.
+    pub fn dir(topology: &str, namespace: &str, name: &str) -> PathBuf {
+        PathBuf::from(STATE_DIR).join(topology).join(format!("proc-{}-{}", namespace, name))
+    }
+
+    /// Processes started for `topology`, sorted by namespace and name.
+    pub fn list(topology: &str) -> Result<Vec<Process>>{
+        let dir = PathBuf::from(STATE_DIR).join(topology);
+        let mut processes = Vec::new();
+        if !dir.exists() {
+            return Ok(processes);
+        }
+        for entry in std::fs::read_dir(dir)?{
+            let path = entry?.path();
+            if !path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with("proc-")) {
+                continue;
+            }
+            let Ok(started) = std::fs::read_to_string(path.join("process.json")) else {
+                continue;
+            };
+            let started: Started = serde_json::from_str(&started)
+                .map_err(|e| failed!("Invalid {}: {}", path.join("process.json").display(), e))?;
+            processes.push(Process{
+                topology: topology.to_string(),
+                namespace: started.namespace,
+                netns: started.netns,
+                spec: started.spec,
+                dir: path,
+            });
+        }
+        processes.sort_by(|a, b| (&a.namespace, &a.spec.name).cmp(&(&b.namespace, &b.spec.name)));
+        Ok(processes)
+    }
+
+    /// Starts the process unless it runs already with the same spec, or
+    /// ran and exited for good. Returns true if it was started.
+    pub fn start(&self) -> Result<bool>{
+        let started = serde_json::to_string_pretty(&Started{
+            namespace: self.namespace.clone(),
+            netns: self.netns.clone(),
+            spec: self.spec.clone(),
+        })?;
+        let path = self.dir.join("process.json");
+        if (self.running() || self.exited().is_some()) && std::fs::read_to_string(&path).ok().as_deref() == Some(started.as_str()) {
+            return Ok(false);
+        }
+        daemon::stop_dir(&self.dir)?;
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(&path, started)?;
+        self.launch()?;
+        Ok(true)
+    }
+
+    fn launch(&self) -> Result<()>{
+        let mut args = vec![
+            "router-rs".to_string(), "process".to_string(), "run".to_string(),
+            "--dir".to_string(), self.dir.to_string_lossy().to_string(),
+            "--restart".to_string(), self.spec.restart.to_string(),
+            self.spec.name.clone(),
+            "--".to_string(),
+        ];
+        args.extend(self.spec.command.iter().cloned());
+        daemon::spawn(&self.topology, &self.netns, &self.dir, &self.spec.name, &args)?;
+        // programs which can't be started fail right away
+        std::thread::sleep(Duration::from_millis(200));
+        if !self.running() && self.exited().is_none_or(|e| e != "exit status: 0") {
+            return Err(failed!("Process {} in {} exited: {}", self.spec.name, self.namespace,
+                logs::tail(&logs::path(&self.topology, &self.netns, &self.spec.name), 5)));
+        }
+        Ok(())
+    }
+
+    /// Whether the supervisor runs.
+    pub fn running(&self) -> bool {
+        self.supervisor().is_some_and(daemon::alive)
+    }
+
+    /// Pid of the supervisor, the one in the state.
+    pub fn supervisor(&self) -> Option<i32> {
+        daemon::pid(&self.dir.join(format!("{}.pid", self.spec.name)))
+    }
+
+    /// Pid of the program, None between a crash and its restart.
+    pub fn pid(&self) -> Option<i32> {
+        daemon::pid(&self.dir.join("child.pid")).filter(|p| daemon::alive(*p))
+    }
+
+    /// How the program exited when it was not restarted.
+    pub fn exited(&self) -> Option<String> {
+        std::fs::read_to_string(self.dir.join("exit")).ok().map(|s| s.trim().to_string())
+    }
+
+    /// Times the program was restarted.
+    pub fn restarts(&self) -> u32 {
+        std::fs::read_to_string(self.dir.join("restarts")).ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// Restarts the supervisor if it died before the program exited for
+    /// good. Returns true if it was restarted.
+    pub fn supervise(&self) -> Result<bool>{
+        if self.running() || self.exited().is_some() {
+            return Ok(false);
+        }
+        self.launch()?;
+        Ok(true)
+    }
+
+    pub fn stop(&self) -> Result<()>{
+        daemon::stop_dir(&self.dir)
+    }
+
+    pub fn status(&self) -> ProcessStatus {
+        ProcessStatus{
+            namespace: self.namespace.clone(),
+            name: self.spec.name.clone(),
+            command: self.spec.command.clone(),
+            restart: self.spec.restart,
+            running: self.running(),
+            supervisor: self.supervisor().filter(|p| daemon::alive(*p)),
+            pid: self.pid(),
+            restarts: self.restarts(),
+            exited: self.exited(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ProcessStatus{
+    pub namespace: String,
+    pub name: String,
+    pub command: Vec<String>,
+    pub restart: RestartPolicy,
+    /// whether the supervisor runs
+    pub running: bool,
+    pub supervisor: Option<i32>,
+    /// of the program
+    pub pid: Option<i32>,
+    pub restarts: u32,
+    /// exit status of a program not restarted
+    pub exited: Option<String>,
+}
+
+impl fmt::Display for ProcessStatus{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match (&self.exited, self.pid){
+            (Some(exited), _) => exited.clone(),
+            (None, Some(pid)) => format!("running, pid {}", pid),
+            (None, None) if self.running => "restarting".to_string(),
+            (None, None) => "dead".to_string(),
+        };
+        write!(f, "{:<16} {:<16} {:<10} {:>3} restarts  {:<24} {}", self.namespace, self.name, self.restart.to_string(), self.restarts, state, self.command.join(" "))
+    }
+}
+
+/// Runs `command` in the foreground until it exits for good under
+/// `restart`, the supervisor of process `name`. The program's pid file,
+/// the restart count and the final exit status go to `dir`. Fails if the
+/// program last exited unsuccessfully.
+pub fn run(dir: &Path, name: &str, restart: RestartPolicy, command: &[String]) -> Result<()>{
+    let Some((program, args)) = command.split_first() else {
+        return Err(failed!("No command to run"));
+    };
+    let pidfile = dir.join("child.pid");
+    // counting on after a supervisor restarted by the healer
+    let mut restarts: u32 = std::fs::read_to_string(dir.join("restarts")).ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or_default();
+    loop{
+        let mut cmd = Command::new(program);
+        cmd.args(args);
+        // the program goes with its supervisor
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = cmd.spawn()
+            .map_err(|e| failed!("Failed to start {}: {}", program, e))?;
+        std::fs::write(&pidfile, child.id().to_string())?;
+        let status = child.wait()?;
+        let _ = std::fs::remove_file(&pidfile);
+        println!("{} exited with {}", name, status);
+        let again = match restart{
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !status.success(),
+            RestartPolicy::Always => true,
+        };
+        if !again {
+            std::fs::write(dir.join("exit"), status.to_string())?;
+            if !status.success() {
+                return Err(failed!("{} exited with {}", name, status));
+            }
+            return Ok(());
+        }
+        restarts += 1;
+        std::fs::write(dir.join("restarts"), restarts.to_string())?;
+        std::thread::sleep(RESTART_DELAY);
+        println!("restarting {}", name);
+    }
+}
@@ -0,0 +1,124 @@
+//! WAN emulation for links: netem for delay, jitter, loss and reordering,
+//! tbf for rate limits. Both shape egress, so a link's settings are applied
+//! to both of its veth ends, each end's `endpoint_qos` override impairing
+//! only the direction away from it.
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::{failed, Result};
+
+/// Impairment of one direction of a link. Unset fields are left alone,
+/// percentages are 0-100.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct LinkQos{
+    /// milliseconds
+    #[serde(default)]
+    pub delay: Option<f64>,
+    /// milliseconds of random variation around `delay`
+    #[serde(default)]
+    pub jitter: Option<f64>,
+    #[serde(default)]
+    pub loss: Option<f64>,
+    /// share of packets sent right away instead of delayed, needs `delay`
+    #[serde(default)]
+    pub reorder: Option<f64>,
+    /// Mbit/s
+    #[serde(default)]
+    pub rate: Option<u64>,
+}
+
+impl LinkQos{
+    /// `self` with every field set in `over` replaced.
+    pub fn merge(&self, over: &LinkQos) -> LinkQos {
+        LinkQos{
+            delay: over.delay.or(self.delay),
+            jitter: over.jitter.or(self.jitter),
+            loss: over.loss.or(self.loss),
+            reorder: over.reorder.or(self.reorder),
+            rate: over.rate.or(self.rate),
+        }
+    }
+
+    fn netem(&self) -> bool {
+        self.delay.is_some() || self.jitter.is_some() || self.loss.is_some() || self.reorder.is_some()
+    }
+
+    pub fn validate(&self) -> Result<()>{
+        for (name, value) in [("delay", self.delay), ("jitter", self.jitter)]{
+            if value.is_some_and(|v| v < 0.0) {
+                return Err(failed!("Invalid {} {:?}, expected milliseconds", name, value));
+            }
+        }
+        for (name, value) in [("loss", self.loss), ("reorder", self.reorder)]{
+            if value.is_some_and(|v| !(0.0..=100.0).contains(&v)) {
+                return Err(failed!("Invalid {} {:?}, expected a percentage between 0 and 100", name, value));
+            }
+        }
+        if self.jitter.is_some() && self.delay.is_none() {
+            return Err(failed!("jitter needs a delay"));
+        }
+        if self.reorder.is_some() && self.delay.is_none() {
+            return Err(failed!("reorder needs a delay, netem only reorders packets it delays"));
+        }
+        if self.rate == Some(0) {
+            return Err(failed!("Invalid rate 0"));
+        }
+        Ok(())
+    }
+
+    /// `tc` invocations installing the settings on `interface`: a netem root
+    /// qdisc with a tbf child if both are needed, otherwise just one of them.
+    pub fn commands(&self, interface: &str) -> Result<Vec<Vec<String>>>{
+        self.validate()?;
+        let mut commands = Vec::new();
+        if self.netem() {
+            let mut netem: Vec<String> = ["qdisc", "replace", "dev", interface, "root", "handle", "1:", "netem"]
+                .iter().map(|a| a.to_string()).collect();
+            if let Some(delay) = self.delay{
+                netem.extend(["delay".to_string(), format!("{}ms", delay)]);
+                if let Some(jitter) = self.jitter{
+                    netem.push(format!("{}ms", jitter));
+                }
+            }
+            if let Some(loss) = self.loss{
+                netem.extend(["loss".to_string(), format!("{}%", loss)]);
+            }
+            if let Some(reorder) = self.reorder{
+                netem.extend(["reorder".to_string(), format!("{}%", reorder)]);
+            }
+            commands.push(netem);
+        }
+        if let Some(rate) = self.rate{
+            // 10ms worth of traffic, at least a few jumbo frames
+            let burst = (rate * 1_000_000 / 8 / 100).max(16384);
+            let parent: &[&str] = if self.netem() { &["parent", "1:1", "handle", "10:"] } else { &["root"] };
+            let mut tbf: Vec<String> = ["qdisc", "replace", "dev", interface].iter().map(|a| a.to_string()).collect();
+            tbf.extend(parent.iter().map(|a| a.to_string()));
+            tbf.extend(["tbf", "rate", &format!("{}mbit", rate), "burst", &burst.to_string(), "latency", "50ms"].iter().map(|a| a.to_string()));
+            commands.push(tbf);
+        }
+        Ok(commands)
+    }
+
+    /// Replaces the qdiscs of `interface` in `netns` with these settings.
+    pub fn apply(&self, netns: &str, interface: &str) -> Result<()>{
+        if self.rate.is_some() && self.netem() {
+            // a tbf root left from before can't take netem's place in one step
+            clear(netns, interface)?;
+        }
+        for args in self.commands(interface)?{
+            cmd::tc(Some(netns), &args.iter().map(|a| a.as_str()).collect::<Vec<_>>())?;
+        }
+        Ok(())
+    }
+}
+
+/// Removes netem and tbf qdiscs from `interface`, if there are any.
+pub fn clear(netns: &str, interface: &str) -> Result<()>{
+    let qdiscs = cmd::tc(Some(netns), &["qdisc", "show", "dev", interface, "root"])?;
+    if qdiscs.contains("netem") || qdiscs.contains("tbf") {
+        cmd::tc(Some(netns), &["qdisc", "del", "dev", interface, "root"])?;
+    }
+    Ok(())
+}
@@ -0,0 +1,112 @@
+//! Queueing of interfaces: the length of the transmit queue, the number of
+//! transmit and receive queues of veth pairs, which the kernel only takes
+//! when the pair is created, and the root qdisc. veths come with noqueue
+//! and a single queue pair, so packets never wait in a queue of their own
+//! and the latency a lab measures hides the bufferbloat a real interface
+//! would add. fq_codel or pfifo with a long `txqueuelen` brings it back.
+//!
+//! Ends shaped by `qos` keep the netem or tbf root qdisc it installs, the
+//! qdisc set here is for the unshaped ones.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{failed, Result, RouterError};
+
+/// Root qdisc of an interface.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Qdisc{
+    /// fair queueing with controlled delay, the usual Linux default
+    FqCodel,
+    /// fair queueing with pacing
+    Fq,
+    /// tail-drop FIFO of `txqueuelen` packets
+    Pfifo,
+    /// three-band priority FIFO
+    PfifoFast,
+    /// no queue, the veth default
+    Noqueue,
+}
+
+impl Qdisc{
+    /// Kernel module of the qdisc, None if built in.
+    pub fn module(&self) -> Option<&'static str> {
+        match self{
+            Qdisc::FqCodel => Some("sch_fq_codel"),
+            Qdisc::Fq => Some("sch_fq"),
+            Qdisc::Pfifo | Qdisc::PfifoFast | Qdisc::Noqueue => None,
+        }
+    }
+}
+
+impl fmt::Display for Qdisc{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Qdisc::FqCodel => write!(f, "fq_codel"),
+            Qdisc::Fq => write!(f, "fq"),
+            Qdisc::Pfifo => write!(f, "pfifo"),
+            Qdisc::PfifoFast => write!(f, "pfifo_fast"),
+            Qdisc::Noqueue => write!(f, "noqueue"),
+        }
+    }
+}
+
+impl FromStr for Qdisc{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<Self>{
+        match s{
+            "fq_codel" => Ok(Qdisc::FqCodel),
+            "fq" => Ok(Qdisc::Fq),
+            "pfifo" => Ok(Qdisc::Pfifo),
+            "pfifo_fast" => Ok(Qdisc::PfifoFast),
+            "noqueue" => Ok(Qdisc::Noqueue),
+            _ => Err(RouterError::Invalid(format!("Unknown qdisc {}, expected fq_codel, fq, pfifo, pfifo_fast or noqueue", s))),
+        }
+    }
+}
+
+/// Queue settings of an interface, the kernel's own where not set.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct QueueSpec{
+    /// packets the transmit queue holds
+    #[serde(default)]
+    pub txqueuelen: Option<u32>,
+    /// transmit queues of each end of a veth pair
+    #[serde(default)]
+    pub numtxqueues: Option<u32>,
+    /// receive queues of each end of a veth pair
+    #[serde(default)]
+    pub numrxqueues: Option<u32>,
+    #[serde(default)]
+    pub qdisc: Option<Qdisc>,
+}
+
+impl QueueSpec{
+    /// `self` with the settings of `over` taking precedence.
+    pub fn merge(&self, over: &QueueSpec) -> QueueSpec {
+        QueueSpec{
+            txqueuelen: over.txqueuelen.or(self.txqueuelen),
+            numtxqueues: over.numtxqueues.or(self.numtxqueues),
+            numrxqueues: over.numrxqueues.or(self.numrxqueues),
+            qdisc: over.qdisc.or(self.qdisc),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()>{
+        for (name, n) in [("numtxqueues", self.numtxqueues), ("numrxqueues", self.numrxqueues)]{
+            if n.is_some_and(|n| n == 0 || n > 4096) {
+                return Err(failed!("{} must be between 1 and 4096", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the queues of veth pairs are set, which only creating them
+    /// does.
+    pub fn multiqueue(&self) -> bool {
+        self.numtxqueues.is_some() || self.numrxqueues.is_some()
+    }
+}
@@ -0,0 +1,237 @@
+//! IPv6 router advertisements: a host namespace may learn its IPv6 default
+//! route, and an address by SLAAC, from the routers on its links instead
+//! of getting a static default route, so edge behavior like router
+//! lifetimes, preferences and failover between advertising routers is
+//! tested end to end.
+//!
+//! Every routed namespace sharing a link or bridge with such a host runs
+//! `radvd` advertising itself as default router on the interface facing
+//! the host, with the IPv6 subnet of the segment as prefix if it is a /64.
+//! radvd runs with its config and pid file in a runtime directory next to
+//! those of the routing daemons and is stopped with the topology. The
+//! host keeps the addresses assigned from the subnet; routes it learns
+//! show up with protocol `ra`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::daemon;
+use crate::error::{failed, Result, RouterError};
+use crate::interface;
+use crate::logs;
+use crate::state::STATE_DIR;
+use crate::topology::Topology;
+
+/// Advertisement interval if not set, much shorter than radvd's own so a
+/// host learns its route within seconds.
+pub const DEFAULT_INTERVAL: u32 = 10;
+
+/// Preference of the advertising routers over other default routers.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RouterPreference{
+    Low,
+    #[default]
+    Medium,
+    High,
+}
+
+impl fmt::Display for RouterPreference{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            RouterPreference::Low => write!(f, "low"),
+            RouterPreference::Medium => write!(f, "medium"),
+            RouterPreference::High => write!(f, "high"),
+        }
+    }
+}
+
+/// Router advertisements a host namespace learns its default route from,
+/// as sent by the routers on its links.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RaSpec{
+    /// maximum seconds between unsolicited advertisements, 4 to 1800
+    #[serde(default)]
+    pub interval: Option<u32>,
+    /// seconds the default route stays valid without a new advertisement,
+    /// three intervals if not set, 0 withdraws it
+    #[serde(default)]
+    pub lifetime: Option<u32>,
+    #[serde(default)]
+    pub preference: Option<RouterPreference>,
+}
+
+impl RaSpec{
+    pub fn check(&self) -> Result<()>{
+        let interval = self.interval.unwrap_or(DEFAULT_INTERVAL);
+        if !(4..=1800).contains(&interval) {
+            return Err(RouterError::Invalid(format!("Router advertisement interval {} out of range 4 to 1800", interval)));
+        }
+        if let Some(lifetime) = self.lifetime{
+            if lifetime != 0 && !(interval..=9000).contains(&lifetime) {
+                return Err(RouterError::Invalid(format!("Router lifetime {} must be 0 or between the interval {} and 9000", lifetime, interval)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Advertisements sent out of one interface of a router.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Advertisement{
+    pub interface: String,
+    /// /64 of the segment hosts autoconfigure addresses from
+    pub prefix: Option<String>,
+    pub spec: RaSpec,
+}
+
+/// Advertisements each router sends, by namespace, sorted. Needs the
+/// assigned subnets of links and bridges, as `paths::stub_routes`. Fails
+/// for a host no router on its links can send them to.
+pub fn advertisements(topology: &Topology, subnets: &HashMap<String, (String, Option<String>)>) -> Result<Vec<(String, Vec<Advertisement>)>>{
+    let segments = topology.links.iter().map(|l| (&l.name, &l.endpoints))
+        .chain(topology.bridges.iter().map(|b| (&b.name, &b.members)));
+    let mut routers: Vec<(String, Vec<Advertisement>)> = Vec::new();
+    let mut served = Vec::new();
+    for (segment, members) in segments{
+        let Some(host) = topology.namespaces.iter().find(|n| n.ra.is_some() && members.contains(&n.name)) else {
+            continue;
+        };
+        let spec = host.ra.clone().unwrap_or_default();
+        spec.check().map_err(|e| failed!("Namespace {}: {}", host.name, e))?;
+        let (subnet, subnet6) = subnets.get(segment).cloned().unwrap_or_default();
+        let Some(subnet6) = Some(subnet).filter(|s| s.contains(':')).or(subnet6) else {
+            continue;
+        };
+        let subnet6: ipnet::IpNet = subnet6.parse()
+            .map_err(|e| failed!("Invalid IPv6 subnet {} of {}: {}", subnet6, segment, e))?;
+        let prefix = Some(subnet6.trunc()).filter(|s| s.prefix_len() == 64).map(|s| s.to_string());
+        let advertising: Vec<&String> = members.iter().filter(|m| topology.routed(m)).collect();
+        for router in &advertising{
+            let advertisement = Advertisement{ interface: interface::name(router, segment), prefix: prefix.clone(), spec: spec.clone() };
+            match routers.iter_mut().find(|(r, _)| r == *router){
+                Some((_, ads)) => ads.push(advertisement),
+                None => routers.push((router.to_string(), vec![advertisement])),
+            }
+        }
+        if !advertising.is_empty() {
+            served.extend(members.iter().cloned());
+        }
+    }
+    if let Some(host) = topology.namespaces.iter().find(|n| n.ra.is_some() && !served.contains(&n.name)) {
+        return Err(failed!("Namespace {} learns its default route from router advertisements, but no router on its IPv6 links sends them", host.name));
+    }
+    routers.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(routers)
+}
+
+/// radvd config sending `advertisements`.
+pub fn radvd_config(advertisements: &[Advertisement]) -> String {
+    let mut s = String::new();
+    for a in advertisements{
+        let interval = a.spec.interval.unwrap_or(DEFAULT_INTERVAL);
+        let _ = writeln!(s, "interface {} {{", a.interface);
+        let _ = writeln!(s, "    AdvSendAdvert on;");
+        let _ = writeln!(s, "    MinRtrAdvInterval {};", (interval / 3).max(3));
+        let _ = writeln!(s, "    MaxRtrAdvInterval {};", interval);
+        let _ = writeln!(s, "    AdvDefaultLifetime {};", a.spec.lifetime.unwrap_or(3 * interval));
+        let _ = writeln!(s, "    AdvDefaultPreference {};", a.spec.preference.unwrap_or_default());
+        if let Some(prefix) = &a.prefix{
+            let _ = writeln!(s, "    prefix {} {{\n        AdvOnLink on;\n        AdvAutonomous on;\n    }};", prefix);
+        }
+        let _ = writeln!(s, "}};");
+    }
+    s
+}
+
+/// Sysctl settings letting a host on `interfaces` learn routes and
+/// addresses from advertisements, even while it forwards.
+pub fn host_sysctls(interfaces: &[String]) -> Vec<String> {
+    let mut settings = Vec::new();
+    for dev in interfaces{
+        settings.push(format!("net.ipv6.conf.{}.accept_ra=2", dev));
+        settings.push(format!("net.ipv6.conf.{}.accept_ra_defrtr=1", dev));
+        settings.push(format!("net.ipv6.conf.{}.autoconf=1", dev));
+    }
+    settings
+}
+
+/// radvd of the namespace `netns`.
+pub struct Advertiser{
+    pub topology: String,
+    pub netns: String,
+    /// config and pid file
+    pub dir: PathBuf,
+}
+
+impl Advertiser{
+    pub fn new(topology: &str, namespace: &str, netns: &str) -> Advertiser {
+        Advertiser{
+            topology: topology.to_string(),
+            netns: netns.to_string(),
+            dir: Advertiser::dir(topology, namespace),
+        }
+    }
+
+    /// Runtime directory of the radvd of `namespace` of `topology`.
+    pub fn dir(topology: &str, namespace: &str) -> PathBuf {
+        PathBuf::from(STATE_DIR).join(topology).join(format!("ra-{}", namespace))
+    }
+
+    /// Namespaces of `topology` with a radvd directory.
+    pub fn list(topology: &str) -> Result<Vec<String>>{
+        let dir = PathBuf::from(STATE_DIR).join(topology);
+        let mut namespaces = Vec::new();
+        if !dir.exists() {
+            return Ok(namespaces);
+        }
+        for entry in std::fs::read_dir(dir)?{
+            if let Some(ns) = entry?.file_name().to_str().and_then(|n| n.strip_prefix("ra-")){
+                namespaces.push(ns.to_string());
+            }
+        }
+        Ok(namespaces)
+    }
+
+    /// Runs radvd with `config` unless it runs already with the same one.
+    /// Returns true if it was started.
+    pub fn start(&self, config: &str) -> Result<bool>{
+        // not .conf, which marks the directory of a routing daemon
+        let path = self.dir.join("radvd.cfg");
+        if self.running() && std::fs::read_to_string(&path).ok().as_deref() == Some(config) {
+            return Ok(false);
+        }
+        daemon::stop_dir(&self.dir)?;
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(&path, config)?;
+        let args: Vec<String> = vec![
+            "radvd".to_string(),
+            "--nodaemon".to_string(),
+            "--config".to_string(),
+            path.to_string_lossy().to_string(),
+            // its own, the one stop_dir reads is written by spawn
+            "--pidfile".to_string(),
+            self.dir.join("radvd.lock").to_string_lossy().to_string(),
+        ];
+        daemon::spawn(&self.topology, &self.netns, &self.dir, "radvd", &args)?;
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        if !self.running() {
+            let tail = logs::tail(&logs::path(&self.topology, &self.netns, "radvd"), 5);
+            let _ = daemon::stop_dir(&self.dir);
+            return Err(failed!("radvd in {} exited: {}", self.netns, tail));
+        }
+        Ok(true)
+    }
+
+    pub fn stop(&self) -> Result<()>{
+        daemon::stop_dir(&self.dir)
+    }
+
+    fn running(&self) -> bool {
+        daemon::pid(&self.dir.join("radvd.pid")).is_some_and(daemon::alive)
+    }
+}
@@ -0,0 +1,187 @@
+//! Graceful restart of a routing daemon inside a namespace. The daemon is
+//! stopped and started again by shell commands while a sequence probe sends
+//! traffic across the namespace, so the report shows what the restart cost
+//! the data plane. With `preserve` the routes the daemon withdraws on its
+//! way down are put back right away, as a daemon in graceful restart mode
+//! would have left them in the kernel.
+
+use std::fmt;
+use std::process::Stdio;
+use std::time::Duration;
+
+use crate::cmd;
+use crate::error::{failed, Result};
+use crate::logs;
+use crate::netns;
+use crate::stress::{SequenceProbe, SequenceReport};
+use crate::trace::Traced;
+
+pub struct GracefulRestart{
+    pub topology: String,
+    pub netns: String,
+    /// shell command stopping the daemon, run inside the namespace, its
+    /// output goes to the node log `restart`
+    pub stop: String,
+    /// shell command starting the daemon, run inside the namespace
+    pub start: String,
+    /// traffic before the stop, so the probe has a baseline
+    pub delay: Duration,
+    /// time between stop and start
+    pub downtime: Duration,
+    pub preserve: bool,
+}
+
+/// Route counts only cover routes not installed by the kernel itself.
+#[derive(Clone, Debug, Default)]
+pub struct RestartReport{
+    pub traffic: SequenceReport,
+    /// routes gone after the stop
+    pub withdrawn: usize,
+    /// withdrawn routes put back because of `preserve`
+    pub restored: usize,
+    /// routes present before the restart but not after it
+    pub missing: usize,
+}
+
+impl fmt::Display for RestartReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} routes withdrawn, {} restored, {} missing after restart; {}",
+            self.withdrawn, self.restored, self.missing, self.traffic)
+    }
+}
+
+impl RestartReport{
+    /// Fails if more than `max_lost` probes were lost or the daemon didn't
+    /// bring back all of its routes.
+    pub fn verify(&self, max_lost: u32) -> Result<()>{
+        if self.traffic.lost > max_lost {
+            return Err(failed!("Lost {} probes during restart, at most {} allowed", self.traffic.lost, max_lost));
+        }
+        if self.missing > 0 {
+            return Err(failed!("{} routes missing after restart", self.missing));
+        }
+        Ok(())
+    }
+}
+
+impl GracefulRestart{
+    /// Restarts the daemon while `probe` runs. The probe needs to send for
+    /// longer than `delay + downtime` to see the whole restart.
+    pub fn run(&self, probe: SequenceProbe) -> Result<RestartReport>{
+        let before = routes(&self.netns)?;
+        let traffic = std::thread::spawn(move || probe.run());
+        std::thread::sleep(self.delay);
+        let mut report = RestartReport::default();
+        let result = self.restart(&before, &mut report);
+        let traffic = traffic.join().map_err(|_| failed!("Probe thread panicked"))??;
+        result?;
+        report.traffic = traffic;
+        let after = routes(&self.netns)?;
+        report.missing = before.iter().filter(|r| !after.iter().any(|a| key(a) == key(r))).count();
+        Ok(report)
+    }
+
+    fn restart(&self, before: &[serde_json::Value], report: &mut RestartReport) -> Result<()>{
+        self.sh(&self.stop)?;
+        let down = routes(&self.netns)?;
+        let withdrawn: Vec<&serde_json::Value> = before.iter()
+            .filter(|r| !down.iter().any(|d| key(d) == key(r)))
+            .collect();
+        report.withdrawn = withdrawn.len();
+        let mut restored = Ok(());
+        if self.preserve {
+            for r in withdrawn{
+                let args = route_args(r);
+                match cmd::ip(Some(&self.netns), &args.iter().map(|a| a.as_str()).collect::<Vec<_>>()){
+                    Ok(_) => report.restored += 1,
+                    Err(e) => restored = Err(e),
+                }
+            }
+        }
+        // start the daemon again even if restoring failed
+        std::thread::sleep(self.downtime);
+        self.sh(&self.start)?;
+        restored
+    }
+
+    fn sh(&self, command: &str) -> Result<()>{
+        let log = logs::open(&self.topology, &self.netns, "restart")?;
+        let status = netns::command(&self.netns, "sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .traced_status()?;
+        if !status.success() {
+            return Err(failed!("Failed to run {} in {}: {}", command, self.netns,
+                logs::tail(&logs::path(&self.topology, &self.netns, "restart"), 5)));
+        }
+        Ok(())
+    }
+}
+
+/// All non-kernel routes of both families, each tagged with its family.
+fn routes(netns: &str) -> Result<Vec<serde_json::Value>>{
+    let mut routes = Vec::new();
+    for family in ["-4", "-6"]{
+        let installed: serde_json::Value = serde_json::from_str(&cmd::ip(Some(netns), &[family, "-j", "route", "show"])?)?;
+        for mut r in installed.as_array().cloned().unwrap_or_default(){
+            if r["protocol"] == "kernel" {
+                continue;
+            }
+            r["family"] = family.into();
+            routes.push(r);
+        }
+    }
+    Ok(routes)
+}
+
+fn key(route: &serde_json::Value) -> (String, String, u64) {
+    (
+        route["family"].as_str().unwrap_or_default().to_string(),
+        route["dst"].as_str().unwrap_or_default().to_string(),
+        route["metric"].as_u64().unwrap_or_default(),
+    )
+}
+
+/// `ip` arguments recreating a route as listed by `ip -j route show`.
+fn route_args(route: &serde_json::Value) -> Vec<String> {
+    let mut args = vec![
+        route["family"].as_str().unwrap_or("-4").to_string(),
+        "route".to_string(),
+        "replace".to_string(),
+        route["dst"].as_str().unwrap_or("default").to_string(),
+    ];
+    match route["nexthops"].as_array(){
+        Some(hops) => {
+            for hop in hops{
+                args.push("nexthop".to_string());
+                if let Some(gw) = hop["gateway"].as_str(){
+                    args.extend(["via".to_string(), gw.to_string()]);
+                }
+                if let Some(dev) = hop["dev"].as_str(){
+                    args.extend(["dev".to_string(), dev.to_string()]);
+                }
+                if let Some(weight) = hop["weight"].as_u64(){
+                    args.extend(["weight".to_string(), weight.to_string()]);
+                }
+            }
+        },
+        None => {
+            if let Some(gw) = route["gateway"].as_str(){
+                args.extend(["via".to_string(), gw.to_string()]);
+            }
+            if let Some(dev) = route["dev"].as_str(){
+                args.extend(["dev".to_string(), dev.to_string()]);
+            }
+        },
+    }
+    if let Some(proto) = route["protocol"].as_str(){
+        args.extend(["proto".to_string(), proto.to_string()]);
+    }
+    if let Some(metric) = route["metric"].as_u64(){
+        args.extend(["metric".to_string(), metric.to_string()]);
+    }
+    args
+}
@@ -0,0 +1,329 @@
+//! Routing information base of a namespace: the routes it should have, held
+//! as data and brought into the kernel by `Rib::sync`, which compares them
+//! with the routes installed and makes only the changes needed. A route
+//! with nexthops of several metrics is several kernel routes, each is
+//! added, replaced or deleted on its own, so changing one metric leaves the
+//! traffic over the others alone.
+//!
+//! The RIB owns every route of its namespace but those the kernel adds for
+//! addresses and links and those learned by routing daemons and from router
+//! advertisements, see `daemon::learned`; other routes are stale and
+//! deleted. Topologies fill the RIB of each namespace from their `routes`
+//! and `auto_routes`, anything else, e.g. an integration taking the routes
+//! of a daemon, can set routes as well and sync again. Routes of a running
+//! topology are changed with `set_route` and `remove_route`, which keep
+//! them in its saved state so reconciling doesn't take them away.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::error::{Result, RouterError};
+use crate::policy::TABLE_MAIN;
+use crate::state::State;
+use crate::topology::{NexthopSpec, RouteSpec};
+use crate::{Config, Namespace, Nexthop, Route, RouteKind};
+
+/// (table, destination) of a route, main table as None
+type RouteKey = (Option<u32>, ipnet::IpNet);
+/// (table, destination, metric) of a kernel route
+type KernelKey = (Option<u32>, ipnet::IpNet, u32);
+
+/// Change of one kernel route, the nexthops of a route with the same
+/// metric.
+#[derive(Clone)]
+pub enum RibChange{
+    Add(Route),
+    Replace(Route),
+    Delete(Route),
+}
+
+impl RibChange{
+    pub fn route(&self) -> &Route {
+        match self{
+            RibChange::Add(r) | RibChange::Replace(r) | RibChange::Delete(r) => r,
+        }
+    }
+}
+
+impl fmt::Display for RibChange{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = match self{
+            RibChange::Add(_) => '+',
+            RibChange::Replace(_) => '~',
+            RibChange::Delete(_) => '-',
+        };
+        let route = self.route();
+        write!(f, "{} {}", sign, route.dst)?;
+        if let Some(table) = route.table{
+            write!(f, " table {}", table)?;
+        }
+        if route.kind != RouteKind::Unicast {
+            return write!(f, " {}", route.kind);
+        }
+        if let Some(metric) = route.gateway.first().and_then(|n| n.metric) {
+            write!(f, " metric {}", metric)?;
+        }
+        let v6 = route.dst.contains(':');
+        for n in &route.gateway{
+            match n.gateway(v6).ok().flatten(){
+                Some(gateway) => write!(f, " via {}", gateway)?,
+                None => write!(f, " dev {}", n.dev.as_deref().unwrap_or("?"))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Routes a namespace should have.
+pub struct Rib{
+    pub namespace: Arc<Namespace>,
+    routes: Mutex<BTreeMap<RouteKey, Route>>,
+}
+
+impl Rib{
+    pub fn new(namespace: Arc<Namespace>) -> Rib {
+        Rib{ namespace, routes: Mutex::default() }
+    }
+
+    /// RIB holding the routes installed in `namespace` it owns, to change
+    /// them one at a time.
+    pub fn installed(namespace: Arc<Namespace>, config: &Config) -> Result<Rib>{
+        let rib = Rib::new(namespace);
+        for route in rib.namespace.static_routes(config)?{
+            rib.set(route)?;
+        }
+        Ok(rib)
+    }
+
+    /// Sets the routes installed in the namespace which aren't ours, those
+    /// to destinations not in `owned`, by destination and table, unless a
+    /// route to their destination is set, so syncing leaves them alone.
+    pub fn keep_foreign(&self, config: &Config, owned: &[(String, Option<u32>)]) -> Result<()>{
+        let owned = owned.iter().map(|(dst, table)| key(dst, *table)).collect::<Result<Vec<_>>>()?;
+        for route in self.namespace.static_routes(config)?{
+            let key = key(&route.dst, route.table)?;
+            if !owned.contains(&key) {
+                self.lock().entry(key).or_insert(route);
+            }
+        }
+        Ok(())
+    }
+
+    /// RIB of `namespace` in `config`, created empty on first use.
+    pub fn of(namespace: &Arc<Namespace>, config: &Config) -> Arc<Rib> {
+        let rib = Arc::new(Rib::new(namespace.clone()));
+        match config.ribs.try_insert(namespace.name.clone(), rib.clone()){
+            Ok(()) => rib,
+            Err(existing) => existing,
+        }
+    }
+
+    /// Sets the route to the destination of `route` in its table, replacing
+    /// the one set before.
+    pub fn set(&self, route: Route) -> Result<()>{
+        let key = key(&route.dst, route.table)?;
+        if route.kind == RouteKind::Unicast && route.gateway.is_empty() {
+            return Err(RouterError::Invalid(format!("Route to {} has no nexthop", route.dst)));
+        }
+        self.lock().insert(key, route);
+        Ok(())
+    }
+
+    /// Drops the route to `dst` in `table`, returns whether there was one.
+    pub fn remove(&self, dst: &str, table: Option<u32>) -> Result<bool>{
+        let key = key(dst, table)?;
+        Ok(self.lock().remove(&key).is_some())
+    }
+
+    /// Routes set, by table and destination.
+    pub fn routes(&self) -> Vec<Route> {
+        self.lock().values().cloned().collect()
+    }
+
+    /// Changes bringing the `installed` routes in line with those set:
+    /// kernel routes missing are added, those differing replaced, both in
+    /// the order of their destinations, and stale ones deleted last.
+    pub fn diff(&self, installed: &[Route]) -> Result<Vec<RibChange>>{
+        let wanted = kernel_routes(self.lock().values())?;
+        let installed = kernel_routes(installed)?;
+        let mut changes = Vec::new();
+        for (k, route) in &wanted{
+            match installed.iter().find(|(i, _)| i == k){
+                None => changes.push(RibChange::Add(route.clone())),
+                Some((_, current)) if !same(route, current)? => changes.push(RibChange::Replace(route.clone())),
+                Some(_) => {},
+            }
+        }
+        for (k, route) in &installed{
+            if !wanted.iter().any(|(w, _)| w == k) {
+                changes.push(RibChange::Delete(route.clone()));
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Makes the changes `diff` finds against the routes installed in the
+    /// namespace and returns them.
+    pub fn sync(&self, config: &Config) -> Result<Vec<RibChange>>{
+        let changes = self.diff(&self.namespace.static_routes(config)?)?;
+        for change in &changes{
+            let verb = match change{
+                RibChange::Add(_) => "add",
+                RibChange::Replace(_) => "replace",
+                RibChange::Delete(_) => "del",
+            };
+            self.namespace.route(verb, change.route().clone())?;
+        }
+        Ok(changes)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<RouteKey, Route>> {
+        self.routes.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Sets `route` in the namespace `netns`, replacing the route to its
+/// destination, and returns the kernel changes made. In a namespace of a
+/// running topology the route is kept in its saved state.
+pub fn set_route(netns: &str, route: Route) -> Result<Vec<RibChange>>{
+    let key = key(&route.dst, route.table)?;
+    let spec = spec(&route)?;
+    change(netns, key, move |rib| rib.set(route), Some(spec))
+}
+
+/// Like `set_route`, but fails if the namespace has a route to the
+/// destination of `route` already.
+pub fn add_route(netns: &str, route: Route) -> Result<Vec<RibChange>>{
+    let key = key(&route.dst, route.table)?;
+    let spec = spec(&route)?;
+    change(netns, key, move |rib| {
+        if rib.lock().contains_key(&key) {
+            return Err(RouterError::Exists{ kind: "route", name: format!("{} in {}", route.dst, netns) });
+        }
+        rib.set(route)
+    }, Some(spec))
+}
+
+/// Removes the route to `dst` in `table` from the namespace `netns`, like
+/// `set_route`.
+pub fn remove_route(netns: &str, dst: &str, table: Option<u32>) -> Result<Vec<RibChange>>{
+    let key = key(dst, table)?;
+    change(netns, key, |rib| rib.remove(dst, table).map(|_| ()), None)
+}
+
+/// Applies `update` to a RIB of the routes installed in `netns` and syncs
+/// it, then records `spec`, the route to `key` set, or its removal in the
+/// saved state of the topology holding the namespace.
+fn change(netns: &str, target: RouteKey, update: impl FnOnce(&Rib) -> Result<()>, spec: Option<RouteSpec>) -> Result<Vec<RibChange>>{
+    let mut owner = None;
+    for name in State::list()?{
+        if let Some(state) = State::load(&name)? {
+            if let Some(ns) = state.namespaces.iter().find(|n| n.netns == netns) {
+                owner = Some((ns.name.clone(), state));
+                break;
+            }
+        }
+    }
+    let topology = owner.as_ref().map_or(String::new(), |(_, s)| s.name.clone());
+    let config = Config::new(topology);
+    let name = owner.as_ref().map_or(netns.to_string(), |(n, _)| n.clone());
+    let rib = Rib::installed(Arc::new(Namespace{ name: name.clone(), netns: netns.to_string() }), &config)?;
+    update(&rib)?;
+    let changes = rib.sync(&config)?;
+    if let Some((_, mut state)) = owner {
+        let matches = |dst: &str, table: Option<u32>| key(dst, table).is_ok_and(|k| k == target);
+        // a route of the description set anew is no longer the
+        // description's until reconciling puts it back
+        state.routes.retain(|r| r.netns != netns || !matches(&r.dst, r.table));
+        state.set_routes.retain(|r| r.namespace != name || !matches(&r.dst, r.table));
+        if let Some(spec) = spec{
+            state.set_routes.push(RouteSpec{ namespace: name, ..spec });
+        }
+        state.save()?;
+    }
+    Ok(changes)
+}
+
+/// `route` as it is saved, gateways as addresses.
+fn spec(route: &Route) -> Result<RouteSpec>{
+    let v6 = route.dst.contains(':');
+    let mut nexthops = Vec::new();
+    for n in &route.gateway{
+        nexthops.push(NexthopSpec{
+            via: None,
+            address: n.gateway(v6)?.map(|a| a.to_string()),
+            dev: n.dev.clone(),
+            onlink: n.onlink,
+            weight: n.weight,
+            metric: n.metric,
+            seg6: n.seg6.clone(),
+            seg6local: n.seg6local.clone(),
+        });
+    }
+    Ok(RouteSpec{ dst: route.dst.clone(), nexthops, table: route.table, kind: route.kind, ..Default::default() })
+}
+
+fn key(dst: &str, table: Option<u32>) -> Result<RouteKey>{
+    let net: ipnet::IpNet = dst.parse()
+        .map_err(|e: ipnet::AddrParseError| RouterError::InvalidSubnet{ subnet: dst.to_string(), reason: e.to_string() })?;
+    Ok((table.filter(|t| *t != TABLE_MAIN), net.trunc()))
+}
+
+/// Metric the kernel gives a route of the family, IPv6 routes without
+/// one get 1024.
+fn metric(metric: Option<u32>, v6: bool) -> u32 {
+    match metric{
+        None | Some(0) if v6 => 1024,
+        m => m.unwrap_or(0),
+    }
+}
+
+/// `routes` split into kernel routes, keyed by (table, destination,
+/// metric).
+fn kernel_routes<'a>(routes: impl IntoIterator<Item = &'a Route>) -> Result<Vec<(KernelKey, Route)>>{
+    let mut kernel = Vec::new();
+    for route in routes{
+        let (table, dst) = key(&route.dst, route.table)?;
+        let v6 = dst.addr().is_ipv6();
+        if route.kind != RouteKind::Unicast {
+            kernel.push(((table, dst, metric(None, v6)), route.clone()));
+            continue;
+        }
+        for (m, nexthops) in route.by_metric(){
+            let gateway: Vec<Nexthop> = nexthops.into_iter().cloned().collect();
+            kernel.push(((table, dst, metric(m, v6)), Route{ gateway, ..route.clone() }));
+        }
+    }
+    Ok(kernel)
+}
+
+/// Whether kernel route `installed` is what `wanted` asks for. Nexthops
+/// match in any order, an interface left to the kernel matches the one
+/// it picked.
+fn same(wanted: &Route, installed: &Route) -> Result<bool>{
+    if wanted.kind != installed.kind || wanted.gateway.len() != installed.gateway.len() {
+        return Ok(false);
+    }
+    let v6 = wanted.dst.contains(':');
+    let multipath = wanted.gateway.len() > 1;
+    let mut left: Vec<&Nexthop> = installed.gateway.iter().collect();
+    for w in &wanted.gateway{
+        let gateway = w.gateway(v6).map_err(|e| RouterError::Invalid(format!("Route to {}: {}", wanted.dst, e)))?;
+        let found = left.iter().position(|i| {
+            i.gateway(v6).ok().flatten() == gateway
+                && (w.dev.is_none() || w.dev == i.dev)
+                && w.onlink == i.onlink
+                && (!multipath || w.weight.unwrap_or(1) == i.weight.unwrap_or(1))
+                && w.seg6 == i.seg6
+                && w.seg6local == i.seg6local
+        });
+        match found{
+            Some(n) => {
+                left.remove(n);
+            },
+            None => return Ok(false),
+        }
+    }
+    Ok(true)
+}
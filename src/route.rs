@@ -0,0 +1,287 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{failed, Result, RouterError};
+use crate::Interface;
+
+#[derive(Clone)]
+pub struct Route{
+    pub dst: String,
+    pub gateway: Vec<Nexthop>,
+    /// routing table, main if not set, see `policy`
+    pub table: Option<u32>,
+    /// routes other than unicast have no nexthops
+    pub kind: RouteKind,
+}
+
+/// Type of a route, `ip route add <kind> <dst>`. Only unicast routes
+/// forward, the others drop what they match: blackhole silently,
+/// unreachable with an ICMP host unreachable, prohibit with an ICMP
+/// administratively prohibited.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteKind{
+    /// over gateways or directly out of an interface, see `Nexthop`
+    #[default]
+    Unicast,
+    Blackhole,
+    Unreachable,
+    Prohibit,
+}
+
+impl fmt::Display for RouteKind{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            RouteKind::Unicast => write!(f, "unicast"),
+            RouteKind::Blackhole => write!(f, "blackhole"),
+            RouteKind::Unreachable => write!(f, "unreachable"),
+            RouteKind::Prohibit => write!(f, "prohibit"),
+        }
+    }
+}
+
+impl FromStr for RouteKind{
+    type Err = RouterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s{
+            "unicast" => Ok(RouteKind::Unicast),
+            "blackhole" => Ok(RouteKind::Blackhole),
+            "unreachable" => Ok(RouteKind::Unreachable),
+            "prohibit" => Ok(RouteKind::Prohibit),
+            _ => Err(failed!("Unknown route type {}, expected unicast, blackhole, unreachable or prohibit", s)),
+        }
+    }
+}
+
+/// Nexthop of a route: a gateway given as peer interface or address, an
+/// outgoing interface, or both. The kernel keeps metrics per route, so
+/// nexthops with different metrics are installed as separate routes to the
+/// same destination, the lowest metric is used and the others are backups.
+#[derive(Clone, Default)]
+pub struct Nexthop{
+    /// interface whose address of the route's family is the gateway
+    pub via: Option<Arc<Interface>>,
+    /// gateway address instead of `via`, e.g. an address of the peer of an
+    /// unnumbered link. An IPv6 gateway of an IPv4 route is used as is.
+    pub address: Option<IpAddr>,
+    /// outgoing interface, without gateway the destination is reached
+    /// directly on it, e.g. over an unnumbered point to point link
+    pub dev: Option<String>,
+    /// the gateway is reachable on `dev` even though no subnet of it says so
+    pub onlink: bool,
+    /// share of the traffic relative to the other nexthops of the same
+    /// metric, 1 if not set
+    pub weight: Option<u32>,
+    pub metric: Option<u32>,
+    /// SRv6 encapsulation of the traffic taking the nexthop
+    pub seg6: Option<Seg6>,
+    /// SRv6 behavior of a local segment, the route's destination, `dev`
+    /// is required
+    pub seg6local: Option<Seg6Local>,
+}
+
+/// How `Seg6` adds the segment list.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Seg6Mode{
+    /// in an outer IPv6 header, IPv4 and IPv6 packets alike
+    #[default]
+    Encap,
+    /// as segment routing header inserted into the IPv6 packet
+    Inline,
+}
+
+impl fmt::Display for Seg6Mode{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Seg6Mode::Encap => write!(f, "encap"),
+            Seg6Mode::Inline => write!(f, "inline"),
+        }
+    }
+}
+
+/// SRv6 policy steering traffic through `segs`, the first segment first,
+/// `ip route ... encap seg6`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Seg6{
+    #[serde(default)]
+    pub mode: Seg6Mode,
+    pub segs: Vec<Ipv6Addr>,
+}
+
+/// End behavior of a local segment, `ip route ... encap seg6local`. Which
+/// parameter is needed depends on `action`:
+///
+/// - `End`: none
+/// - `End.X`, `End.DX6`: `nh6`
+/// - `End.DX4`: `nh4`
+/// - `End.T`: `table`
+/// - `End.DT4`, `End.DT6`, `End.DT46`: `table`, that of a VRF of the
+///   namespace
+/// - `End.B6`, `End.B6.Encaps`: `segs`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Seg6Local{
+    pub action: String,
+    #[serde(default)]
+    pub nh4: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub nh6: Option<Ipv6Addr>,
+    #[serde(default)]
+    pub table: Option<u32>,
+    #[serde(default)]
+    pub segs: Vec<Ipv6Addr>,
+}
+
+impl Seg6{
+    pub fn args(&self) -> Result<Vec<String>>{
+        if self.segs.is_empty() {
+            return Err(failed!("SRv6 encapsulation without segments"));
+        }
+        Ok(vec![
+            "encap".to_string(), "seg6".to_string(),
+            "mode".to_string(), self.mode.to_string(),
+            "segs".to_string(), segs(&self.segs),
+        ])
+    }
+}
+
+impl Seg6Local{
+    pub fn args(&self) -> Result<Vec<String>>{
+        let mut args = vec!["encap".to_string(), "seg6local".to_string(), "action".to_string(), self.action.clone()];
+        let missing = |what: &str| failed!("SRv6 action {} needs {}", self.action, what);
+        match self.action.as_str(){
+            "End" => {},
+            "End.X" | "End.DX6" => {
+                let nh6 = self.nh6.ok_or_else(|| missing("nh6"))?;
+                args.extend(["nh6".to_string(), nh6.to_string()]);
+            },
+            "End.DX4" => {
+                let nh4 = self.nh4.ok_or_else(|| missing("nh4"))?;
+                args.extend(["nh4".to_string(), nh4.to_string()]);
+            },
+            "End.T" => {
+                let table = self.table.ok_or_else(|| missing("table"))?;
+                args.extend(["table".to_string(), table.to_string()]);
+            },
+            "End.DT4" | "End.DT6" | "End.DT46" => {
+                let table = self.table.ok_or_else(|| missing("table"))?;
+                args.extend(["vrftable".to_string(), table.to_string()]);
+            },
+            "End.B6" | "End.B6.Encaps" => {
+                if self.segs.is_empty() {
+                    return Err(missing("segs"));
+                }
+                args.extend(["srh".to_string(), "segs".to_string(), segs(&self.segs)]);
+            },
+            other => return Err(failed!("Unknown SRv6 action {}", other)),
+        }
+        Ok(args)
+    }
+
+    /// Decapsulates into a VRF, which the kernel only does in VRF strict
+    /// mode.
+    pub fn vrf(&self) -> bool {
+        matches!(self.action.as_str(), "End.DT4" | "End.DT6" | "End.DT46")
+    }
+}
+
+fn segs(segs: &[Ipv6Addr]) -> String {
+    segs.iter().map(|s| s.to_string()).collect::<Vec<String>>().join(",")
+}
+
+impl From<Arc<Interface>> for Nexthop{
+    fn from(via: Arc<Interface>) -> Nexthop {
+        Nexthop{
+            via: Some(via),
+            ..Default::default()
+        }
+    }
+}
+
+impl Nexthop{
+    /// Gateway address for a route of the given family, None for a nexthop
+    /// without gateway.
+    pub fn gateway(&self, v6: bool) -> Result<Option<IpAddr>>{
+        if let Some(address) = self.address{
+            return Ok(Some(address));
+        }
+        let Some(intf) = &self.via else {
+            if self.dev.is_none() {
+                return Err(failed!("Nexthop has neither gateway nor interface"));
+            }
+            return Ok(None);
+        };
+        let ip = if v6 { &intf.ip6 } else { &intf.ip };
+        let ip = ip.as_ref()
+            .ok_or_else(|| failed!("Interface {} does not have an {} address", intf.name, if v6 { "IPv6" } else { "IPv4" }))?;
+        let ip = ip.split('/').next().unwrap_or_default();
+        Ok(Some(ip.parse().map_err(|e| failed!("Invalid address {} of interface {}: {}", ip, intf.name, e))?))
+    }
+
+    /// `ip route` arguments of the nexthop, following the destination or
+    /// `nexthop`.
+    pub fn args(&self, v6: bool, multipath: bool) -> Result<Vec<String>>{
+        let mut args = Vec::new();
+        if let Some(seg6) = &self.seg6{
+            args.extend(seg6.args()?);
+        }
+        if let Some(seg6local) = &self.seg6local{
+            if self.dev.is_none() {
+                return Err(failed!("SRv6 action {} needs an interface", seg6local.action));
+            }
+            args.extend(seg6local.args()?);
+        }
+        if let Some(gateway) = self.gateway(v6)?{
+            args.push("via".to_string());
+            if gateway.is_ipv6() && !v6 {
+                args.push("inet6".to_string());
+            }
+            args.push(gateway.to_string());
+        }
+        if let Some(dev) = &self.dev{
+            args.push("dev".to_string());
+            args.push(dev.clone());
+        }
+        if multipath {
+            args.push("weight".to_string());
+            args.push(self.weight.unwrap_or(1).to_string());
+        }
+        if self.onlink {
+            args.push("onlink".to_string());
+        }
+        Ok(args)
+    }
+}
+
+impl Route{
+    /// `ip route` arguments of a route of `kind` other than unicast,
+    /// replacing those of the nexthops. Fails for one with nexthops.
+    pub fn kind_args(&self) -> Result<Vec<String>>{
+        if !self.gateway.is_empty() {
+            return Err(failed!("{} route to {} cannot have nexthops", self.kind, self.dst));
+        }
+        let mut args = vec![self.kind.to_string(), self.dst.clone()];
+        if let Some(table) = self.table{
+            args.push("table".to_string());
+            args.push(table.to_string());
+        }
+        Ok(args)
+    }
+
+    /// Nexthops grouped by metric, each group is one kernel route.
+    pub fn by_metric(&self) -> Vec<(Option<u32>, Vec<&Nexthop>)> {
+        let mut groups: Vec<(Option<u32>, Vec<&Nexthop>)> = Vec::new();
+        for n in &self.gateway{
+            match groups.iter_mut().find(|(m, _)| *m == n.metric){
+                Some((_, group)) => group.push(n),
+                None => groups.push((n.metric, vec![n])),
+            }
+        }
+        groups
+    }
+}
@@ -0,0 +1,250 @@
+//! Scaling a topology out while it runs: more namespaces wired like an
+//! existing one, e.g. two more leaves of a leaf-spine fabric, or more
+//! parallel links between two namespaces. The description is extended the
+//! way it was written and then applied with `Topology::reconcile`, which
+//! creates what is new and recomputes `auto_routes`:
+//!
+//! - names continue the numbering of the copied one, `leaf2` is followed
+//!   by `leaf3`, `link6` by `link7`
+//! - hand-assigned subnets continue after the copied one with the first
+//!   free subnet of the same size, links without subnet stay that way and
+//!   get theirs from the `ipam` pools, declared loopback addresses
+//!   likewise
+//! - copied links drop the MAC addresses of the template, copied
+//!   namespaces its NAT64 translator, whose prefix and pool exist once
+//! - routes whose gateways already spread over parallel links, or over
+//!   several namespaces including the copied one, get the new gateways as
+//!   well, so ECMP routes stay complete
+//!
+//! New links and namespaces are appended to the description, links taking
+//! their subnets from IPAM in order keep the ones they have.
+
+use std::collections::BTreeSet;
+
+use ipnet::IpNet;
+
+use crate::error::{failed, Result, RouterError};
+use crate::topology::{BgpNeighborSpec, LinkSpec, NexthopSpec, RouteSpec, Topology, VtepSpec};
+
+/// Adds `count` links between namespaces `a` and `b`, copies of the last
+/// link between them. Returns the names of the new links.
+pub fn add_links(topology: &mut Topology, a: &str, b: &str, count: u32) -> Result<Vec<String>>{
+    let between = |l: &LinkSpec| l.endpoints.len() == 2 && l.endpoints.iter().any(|e| e == a) && l.endpoints.iter().any(|e| e == b);
+    let siblings: Vec<String> = topology.links.iter().filter(|l| between(l)).map(|l| l.name.clone()).collect();
+    let Some(template) = topology.links.iter().rev().find(|l| between(l)).cloned() else {
+        return Err(failed!("No link between {} and {} to copy", a, b));
+    };
+    let mut added = Vec::new();
+    for _ in 0..count{
+        let link = copy_link(topology, &template, &template.endpoints)?;
+        for (ns, peer) in [(a, b), (b, a)]{
+            let old: Vec<String> = siblings.iter().map(|s| format!("{}_{}", peer, s)).collect();
+            extend_gateways(topology, |r| r.namespace == ns, &old, &format!("{}_{}", peer, link));
+            let old: Vec<String> = siblings.iter().map(|s| format!("{}_{}", ns, s)).collect();
+            for vrf in topology.namespaces.iter_mut().filter(|n| n.name == ns).flat_map(|n| n.vrfs.iter_mut()){
+                if vrf.interfaces.iter().any(|i| old.contains(i)) {
+                    vrf.interfaces.push(format!("{}_{}", ns, link));
+                }
+            }
+        }
+        added.push(link);
+    }
+    Ok(added)
+}
+
+/// Adds `count` namespaces copied from `like`, with copies of its links,
+/// bridge memberships, routes and policy, and as further instance of its
+/// services, BGP neighbor of its neighbors and VTEP of its VXLAN links
+/// tunnelled from a copied link. Links towards stub
+/// namespaces aren't copied, hosts stay single-homed. Returns the names
+/// of the new namespaces.
+pub fn add_namespaces(topology: &mut Topology, like: &str, count: u32) -> Result<Vec<String>>{
+    let Some(template) = topology.namespaces.iter().find(|n| n.name == like).cloned() else {
+        return Err(RouterError::NamespaceNotFound(like.to_string()));
+    };
+    let stubs: BTreeSet<String> = topology.namespaces.iter().filter(|n| n.stub).map(|n| n.name.clone()).collect();
+    let links: Vec<LinkSpec> = topology.links.iter()
+        .filter(|l| l.endpoints.iter().any(|e| e == like) && !l.endpoints.iter().any(|e| stubs.contains(e)))
+        .cloned()
+        .collect();
+    let mut added = Vec::new();
+    for _ in 0..count{
+        let mut taken: BTreeSet<String> = topology.namespaces.iter().map(|n| n.name.clone()).collect();
+        taken.extend(topology.bridges.iter().map(|b| b.name.clone()));
+        let name = next_name(like, &taken);
+
+        // interface names of the template and its peers and their copies
+        let mut renamed: Vec<(String, String)> = Vec::new();
+        for l in &links{
+            let endpoints: Vec<String> = l.endpoints.iter().map(|e| if e == like { name.clone() } else { e.clone() }).collect();
+            let link = copy_link(topology, l, &endpoints)?;
+            for (old, new) in l.endpoints.iter().zip(&endpoints){
+                renamed.push((format!("{}_{}", old, l.name), format!("{}_{}", new, link)));
+            }
+        }
+        let rename = |i: &String| renamed.iter().find(|(old, _)| old == i).map(|(_, new)| new.clone());
+
+        let mut spec = template.clone();
+        spec.name = name.clone();
+        if let Some(bgp) = &mut spec.bgp{
+            // a copy of an AS of its own gets an AS of its own
+            if topology.namespaces.iter().filter(|n| n.bgp.as_ref().is_some_and(|b| b.asn == bgp.asn)).count() == 1 {
+                bgp.asn = topology.namespaces.iter().filter_map(|n| n.bgp.as_ref()).map(|b| b.asn).max().unwrap_or(bgp.asn) + 1;
+            }
+        }
+        if let Some(nat) = &mut spec.nat{
+            nat.out = rename(&nat.out).unwrap_or(nat.out.clone());
+        }
+        for rule in &mut spec.rules{
+            for i in [&mut rule.iif, &mut rule.oif].into_iter().flatten(){
+                *i = rename(i).unwrap_or(i.clone());
+            }
+        }
+        for vrf in &mut spec.vrfs{
+            vrf.interfaces = vrf.interfaces.iter().filter_map(rename).collect();
+        }
+        spec.nat64 = None;
+        if let Some(lo) = &mut spec.loopback{
+            let used = used(topology)?;
+            for address in [&mut lo.address, &mut lo.address6].into_iter().flatten(){
+                let addr: std::net::IpAddr = address.parse()
+                    .map_err(|e| failed!("Invalid loopback address {}: {}", address, e))?;
+                let next: IpNet = next_subnet(&IpNet::from(addr).to_string(), &used)?.parse()?;
+                *address = next.addr().to_string();
+            }
+        }
+        topology.namespaces.push(spec);
+
+        for ns in topology.namespaces.iter_mut().filter_map(|n| n.bgp.as_mut()){
+            if ns.neighbors.iter().any(|n| n.peer == like) {
+                ns.neighbors.push(BgpNeighborSpec{ peer: name.clone(), address: None });
+            }
+        }
+        for b in topology.bridges.iter_mut().filter(|b| b.members.iter().any(|m| m == like)){
+            b.members.push(name.clone());
+        }
+        for svc in topology.services.iter_mut().filter(|s| s.instances.iter().any(|i| i == like)){
+            svc.instances.push(name.clone());
+        }
+        for v in &mut topology.vxlans{
+            if let Some(local) = v.endpoints.iter().find(|e| e.namespace == like).and_then(|e| rename(&e.local)) {
+                v.endpoints.push(VtepSpec{ namespace: name.clone(), local, host: None });
+            }
+        }
+        let mut routes = Vec::new();
+        for r in topology.routes.iter().filter(|r| r.namespace == like){
+            let mut r = r.clone();
+            r.namespace = name.clone();
+            r.gateways = r.gateways.iter().map(|g| rename(g).unwrap_or(g.clone())).collect();
+            for n in &mut r.nexthops{
+                n.via = n.via.as_ref().map(|v| rename(v).unwrap_or(v.clone()));
+                n.dev = n.dev.as_ref().map(|d| rename(d).unwrap_or(d.clone()));
+            }
+            routes.push(r);
+        }
+        topology.routes.extend(routes);
+        // ECMP routes over several namespaces including the template
+        for (old, new) in renamed.iter().filter(|(old, _)| old.starts_with(&format!("{}_", like))){
+            let spread = |r: &RouteSpec| r.namespace != name && r.gateways.iter().any(|g| !g.starts_with(&format!("{}_", like)));
+            extend_gateways(topology, spread, std::slice::from_ref(old), new);
+        }
+        added.push(name);
+    }
+    Ok(added)
+}
+
+/// Appends a copy of `template` between `endpoints` to the links of
+/// `topology` and returns its name.
+fn copy_link(topology: &mut Topology, template: &LinkSpec, endpoints: &[String]) -> Result<String>{
+    let mut taken: BTreeSet<String> = topology.links.iter().map(|l| l.name.clone()).collect();
+    taken.extend(topology.bridges.iter().map(|b| b.name.clone()));
+    let mut used = used(topology)?;
+    let mut link = template.clone();
+    link.name = next_name(&template.name, &taken);
+    link.endpoints = endpoints.to_vec();
+    // MAC addresses are the template's own, stable_macs derives new ones
+    link.macs.clear();
+    if !template.subnet.is_empty() {
+        link.subnet = next_subnet(&template.subnet, &used)?;
+        used.push(link.subnet.parse()?);
+    }
+    if let Some(subnet6) = &template.subnet6{
+        link.subnet6 = Some(next_subnet(subnet6, &used)?);
+    }
+    let name = link.name.clone();
+    topology.links.push(link);
+    Ok(name)
+}
+
+/// Adds gateway `new` to the routes selected by `select` which use any of
+/// the gateways `old`, nexthops through them are copied.
+fn extend_gateways(topology: &mut Topology, select: impl Fn(&RouteSpec) -> bool, old: &[String], new: &str){
+    for r in topology.routes.iter_mut().filter(|r| select(r)){
+        if r.gateways.iter().any(|g| old.contains(g)) && !r.gateways.iter().any(|g| g == new) {
+            r.gateways.push(new.to_string());
+        }
+        let via = |n: &NexthopSpec| n.via.as_ref().is_some_and(|v| old.contains(v));
+        if let Some(n) = r.nexthops.iter().rev().find(|n| via(n)).cloned() {
+            r.nexthops.push(NexthopSpec{ via: Some(new.to_string()), ..n });
+        }
+    }
+}
+
+/// `name` with its trailing number replaced by the next one not in
+/// `taken`, a name without number continues with 2.
+fn next_name(name: &str, taken: &BTreeSet<String>) -> String {
+    let stem = name.trim_end_matches(|c: char| c.is_ascii_digit());
+    let highest = taken.iter()
+        .filter_map(|t| t.strip_prefix(stem))
+        .filter_map(|n| n.parse::<u64>().ok())
+        .max()
+        .unwrap_or(1);
+    format!("{}{}", stem, highest + 1)
+}
+
+/// Prefixes taken by hand-assigned subnets, declared loopback addresses
+/// and the `ipam` pools.
+fn used(topology: &Topology) -> Result<Vec<IpNet>>{
+    let mut used = Vec::new();
+    for subnet in topology.links.iter().flat_map(|l| std::iter::once(&l.subnet).chain(l.subnet6.iter()))
+        .chain(topology.bridges.iter().flat_map(|b| std::iter::once(&b.subnet).chain(b.subnet6.iter())))
+        .chain(topology.ipam.iter().flat_map(|i| [&i.pool, &i.pool6, &i.loopbacks, &i.loopbacks6].into_iter().flatten()))
+        .filter(|s| !s.is_empty()){
+        let net: IpNet = subnet.parse()
+            .map_err(|e| failed!("Invalid subnet {}: {}", subnet, e))?;
+        used.push(net.trunc());
+    }
+    for address in topology.namespaces.iter().filter_map(|n| n.loopback.as_ref()).flat_map(|l| l.address.iter().chain(l.address6.iter())){
+        let addr: std::net::IpAddr = address.parse()
+            .map_err(|e| failed!("Invalid loopback address {}: {}", address, e))?;
+        used.push(IpNet::from(addr));
+    }
+    Ok(used)
+}
+
+/// First subnet of the size of `subnet` following it that overlaps none
+/// of `used`.
+fn next_subnet(subnet: &str, used: &[IpNet]) -> Result<String>{
+    let net: IpNet = subnet.parse()
+        .map_err(|e| failed!("Invalid subnet {}: {}", subnet, e))?;
+    let net = net.trunc();
+    let overlaps = |n: &IpNet| used.iter().any(|u| u.contains(&n.network()) || n.contains(&u.network()));
+    let mut candidate = net;
+    loop{
+        let next = match candidate{
+            IpNet::V4(n) => 1u32.checked_shl(32 - n.prefix_len() as u32)
+                .and_then(|size| u32::from(n.network()).checked_add(size))
+                .map(|a| IpNet::new(std::net::Ipv4Addr::from(a).into(), n.prefix_len())),
+            IpNet::V6(n) => 1u128.checked_shl(128 - n.prefix_len() as u32)
+                .and_then(|size| u128::from(n.network()).checked_add(size))
+                .map(|a| IpNet::new(std::net::Ipv6Addr::from(a).into(), n.prefix_len())),
+        };
+        candidate = match next{
+            Some(next) => next?,
+            None => return Err(failed!("No free subnet of the size of {} left after it", net)),
+        };
+        if !overlaps(&candidate) {
+            return Ok(candidate.to_string());
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Scenarios: timed steps run against a topology from a file, e.g. bring
+//! it up at 0 s, fail a link at 10 s, restore it at 20 s and check
+//! connectivity at 30 s, so an experiment can be repeated as it was and
+//! its report compared with those of earlier runs, see `experiment`.
+//!
+//! Steps run in the order of their time, steps at the same time in the
+//! order of the file. A step runs once it is due and the one before it is
+//! done, a step taking longer than the time to the next one delays it, and
+//! the report has both the time a step was due and the time it started.
+//!
+//! Checks are recorded, passed or not, and the run goes on. A step which
+//! fails to do what it should, e.g. a link that doesn't exist, ends the
+//! run: the steps after it are skipped and the links failed by the
+//! scenario are restored, so the topology isn't left broken.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::chaos::{self, ChaosAction};
+use crate::error::{failed, Result, RouterError};
+use crate::experiment::RunResult;
+use crate::netns;
+use crate::state::State;
+use crate::topology::Topology;
+use crate::trace::Traced;
+use crate::verify::{self, CheckResult, CheckSpec, VerifyOptions};
+use crate::{Config, Namespace};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Action{
+    /// creates the topology or reconciles it with `file`, the scenario's
+    /// topology if not set
+    Up{
+        #[serde(default)]
+        file: Option<PathBuf>,
+    },
+    /// destroys the topology
+    Destroy,
+    /// takes both ends of a link down
+    Fail{ link: String },
+    /// brings both ends of a link up and stops dropping packets on it
+    Restore{ link: String },
+    /// drops `percent` of the packets of a link in both directions
+    Loss{ link: String, percent: f64 },
+    /// stops dropping packets on a link
+    Clear{ link: String },
+    /// checks connectivity, see `CheckSpec`
+    Check(CheckSpec),
+    /// runs a shell command in a namespace, which has to succeed
+    Exec{ namespace: String, command: String },
+}
+
+impl fmt::Display for Action{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Action::Up{ file: Some(file) } => write!(f, "up {}", file.display()),
+            Action::Up{ file: None } => write!(f, "up"),
+            Action::Destroy => write!(f, "destroy"),
+            Action::Fail{ link } => write!(f, "fail {}", link),
+            Action::Restore{ link } => write!(f, "restore {}", link),
+            Action::Loss{ link, percent } => write!(f, "loss {} {}%", link, percent),
+            Action::Clear{ link } => write!(f, "clear {}", link),
+            Action::Check(c) => write!(f, "check {} -> {}", c.from, c.to),
+            Action::Exec{ namespace, command } => write!(f, "exec {}: {}", namespace, command),
+        }
+    }
+}
+
+/// Action at a point of the scenario.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Step{
+    /// milliseconds since the start of the run
+    pub at: u64,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Scenario{
+    #[serde(default)]
+    pub name: String,
+    /// topology file, relative to the scenario file
+    pub topology: PathBuf,
+    pub steps: Vec<Step>,
+}
+
+impl Scenario{
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Scenario>{
+        let path = path.as_ref();
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| failed!("Failed to read scenario {}: {}", path.display(), e))?;
+        let mut scenario: Scenario = serde_yaml::from_str(&data)
+            .map_err(|e| failed!("Failed to parse scenario {}: {}", path.display(), e))?;
+        if scenario.name.is_empty() {
+            if let Some(stem) = path.file_stem(){
+                scenario.name = stem.to_string_lossy().to_string();
+            }
+        }
+        let dir = path.parent().unwrap_or(Path::new("."));
+        scenario.topology = dir.join(&scenario.topology);
+        for step in &mut scenario.steps{
+            if let Action::Up{ file: Some(file) } = &mut step.action {
+                *file = dir.join(&*file);
+            }
+        }
+        Ok(scenario)
+    }
+}
+
+/// Step as it ran.
+#[derive(Serialize, Clone, Debug)]
+pub struct StepRecord{
+    /// when the step was due, since the start of the run
+    pub at: Duration,
+    /// when it started
+    pub started: Duration,
+    pub took: Duration,
+    pub step: String,
+    pub passed: bool,
+    pub error: Option<String>,
+    /// result of a check
+    pub check: Option<CheckResult>,
+}
+
+impl fmt::Display for StepRecord{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>9.3}s {} {}", self.started.as_secs_f64(), if self.passed { "ok  " } else { "FAIL" }, self.step)?;
+        let late = self.started.saturating_sub(self.at);
+        if late >= Duration::from_millis(100) {
+            write!(f, " ({:.1} s late)", late.as_secs_f64())?;
+        }
+        write!(f, ", took {:.1} ms", self.took.as_secs_f64() * 1000.0)?;
+        match (&self.check, &self.error){
+            (Some(check), _) => write!(f, ": {}", check),
+            (None, Some(e)) => write!(f, ": {}", e),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ScenarioReport{
+    pub scenario: String,
+    pub topology: String,
+    pub steps: Vec<StepRecord>,
+    /// steps not run after a step failed
+    pub skipped: Vec<String>,
+}
+
+impl ScenarioReport{
+    pub fn passed(&self) -> bool {
+        self.skipped.is_empty() && self.steps.iter().all(|s| s.passed)
+    }
+
+    /// Results of the checks, to compare with other runs, keyed by the
+    /// time and the check.
+    pub fn result(&self) -> RunResult {
+        RunResult{
+            name: self.scenario.clone(),
+            verifications: self.steps.iter()
+                .filter(|s| s.check.is_some())
+                .map(|s| (format!("{}ms {}", s.at.as_millis(), s.step), s.passed))
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl fmt::Display for ScenarioReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "scenario {} on {}", self.scenario, self.topology)?;
+        for s in &self.steps{
+            writeln!(f, "{}", s)?;
+        }
+        for s in &self.skipped{
+            writeln!(f, "      -   skipped {}", s)?;
+        }
+        let failed = self.steps.iter().filter(|s| !s.passed).count();
+        write!(f, "{} of {} steps passed", self.steps.len() - failed, self.steps.len() + self.skipped.len())
+    }
+}
+
+pub struct ScenarioRun{
+    pub scenario: Scenario,
+    /// let `up` change the host outside the namespaces
+    pub host_changes: bool,
+    /// options of the checks
+    pub options: VerifyOptions,
+}
+
+impl ScenarioRun{
+    pub fn run(&self) -> Result<ScenarioReport>{
+        let topology = Topology::from_file(&self.scenario.topology)?;
+        let mut steps: Vec<&Step> = self.scenario.steps.iter().collect();
+        // stable, steps at the same time keep the order of the file
+        steps.sort_by_key(|s| s.at);
+        let mut report = ScenarioReport{ scenario: self.scenario.name.clone(), topology: topology.name.clone(), ..Default::default() };
+        let mut failed: Vec<String> = Vec::new();
+        let start = Instant::now();
+        for (n, step) in steps.iter().enumerate(){
+            let due = start + Duration::from_millis(step.at);
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+            let started = Instant::now();
+            let mut record = StepRecord{
+                at: Duration::from_millis(step.at),
+                started: started - start,
+                took: Duration::ZERO,
+                step: step.action.to_string(),
+                passed: true,
+                error: None,
+                check: None,
+            };
+            match self.step(&topology, &step.action, &mut failed){
+                Ok(Some(check)) => {
+                    record.passed = check.passed;
+                    record.check = Some(check);
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    record.passed = false;
+                    record.error = Some(e.to_string());
+                },
+            }
+            record.took = started.elapsed();
+            let aborted = record.error.is_some();
+            report.steps.push(record);
+            if aborted {
+                report.skipped = steps[n + 1..].iter().map(|s| s.action.to_string()).collect();
+                if let Ok(Some(state)) = State::load(&topology.name) {
+                    for link in &failed{
+                        for (netns, interface) in chaos::link_ends(&state, link).unwrap_or_default(){
+                            let _ = chaos::apply(&netns, &interface, ChaosAction::Up, None);
+                            let _ = chaos::apply(&netns, &interface, ChaosAction::Clear, None);
+                        }
+                    }
+                }
+                break;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Runs `action`, returns the result of a check. Links it leaves
+    /// failed or lossy are kept in `failed`.
+    fn step(&self, topology: &Topology, action: &Action, failed: &mut Vec<String>) -> Result<Option<CheckResult>>{
+        let state = || State::load(&topology.name)?
+            .ok_or_else(|| RouterError::TopologyNotFound(topology.name.to_string()));
+        let change = |link: &str, actions: &[(ChaosAction, Option<f64>)]| -> Result<()>{
+            for (netns, interface) in chaos::link_ends(&state()?, link)?{
+                for (action, percent) in actions{
+                    chaos::apply(&netns, &interface, *action, *percent)
+                        .map_err(|e| failed!("Failed to {} link {}: {}", action, link, e))?;
+                }
+            }
+            Ok(())
+        };
+        match action{
+            Action::Up{ file } => {
+                let topology = match file{
+                    Some(file) => {
+                        let mut t = Topology::from_file(file)?;
+                        t.name = topology.name.clone();
+                        t
+                    },
+                    None => topology.clone(),
+                };
+                let mut config = Config::new(topology.name.clone());
+                config.host_changes = self.host_changes;
+                topology.reconcile_with(config)?;
+            },
+            Action::Destroy => {
+                Topology::destroy(&topology.name)?;
+                failed.clear();
+            },
+            Action::Fail{ link } => {
+                change(link, &[(ChaosAction::Down, None)])?;
+                failed.push(link.clone());
+            },
+            Action::Restore{ link } => {
+                change(link, &[(ChaosAction::Up, None), (ChaosAction::Clear, None)])?;
+                failed.retain(|l| l != link);
+            },
+            Action::Loss{ link, percent } => {
+                if !(*percent > 0.0 && *percent <= 100.0) {
+                    return Err(failed!("Loss on {} needs a percentage above 0 up to 100", link));
+                }
+                change(link, &[(ChaosAction::Loss, Some(*percent))])?;
+                failed.push(link.clone());
+            },
+            Action::Clear{ link } => {
+                change(link, &[(ChaosAction::Clear, None)])?;
+                failed.retain(|l| l != link);
+            },
+            Action::Check(spec) => return Ok(Some(verify::check(&state()?, spec, &self.options))),
+            Action::Exec{ namespace, command } => {
+                let netns = Namespace::netns_name(&topology.name, namespace);
+                let output = netns::command(&netns, "sh").args(["-c", command.as_str()]).traced_output()?;
+                if !output.status.success() {
+                    return Err(failed!("{} in {}: {}", output.status, namespace, String::from_utf8_lossy(&output.stderr).trim()));
+                }
+            },
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,494 @@
+//! Interactive shell exploring and modifying a topology: every change to
+//! the description is applied at once by reconciling the topology, a
+//! change which fails to apply is dropped. The shell starts from a
+//! description file, the running topology as imported from its namespaces,
+//! or an empty topology.
+//!
+//! On a terminal, lines are edited with the arrow keys, history and tab
+//! completion over commands and the names of namespaces, links and
+//! interfaces. Otherwise commands are read line by line, so the shell can
+//! run scripts.
+
+use std::io::{BufRead, Read, Write};
+
+use crate::error::{failed, Result, RouterError};
+use crate::parallel::Parallelism;
+use crate::topology::{LinkSpec, NamespaceSpec, NexthopSpec, RouteSpec, Topology};
+use crate::trace::Traced;
+use crate::verify::{self, CheckSpec, VerifyOptions};
+use crate::{environment, netns, Config, Namespace, RouteKind};
+
+const HELP: &str = "\
+add ns <name> [stub]               add a namespace, a host if stub
+del ns <name>                      delete a namespace with its links and routes
+link <a> <b> [subnet] [subnet6]    connect two namespaces
+del link <name>                    delete a link and the routes over it
+route <ns> <dst> via <gw> [via ..] add a route, gateways are namespaces,
+                                   their interfaces or addresses
+route <ns> <dst> <type>            add a blackhole, unreachable or
+                                   prohibit route
+del route <ns> <dst>               delete a route
+ping <from> <to>                   ping a namespace or address
+exec <ns> <command> ..             run a command in a namespace
+list                               namespaces, links and routes
+show                               print the description as YAML
+save <file>                        write the description, YAML or TOML
+help
+exit";
+
+const COMMANDS: [&str; 11] = ["add", "del", "link", "route", "ping", "exec", "list", "show", "save", "help", "exit"];
+
+pub struct Shell{
+    pub topology: Topology,
+    pub parallelism: Parallelism,
+    /// allow edits to change the host outside the namespaces, see
+    /// `preflight::host_changes`
+    pub host_changes: bool,
+}
+
+impl Shell{
+    pub fn new(topology: Topology, parallelism: Parallelism) -> Self {
+        Shell{ topology, parallelism, host_changes: false }
+    }
+
+    /// Reads and runs commands until `exit` or the end of input.
+    pub fn run(&mut self) -> Result<()>{
+        if unsafe { libc::isatty(0) } != 1 {
+            for line in std::io::stdin().lock().lines(){
+                if !self.run_line(&line?) {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+        let mut editor = LineEditor::default();
+        let prompt = format!("{}> ", self.topology.name);
+        while let Some(line) = editor.read(&prompt, |line| self.completions(line))?{
+            if !self.run_line(&line) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `line`, reporting errors, false once the shell should exit.
+    fn run_line(&mut self, line: &str) -> bool {
+        self.execute(line).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            true
+        })
+    }
+
+    /// Runs one command line, false once the shell should exit.
+    pub fn execute(&mut self, line: &str) -> Result<bool>{
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice(){
+            [] => {},
+            [w, ..] if w.starts_with('#') => {},
+            ["exit"] | ["quit"] => return Ok(false),
+            ["help"] => println!("{}", HELP),
+            ["add", "ns" | "namespace", name, rest @ ..] => {
+                let stub = match rest{
+                    [] => false,
+                    ["stub"] => true,
+                    _ => return Err(failed!("Usage: add ns <name> [stub]")),
+                };
+                if self.namespace(name).is_some() {
+                    return Err(failed!("Namespace {} exists", name));
+                }
+                let spec = NamespaceSpec{ name: name.to_string(), stub, ..Default::default() };
+                self.change(|t| {
+                    t.namespaces.push(spec);
+                    Ok(())
+                })?;
+            },
+            ["del", "ns" | "namespace", name] => {
+                self.namespace(name).ok_or_else(|| RouterError::NamespaceNotFound(name.to_string()))?;
+                let name = name.to_string();
+                self.change(|t| {
+                    let links: Vec<String> = t.links.iter().filter(|l| l.endpoints.contains(&name)).map(|l| l.name.clone()).collect();
+                    for link in links{
+                        remove_link(t, &link);
+                    }
+                    for b in &mut t.bridges{
+                        b.members.retain(|m| *m != name);
+                    }
+                    t.routes.retain(|r| r.namespace != name);
+                    t.checks.retain(|c| c.from != name && c.to != name);
+                    t.namespaces.retain(|n| n.name != name);
+                    Ok(())
+                })?;
+            },
+            ["link", a, b, rest @ ..] => {
+                for ns in [a, b]{
+                    self.namespace(ns).ok_or_else(|| RouterError::NamespaceNotFound(ns.to_string()))?;
+                }
+                let (subnet, subnet6) = match rest{
+                    [] => (String::new(), None),
+                    [subnet] => (subnet.to_string(), None),
+                    [subnet, subnet6] => (subnet.to_string(), Some(subnet6.to_string())),
+                    _ => return Err(failed!("Usage: link <a> <b> [subnet] [subnet6]")),
+                };
+                let mut n = self.topology.links.len();
+                while self.topology.links.iter().any(|l| l.name == format!("l{}", n)){
+                    n += 1;
+                }
+                let spec = LinkSpec{
+                    name: format!("l{}", n),
+                    subnet,
+                    subnet6,
+                    endpoints: vec![a.to_string(), b.to_string()],
+                    ..Default::default()
+                };
+                println!("link {}: {}_{} <-> {}_{}", spec.name, a, spec.name, b, spec.name);
+                self.change(|t| {
+                    t.links.push(spec);
+                    Ok(())
+                })?;
+            },
+            ["del", "link", name] => {
+                if !self.topology.links.iter().any(|l| l.name == *name) {
+                    return Err(failed!("Link {} not found", name));
+                }
+                self.change(|t| {
+                    remove_link(t, name);
+                    Ok(())
+                })?;
+            },
+            ["route", ns, dst, rest @ ..] if !rest.is_empty() => {
+                self.namespace(ns).ok_or_else(|| RouterError::NamespaceNotFound(ns.to_string()))?;
+                let mut route = RouteSpec{ namespace: ns.to_string(), dst: dst.to_string(), ..Default::default() };
+                let pairs = match rest{
+                    [kind] => {
+                        route.kind = kind.parse()?;
+                        &[]
+                    },
+                    _ => rest,
+                };
+                for pair in pairs.chunks(2){
+                    let ["via", gw] = pair else {
+                        return Err(failed!("Usage: route <ns> <dst> via <gateway> [via <gateway> ..]"));
+                    };
+                    if gw.parse::<std::net::IpAddr>().is_ok() {
+                        route.nexthops.push(NexthopSpec{ address: Some(gw.to_string()), ..Default::default() });
+                    } else {
+                        route.gateways.push(self.gateway(ns, gw)?);
+                    }
+                }
+                self.change(|t| {
+                    t.routes.retain(|r| r.namespace != route.namespace || r.dst != route.dst);
+                    t.routes.push(route);
+                    Ok(())
+                })?;
+            },
+            ["del", "route", ns, dst] => {
+                if !self.topology.routes.iter().any(|r| r.namespace == *ns && r.dst == *dst) {
+                    return Err(failed!("Route {} in {} not found", dst, ns));
+                }
+                self.change(|t| {
+                    t.routes.retain(|r| r.namespace != *ns || r.dst != *dst);
+                    Ok(())
+                })?;
+            },
+            ["ping", from, to] => {
+                let mut topology = self.topology.clone();
+                topology.checks = vec![CheckSpec{ from: from.to_string(), to: to.to_string(), ..Default::default() }];
+                for result in verify::run(&topology, &VerifyOptions::default())?{
+                    println!("{}", result);
+                }
+            },
+            ["exec", ns, command @ ..] if !command.is_empty() => {
+                let netns = Namespace::netns_name(&self.topology.name, ns);
+                if !std::path::Path::new("/run/netns").join(&netns).exists() {
+                    return Err(RouterError::NamespaceNotFound(ns.to_string()));
+                }
+                let mut cmd = netns::command(&netns, command[0]);
+                environment::apply(&mut cmd, &self.topology.name, ns)?;
+                cmd.args(&command[1..]).traced_status()
+                    .map_err(|e| failed!("Failed to run {} in {}: {}", command[0], netns, e))?;
+            },
+            ["list"] => self.list(),
+            ["show"] => print!("{}", serde_yaml::to_string(&self.topology)?),
+            ["save", file] => {
+                let data = match std::path::Path::new(file).extension().and_then(|e| e.to_str()){
+                    Some("toml") => toml::to_string(&self.topology)?,
+                    _ => serde_yaml::to_string(&self.topology)?,
+                };
+                std::fs::write(file, data).map_err(|e| failed!("Failed to write {}: {}", file, e))?;
+            },
+            _ => return Err(failed!("Unknown command {}, try help", line.trim())),
+        }
+        Ok(true)
+    }
+
+    /// Applies `edit` to a copy of the description and reconciles the
+    /// topology with it, keeping the copy if that worked.
+    fn change<F>(&mut self, edit: F) -> Result<()>
+    where
+        F: FnOnce(&mut Topology) -> Result<()>,
+    {
+        let mut topology = self.topology.clone();
+        edit(&mut topology)?;
+        let mut config = Config::new(topology.name.clone());
+        config.parallelism = self.parallelism;
+        config.host_changes = self.host_changes;
+        topology.reconcile_with(config)?;
+        self.topology = topology;
+        Ok(())
+    }
+
+    fn namespace(&self, name: &str) -> Option<&NamespaceSpec> {
+        self.topology.namespaces.iter().find(|n| n.name == name)
+    }
+
+    /// Gateway interface of a route from `ns` via `gw`: the interface of
+    /// the namespace `gw` on a link or bridge shared with `ns`, or `gw`
+    /// itself if it is no namespace.
+    fn gateway(&self, ns: &str, gw: &str) -> Result<String>{
+        if self.namespace(gw).is_none() {
+            return Ok(gw.to_string());
+        }
+        let segments = self.topology.links.iter().map(|l| (&l.name, &l.endpoints))
+            .chain(self.topology.bridges.iter().map(|b| (&b.name, &b.members)));
+        for (segment, members) in segments{
+            if members.iter().any(|m| m == ns) && members.iter().any(|m| m == gw) {
+                return Ok(format!("{}_{}", gw, segment));
+            }
+        }
+        Err(failed!("{} and {} share no link", ns, gw))
+    }
+
+    fn list(&self){
+        for ns in &self.topology.namespaces{
+            println!("{}{}", ns.name, if ns.stub { " (stub)" } else { "" });
+            for i in self.interfaces().iter().filter(|i| i.starts_with(&format!("{}_", ns.name))){
+                println!("  {}", i);
+            }
+            for r in self.topology.routes.iter().filter(|r| r.namespace == ns.name){
+                let via: Vec<&str> = r.gateways.iter().map(|g| g.as_str())
+                    .chain(r.nexthops.iter().filter_map(|n| n.address.as_deref().or(n.via.as_deref())))
+                    .collect();
+                match r.kind{
+                    RouteKind::Unicast => println!("  route {} via {}", r.dst, via.join(", ")),
+                    kind => println!("  route {} {}", r.dst, kind),
+                }
+            }
+        }
+        for l in &self.topology.links{
+            println!("link {} {} {}", l.name, l.endpoints.join(" <-> "), l.subnet);
+        }
+    }
+
+    /// Names of the interfaces of the namespaces on links and bridges.
+    fn interfaces(&self) -> Vec<String> {
+        let segments = self.topology.links.iter().map(|l| (&l.name, &l.endpoints))
+            .chain(self.topology.bridges.iter().map(|b| (&b.name, &b.members)));
+        let mut interfaces = Vec::new();
+        for (segment, members) in segments{
+            interfaces.extend(members.iter().map(|m| format!("{}_{}", m, segment)));
+        }
+        interfaces.sort();
+        interfaces
+    }
+
+    /// Candidates for the last word of `line`.
+    fn completions(&self, line: &str) -> Vec<String> {
+        let mut words: Vec<&str> = line.split_whitespace().collect();
+        if line.is_empty() || line.ends_with(' ') {
+            words.push("");
+        }
+        let namespaces = || self.topology.namespaces.iter().map(|n| n.name.clone()).collect::<Vec<_>>();
+        let candidates: Vec<String> = match words.as_slice(){
+            [_] => COMMANDS.iter().map(|c| c.to_string()).collect(),
+            ["add", _] => vec!["ns".to_string()],
+            ["del", _] => vec!["ns".to_string(), "link".to_string(), "route".to_string()],
+            ["del", "ns" | "namespace", _] | ["link", _] | ["link", _, _] | ["route", _] | ["ping", _] | ["ping", _, _] | ["exec", _] => namespaces(),
+            ["del", "link", _] => self.topology.links.iter().map(|l| l.name.clone()).collect(),
+            ["del", "route", _] => self.topology.routes.iter().map(|r| r.namespace.clone()).collect(),
+            ["del", "route", ns, _] => self.topology.routes.iter().filter(|r| r.namespace == *ns).map(|r| r.dst.clone()).collect(),
+            ["route", _, _, rest @ ..] if rest.len() % 2 == 1 => vec!["via".to_string()],
+            ["route", _, _, rest @ ..] if rest.len() % 2 == 0 => {
+                let mut names = namespaces();
+                names.extend(self.interfaces());
+                names
+            },
+            _ => Vec::new(),
+        };
+        let last = words.last().copied().unwrap_or_default();
+        let mut candidates: Vec<String> = candidates.into_iter().filter(|c| c.starts_with(last)).collect();
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+}
+
+/// Removes link `name` and the routes over it.
+fn remove_link(topology: &mut Topology, name: &str){
+    let Some(link) = topology.links.iter().find(|l| l.name == name).cloned() else {
+        return;
+    };
+    let ends: Vec<String> = link.endpoints.iter().map(|e| format!("{}_{}", e, name)).collect();
+    topology.routes.retain(|r| {
+        !r.gateways.iter().any(|g| ends.contains(g))
+            && !r.nexthops.iter().any(|n| n.via.as_ref().is_some_and(|v| ends.contains(v)) || n.dev.as_ref().is_some_and(|d| ends.contains(d)))
+    });
+    topology.links.retain(|l| l.name != name);
+}
+
+/// Line editing on a terminal in non-canonical mode.
+#[derive(Default)]
+struct LineEditor{
+    history: Vec<String>,
+}
+
+impl LineEditor{
+    /// Reads a line, None at the end of input. `complete` gives the
+    /// candidates for the last word of the line before the cursor.
+    fn read<F>(&mut self, prompt: &str, complete: F) -> Result<Option<String>>
+    where
+        F: Fn(&str) -> Vec<String>,
+    {
+        let _raw = RawMode::enter()?;
+        let mut out = std::io::stdout();
+        let mut line: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        let mut entry = self.history.len();
+        let redraw = |out: &mut std::io::Stdout, line: &[char], cursor: usize| -> std::io::Result<()> {
+            let text: String = line.iter().collect();
+            write!(out, "\r\x1b[K{}{}", prompt, text)?;
+            if cursor < line.len() {
+                write!(out, "\x1b[{}D", line.len() - cursor)?;
+            }
+            out.flush()
+        };
+        redraw(&mut out, &line, cursor)?;
+        let mut stdin = std::io::stdin();
+        let mut byte = [0u8; 1];
+        loop{
+            if stdin.read(&mut byte)? == 0 {
+                writeln!(out, "\r")?;
+                return Ok(None);
+            }
+            match byte[0]{
+                b'\r' | b'\n' => {
+                    write!(out, "\r\n")?;
+                    let text: String = line.iter().collect();
+                    if !text.trim().is_empty() && self.history.last() != Some(&text) {
+                        self.history.push(text.clone());
+                    }
+                    return Ok(Some(text));
+                },
+                // ctrl-d on an empty line
+                4 if line.is_empty() => {
+                    write!(out, "\r\n")?;
+                    return Ok(None);
+                },
+                // ctrl-c drops the line
+                3 => {
+                    write!(out, "^C\r\n")?;
+                    line.clear();
+                    cursor = 0;
+                },
+                // ctrl-u
+                21 => {
+                    line.drain(..cursor);
+                    cursor = 0;
+                },
+                127 | 8 if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                },
+                b'\t' => {
+                    let before: String = line[..cursor].iter().collect();
+                    let candidates = complete(&before);
+                    let word = before.rsplit(' ').next().unwrap_or_default().chars().count();
+                    match candidates.as_slice(){
+                        [] => {},
+                        [only] => {
+                            let rest: Vec<char> = only.chars().skip(word).chain(std::iter::once(' ')).collect();
+                            let n = rest.len();
+                            line.splice(cursor..cursor, rest);
+                            cursor += n;
+                        },
+                        _ => {
+                            let common = common_prefix(&candidates);
+                            if common.chars().count() > word {
+                                let rest: Vec<char> = common.chars().skip(word).collect();
+                                let n = rest.len();
+                                line.splice(cursor..cursor, rest);
+                                cursor += n;
+                            } else {
+                                write!(out, "\r\n{}\r\n", candidates.join("  "))?;
+                            }
+                        },
+                    }
+                },
+                0x1b => {
+                    let mut seq = [0u8; 2];
+                    stdin.read_exact(&mut seq)?;
+                    match seq{
+                        [b'[', b'A'] if entry > 0 => {
+                            entry -= 1;
+                            line = self.history[entry].chars().collect();
+                            cursor = line.len();
+                        },
+                        [b'[', b'B'] if entry < self.history.len() => {
+                            entry += 1;
+                            line = self.history.get(entry).map(|h| h.chars().collect()).unwrap_or_default();
+                            cursor = line.len();
+                        },
+                        [b'[', b'C'] if cursor < line.len() => cursor += 1,
+                        [b'[', b'D'] if cursor > 0 => cursor -= 1,
+                        _ => {},
+                    }
+                },
+                c if (0x20..0x7f).contains(&c) => {
+                    line.insert(cursor, c as char);
+                    cursor += 1;
+                },
+                _ => {},
+            }
+            redraw(&mut out, &line, cursor)?;
+        }
+    }
+}
+
+fn common_prefix(words: &[String]) -> String {
+    let mut prefix = words[0].clone();
+    for w in &words[1..]{
+        while !w.starts_with(&prefix){
+            prefix.pop();
+        }
+    }
+    prefix
+}
+
+/// Terminal settings of stdin without line buffering, echo and signal
+/// keys, restored when dropped.
+struct RawMode{
+    saved: libc::termios,
+}
+
+impl RawMode{
+    fn enter() -> Result<RawMode>{
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(0, &mut saved) } != 0 {
+            return Err(failed!("Failed to read the terminal settings: {}", std::io::Error::last_os_error()));
+        }
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+        raw.c_iflag &= !libc::IXON;
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(0, libc::TCSAFLUSH, &raw) } != 0 {
+            return Err(failed!("Failed to set the terminal settings: {}", std::io::Error::last_os_error()));
+        }
+        Ok(RawMode{ saved })
+    }
+}
+
+impl Drop for RawMode{
+    fn drop(&mut self){
+        unsafe { libc::tcsetattr(0, libc::TCSAFLUSH, &self.saved) };
+    }
+}
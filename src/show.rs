@@ -0,0 +1,170 @@
+//! What the kernel holds for a running topology, as `show` prints it: per
+//! namespace the interfaces with their state, MTU, MAC and addresses, and
+//! the routes of every table but the local one, including the connected
+//! routes of the addresses. Read live rather than from the saved state, so
+//! changes made by hand or by routing daemons show up.
+//!
+//! The view serializes to JSON for scripts and displays as tables.
+
+use std::fmt;
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{failed, Result, RouterError};
+use crate::snapshot::{self, RouteSnapshot};
+use crate::state;
+use crate::trace::Traced;
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct TopologyView{
+    pub name: String,
+    pub namespaces: Vec<NamespaceView>,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct NamespaceView{
+    /// name in the topology
+    pub name: String,
+    pub netns: String,
+    pub interfaces: Vec<InterfaceView>,
+    pub routes: Vec<RouteSnapshot>,
+}
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct InterfaceView{
+    pub name: String,
+    /// operational state, UP, DOWN, LOWERLAYERDOWN, UNKNOWN, ...
+    pub state: String,
+    pub mtu: Option<u32>,
+    pub mac: Option<String>,
+    /// address/prefix length, link-local ones left out
+    pub addresses: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct AddrJson{
+    ifname: String,
+    #[serde(default)]
+    operstate: Option<String>,
+    #[serde(default)]
+    mtu: Option<u32>,
+    #[serde(default)]
+    address: Option<String>,
+    #[serde(default)]
+    addr_info: Vec<AddrInfoJson>,
+}
+
+#[derive(Deserialize)]
+struct AddrInfoJson{
+    local: String,
+    prefixlen: u8,
+    #[serde(default)]
+    scope: Option<String>,
+}
+
+impl TopologyView{
+    /// Reads the namespaces of running topology `name`.
+    pub fn read(name: &str) -> Result<TopologyView>{
+        let namespaces = state::namespaces(name)?;
+        if namespaces.is_empty() {
+            return Err(RouterError::TopologyNotFound(name.to_string()));
+        }
+        let prefix = format!("{}-", name);
+        let mut view = TopologyView{ name: name.to_string(), namespaces: Vec::new() };
+        for netns in namespaces{
+            let ns = netns.strip_prefix(prefix.as_str()).unwrap_or(&netns);
+            view.namespaces.push(NamespaceView::read(ns, &netns)?);
+        }
+        Ok(view)
+    }
+}
+
+impl NamespaceView{
+    /// Reads namespace `netns`, known as `name` in its topology.
+    pub fn read(name: &str, netns: &str) -> Result<NamespaceView>{
+        let output = Command::new("ip").args(["-n", netns, "-j", "addr", "show"]).traced_output()?;
+        if !output.status.success() {
+            return Err(failed!("Failed to run ip addr show in {}: {}", netns, String::from_utf8_lossy(&output.stderr)));
+        }
+        let addrs: Vec<AddrJson> = serde_json::from_slice(&output.stdout)
+            .map_err(|e| failed!("Failed to parse ip addr output of {}: {}", netns, e))?;
+        let interfaces = addrs.into_iter().map(|a| InterfaceView{
+            name: a.ifname,
+            state: a.operstate.unwrap_or_default(),
+            mtu: a.mtu,
+            mac: a.address.filter(|m| m != "00:00:00:00:00:00"),
+            addresses: a.addr_info.into_iter()
+                .filter(|i| i.scope.as_deref() != Some("link"))
+                .map(|i| format!("{}/{}", i.local, i.prefixlen))
+                .collect(),
+        }).collect();
+        Ok(NamespaceView{
+            name: name.to_string(),
+            netns: netns.to_string(),
+            interfaces,
+            routes: snapshot::routes(netns, true)?,
+        })
+    }
+}
+
+/// Nexthops of `r` as `ip route` writes them, `via <gw> dev <dev>` joined
+/// by commas for multipath routes.
+fn nexthops(r: &RouteSnapshot) -> String {
+    let hop = |gateway: &Option<String>, dev: &Option<String>, weight: Option<u32>| {
+        let mut s = Vec::new();
+        if let Some(gateway) = gateway{
+            s.push(format!("via {}", gateway));
+        }
+        if let Some(dev) = dev{
+            s.push(format!("dev {}", dev));
+        }
+        if let Some(weight) = weight{
+            s.push(format!("weight {}", weight));
+        }
+        s.join(" ")
+    };
+    if r.nexthops.is_empty() {
+        return hop(&r.gateway, &r.dev, None);
+    }
+    r.nexthops.iter().map(|n| hop(&n.gateway, &n.dev, n.weight)).collect::<Vec<_>>().join(", ")
+}
+
+impl fmt::Display for NamespaceView{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} ({})", self.name, self.netns)?;
+        writeln!(f, "  {:<16} {:<15} {:<6} {:<18} ADDRESSES", "INTERFACE", "STATE", "MTU", "MAC")?;
+        for i in &self.interfaces{
+            let mtu = i.mtu.map(|m| m.to_string()).unwrap_or_default();
+            writeln!(f, "  {:<16} {:<15} {:<6} {:<18} {}", i.name, i.state, mtu, i.mac.as_deref().unwrap_or("-"), i.addresses.join(" "))?;
+        }
+        write!(f, "  {:<24} {:<8} {:<8} {:<7} NEXTHOPS", "ROUTE", "TABLE", "PROTO", "METRIC")?;
+        for r in &self.routes{
+            let dst = match &r.kind{
+                Some(kind) => format!("{} {}", kind, r.dst),
+                None => r.dst.clone(),
+            };
+            let metric = r.metric.map(|m| m.to_string()).unwrap_or_default();
+            write!(f, "\n  {:<24} {:<8} {:<8} {:<7} {}",
+                dst,
+                r.table.as_deref().unwrap_or("main"),
+                r.protocol.as_deref().unwrap_or("boot"),
+                metric,
+                nexthops(r),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TopologyView{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (n, ns) in self.namespaces.iter().enumerate(){
+            if n > 0 {
+                write!(f, "\n\n")?;
+            }
+            write!(f, "{}", ns)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,377 @@
+//! Snapshot of the kernel state of a running topology in a single JSON
+//! file and its restore, so a lab changed by hand after create comes back
+//! as it was, later or on another machine. A snapshot holds
+//!
+//! - the description with every allocated subnet and loopback address
+//!   written in, see `backup::pin`, and the saved state
+//! - per namespace the interfaces with their kind, mtu and link state, the
+//!   addresses, the routes of every table and the qdiscs as the kernel
+//!   reports them
+//!
+//! A restore builds the pinned description, then brings the namespaces to
+//! the snapshot: missing dummy interfaces are added, mtu and link state set,
+//! missing addresses added and the qdiscs and static routes replaced.
+//! Routes of routing daemons are left to the daemons. Whatever cannot be
+//! recreated is returned as a difference.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::backup::{self, Assignments};
+use crate::cmd;
+use crate::error::{failed, Result, RouterError};
+use crate::state::{self, State};
+use crate::topology::Topology;
+use crate::Config;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Snapshot{
+    pub topology: Topology,
+    pub state: State,
+    pub namespaces: Vec<NamespaceSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct NamespaceSnapshot{
+    pub name: String,
+    pub netns: String,
+    pub interfaces: Vec<InterfaceSnapshot>,
+    pub addresses: Vec<AddressSnapshot>,
+    pub routes: Vec<RouteSnapshot>,
+    pub qdiscs: Vec<QdiscSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct InterfaceSnapshot{
+    pub name: String,
+    /// veth, bridge, dummy, ..., None for lo and plain devices
+    pub kind: Option<String>,
+    pub mtu: Option<u32>,
+    pub up: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct AddressSnapshot{
+    pub interface: String,
+    /// address/prefix length
+    pub address: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RouteSnapshot{
+    pub ipv6: bool,
+    pub dst: String,
+    /// blackhole, unreachable or prohibit, unicast if None
+    #[serde(default)]
+    pub kind: Option<String>,
+    pub gateway: Option<String>,
+    pub dev: Option<String>,
+    pub nexthops: Vec<NexthopSnapshot>,
+    /// main if None
+    pub table: Option<String>,
+    pub metric: Option<u32>,
+    /// boot if None
+    pub protocol: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct NexthopSnapshot{
+    pub gateway: Option<String>,
+    pub dev: Option<String>,
+    pub weight: Option<u32>,
+}
+
+/// Qdisc as printed by `tc qdisc show`, `options` are passed back to tc as
+/// they are.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct QdiscSnapshot{
+    pub interface: String,
+    pub kind: String,
+    pub handle: String,
+    /// root if None
+    pub parent: Option<String>,
+    pub options: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct LinkJson{
+    ifname: String,
+    #[serde(default)]
+    mtu: Option<u32>,
+    #[serde(default)]
+    flags: Vec<String>,
+    #[serde(default)]
+    linkinfo: Option<LinkInfoJson>,
+}
+
+#[derive(Deserialize)]
+struct LinkInfoJson{
+    #[serde(default)]
+    info_kind: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AddrJson{
+    ifname: String,
+    #[serde(default)]
+    addr_info: Vec<AddrInfoJson>,
+}
+
+#[derive(Deserialize)]
+struct AddrInfoJson{
+    local: String,
+    prefixlen: u8,
+    #[serde(default)]
+    scope: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RouteJson{
+    dst: String,
+    #[serde(default, rename = "type")]
+    kind: Option<String>,
+    #[serde(default)]
+    gateway: Option<String>,
+    #[serde(default)]
+    dev: Option<String>,
+    #[serde(default)]
+    nexthops: Vec<NexthopSnapshot>,
+    #[serde(default)]
+    table: Option<String>,
+    #[serde(default)]
+    metric: Option<u32>,
+    #[serde(default)]
+    protocol: Option<String>,
+}
+
+impl NamespaceSnapshot{
+    /// Reads the kernel state of namespace `netns`, known as `name` in its
+    /// topology.
+    pub fn read(name: &str, netns: &str) -> Result<NamespaceSnapshot>{
+        let links: Vec<LinkJson> = serde_json::from_str(&cmd::ip(Some(netns), &["-d", "-j", "link", "show"])?)
+            .map_err(|e| failed!("Failed to parse ip link output of {}: {}", netns, e))?;
+        let interfaces = links.into_iter().map(|l| InterfaceSnapshot{
+            name: l.ifname,
+            kind: l.linkinfo.and_then(|k| k.info_kind),
+            mtu: l.mtu,
+            up: l.flags.iter().any(|f| f == "UP"),
+        }).collect();
+        let addrs: Vec<AddrJson> = serde_json::from_str(&cmd::ip(Some(netns), &["-j", "addr", "show"])?)
+            .map_err(|e| failed!("Failed to parse ip addr output of {}: {}", netns, e))?;
+        let addresses = addrs.into_iter()
+            .flat_map(|a| {
+                let interface = a.ifname;
+                a.addr_info.into_iter()
+                    .filter(|i| i.scope.as_deref() != Some("link") && i.scope.as_deref() != Some("host"))
+                    .map(move |i| AddressSnapshot{ interface: interface.clone(), address: format!("{}/{}", i.local, i.prefixlen) })
+            })
+            .collect();
+        let routes = routes(netns, false)?;
+        let qdiscs = parse_qdiscs(&cmd::tc(Some(netns), &["qdisc", "show"])?);
+        Ok(NamespaceSnapshot{ name: name.to_string(), netns: netns.to_string(), interfaces, addresses, routes, qdiscs })
+    }
+}
+
+/// Routes of every table of namespace `netns` but the local one, IPv4
+/// first, with the routes the kernel adds for addresses if `connected`.
+pub fn routes(netns: &str, connected: bool) -> Result<Vec<RouteSnapshot>>{
+    let mut routes = Vec::new();
+    for (family, ipv6) in [("-4", false), ("-6", true)]{
+        let parsed: Vec<RouteJson> = serde_json::from_str(&cmd::ip(Some(netns), &[family, "-j", "route", "show", "table", "all"])?)
+            .map_err(|e| failed!("Failed to parse ip route output of {}: {}", netns, e))?;
+        routes.extend(parsed.into_iter()
+            .filter(|r| r.table.as_deref() != Some("local") && (connected || r.protocol.as_deref() != Some("kernel")))
+            .filter(|r| !r.dst.starts_with("fe80:") && !r.dst.starts_with("ff00:"))
+            .filter(|r| !matches!(r.kind.as_deref(), Some("local" | "broadcast" | "multicast" | "anycast")))
+            .map(|r| RouteSnapshot{
+                ipv6,
+                dst: r.dst,
+                kind: r.kind.filter(|k| k != "unicast"),
+                gateway: r.gateway,
+                dev: r.dev,
+                nexthops: r.nexthops,
+                table: r.table.filter(|t| t != "main"),
+                metric: r.metric,
+                protocol: r.protocol,
+            }));
+    }
+    Ok(routes)
+}
+
+impl RouteSnapshot{
+    /// Routes a restore recreates: those added by hand or by router-rs,
+    /// not the ones of routing daemons or autoconfiguration.
+    fn is_static(&self) -> bool {
+        matches!(self.protocol.as_deref(), None | Some("boot") | Some("static"))
+    }
+
+    /// `ip` arguments installing the route.
+    fn args(&self) -> Vec<String>{
+        let mut args: Vec<String> = vec![if self.ipv6 { "-6" } else { "-4" }.to_string(), "route".to_string(), "replace".to_string()];
+        args.extend(self.kind.clone());
+        args.push(self.dst.clone());
+        if self.nexthops.is_empty() {
+            if let Some(gateway) = &self.gateway{
+                args.extend(["via".to_string(), gateway.clone()]);
+            }
+            if let Some(dev) = &self.dev{
+                args.extend(["dev".to_string(), dev.clone()]);
+            }
+        }
+        for n in &self.nexthops{
+            args.push("nexthop".to_string());
+            if let Some(gateway) = &n.gateway{
+                args.extend(["via".to_string(), gateway.clone()]);
+            }
+            if let Some(dev) = &n.dev{
+                args.extend(["dev".to_string(), dev.clone()]);
+            }
+            if let Some(weight) = n.weight{
+                args.extend(["weight".to_string(), weight.to_string()]);
+            }
+        }
+        if let Some(table) = &self.table{
+            args.extend(["table".to_string(), table.clone()]);
+        }
+        if let Some(metric) = self.metric{
+            args.extend(["metric".to_string(), metric.to_string()]);
+        }
+        if let Some(protocol) = &self.protocol{
+            args.extend(["proto".to_string(), protocol.clone()]);
+        }
+        args
+    }
+}
+
+impl QdiscSnapshot{
+    /// `tc` arguments installing the qdisc.
+    fn args(&self) -> Vec<String>{
+        let mut args: Vec<String> = ["qdisc", "replace", "dev", &self.interface].iter().map(|a| a.to_string()).collect();
+        if matches!(self.kind.as_str(), "ingress" | "clsact") {
+            args.push(self.kind.clone());
+            return args;
+        }
+        match &self.parent{
+            Some(parent) => args.extend(["parent".to_string(), parent.clone()]),
+            None => args.push("root".to_string()),
+        }
+        args.extend(["handle".to_string(), self.handle.clone(), self.kind.clone()]);
+        args.extend(self.options.iter().cloned());
+        args
+    }
+}
+
+/// Parses the output of `tc qdisc show`, leaving out the default qdiscs
+/// the kernel attaches by itself.
+pub fn parse_qdiscs(output: &str) -> Vec<QdiscSnapshot>{
+    let mut qdiscs = Vec::new();
+    for line in output.lines(){
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let [ "qdisc", kind, handle, "dev", interface, rest @ .. ] = tokens.as_slice() else {
+            continue;
+        };
+        if *handle == "0:" || matches!(*kind, "noqueue" | "noop") {
+            continue;
+        }
+        let (parent, rest) = match rest{
+            ["root", rest @ ..] => (None, rest),
+            ["parent", parent, rest @ ..] => (Some(parent.to_string()), rest),
+            _ => continue,
+        };
+        let rest = match rest{
+            ["refcnt", _, rest @ ..] => rest,
+            rest => rest,
+        };
+        qdiscs.push(QdiscSnapshot{
+            interface: interface.to_string(),
+            kind: kind.to_string(),
+            handle: handle.to_string(),
+            parent,
+            options: rest.iter().filter(|o| !o.starts_with("---")).map(|o| o.to_string()).collect(),
+        });
+    }
+    qdiscs
+}
+
+/// Writes the snapshot of running topology `topology` to `file`.
+pub fn snapshot(topology: &Topology, file: &Path) -> Result<()>{
+    let Some(state) = State::load(&topology.name)? else {
+        return Err(RouterError::TopologyNotFound(topology.name.to_string()));
+    };
+    let mut namespaces = Vec::new();
+    for ns in &state.namespaces{
+        namespaces.push(NamespaceSnapshot::read(&ns.name, &ns.netns)?);
+    }
+    let snapshot = Snapshot{
+        topology: backup::pin(topology, &Assignments::from_state(&state)),
+        state,
+        namespaces,
+    };
+    std::fs::write(file, serde_json::to_string_pretty(&snapshot)?)
+        .map_err(|e| failed!("Failed to write {}: {}", file.display(), e))
+}
+
+/// Reads the snapshot in `file`.
+pub fn load(file: &Path) -> Result<Snapshot>{
+    let data = std::fs::read_to_string(file)
+        .map_err(|e| failed!("Failed to read {}: {}", file.display(), e))?;
+    serde_json::from_str(&data).map_err(|e| failed!("Failed to parse snapshot {}: {}", file.display(), e))
+}
+
+/// Whether `file` is a snapshot rather than a backup archive.
+pub fn is_snapshot(file: &Path) -> bool {
+    std::fs::read(file).is_ok_and(|data| data.trim_ascii_start().first() == Some(&b'{'))
+}
+
+/// Recreates the topology of `snapshot` with `config`, named as the
+/// snapshot's. Fails if a topology of that name exists. Returns what could
+/// not be brought to the snapshot.
+pub fn restore(snapshot: &Snapshot, config: Config) -> Result<Vec<String>>{
+    let topology = &snapshot.topology;
+    if config.name != topology.name {
+        return Err(failed!("Snapshot holds topology {}, not {}", topology.name, config.name));
+    }
+    if !state::namespaces(&topology.name)?.is_empty() {
+        return Err(failed!("Topology {} exists, destroy it first", topology.name));
+    }
+    topology.apply_with(config)?;
+    let mut differences = Vec::new();
+    for ns in &snapshot.namespaces{
+        let current = NamespaceSnapshot::read(&ns.name, &ns.netns)?;
+        let mut run = |what: String, result: Result<String, RouterError>|{
+            if let Err(e) = result {
+                differences.push(format!("{} of {}: {}", what, ns.name, e));
+            }
+        };
+        for i in &ns.interfaces{
+            let found = current.interfaces.iter().find(|c| c.name == i.name);
+            if found.is_none() {
+                if i.kind.as_deref() != Some("dummy") {
+                    run(format!("interface {}", i.name), Err(RouterError::NotFound{ kind: "interface", name: i.name.clone() }));
+                    continue;
+                }
+                run(format!("interface {}", i.name), cmd::ip(Some(&ns.netns), &["link", "add", &i.name, "type", "dummy"]));
+            }
+            if let Some(mtu) = i.mtu.filter(|m| found.is_none_or(|c| c.mtu != Some(*m))) {
+                run(format!("mtu of {}", i.name), cmd::ip(Some(&ns.netns), &["link", "set", "dev", &i.name, "mtu", &mtu.to_string()]));
+            }
+            if found.is_none_or(|c| c.up != i.up) {
+                let state = if i.up { "up" } else { "down" };
+                run(format!("link state of {}", i.name), cmd::ip(Some(&ns.netns), &["link", "set", "dev", &i.name, state]));
+            }
+        }
+        for a in ns.addresses.iter().filter(|a| !current.addresses.contains(a)){
+            run(format!("address {} on {}", a.address, a.interface), cmd::ip(Some(&ns.netns), &["addr", "add", &a.address, "dev", &a.interface]));
+        }
+        for q in ns.qdiscs.iter().filter(|q| !current.qdiscs.contains(q)){
+            let args = q.args();
+            run(format!("{} qdisc on {}", q.kind, q.interface), cmd::tc(Some(&ns.netns), &args.iter().map(|a| a.as_str()).collect::<Vec<_>>()));
+        }
+        for r in ns.routes.iter().filter(|r| r.is_static() && !current.routes.contains(r)){
+            let args = r.args();
+            run(format!("route {}", r.dst), cmd::ip(Some(&ns.netns), &args.iter().map(|a| a.as_str()).collect::<Vec<_>>()));
+        }
+    }
+    Ok(differences)
+}
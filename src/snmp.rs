@@ -0,0 +1,458 @@
+//! Tiny read-only SNMP agent for the namespaces of a topology, so network
+//! management tools can poll the simulated routers. Every namespace gets
+//! its own agent on UDP port 161, answering SNMPv1 and SNMPv2c for one
+//! community with a view built from the live kernel state:
+//!
+//! - system: sysDescr, sysUpTime and sysName
+//! - interfaces: ifNumber and the ifTable with status and counters, 32 bit
+//!   counters wrap as they do on real devices
+//! - ip: the RFC 1213 ipRouteTable, IPv4 only and one route per
+//!   destination, as the table is indexed by destination alone
+//!
+//! The view is rebuilt at most once a second, so a walk sees one snapshot.
+//! Set requests are refused, as is anything with another community.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::ops::Bound;
+use std::time::{Duration, Instant};
+
+use crate::cmd;
+use crate::error::{failed, Result};
+use crate::netns;
+use crate::state::State;
+use crate::stats::InterfaceStats;
+
+pub const PORT: u16 = 161;
+/// Age at which a view is rebuilt.
+const CACHE: Duration = Duration::from_secs(1);
+/// Bulk responses stop growing at this size, below the UDP limit.
+const MAX_RESPONSE: usize = 60000;
+
+const VERSION_1: i64 = 0;
+const VERSION_2C: i64 = 1;
+
+const GET: u8 = 0xa0;
+const GET_NEXT: u8 = 0xa1;
+const RESPONSE: u8 = 0xa2;
+const SET: u8 = 0xa3;
+const GET_BULK: u8 = 0xa5;
+
+const NO_SUCH_NAME: i64 = 2;
+const NOT_WRITABLE: i64 = 17;
+
+const SYSTEM: [u32; 7] = [1, 3, 6, 1, 2, 1, 1];
+const IF_NUMBER: [u32; 8] = [1, 3, 6, 1, 2, 1, 2, 1];
+const IF_ENTRY: [u32; 9] = [1, 3, 6, 1, 2, 1, 2, 2, 1];
+const IP_ROUTE_ENTRY: [u32; 9] = [1, 3, 6, 1, 2, 1, 4, 21, 1];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value{
+    Integer(i64),
+    OctetString(Vec<u8>),
+    IpAddress(Ipv4Addr),
+    Counter32(u32),
+    Gauge32(u32),
+    TimeTicks(u32),
+}
+
+/// Object instances of an agent by OID, in OID order.
+pub type Mib = BTreeMap<Vec<u32>, Value>;
+
+/// Varbind value of a response, a value or one of the v2c exceptions.
+enum Answer{
+    Value(Value),
+    Null,
+    NoSuchObject,
+    NoSuchInstance,
+    EndOfMibView,
+}
+
+pub struct SnmpAgent{
+    pub topology: String,
+    pub community: String,
+    /// namespaces to serve, all if empty
+    pub namespaces: Vec<String>,
+}
+
+impl SnmpAgent{
+    /// Serves until the topology is destroyed.
+    pub fn run(&self) -> Result<()>{
+        let start = Instant::now();
+        let mut serving = BTreeSet::new();
+        loop{
+            let Some(state) = State::load(&self.topology)? else {
+                return Ok(());
+            };
+            for ns in &state.namespaces{
+                if serving.contains(&ns.netns) || !(self.namespaces.is_empty() || self.namespaces.contains(&ns.name)) {
+                    continue;
+                }
+                let listen = SocketAddr::from(([0u16; 8], PORT));
+                let socket = netns::run_in(&ns.netns, || UdpSocket::bind(listen)
+                    .map_err(|e| failed!("Failed to listen on {} in {}: {}", listen, ns.netns, e)))?;
+                serving.insert(ns.netns.clone());
+                let (ns, community) = (ns.clone(), self.community.clone());
+                std::thread::spawn(move || serve(socket, &ns.netns, &ns.name, &community, start));
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+fn serve(socket: UdpSocket, netns: &str, name: &str, community: &str, start: Instant){
+    let mut buf = [0u8; 65535];
+    let mut view: Option<(Instant, Mib)> = None;
+    loop{
+        let Ok((len, peer)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        if view.as_ref().is_none_or(|(at, _)| at.elapsed() >= CACHE) {
+            match mib(netns, name, start.elapsed()){
+                Ok(mib) => view = Some((Instant::now(), mib)),
+                // the namespace is gone
+                Err(_) => return,
+            }
+        }
+        let Some((_, mib)) = &view else {
+            continue;
+        };
+        if let Some(response) = respond(&buf[..len], community, mib){
+            let _ = socket.send_to(&response, peer);
+        }
+    }
+}
+
+/// View of `netns`, named `name`, for an agent running for `uptime`.
+pub fn mib(netns: &str, name: &str, uptime: Duration) -> Result<Mib>{
+    let mut mib = Mib::new();
+    let oid = |base: &[u32], rest: &[u32]| -> Vec<u32> { base.iter().chain(rest).copied().collect() };
+    mib.insert(oid(&SYSTEM, &[1, 0]), Value::OctetString(format!("router-rs namespace {}", netns).into_bytes()));
+    mib.insert(oid(&SYSTEM, &[3, 0]), Value::TimeTicks((uptime.as_millis() / 10) as u32));
+    mib.insert(oid(&SYSTEM, &[5, 0]), Value::OctetString(name.as_bytes().to_vec()));
+
+    let links = cmd::ip_json(Some(netns), &["-s", "-j", "link", "show"])?;
+    let links = links.as_array().cloned().unwrap_or_default();
+    let mut indexes = BTreeMap::new();
+    mib.insert(IF_NUMBER.to_vec(), Value::Integer(links.len() as i64));
+    for l in &links{
+        let (Some(index), Some(ifname)) = (l["ifindex"].as_u64(), l["ifname"].as_str()) else {
+            continue;
+        };
+        let index = index as u32;
+        indexes.insert(ifname.to_string(), index);
+        let up = l["flags"].as_array().is_some_and(|f| f.iter().any(|f| f == "UP"));
+        let oper = match l["operstate"].as_str(){
+            Some("UP") => 1,
+            Some("DOWN") => 2,
+            Some("DORMANT") => 5,
+            Some("NOTPRESENT") => 6,
+            Some("LOWERLAYERDOWN") => 7,
+            // the loopback reports UNKNOWN
+            _ if l["link_type"] == "loopback" && up => 1,
+            _ => 4,
+        };
+        let mac: Vec<u8> = l["address"].as_str().unwrap_or_default().split(':')
+            .filter_map(|b| u8::from_str_radix(b, 16).ok())
+            .collect();
+        let stats = InterfaceStats::from_json(l);
+        let counter = |v: u64| Value::Counter32(v as u32);
+        for (column, value) in [
+            (1, Value::Integer(index as i64)),
+            (2, Value::OctetString(ifname.as_bytes().to_vec())),
+            (3, Value::Integer(if l["link_type"] == "loopback" { 24 } else { 6 })),
+            (4, Value::Integer(l["mtu"].as_i64().unwrap_or_default())),
+            (5, Value::Gauge32(0)),
+            (6, Value::OctetString(if mac.iter().all(|b| *b == 0) { Vec::new() } else { mac })),
+            (7, Value::Integer(if up { 1 } else { 2 })),
+            (8, Value::Integer(oper)),
+            (10, counter(stats.rx_bytes)),
+            (11, counter(stats.rx_packets)),
+            (13, counter(stats.rx_dropped)),
+            (14, counter(stats.rx_errors)),
+            (16, counter(stats.tx_bytes)),
+            (17, counter(stats.tx_packets)),
+            (19, counter(stats.tx_dropped)),
+            (20, counter(stats.tx_errors)),
+        ]{
+            mib.insert(oid(&IF_ENTRY, &[column, index]), value);
+        }
+    }
+
+    let routes = cmd::ip_json(Some(netns), &["-4", "-j", "route", "show"])?;
+    for r in routes.as_array().cloned().unwrap_or_default(){
+        let dst = match r["dst"].as_str(){
+            Some("default") => "0.0.0.0/0".to_string(),
+            Some(dst) if dst.contains('/') => dst.to_string(),
+            Some(dst) => format!("{}/32", dst),
+            None => continue,
+        };
+        let Ok(dst) = dst.parse::<ipnet::Ipv4Net>() else {
+            continue;
+        };
+        let first = r["nexthops"].as_array().and_then(|n| n.first()).unwrap_or(&r);
+        let gateway: Option<Ipv4Addr> = first["gateway"].as_str().and_then(|g| g.parse().ok());
+        let dev = first["dev"].as_str().and_then(|d| indexes.get(d)).copied().unwrap_or(0);
+        let proto = match r["protocol"].as_str(){
+            Some("kernel") => 2,
+            Some("ospf") => 13,
+            Some("bgp") => 14,
+            _ => 3,
+        };
+        let [a, b, c, d] = dst.addr().octets().map(|o| o as u32);
+        // one route per destination, the first has the lowest metric
+        if mib.contains_key(&oid(&IP_ROUTE_ENTRY, &[1, a, b, c, d])) {
+            continue;
+        }
+        for (column, value) in [
+            (1, Value::IpAddress(dst.addr())),
+            (2, Value::Integer(dev as i64)),
+            (3, Value::Integer(r["metric"].as_i64().unwrap_or_default())),
+            (7, Value::IpAddress(gateway.unwrap_or(Ipv4Addr::UNSPECIFIED))),
+            (8, Value::Integer(if gateway.is_some() { 4 } else { 3 })),
+            (9, Value::Integer(proto)),
+            (11, Value::IpAddress(dst.netmask())),
+        ]{
+            mib.insert(oid(&IP_ROUTE_ENTRY, &[column, a, b, c, d]), value);
+        }
+    }
+    Ok(mib)
+}
+
+/// Response to the request in `packet`, None if it gets none.
+pub fn respond(packet: &[u8], community: &str, mib: &Mib) -> Option<Vec<u8>> {
+    let (0x30, message, _) = tlv(packet)? else {
+        return None;
+    };
+    let (0x02, version, rest) = tlv(message)? else {
+        return None;
+    };
+    let version = integer(version);
+    if version != VERSION_1 && version != VERSION_2C {
+        return None;
+    }
+    let (0x04, name, rest) = tlv(rest)? else {
+        return None;
+    };
+    if name != community.as_bytes() {
+        return None;
+    }
+    let (pdu_type, pdu, _) = tlv(rest)?;
+    let (0x02, id, rest) = tlv(pdu)? else {
+        return None;
+    };
+    let (0x02, first, rest) = tlv(rest)? else {
+        return None;
+    };
+    let (0x02, second, rest) = tlv(rest)? else {
+        return None;
+    };
+    let (0x30, mut list, _) = tlv(rest)? else {
+        return None;
+    };
+    let mut oids = Vec::new();
+    while !list.is_empty() {
+        let (0x30, varbind, next) = tlv(list)? else {
+            return None;
+        };
+        let (0x06, name, _) = tlv(varbind)? else {
+            return None;
+        };
+        oids.push(decode_oid(name)?);
+        list = next;
+    }
+
+    let v1 = version == VERSION_1;
+    let mut error = (0, 0);
+    let mut varbinds: Vec<(Vec<u32>, Answer)> = Vec::new();
+    match pdu_type{
+        GET => for (i, oid) in oids.iter().enumerate(){
+            match mib.get(oid){
+                Some(value) => varbinds.push((oid.clone(), Answer::Value(value.clone()))),
+                None if v1 => {
+                    error = (NO_SUCH_NAME, i as i64 + 1);
+                    break;
+                },
+                None => {
+                    // an instance is missing if its object has others
+                    let object = &oid[..oid.len().saturating_sub(1)];
+                    let known = mib.keys().any(|k| k.starts_with(object) && k.len() == oid.len());
+                    varbinds.push((oid.clone(), if known { Answer::NoSuchInstance } else { Answer::NoSuchObject }));
+                },
+            }
+        },
+        GET_NEXT => for (i, oid) in oids.iter().enumerate(){
+            match next(mib, oid){
+                Some((oid, value)) => varbinds.push((oid.clone(), Answer::Value(value.clone()))),
+                None if v1 => {
+                    error = (NO_SUCH_NAME, i as i64 + 1);
+                    break;
+                },
+                None => varbinds.push((oid.clone(), Answer::EndOfMibView)),
+            }
+        },
+        GET_BULK if !v1 => {
+            let non_repeaters = (integer(first).max(0) as usize).min(oids.len());
+            let repetitions = integer(second).max(0) as usize;
+            for oid in &oids[..non_repeaters]{
+                varbinds.push(step(mib, oid));
+            }
+            let mut cursors: Vec<Vec<u32>> = oids[non_repeaters..].to_vec();
+            let mut size = 0;
+            'bulk: for _ in 0..repetitions{
+                let mut ended = true;
+                for cursor in cursors.iter_mut(){
+                    let (oid, answer) = step(mib, cursor);
+                    ended &= matches!(answer, Answer::EndOfMibView);
+                    size += oid.len() * 2 + 16;
+                    if size > MAX_RESPONSE {
+                        break 'bulk;
+                    }
+                    *cursor = oid.clone();
+                    varbinds.push((oid, answer));
+                }
+                if ended {
+                    break;
+                }
+            }
+        },
+        SET => {
+            error = (if v1 { NO_SUCH_NAME } else { NOT_WRITABLE }, 1);
+        },
+        _ => return None,
+    }
+    // errors echo the request's varbinds
+    if error.0 != 0 {
+        varbinds = oids.into_iter().map(|oid| (oid, Answer::Null)).collect();
+    }
+
+    let mut list = Vec::new();
+    for (oid, answer) in &varbinds{
+        let value = match answer{
+            Answer::Value(value) => encode_value(value),
+            Answer::Null => encode(0x05, &[]),
+            Answer::NoSuchObject => encode(0x80, &[]),
+            Answer::NoSuchInstance => encode(0x81, &[]),
+            Answer::EndOfMibView => encode(0x82, &[]),
+        };
+        list.extend(encode(0x30, &[encode_oid(oid), value].concat()));
+    }
+    let pdu = [
+        encode(0x02, id),
+        encode_integer(error.0),
+        encode_integer(error.1),
+        encode(0x30, &list),
+    ].concat();
+    let message = [
+        encode_integer(version),
+        encode(0x04, community.as_bytes()),
+        encode(RESPONSE, &pdu),
+    ].concat();
+    Some(encode(0x30, &message))
+}
+
+fn next<'a>(mib: &'a Mib, oid: &[u32]) -> Option<(&'a Vec<u32>, &'a Value)> {
+    mib.range::<[u32], _>((Bound::Excluded(oid), Bound::Unbounded)).next()
+}
+
+/// GetNext of one variable in v2c terms.
+fn step(mib: &Mib, oid: &[u32]) -> (Vec<u32>, Answer) {
+    match next(mib, oid){
+        Some((oid, value)) => (oid.clone(), Answer::Value(value.clone())),
+        None => (oid.to_vec(), Answer::EndOfMibView),
+    }
+}
+
+/// Tag, contents and the bytes after a BER element.
+fn tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+    let (len, start) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let count = first & 0x7f;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let len = data.get(2..2 + count)?.iter().fold(0usize, |len, b| len << 8 | *b as usize);
+        (len, 2 + count)
+    };
+    let end = start.checked_add(len)?;
+    Some((tag, data.get(start..end)?, &data[end..]))
+}
+
+fn integer(data: &[u8]) -> i64 {
+    let init = if data.first().is_some_and(|b| b & 0x80 != 0) { -1 } else { 0 };
+    data.iter().take(8).fold(init, |v, b| v << 8 | *b as i64)
+}
+
+fn decode_oid(data: &[u8]) -> Option<Vec<u32>> {
+    let first = *data.first()? as u32;
+    let mut oid = vec![first / 40, first % 40];
+    let mut arc: u32 = 0;
+    for b in &data[1..]{
+        arc = arc.checked_mul(128)? | (*b & 0x7f) as u32;
+        if b & 0x80 == 0 {
+            oid.push(arc);
+            arc = 0;
+        }
+    }
+    Some(oid)
+}
+
+fn encode(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().into_iter().skip_while(|b| *b == 0).collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn encode_integer(value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    // shortest two's complement form
+    let mut start = 0;
+    while start < 7 && ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0) || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0)) {
+        start += 1;
+    }
+    encode(0x02, &bytes[start..])
+}
+
+fn encode_unsigned(tag: u8, value: u32) -> Vec<u8> {
+    let mut bytes: Vec<u8> = value.to_be_bytes().into_iter().skip_while(|b| *b == 0).collect();
+    if bytes.first().is_none_or(|b| b & 0x80 != 0) {
+        bytes.insert(0, 0);
+    }
+    encode(tag, &bytes)
+}
+
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut content = vec![(oid.first().copied().unwrap_or(0) * 40 + oid.get(1).copied().unwrap_or(0)) as u8];
+    for arc in oid.iter().skip(2){
+        let mut bytes = vec![(arc & 0x7f) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            bytes.push(0x80 | (rest & 0x7f) as u8);
+            rest >>= 7;
+        }
+        content.extend(bytes.iter().rev());
+    }
+    encode(0x06, &content)
+}
+
+fn encode_value(value: &Value) -> Vec<u8> {
+    match value{
+        Value::Integer(v) => encode_integer(*v),
+        Value::OctetString(v) => encode(0x04, v),
+        Value::IpAddress(v) => encode(0x40, &v.octets()),
+        Value::Counter32(v) => encode_unsigned(0x41, *v),
+        Value::Gauge32(v) => encode_unsigned(0x42, *v),
+        Value::TimeTicks(v) => encode_unsigned(0x43, *v),
+    }
+}
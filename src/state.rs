@@ -0,0 +1,464 @@
+//! Record of what was created for a topology, kept as JSON under
+//! `/run/router-rs/<topology>.json` so later invocations know exactly which
+//! namespaces belong to it and can tell when the kernel state has drifted.
+//! /run is a tmpfs, so like the namespaces themselves the state is gone
+//! after a reboot.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::{failed, Result};
+use crate::hooks::HookSpec;
+use crate::mirror::MirrorSpec;
+use crate::passthrough::MovedNic;
+use crate::policy::{self, PolicyRule};
+use crate::process::RestartPolicy;
+use crate::topology::RouteSpec;
+use crate::{daemon, ovs, tunnel, BridgeBackend, Config, Namespace, RouteKind};
+
+pub const STATE_DIR: &str = "/run/router-rs";
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct State{
+    pub name: String,
+    pub namespaces: Vec<NamespaceState>,
+    pub links: Vec<SegmentState>,
+    pub bridges: Vec<SegmentState>,
+    #[serde(default)]
+    pub vxlans: Vec<SegmentState>,
+    #[serde(default)]
+    pub tunnels: Vec<SegmentState>,
+    #[serde(default)]
+    pub wireguards: Vec<SegmentState>,
+    pub interfaces: Vec<InterfaceState>,
+    pub routes: Vec<RouteState>,
+    /// routes set outside the description, see `rib::set_route`
+    #[serde(default)]
+    pub set_routes: Vec<RouteSpec>,
+    #[serde(default)]
+    pub rules: Vec<RuleState>,
+    #[serde(default)]
+    pub vrfs: Vec<VrfState>,
+    #[serde(default)]
+    pub processes: Vec<ProcessState>,
+    /// scripts run on events, teardown ones by `Topology::destroy`
+    #[serde(default)]
+    pub hooks: Vec<HookSpec>,
+    /// host NICs moved into the namespaces, given back by
+    /// `Topology::destroy`
+    #[serde(default)]
+    pub nics: Vec<MovedNic>,
+    #[serde(default)]
+    pub mirrors: Vec<MirrorSpec>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct NamespaceState{
+    pub name: String,
+    pub netns: String,
+}
+
+/// Link, bridge, VXLAN link or tunnel, `netns` holds the bridge device.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SegmentState{
+    pub name: String,
+    pub subnet: String,
+    pub subnet6: Option<String>,
+    #[serde(default)]
+    pub netns: Option<String>,
+    /// bridge switched by Open vSwitch
+    #[serde(default)]
+    pub ovs: bool,
+}
+
+/// `netns` is None for interfaces left in the host namespace.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct InterfaceState{
+    pub name: String,
+    pub netns: Option<String>,
+    pub ip: Option<String>,
+    pub ip6: Option<String>,
+    pub mtu: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RouteState{
+    pub netns: String,
+    pub dst: String,
+    /// gateway addresses, nexthops without gateway are left out
+    pub via: Vec<String>,
+    /// routing table, main if None
+    #[serde(default)]
+    pub table: Option<u32>,
+    #[serde(default)]
+    pub kind: RouteKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct VrfState{
+    pub name: String,
+    pub netns: String,
+    pub table: u32,
+    pub interfaces: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RuleState{
+    pub netns: String,
+    pub rule: PolicyRule,
+}
+
+/// Supervised program, see `process`. `pid` is its supervisor's when the
+/// state was saved, the supervisor keeps it across restarts.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ProcessState{
+    pub name: String,
+    pub netns: String,
+    pub command: Vec<String>,
+    pub restart: RestartPolicy,
+    pub pid: Option<i32>,
+}
+
+/// Difference between the saved state and the kernel.
+#[derive(Clone, Debug)]
+pub struct Drift{
+    pub object: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for Drift{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: expected {}, found {}", self.object, self.expected, self.actual)
+    }
+}
+
+impl State{
+    pub fn from_config(config: &Config) -> State {
+        let mut state = State{
+            name: config.name.clone(),
+            ..Default::default()
+        };
+        for ns in config.namespaces.values(){
+            state.namespaces.push(NamespaceState{ name: ns.name.clone(), netns: ns.netns.clone() });
+        }
+        for l in config.links.values(){
+            state.links.push(SegmentState{ name: l.name.clone(), subnet: l.subnet.clone(), subnet6: l.subnet6.clone(), netns: None, ovs: false });
+        }
+        for b in config.bridges.values(){
+            state.bridges.push(SegmentState{ name: b.name.clone(), subnet: b.subnet.clone(), subnet6: b.subnet6.clone(), netns: Some(b.namespace.netns.clone()), ovs: b.backend == BridgeBackend::Ovs });
+        }
+        for v in config.vxlans.values(){
+            state.vxlans.push(SegmentState{ name: v.name.clone(), subnet: v.subnet.clone(), subnet6: v.subnet6.clone(), netns: None, ovs: false });
+        }
+        for t in config.tunnels.values(){
+            state.tunnels.push(SegmentState{ name: t.name.clone(), subnet: t.subnet.clone(), subnet6: t.subnet6.clone(), netns: None, ovs: false });
+        }
+        for w in config.wireguards.values(){
+            state.wireguards.push(SegmentState{ name: w.name.clone(), subnet: w.subnet.clone(), subnet6: w.subnet6.clone(), netns: None, ovs: false });
+        }
+        for i in config.interfaces.values(){
+            state.interfaces.push(InterfaceState{
+                name: i.name.clone(),
+                netns: i.namespace.as_ref().map(|n| n.netns.clone()),
+                ip: i.ip.clone(),
+                ip6: i.ip6.clone(),
+                mtu: i.mtu,
+            });
+        }
+        for (ns, r) in config.routes.iter(){
+            let v6 = r.dst.contains(':');
+            let via = r.gateway.iter()
+                .filter_map(|n| n.gateway(v6).ok().flatten())
+                .map(|ip| ip.to_string())
+                .collect();
+            state.routes.push(RouteState{ netns: ns.netns.clone(), dst: r.dst.clone(), via, table: r.table, kind: r.kind });
+        }
+        state.set_routes = config.set_routes.to_vec();
+        for v in config.vrfs.iter(){
+            state.vrfs.push(VrfState{ name: v.name.clone(), netns: v.namespace.netns.clone(), table: v.table, interfaces: v.interfaces.clone() });
+        }
+        for (ns, rule) in config.rules.iter(){
+            state.rules.push(RuleState{ netns: ns.netns.clone(), rule: rule.clone() });
+        }
+        for p in config.processes.iter(){
+            state.processes.push(ProcessState{
+                name: p.spec.name.clone(),
+                netns: p.netns.clone(),
+                command: p.spec.command.clone(),
+                restart: p.spec.restart,
+                pid: p.supervisor(),
+            });
+        }
+        state.hooks = config.hooks.scripts();
+        state.nics = config.nics.values().collect();
+        state.nics.sort_by(|a, b| a.name.cmp(&b.name));
+        state.mirrors = config.mirrors.iter().map(|m| m.spec.clone()).collect();
+        state.mirrors.sort_by(|a, b| a.name.cmp(&b.name));
+        state.namespaces.sort_by(|a, b| a.name.cmp(&b.name));
+        state.links.sort_by(|a, b| a.name.cmp(&b.name));
+        state.bridges.sort_by(|a, b| a.name.cmp(&b.name));
+        state.vxlans.sort_by(|a, b| a.name.cmp(&b.name));
+        state.tunnels.sort_by(|a, b| a.name.cmp(&b.name));
+        state.wireguards.sort_by(|a, b| a.name.cmp(&b.name));
+        state.interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+        state
+    }
+
+    pub fn path(name: &str) -> PathBuf {
+        PathBuf::from(STATE_DIR).join(format!("{}.json", name))
+    }
+
+    pub fn save(&self) -> Result<()>{
+        std::fs::create_dir_all(STATE_DIR)?;
+        let data = serde_json::to_string_pretty(self)?;
+        std::fs::write(State::path(&self.name), data)
+            .map_err(|e| failed!("Failed to save state of {}: {}", self.name, e))
+    }
+
+    /// Returns None if no state was saved for `name`.
+    pub fn load(name: &str) -> Result<Option<State>>{
+        let path = State::path(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = std::fs::read_to_string(&path)?;
+        let state = serde_json::from_str(&data)
+            .map_err(|e| failed!("Failed to parse state {}: {}", path.display(), e))?;
+        Ok(Some(state))
+    }
+
+    pub fn remove(name: &str) -> Result<()>{
+        let path = State::path(name);
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Names of the topologies with saved state, sorted.
+    pub fn list() -> Result<Vec<String>>{
+        let mut names = Vec::new();
+        let Ok(entries) = std::fs::read_dir(STATE_DIR) else {
+            return Ok(names);
+        };
+        for entry in entries{
+            let path = entry?.path();
+            if path.is_file() && path.extension().is_some_and(|e| e == "json") {
+                if let Some(stem) = path.file_stem(){
+                    names.push(stem.to_string_lossy().to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Compares the saved state with the kernel: namespaces, interfaces
+    /// with their addresses, mtu and link state, bridges, VRFs, routes and
+    /// policy rules. Interfaces, routes and rules the state doesn't know about are
+    /// reported too.
+    pub fn drift(&self) -> Result<Vec<Drift>>{
+        let mut drift = Vec::new();
+        let mut push = |object: String, expected: &str, actual: &str|{
+            drift.push(Drift{ object, expected: expected.to_string(), actual: actual.to_string() });
+        };
+        for ns in &self.namespaces{
+            if !PathBuf::from("/run/netns").join(&ns.netns).exists() {
+                push(format!("namespace {}", ns.netns), "present", "missing");
+                continue;
+            }
+            let links: serde_json::Value = serde_json::from_str(&cmd::ip(Some(&ns.netns), &["-j", "addr", "show"])?)?;
+            let links = links.as_array().cloned().unwrap_or_default();
+            let mut expected: BTreeSet<String> = self.interfaces.iter()
+                .filter(|i| i.netns.as_deref() == Some(ns.netns.as_str()))
+                .map(|i| i.name.clone())
+                .collect();
+            for b in self.bridges.iter().filter(|b| b.netns.as_deref() == Some(ns.netns.as_str())){
+                expected.insert(b.name.clone());
+                if b.ovs {
+                    expected.insert(ovs::DATAPATH_PORT.to_string());
+                }
+                // bridge ports are named <bridge>_<member>
+                let prefix = format!("{}_", b.name);
+                for l in &links{
+                    if let Some(name) = l["ifname"].as_str().filter(|n| n.starts_with(prefix.as_str())){
+                        expected.insert(name.to_string());
+                    }
+                }
+            }
+            for v in self.vrfs.iter().filter(|v| v.netns == ns.netns){
+                expected.insert(v.name.clone());
+                if !links.iter().any(|l| l["ifname"] == v.name.as_str()) {
+                    push(format!("vrf {} in {}", v.name, ns.netns), "present", "missing");
+                    continue;
+                }
+                for i in &v.interfaces{
+                    let master = links.iter().find(|l| l["ifname"] == i.as_str()).and_then(|l| l["master"].as_str());
+                    if master != Some(v.name.as_str()) {
+                        push(format!("interface {} in {}", i, ns.netns), &format!("in vrf {}", v.name), &master.map(|m| format!("in {}", m)).unwrap_or("unbound".to_string()));
+                    }
+                }
+            }
+            for l in &links{
+                let name = l["ifname"].as_str().unwrap_or_default();
+                if name != "lo" && !expected.contains(name) && !tunnel::FALLBACK_DEVICES.contains(&name) {
+                    push(format!("interface {} in {}", name, ns.netns), "absent", "present");
+                }
+            }
+            for i in self.interfaces.iter().filter(|i| i.netns.as_deref() == Some(ns.netns.as_str())){
+                let l = match links.iter().find(|l| l["ifname"] == i.name.as_str()){
+                    Some(l) => l,
+                    None => {
+                        push(format!("interface {} in {}", i.name, ns.netns), "present", "missing");
+                        continue;
+                    },
+                };
+                interface_drift(i, l, &mut push);
+            }
+            for b in self.bridges.iter().filter(|b| b.netns.as_deref() == Some(ns.netns.as_str())){
+                if !links.iter().any(|l| l["ifname"] == b.name.as_str()) {
+                    push(format!("bridge {} in {}", b.name, ns.netns), "present", "missing");
+                }
+            }
+
+            let mut installed = Vec::new();
+            for family in ["-4", "-6"]{
+                let routes: serde_json::Value = serde_json::from_str(&cmd::ip(Some(&ns.netns), &[family, "-j", "route", "show", "table", "all"])?)?;
+                for r in routes.as_array().cloned().unwrap_or_default(){
+                    if r["protocol"] == "kernel" || daemon::learned(&r) {
+                        continue;
+                    }
+                    let Some(table) = policy::route_table(&r) else {
+                        continue;
+                    };
+                    let via = r["nexthops"].as_array().cloned().unwrap_or_default().iter()
+                        .chain(std::iter::once(&r))
+                        .filter_map(|n| n["gateway"].as_str().or(n["via"]["host"].as_str()).map(|g| g.to_string()))
+                        .collect::<Vec<String>>();
+                    let kind: RouteKind = r["type"].as_str().unwrap_or("unicast").parse().unwrap_or_default();
+                    // routes to one destination with different metrics
+                    let dst = normalize(r["dst"].as_str().unwrap_or_default(), family == "-6");
+                    match installed.iter_mut().find(|(t, d, _, _): &&mut (Option<u32>, String, RouteKind, Vec<String>)| *t == table && *d == dst){
+                        Some((_, _, _, v)) => v.extend(via),
+                        None => installed.push((table, dst, kind, via)),
+                    }
+                }
+            }
+            for (_, _, _, via) in installed.iter_mut(){
+                via.sort();
+            }
+            let object = |table: Option<u32>, dst: &str| match table{
+                Some(table) => format!("route {} table {} in {}", dst, table, ns.netns),
+                None => format!("route {} in {}", dst, ns.netns),
+            };
+            let mut wanted: Vec<RouteState> = self.routes.iter().filter(|r| r.netns == ns.netns).cloned().collect();
+            for r in self.set_routes.iter().filter(|r| r.namespace == ns.name){
+                let via = r.nexthops.iter().filter_map(|n| n.address.clone()).collect();
+                wanted.push(RouteState{ netns: ns.netns.clone(), dst: r.dst.clone(), via, table: r.table, kind: r.kind });
+            }
+            for r in &wanted{
+                let dst = normalize(&r.dst, r.dst.contains(':'));
+                let mut via = r.via.clone();
+                via.sort();
+                match installed.iter().find(|(t, d, _, _)| *t == r.table && *d == dst){
+                    None => push(object(r.table, &r.dst), "present", "missing"),
+                    Some((_, _, k, _)) if *k != r.kind => push(object(r.table, &r.dst), &r.kind.to_string(), &k.to_string()),
+                    Some((_, _, _, v)) if *v != via => push(object(r.table, &r.dst), &format!("via {}", via.join(",")), &format!("via {}", v.join(","))),
+                    _ => {},
+                }
+            }
+            for (table, dst, _, _) in &installed{
+                if !wanted.iter().any(|r| r.table == *table && normalize(&r.dst, r.dst.contains(':')) == *dst) {
+                    push(object(*table, dst), "absent", "present");
+                }
+            }
+
+            let installed = policy::installed(&ns.netns)?;
+            let wanted: Vec<&PolicyRule> = self.rules.iter().filter(|r| r.netns == ns.netns).map(|r| &r.rule).collect();
+            for rule in &wanted{
+                for v6 in rule.families()?{
+                    if !installed.iter().any(|(f, old)| *f == v6 && rule.matches(old)) {
+                        push(format!("rule {} in {}", rule.args()?.join(" "), ns.netns), "present", "missing");
+                    }
+                }
+            }
+            for (v6, old) in &installed{
+                if !wanted.iter().any(|r| r.families().is_ok_and(|f| f.contains(v6)) && r.matches(old)) {
+                    push(format!("rule {} in {}", old.args()?.join(" "), ns.netns), "absent", "present");
+                }
+            }
+        }
+        for i in self.interfaces.iter().filter(|i| i.netns.is_none()){
+            let links: serde_json::Value = match cmd::ip(None, &["-j", "addr", "show", "dev", i.name.as_str()]){
+                Ok(out) => serde_json::from_str(&out)?,
+                Err(_) => {
+                    push(format!("interface {}", i.name), "present", "missing");
+                    continue;
+                },
+            };
+            if let Some(l) = links.as_array().and_then(|l| l.first()){
+                interface_drift(i, l, &mut push);
+            }
+        }
+        Ok(drift)
+    }
+}
+
+fn interface_drift(i: &InterfaceState, link: &serde_json::Value, push: &mut impl FnMut(String, &str, &str)){
+    let object = format!("interface {}", i.name);
+    // admin state, operstate lags behind right after creation
+    if !link["flags"].as_array().is_some_and(|f| f.iter().any(|f| f == "UP")) {
+        push(object.clone(), "up", "down");
+    }
+    if let Some(mtu) = i.mtu{
+        if link["mtu"].as_u64() != Some(mtu as u64) {
+            push(object.clone(), &format!("mtu {}", mtu), &format!("mtu {}", link["mtu"]));
+        }
+    }
+    let actual: BTreeSet<String> = link["addr_info"].as_array().cloned().unwrap_or_default().iter()
+        .filter(|a| a["scope"] == "global")
+        .filter_map(|a| format!("{}/{}", a["local"].as_str()?, a["prefixlen"]).parse::<ipnet::IpNet>().ok())
+        .map(|a| a.to_string())
+        .collect();
+    let wanted: BTreeSet<String> = i.ip.iter().chain(i.ip6.iter())
+        .filter_map(|a| a.parse::<ipnet::IpNet>().ok())
+        .map(|a| a.to_string())
+        .collect();
+    if actual != wanted {
+        let join = |s: &BTreeSet<String>| if s.is_empty() { "no addresses".to_string() } else { s.iter().cloned().collect::<Vec<_>>().join(" ") };
+        push(object, &join(&wanted), &join(&actual));
+    }
+}
+
+/// Route destination as prefix, `default` and bare host addresses included.
+fn normalize(dst: &str, v6: bool) -> String {
+    let dst = match dst{
+        "default" if v6 => "::/0".to_string(),
+        "default" => "0.0.0.0/0".to_string(),
+        d if d.contains('/') => d.to_string(),
+        d if v6 => format!("{}/128", d),
+        d => format!("{}/32", d),
+    };
+    dst.parse::<ipnet::IpNet>().map(|n| n.trunc().to_string()).unwrap_or(dst)
+}
+
+/// Kernel names of the namespaces of `topology`: from its state if saved,
+/// otherwise those tagged as created for it, see `Namespace::list`.
+pub fn namespaces(topology: &str) -> Result<Vec<String>>{
+    match State::load(topology)?{
+        Some(state) => {
+            let mut namespaces: Vec<String> = state.namespaces.into_iter()
+                .map(|n| n.netns)
+                .filter(|n| PathBuf::from("/run/netns").join(n).exists())
+                .collect();
+            namespaces.sort();
+            Ok(namespaces)
+        },
+        None => Ok(Namespace::list(topology)?),
+    }
+}
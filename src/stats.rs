@@ -0,0 +1,335 @@
+//! Interface counters read from inside the namespaces of a topology, as
+//! totals or as deltas between polls, e.g. to watch how ECMP spreads
+//! traffic over parallel links. Counters come from the kernel's 64 bit link
+//! statistics as reported by `ip -s -j link`.
+//!
+//! Counter assertions turn such observations into pass/fail criteria, e.g.
+//! that each of six parallel links carried between 10% and 25% of the
+//! packets a namespace sent while test traffic ran.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Sub;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::{failed, Result, RouterError};
+
+#[derive(Serialize, Clone, Copy, Default, Debug, PartialEq)]
+pub struct InterfaceStats{
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_dropped: u64,
+    pub rx_errors: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_dropped: u64,
+    pub tx_errors: u64,
+}
+
+impl InterfaceStats{
+    pub(crate) fn from_json(link: &serde_json::Value) -> InterfaceStats {
+        let stats = &link["stats64"];
+        let counter = |direction: &str, name: &str| stats[direction][name].as_u64().unwrap_or(0);
+        InterfaceStats{
+            rx_bytes: counter("rx", "bytes"),
+            rx_packets: counter("rx", "packets"),
+            rx_dropped: counter("rx", "dropped"),
+            rx_errors: counter("rx", "errors"),
+            tx_bytes: counter("tx", "bytes"),
+            tx_packets: counter("tx", "packets"),
+            tx_dropped: counter("tx", "dropped"),
+            tx_errors: counter("tx", "errors"),
+        }
+    }
+}
+
+impl InterfaceStats{
+    pub fn get(&self, counter: Counter) -> u64 {
+        match counter{
+            Counter::RxBytes => self.rx_bytes,
+            Counter::RxPackets => self.rx_packets,
+            Counter::RxDropped => self.rx_dropped,
+            Counter::RxErrors => self.rx_errors,
+            Counter::TxBytes => self.tx_bytes,
+            Counter::TxPackets => self.tx_packets,
+            Counter::TxDropped => self.tx_dropped,
+            Counter::TxErrors => self.tx_errors,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Counter{
+    RxBytes,
+    RxPackets,
+    RxDropped,
+    RxErrors,
+    TxBytes,
+    #[default]
+    TxPackets,
+    TxDropped,
+    TxErrors,
+}
+
+impl FromStr for Counter{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<Self>{
+        match s{
+            "rx_bytes" => Ok(Counter::RxBytes),
+            "rx_packets" => Ok(Counter::RxPackets),
+            "rx_dropped" => Ok(Counter::RxDropped),
+            "rx_errors" => Ok(Counter::RxErrors),
+            "tx_bytes" => Ok(Counter::TxBytes),
+            "tx_packets" => Ok(Counter::TxPackets),
+            "tx_dropped" => Ok(Counter::TxDropped),
+            "tx_errors" => Ok(Counter::TxErrors),
+            _ => Err(RouterError::Invalid(format!("Unknown counter {}, expected rx_ or tx_ followed by bytes, packets, dropped or errors", s))),
+        }
+    }
+}
+
+impl fmt::Display for Counter{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Counter::RxBytes => write!(f, "rx_bytes"),
+            Counter::RxPackets => write!(f, "rx_packets"),
+            Counter::RxDropped => write!(f, "rx_dropped"),
+            Counter::RxErrors => write!(f, "rx_errors"),
+            Counter::TxBytes => write!(f, "tx_bytes"),
+            Counter::TxPackets => write!(f, "tx_packets"),
+            Counter::TxDropped => write!(f, "tx_dropped"),
+            Counter::TxErrors => write!(f, "tx_errors"),
+        }
+    }
+}
+
+/// Counter increase from `rhs` to `self`. A counter that went backwards,
+/// because the interface was recreated, counts from zero.
+impl Sub for InterfaceStats{
+    type Output = InterfaceStats;
+    fn sub(self, rhs: InterfaceStats) -> InterfaceStats {
+        let delta = |now: u64, before: u64| if now >= before { now - before } else { now };
+        InterfaceStats{
+            rx_bytes: delta(self.rx_bytes, rhs.rx_bytes),
+            rx_packets: delta(self.rx_packets, rhs.rx_packets),
+            rx_dropped: delta(self.rx_dropped, rhs.rx_dropped),
+            rx_errors: delta(self.rx_errors, rhs.rx_errors),
+            tx_bytes: delta(self.tx_bytes, rhs.tx_bytes),
+            tx_packets: delta(self.tx_packets, rhs.tx_packets),
+            tx_dropped: delta(self.tx_dropped, rhs.tx_dropped),
+            tx_errors: delta(self.tx_errors, rhs.tx_errors),
+        }
+    }
+}
+
+/// Counters of interface `name`, `netns` None for the host.
+pub fn read(netns: Option<&str>, name: &str) -> Result<InterfaceStats>{
+    let links = cmd::ip_json(netns, &["-s", "-j", "link", "show", "dev", name])?;
+    links.as_array().and_then(|l| l.first()).map(InterfaceStats::from_json)
+        .ok_or_else(|| failed!("Interface {} not found", name))
+}
+
+/// Counters of every interface in `netns` but the loopback, by name.
+pub fn read_all(netns: &str) -> Result<BTreeMap<String, InterfaceStats>>{
+    let links = cmd::ip_json(Some(netns), &["-s", "-j", "link", "show"])?;
+    Ok(links.as_array().cloned().unwrap_or_default().iter()
+        .filter_map(|l| Some((l["ifname"].as_str()?.to_string(), InterfaceStats::from_json(l))))
+        .filter(|(name, _)| name != "lo")
+        .collect())
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Sample{
+    /// kernel name of the namespace
+    pub netns: String,
+    pub interface: String,
+    pub total: InterfaceStats,
+    /// increase since the previous poll, the total on the first
+    pub delta: InterfaceStats,
+    /// time since the previous poll, None on the first
+    pub interval: Option<Duration>,
+}
+
+impl Sample{
+    /// Bytes per second received and sent during the interval.
+    pub fn rates(&self) -> Option<(f64, f64)> {
+        let secs = self.interval?.as_secs_f64();
+        if secs <= 0.0 {
+            return None;
+        }
+        Some((self.delta.rx_bytes as f64 / secs, self.delta.tx_bytes as f64 / secs))
+    }
+}
+
+/// Reads the counters of a set of namespaces on every `poll` and reports
+/// how much they grew since the last one.
+pub struct Poller{
+    pub namespaces: Vec<String>,
+    previous: BTreeMap<(String, String), (Instant, InterfaceStats)>,
+}
+
+impl Poller{
+    pub fn new(namespaces: Vec<String>) -> Poller {
+        Poller{
+            namespaces,
+            previous: BTreeMap::new(),
+        }
+    }
+
+    pub fn poll(&mut self) -> Result<Vec<Sample>>{
+        let mut samples = Vec::new();
+        for netns in &self.namespaces{
+            for (interface, total) in read_all(netns)?{
+                let now = Instant::now();
+                let key = (netns.clone(), interface.clone());
+                let (delta, interval) = match self.previous.get(&key){
+                    Some((then, before)) => (total - *before, Some(now - *then)),
+                    None => (total, None),
+                };
+                self.previous.insert(key, (now, total));
+                samples.push(Sample{
+                    netns: netns.clone(),
+                    interface,
+                    total,
+                    delta,
+                    interval,
+                });
+            }
+        }
+        Ok(samples)
+    }
+}
+
+/// Bounds on how much a counter of each of a set of interfaces of a
+/// namespace grew, absolute and as a share in percent of the growth of all
+/// of them together.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CounterAssertion{
+    /// namespace of the topology
+    pub namespace: String,
+    /// interface names, a trailing `*` matches any suffix, e.g. `a_link*`.
+    /// All interfaces of the namespace if empty
+    #[serde(default)]
+    pub interfaces: Vec<String>,
+    #[serde(default)]
+    pub counter: Counter,
+    #[serde(default)]
+    pub min: Option<u64>,
+    #[serde(default)]
+    pub max: Option<u64>,
+    #[serde(default)]
+    pub min_share: Option<f64>,
+    #[serde(default)]
+    pub max_share: Option<f64>,
+}
+
+/// Outcome of an assertion for one interface.
+#[derive(Serialize, Clone, Debug)]
+pub struct AssertionResult{
+    pub netns: String,
+    pub interface: String,
+    pub counter: Counter,
+    pub value: u64,
+    /// percent of the growth of all interfaces of the assertion
+    pub share: f64,
+    /// violated bounds, empty if the assertion holds
+    pub violations: Vec<String>,
+}
+
+impl AssertionResult{
+    pub fn passed(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl fmt::Display for AssertionResult{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {} {} ({:.1}%)", self.netns, self.interface, self.counter, self.value, self.share)?;
+        if self.passed() {
+            write!(f, " ok")
+        } else {
+            write!(f, " FAILED: {}", self.violations.join(", "))
+        }
+    }
+}
+
+impl CounterAssertion{
+    pub fn check(&self) -> Result<()>{
+        for share in [self.min_share, self.max_share].into_iter().flatten(){
+            if !(0.0..=100.0).contains(&share) {
+                return Err(RouterError::Invalid(format!("Share {} of the assertion on {} is not a percentage", share, self.namespace)));
+            }
+        }
+        if self.min.zip(self.max).is_some_and(|(min, max)| min > max)
+            || self.min_share.zip(self.max_share).is_some_and(|(min, max)| min > max) {
+            return Err(RouterError::Invalid(format!("Assertion on {} has a minimum above its maximum", self.namespace)));
+        }
+        Ok(())
+    }
+
+    fn matches(&self, interface: &str) -> bool {
+        self.interfaces.is_empty() || self.interfaces.iter().any(|i| match i.strip_suffix('*'){
+            Some(prefix) => interface.starts_with(prefix),
+            None => interface == i,
+        })
+    }
+
+    /// Evaluates the assertion against the deltas of `samples` from the
+    /// namespaces of `topology`. Fails if no interface matches, an interface
+    /// listed by name but missing counts as failed.
+    pub fn evaluate(&self, topology: &str, samples: &[Sample]) -> Result<Vec<AssertionResult>>{
+        self.check()?;
+        let netns = crate::Namespace::netns_name(topology, &self.namespace);
+        let samples: Vec<&Sample> = samples.iter()
+            .filter(|s| s.netns == netns && self.matches(&s.interface))
+            .collect();
+        if samples.is_empty() {
+            return Err(failed!("No interface of {} matches {}", self.namespace, self.interfaces.join(", ")));
+        }
+        let total: u64 = samples.iter().map(|s| s.delta.get(self.counter)).sum();
+        let mut results = Vec::new();
+        for s in &samples{
+            let value = s.delta.get(self.counter);
+            let share = if total > 0 { 100.0 * value as f64 / total as f64 } else { 0.0 };
+            let mut violations = Vec::new();
+            if let Some(min) = self.min.filter(|min| value < *min){
+                violations.push(format!("below {}", min));
+            }
+            if let Some(max) = self.max.filter(|max| value > *max){
+                violations.push(format!("above {}", max));
+            }
+            if let Some(min) = self.min_share.filter(|min| share < *min){
+                violations.push(format!("below {}%", min));
+            }
+            if let Some(max) = self.max_share.filter(|max| share > *max){
+                violations.push(format!("above {}%", max));
+            }
+            results.push(AssertionResult{
+                netns: netns.clone(),
+                interface: s.interface.clone(),
+                counter: self.counter,
+                value,
+                share,
+                violations,
+            });
+        }
+        for name in self.interfaces.iter().filter(|i| !i.ends_with('*')){
+            if !samples.iter().any(|s| s.interface == *name) {
+                results.push(AssertionResult{
+                    netns: netns.clone(),
+                    interface: name.clone(),
+                    counter: self.counter,
+                    value: 0,
+                    share: 0.0,
+                    violations: vec!["interface not found".to_string()],
+                });
+            }
+        }
+        Ok(results)
+    }
+}
@@ -0,0 +1,215 @@
+//! Preset duplication and reordering profiles for links, plus a sequence
+//! probe verifying what applications on top actually see.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::{SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use crate::cmd;
+use crate::error::{failed, Result, RouterError};
+use crate::netns;
+
+/// netem only reorders packets it delays, the reordered ones skip the delay.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Profile{
+    /// 5ms delay, 10% reordered, 1% duplicated
+    Light,
+    /// 10ms delay, 25% reordered, 10% duplicated
+    Heavy,
+    /// 20ms delay, 50% reordered, 25% duplicated
+    Extreme,
+}
+
+/// netem settings of a profile, percentages are 0-100.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ProfileParams{
+    pub delay_ms: u32,
+    pub reorder: f64,
+    pub reorder_correlation: f64,
+    pub duplicate: f64,
+}
+
+impl Profile{
+    pub fn params(&self) -> ProfileParams {
+        match self{
+            Profile::Light => ProfileParams{ delay_ms: 5, reorder: 10.0, reorder_correlation: 25.0, duplicate: 1.0 },
+            Profile::Heavy => ProfileParams{ delay_ms: 10, reorder: 25.0, reorder_correlation: 50.0, duplicate: 10.0 },
+            Profile::Extreme => ProfileParams{ delay_ms: 20, reorder: 50.0, reorder_correlation: 50.0, duplicate: 25.0 },
+        }
+    }
+
+    /// Replaces the root qdisc of `interface` in `netns` with the profile.
+    /// Affects egress only, stress both ends for both directions.
+    pub fn apply(&self, netns: &str, interface: &str) -> Result<()>{
+        let p = self.params();
+        let delay = format!("{}ms", p.delay_ms);
+        let reorder = format!("{}%", p.reorder);
+        let correlation = format!("{}%", p.reorder_correlation);
+        let duplicate = format!("{}%", p.duplicate);
+        cmd::tc(Some(netns), &[
+            "qdisc", "replace", "dev", interface, "root", "netem",
+            "delay", delay.as_str(),
+            "reorder", reorder.as_str(), correlation.as_str(),
+            "duplicate", duplicate.as_str(),
+        ])?;
+        Ok(())
+    }
+
+    /// Fails unless `report` shows the duplicates and reordering this
+    /// profile produces, i.e. the stress actually reached the application.
+    pub fn verify(&self, report: &SequenceReport) -> Result<()>{
+        if report.unique == 0 {
+            return Err(failed!("No probes received"));
+        }
+        let p = self.params();
+        let mut missing = Vec::new();
+        if p.duplicate > 0.0 && report.duplicates == 0 {
+            missing.push("duplicates");
+        }
+        if p.reorder > 0.0 && report.reordered == 0 {
+            missing.push("reordering");
+        }
+        if !missing.is_empty() {
+            return Err(failed!("Profile {} expected but no {} observed: {}", self, missing.join(" or "), report));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Profile{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Profile::Light => write!(f, "light"),
+            Profile::Heavy => write!(f, "heavy"),
+            Profile::Extreme => write!(f, "extreme"),
+        }
+    }
+}
+
+impl FromStr for Profile{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<Profile>{
+        match s{
+            "light" => Ok(Profile::Light),
+            "heavy" => Ok(Profile::Heavy),
+            "extreme" => Ok(Profile::Extreme),
+            _ => Err(RouterError::Invalid(format!("Invalid stress profile {}, expected light, heavy or extreme", s))),
+        }
+    }
+}
+
+/// Removes a stress profile (or any other root qdisc) from an interface.
+pub fn clear(netns: &str, interface: &str) -> Result<()>{
+    cmd::tc(Some(netns), &["qdisc", "del", "dev", interface, "root"])?;
+    Ok(())
+}
+
+/// Sends numbered UDP datagrams from `src` to `target` in `dst` and records
+/// the order they arrive in.
+pub struct SequenceProbe{
+    pub src: String,
+    pub dst: String,
+    pub target: SocketAddr,
+    pub count: u32,
+    pub interval: Duration,
+}
+
+/// `reordered` counts datagrams arriving after one with a higher sequence
+/// number, `displacement` is the largest such gap. `gap` is the longest run
+/// of consecutive datagrams which never arrived.
+#[derive(Clone, Debug, Default)]
+pub struct SequenceReport{
+    pub sent: u32,
+    pub received: u32,
+    pub unique: u32,
+    pub duplicates: u32,
+    pub reordered: u32,
+    pub displacement: u32,
+    pub lost: u32,
+    pub gap: u32,
+}
+
+impl fmt::Display for SequenceReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} sent, {} received ({} unique), {} duplicates, {} reordered, max displacement {}, {} lost, longest gap {}",
+            self.sent, self.received, self.unique, self.duplicates, self.reordered, self.displacement, self.lost, self.gap)
+    }
+}
+
+impl SequenceProbe{
+    pub fn run(&self) -> Result<SequenceReport>{
+        let (port, v6) = (self.target.port(), self.target.is_ipv6());
+        let (target, count, interval) = (self.target, self.count, self.interval);
+        // delayed and duplicated packets may still be in flight
+        let ((), arrivals) = netns::send_receive(&self.src, &self.dst, Duration::from_millis(500), move || {
+            let socket = UdpSocket::bind(if target.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" })?;
+            for seq in 0..count{
+                // no route while routing changes, counts as lost
+                if let Err(e) = socket.send_to(&seq.to_be_bytes(), target) {
+                    if !matches!(e.raw_os_error(), Some(libc::ENETUNREACH) | Some(libc::EHOSTUNREACH)) {
+                        return Err(e.into());
+                    }
+                }
+                std::thread::sleep(interval);
+            }
+            Ok(())
+        }, move |done, ready| receive(port, v6, done, ready))?;
+        Ok(report(&arrivals, count))
+    }
+}
+
+fn report(arrivals: &[u32], sent: u32) -> SequenceReport {
+    let mut seen = BTreeSet::new();
+    let mut r = SequenceReport{
+        sent,
+        received: arrivals.len() as u32,
+        ..Default::default()
+    };
+    let mut highest: Option<u32> = None;
+    for seq in arrivals{
+        if !seen.insert(*seq) {
+            r.duplicates += 1;
+            continue;
+        }
+        match highest{
+            Some(h) if *seq < h => {
+                r.reordered += 1;
+                r.displacement = r.displacement.max(h - seq);
+            },
+            _ => highest = Some(*seq),
+        }
+    }
+    r.unique = seen.len() as u32;
+    r.lost = sent.saturating_sub(r.unique);
+    let mut run = 0;
+    for seq in 0..sent{
+        if seen.contains(&seq) {
+            run = 0;
+        } else {
+            run += 1;
+            r.gap = r.gap.max(run);
+        }
+    }
+    r
+}
+
+fn receive(port: u16, v6: bool, done: Arc<AtomicBool>, ready: mpsc::Sender<()>) -> Result<Vec<u32>>{
+    let socket = UdpSocket::bind(if v6 { format!("[::]:{}", port) } else { format!("0.0.0.0:{}", port) })?;
+    socket.set_read_timeout(Some(Duration::from_millis(50)))?;
+    let _ = ready.send(());
+    let mut arrivals = Vec::new();
+    let mut buf = [0u8; 64];
+    while !done.load(Ordering::SeqCst) {
+        match socket.recv_from(&mut buf){
+            Ok((n, _)) if n >= 4 => arrivals.push(u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])),
+            Ok(_) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {},
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(arrivals)
+}
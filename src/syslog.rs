@@ -0,0 +1,236 @@
+//! Control-plane logs of a topology gathered into one file, so they line
+//! up with data-plane events of an experiment. The collector takes
+//!
+//! - syslog messages, RFC 3164 or 5424, sent to UDP port 514 on the
+//!   loopback of any namespace, e.g. `logger -n 127.0.0.1` or daemons
+//!   configured for a remote syslog server. syslog(3) writes to the host's
+//!   `/dev/log`, which all namespaces share, so it can't tell them apart.
+//! - lines appended to the node logs of every namespace, see `logs`, where
+//!   FRR, BIRD and processes started by us write.
+//!
+//! Each entry is written as `<time> <netns> <program> <severity>: <text>`
+//! with the time it was received in seconds since the epoch, the format of
+//! `tcpdump -tt`. The file is kept with the node logs and so ends up in a
+//! run's artifacts with `logs::collect`.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::mpsc::{self, Sender};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::{failed, Result};
+use crate::logs;
+use crate::netns;
+use crate::state::State;
+use crate::trace::Traced;
+
+pub const PORT: u16 = 514;
+/// Name of the collector file in the log directory of a topology.
+pub const FILE: &str = "syslog.log";
+/// Interval at which node logs are read and new namespaces picked up.
+const POLL: Duration = Duration::from_millis(250);
+
+const SEVERITIES: [&str; 8] = ["emerg", "alert", "crit", "err", "warning", "notice", "info", "debug"];
+
+/// Collector file of `topology`.
+pub fn path(topology: &str) -> PathBuf {
+    PathBuf::from(logs::LOG_DIR).join(topology).join(FILE)
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry{
+    /// time received, since the epoch
+    pub time: Duration,
+    pub netns: String,
+    pub program: String,
+    /// syslog severity, 0 (emerg) to 7 (debug), None for node log lines
+    pub severity: Option<u8>,
+    pub text: String,
+}
+
+impl fmt::Display for Entry{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = self.severity.and_then(|s| SEVERITIES.get(s as usize)).unwrap_or(&"-");
+        write!(f, "{}.{:06} {} {} {}: {}", self.time.as_secs(), self.time.subsec_micros(), self.netns, self.program, severity, self.text)
+    }
+}
+
+/// Program, severity and text of a syslog message.
+pub fn parse(data: &[u8]) -> (String, Option<u8>, String) {
+    let message = String::from_utf8_lossy(data);
+    let mut rest = message.trim_end_matches(['\n', '\r', '\0']);
+    let mut severity = None;
+    if let Some((pri, after)) = rest.strip_prefix('<').and_then(|r| r.split_once('>')) {
+        if let Ok(pri) = pri.parse::<u8>() {
+            severity = Some(pri & 7);
+            rest = after;
+        }
+    }
+    // RFC 5424: version timestamp host app procid msgid data text
+    if let Some(after) = rest.strip_prefix("1 ") {
+        let fields: Vec<&str> = after.splitn(6, ' ').collect();
+        if let [_, _, app, procid, _, data] = fields[..] {
+            let program = if procid == "-" { app.to_string() } else { format!("{}[{}]", app, procid) };
+            let text = match data.strip_prefix('-'){
+                Some(text) => text,
+                // structured data elements end with "] "
+                None => data.rsplit_once("] ").map(|(_, text)| text).unwrap_or(""),
+            };
+            return (program, severity, text.trim_start_matches('\u{feff}').trim().to_string());
+        }
+    }
+    // RFC 3164: "Mmm dd hh:mm:ss " then an optional host and the tag
+    let months = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    if rest.len() > 16 && months.iter().any(|m| rest.starts_with(m)) && rest.as_bytes()[15] == b' ' {
+        rest = &rest[16..];
+    }
+    let tokens: Vec<&str> = rest.splitn(3, ' ').collect();
+    match tokens[..]{
+        [tag, ..] if tag.ends_with(':') => (tag.trim_end_matches(':').to_string(), severity, rest[tag.len()..].trim().to_string()),
+        [_, tag, ..] if tag.ends_with(':') => {
+            let text = tokens.get(2).copied().unwrap_or_default();
+            (tag.trim_end_matches(':').to_string(), severity, text.trim().to_string())
+        },
+        _ => ("-".to_string(), severity, rest.trim().to_string()),
+    }
+}
+
+/// Gathers the logs of `topology` into `output` until the topology is
+/// destroyed. Waits for it to be created, so the collector can be started
+/// first and miss nothing.
+pub struct Collector{
+    pub topology: String,
+    pub output: PathBuf,
+}
+
+impl Collector{
+    pub fn run(&self) -> Result<()>{
+        if let Some(dir) = self.output.parent(){
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut output = OpenOptions::new().create(true).append(true).open(&self.output)
+            .map_err(|e| failed!("Failed to open {}: {}", self.output.display(), e))?;
+        let (tx, rx) = mpsc::channel::<Entry>();
+        let mut listening = BTreeSet::new();
+        // read position per node log, those existing at the start are
+        // followed from their end
+        let mut offsets: HashMap<PathBuf, u64> = HashMap::new();
+        for log in node_logs(&self.topology){
+            let len = std::fs::metadata(&log).map(|m| m.len()).unwrap_or_default();
+            offsets.insert(log, len);
+        }
+        let mut seen = false;
+        loop{
+            let state = State::load(&self.topology)?;
+            match &state{
+                Some(_) => seen = true,
+                None if seen => return Ok(()),
+                None => {},
+            }
+            for ns in state.iter().flat_map(|s| &s.namespaces){
+                if listening.contains(&ns.netns) {
+                    continue;
+                }
+                for socket in listen(&ns.netns)?{
+                    let (netns, tx) = (ns.netns.clone(), tx.clone());
+                    std::thread::spawn(move || receive(socket, &netns, tx));
+                }
+                listening.insert(ns.netns.clone());
+            }
+            for log in node_logs(&self.topology){
+                let offset = offsets.entry(log.clone()).or_insert(0);
+                for entry in follow(&log, offset)?{
+                    writeln!(output, "{}", entry)?;
+                }
+            }
+            if let Ok(entry) = rx.recv_timeout(POLL) {
+                for entry in std::iter::once(entry).chain(rx.try_iter()){
+                    writeln!(output, "{}", entry)?;
+                }
+            }
+            output.flush()?;
+        }
+    }
+}
+
+/// Sockets on the syslog port of the loopback of `netns`. IPv6 is left
+/// out where the namespace has it disabled.
+fn listen(netns: &str) -> Result<Vec<UdpSocket>>{
+    let output = Command::new("ip").args(["-n", netns, "link", "set", "dev", "lo", "up"]).traced_output()?;
+    if !output.status.success() {
+        return Err(failed!("Failed to bring up lo in {}: {}", netns, String::from_utf8_lossy(&output.stderr)));
+    }
+    let v4 = SocketAddr::from(([127, 0, 0, 1], PORT));
+    let v6 = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], PORT));
+    netns::run_in(netns, || {
+        let mut sockets = vec![UdpSocket::bind(v4)
+            .map_err(|e| failed!("Failed to listen on {} in {}: {}", v4, netns, e))?];
+        sockets.extend(UdpSocket::bind(v6).ok());
+        Ok(sockets)
+    })
+}
+
+fn receive(socket: UdpSocket, netns: &str, tx: Sender<Entry>){
+    let mut buf = [0u8; 8192];
+    loop{
+        let Ok(len) = socket.recv(&mut buf) else {
+            continue;
+        };
+        let (program, severity, text) = parse(&buf[..len]);
+        let entry = Entry{ time: now(), netns: netns.to_string(), program, severity, text };
+        // the collector is gone
+        if tx.send(entry).is_err() {
+            return;
+        }
+    }
+}
+
+/// Node logs of all namespaces of `topology`, rotated ones left out.
+fn node_logs(topology: &str) -> Vec<PathBuf> {
+    let mut logs = Vec::new();
+    let Ok(dirs) = std::fs::read_dir(PathBuf::from(logs::LOG_DIR).join(topology)) else {
+        return logs;
+    };
+    for dir in dirs.flatten().map(|d| d.path()).filter(|d| d.is_dir()){
+        let Ok(files) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        logs.extend(files.flatten().map(|f| f.path()).filter(|f| f.extension().is_some_and(|e| e == "log")));
+    }
+    logs.sort();
+    logs
+}
+
+/// Complete lines appended to `log` since `offset`, which is moved past
+/// them. A log shorter than `offset` was rotated and is read from the start.
+fn follow(log: &Path, offset: &mut u64) -> Result<Vec<Entry>>{
+    let Ok(mut file) = std::fs::File::open(log) else {
+        return Ok(Vec::new());
+    };
+    if file.metadata()?.len() < *offset {
+        *offset = 0;
+    }
+    file.seek(SeekFrom::Start(*offset))?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    let Some(end) = data.iter().rposition(|b| *b == b'\n') else {
+        return Ok(Vec::new());
+    };
+    *offset += end as u64 + 1;
+    let netns = log.parent().and_then(|d| d.file_name()).unwrap_or_default().to_string_lossy().to_string();
+    let program = log.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let time = now();
+    Ok(String::from_utf8_lossy(&data[..end]).lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| Entry{ time, netns: netns.clone(), program: program.clone(), severity: None, text: l.to_string() })
+        .collect())
+}
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
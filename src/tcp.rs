@@ -0,0 +1,119 @@
+//! TCP tuning of a namespace: congestion control, socket buffers and
+//! pacing, so transport behavior over impaired links is studied with
+//! controlled settings rather than whatever the host defaults to.
+//!
+//! The largest buffers are given in bytes or sized from the
+//! bandwidth-delay product of the paths the namespace sends over: twice
+//! the bytes in flight at `bandwidth` and `rtt`, since the kernel counts
+//! its own overhead against the buffer. Without `bandwidth` the fastest
+//! link of the namespace is taken, its rate limit or else its bandwidth.
+//!
+//! Pacing puts the fq qdisc on the interfaces of the namespace. Interfaces
+//! with link impairments keep their netem or tbf and are only paced by
+//! congestion controls pacing on their own, like bbr.
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::{failed, Result, RouterError};
+
+/// Smallest buffer the kernel works with.
+const MIN_BUFFER: u64 = 4096;
+
+/// TCP settings of a namespace, the kernel's own where not set.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TcpSpec{
+    /// congestion control of new connections, e.g. cubic, reno or bbr,
+    /// its module must be available on the host
+    #[serde(default)]
+    pub congestion: Option<String>,
+    /// largest receive buffer in bytes, sized from the bandwidth-delay
+    /// product if not set and `rtt` is
+    #[serde(default)]
+    pub receive_buffer: Option<u64>,
+    /// largest send buffer in bytes, as `receive_buffer`
+    #[serde(default)]
+    pub send_buffer: Option<u64>,
+    /// Mbit/s of the bandwidth-delay product
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+    /// round trip time in milliseconds of the bandwidth-delay product
+    #[serde(default)]
+    pub rtt: Option<f64>,
+    /// pace the namespace's unimpaired interfaces with fq
+    #[serde(default)]
+    pub pacing: Option<bool>,
+}
+
+impl TcpSpec{
+    pub fn check(&self) -> Result<()>{
+        if self.congestion.as_deref().is_some_and(|c| c.is_empty() || c.contains(char::is_whitespace)) {
+            return Err(RouterError::Invalid(format!("Invalid congestion control {:?}", self.congestion)));
+        }
+        for (name, value) in [("receive_buffer", self.receive_buffer), ("send_buffer", self.send_buffer)]{
+            if value.is_some_and(|v| v < MIN_BUFFER) {
+                return Err(RouterError::Invalid(format!("Invalid {} {:?}, expected at least {} bytes", name, value, MIN_BUFFER)));
+            }
+        }
+        if self.rtt.is_some_and(|r| r <= 0.0) {
+            return Err(RouterError::Invalid(format!("Invalid rtt {:?}, expected milliseconds", self.rtt)));
+        }
+        if self.bandwidth == Some(0) {
+            return Err(RouterError::Invalid("Invalid bandwidth 0".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Buffer holding twice the bandwidth-delay product, None without
+    /// `rtt`. `link_bandwidth` is used if `bandwidth` isn't set.
+    pub fn bdp_buffer(&self, link_bandwidth: Option<u64>) -> Result<Option<u64>>{
+        let Some(rtt) = self.rtt else {
+            return Ok(None);
+        };
+        let bandwidth = self.bandwidth.or(link_bandwidth)
+            .ok_or_else(|| failed!("rtt needs a bandwidth, neither set nor known from the links"))?;
+        let bytes = (bandwidth as f64 * 1_000_000.0 / 8.0 * rtt / 1000.0 * 2.0).ceil() as u64;
+        Ok(Some(bytes.max(MIN_BUFFER)))
+    }
+
+    /// sysctl settings for the namespace, with `link_bandwidth` as for
+    /// `bdp_buffer`. Buffer settings hold spaces.
+    pub fn sysctls(&self, link_bandwidth: Option<u64>) -> Result<Vec<String>>{
+        self.check()?;
+        let mut settings = Vec::new();
+        if let Some(congestion) = &self.congestion{
+            settings.push(format!("net.ipv4.tcp_congestion_control={}", congestion));
+        }
+        let bdp = self.bdp_buffer(link_bandwidth)?;
+        // the kernel's minimum and initial sizes, the initial one lowered
+        // to a smaller limit
+        if let Some(max) = self.receive_buffer.or(bdp) {
+            settings.push(format!("net.ipv4.tcp_rmem={} {} {}", MIN_BUFFER, max.min(131072), max));
+        }
+        if let Some(max) = self.send_buffer.or(bdp) {
+            settings.push(format!("net.ipv4.tcp_wmem={} {} {}", MIN_BUFFER, max.min(16384), max));
+        }
+        Ok(settings)
+    }
+}
+
+/// Congestion controls loaded on the host, others are loaded when first
+/// set if their module exists.
+pub fn available() -> Vec<String> {
+    std::fs::read_to_string("/proc/sys/net/ipv4/tcp_available_congestion_control")
+        .map(|s| s.split_whitespace().map(|c| c.to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// Puts fq on `interface` in `netns`, or removes it, unless the interface
+/// has another root qdisc.
+pub fn pace(netns: &str, interface: &str, on: bool) -> Result<()>{
+    let root = cmd::tc(Some(netns), &["qdisc", "show", "dev", interface, "root"])?;
+    let fq = root.split_whitespace().nth(1) == Some("fq");
+    if on && !fq && !root.contains("netem") && !root.contains("tbf") {
+        cmd::tc(Some(netns), &["qdisc", "replace", "dev", interface, "root", "fq"])?;
+    } else if !on && fq {
+        cmd::tc(Some(netns), &["qdisc", "del", "dev", interface, "root"])?;
+    }
+    Ok(())
+}
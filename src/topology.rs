@@ -0,0 +1,3006 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+use serde::{Deserialize, Serialize};
+
+use crate::alert::AlertSpec;
+use crate::bond::BondSpec;
+use crate::clock::{self, ClockSkew};
+use crate::cmd;
+use crate::container::ContainerRuntime;
+use crate::daemon::{self, BgpConfig, BgpNeighbor, DaemonConfig, DaemonKind, OspfInterface, RoutingDaemon};
+use crate::distributed::{HostSpec, Transport};
+use crate::dns::{self, DnsSpec, Responder};
+use crate::error::{failed, Result, RouterError};
+use crate::firewall::{self, FirewallSpec, NatSpec};
+use crate::forwarder::{Forwarder, ForwarderKind};
+use crate::graph;
+use crate::group::{self, GroupSpec};
+use crate::guard::TopologyGuard;
+use crate::hooks::{self, HookEvent, HookSpec};
+use crate::icmp::IcmpSpec;
+use crate::interface;
+use crate::ipam::IpamSpec;
+use crate::loopback::{self, LoopbackSpec};
+use crate::compose::{self, ModuleSpec};
+use crate::conntrack::FlowAssertion;
+use crate::mirror::{Mirror, MirrorSpec};
+use crate::namespace;
+use crate::nat64::{self, Nat64Spec, Translator};
+use crate::neighbor::{self, NeighborGc, NeighborSpec};
+use crate::offload::OffloadSpec;
+use crate::owner::Owner;
+use crate::parallel;
+use crate::passthrough::{self, Passthrough};
+use crate::paths;
+use crate::ovs;
+use crate::p4::P4Switch;
+use crate::policy::{self, PolicyRule};
+use crate::preflight;
+use crate::process::{Process, ProcessSpec, RestartPolicy};
+use crate::qos::{self, LinkQos};
+use crate::queue::QueueSpec;
+use crate::ra::{self, Advertiser, RaSpec};
+use crate::state::{self, State};
+use crate::stats::CounterAssertion;
+use crate::tcp::{self, TcpSpec};
+use crate::transaction::{self, Resource};
+use crate::tunnel;
+use crate::validate;
+use crate::verify::CheckSpec;
+use crate::{Bridge, BridgeBackend, Config, Ends, Interface, Link, Namespace, Nexthop, Rib, Route, RouteKind, Seg6, Seg6Local, Tunnel, TunnelEnd, TunnelKind, Vrf, Vtep, VxlanLink, WireguardEnd, WireguardLink};
+
+/// Declarative description of a lab. Loaded from YAML or TOML and turned into
+/// kernel objects by `Topology::build`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Topology{
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub namespaces: Vec<NamespaceSpec>,
+    #[serde(default)]
+    pub links: Vec<LinkSpec>,
+    #[serde(default)]
+    pub bridges: Vec<BridgeSpec>,
+    /// overlay segments tunnelled over the links and bridges
+    #[serde(default)]
+    pub vxlans: Vec<VxlanSpec>,
+    /// GRE and IP-in-IP tunnels over the links and bridges
+    #[serde(default)]
+    pub tunnels: Vec<TunnelSpec>,
+    /// encrypted links over the links and bridges
+    #[serde(default)]
+    pub wireguards: Vec<WireguardSpec>,
+    #[serde(default)]
+    pub interfaces: Vec<InterfaceSpec>,
+    #[serde(default)]
+    pub routes: Vec<RouteSpec>,
+    #[serde(default)]
+    pub services: Vec<ServiceSpec>,
+    /// connectivity checks run by `verify`
+    #[serde(default)]
+    pub checks: Vec<CheckSpec>,
+    /// assertions on interface counters checked by `assert-counters`
+    #[serde(default)]
+    pub counters: Vec<CounterAssertion>,
+    /// flows expected in the connection tracking tables, checked by
+    /// `verify` after the checks
+    #[serde(default)]
+    pub conntrack: Vec<FlowAssertion>,
+    /// copies of the traffic of interfaces for analysis tools, see
+    /// `mirror`
+    #[serde(default)]
+    pub mirrors: Vec<MirrorSpec>,
+    /// other topology files included under a name, see `compose`
+    #[serde(default)]
+    pub modules: Vec<ModuleSpec>,
+    /// namespaces a topology included as module is attached through, by
+    /// the name the including topology uses, see `compose`
+    #[serde(default)]
+    pub ports: BTreeMap<String, String>,
+    /// watermarks on the queues of the interfaces watched by `alerts`
+    #[serde(default)]
+    pub alerts: Option<AlertSpec>,
+    /// offloads of both ends of every link and bridge member, see
+    /// `offload`
+    #[serde(default)]
+    pub offloads: Option<OffloadSpec>,
+    /// queues of both ends of every link, see `queue`
+    #[serde(default)]
+    pub queues: Option<QueueSpec>,
+    /// interface groups links, bridges and interfaces can join
+    #[serde(default)]
+    pub groups: Vec<GroupSpec>,
+    /// pools for links without `subnet`
+    #[serde(default)]
+    pub ipam: Option<IpamSpec>,
+    /// install cheapest-path static routes between all namespaces, see
+    /// `paths::static_routes`
+    #[serde(default)]
+    pub auto_routes: bool,
+    /// give link ends without `macs` a MAC address hashed from the
+    /// topology and interface name instead of a random one
+    #[serde(default)]
+    pub stable_macs: bool,
+    /// run a routing daemon speaking OSPF, and BGP where namespaces declare
+    /// it, in every namespace, see `daemon`
+    #[serde(default)]
+    pub daemon: Option<DaemonKind>,
+    /// garbage collection thresholds of the neighbor tables, shared with
+    /// the host, see `neighbor`
+    #[serde(default)]
+    pub neighbor_gc: Option<NeighborGc>,
+    /// machines the topology spans, see `distributed`
+    #[serde(default)]
+    pub hosts: Vec<HostSpec>,
+    /// sysctls of every namespace the topology creates, overridden by
+    /// those of a namespace, e.g. `net.ipv4.conf.all.rp_filter: 2`
+    #[serde(default)]
+    pub sysctls: BTreeMap<String, String>,
+    /// scripts run on the host at points of the topology's life, see
+    /// `hooks`
+    #[serde(default)]
+    pub hooks: Vec<HookSpec>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct NamespaceSpec{
+    pub name: String,
+    /// hashes multipath routes on the ports too, shorthand for the
+    /// `fib_multipath_hash_policy` sysctls
+    #[serde(default)]
+    pub ecmp: bool,
+    /// sysctls set when the namespace is created, after forwarding, `ecmp`
+    /// and the topology's `sysctls`, which they override, e.g.
+    /// `net.ipv4.fib_multipath_use_neigh: 1`. Per-interface ones belong to
+    /// a group
+    #[serde(default)]
+    pub sysctls: BTreeMap<String, String>,
+    /// processes SRv6 segment routing headers, namespaces with SRv6 routes
+    /// do so anyway
+    #[serde(default)]
+    pub srv6: bool,
+    /// clock offsets for every program started in this namespace, daemons
+    /// and processes included, see `netns::command`
+    #[serde(default)]
+    pub clock: Option<ClockSkew>,
+    /// BGP speaker run by the topology's `daemon`
+    #[serde(default)]
+    pub bgp: Option<BgpSpec>,
+    /// host rather than router: runs no daemon, interfaces facing it are
+    /// passive in OSPF and, when it has a single link and no routes of its
+    /// own, it gets default routes via the other end
+    #[serde(default)]
+    pub stub: bool,
+    /// masquerade traffic forwarded out of an interface, see `firewall`
+    #[serde(default)]
+    pub nat: Option<NatSpec>,
+    /// stateful packet filter, see `firewall`
+    #[serde(default)]
+    pub firewall: Option<FirewallSpec>,
+    /// software fastpath: established TCP and UDP connections are forwarded
+    /// through an nftables flowtable over all interfaces, see `fastpath`
+    #[serde(default)]
+    pub flowtable: bool,
+    /// ICMP rate limit and redirects, see `icmp`
+    #[serde(default)]
+    pub icmp: Option<IcmpSpec>,
+    /// congestion control, buffer sizes and pacing, see `tcp`
+    #[serde(default)]
+    pub tcp: Option<TcpSpec>,
+    /// dummy device with a host address, the router id, see `loopback`
+    #[serde(default)]
+    pub loopback: Option<LoopbackSpec>,
+    /// translate between an IPv6 prefix and an IPv4 pool, see `nat64`
+    #[serde(default)]
+    pub nat64: Option<Nat64Spec>,
+    /// DNS server for the names of the topology, see `dns`
+    #[serde(default)]
+    pub dns: Option<DnsSpec>,
+    /// learn the IPv6 default route from router advertisements of the
+    /// routers on its links instead of getting a static one, see `ra`
+    #[serde(default)]
+    pub ra: Option<RaSpec>,
+    /// neighbor cache timers of the interfaces, see `neighbor`
+    #[serde(default)]
+    pub neighbor: Option<NeighborSpec>,
+    /// policy routing rules selecting the table of `routes`, see `policy`
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,
+    #[serde(default)]
+    pub vrfs: Vec<VrfSpec>,
+    /// P4 program switching the namespace's interfaces instead of the
+    /// kernel, see `p4`
+    #[serde(default)]
+    pub p4: Option<P4Spec>,
+    /// userspace forwarder taking over the namespace's interfaces, see
+    /// `forwarder`
+    #[serde(default)]
+    pub forwarder: Option<ForwarderSpec>,
+    /// network namespace of a running container or process, attached
+    /// instead of created, see `container`
+    #[serde(default)]
+    pub container: Option<ContainerSpec>,
+    /// one of `hosts` the namespace runs on, the first if not set
+    #[serde(default)]
+    pub host: Option<String>,
+    /// long-running programs started once the topology is up and restarted
+    /// as their policy asks, see `process`
+    #[serde(default)]
+    pub processes: Vec<ProcessSpec>,
+}
+
+/// bmv2 switch of a namespace running `program`, see `P4Switch`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct P4Spec{
+    /// P4_16 source, compiled with p4c-bm2-ss, or the compiler's JSON
+    pub program: String,
+    /// interfaces of the namespace as ports 0, 1, ..., all of them sorted
+    /// by name if empty
+    #[serde(default)]
+    pub ports: Vec<String>,
+    /// simple_switch_CLI commands filling the tables, e.g.
+    /// `table_add ipv4_lpm forward 10.0.2.0/24 => 1`
+    #[serde(default)]
+    pub commands: Vec<String>,
+}
+
+/// Forwarder process of a namespace, see `Forwarder`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ForwarderSpec{
+    #[serde(default)]
+    pub kind: ForwarderKind,
+    /// program and arguments, `{ports}` and `{eal}` replaced, the
+    /// software router of `dataplane` if empty, but for `dpdk`
+    #[serde(default)]
+    pub command: Vec<String>,
+    /// interfaces of the namespace handed to the forwarder, all of them
+    /// sorted by name if empty
+    #[serde(default)]
+    pub ports: Vec<String>,
+}
+
+/// Container or process whose network namespace a namespace is, either
+/// `name` asked from `runtime` or `pid`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ContainerSpec{
+    #[serde(default)]
+    pub runtime: ContainerRuntime,
+    /// name or id of the container
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub pid: Option<u32>,
+}
+
+impl ContainerSpec{
+    /// Pid of the process whose namespace is attached.
+    pub fn pid(&self) -> Result<u32>{
+        match (&self.name, self.pid){
+            (Some(name), None) => self.runtime.pid(name),
+            (None, Some(pid)) => Ok(pid),
+            _ => Err(failed!("A container needs either a name or a pid")),
+        }
+    }
+}
+
+/// VRF device of a namespace routing `interfaces` with `table`, see `Vrf`.
+/// Interfaces are named as in the namespace, e.g. `<namespace>_<link>`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct VrfSpec{
+    pub name: String,
+    pub table: u32,
+    #[serde(default)]
+    pub interfaces: Vec<String>,
+}
+
+/// BGP configuration of a namespace. Links between namespaces of different
+/// AS numbers are left out of OSPF, so those carry eBGP only.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BgpSpec{
+    pub asn: u32,
+    #[serde(default)]
+    pub neighbors: Vec<BgpNeighborSpec>,
+    /// prefixes announced to the neighbors of the same family
+    #[serde(default)]
+    pub networks: Vec<String>,
+    /// suppress routes that flap with RFC 2439 route flap dampening, FRR
+    /// only
+    #[serde(default)]
+    pub dampening: bool,
+}
+
+/// Session with the BGP speaker of namespace `peer`, whose `bgp` section
+/// gives the remote AS number.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BgpNeighborSpec{
+    pub peer: String,
+    /// address of the peer, by default its IPv4 (else IPv6) address on a
+    /// link or bridge both are attached to
+    #[serde(default)]
+    pub address: Option<String>,
+}
+
+/// Point-to-point link between exactly two namespaces. The interface names
+/// are derived as `<namespace>_<link>`. `subnet` is IPv4 or IPv6, adding
+/// `subnet6` to an IPv4 link makes it dual-stack. Without `subnet` the
+/// link gets its subnets from the topology's `ipam` pools.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LinkSpec{
+    pub name: String,
+    #[serde(default)]
+    pub subnet: String,
+    #[serde(default)]
+    pub subnet6: Option<String>,
+    pub endpoints: Vec<String>,
+    /// Mbit/s, only used to derive the path cost, `qos` shapes the link
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+    /// milliseconds, only used to derive the path cost, `qos` delays the link
+    #[serde(default)]
+    pub latency: Option<f64>,
+    /// path cost, overrides the one derived from bandwidth and latency
+    #[serde(default)]
+    pub cost: Option<u64>,
+    /// impairment applied to both ends
+    #[serde(default)]
+    pub qos: Option<LinkQos>,
+    /// per-end overrides of `qos`, keyed by endpoint namespace. An end
+    /// shapes what it sends, so different overrides at both ends make the
+    /// link asymmetric, e.g. ADSL-like with a slower upstream.
+    #[serde(default)]
+    pub endpoint_qos: BTreeMap<String, LinkQos>,
+    /// overrides of the topology's `offloads` for both ends
+    #[serde(default)]
+    pub offloads: Option<OffloadSpec>,
+    /// overrides of the topology's `queues` for both ends. Ends shaped by
+    /// `qos` keep its qdisc.
+    #[serde(default)]
+    pub queues: Option<QueueSpec>,
+    /// OSPF area, backbone by default
+    #[serde(default)]
+    pub area: Option<u32>,
+    /// interface group of both ends
+    #[serde(default)]
+    pub group: Option<String>,
+    /// encapsulation between endpoints on different `hosts`
+    #[serde(default)]
+    pub transport: Transport,
+    /// MAC addresses of the ends in the order of `endpoints`
+    #[serde(default)]
+    pub macs: Vec<String>,
+    /// aggregate several veth pairs into a bonding device at each end,
+    /// see `bond`
+    #[serde(default)]
+    pub bond: Option<BondSpec>,
+    /// host numbers of the ends within every subnet in the order of
+    /// `endpoints`, 1 and 2 by default, or 0 and 1 in a /31 (/127)
+    #[serde(default)]
+    pub hosts: Vec<u32>,
+    /// exact addresses of the ends keyed by endpoint namespace, each
+    /// taking the place of the one of its family
+    #[serde(default)]
+    pub addresses: BTreeMap<String, Vec<String>>,
+    /// no subnet, each end takes the loopback address of its namespace
+    /// and reaches the other end's over a peer route
+    #[serde(default)]
+    pub unnumbered: bool,
+}
+
+impl LinkSpec{
+    /// Numbering of the ends from `hosts`, `addresses` and `unnumbered`.
+    pub fn ends(&self) -> Result<Ends>{
+        let mut ends = Ends{ unnumbered: self.unnumbered, ..Default::default() };
+        match self.hosts[..]{
+            [] => {},
+            [a, b] => ends.hosts = Some([a as u128, b as u128]),
+            _ => return Err(failed!("Link {} needs a host number per end, got {}", self.name, self.hosts.len())),
+        }
+        for (end, addresses) in &self.addresses{
+            let n = self.endpoints.iter().position(|e| e == end)
+                .ok_or_else(|| failed!("Link {} has addresses for {}, which is none of its endpoints", self.name, end))?;
+            for a in addresses{
+                // the prefix length is the subnet's
+                let addr = a.split('/').next().unwrap_or_default().parse()
+                    .map_err(|e| failed!("Invalid address {} of link {}: {}", a, self.name, e))?;
+                ends.addresses[n].push(addr);
+            }
+        }
+        if self.unnumbered && (!self.subnet.is_empty() || self.subnet6.is_some() || ends.hosts.is_some() || !self.addresses.is_empty()) {
+            return Err(failed!("Unnumbered link {} takes no subnet, hosts or addresses", self.name));
+        }
+        Ok(ends)
+    }
+
+    /// Impairment of the end in `namespace`: `qos` with the end's override
+    /// on top.
+    pub fn qos_at(&self, namespace: &str) -> Option<LinkQos> {
+        match (&self.qos, self.endpoint_qos.get(namespace)){
+            (Some(qos), Some(over)) => Some(qos.merge(over)),
+            (Some(qos), None) => Some(qos.clone()),
+            (None, over) => over.cloned(),
+        }
+    }
+}
+
+/// LAN segment joining any number of namespaces through a Linux bridge,
+/// see `Bridge`. The bridge lives in `namespace` if given, otherwise in a
+/// namespace of its own named after the bridge. An allocated subnet is
+/// large enough for all `members`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BridgeSpec{
+    pub name: String,
+    #[serde(default)]
+    pub subnet: String,
+    #[serde(default)]
+    pub subnet6: Option<String>,
+    #[serde(default)]
+    pub namespace: Option<String>,
+    pub members: Vec<String>,
+    /// OSPF area, backbone by default
+    #[serde(default)]
+    pub area: Option<u32>,
+    /// interface group of the member ends and the bridge ports
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub backend: BridgeBackend,
+    /// OpenFlow rules of an OVS bridge in `ovs-ofctl add-flow` syntax,
+    /// replacing the default `NORMAL` switching, see `ovs`
+    #[serde(default)]
+    pub flows: Vec<String>,
+}
+
+/// VXLAN segment with VNI `vni` between `endpoints`, see `VxlanLink`. The
+/// overlay interfaces are named `<namespace>_<vxlan>`, subnets are given or
+/// allocated like those of a link. `remotes` are VTEP addresses outside
+/// the topology, e.g. of another host describing the same VNI, whose
+/// endpoints then need distinct `host` numbers. Overlay subnets are left
+/// out of `auto_routes` and the routing daemon, the underlay has to reach
+/// the VTEPs.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct VxlanSpec{
+    pub name: String,
+    pub vni: u32,
+    #[serde(default)]
+    pub subnet: String,
+    #[serde(default)]
+    pub subnet6: Option<String>,
+    pub endpoints: Vec<VtepSpec>,
+    #[serde(default)]
+    pub remotes: Vec<String>,
+    /// multicast group the VTEPs join instead of flooding to each other
+    #[serde(default)]
+    pub group: Option<String>,
+    /// UDP port, 4789 if not set
+    #[serde(default)]
+    pub port: Option<u16>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct VtepSpec{
+    pub namespace: String,
+    /// VTEP address, or an interface of the namespace whose address is
+    /// used and which carries the tunnel, e.g. `<namespace>_<link>`
+    pub local: String,
+    /// host number of the overlay addresses, by position if not set
+    #[serde(default)]
+    pub host: Option<u32>,
+}
+
+/// Point-to-point tunnel between two `endpoints`, or from one endpoint to
+/// `remote`, e.g. a router on another host, see `Tunnel`. Interfaces are
+/// named `<namespace>_<tunnel>`, subnets are given or allocated like those
+/// of a link. Tunnels are left out of `auto_routes`, routes over them name
+/// their interfaces as gateways; the routing daemon runs over them like
+/// over links, the underlay has to reach the endpoints.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TunnelSpec{
+    pub name: String,
+    #[serde(default)]
+    pub kind: TunnelKind,
+    #[serde(default)]
+    pub subnet: String,
+    #[serde(default)]
+    pub subnet6: Option<String>,
+    pub endpoints: Vec<TunnelEndSpec>,
+    #[serde(default)]
+    pub remote: Option<String>,
+    /// GRE key
+    #[serde(default)]
+    pub key: Option<u32>,
+    #[serde(default)]
+    pub ttl: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TunnelEndSpec{
+    pub namespace: String,
+    /// tunnel source address, or an interface of the namespace whose
+    /// address is used and which carries the tunnel
+    pub local: String,
+    /// host number of the tunnel addresses, those of a link's ends if not
+    /// set
+    #[serde(default)]
+    pub host: Option<u32>,
+}
+
+/// WireGuard link between two `endpoints`, see `WireguardLink`. Interfaces
+/// are named `<namespace>_<link>`, subnets are given or allocated like
+/// those of a link. Like tunnels, WireGuard links are left out of
+/// `auto_routes` and the underlay has to reach the endpoints.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WireguardSpec{
+    pub name: String,
+    #[serde(default)]
+    pub subnet: String,
+    #[serde(default)]
+    pub subnet6: Option<String>,
+    pub endpoints: Vec<WireguardEndSpec>,
+    /// seconds between keepalives
+    #[serde(default)]
+    pub keepalive: Option<u16>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WireguardEndSpec{
+    pub namespace: String,
+    /// underlay address, or an interface of the namespace whose address
+    /// is used
+    pub local: String,
+    /// UDP port, 51820 if not set
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// prefixes behind the end routed over the link, the peer drops
+    /// packets from anything else but the link's subnets
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+    /// host number of the link addresses, those of a link's ends if not
+    /// set
+    #[serde(default)]
+    pub host: Option<u32>,
+}
+
+/// An existing interface which is moved into a namespace and configured.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct InterfaceSpec{
+    pub name: String,
+    #[serde(default)]
+    pub namespace: Option<String>,
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub ip6: Option<String>,
+    #[serde(default)]
+    pub mtu: Option<u32>,
+    #[serde(default)]
+    pub group: Option<String>,
+    /// transmit queue length and qdisc, the number of queues of an
+    /// existing interface is fixed
+    #[serde(default)]
+    pub queues: Option<QueueSpec>,
+    /// host NIC the interface is, moved or as macvlan or ipvlan child,
+    /// the NIC called `name` moved if not set
+    #[serde(default)]
+    pub passthrough: Option<Passthrough>,
+}
+
+impl InterfaceSpec{
+    /// Name of the NIC on the host the interface comes from.
+    pub fn host_name(&self) -> &str {
+        self.passthrough.as_ref().map_or(self.name.as_str(), |p| p.nic.as_str())
+    }
+}
+
+/// Route installed in `namespace`. Each gateway names the interface whose
+/// address (of the same family as `dst`) is used as nexthop, more than one
+/// gateway makes it an ECMP route. `nexthops` add nexthops with weights,
+/// metrics or without gateway interface, see `Nexthop`. `table` puts the
+/// route into another routing table than main, used by the namespace's
+/// policy `rules`, `vrf` into the table of a VRF of the namespace.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RouteSpec{
+    pub namespace: String,
+    pub dst: String,
+    #[serde(default)]
+    pub gateways: Vec<String>,
+    #[serde(default)]
+    pub nexthops: Vec<NexthopSpec>,
+    #[serde(default)]
+    pub table: Option<u32>,
+    #[serde(default)]
+    pub vrf: Option<String>,
+    /// blackhole, unreachable and prohibit routes drop what they match and
+    /// take neither `gateways` nor `nexthops`
+    #[serde(default)]
+    pub kind: RouteKind,
+}
+
+/// Nexthop of a route, `via` or `address` name the gateway, `dev` the
+/// outgoing interface. Unnumbered links use `dev` alone, or `dev` with the
+/// address of the peer's loopback and `onlink`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct NexthopSpec{
+    /// interface whose address is the gateway, as in `gateways`
+    #[serde(default)]
+    pub via: Option<String>,
+    #[serde(default)]
+    pub address: Option<String>,
+    #[serde(default)]
+    pub dev: Option<String>,
+    #[serde(default)]
+    pub onlink: bool,
+    #[serde(default)]
+    pub weight: Option<u32>,
+    #[serde(default)]
+    pub metric: Option<u32>,
+    /// SRv6 encapsulation, e.g. `{segs: [fc00:2::1, fc00:3::1]}`
+    #[serde(default)]
+    pub seg6: Option<Seg6>,
+    /// SRv6 behavior of the route's destination as local segment, e.g.
+    /// `{action: End}`, needs `dev`
+    #[serde(default)]
+    pub seg6local: Option<Seg6Local>,
+}
+
+/// Service address (VIP) announced by every namespace in `instances`. Each
+/// instance gets the address on its loopback, every other namespace a host
+/// route towards the nearest instances, see `paths::service_routes`.
+/// Routes back to the clients are not part of it, use `auto_routes`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ServiceSpec{
+    pub name: String,
+    /// IPv4 or IPv6 address without prefix length
+    pub address: String,
+    pub instances: Vec<String>,
+}
+
+impl Topology{
+    /// Starts a fluent description of a topology. Modifiers such as `ecmp`,
+    /// `connect`, `ip` or `via` apply to the item added last:
+    ///
+    /// ```no_run
+    /// let topology = router_rs::Topology::builder("lab")
+    ///     .namespace("r1").ecmp()
+    ///     .namespace("r2").ecmp()
+    ///     .link("l1", "10.0.0.0/24").connect("r1", "r2")
+    ///     .route("r1", "192.168.1.0/24").via("r2_l1")
+    ///     .build()?;
+    /// let config = topology.apply()?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn builder(name: &str) -> TopologyBuilder {
+        TopologyBuilder{
+            topology: Topology{
+                name: name.to_string(),
+                ..Default::default()
+            },
+            last: None,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Graphviz DOT picture of the topology, see `graph`.
+    pub fn to_dot(&self) -> Result<String>{
+        graph::dot(self)
+    }
+
+    /// Mermaid flowchart of the topology, see `graph`.
+    pub fn to_mermaid(&self) -> Result<String>{
+        graph::mermaid(self)
+    }
+
+    /// Loads a topology, with the contents of its `modules`, see `compose`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Topology>{
+        let path = path.as_ref();
+        let mut topology = Topology::from_raw_file(path)?;
+        compose::expand(&mut topology, path)?;
+        Ok(topology)
+    }
+
+    /// Loads a topology leaving its `modules` out.
+    pub(crate) fn from_raw_file(path: &Path) -> Result<Topology>{
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| failed!("Failed to read topology {}: {}", path.display(), e))?;
+        let mut topology: Topology = match path.extension().and_then(|e| e.to_str()){
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&data)
+                .map_err(|e| failed!("Failed to parse topology {}: {}", path.display(), e))?,
+            Some("toml") => toml::from_str(&data)
+                .map_err(|e| failed!("Failed to parse topology {}: {}", path.display(), e))?,
+            _ => return Err(failed!("Unsupported topology format {}, expected .yaml, .yml or .toml", path.display())),
+        };
+        if topology.name.is_empty(){
+            if let Some(stem) = path.file_stem(){
+                topology.name = stem.to_string_lossy().to_string();
+            }
+        }
+        Ok(topology)
+    }
+
+    /// Returns copy number `index` of this topology, named `<name>-<index>`
+    /// with every IPv4 prefix moved up by `index * shift` addresses and every
+    /// IPv6 prefix by `index * shift` /64s, so copies never overlap. Host
+    /// interfaces exist only once and make a topology impossible to clone.
+    /// VXLAN remotes and tunnel remotes move only if they are on a subnet of
+    /// the topology, those of other hosts stay, and so do multicast groups,
+    /// which each copy joins in its own namespaces.
+    pub fn stamp(&self, index: u32, shift: u32) -> Result<Topology>{
+        if !self.interfaces.is_empty() {
+            return Err(failed!("Topology {} moves host interfaces into namespaces and cannot be cloned", self.name));
+        }
+        let offset = index.checked_mul(shift)
+            .ok_or_else(|| failed!("Address shift {} for copy {} is out of range", shift, index))?;
+        let mut t = self.clone();
+        t.name = format!("{}-{}", self.name, index);
+        let subnets: Vec<ipnet::IpNet> = self.links.iter().flat_map(|l| std::iter::once(&l.subnet).chain(l.subnet6.iter()))
+            .chain(self.bridges.iter().flat_map(|b| std::iter::once(&b.subnet).chain(b.subnet6.iter())))
+            .filter_map(|s| s.parse().ok())
+            .collect();
+        let internal = |address: &str| address.parse::<std::net::IpAddr>().is_ok_and(|a| subnets.iter().any(|s| s.contains(&a)));
+        if let Some(ipam) = &mut t.ipam{
+            if let Some(pool) = &ipam.pool{
+                ipam.pool = Some(shift_net(pool, offset)?);
+            }
+            if let Some(pool) = &ipam.pool6{
+                ipam.pool6 = Some(shift_net(pool, offset)?);
+            }
+            for pool in [&mut ipam.loopbacks, &mut ipam.loopbacks6].into_iter().flatten(){
+                *pool = shift_net(pool, offset)?;
+            }
+        }
+        for lo in t.namespaces.iter_mut().filter_map(|ns| ns.loopback.as_mut()){
+            for address in [&mut lo.address, &mut lo.address6].into_iter().flatten(){
+                let addr: std::net::IpAddr = address.parse()
+                    .map_err(|e| failed!("Invalid loopback address {}: {}", address, e))?;
+                let net: ipnet::IpNet = shift_net(&ipnet::IpNet::from(addr).to_string(), offset)?.parse()?;
+                *address = net.addr().to_string();
+            }
+        }
+        for nat64 in t.namespaces.iter_mut().filter_map(|ns| ns.nat64.as_mut()){
+            nat64.prefix = Some(shift_net(&nat64.prefix()?.to_string(), offset)?);
+            nat64.pool = Some(shift_net(&nat64.pool()?.to_string(), offset)?);
+        }
+        for l in &mut t.links{
+            if !l.subnet.is_empty() {
+                l.subnet = shift_net(&l.subnet, offset)?;
+            }
+            if let Some(subnet6) = &l.subnet6{
+                l.subnet6 = Some(shift_net(subnet6, offset)?);
+            }
+            for address in l.addresses.values_mut().flatten(){
+                *address = shift_addr(address, offset)?;
+            }
+        }
+        for b in &mut t.bridges{
+            if !b.subnet.is_empty() {
+                b.subnet = shift_net(&b.subnet, offset)?;
+            }
+            if let Some(subnet6) = &b.subnet6{
+                b.subnet6 = Some(shift_net(subnet6, offset)?);
+            }
+        }
+        for v in &mut t.vxlans{
+            if !v.subnet.is_empty() {
+                v.subnet = shift_net(&v.subnet, offset)?;
+            }
+            if let Some(subnet6) = &v.subnet6{
+                v.subnet6 = Some(shift_net(subnet6, offset)?);
+            }
+            for e in &mut v.endpoints{
+                if let Ok(addr) = e.local.parse::<std::net::IpAddr>() {
+                    let net: ipnet::IpNet = shift_net(&ipnet::IpNet::from(addr).to_string(), offset)?.parse()?;
+                    e.local = net.addr().to_string();
+                }
+            }
+            for remote in v.remotes.iter_mut().filter(|r| internal(r)){
+                *remote = shift_addr(remote, offset)?;
+            }
+        }
+        for tun in &mut t.tunnels{
+            if !tun.subnet.is_empty() {
+                tun.subnet = shift_net(&tun.subnet, offset)?;
+            }
+            if let Some(subnet6) = &tun.subnet6{
+                tun.subnet6 = Some(shift_net(subnet6, offset)?);
+            }
+            for e in &mut tun.endpoints{
+                if let Ok(addr) = e.local.parse::<std::net::IpAddr>() {
+                    let net: ipnet::IpNet = shift_net(&ipnet::IpNet::from(addr).to_string(), offset)?.parse()?;
+                    e.local = net.addr().to_string();
+                }
+            }
+            if let Some(remote) = tun.remote.as_mut().filter(|r| internal(r)) {
+                *remote = shift_addr(remote, offset)?;
+            }
+        }
+        for w in &mut t.wireguards{
+            if !w.subnet.is_empty() {
+                w.subnet = shift_net(&w.subnet, offset)?;
+            }
+            if let Some(subnet6) = &w.subnet6{
+                w.subnet6 = Some(shift_net(subnet6, offset)?);
+            }
+            for e in &mut w.endpoints{
+                if let Ok(addr) = e.local.parse::<std::net::IpAddr>() {
+                    let net: ipnet::IpNet = shift_net(&ipnet::IpNet::from(addr).to_string(), offset)?.parse()?;
+                    e.local = net.addr().to_string();
+                }
+                for prefix in &mut e.allowed_ips{
+                    *prefix = shift_net(prefix, offset)?;
+                }
+            }
+        }
+        for r in &mut t.routes{
+            r.dst = shift_net(&r.dst, offset)?;
+            for address in r.nexthops.iter_mut().filter_map(|n| n.address.as_mut()){
+                *address = shift_addr(address, offset)?;
+            }
+        }
+        for rule in t.namespaces.iter_mut().flat_map(|ns| ns.rules.iter_mut()){
+            for prefix in [&mut rule.from, &mut rule.to].into_iter().flatten(){
+                *prefix = shift_net(prefix, offset)?;
+            }
+        }
+        for svc in &mut t.services{
+            let addr: std::net::IpAddr = svc.address.parse()
+                .map_err(|e| failed!("Invalid address {} of service {}: {}", svc.address, svc.name, e))?;
+            let net: ipnet::IpNet = shift_net(&ipnet::IpNet::from(addr).to_string(), offset)?.parse()?;
+            svc.address = net.addr().to_string();
+        }
+        for c in &mut t.checks{
+            if let Ok(addr) = c.to.parse::<std::net::IpAddr>() {
+                let net: ipnet::IpNet = shift_net(&ipnet::IpNet::from(addr).to_string(), offset)?.parse()?;
+                c.to = net.addr().to_string();
+            }
+        }
+        for bgp in t.namespaces.iter_mut().filter_map(|ns| ns.bgp.as_mut()){
+            for net in &mut bgp.networks{
+                *net = shift_net(net, offset)?;
+            }
+            for n in &mut bgp.neighbors{
+                if let Some(address) = &n.address{
+                    let addr: std::net::IpAddr = address.parse()
+                        .map_err(|e| failed!("Invalid address {} of BGP neighbor {}: {}", address, n.peer, e))?;
+                    let net: ipnet::IpNet = shift_net(&ipnet::IpNet::from(addr).to_string(), offset)?.parse()?;
+                    n.address = Some(net.addr().to_string());
+                }
+            }
+        }
+        Ok(t)
+    }
+
+    /// Creates the topology on the host and returns its registry.
+    pub fn apply(&self) -> Result<Config>{
+        self.apply_with(Config::new(self.name.clone()))
+    }
+
+    /// Like `apply`, but starts from a caller prepared registry, e.g. one
+    /// with `pool` enabled. If any step fails, everything created so far is
+    /// rolled back.
+    pub fn apply_with(&self, config: Config) -> Result<Config>{
+        let mut phase = Instant::now();
+        validate::run(self)?;
+        if !config.host_changes {
+            let changes = preflight::host_changes(self, false);
+            if !changes.is_empty() {
+                return Err(RouterError::HostChanges{ topology: self.name.clone(), changes });
+            }
+        }
+        if config.preflight {
+            preflight::run(self, false, &config.modules)?;
+        }
+        if !Namespace::list(&self.name)?.is_empty() {
+            return Err(RouterError::TopologyExists(self.name.clone()));
+        }
+        config.phase("preflight", &mut phase);
+        let result = self.build(&config).and_then(|_| {
+            let mut phase = Instant::now();
+            State::from_config(&config).save()?;
+            config.phase("state", &mut phase);
+            Ok(())
+        });
+        if let Err(e) = result {
+            let _ = neighbor::restore(&self.name);
+            if let Err(r) = config.transaction().rollback() {
+                return Err(RouterError::RollbackFailed{ error: Box::new(e), rollback: r.to_string() });
+            }
+            return Err(e);
+        }
+        config.transaction().commit();
+        Ok(config)
+    }
+
+    /// Like `apply`, but the topology is destroyed when the returned guard
+    /// goes out of scope, see `guard`.
+    pub fn guard(&self) -> Result<TopologyGuard>{
+        self.guard_with(Config::new(self.name.clone()))
+    }
+
+    /// `guard` starting from a caller prepared registry, see `apply_with`.
+    pub fn guard_with(&self, config: Config) -> Result<TopologyGuard>{
+        TopologyGuard::apply(self, config)
+    }
+
+    /// Deletes all namespaces of the topology called `name`, which also
+    /// removes the veth pairs between them, and its saved state.
+    pub fn destroy(name: &str) -> Result<()>{
+        let namespaces = state::namespaces(name)?;
+        if namespaces.is_empty() {
+            return Err(RouterError::TopologyNotFound(name.to_string()));
+        }
+        let state = State::load(name)?;
+        if let Some(state) = &state{
+            hooks::teardown(name, &state.hooks);
+        }
+        neighbor::restore(name)?;
+        // processes keep a namespace alive after it's deleted, teardown
+        // stops them first
+        let mut resources: Vec<Resource> = daemon::dirs(name)?.into_iter()
+            .map(|dir| Resource::Daemon{ dir })
+            .collect();
+        // moved NICs go back to the host as they were before their
+        // namespaces are deleted
+        resources.extend(state.into_iter().flat_map(|s| s.nics).map(Resource::Moved));
+        for ns in namespaces{
+            dns::unconfigure(&ns)?;
+            resources.push(Resource::Namespace{ netns: ns, pooled: false });
+        }
+        transaction::undo_all(resources)?;
+        daemon::stop_topology(name)?;
+        State::remove(name)
+    }
+
+    /// Creates all namespaces, links, interfaces and routes and registers
+    /// them in `config`.
+    pub fn build(&self, config: &Config) -> Result<()>{
+        let mut phase = Instant::now();
+        for h in &self.hooks{
+            h.check()?;
+            config.hooks.script(h.clone());
+        }
+        for ns in &self.namespaces{
+            if let Some(clock) = &ns.clock{
+                clock.check()?;
+            }
+            if ns.bgp.is_some() && self.daemon.is_none() {
+                return Err(RouterError::Invalid(format!("Namespace {} declares BGP but the topology runs no daemon", ns.name)));
+            }
+            if ns.bgp.is_some() && ns.stub {
+                return Err(RouterError::Invalid(format!("Namespace {} is a stub and cannot run BGP", ns.name)));
+            }
+            if ns.bgp.is_some() && ns.p4.is_some() {
+                return Err(RouterError::Invalid(format!("Namespace {} runs a P4 program and cannot run BGP", ns.name)));
+            }
+            if let Some(fwd) = &ns.forwarder{
+                if ns.bgp.is_some() || ns.p4.is_some() {
+                    return Err(RouterError::Invalid(format!("Namespace {} runs a forwarder and cannot run BGP or a P4 program", ns.name)));
+                }
+                if fwd.command.is_empty() {
+                    return Err(RouterError::Invalid(format!("Forwarder of namespace {} has no command", ns.name)));
+                }
+            }
+            if ns.container.is_some() && (ns.bgp.is_some() || ns.p4.is_some() || ns.forwarder.is_some()) {
+                return Err(RouterError::Invalid(format!("Namespace {} is a container and cannot run BGP, a P4 program or a forwarder", ns.name)));
+            }
+            if let Some(nat64) = &ns.nat64{
+                if ns.p4.is_some() || ns.forwarder.is_some() {
+                    return Err(RouterError::Invalid(format!("Namespace {} translates NAT64 in the kernel and cannot run a P4 program or a forwarder", ns.name)));
+                }
+                nat64.check().map_err(|e| failed!("Namespace {}: {}", ns.name, e))?;
+            }
+            if let Some(dns) = &ns.dns{
+                dns.check().map_err(|e| failed!("Namespace {}: {}", ns.name, e))?;
+                if let Some(client) = dns.clients.iter().find(|c| !self.namespaces.iter().any(|n| n.name == **c)) {
+                    return Err(RouterError::Invalid(format!("Namespace {}: DNS client {} not found", ns.name, client)));
+                }
+            }
+            for (n, p) in ns.processes.iter().enumerate(){
+                p.check().map_err(|e| failed!("Namespace {}: {}", ns.name, e))?;
+                if ns.processes[..n].iter().any(|o| o.name == p.name) {
+                    return Err(RouterError::Invalid(format!("Namespace {} has two processes named {}", ns.name, p.name)));
+                }
+            }
+        }
+        let mut specs = Vec::new();
+        for ns in &self.namespaces{
+            let pid = match &ns.container{
+                Some(c) => Some(c.pid().map_err(|e| failed!("Namespace {}: {}", ns.name, e))?),
+                None => None,
+            };
+            let sysctls = self.sysctls(ns).map_err(|e| failed!("Namespace {}: {}", ns.name, e))?;
+            specs.push((ns.name.clone(), sysctls, pid));
+        }
+        Namespace::new_all(&specs, config)?;
+        for ns in &self.namespaces{
+            clock::record(&Namespace::netns_name(&self.name, &ns.name), ns.clock.as_ref())?;
+        }
+        match &self.neighbor_gc{
+            Some(gc) => gc.apply(&self.name)?,
+            None => neighbor::restore(&self.name)?,
+        }
+        if let Some(ipam) = &self.ipam{
+            config.ipam().configure(ipam)?;
+        }
+        for ns in &self.namespaces{
+            if let Some(lo) = &ns.loopback{
+                let (ip, ip6) = config.ipam().assign_loopback(lo.address.clone(), lo.address6.clone())
+                    .map_err(|e| failed!("Loopback of {}: {}", ns.name, e))?;
+                loopback::create(namespace(config, &ns.name)?, ip, ip6, config)?;
+            }
+        }
+        self.start_translators(config)?;
+        config.phase("namespaces", &mut phase);
+        // hand-assigned subnets first, so allocated subnets never take the
+        // place of a later hand-assigned one
+        let mut vxlans = Vec::new();
+        let mut tunnels = Vec::new();
+        let mut wireguards = Vec::new();
+        let mut attached = Vec::new();
+        for auto in [false, true]{
+            for l in self.links.iter().filter(|l| l.subnet.is_empty() == auto){
+                if l.endpoints.len() != 2 {
+                    return Err(RouterError::Invalid(format!("Link {} needs exactly two endpoints, got {}", l.name, l.endpoints.len())));
+                }
+                if let Some(end) = l.endpoint_qos.keys().find(|e| !l.endpoints.contains(e)) {
+                    return Err(RouterError::Invalid(format!("Link {} has endpoint_qos for {}, which is none of its endpoints", l.name, end)));
+                }
+                let queues = self.link_queues(l);
+                queues.validate().map_err(|e| failed!("Link {}: {}", l.name, e))?;
+                let ns1 = namespace(config, &l.endpoints[0])?;
+                let ns2 = namespace(config, &l.endpoints[1])?;
+                let link = Link::new(l.name.clone(), l.subnet.clone(), l.subnet6.clone(), l.ends()?, config)?;
+                let macs = self.link_macs(l)?;
+                let (i1, i2) = match &l.bond{
+                    Some(_) if queues.multiqueue() => {
+                        return Err(RouterError::Invalid(format!("Link {} is bonded, numtxqueues and numrxqueues only apply to veth links", l.name)));
+                    },
+                    Some(bond) => {
+                        bond.check().map_err(|e| failed!("Link {}: {}", l.name, e))?;
+                        link.bond(ns1.clone(), ns2.clone(), bond, &macs, config)?
+                    },
+                    None => link.attach(ns1.clone(), ns2.clone(), &macs, &queues, config)?,
+                };
+                attached.push((l, queues, [(ns1, i1), (ns2, i2)]));
+            }
+            // batched links exist from here on
+            config.flush()?;
+            for (l, queues, ends) in attached.drain(..){
+                let offloads = match (&self.offloads, &l.offloads){
+                    (Some(offloads), Some(over)) => Some(offloads.merge(over)),
+                    (offloads, over) => over.clone().or(offloads.clone()),
+                };
+                for (ns, intf) in &ends{
+                    if let Some(offloads) = &offloads{
+                        offloads.apply(&ns.netns, &intf.name)
+                            .map_err(|e| failed!("Link {}: {}", l.name, e))?;
+                    }
+                    match l.qos_at(&ns.name){
+                        Some(qos) => qos.apply(&ns.netns, &intf.name)
+                            .map_err(|e| failed!("Link {}: {}", l.name, e))?,
+                        // settings dropped from the description
+                        None if config.reconcile => qos::clear(&ns.netns, &intf.name)?,
+                        None => {},
+                    }
+                    // qos brings its own root qdisc
+                    let queues = match l.qos_at(&ns.name){
+                        Some(_) => QueueSpec{ qdisc: None, ..queues.clone() },
+                        None => queues.clone(),
+                    };
+                    intf.set_queues(&queues)
+                        .map_err(|e| e.context(format!("Link {}", l.name)))?;
+                }
+            }
+            for b in self.bridges.iter().filter(|b| b.subnet.is_empty() == auto){
+                let ns = match &b.namespace{
+                    Some(ns) => Some(namespace(config, ns)?),
+                    None => None,
+                };
+                let members = b.members.iter()
+                    .map(|m| namespace(config, m))
+                    .collect::<Result<Vec<_>>>()?;
+                if !b.flows.is_empty() && b.backend != BridgeBackend::Ovs {
+                    return Err(RouterError::Invalid(format!("Bridge {} has flows but is no OVS bridge", b.name)));
+                }
+                let bridge = Bridge::new(b.name.clone(), b.subnet.clone(), b.subnet6.clone(), ns, b.backend, members.len(), config)?;
+                let interfaces = bridge.attach(&members, config)?;
+                if let Some(offloads) = &self.offloads{
+                    for (ns, intf) in members.iter().zip(&interfaces){
+                        offloads.apply(&ns.netns, &intf.name)
+                            .and_then(|_| offloads.apply(&bridge.namespace.netns, &interface::name(&b.name, &ns.name)))
+                            .map_err(|e| failed!("Bridge {}: {}", b.name, e))?;
+                    }
+                }
+                if let Some(switch) = bridge.switch(&config.name){
+                    // flows dropped from the description go back to switching
+                    let normal = ["priority=0,actions=NORMAL".to_string()];
+                    switch.replace_flows(if b.flows.is_empty() { &normal } else { &b.flows })?;
+                }
+            }
+            for v in self.vxlans.iter().filter(|v| v.subnet.is_empty() == auto){
+                let group = v.group.as_ref()
+                    .map(|g| g.parse().map_err(|e| failed!("Invalid group {} of VXLAN link {}: {}", g, v.name, e)))
+                    .transpose()?;
+                let link = VxlanLink::new(v.name.clone(), v.vni, v.subnet.clone(), v.subnet6.clone(), group, v.port.unwrap_or(VxlanLink::PORT), config)?;
+                vxlans.push((v, link));
+            }
+            for t in self.tunnels.iter().filter(|t| t.subnet.is_empty() == auto){
+                let tunnel = Tunnel::new(t.name.clone(), t.kind, t.subnet.clone(), t.subnet6.clone(), t.key, t.ttl, config)?;
+                tunnels.push((t, tunnel));
+            }
+            for w in self.wireguards.iter().filter(|w| w.subnet.is_empty() == auto){
+                let link = WireguardLink::new(w.name.clone(), w.subnet.clone(), w.subnet6.clone(), w.keepalive, config)?;
+                wireguards.push((w, link));
+            }
+        }
+        config.phase("links", &mut phase);
+        // NICs moved by an earlier build, to be given back as they were
+        let nics = match config.reconcile{
+            true => State::load(&self.name)?.map(|s| s.nics).unwrap_or_default(),
+            false => Vec::new(),
+        };
+        for i in &self.interfaces{
+            let ns = match &i.namespace{
+                Some(ns) => Some(namespace(config, ns)?),
+                None => None,
+            };
+            match (&ns, &i.passthrough){
+                (Some(ns), p) => {
+                    let p = p.clone().unwrap_or_else(|| Passthrough{ nic: i.name.clone(), ..Default::default() });
+                    p.setup(&i.name, ns, &nics, config)
+                        .map_err(|e| e.context(format!("Interface {}", i.name)))?;
+                },
+                (None, Some(_)) => return Err(RouterError::Invalid(format!("Interface {} passes a host interface through but has no namespace", i.name))),
+                (None, None) => {},
+            }
+            let intf = Interface::new(i.name.clone(), ns, i.ip.clone(), i.ip6.clone(), i.mtu, config)?;
+            if let Some(queues) = &i.queues{
+                if queues.multiqueue() {
+                    return Err(RouterError::Invalid(format!("Interface {} exists, its numtxqueues and numrxqueues cannot be set", i.name)));
+                }
+                intf.set_queues(queues)?;
+            }
+        }
+        // tunnels start from addresses of the links and host interfaces,
+        // VXLAN links may run over GRE and IP-in-IP tunnels
+        for (spec, tunnel) in tunnels{
+            let mut ends = Vec::new();
+            for e in &spec.endpoints{
+                let ns = namespace(config, &e.namespace)?;
+                let (local, dev) = underlay(config, &ns, &e.local)
+                    .map_err(|err| failed!("Tunnel {}: {}", spec.name, err))?;
+                ends.push(TunnelEnd{ namespace: ns, local, dev, host: e.host });
+            }
+            let remote = spec.remote.as_ref()
+                .map(|r| r.parse().map_err(|e| failed!("Invalid remote {} of tunnel {}: {}", r, spec.name, e)))
+                .transpose()?;
+            tunnel.attach(&ends, remote, config)?;
+        }
+        for (spec, link) in wireguards{
+            let mut ends = Vec::new();
+            for e in &spec.endpoints{
+                let ns = namespace(config, &e.namespace)?;
+                let (local, _) = underlay(config, &ns, &e.local)
+                    .map_err(|err| failed!("WireGuard link {}: {}", spec.name, err))?;
+                let allowed_ips = e.allowed_ips.iter()
+                    .map(|p| p.parse().map_err(|err| failed!("Invalid allowed IP {} of WireGuard link {}: {}", p, spec.name, err)))
+                    .collect::<Result<Vec<ipnet::IpNet>>>()?;
+                ends.push(WireguardEnd{ namespace: ns, local, port: e.port.unwrap_or(WireguardLink::PORT), allowed_ips, host: e.host });
+            }
+            link.attach(&ends, config)?;
+        }
+        for (spec, link) in vxlans{
+            let mut vteps = Vec::new();
+            for e in &spec.endpoints{
+                let ns = namespace(config, &e.namespace)?;
+                let (local, dev) = underlay(config, &ns, &e.local)
+                    .map_err(|err| failed!("VXLAN link {}: {}", spec.name, err))?;
+                vteps.push(Vtep{ namespace: ns, local, dev, host: e.host });
+            }
+            let remotes = spec.remotes.iter()
+                .map(|r| r.parse().map_err(|e| failed!("Invalid remote VTEP {} of VXLAN link {}: {}", r, spec.name, e)))
+                .collect::<Result<Vec<std::net::IpAddr>>>()?;
+            link.attach(&vteps, &remotes, config)?;
+        }
+        self.apply_groups(config)?;
+        for spec in &self.namespaces{
+            for v in &spec.vrfs{
+                let ns = namespace(config, &spec.name)?;
+                Vrf::new(v.name.clone(), v.table, ns, v.interfaces.clone(), config)?;
+            }
+        }
+        for spec in &self.namespaces{
+            let ns = namespace(config, &spec.name)?;
+            let mut flowtable = Vec::new();
+            if spec.flowtable {
+                flowtable = config.interfaces.values()
+                    .filter(|i| i.namespace.as_ref().is_some_and(|n| n.netns == ns.netns))
+                    .filter(|i| i.name != loopback::name(&spec.name) && i.name != nat64::name(&spec.name))
+                    .map(|i| i.name.clone())
+                    .collect();
+                flowtable.sort();
+            }
+            match firewall::ruleset(spec.nat.as_ref(), spec.firewall.as_ref(), &flowtable)?{
+                Some(ruleset) => firewall::apply(&ns.netns, Some(&ruleset))
+                    .map_err(|e| failed!("Namespace {}: {}", spec.name, e))?,
+                // firewall dropped from the description
+                None if config.reconcile && firewall::present(&ns.netns) => firewall::apply(&ns.netns, None)?,
+                None => {},
+            }
+        }
+        for spec in &self.namespaces{
+            let ns = namespace(config, &spec.name)?;
+            // ports given, or all interfaces of the namespace but the
+            // loopback and the NAT64 device
+            let ports = |ports: &[String]| {
+                let mut ports = ports.to_vec();
+                if ports.is_empty() {
+                    ports = config.interfaces.values()
+                        .filter(|i| i.namespace.as_ref().is_some_and(|n| n.netns == ns.netns))
+                        .filter(|i| i.name != loopback::name(&spec.name) && i.name != nat64::name(&spec.name))
+                        .map(|i| i.name.clone())
+                        .collect();
+                    ports.sort();
+                }
+                ports
+            };
+            if let Some(p4) = &spec.p4{
+                let switch = P4Switch::new(&self.name, &spec.name, &ns.netns);
+                if switch.start(Path::new(&p4.program), &ports(&p4.ports), &p4.commands)
+                    .map_err(|e| failed!("Namespace {}: {}", spec.name, e))? {
+                    config.transaction().record(Resource::Daemon{ dir: switch.dir.clone() });
+                }
+            }
+            if let Some(fwd) = &spec.forwarder{
+                let ports = ports(&fwd.ports);
+                let forwarder = Forwarder::new(&self.name, &spec.name, &ns.netns);
+                if forwarder.start(fwd.kind, &fwd.kind.expand(&fwd.command, &ports), &ports)
+                    .map_err(|e| failed!("Namespace {}: {}", spec.name, e))? {
+                    config.transaction().record(Resource::Daemon{ dir: forwarder.dir.clone() });
+                }
+            }
+        }
+        for (n, spec) in self.mirrors.iter().enumerate(){
+            if self.mirrors[..n].iter().any(|o| o.name == spec.name) {
+                return Err(RouterError::Invalid(format!("Mirror {} is declared twice", spec.name)));
+            }
+            for ns in std::iter::once(&spec.namespace).chain(spec.monitor.iter()){
+                namespace(config, ns)?;
+            }
+            // interfaces may be given by the link or bridge they belong to
+            // or by their logical name, both resolve to the kernel's
+            let kernel = |name: &String| config.interface(&interface::name(&spec.namespace, name))
+                .or_else(|| config.interface(name))
+                .map_or(name.clone(), |i| i.name.clone());
+            let spec = MirrorSpec{ interface: kernel(&spec.interface), to: spec.to.as_ref().map(kernel), ..spec.clone() };
+            let mirror = Mirror::new(&self.name, spec)?;
+            if mirror.start()? {
+                if let Some((local, _)) = mirror.spec.span() {
+                    config.transaction().record(Resource::Veth{ name: local, netns: mirror.netns.clone() });
+                }
+            }
+            config.mirrors.push(Arc::new(mirror));
+        }
+        config.phase("interfaces", &mut phase);
+        for svc in &self.services{
+            if svc.instances.is_empty() {
+                return Err(RouterError::Invalid(format!("Service {} has no instances", svc.name)));
+            }
+            for instance in &svc.instances{
+                namespace(config, instance)?.add_service_address(&svc.address, config)?;
+            }
+        }
+        let described = self.route_specs(config)?;
+        let saved = match config.reconcile{
+            true => State::load(&self.name)?,
+            false => None,
+        };
+        // routes set on the running topology stay unless the description
+        // now has a route to their destination or lost their namespace
+        let mut set = Vec::new();
+        if let Some(saved) = &saved {
+            for r in saved.set_routes.iter().cloned(){
+                let table = self.table_of(&r)?;
+                let mut replaced = false;
+                for d in &described{
+                    replaced |= d.namespace == r.namespace && d.dst == r.dst && self.table_of(d)? == table;
+                }
+                if !replaced && self.namespaces.iter().any(|n| n.name == r.namespace) {
+                    set.push(r);
+                }
+            }
+        }
+        let count = described.len();
+        for (i, r) in described.into_iter().chain(set.iter().cloned()).enumerate(){
+            let ns = namespace(config, &r.namespace)?;
+            let interface = |gw: &String| match config.interface(gw){
+                Some(intf) => Ok(intf.clone()),
+                None => Err(failed!("Gateway interface {} of route {} in {} not found", gw, r.dst, r.namespace)),
+            };
+            let mut gateway = Vec::new();
+            for gw in &r.gateways{
+                gateway.push(Nexthop::from(interface(gw)?));
+            }
+            for n in &r.nexthops{
+                let address = n.address.as_ref()
+                    .map(|a| a.parse().map_err(|e| failed!("Invalid nexthop {} of route {} in {}: {}", a, r.dst, r.namespace, e)))
+                    .transpose()?;
+                gateway.push(Nexthop{
+                    via: n.via.as_ref().map(interface).transpose()?,
+                    address,
+                    dev: n.dev.clone(),
+                    onlink: n.onlink,
+                    weight: n.weight,
+                    metric: n.metric,
+                    seg6: n.seg6.clone(),
+                    seg6local: n.seg6local.clone(),
+                });
+            }
+            if r.kind != RouteKind::Unicast && !gateway.is_empty() {
+                return Err(RouterError::Invalid(format!("{} route {} in {} cannot have gateways or nexthops", r.kind, r.dst, r.namespace)));
+            }
+            let route = Route{
+                dst: r.dst.clone(),
+                gateway,
+                table: self.table_of(&r)?,
+                kind: r.kind,
+            };
+            Rib::of(&ns, config).set(route.clone())?;
+            if i < count {
+                config.routes.push((ns, route));
+            }
+        }
+        for r in set{
+            config.set_routes.push(r);
+        }
+        for spec in &self.namespaces{
+            let ns = namespace(config, &spec.name)?;
+            let rib = Rib::of(&ns, config);
+            if let Some(route) = spec.nat.as_ref().map(firewall::default_route).transpose()?.flatten() {
+                rib.set(route)?;
+            }
+        }
+        // routes of others, neither saved nor set on the topology, stay
+        if config.reconcile {
+            for ns in config.namespaces.values(){
+                let mut owned = Vec::new();
+                if let Some(s) = &saved {
+                    owned.extend(s.routes.iter().filter(|r| r.netns == ns.netns).map(|r| (r.dst.clone(), r.table)));
+                    owned.extend(s.set_routes.iter().filter(|r| r.namespace == ns.name).map(|r| (r.dst.clone(), r.table)));
+                }
+                Rib::of(&ns, config).keep_foreign(config, &owned)?;
+            }
+        }
+        // routes missing are added and stale ones, e.g. dropped from the
+        // description, removed
+        let ribs: Vec<Arc<Rib>> = config.ribs.values().collect();
+        let results = parallel::map(&ribs, config.parallelism.routes, |rib| rib.sync(config));
+        results.into_iter().collect::<Result<Vec<_>, _>>()?;
+        config.phase("routes", &mut phase);
+        for spec in &self.namespaces{
+            let ns = namespace(config, &spec.name)?;
+            let interfaces: Vec<String> = config.interfaces.values()
+                .filter(|i| i.namespace.as_ref().is_some_and(|n| n.netns == ns.netns))
+                .map(|i| i.name.clone())
+                .collect();
+            if let Some(neighbor) = &spec.neighbor{
+                ns.tune_neighbors(neighbor, &interfaces)
+                    .map_err(|e| failed!("Namespace {}: {}", spec.name, e))?;
+            }
+            if let Some(icmp) = &spec.icmp{
+                ns.configure_icmp(icmp, &interfaces)
+                    .map_err(|e| failed!("Namespace {}: {}", spec.name, e))?;
+            }
+            match &spec.tcp{
+                Some(t) => ns.tune_tcp(t, self.link_bandwidth(&spec.name), &interfaces)
+                    .map_err(|e| failed!("Namespace {}: {}", spec.name, e))?,
+                // pacing dropped from the description
+                None if config.reconcile => {
+                    for interface in &interfaces{
+                        tcp::pace(&ns.netns, interface, false)?;
+                    }
+                },
+                None => {},
+            }
+            if spec.ra.is_some() {
+                ns.accept_ra(&interfaces)
+                    .map_err(|e| failed!("Namespace {}: {}", spec.name, e))?;
+            }
+            let nexthops: Vec<Nexthop> = config.routes.iter().filter(|(n, _)| n.netns == ns.netns).flat_map(|(_, r)| r.gateway).collect();
+            if spec.srv6 || nexthops.iter().any(|n| n.seg6.is_some() || n.seg6local.is_some()) {
+                let vrf = nexthops.iter().any(|n| n.seg6local.as_ref().is_some_and(|s| s.vrf()));
+                ns.enable_srv6(&interfaces, vrf)
+                    .map_err(|e| failed!("Namespace {}: {}", spec.name, e))?;
+            }
+        }
+        for spec in &self.namespaces{
+            let ns = namespace(config, &spec.name)?;
+            policy::apply(&ns.netns, &spec.rules, config.reconcile)
+                .map_err(|e| failed!("Namespace {}: {}", spec.name, e))?;
+            for rule in &spec.rules{
+                config.rules.push((ns.clone(), rule.clone()));
+            }
+        }
+        self.start_advertisers(config)?;
+        self.start_responders(config)?;
+        match self.daemon{
+            Some(kind) => self.start_daemons(kind, config)?,
+            // daemon dropped from the description, other processes stay
+            None if config.reconcile => {
+                for d in RoutingDaemon::list(&self.name)?{
+                    d.stop()?;
+                }
+            },
+            None => {},
+        }
+        self.start_processes(config)?;
+        config.phase("daemons", &mut phase);
+        Ok(())
+    }
+
+    /// Starts the processes of every namespace under their supervisor and
+    /// stops those no longer described, see `process`. When reconciling,
+    /// processes with an unchanged spec are left alone.
+    fn start_processes(&self, config: &Config) -> Result<()>{
+        for p in Process::list(&self.name)?{
+            let described = self.namespaces.iter()
+                .any(|n| n.name == p.namespace && n.processes.iter().any(|s| s.name == p.spec.name));
+            if !described {
+                p.stop()?;
+            }
+        }
+        for spec in &self.namespaces{
+            if spec.processes.is_empty() {
+                continue;
+            }
+            let ns = namespace(config, &spec.name)?;
+            for p in &spec.processes{
+                let process = Process::new(&self.name, &spec.name, &ns.netns, p);
+                if process.start()
+                    .map_err(|e| failed!("Namespace {}: {}", spec.name, e))? {
+                    config.transaction().record(Resource::Daemon{ dir: process.dir.clone() });
+                }
+                config.processes.push(Arc::new(process));
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the tun device of every NAT64 namespace and starts tayga on
+    /// it, stops it in namespaces without, see `nat64`.
+    fn start_translators(&self, config: &Config) -> Result<()>{
+        for ns in Translator::list(&self.name)?{
+            if !self.namespaces.iter().any(|n| n.name == ns && n.nat64.is_some()) {
+                daemon::stop_dir(&Translator::dir(&self.name, &ns))?;
+            }
+        }
+        for spec in &self.namespaces{
+            let Some(nat64) = &spec.nat64 else {
+                continue;
+            };
+            let ns = namespace(config, &spec.name)?;
+            let device = nat64::create(ns.clone(), nat64, config)
+                .map_err(|e| failed!("Namespace {}: {}", spec.name, e))?;
+            let translator = Translator::new(&self.name, &spec.name, &ns.netns);
+            if translator.start(&nat64.tayga_config(&device.name, &translator.dir)?)
+                .map_err(|e| failed!("Namespace {}: {}", spec.name, e))? {
+                config.transaction().record(Resource::Daemon{ dir: translator.dir.clone() });
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts the DNS server of every namespace with `dns` and points the
+    /// resolv.conf of its clients at it, stops it in namespaces without,
+    /// see `dns`.
+    fn start_responders(&self, config: &Config) -> Result<()>{
+        let running = Responder::list(&self.name)?;
+        for ns in &running{
+            if !self.namespaces.iter().any(|n| n.name == *ns && n.dns.is_some()) {
+                daemon::stop_dir(&Responder::dir(&self.name, ns))?;
+            }
+        }
+        let state = State::from_config(config);
+        // nameservers and zones of each client
+        let mut resolvers: BTreeMap<String, (Vec<std::net::IpAddr>, Vec<String>)> = BTreeMap::new();
+        for spec in &self.namespaces{
+            let Some(dns) = &spec.dns else {
+                continue;
+            };
+            let ns = namespace(config, &spec.name)?;
+            let responder = Responder::new(&self.name, &spec.name, &ns.netns);
+            if responder.start(dns, &state)
+                .map_err(|e| failed!("Namespace {}: {}", spec.name, e))? {
+                config.transaction().record(Resource::Daemon{ dir: responder.dir.clone() });
+            }
+            for client in self.namespaces.iter().filter(|c| dns.clients.is_empty() || dns.clients.contains(&c.name)){
+                let Some(address) = dns::nameserver(&state, &spec.name, &client.name) else {
+                    continue;
+                };
+                let (nameservers, zones) = resolvers.entry(client.name.clone()).or_default();
+                nameservers.push(address);
+                if !zones.contains(&dns.zone()) {
+                    zones.push(dns.zone());
+                }
+            }
+        }
+        for spec in &self.namespaces{
+            let ns = namespace(config, &spec.name)?;
+            match resolvers.get(&spec.name){
+                Some((nameservers, zones)) => dns::configure(&ns.netns, nameservers, zones)?,
+                // pointed at a server the reconcile removed
+                None if !running.is_empty() => dns::unconfigure(&ns.netns)?,
+                None => {},
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts radvd in every router with hosts learning their default route
+    /// from it and stops it in those without, see `ra`.
+    fn start_advertisers(&self, config: &Config) -> Result<()>{
+        let routers = ra::advertisements(self, &subnets(config))?;
+        for ns in Advertiser::list(&self.name)?{
+            if !routers.iter().any(|(r, _)| *r == ns) {
+                daemon::stop_dir(&Advertiser::dir(&self.name, &ns))?;
+            }
+        }
+        for (router, advertisements) in &routers{
+            let ns = namespace(config, router)?;
+            let advertiser = Advertiser::new(&self.name, router, &ns.netns);
+            if advertiser.start(&ra::radvd_config(advertisements))
+                .map_err(|e| failed!("Namespace {}: {}", router, e))? {
+                config.transaction().record(Resource::Daemon{ dir: advertiser.dir.clone() });
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts a daemon in every namespace with addressed interfaces. OSPF
+    /// costs follow `paths::end_cost`, the router id is the IPv4 address
+    /// of the loopback, else the lowest IPv4 address of the namespace. When
+    /// reconciling, daemons whose config is unchanged keep running, the
+    /// others are restarted.
+    /// Links between different AS numbers are left out of OSPF.
+    fn start_daemons(&self, kind: DaemonKind, config: &Config) -> Result<()>{
+        let mut namespaces: Vec<Arc<Namespace>> = config.namespaces.values().collect();
+        namespaces.sort_by(|a, b| a.name.cmp(&b.name));
+        for (n, ns) in namespaces.iter().enumerate(){
+            let mut interfaces: Vec<Arc<Interface>> = config.interfaces.values()
+                .filter(|i| i.namespace.as_ref().is_some_and(|m| m.netns == ns.netns))
+                .filter(|i| i.ip.is_some() || i.ip6.is_some())
+                .collect();
+            interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+            let d = RoutingDaemon::new(kind, &self.name, &ns.netns);
+            if interfaces.is_empty() || !self.routed(&ns.name) {
+                d.stop()?;
+                continue;
+            }
+            let router_id = loopback::router_id(config, &ns.name)
+                .or_else(|| interfaces.iter()
+                    .filter_map(|i| i.ip.as_ref()?.split('/').next()?.parse::<std::net::Ipv4Addr>().ok())
+                    .min())
+                .unwrap_or(std::net::Ipv4Addr::from(n as u32 + 1));
+            let ospf = DaemonConfig{
+                router_id,
+                interfaces: interfaces.iter()
+                    .filter(|i| !self.ebgp_link(&ns.name, &i.name))
+                    .map(|i| {
+                        let (area, peers) = self.segment_of(&ns.name, &i.name).unwrap_or_default();
+                        OspfInterface{
+                            name: i.name.clone(),
+                            cost: self.links.iter()
+                                .find(|l| interface::name(&ns.name, &l.name) == i.name)
+                                .map(|l| paths::end_cost(l, &ns.name))
+                                .unwrap_or(1),
+                            area,
+                            passive: i.name == loopback::name(&ns.name) || i.name == nat64::name(&ns.name)
+                                || (!peers.is_empty() && peers.iter().all(|p| !self.routed(p))),
+                            v4: i.ip.is_some(),
+                            v6: i.ip6.is_some(),
+                        }
+                    }).collect(),
+                bgp: self.bgp_config(&ns.name, config)?,
+            };
+            if config.reconcile {
+                if d.current(&ospf)? {
+                    continue;
+                }
+                d.stop()?;
+            }
+            config.transaction().record(Resource::Daemon{ dir: d.dir.clone() });
+            d.start(&ospf)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the interfaces of every group into its kernel group, then
+    /// applies the group's settings in each namespace it has members in.
+    fn apply_groups(&self, config: &Config) -> Result<()>{
+        let used = self.links.iter().filter_map(|l| l.group.as_ref())
+            .chain(self.bridges.iter().filter_map(|b| b.group.as_ref()))
+            .chain(self.interfaces.iter().filter_map(|i| i.group.as_ref()));
+        for name in used{
+            if !self.groups.iter().any(|g| g.name == *name) {
+                return Err(failed!("Group {} not found", name));
+            }
+        }
+        for g in &self.groups{
+            g.check()?;
+            if self.groups.iter().filter(|o| o.id == g.id).count() > 1 {
+                return Err(failed!("Group id {} is used twice", g.id));
+            }
+            let mut namespaces = Vec::new();
+            for (ns, interface) in group::topology_members(self, &g.name){
+                let netns = match ns{
+                    Some(ns) => Some(namespace(config, &ns)?.netns.clone()),
+                    None => None,
+                };
+                group::join(netns.as_deref(), &interface, g.id)?;
+                if !namespaces.contains(&netns) {
+                    namespaces.push(netns);
+                }
+            }
+            for netns in namespaces{
+                g.apply(netns.as_deref())
+                    .map_err(|e| failed!("Group {}: {}", g.name, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// False for stubs, P4 switches and forwarders, which run no routing
+    /// daemon.
+    pub(crate) fn routed(&self, namespace: &str) -> bool {
+        !self.namespaces.iter().any(|n| n.name == namespace && (n.stub || n.p4.is_some() || n.forwarder.is_some() || n.container.is_some()))
+    }
+
+    /// Queues of the ends of `link`: its `queues` over the topology's.
+    pub(crate) fn link_queues(&self, link: &LinkSpec) -> QueueSpec {
+        let queues = self.queues.clone().unwrap_or_default();
+        match &link.queues{
+            Some(over) => queues.merge(over),
+            None => queues,
+        }
+    }
+
+    /// MAC addresses of the ends of `link`: its `macs`, else derived ones
+    /// with `stable_macs`, else random.
+    pub(crate) fn link_macs(&self, link: &LinkSpec) -> Result<[Option<String>; 2]>{
+        match (&link.macs[..], &link.endpoints[..]){
+            ([a, b], _) => {
+                for mac in [a, b]{
+                    interface::check_mac(mac).map_err(|e| failed!("Link {}: {}", link.name, e))?;
+                }
+                if a.eq_ignore_ascii_case(b) {
+                    return Err(failed!("Link {} gives both ends MAC address {}", link.name, a));
+                }
+                Ok([Some(a.clone()), Some(b.clone())])
+            },
+            ([], [a, b]) if self.stable_macs => Ok([a, b].map(|ns| Some(interface::derived_mac(&self.name, &format!("{}_{}", ns, link.name))))),
+            ([], _) => Ok([None, None]),
+            _ => Err(failed!("Link {} needs a MAC address per end, got {}", link.name, link.macs.len())),
+        }
+    }
+
+    /// Sysctl assignments of namespace `ns` in the order they are set:
+    /// forwarding, the ECMP hash policy, the topology's `sysctls` and its
+    /// own, a later one replacing an earlier one of the same key. An
+    /// attached container only gets its own.
+    pub fn sysctls(&self, ns: &NamespaceSpec) -> Result<Vec<String>>{
+        let mut settings = BTreeMap::new();
+        if ns.container.is_none() {
+            settings.extend(namespace::ROUTING.map(|(k, v)| (k.to_string(), v.to_string())));
+        }
+        if ns.ecmp {
+            settings.extend(namespace::ECMP.map(|(k, v)| (k.to_string(), v.to_string())));
+        }
+        if ns.container.is_none() {
+            settings.extend(self.sysctls.clone());
+        }
+        for (key, value) in self.sysctls.iter().chain(&ns.sysctls){
+            // other sysctls are the host's, shared by all namespaces
+            if !key.starts_with("net.") || key.contains(['=', ' ', '/']) {
+                return Err(failed!("Invalid sysctl {}, expected a net.<setting> of the namespace", key));
+            }
+            if value.is_empty() {
+                return Err(failed!("Sysctl {} needs a value", key));
+            }
+        }
+        settings.extend(ns.sysctls.clone());
+        Ok(settings.into_iter().map(|(k, v)| format!("{}={}", k, v)).collect())
+    }
+
+    /// Mbit/s of the fastest link of `namespace`, its rate limit or else
+    /// its bandwidth, None if no link says.
+    pub(crate) fn link_bandwidth(&self, namespace: &str) -> Option<u64> {
+        self.links.iter()
+            .filter(|l| l.endpoints.iter().any(|e| e == namespace))
+            .filter_map(|l| l.qos_at(namespace).and_then(|q| q.rate).or(l.bandwidth))
+            .max()
+    }
+
+    /// OSPF area of the link or bridge `interface` of `namespace` is
+    /// attached to and the other namespaces on it.
+    fn segment_of(&self, namespace: &str, interface: &str) -> Option<(u32, Vec<String>)> {
+        let segments = self.links.iter().map(|l| (&l.name, l.area, &l.endpoints))
+            .chain(self.bridges.iter().map(|b| (&b.name, b.area, &b.members)));
+        for (name, area, members) in segments{
+            if interface::name(namespace, name) == interface {
+                let peers = members.iter().filter(|m| *m != namespace).cloned().collect();
+                return Some((area.unwrap_or(0), peers));
+            }
+        }
+        None
+    }
+
+    /// True if `interface` of `namespace` is the end of a link to a namespace
+    /// of another AS.
+    fn ebgp_link(&self, namespace: &str, interface: &str) -> bool {
+        let asn = |name: &str| self.namespaces.iter().find(|n| n.name == name).and_then(|n| Some(n.bgp.as_ref()?.asn));
+        self.links.iter()
+            .filter(|l| interface::name(namespace, &l.name) == interface)
+            .flat_map(|l| l.endpoints.iter().filter(|e| *e != namespace))
+            .any(|peer| matches!((asn(namespace), asn(peer)), (Some(a), Some(b)) if a != b))
+    }
+
+    /// BGP configuration of `namespace` with neighbor addresses resolved
+    /// from the interfaces in `config`.
+    fn bgp_config(&self, namespace: &str, config: &Config) -> Result<Option<BgpConfig>>{
+        let Some(bgp) = self.namespaces.iter().find(|n| n.name == namespace).and_then(|n| n.bgp.as_ref()) else {
+            return Ok(None);
+        };
+        let mut neighbors: Vec<BgpNeighbor> = Vec::new();
+        for n in &bgp.neighbors{
+            let asn = match self.namespaces.iter().find(|p| p.name == n.peer){
+                Some(peer) => match &peer.bgp{
+                    Some(b) => b.asn,
+                    None => return Err(failed!("BGP neighbor {} of {} has no bgp section", n.peer, namespace)),
+                },
+                None => return Err(failed!("BGP neighbor {} of {} not found", n.peer, namespace)),
+            };
+            let address = match &n.address{
+                Some(address) => address.clone(),
+                None => self.peer_address(namespace, &n.peer, config).ok_or_else(|| failed!(
+                    "BGP neighbor {} of {} shares no addressed link or bridge with it, set its address", n.peer, namespace))?,
+            };
+            let address: std::net::IpAddr = address.parse()
+                .map_err(|e| failed!("Invalid address {} of BGP neighbor {} of {}: {}", address, n.peer, namespace, e))?;
+            if neighbors.iter().any(|b| b.name == n.peer && b.address.is_ipv6() == address.is_ipv6()) {
+                return Err(failed!("BGP neighbor {} of {} is listed twice", n.peer, namespace));
+            }
+            neighbors.push(BgpNeighbor{ name: n.peer.clone(), address, asn });
+        }
+        let networks = bgp.networks.iter()
+            .map(|net| net.parse().map_err(|e| failed!("Invalid BGP network {} of {}: {}", net, namespace, e)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(BgpConfig{ asn: bgp.asn, neighbors, networks, dampening: bgp.dampening }))
+    }
+
+    /// Address of `peer` on the first link or bridge it shares with
+    /// `namespace`, IPv4 if there is one.
+    fn peer_address(&self, namespace: &str, peer: &str, config: &Config) -> Option<String> {
+        let segments = self.links.iter().map(|l| (&l.name, &l.endpoints))
+            .chain(self.bridges.iter().map(|b| (&b.name, &b.members)));
+        for (segment, members) in segments{
+            if !members.iter().any(|m| m == namespace) || !members.iter().any(|m| m == peer) {
+                continue;
+            }
+            let Some(intf) = config.interfaces.get(&interface::name(peer, segment)) else {
+                continue;
+            };
+            if let Some(ip) = intf.ip.as_ref().or(intf.ip6.as_ref()){
+                return ip.split('/').next().map(|a| a.to_string());
+            }
+        }
+        None
+    }
+
+    /// Routing table of a route, that of its VRF if it names one.
+    pub(crate) fn table_of(&self, r: &RouteSpec) -> Result<Option<u32>>{
+        let Some(vrf) = &r.vrf else {
+            return Ok(r.table);
+        };
+        let table = self.namespaces.iter()
+            .filter(|n| n.name == r.namespace)
+            .flat_map(|n| &n.vrfs)
+            .find(|v| v.name == *vrf)
+            .map(|v| v.table)
+            .ok_or_else(|| failed!("VRF {} of route {} in {} not found", vrf, r.dst, r.namespace))?;
+        if r.table.is_some_and(|t| t != table) {
+            return Err(failed!("Route {} in {} names table {} and VRF {} with table {}", r.dst, r.namespace, r.table.unwrap_or_default(), vrf, table));
+        }
+        Ok(Some(table))
+    }
+
+    /// Routes given by hand, those to services, default routes of stub
+    /// namespaces and the generated ones if `auto_routes` is set. Needs the
+    /// links of `config` for their assigned subnets.
+    fn route_specs(&self, config: &Config) -> Result<Vec<RouteSpec>>{
+        self.route_specs_with(&subnets(config))
+    }
+
+    /// `route_specs` with the subnets of the segments by name, see `plan`.
+    pub(crate) fn route_specs_with(&self, subnets: &HashMap<String, (String, Option<String>)>) -> Result<Vec<RouteSpec>>{
+        let mut routes = self.routes.clone();
+        routes.extend(paths::service_routes(self, subnets)?);
+        routes.extend(paths::stub_routes(self, subnets));
+        if self.auto_routes {
+            routes.extend(paths::static_routes(self, subnets)?);
+        }
+        Ok(routes)
+    }
+
+    /// Brings an existing topology in line with this description: objects
+    /// which exist are kept and fixed up, missing ones are created and stale
+    /// ones, those the topology made before, removed. Creates the topology
+    /// if it doesn't exist yet.
+    pub fn reconcile(&self) -> Result<Config>{
+        self.reconcile_with(Config::new(self.name.clone()))
+    }
+
+    pub fn reconcile_with(&self, mut config: Config) -> Result<Config>{
+        config.reconcile = true;
+        validate::run(self)?;
+        if !config.host_changes {
+            let changes = preflight::host_changes(self, true);
+            if !changes.is_empty() {
+                return Err(RouterError::HostChanges{ topology: self.name.clone(), changes });
+            }
+        }
+        if config.preflight {
+            preflight::run(self, true, &config.modules)?;
+        }
+        // on failure the host's thresholds go back only if this reconcile
+        // is what changed them, those of an existing topology stay
+        let gc_saved = neighbor::saved(&self.name);
+        let result = self.build(&config)
+            .and_then(|_| self.prune(&config))
+            .and_then(|_| State::from_config(&config).save());
+        if let Err(e) = result {
+            if !gc_saved {
+                let _ = neighbor::restore(&self.name);
+            }
+            if let Err(r) = config.transaction().rollback() {
+                return Err(RouterError::RollbackFailed{ error: Box::new(e), rollback: r.to_string() });
+            }
+            return Err(e);
+        }
+        config.transaction().commit();
+        Ok(config)
+    }
+
+    /// Removes what `build` didn't account for: namespaces of the topology
+    /// not described anymore and interfaces inside its namespaces. Stale
+    /// routes are removed by the namespaces' `Rib`.
+    /// Stale namespaces are those the saved state records or tagged as
+    /// created for the topology, see `state::namespaces`, stale interfaces
+    /// those the saved state records, their bridge and bond members, and
+    /// those tagged, see `owner`. Interfaces of others are left alone.
+    fn prune(&self, config: &Config) -> Result<()>{
+        let managed: Vec<Arc<Namespace>> = config.namespaces.values().collect();
+        let saved = State::load(&self.name)?;
+        // Open vSwitch of bridges gone or switched to Linux
+        for b in saved.iter().flat_map(|s| &s.bridges).filter(|b| b.ovs){
+            if !config.bridges.get(&b.name).is_some_and(|c| c.backend == BridgeBackend::Ovs) {
+                daemon::stop_dir(&ovs::Switch::dir(&self.name, &b.name))?;
+            }
+        }
+        // P4 switches of namespaces gone or no longer switching
+        for ns in P4Switch::list(&self.name)?{
+            if !self.namespaces.iter().any(|n| n.name == ns && n.p4.is_some()) {
+                daemon::stop_dir(&P4Switch::dir(&self.name, &ns))?;
+            }
+        }
+        for fwd in Forwarder::list(&self.name)?{
+            if !self.namespaces.iter().any(|n| n.name == fwd.namespace && n.forwarder.is_some()) {
+                fwd.stop()?;
+            }
+        }
+        // moved NICs go back to the host rather than being deleted
+        let nics = saved.as_ref().map(|s| s.nics.clone()).unwrap_or_default();
+        let saved_mirrors = saved.as_ref().map(|s| s.mirrors.clone()).unwrap_or_default();
+        let mut stale = Vec::new();
+        for netns in state::namespaces(&self.name)?{
+            if !managed.iter().any(|m| m.netns == netns) {
+                dns::unconfigure(&netns)?;
+                stale.extend(nics.iter().filter(|n| n.netns == netns).cloned().map(Resource::Moved));
+                stale.push(Resource::Daemon{ dir: RoutingDaemon::dir(&self.name, &netns) });
+                stale.push(Resource::Namespace{ netns, pooled: false });
+            }
+        }
+        transaction::undo_all(stale)?;
+        // mirrors gone lose their span port, those moved to another
+        // interface leave their filters behind
+        for spec in saved_mirrors{
+            let current = config.mirrors.iter().find(|m| m.spec.name == spec.name);
+            if current.as_ref().is_some_and(|m| m.spec.namespace == spec.namespace && m.spec.interface == spec.interface) {
+                continue;
+            }
+            let old = Mirror::new(&self.name, spec)?;
+            match current{
+                Some(_) => old.remove_filters(),
+                None => old.stop()?,
+            }
+        }
+
+        for ns in &managed{
+            let mut expected: Vec<String> = config.interfaces.values()
+                .filter(|i| i.namespace.as_ref().is_some_and(|n| n.netns == ns.netns))
+                .map(|i| i.name.clone())
+                .collect();
+            for b in config.bridges.values().filter(|b| b.namespace.netns == ns.netns){
+                expected.push(b.name.clone());
+                if b.backend == BridgeBackend::Ovs {
+                    expected.push(ovs::DATAPATH_PORT.to_string());
+                }
+                for spec in self.bridges.iter().filter(|s| s.name == b.name){
+                    expected.extend(spec.members.iter().map(|m| interface::name(&b.name, m)));
+                }
+            }
+            for l in self.links.iter().filter(|l| l.endpoints.contains(&ns.name)){
+                if let Some(bond) = &l.bond{
+                    expected.extend(bond.members(&ns.name, &l.name));
+                }
+            }
+            for m in config.mirrors.iter(){
+                if let Some((local, remote)) = m.spec.span() {
+                    if m.netns == ns.netns {
+                        expected.push(local);
+                    }
+                    if m.monitor.as_ref() == Some(&ns.netns) {
+                        expected.push(remote);
+                    }
+                }
+            }
+            let vrfs: Vec<Arc<Vrf>> = config.vrfs.iter().filter(|v| v.namespace.netns == ns.netns).collect();
+            expected.extend(vrfs.iter().map(|v| v.name.clone()));
+            let mut recorded: Vec<String> = nics.iter().filter(|n| n.netns == ns.netns).map(|n| n.name.clone()).collect();
+            if let Some(s) = &saved {
+                let here = |netns: &Option<String>| netns.as_ref() == Some(&ns.netns);
+                recorded.extend(s.interfaces.iter().filter(|i| here(&i.netns)).map(|i| i.name.clone()));
+                for b in s.bridges.iter().filter(|b| here(&b.netns)){
+                    recorded.push(b.name.clone());
+                    if b.ovs {
+                        recorded.push(ovs::DATAPATH_PORT.to_string());
+                    }
+                }
+                recorded.extend(s.vrfs.iter().filter(|v| v.netns == ns.netns).map(|v| v.name.clone()));
+                recorded.extend(s.mirrors.iter().filter_map(|m| m.span()).flat_map(|(local, remote)| [local, remote]));
+            }
+            let links: serde_json::Value = serde_json::from_str(&cmd::ip(Some(&ns.netns), &["-d", "-j", "link", "show"])?)?;
+            for l in links.as_array().cloned().unwrap_or_default(){
+                let name = l["ifname"].as_str().unwrap_or_default();
+                // unbound from its VRF in the description
+                if let Some(master) = l["master"].as_str() {
+                    let bound = vrfs.iter().any(|v| v.name == master && v.interfaces.iter().any(|i| i == name));
+                    if l["linkinfo"]["info_slave_kind"] == "vrf" && !bound {
+                        cmd::ip(Some(&ns.netns), &["link", "set", "dev", name, "nomaster"])?;
+                    }
+                }
+                if name == "lo" || name.is_empty() || expected.iter().any(|e| e == name) || tunnel::FALLBACK_DEVICES.contains(&name) {
+                    continue;
+                }
+                // members of a VRF may be anyone's
+                let member = l["linkinfo"]["info_slave_kind"] != "vrf" && l["master"].as_str().is_some_and(|m| recorded.iter().any(|r| r == m));
+                let tagged = l["ifalias"].as_str().and_then(Owner::parse).is_some_and(|o| o.topology == self.name);
+                if !recorded.iter().any(|r| r == name) && !member && !tagged {
+                    continue;
+                }
+                if let Some(nic) = nics.iter().find(|n| n.netns == ns.netns && n.name == name) {
+                    passthrough::restore(nic)?;
+                } else if l["linkinfo"]["info_kind"].is_string() {
+                    // deleting one end of a veth removes the peer as well
+                    let _ = cmd::ip(Some(&ns.netns), &["link", "del", "dev", name]);
+                } else {
+                    cmd::ip(Some(&ns.netns), &["link", "set", "dev", name, "netns", "1"])?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Underlay address of a tunnel end given as `local`: an address, or an
+/// interface of `ns` whose address is used and which then carries the
+/// tunnel.
+fn underlay(config: &Config, ns: &Namespace, local: &str) -> Result<(std::net::IpAddr, Option<String>)>{
+    if let Ok(addr) = local.parse() {
+        return Ok((addr, None));
+    }
+    let intf = config.interface(local)
+        .filter(|i| i.namespace.as_ref().is_some_and(|n| n.netns == ns.netns))
+        .ok_or_else(|| failed!("Interface {} not found in {}", local, ns.name))?;
+    let addr = intf.ip.as_ref().or(intf.ip6.as_ref())
+        .and_then(|a| a.split('/').next()?.parse().ok())
+        .ok_or_else(|| failed!("Interface {} has no address", local))?;
+    Ok((addr, Some(intf.name.clone())))
+}
+
+enum Item{
+    Namespace,
+    Link,
+    Bridge,
+    Vxlan,
+    Tunnel,
+    Wireguard,
+    Interface,
+    Route,
+    Service,
+    Check,
+    Group,
+}
+
+/// Fluent builder for a `Topology`, see `Topology::builder`. Misplaced
+/// modifiers are collected and reported by `build`.
+pub struct TopologyBuilder{
+    topology: Topology,
+    last: Option<Item>,
+    errors: Vec<String>,
+}
+
+impl TopologyBuilder{
+    pub fn namespace(mut self, name: &str) -> Self {
+        self.topology.namespaces.push(NamespaceSpec{
+            name: name.to_string(),
+            ..Default::default()
+        });
+        self.last = Some(Item::Namespace);
+        self
+    }
+
+    /// Makes the last namespace a host without routing daemon, see
+    /// `NamespaceSpec::stub`.
+    pub fn stub(mut self) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.stub = true,
+            _ => self.errors.push("stub() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Makes the last namespace a NAT gateway masquerading everything it
+    /// forwards out of `out`.
+    pub fn nat(mut self, out: &str) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.nat = Some(NatSpec{ out: out.to_string(), ..Default::default() }),
+            _ => self.errors.push(format!("nat({}) must follow namespace()", out)),
+        }
+        self
+    }
+
+    /// Filters the traffic of the last namespace.
+    pub fn firewall(mut self, firewall: FirewallSpec) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.firewall = Some(firewall),
+            _ => self.errors.push("firewall() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Adds a VRF to the last namespace.
+    pub fn vrf(mut self, vrf: VrfSpec) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.vrfs.push(vrf),
+            _ => self.errors.push("vrf() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Adds a policy routing rule to the last namespace.
+    pub fn rule(mut self, rule: PolicyRule) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.rules.push(rule),
+            _ => self.errors.push("rule() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Switches the last namespace with a P4 program, see `P4Spec`.
+    pub fn p4(mut self, p4: P4Spec) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.p4 = Some(p4),
+            _ => self.errors.push("p4() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Hands the interfaces of the last namespace to a forwarder, see
+    /// `ForwarderSpec`.
+    pub fn forwarder(mut self, forwarder: ForwarderSpec) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.forwarder = Some(forwarder),
+            _ => self.errors.push("forwarder() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Makes the last namespace the network namespace of `container`, see
+    /// `ContainerSpec`.
+    pub fn container(mut self, container: ContainerSpec) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.container = Some(container),
+            _ => self.errors.push("container() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Sets the ICMP rate limit and redirect behavior of the last
+    /// namespace, see `IcmpSpec`.
+    pub fn icmp(mut self, icmp: IcmpSpec) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.icmp = Some(icmp),
+            _ => self.errors.push("icmp() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Sets the congestion control, buffers and pacing of the last
+    /// namespace, see `TcpSpec`.
+    pub fn tcp(mut self, tcp: TcpSpec) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.tcp = Some(tcp),
+            _ => self.errors.push("tcp() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Gives the last namespace a loopback, see `LoopbackSpec`.
+    pub fn loopback(mut self, loopback: LoopbackSpec) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.loopback = Some(loopback),
+            _ => self.errors.push("loopback() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Lets the last namespace translate NAT64, see `Nat64Spec`.
+    pub fn nat64(mut self, nat64: Nat64Spec) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.nat64 = Some(nat64),
+            _ => self.errors.push("nat64() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Lets the last namespace serve the names of the topology, see
+    /// `DnsSpec`.
+    pub fn dns(mut self, dns: DnsSpec) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.dns = Some(dns),
+            _ => self.errors.push("dns() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Runs `command` on the host on `on`, see `hooks`.
+    pub fn hook(mut self, on: HookEvent, command: &[&str]) -> Self {
+        self.topology.hooks.push(HookSpec{
+            on,
+            command: command.iter().map(|c| c.to_string()).collect(),
+        });
+        self
+    }
+
+    /// Runs `command` as process `name` of the last namespace, restarted
+    /// under `restart`, see `process`.
+    pub fn process(mut self, name: &str, command: &[&str], restart: RestartPolicy) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.processes.push(ProcessSpec{
+                name: name.to_string(),
+                command: command.iter().map(|c| c.to_string()).collect(),
+                restart,
+            }),
+            _ => self.errors.push(format!("process({}) must follow namespace()", name)),
+        }
+        self
+    }
+
+    /// Lets the last namespace learn its IPv6 default route from router
+    /// advertisements, see `RaSpec`.
+    pub fn ra(mut self, ra: RaSpec) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.ra = Some(ra),
+            _ => self.errors.push("ra() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Sets the neighbor timers of the interfaces of the last namespace,
+    /// see `NeighborSpec`.
+    pub fn neighbor(mut self, neighbor: NeighborSpec) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.neighbor = Some(neighbor),
+            _ => self.errors.push("neighbor() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Enables SRv6 on the last namespace even without SRv6 routes, e.g. on
+    /// the last segment of an encapsulated path.
+    pub fn srv6(mut self) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.srv6 = true,
+            _ => self.errors.push("srv6() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Forwards established connections of the last namespace through a
+    /// flowtable, see `fastpath`.
+    pub fn flowtable(mut self) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.flowtable = true,
+            _ => self.errors.push("flowtable() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Enables ECMP hashing on the last namespace.
+    pub fn ecmp(mut self) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.ecmp = true,
+            _ => self.errors.push("ecmp() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Sets clock offsets for processes in the last namespace.
+    pub fn clock(mut self, skew: ClockSkew) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.clock = Some(skew),
+            _ => self.errors.push("clock() must follow namespace()".to_string()),
+        }
+        self
+    }
+
+    /// Makes the last namespace a BGP speaker in AS `asn`.
+    pub fn bgp(mut self, asn: u32) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut()){
+            (Some(Item::Namespace), Some(ns)) => ns.bgp = Some(BgpSpec{ asn, ..Default::default() }),
+            _ => self.errors.push(format!("bgp({}) must follow namespace()", asn)),
+        }
+        self
+    }
+
+    /// Adds BGP sessions with the given namespaces to the last namespace.
+    pub fn neighbors(mut self, peers: &[&str]) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut().and_then(|ns| ns.bgp.as_mut())){
+            (Some(Item::Namespace), Some(bgp)) => bgp.neighbors.extend(peers.iter().map(|p| BgpNeighborSpec{
+                peer: p.to_string(),
+                address: None,
+            })),
+            _ => self.errors.push(format!("neighbors({}) must follow bgp()", peers.join(", "))),
+        }
+        self
+    }
+
+    /// Prefixes the last namespace announces over BGP.
+    pub fn networks(mut self, networks: &[&str]) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut().and_then(|ns| ns.bgp.as_mut())){
+            (Some(Item::Namespace), Some(bgp)) => bgp.networks.extend(networks.iter().map(|n| n.to_string())),
+            _ => self.errors.push(format!("networks({}) must follow bgp()", networks.join(", "))),
+        }
+        self
+    }
+
+    /// Enables route flap dampening on the BGP speaker of the last
+    /// namespace.
+    pub fn dampening(mut self) -> Self {
+        match (&self.last, self.topology.namespaces.last_mut().and_then(|ns| ns.bgp.as_mut())){
+            (Some(Item::Namespace), Some(bgp)) => bgp.dampening = true,
+            _ => self.errors.push("dampening() must follow bgp()".to_string()),
+        }
+        self
+    }
+
+    pub fn link(mut self, name: &str, subnet: &str) -> Self {
+        self.topology.links.push(LinkSpec{
+            name: name.to_string(),
+            subnet: subnet.to_string(),
+            ..Default::default()
+        });
+        self.last = Some(Item::Link);
+        self
+    }
+
+    /// Declares the bandwidth (Mbit/s) and latency (ms) of the last link,
+    /// from which its path cost is derived.
+    pub fn bandwidth(mut self, mbit: u64, latency: f64) -> Self {
+        match (&self.last, self.topology.links.last_mut()){
+            (Some(Item::Link), Some(l)) => {
+                l.bandwidth = Some(mbit);
+                l.latency = Some(latency);
+            },
+            _ => self.errors.push(format!("bandwidth({}, {}) must follow link()", mbit, latency)),
+        }
+        self
+    }
+
+    /// Sets the path cost of the last link explicitly.
+    pub fn cost(mut self, cost: u64) -> Self {
+        match (&self.last, self.topology.links.last_mut()){
+            (Some(Item::Link), Some(l)) => l.cost = Some(cost),
+            _ => self.errors.push(format!("cost({}) must follow link()", cost)),
+        }
+        self
+    }
+
+    /// Puts the last link or bridge into OSPF area `area`.
+    pub fn area(mut self, area: u32) -> Self {
+        match (&self.last, self.topology.links.last_mut(), self.topology.bridges.last_mut()){
+            (Some(Item::Link), Some(l), _) => l.area = Some(area),
+            (Some(Item::Bridge), _, Some(b)) => b.area = Some(area),
+            _ => self.errors.push(format!("area({}) must follow link() or bridge()", area)),
+        }
+        self
+    }
+
+    /// Puts the interfaces of the last link, bridge or interface into
+    /// `group`, declared with `group()`.
+    pub fn member_of(mut self, group: &str) -> Self {
+        let name = Some(group.to_string());
+        match &self.last{
+            Some(Item::Link) => if let Some(l) = self.topology.links.last_mut() { l.group = name },
+            Some(Item::Bridge) => if let Some(b) = self.topology.bridges.last_mut() { b.group = name },
+            Some(Item::Interface) => if let Some(i) = self.topology.interfaces.last_mut() { i.group = name },
+            _ => self.errors.push(format!("member_of({}) must follow link(), bridge() or interface()", group)),
+        }
+        self
+    }
+
+    /// Impairs both ends of the last link.
+    pub fn qos(mut self, qos: LinkQos) -> Self {
+        match (&self.last, self.topology.links.last_mut()){
+            (Some(Item::Link), Some(l)) => l.qos = Some(qos),
+            _ => self.errors.push("qos() must follow link()".to_string()),
+        }
+        self
+    }
+
+    /// Sets the queues of both ends of the last link or of the last
+    /// interface, see `queue`.
+    pub fn queues(mut self, queues: QueueSpec) -> Self {
+        match self.last{
+            Some(Item::Link) => {
+                if let Some(l) = self.topology.links.last_mut() {
+                    l.queues = Some(queues);
+                }
+            },
+            Some(Item::Interface) => {
+                if let Some(i) = self.topology.interfaces.last_mut() {
+                    i.queues = Some(queues);
+                }
+            },
+            _ => self.errors.push("queues() must follow link() or interface()".to_string()),
+        }
+        self
+    }
+
+    /// Sets the offloads of both ends of the last link, see `offload`.
+    pub fn offloads(mut self, offloads: OffloadSpec) -> Self {
+        match (&self.last, self.topology.links.last_mut()){
+            (Some(Item::Link), Some(l)) => l.offloads = Some(offloads),
+            _ => self.errors.push("offloads() must follow link()".to_string()),
+        }
+        self
+    }
+
+    /// Overrides the impairment of the last link's end in `namespace`.
+    pub fn endpoint_qos(mut self, namespace: &str, qos: LinkQos) -> Self {
+        match (&self.last, self.topology.links.last_mut()){
+            (Some(Item::Link), Some(l)) => {
+                l.endpoint_qos.insert(namespace.to_string(), qos);
+            },
+            _ => self.errors.push(format!("endpoint_qos({}) must follow link()", namespace)),
+        }
+        self
+    }
+
+    /// Gives the ends of the last link the MAC addresses `a` and `b`.
+    pub fn macs(mut self, a: &str, b: &str) -> Self {
+        match (&self.last, self.topology.links.last_mut()){
+            (Some(Item::Link), Some(l)) => l.macs = vec![a.to_string(), b.to_string()],
+            _ => self.errors.push(format!("macs({}, {}) must follow link()", a, b)),
+        }
+        self
+    }
+
+    /// Numbers the ends of the last link with hosts `a` and `b` of its
+    /// subnets.
+    pub fn hosts(mut self, a: u32, b: u32) -> Self {
+        match (&self.last, self.topology.links.last_mut()){
+            (Some(Item::Link), Some(l)) => l.hosts = vec![a, b],
+            _ => self.errors.push(format!("hosts({}, {}) must follow link()", a, b)),
+        }
+        self
+    }
+
+    /// Gives the end of the last link in `namespace` address `address`.
+    pub fn end_address(mut self, namespace: &str, address: &str) -> Self {
+        match (&self.last, self.topology.links.last_mut()){
+            (Some(Item::Link), Some(l)) => l.addresses.entry(namespace.to_string()).or_default().push(address.to_string()),
+            _ => self.errors.push(format!("end_address({}, {}) must follow link()", namespace, address)),
+        }
+        self
+    }
+
+    /// Makes the last link unnumbered, its ends take the loopback
+    /// addresses of their namespaces.
+    pub fn unnumbered(mut self) -> Self {
+        match (&self.last, self.topology.links.last_mut()){
+            (Some(Item::Link), Some(l)) => {
+                l.unnumbered = true;
+                l.subnet.clear();
+            },
+            _ => self.errors.push("unnumbered() must follow link()".to_string()),
+        }
+        self
+    }
+
+    /// Aggregates the last link from several veth pairs, see `BondSpec`.
+    pub fn bond(mut self, bond: BondSpec) -> Self {
+        match (&self.last, self.topology.links.last_mut()){
+            (Some(Item::Link), Some(l)) => l.bond = Some(bond),
+            _ => self.errors.push("bond() must follow link()".to_string()),
+        }
+        self
+    }
+
+    /// Derives the MAC addresses of link ends from the topology and
+    /// interface names, see `Topology::stable_macs`.
+    pub fn stable_macs(mut self) -> Self {
+        self.topology.stable_macs = true;
+        self
+    }
+
+    /// Generates cheapest-path static routes between all namespaces.
+    pub fn auto_routes(mut self) -> Self {
+        self.topology.auto_routes = true;
+        self
+    }
+
+    /// Sets the garbage collection thresholds of the neighbor tables while
+    /// the topology exists, see `NeighborGc`.
+    pub fn neighbor_gc(mut self, gc: NeighborGc) -> Self {
+        self.topology.neighbor_gc = Some(gc);
+        self
+    }
+
+    /// Runs a routing daemon in every namespace, see `Topology::daemon`.
+    pub fn daemon(mut self, kind: DaemonKind) -> Self {
+        self.topology.daemon = Some(kind);
+        self
+    }
+
+    /// Adds a bridge, members are added with `members`. An empty subnet is
+    /// allocated like that of a link.
+    pub fn bridge(mut self, name: &str, subnet: &str) -> Self {
+        self.topology.bridges.push(BridgeSpec{
+            name: name.to_string(),
+            subnet: subnet.to_string(),
+            ..Default::default()
+        });
+        self.last = Some(Item::Bridge);
+        self
+    }
+
+    /// Adds namespaces to the last bridge.
+    pub fn members(mut self, namespaces: &[&str]) -> Self {
+        match (&self.last, self.topology.bridges.last_mut()){
+            (Some(Item::Bridge), Some(b)) => b.members.extend(namespaces.iter().map(|n| n.to_string())),
+            _ => self.errors.push(format!("members({}) must follow bridge()", namespaces.join(", "))),
+        }
+        self
+    }
+
+    /// Places the last bridge inside an existing namespace instead of a
+    /// namespace of its own.
+    pub fn inside(mut self, namespace: &str) -> Self {
+        match (&self.last, self.topology.bridges.last_mut()){
+            (Some(Item::Bridge), Some(b)) => b.namespace = Some(namespace.to_string()),
+            _ => self.errors.push(format!("inside({}) must follow bridge()", namespace)),
+        }
+        self
+    }
+
+    /// Adds a VXLAN link with VNI `vni`, an empty `subnet` is allocated.
+    pub fn vxlan(mut self, name: &str, vni: u32, subnet: &str) -> Self {
+        self.topology.vxlans.push(VxlanSpec{
+            name: name.to_string(),
+            vni,
+            subnet: subnet.to_string(),
+            ..Default::default()
+        });
+        self.last = Some(Item::Vxlan);
+        self
+    }
+
+    /// Adds an end in `namespace` to the last VXLAN link, tunnelling from
+    /// `local`, an address or interface of the namespace.
+    pub fn vtep(mut self, namespace: &str, local: &str) -> Self {
+        match (&self.last, self.topology.vxlans.last_mut()){
+            (Some(Item::Vxlan), Some(v)) => v.endpoints.push(VtepSpec{
+                namespace: namespace.to_string(),
+                local: local.to_string(),
+                host: None,
+            }),
+            _ => self.errors.push(format!("vtep({}, {}) must follow vxlan()", namespace, local)),
+        }
+        self
+    }
+
+    /// Adds a VTEP outside the topology to the last VXLAN link, or makes
+    /// `address` the far end of the last tunnel.
+    pub fn remote(mut self, address: &str) -> Self {
+        match (&self.last, self.topology.vxlans.last_mut(), self.topology.tunnels.last_mut()){
+            (Some(Item::Vxlan), Some(v), _) => v.remotes.push(address.to_string()),
+            (Some(Item::Tunnel), _, Some(t)) => t.remote = Some(address.to_string()),
+            _ => self.errors.push(format!("remote({}) must follow vxlan() or tunnel()", address)),
+        }
+        self
+    }
+
+    /// Adds a tunnel of type `kind`, an empty `subnet` is allocated.
+    pub fn tunnel(mut self, name: &str, kind: TunnelKind, subnet: &str) -> Self {
+        self.topology.tunnels.push(TunnelSpec{
+            name: name.to_string(),
+            kind,
+            subnet: subnet.to_string(),
+            ..Default::default()
+        });
+        self.last = Some(Item::Tunnel);
+        self
+    }
+
+    /// Adds an end in `namespace` to the last tunnel, sourced from `local`,
+    /// an address or interface of the namespace.
+    pub fn tunnel_end(mut self, namespace: &str, local: &str) -> Self {
+        match (&self.last, self.topology.tunnels.last_mut()){
+            (Some(Item::Tunnel), Some(t)) => t.endpoints.push(TunnelEndSpec{
+                namespace: namespace.to_string(),
+                local: local.to_string(),
+                host: None,
+            }),
+            _ => self.errors.push(format!("tunnel_end({}, {}) must follow tunnel()", namespace, local)),
+        }
+        self
+    }
+
+    /// Sets the GRE key of the last tunnel.
+    pub fn key(mut self, key: u32) -> Self {
+        match (&self.last, self.topology.tunnels.last_mut()){
+            (Some(Item::Tunnel), Some(t)) => t.key = Some(key),
+            _ => self.errors.push(format!("key({}) must follow tunnel()", key)),
+        }
+        self
+    }
+
+    /// Adds a WireGuard link, an empty `subnet` is allocated.
+    pub fn wireguard(mut self, name: &str, subnet: &str) -> Self {
+        self.topology.wireguards.push(WireguardSpec{
+            name: name.to_string(),
+            subnet: subnet.to_string(),
+            ..Default::default()
+        });
+        self.last = Some(Item::Wireguard);
+        self
+    }
+
+    /// Adds an end in `namespace` to the last WireGuard link, reached at
+    /// `local`, an address or interface of the namespace, with `allowed_ips`
+    /// behind it.
+    pub fn wireguard_end(mut self, namespace: &str, local: &str, allowed_ips: &[&str]) -> Self {
+        match (&self.last, self.topology.wireguards.last_mut()){
+            (Some(Item::Wireguard), Some(w)) => w.endpoints.push(WireguardEndSpec{
+                namespace: namespace.to_string(),
+                local: local.to_string(),
+                allowed_ips: allowed_ips.iter().map(|p| p.to_string()).collect(),
+                ..Default::default()
+            }),
+            _ => self.errors.push(format!("wireguard_end({}, {}) must follow wireguard()", namespace, local)),
+        }
+        self
+    }
+
+    /// Adds an address pool links with an empty subnet are allocated
+    /// `/prefix` subnets from. Takes one IPv4 and one IPv6 pool.
+    pub fn ipam(mut self, pool: &str, prefix: u8) -> Self {
+        let spec = self.topology.ipam.get_or_insert_with(Default::default);
+        if pool.contains(':') {
+            spec.pool6 = Some(pool.to_string());
+            spec.prefix6 = prefix;
+        } else {
+            spec.pool = Some(pool.to_string());
+            spec.prefix = prefix;
+        }
+        self
+    }
+
+    /// Adds a pool loopbacks without address are allocated from, one IPv4
+    /// and one IPv6 pool.
+    pub fn loopbacks(mut self, pool: &str) -> Self {
+        let spec = self.topology.ipam.get_or_insert_with(Default::default);
+        if pool.contains(':') {
+            spec.loopbacks6 = Some(pool.to_string());
+        } else {
+            spec.loopbacks = Some(pool.to_string());
+        }
+        self
+    }
+
+    /// Adds an IPv6 subnet to the last (IPv4) link, bridge, VXLAN link,
+    /// tunnel or WireGuard link, making it dual-stack.
+    pub fn subnet6(mut self, subnet: &str) -> Self {
+        match (&self.last, self.topology.links.last_mut(), self.topology.bridges.last_mut()){
+            (Some(Item::Link), Some(l), _) => l.subnet6 = Some(subnet.to_string()),
+            (Some(Item::Bridge), _, Some(b)) => b.subnet6 = Some(subnet.to_string()),
+            (Some(Item::Vxlan), _, _) => if let Some(v) = self.topology.vxlans.last_mut() { v.subnet6 = Some(subnet.to_string()) },
+            (Some(Item::Tunnel), _, _) => if let Some(t) = self.topology.tunnels.last_mut() { t.subnet6 = Some(subnet.to_string()) },
+            (Some(Item::Wireguard), _, _) => if let Some(w) = self.topology.wireguards.last_mut() { w.subnet6 = Some(subnet.to_string()) },
+            _ => self.errors.push(format!("subnet6({}) must follow link(), bridge(), vxlan(), tunnel() or wireguard()", subnet)),
+        }
+        self
+    }
+
+    /// Sets the two namespaces joined by the last link.
+    pub fn connect(mut self, a: &str, b: &str) -> Self {
+        match (&self.last, self.topology.links.last_mut()){
+            (Some(Item::Link), Some(l)) => l.endpoints = vec![a.to_string(), b.to_string()],
+            _ => self.errors.push(format!("connect({}, {}) must follow link()", a, b)),
+        }
+        self
+    }
+
+    /// Adds an existing host interface to be moved into `namespace`.
+    pub fn interface(mut self, name: &str, namespace: &str) -> Self {
+        self.topology.interfaces.push(InterfaceSpec{
+            name: name.to_string(),
+            namespace: Some(namespace.to_string()),
+            ..Default::default()
+        });
+        self.last = Some(Item::Interface);
+        self
+    }
+
+    pub fn ip(mut self, ip: &str) -> Self {
+        match (&self.last, self.topology.interfaces.last_mut()){
+            (Some(Item::Interface), Some(i)) => i.ip = Some(ip.to_string()),
+            _ => self.errors.push(format!("cmd::ip(Some({})) must follow interface()", ip)),
+        }
+        self
+    }
+
+    /// Makes the last interface a host NIC moved into its namespace or a
+    /// macvlan or ipvlan child of one, see `passthrough`.
+    pub fn passthrough(mut self, passthrough: Passthrough) -> Self {
+        match (&self.last, self.topology.interfaces.last_mut()){
+            (Some(Item::Interface), Some(i)) => i.passthrough = Some(passthrough),
+            _ => self.errors.push("passthrough() must follow interface()".to_string()),
+        }
+        self
+    }
+
+    pub fn ip6(mut self, ip: &str) -> Self {
+        match (&self.last, self.topology.interfaces.last_mut()){
+            (Some(Item::Interface), Some(i)) => i.ip6 = Some(ip.to_string()),
+            _ => self.errors.push(format!("ip6({}) must follow interface()", ip)),
+        }
+        self
+    }
+
+    /// Sets the mtu of the last interface or group, or makes the last check
+    /// send unfragmentable packets of this size.
+    pub fn mtu(mut self, mtu: u32) -> Self {
+        match &self.last{
+            Some(Item::Interface) => if let Some(i) = self.topology.interfaces.last_mut() { i.mtu = Some(mtu) },
+            Some(Item::Group) => if let Some(g) = self.topology.groups.last_mut() { g.mtu = Some(mtu) },
+            Some(Item::Check) => if let Some(c) = self.topology.checks.last_mut() { c.mtu = Some(mtu) },
+            _ => self.errors.push(format!("mtu({}) must follow interface(), group() or check()", mtu)),
+        }
+        self
+    }
+
+    pub fn route(mut self, namespace: &str, dst: &str) -> Self {
+        self.topology.routes.push(RouteSpec{
+            namespace: namespace.to_string(),
+            dst: dst.to_string(),
+            ..Default::default()
+        });
+        self.last = Some(Item::Route);
+        self
+    }
+
+    /// Adds a nexthop interface to the last route, call repeatedly for ECMP.
+    pub fn via(mut self, gateway: &str) -> Self {
+        match (&self.last, self.topology.routes.last_mut()){
+            (Some(Item::Route), Some(r)) => r.gateways.push(gateway.to_string()),
+            _ => self.errors.push(format!("via({}) must follow route()", gateway)),
+        }
+        self
+    }
+
+    /// Installs the last route into the table of VRF `vrf` of its namespace.
+    pub fn in_vrf(mut self, vrf: &str) -> Self {
+        match (&self.last, self.topology.routes.last_mut()){
+            (Some(Item::Route), Some(r)) => r.vrf = Some(vrf.to_string()),
+            _ => self.errors.push(format!("in_vrf({}) must follow route()", vrf)),
+        }
+        self
+    }
+
+    /// Installs the last route into routing table `table` instead of main.
+    pub fn table(mut self, table: u32) -> Self {
+        match (&self.last, self.topology.routes.last_mut()){
+            (Some(Item::Route), Some(r)) => r.table = Some(table),
+            _ => self.errors.push(format!("table({}) must follow route()", table)),
+        }
+        self
+    }
+
+    /// Makes the last route drop what it matches instead of forwarding it,
+    /// see `RouteKind`.
+    pub fn route_kind(mut self, kind: RouteKind) -> Self {
+        match (&self.last, self.topology.routes.last_mut()){
+            (Some(Item::Route), Some(r)) => r.kind = kind,
+            _ => self.errors.push(format!("route_kind({}) must follow route()", kind)),
+        }
+        self
+    }
+
+    /// Adds a nexthop with weight, metric or outgoing interface to the last
+    /// route.
+    pub fn nexthop(mut self, nexthop: NexthopSpec) -> Self {
+        match (&self.last, self.topology.routes.last_mut()){
+            (Some(Item::Route), Some(r)) => r.nexthops.push(nexthop),
+            _ => self.errors.push("nexthop() must follow route()".to_string()),
+        }
+        self
+    }
+
+    /// Declares a service address, see `ServiceSpec`.
+    pub fn service(mut self, name: &str, address: &str) -> Self {
+        self.topology.services.push(ServiceSpec{
+            name: name.to_string(),
+            address: address.to_string(),
+            instances: Vec::new(),
+        });
+        self.last = Some(Item::Service);
+        self
+    }
+
+    /// Adds namespaces running an instance of the last service.
+    pub fn instances(mut self, namespaces: &[&str]) -> Self {
+        match (&self.last, self.topology.services.last_mut()){
+            (Some(Item::Service), Some(svc)) => svc.instances.extend(namespaces.iter().map(|n| n.to_string())),
+            _ => self.errors.push("instances() must follow service()".to_string()),
+        }
+        self
+    }
+
+    /// Adds a connectivity check from namespace `from` to `to`, an address
+    /// or namespace, see `verify`.
+    /// Declares interface group `name` with kernel group `id`, followed by
+    /// its settings, `mtu` or `sysctl`.
+    pub fn group(mut self, name: &str, id: u32) -> Self {
+        self.topology.groups.push(GroupSpec{
+            name: name.to_string(),
+            id,
+            ..Default::default()
+        });
+        self.last = Some(Item::Group);
+        self
+    }
+
+    /// Sets a per-interface sysctl, e.g. `ipv4.rp_filter`, on the members of
+    /// the last group, or a sysctl of the last namespace, e.g.
+    /// `net.ipv4.fib_multipath_use_neigh`.
+    pub fn sysctl(mut self, key: &str, value: &str) -> Self {
+        match (&self.last, self.topology.groups.last_mut(), self.topology.namespaces.last_mut()){
+            (Some(Item::Group), Some(g), _) => {
+                g.sysctls.insert(key.to_string(), value.to_string());
+            },
+            (Some(Item::Namespace), _, Some(ns)) => {
+                ns.sysctls.insert(key.to_string(), value.to_string());
+            },
+            _ => self.errors.push(format!("sysctl({}) must follow group() or namespace()", key)),
+        }
+        self
+    }
+
+    /// Mirrors an interface to another one or a span port, see
+    /// `MirrorSpec`.
+    pub fn mirror(mut self, mirror: MirrorSpec) -> Self {
+        self.topology.mirrors.push(mirror);
+        self
+    }
+
+    /// Asserts how the interfaces of a namespace share the growth of a
+    /// counter, see `CounterAssertion`.
+    pub fn counter(mut self, assertion: CounterAssertion) -> Self {
+        self.topology.counters.push(assertion);
+        self
+    }
+
+    pub fn check(mut self, from: &str, to: &str) -> Self {
+        self.topology.checks.push(CheckSpec{
+            from: from.to_string(),
+            to: to.to_string(),
+            ..Default::default()
+        });
+        self.last = Some(Item::Check);
+        self
+    }
+
+    /// Makes the last check a TCP connect to `port`.
+    pub fn port(mut self, port: u16) -> Self {
+        match (&self.last, self.topology.checks.last_mut()){
+            (Some(Item::Check), Some(c)) => c.port = Some(port),
+            _ => self.errors.push(format!("port({}) must follow check()", port)),
+        }
+        self
+    }
+
+    /// Expects the last check to fail.
+    pub fn blocked(mut self) -> Self {
+        match (&self.last, self.topology.checks.last_mut()){
+            (Some(Item::Check), Some(c)) => c.blocked = true,
+            _ => self.errors.push("blocked() must follow check()".to_string()),
+        }
+        self
+    }
+
+    /// Gives the last check a latency budget in milliseconds.
+    pub fn max_rtt(mut self, ms: f64) -> Self {
+        match (&self.last, self.topology.checks.last_mut()){
+            (Some(Item::Check), Some(c)) => c.max_rtt = Some(ms),
+            _ => self.errors.push(format!("max_rtt({}) must follow check()", ms)),
+        }
+        self
+    }
+
+    /// Makes the last check trace the way to its target, which has to pass
+    /// `namespaces` in order, from the source to the target's namespace.
+    pub fn path(mut self, namespaces: &[&str]) -> Self {
+        match (&self.last, self.topology.checks.last_mut()){
+            (Some(Item::Check), Some(c)) => c.path = namespaces.iter().map(|n| n.to_string()).collect(),
+            _ => self.errors.push(format!("path({}) must follow check()", namespaces.join(" "))),
+        }
+        self
+    }
+
+    /// Expects the answers of the last path check to come back through
+    /// `namespaces` rather than its path reversed.
+    pub fn return_path(mut self, namespaces: &[&str]) -> Self {
+        match (&self.last, self.topology.checks.last_mut()){
+            (Some(Item::Check), Some(c)) => c.return_path = namespaces.iter().map(|n| n.to_string()).collect(),
+            _ => self.errors.push(format!("return_path({}) must follow check()", namespaces.join(" "))),
+        }
+        self
+    }
+
+    pub fn build(self) -> Result<Topology>{
+        if !self.errors.is_empty() {
+            return Err(failed!("Invalid topology {}: {}", self.topology.name, self.errors.join(", ")));
+        }
+        Ok(self.topology)
+    }
+}
+
+fn shift_net(net: &str, offset: u32) -> Result<String>{
+    let n: ipnet::IpNet = net.parse()
+        .map_err(|e| failed!("Invalid prefix {}: {}", net, e))?;
+    // default routes cover every copy alike
+    if n.prefix_len() == 0 {
+        return Ok(net.to_string());
+    }
+    let addr = match n{
+        ipnet::IpNet::V4(n) => u32::from(n.addr()).checked_add(offset)
+            .map(|a| std::net::IpAddr::V4(a.into())),
+        ipnet::IpNet::V6(n) => u128::from(n.addr()).checked_add((offset as u128) << 64)
+            .map(|a| std::net::IpAddr::V6(a.into())),
+    };
+    let addr = addr.ok_or_else(|| failed!("Shifting {} by {} leaves the address space", net, offset))?;
+    Ok(format!("{}/{}", addr, n.prefix_len()))
+}
+
+/// `address`, with or without prefix length, moved like `shift_net` moves
+/// its host prefix.
+fn shift_addr(address: &str, offset: u32) -> Result<String>{
+    let (addr, len) = match address.split_once('/'){
+        Some((addr, len)) => (addr, Some(len)),
+        None => (address, None),
+    };
+    let addr: std::net::IpAddr = addr.parse()
+        .map_err(|e| failed!("Invalid address {}: {}", address, e))?;
+    let net: ipnet::IpNet = shift_net(&ipnet::IpNet::from(addr).to_string(), offset)?.parse()?;
+    Ok(match len{
+        Some(len) => format!("{}/{}", net.addr(), len),
+        None => net.addr().to_string(),
+    })
+}
+
+/// Assigned (subnet, IPv6 subnet) of every link and bridge of `config`,
+/// the host prefixes of the loopbacks and the pool and prefix of the NAT64
+/// devices by device name.
+fn subnets(config: &Config) -> HashMap<String, (String, Option<String>)> {
+    let mut subnets = HashMap::new();
+    for l in config.links.values(){
+        subnets.insert(l.name.clone(), (l.subnet.clone(), l.subnet6.clone()));
+    }
+    for b in config.bridges.values(){
+        subnets.insert(b.name.clone(), (b.subnet.clone(), b.subnet6.clone()));
+    }
+    for ns in config.namespaces.values(){
+        let name = loopback::name(&ns.name);
+        if let Some(lo) = config.interfaces.get(&name){
+            match (&lo.ip, &lo.ip6){
+                (Some(ip), ip6) => subnets.insert(name, (ip.clone(), ip6.clone())),
+                (None, Some(ip6)) => subnets.insert(name, (ip6.clone(), None)),
+                (None, None) => None,
+            };
+        }
+        let name = nat64::name(&ns.name);
+        if let Some(Interface{ ip: Some(ip), ip6, .. }) = config.interfaces.get(&name).as_deref(){
+            let net = |ip: &String| ip.parse::<ipnet::IpNet>().map(|n| n.trunc().to_string()).unwrap_or(ip.clone());
+            subnets.insert(name, (net(ip), ip6.as_ref().map(net)));
+        }
+    }
+    subnets
+}
+
+fn namespace(config: &Config, name: &str) -> Result<Arc<Namespace>>{
+    match config.namespaces.get(name){
+        Some(ns) => Ok(ns.clone()),
+        None => Err(RouterError::NamespaceNotFound(name.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn stamped(yaml: &str) -> Topology {
+        let topology: Topology = serde_yaml::from_str(yaml).unwrap();
+        assert!(validate::check(&topology).is_empty());
+        let copy = topology.stamp(1, 1 << 16).unwrap();
+        assert_eq!(validate::check(&copy), Vec::<String>::new());
+        copy
+    }
+
+    #[test]
+    fn stamp_moves_exact_link_addresses(){
+        let copy = stamped("
+name: lab
+namespaces: [{name: r1}, {name: r2}]
+links:
+- {name: a, subnet: 10.0.0.0/24, subnet6: 'fd00::/64', endpoints: [r1, r2], addresses: {r1: [10.0.0.10, 'fd00::10'], r2: [10.0.0.20/24]}}
+");
+        let l = &copy.links[0];
+        assert_eq!((l.subnet.as_str(), l.subnet6.as_deref()), ("10.1.0.0/24", Some("fd00:0:1::/64")));
+        assert_eq!(l.addresses["r1"], ["10.1.0.10", "fd00:0:1::10"]);
+        assert_eq!(l.addresses["r2"], ["10.1.0.20/24"]);
+    }
+
+    #[test]
+    fn stamp_moves_nexthops_and_remotes_on_the_topology(){
+        let copy = stamped("
+name: lab
+namespaces: [{name: r1}, {name: r2}]
+links:
+- {name: a, subnet: 10.0.0.0/30, endpoints: [r1, r2]}
+routes:
+- {namespace: r1, dst: 10.0.1.0/24, nexthops: [{address: 10.0.0.2}]}
+vxlans:
+- {name: v, vni: 10, subnet: 10.0.2.0/30, endpoints: [{namespace: r1, local: r1_a}], remotes: [10.0.0.2, 192.0.2.1], group: 239.1.1.1}
+tunnels:
+- {name: t, subnet: 10.0.3.0/30, endpoints: [{namespace: r1, local: 10.0.0.1}], remote: 198.51.100.1}
+");
+        assert_eq!(copy.routes[0].dst, "10.1.1.0/24");
+        assert_eq!(copy.routes[0].nexthops[0].address.as_deref(), Some("10.1.0.2"));
+        assert_eq!(copy.vxlans[0].remotes, ["10.1.0.2", "192.0.2.1"]);
+        assert_eq!(copy.vxlans[0].group.as_deref(), Some("239.1.1.1"));
+        assert_eq!(copy.tunnels[0].endpoints[0].local, "10.1.0.1");
+        assert_eq!(copy.tunnels[0].remote.as_deref(), Some("198.51.100.1"));
+    }
+}
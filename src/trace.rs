@@ -0,0 +1,125 @@
+//! Tracing of the programs run to build and operate topologies. Every
+//! `ip`, `bridge`, `tc`, `sysctl` or daemon invocation goes through
+//! `Traced`, or `TracedAsync` within a tokio runtime, which logs a
+//! `command` event with the namespace it acts in, the program and its
+//! arguments, how long it took and how it ended, so a slow or failing
+//! bring-up shows which command was slow or failed.
+//!
+//! Events are logged at debug level, failures with the program's stderr.
+//! `init` installs the subscriber writing them to stderr as text or as one
+//! JSON object per line. `RUST_LOG` overrides the level given, e.g.
+//! `RUST_LOG=router_rs::trace=debug`.
+
+use std::ffi::OsStr;
+use std::future::Future;
+use std::process::{Child, Command, ExitStatus, Output};
+use std::time::Instant;
+
+use tracing_subscriber::EnvFilter;
+
+use crate::error::{failed, Result};
+
+/// `Command` runs that are logged.
+pub trait Traced{
+    /// `Command::output`, logged when the program has exited.
+    fn traced_output(&mut self) -> std::io::Result<Output>;
+    /// `Command::status`, logged when the program has exited.
+    fn traced_status(&mut self) -> std::io::Result<ExitStatus>;
+    /// `Command::spawn`, logged when the program was started.
+    fn traced_spawn(&mut self) -> std::io::Result<Child>;
+}
+
+impl Traced for Command{
+    fn traced_output(&mut self) -> std::io::Result<Output>{
+        let start = Instant::now();
+        let output = self.output();
+        match &output{
+            Ok(o) if o.status.success() => log(self, start, &o.status.to_string(), ""),
+            Ok(o) => log(self, start, &o.status.to_string(), String::from_utf8_lossy(&o.stderr).trim()),
+            Err(e) => log(self, start, "not started", &e.to_string()),
+        }
+        output
+    }
+
+    fn traced_status(&mut self) -> std::io::Result<ExitStatus>{
+        let start = Instant::now();
+        let status = self.status();
+        match &status{
+            Ok(s) => log(self, start, &s.to_string(), ""),
+            Err(e) => log(self, start, "not started", &e.to_string()),
+        }
+        status
+    }
+
+    fn traced_spawn(&mut self) -> std::io::Result<Child>{
+        let start = Instant::now();
+        let child = self.spawn();
+        match &child{
+            Ok(c) => log(self, start, &format!("started pid {}", c.id()), ""),
+            Err(e) => log(self, start, "not started", &e.to_string()),
+        }
+        child
+    }
+}
+
+/// `tokio::process::Command` runs that are logged like those of `Traced`.
+pub trait TracedAsync{
+    /// `Command::output`, logged when the program has exited.
+    fn traced_output(&mut self) -> impl Future<Output = std::io::Result<Output>> + Send;
+}
+
+impl TracedAsync for tokio::process::Command{
+    async fn traced_output(&mut self) -> std::io::Result<Output>{
+        let start = Instant::now();
+        let output = self.output().await;
+        match &output{
+            Ok(o) if o.status.success() => log(self.as_std(), start, &o.status.to_string(), ""),
+            Ok(o) => log(self.as_std(), start, &o.status.to_string(), String::from_utf8_lossy(&o.stderr).trim()),
+            Err(e) => log(self.as_std(), start, "not started", &e.to_string()),
+        }
+        output
+    }
+}
+
+/// Namespace `cmd` acts in: the one of `ip -n <netns>`, `bridge -n
+/// <netns>` or `ip netns exec <netns>`, None for the host.
+pub fn namespace(cmd: &Command) -> Option<String> {
+    let args: Vec<&OsStr> = cmd.get_args().collect();
+    let program = cmd.get_program();
+    if program == "ip" || program == "bridge" || program == "tc" {
+        if let Some(i) = args.iter().position(|a| *a == "-n" || *a == "-netns") {
+            return args.get(i + 1).map(|a| a.to_string_lossy().to_string());
+        }
+    }
+    if program == "ip" && args.len() > 2 && args[0] == "netns" && args[1] == "exec" {
+        return Some(args[2].to_string_lossy().to_string());
+    }
+    None
+}
+
+fn log(cmd: &Command, start: Instant, result: &str, stderr: &str){
+    let namespace = namespace(cmd);
+    let args: Vec<_> = cmd.get_args().map(|a| a.to_string_lossy()).collect();
+    tracing::debug!(
+        target: "router_rs::trace",
+        namespace = namespace.as_deref().unwrap_or("-"),
+        program = %cmd.get_program().to_string_lossy(),
+        args = %args.join(" "),
+        duration_us = start.elapsed().as_micros() as u64,
+        result,
+        stderr,
+        "command",
+    );
+}
+
+/// Installs the subscriber logging events at `level` and above to
+/// stderr, one JSON object per line if `json`.
+pub fn init(level: &str, json: bool) -> Result<()>{
+    let filter = match std::env::var("RUST_LOG"){
+        Ok(filter) if !filter.is_empty() => EnvFilter::try_new(filter),
+        _ => EnvFilter::try_new(level),
+    }.map_err(|e| failed!("Invalid log level {}: {}", level, e))?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr);
+    let result = if json { builder.json().try_init() } else { builder.try_init() };
+    result.map_err(|e| failed!("Failed to install the logger: {}", e))
+}
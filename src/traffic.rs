@@ -0,0 +1,353 @@
+//! Test traffic between two namespaces of a running topology: a number of
+//! parallel TCP connections or UDP flows from `src` to an address of `dst`,
+//! each with a source port of its own, so routers hashing on the ports
+//! (`ecmp`) spread them over their equally cheap paths.
+//!
+//! The built-in generator needs nothing installed: TCP flows write as fast
+//! as the connection takes, UDP flows send 1400 byte datagrams, paced to a
+//! rate if given. With `iperf3` the same flows are run by an `iperf3 -s`
+//! in `dst` and an `iperf3 -c -P <flows>` in `src`, and their JSON report
+//! is read back.
+//!
+//! Besides what every flow delivered the report holds how many bytes each
+//! interface of `src` sent meanwhile, which shows how the flows left it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::error::{failed, Result, RouterError};
+use crate::netns;
+use crate::stats;
+use crate::trace::Traced;
+
+/// Size of the UDP datagrams sent by the built-in generator.
+const DATAGRAM: usize = 1400;
+/// Time given to flows to connect, and to the last datagrams to arrive.
+const GRACE: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol{
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl FromStr for Protocol{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<Self>{
+        match s{
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            _ => Err(RouterError::Invalid(format!("Unknown protocol {}, expected tcp or udp", s))),
+        }
+    }
+}
+
+impl fmt::Display for Protocol{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Generator{
+    #[default]
+    Builtin,
+    Iperf3,
+}
+
+impl FromStr for Generator{
+    type Err = RouterError;
+    fn from_str(s: &str) -> Result<Self>{
+        match s{
+            "builtin" => Ok(Generator::Builtin),
+            "iperf3" => Ok(Generator::Iperf3),
+            _ => Err(RouterError::Invalid(format!("Unknown generator {}, expected builtin or iperf3", s))),
+        }
+    }
+}
+
+impl fmt::Display for Generator{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Generator::Builtin => write!(f, "builtin"),
+            Generator::Iperf3 => write!(f, "iperf3"),
+        }
+    }
+}
+
+/// `flows` flows from the namespace `src` to `target`, an address of the
+/// namespace `dst`, for `duration`.
+#[derive(Clone, Debug)]
+pub struct Traffic{
+    pub src: String,
+    pub dst: String,
+    pub target: SocketAddr,
+    pub protocol: Protocol,
+    pub flows: u32,
+    pub duration: Duration,
+    /// Mbit/s per flow, UDP floods if not set, TCP ignores it
+    pub rate: Option<f64>,
+    pub generator: Generator,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FlowResult{
+    /// source port, None if the generator doesn't say
+    pub port: Option<u16>,
+    /// bytes received by `dst`
+    pub bytes: u64,
+    pub mbps: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TrafficReport{
+    pub generator: Generator,
+    pub protocol: Protocol,
+    pub duration: Duration,
+    pub flows: Vec<FlowResult>,
+    /// bytes sent by each interface of `src` during the run
+    pub interfaces: BTreeMap<String, u64>,
+}
+
+impl TrafficReport{
+    /// Mbit/s of all flows together.
+    pub fn mbps(&self) -> f64 {
+        self.flows.iter().map(|f| f.mbps).sum()
+    }
+}
+
+impl fmt::Display for TrafficReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} {} flows ({}) for {:.1}s: {:.1} Mbit/s", self.flows.len(), self.protocol, self.generator, self.duration.as_secs_f64(), self.mbps())?;
+        for (n, flow) in self.flows.iter().enumerate(){
+            let port = flow.port.map(|p| p.to_string()).unwrap_or("-".to_string());
+            writeln!(f, "  flow {:<3} port {:<6} {:>14} bytes {:>10.1} Mbit/s", n + 1, port, flow.bytes, flow.mbps)?;
+        }
+        let sent: u64 = self.interfaces.values().sum();
+        write!(f, "sent by interface:")?;
+        for (interface, bytes) in &self.interfaces{
+            let share = if sent > 0 { 100.0 * *bytes as f64 / sent as f64 } else { 0.0 };
+            write!(f, "\n  {:<16} {:>14} bytes {:>5.1}%", interface, bytes, share)?;
+        }
+        Ok(())
+    }
+}
+
+impl Traffic{
+    pub fn run(&self) -> Result<TrafficReport>{
+        if self.flows == 0 {
+            return Err(failed!("Traffic needs at least one flow"));
+        }
+        if self.rate.is_some_and(|r| r <= 0.0) {
+            return Err(failed!("Invalid rate {:?}, expected Mbit/s above 0", self.rate));
+        }
+        let before = stats::read_all(&self.src)?;
+        let flows = match self.generator{
+            Generator::Builtin => match self.protocol{
+                Protocol::Tcp => self.tcp()?,
+                Protocol::Udp => self.udp()?,
+            },
+            Generator::Iperf3 => self.iperf3()?,
+        };
+        let interfaces = stats::read_all(&self.src)?.into_iter()
+            .map(|(name, after)| {
+                let sent = before.get(&name).map(|b| (after - *b).tx_bytes).unwrap_or(after.tx_bytes);
+                (name, sent)
+            })
+            .collect();
+        Ok(TrafficReport{ generator: self.generator, protocol: self.protocol, duration: self.duration, flows, interfaces })
+    }
+
+    fn bind(&self) -> SocketAddr {
+        let any: IpAddr = if self.target.is_ipv6() { Ipv6Addr::UNSPECIFIED.into() } else { Ipv4Addr::UNSPECIFIED.into() };
+        SocketAddr::new(any, self.target.port())
+    }
+
+    fn result(&self, port: Option<u16>, bytes: u64) -> FlowResult {
+        FlowResult{ port, bytes, mbps: bytes as f64 * 8.0 / self.duration.as_secs_f64().max(f64::EPSILON) / 1_000_000.0 }
+    }
+
+    fn tcp(&self) -> Result<Vec<FlowResult>>{
+        let bind = self.bind();
+        let listener = netns::run_in(&self.dst, || TcpListener::bind(bind)
+            .map_err(|e| failed!("Failed to listen on {} in {}: {}", bind, self.dst, e)))?;
+        let flows = self.flows;
+        // the socket stays in the namespace it was opened in
+        let receiver = std::thread::spawn(move || accept(listener, flows));
+        let senders: Vec<_> = (0..self.flows).map(|_| {
+            let (target, duration) = (self.target, self.duration);
+            netns::spawn_in(&self.src, move || {
+                let mut stream = TcpStream::connect_timeout(&target, GRACE)
+                    .map_err(|e| failed!("Failed to connect to {}: {}", target, e))?;
+                // a path that stopped forwarding fails the flow instead of hanging it
+                stream.set_write_timeout(Some(GRACE))?;
+                let buf = vec![0u8; 128 * 1024];
+                let start = Instant::now();
+                while start.elapsed() < duration{
+                    stream.write_all(&buf)?;
+                }
+                stream.shutdown(std::net::Shutdown::Write)?;
+                Ok(())
+            })
+        }).collect();
+        for sender in senders{
+            sender.join().map_err(|_| failed!("Sender thread panicked"))?
+                .map_err(|e| failed!("Sending from {}: {}", self.src, e))?;
+        }
+        let received = receiver.join().map_err(|_| failed!("Receiver thread panicked"))??;
+        Ok(received.into_iter().map(|(port, bytes)| self.result(Some(port), bytes)).collect())
+    }
+
+    fn udp(&self) -> Result<Vec<FlowResult>>{
+        let bind = self.bind();
+        let socket = netns::run_in(&self.dst, || UdpSocket::bind(bind)
+            .map_err(|e| failed!("Failed to listen on {} in {}: {}", bind, self.dst, e)))?;
+        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let done = Arc::new(AtomicBool::new(false));
+        let stop = done.clone();
+        let receiver = std::thread::spawn(move || {
+            let mut received: HashMap<u16, u64> = HashMap::new();
+            let mut buf = vec![0u8; 65536];
+            let mut last = Instant::now();
+            // the senders finished, wait for what's still in flight
+            while !stop.load(Ordering::Relaxed) || last.elapsed() < Duration::from_millis(500){
+                if let Ok((n, from)) = socket.recv_from(&mut buf){
+                    *received.entry(from.port()).or_default() += n as u64;
+                    last = Instant::now();
+                }
+            }
+            received
+        });
+        // datagrams per second of a flow, None to flood
+        let pace = self.rate.map(|r| r * 1_000_000.0 / 8.0 / DATAGRAM as f64);
+        let senders: Vec<_> = (0..self.flows).map(|_| {
+            let (target, duration) = (self.target, self.duration);
+            let any: SocketAddr = if target.is_ipv6() { "[::]:0".parse().unwrap() } else { "0.0.0.0:0".parse().unwrap() };
+            netns::spawn_in(&self.src, move || {
+                let socket = UdpSocket::bind(any)?;
+                socket.connect(target)?;
+                let buf = vec![0u8; DATAGRAM];
+                let (start, mut sent) = (Instant::now(), 0u64);
+                while start.elapsed() < duration{
+                    if let Some(pace) = pace{
+                        let due = Duration::from_secs_f64(sent as f64 / pace);
+                        if let Some(wait) = due.checked_sub(start.elapsed()){
+                            std::thread::sleep(wait);
+                        }
+                    }
+                    // a full socket buffer drops the datagram like a link would
+                    let _ = socket.send(&buf);
+                    sent += 1;
+                }
+                Ok(socket.local_addr()?.port())
+            })
+        }).collect();
+        let mut ports = Vec::new();
+        let mut error = None;
+        for sender in senders{
+            match sender.join().map_err(|_| failed!("Sender thread panicked")).and_then(|r| r){
+                Ok(port) => ports.push(port),
+                Err(e) => {
+                    error.get_or_insert(e);
+                },
+            }
+        }
+        done.store(true, Ordering::Relaxed);
+        let received = receiver.join().map_err(|_| failed!("Receiver thread panicked"))?;
+        if let Some(e) = error{
+            return Err(failed!("Sending from {}: {}", self.src, e));
+        }
+        Ok(ports.into_iter().map(|port| self.result(Some(port), received.get(&port).copied().unwrap_or(0))).collect())
+    }
+
+    fn iperf3(&self) -> Result<Vec<FlowResult>>{
+        let port = self.target.port().to_string();
+        let mut server = netns::command(&self.dst, "iperf3")
+            .args(["-s", "-1", "-p", port.as_str()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .traced_spawn()
+            .map_err(|e| failed!("Failed to run iperf3 in {}: {}", self.dst, e))?;
+        // the server needs a moment before it listens
+        std::thread::sleep(Duration::from_millis(300));
+        let target = self.target.ip().to_string();
+        let (flows, seconds) = (self.flows.to_string(), self.duration.as_secs().max(1).to_string());
+        let mut args = vec!["-c", target.as_str(), "-p", port.as_str(), "-P", flows.as_str(), "-t", seconds.as_str(), "-J"];
+        let rate = self.rate.map(|r| format!("{}M", r));
+        if self.protocol == Protocol::Udp {
+            args.push("-u");
+            // iperf3 sends 1 Mbit/s per UDP flow unless told otherwise
+            args.extend(["-b", rate.as_deref().unwrap_or("0")]);
+        }
+        let output = netns::command(&self.src, "iperf3").args(&args).traced_output();
+        let _ = server.kill();
+        let _ = server.wait();
+        let output = output.map_err(|e| failed!("Failed to run iperf3 in {}: {}", self.src, e))?;
+        let report: serde_json::Value = serde_json::from_slice(&output.stdout)
+            .map_err(|e| failed!("Unreadable iperf3 report: {}", e))?;
+        if let Some(error) = report["error"].as_str(){
+            return Err(failed!("iperf3 failed: {}", error));
+        }
+        let ports: HashMap<u64, u16> = report["start"]["connected"].as_array().cloned().unwrap_or_default().iter()
+            .filter_map(|c| Some((c["socket"].as_u64()?, c["local_port"].as_u64()? as u16)))
+            .collect();
+        let streams = report["end"]["streams"].as_array().cloned().unwrap_or_default();
+        Ok(streams.iter().map(|s| {
+            let end = match self.protocol{
+                Protocol::Tcp => &s["receiver"],
+                Protocol::Udp => &s["udp"],
+            };
+            let bytes = end["bytes"].as_u64().unwrap_or(0);
+            let port = end["socket"].as_u64().and_then(|socket| ports.get(&socket).copied());
+            FlowResult{ port, bytes, mbps: end["bits_per_second"].as_f64().unwrap_or(0.0) / 1_000_000.0 }
+        }).collect())
+    }
+}
+
+/// Bytes received on each of the first `flows` connections to `listener`
+/// by the source port, giving up on senders which don't connect.
+fn accept(listener: TcpListener, flows: u32) -> Result<Vec<(u16, u64)>>{
+    listener.set_nonblocking(true)?;
+    let deadline = Instant::now() + GRACE;
+    let mut readers = Vec::new();
+    while readers.len() < flows as usize{
+        match listener.accept(){
+            Ok((mut stream, peer)) => {
+                stream.set_nonblocking(false)?;
+                readers.push(std::thread::spawn(move || -> Result<(u16, u64)>{
+                    let mut buf = vec![0u8; 128 * 1024];
+                    let mut bytes = 0u64;
+                    loop{
+                        let n = stream.read(&mut buf)?;
+                        if n == 0 {
+                            return Ok((peer.port(), bytes));
+                        }
+                        bytes += n as u64;
+                    }
+                }));
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock && Instant::now() < deadline => {
+                std::thread::sleep(Duration::from_millis(10));
+            },
+            Err(e) => return Err(failed!("{} of {} flows connected to the receiver: {}", readers.len(), flows, e)),
+        }
+    }
+    readers.into_iter()
+        .map(|r| r.join().map_err(|_| failed!("Reader thread panicked"))?)
+        .collect()
+}
@@ -0,0 +1,173 @@
+//! Journal of the kernel objects created while building a topology, so a
+//! build failing halfway can be undone instead of leaving orphaned
+//! namespaces, veths and addresses behind.
+//!
+//! Whatever is torn down, a failed build or a destroyed topology, goes in
+//! the order `teardown` works out from the `Layer` of each resource:
+//! processes first, then addresses, devices stacked on others (tunnels,
+//! VXLANs, VRFs), veths and moved interfaces, namespaces last. A resource
+//! rests on those of a lower layer in its namespace, and devices on the
+//! devices of their namespace created before them, so a tunnel goes before
+//! its underlay. New kinds of resources only pick their layer.
+
+use std::path::PathBuf;
+
+use crate::cmd;
+use crate::error::{failed, Result};
+use crate::passthrough::{self, MovedNic};
+use crate::{daemon, pool, Namespace};
+
+/// One successfully created object and what it takes to undo it.
+#[derive(Clone, Debug)]
+pub enum Resource{
+    /// namespace created, or taken from the pool if `pooled`
+    Namespace{ netns: String, pooled: bool },
+    /// veth pair created, `name` is the end inside `netns`
+    Veth{ name: String, netns: String },
+    /// virtual device other than a veth created inside `netns`
+    Device{ name: String, netns: String },
+    /// existing host interface moved into `netns`
+    Moved(MovedNic),
+    /// address added to an interface, `netns` is None for the host
+    Address{ name: String, netns: Option<String>, address: String },
+    /// routing daemon started with its runtime directory `dir`
+    Daemon{ dir: PathBuf },
+}
+
+/// What a resource rests on, torn down in this order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Layer{
+    /// daemons and other processes, which keep namespaces alive
+    Process,
+    Address,
+    Device,
+    Namespace,
+}
+
+impl Resource{
+    pub fn layer(&self) -> Layer {
+        match self{
+            Resource::Daemon{ .. } => Layer::Process,
+            Resource::Address{ .. } => Layer::Address,
+            Resource::Veth{ .. } | Resource::Device{ .. } | Resource::Moved(_) => Layer::Device,
+            Resource::Namespace{ .. } => Layer::Namespace,
+        }
+    }
+
+    /// Namespace the resource lives in, None for the host and for
+    /// processes, which may use any.
+    fn netns(&self) -> Option<&str> {
+        match self{
+            Resource::Namespace{ netns, .. } | Resource::Veth{ netns, .. } | Resource::Device{ netns, .. } => Some(netns),
+            Resource::Moved(nic) => Some(&nic.netns),
+            Resource::Address{ netns, .. } => netns.as_deref(),
+            Resource::Daemon{ .. } => None,
+        }
+    }
+
+    /// True if `self` has to be undone before `other`, `self` created
+    /// after `other` if `later`.
+    fn rests_on(&self, other: &Resource, later: bool) -> bool {
+        let scoped = match (self.netns(), other.netns()){
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        match self.layer().cmp(&other.layer()){
+            std::cmp::Ordering::Less => scoped,
+            std::cmp::Ordering::Equal => self.layer() == Layer::Device && scoped && later,
+            std::cmp::Ordering::Greater => false,
+        }
+    }
+}
+
+/// `resources`, listed in the order they were created, in an order they
+/// can be undone in: every resource before those it rests on, later ones
+/// first where nothing else decides.
+pub fn teardown(resources: Vec<Resource>) -> Vec<Resource> {
+    let n = resources.len();
+    // resources each one waits for
+    let mut blockers = vec![0usize; n];
+    for (i, r) in resources.iter().enumerate(){
+        for (j, o) in resources.iter().enumerate(){
+            if i != j && o.rests_on(r, j > i) {
+                blockers[i] += 1;
+            }
+        }
+    }
+    let mut done = vec![false; n];
+    let mut order = Vec::with_capacity(n);
+    while order.len() < n {
+        // the relation follows the layers, so there is always one ready
+        let Some(next) = (0..n).rev().find(|&i| !done[i] && blockers[i] == 0) else {
+            break;
+        };
+        done[next] = true;
+        order.push(next);
+        for (j, o) in resources.iter().enumerate(){
+            if !done[j] && resources[next].rests_on(o, next > j) {
+                blockers[j] -= 1;
+            }
+        }
+    }
+    let mut resources: Vec<Option<Resource>> = resources.into_iter().map(Some).collect();
+    order.into_iter().filter_map(|i| resources[i].take()).collect()
+}
+
+/// Undoes `resources` in `teardown` order. Keeps going on errors and
+/// reports them together at the end.
+pub fn undo_all(resources: Vec<Resource>) -> Result<()>{
+    let errors = undo_ordered(resources);
+    if !errors.is_empty() {
+        return Err(failed!("Failed to tear down: {}", errors.join("; ")));
+    }
+    Ok(())
+}
+
+fn undo_ordered(resources: Vec<Resource>) -> Vec<String> {
+    teardown(resources).into_iter()
+        .filter_map(|r| undo(&r).err().map(|e| format!("{:?}: {}", r, e)))
+        .collect()
+}
+
+#[derive(Default)]
+pub struct Transaction{
+    resources: Vec<Resource>,
+}
+
+impl Transaction{
+    pub fn record(&mut self, resource: Resource){
+        self.resources.push(resource);
+    }
+
+    /// Keeps everything created so far.
+    pub fn commit(&mut self){
+        self.resources.clear();
+    }
+
+    /// Hands the resources recorded so far over to the caller, the
+    /// transaction no longer undoes them, see `guard::ResourceGuard`.
+    pub fn take(&mut self) -> Vec<Resource> {
+        std::mem::take(&mut self.resources)
+    }
+
+    /// Undoes all recorded resources in `teardown` order. Keeps going on
+    /// errors and reports them together at the end.
+    pub fn rollback(&mut self) -> Result<()>{
+        let errors = undo_ordered(std::mem::take(&mut self.resources));
+        if !errors.is_empty() {
+            return Err(failed!("Failed to roll back: {}", errors.join("; ")));
+        }
+        Ok(())
+    }
+}
+
+fn undo(resource: &Resource) -> Result<()>{
+    match resource{
+        Resource::Namespace{ netns, pooled: true } => pool::release_namespace(netns),
+        Resource::Namespace{ netns, pooled: false } => Ok(Namespace::delete(netns)?),
+        Resource::Veth{ name, netns } | Resource::Device{ name, netns } => Ok(cmd::ip(Some(netns), &["link", "del", "dev", name]).map(|_| ())?),
+        Resource::Moved(nic) => Ok(passthrough::restore(nic)?),
+        Resource::Address{ name, netns, address } => Ok(cmd::ip(netns.as_deref(), &["addr", "del", address, "dev", name]).map(|_| ())?),
+        Resource::Daemon{ dir } => daemon::stop_dir(dir),
+    }
+}
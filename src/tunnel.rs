@@ -0,0 +1,193 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::{Result, RouterError};
+use crate::interface;
+use crate::transaction::Resource;
+use crate::vxlan::overlay_addr;
+use crate::{Config, Interface, Namespace};
+
+/// Devices the kernel creates in every namespace once a tunnel module is
+/// loaded, not ours to delete.
+pub(crate) const FALLBACK_DEVICES: [&str; 7] = ["gre0", "gretap0", "erspan0", "tunl0", "sit0", "ip6gre0", "ip6tnl0"];
+
+/// Encapsulation of a `Tunnel`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelKind{
+    /// IPv4 and IPv6 over GRE
+    #[default]
+    Gre,
+    /// Ethernet over GRE
+    Gretap,
+    /// IPv4 over IPv4
+    Ipip,
+    /// IPv6 and IPv4 over IPv4
+    Sit,
+}
+
+impl fmt::Display for TunnelKind{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            TunnelKind::Gre => write!(f, "gre"),
+            TunnelKind::Gretap => write!(f, "gretap"),
+            TunnelKind::Ipip => write!(f, "ipip"),
+            TunnelKind::Sit => write!(f, "sit"),
+        }
+    }
+}
+
+impl TunnelKind{
+    /// Device type of a tunnel with an IPv4 or IPv6 underlay, ipip and sit
+    /// only run over IPv4.
+    pub fn device(&self, v6: bool) -> Option<&'static str> {
+        match (self, v6){
+            (TunnelKind::Gre, false) => Some("gre"),
+            (TunnelKind::Gre, true) => Some("ip6gre"),
+            (TunnelKind::Gretap, false) => Some("gretap"),
+            (TunnelKind::Gretap, true) => Some("ip6gretap"),
+            (TunnelKind::Ipip, false) => Some("ipip"),
+            (TunnelKind::Sit, false) => Some("sit"),
+            (TunnelKind::Ipip | TunnelKind::Sit, true) => None,
+        }
+    }
+}
+
+/// Point-to-point tunnel between two namespaces, or from one namespace to
+/// an endpoint outside the topology. Each end is a tunnel device named
+/// `<namespace>_<tunnel>`, addressed like the ends of a `Link` from subnets
+/// assigned the same way, so tunnels are gateways of routes and interfaces
+/// of the routing daemon like veths.
+pub struct Tunnel{
+    pub name: String,
+    pub kind: TunnelKind,
+    pub subnet: String,
+    pub subnet6: Option<String>,
+    /// GRE key, in both directions
+    pub key: Option<u32>,
+    /// TTL of the outer header, inherited from the inner one if not set
+    pub ttl: Option<u8>,
+}
+
+/// End of a `Tunnel` in a namespace.
+pub struct TunnelEnd{
+    pub namespace: Arc<Namespace>,
+    /// underlay address the tunnel is sourced from
+    pub local: IpAddr,
+    /// underlay interface carrying the tunnel
+    pub dev: Option<String>,
+    /// host number of the tunnel addresses instead of those of a link's end
+    pub host: Option<u32>,
+}
+
+impl Tunnel{
+    pub fn new(name: String, kind: TunnelKind, subnet: String, subnet6: Option<String>, key: Option<u32>, ttl: Option<u8>, config: &Config) -> Result<Arc<Tunnel>>{
+        if config.tunnels.contains_key(&name) || config.links.contains_key(&name) || config.bridges.contains_key(&name) || config.vxlans.contains_key(&name) || config.wireguards.contains_key(&name) {
+            return Err(RouterError::Exists{ kind: "Tunnel", name });
+        }
+        if key.is_some() && !matches!(kind, TunnelKind::Gre | TunnelKind::Gretap) {
+            return Err(RouterError::Invalid(format!("{} tunnel {} takes no key, only GRE tunnels do", kind, name)));
+        }
+        let (subnet, subnet6) = config.ipam().assign(subnet, subnet6)
+            .map_err(|e| e.context(format!("Tunnel {}", name)))?;
+        if kind == TunnelKind::Ipip && (subnet.contains(':') || subnet6.is_some()) {
+            return Err(RouterError::Invalid(format!("ipip tunnel {} carries IPv4 only, use sit for IPv6", name)));
+        }
+        let t = Arc::new(Tunnel{
+            name: name.clone(),
+            kind,
+            subnet,
+            subnet6,
+            key,
+            ttl,
+        });
+        config.tunnels.insert(name, t.clone());
+        Ok(t)
+    }
+
+    /// Creates the tunnel devices of `ends` and addresses them. With one
+    /// end the tunnel leads to `remote`.
+    pub fn attach(&self, ends: &[TunnelEnd], remote: Option<IpAddr>, config: &Config) -> Result<Vec<Arc<Interface>>>{
+        let underlay: Vec<IpAddr> = ends.iter().map(|e| e.local).chain(remote).collect();
+        if underlay.len() != 2 {
+            return Err(RouterError::Invalid(format!("Tunnel {} needs two ends, or one and a remote, got {}", self.name, underlay.len())));
+        }
+        if underlay[0].is_ipv6() != underlay[1].is_ipv6() {
+            return Err(RouterError::Invalid(format!("Tunnel {} mixes IPv4 and IPv6 endpoints", self.name)));
+        }
+        let device = self.kind.device(underlay[0].is_ipv6())
+            .ok_or_else(|| RouterError::Invalid(format!("{} tunnel {} needs IPv4 endpoints", self.kind, self.name)))?;
+        let mut subnets = Vec::new();
+        for subnet in std::iter::once(&self.subnet).chain(self.subnet6.iter()){
+            let sn: ipnet::IpNet = subnet.parse()
+                .map_err(|e: ipnet::AddrParseError| RouterError::InvalidSubnet{ subnet: subnet.clone(), reason: e.to_string() })?;
+            subnets.push(sn);
+        }
+        let mut interfaces = Vec::new();
+        for (n, end) in ends.iter().enumerate(){
+            let name = interface::name(&end.namespace.name, &self.name);
+            self.setup(&name, device, end, underlay[1 - n], config)?;
+            interface::alias(&name, Some(&end.namespace.netns), &format!("{}_{}", end.namespace.name, self.name), config)?;
+
+            let (mut ip, mut ip6) = (None, None);
+            for sn in &subnets{
+                let addr = overlay_addr(sn, n, true, end.host)?;
+                if sn.addr().is_ipv6() {
+                    ip6 = Some(addr);
+                } else {
+                    ip = Some(addr);
+                }
+            }
+            interfaces.push(Interface::new(name, Some(end.namespace.clone()), ip, ip6, None, config)?);
+        }
+        Ok(interfaces)
+    }
+
+    /// Creates the device `name` of `end` towards `remote`. When
+    /// reconciling, a device with the same settings is kept.
+    fn setup(&self, name: &str, device: &str, end: &TunnelEnd, remote: IpAddr, config: &Config) -> Result<()>{
+        let netns = end.namespace.netns.as_str();
+        if config.reconcile {
+            if let Ok(out) = cmd::ip(Some(netns), &["-d", "-j", "link", "show", "dev", name]) {
+                let links: serde_json::Value = serde_json::from_str(&out)?;
+                let data = &links[0]["linkinfo"]["info_data"];
+                // keys are printed like IPv4 addresses
+                let key = self.key.map(|k| Ipv4Addr::from(k).to_string());
+                let ttl = data["ttl"].as_u64().or(data["hoplimit"].as_u64());
+                let same = links[0]["linkinfo"]["info_kind"] == device
+                    && data["local"].as_str() == Some(end.local.to_string().as_str())
+                    && data["remote"].as_str() == Some(remote.to_string().as_str())
+                    && data["ikey"].as_str() == key.as_deref()
+                    && self.ttl.is_none_or(|t| ttl == Some(t as u64));
+                if same {
+                    return Ok(());
+                }
+                cmd::ip(Some(netns), &["link", "del", "dev", name])?;
+            }
+        }
+        let (local, remote) = (end.local.to_string(), remote.to_string());
+        let mut args = vec!["link", "add", "name", name, "type", device, "local", local.as_str(), "remote", remote.as_str()];
+        let (key, ttl) = (self.key.map(|k| k.to_string()), self.ttl.map(|t| t.to_string()));
+        if let Some(key) = &key{
+            args.extend(["key", key.as_str()]);
+        }
+        if let Some(ttl) = &ttl{
+            args.extend(["ttl", ttl.as_str()]);
+        }
+        if let Some(dev) = &end.dev{
+            args.extend(["dev", dev.as_str()]);
+        }
+        // carry IPv4 as well as IPv6
+        if self.kind == TunnelKind::Sit {
+            args.extend(["mode", "any"]);
+        }
+        cmd::ip(Some(netns), &args)
+            .map_err(|e| e.context(format!("Failed to create tunnel {} in {}", self.name, end.namespace.name)))?;
+        config.transaction().record(Resource::Device{ name: name.to_string(), netns: netns.to_string() });
+        Ok(())
+    }
+}
@@ -0,0 +1,357 @@
+//! Checks of the addressing of a topology, run by `apply` and `reconcile`
+//! before anything is created, so a description with a typo in a subnet
+//! fails with every problem listed instead of halfway through the build
+//! with the first one the kernel rejects:
+//!
+//! - subnets of links, bridges, VXLAN links, tunnels and WireGuard links
+//!   which don't parse or overlap each other
+//! - addresses assigned twice, to link and bridge ends, loopbacks and host
+//!   interfaces
+//! - gateways of routes given by hand which are interfaces without an
+//!   address of the route's family
+//! - nexthops on none of the subnets of the route's namespace, the kernel
+//!   only takes those marked `onlink`
+//!
+//! Only what the description fixes is checked. Subnets left to IPAM don't
+//! overlap by construction, and a namespace with ends whose addresses
+//! aren't known before the build, from IPAM, overlays or containers, may
+//! be on any subnet, its nexthops aren't checked. Neither are IPv6
+//! nexthops where router advertisements may add addresses.
+
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use crate::error::{Result, RouterError};
+use crate::link::host_addr;
+use crate::topology::{NexthopSpec, RouteSpec, Topology};
+use crate::{interface, loopback, RouteKind};
+
+/// Address of an interface as the description assigns it.
+struct Assigned{
+    /// logical name of the interface
+    owner: String,
+    /// None for the host
+    namespace: Option<String>,
+    addr: IpNet,
+}
+
+/// What the description fixes of the addressing.
+#[derive(Default)]
+struct Addressing{
+    /// subnets given by hand, with what they belong to, e.g. "link ab"
+    subnets: Vec<(String, IpNet)>,
+    addresses: Vec<Assigned>,
+    /// addresses of the interfaces by logical and kernel name, None if
+    /// left to IPAM
+    interfaces: HashMap<String, Option<Vec<IpNet>>>,
+    /// addresses on the other end of unnumbered links, reached over a peer
+    /// route, by namespace
+    peers: Vec<(String, IpAddr)>,
+    /// namespaces on subnets not known before the build
+    uncertain: BTreeSet<String>,
+    problems: Vec<String>,
+}
+
+/// Addressing problems of `topology`, none if it is consistent.
+pub fn check(topology: &Topology) -> Vec<String> {
+    let mut a = Addressing::default();
+    a.collect(topology);
+    a.overlaps();
+    a.duplicates();
+    let advertised = topology.namespaces.iter().any(|ns| ns.ra.is_some());
+    for r in &topology.routes{
+        a.route(topology, r, advertised);
+    }
+    a.problems
+}
+
+/// Fails with all problems `check` found.
+pub fn run(topology: &Topology) -> Result<()>{
+    let problems = check(topology);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(RouterError::Addressing{ topology: topology.name.clone(), problems })
+}
+
+impl Addressing{
+    fn collect(&mut self, topology: &Topology){
+        for ns in &topology.namespaces{
+            if ns.nat64.is_some() || ns.container.is_some() {
+                self.uncertain.insert(ns.name.clone());
+            }
+            let Some(lo) = &ns.loopback else {
+                continue;
+            };
+            let what = format!("loopback of {}", ns.name);
+            let addrs: Vec<IpNet> = [&lo.address, &lo.address6].into_iter().flatten()
+                .filter_map(|a| self.address(&what, a))
+                .collect();
+            // without any address IPAM assigns them
+            let known = lo.address.is_some() || lo.address6.is_some();
+            self.assign(&loopback::name(&ns.name), &format!("{}_lo", ns.name), Some(&ns.name), known.then_some(addrs));
+        }
+        for l in &topology.links{
+            let what = format!("link {}", l.name);
+            let ends = match l.ends(){
+                Ok(ends) => ends,
+                Err(e) => {
+                    self.problems.push(e.to_string());
+                    continue;
+                },
+            };
+            let names: Vec<(String, String)> = l.endpoints.iter()
+                .map(|ns| (interface::name(ns, &l.name), format!("{}_{}", ns, l.name)))
+                .collect();
+            if ends.unnumbered {
+                for (n, ns) in l.endpoints.iter().enumerate().take(2){
+                    let Some(other) = l.endpoints.get(1 - n) else {
+                        continue;
+                    };
+                    match self.interfaces.get(&loopback::name(other)).cloned().flatten(){
+                        Some(addrs) => self.peers.extend(addrs.iter().map(|a| (ns.clone(), a.addr()))),
+                        None => {
+                            self.uncertain.insert(ns.clone());
+                        },
+                    }
+                    let own = self.interfaces.get(&loopback::name(ns)).cloned().flatten();
+                    for name in [&names[n].0, &names[n].1]{
+                        self.interfaces.insert(name.clone(), own.clone());
+                    }
+                }
+                continue;
+            }
+            if l.subnet.is_empty() {
+                for ((kernel, logical), ns) in names.iter().zip(&l.endpoints){
+                    self.assign(kernel, logical, Some(ns), None);
+                }
+                continue;
+            }
+            let mut addrs: [Vec<IpNet>; 2] = Default::default();
+            for subnet in std::iter::once(&l.subnet).chain(l.subnet6.iter()){
+                let Some(sn) = self.subnet(&what, subnet) else {
+                    continue;
+                };
+                match ends.addrs(&sn){
+                    Ok((a, b)) => {
+                        for (end, addr) in addrs.iter_mut().zip([a, b]){
+                            end.extend(self.address(&what, &addr));
+                        }
+                    },
+                    Err(e) => self.problems.push(format!("Link {}: {}", l.name, e)),
+                }
+            }
+            for (((kernel, logical), ns), addrs) in names.iter().zip(&l.endpoints).zip(addrs){
+                self.assign(kernel, logical, Some(ns), Some(addrs));
+            }
+        }
+        for b in &topology.bridges{
+            let what = format!("bridge {}", b.name);
+            let subnets: Vec<IpNet> = match b.subnet.is_empty(){
+                true => Vec::new(),
+                false => std::iter::once(&b.subnet).chain(b.subnet6.iter())
+                    .filter_map(|s| self.subnet(&what, s))
+                    .collect(),
+            };
+            for (n, ns) in b.members.iter().enumerate(){
+                let addrs = subnets.iter()
+                    .filter_map(|sn| host_addr(sn, n as u128 + 1).ok())
+                    .filter_map(|a| self.address(&what, &a))
+                    .collect();
+                let known = (!b.subnet.is_empty()).then_some(addrs);
+                self.assign(&interface::name(ns, &b.name), &format!("{}_{}", ns, b.name), Some(ns), known);
+            }
+        }
+        // overlay ends are numbered by the build
+        let overlays = topology.vxlans.iter().map(|v| (format!("VXLAN link {}", v.name), &v.subnet, &v.subnet6, v.endpoints.iter().map(|e| &e.namespace).collect::<Vec<_>>()))
+            .chain(topology.tunnels.iter().map(|t| (format!("tunnel {}", t.name), &t.subnet, &t.subnet6, t.endpoints.iter().map(|e| &e.namespace).collect())))
+            .chain(topology.wireguards.iter().map(|w| (format!("WireGuard link {}", w.name), &w.subnet, &w.subnet6, w.endpoints.iter().map(|e| &e.namespace).collect())));
+        for (what, subnet, subnet6, namespaces) in overlays{
+            for s in std::iter::once(subnet).filter(|s| !s.is_empty()).chain(subnet6.iter()){
+                self.subnet(&what, s);
+            }
+            self.uncertain.extend(namespaces.into_iter().cloned());
+        }
+        for i in &topology.interfaces{
+            let what = format!("interface {}", i.name);
+            let addrs = [&i.ip, &i.ip6].into_iter().flatten()
+                .filter_map(|a| self.address(&what, a))
+                .collect();
+            self.assign(&i.name, &i.name, i.namespace.as_deref(), Some(addrs));
+        }
+    }
+
+    /// Registers interface `kernel`, also called `logical`, with its
+    /// addresses `addrs`, None if not known yet.
+    fn assign(&mut self, kernel: &str, logical: &str, namespace: Option<&str>, addrs: Option<Vec<IpNet>>){
+        match &addrs{
+            Some(addrs) => self.addresses.extend(addrs.iter().map(|addr| Assigned{ owner: logical.to_string(), namespace: namespace.map(str::to_string), addr: *addr })),
+            None => self.uncertain.extend(namespace.map(str::to_string)),
+        }
+        self.interfaces.insert(kernel.to_string(), addrs.clone());
+        self.interfaces.insert(logical.to_string(), addrs);
+    }
+
+    fn subnet(&mut self, what: &str, subnet: &str) -> Option<IpNet>{
+        match subnet.parse::<IpNet>(){
+            Ok(net) => {
+                self.subnets.push((what.to_string(), net.trunc()));
+                Some(net)
+            },
+            Err(e) => {
+                self.problems.push(format!("Invalid subnet {} of {}: {}", subnet, what, e));
+                None
+            },
+        }
+    }
+
+    /// `address` with prefix length, a host prefix if it has none.
+    fn address(&mut self, what: &str, address: &str) -> Option<IpNet>{
+        let parsed = address.parse::<IpNet>()
+            .or_else(|_| address.parse::<IpAddr>().map(IpNet::from));
+        match parsed{
+            Ok(net) => Some(net),
+            Err(e) => {
+                self.problems.push(format!("Invalid address {} of {}: {}", address, what, e));
+                None
+            },
+        }
+    }
+
+    fn overlaps(&mut self){
+        for (n, (what, a)) in self.subnets.iter().enumerate(){
+            for (other, b) in &self.subnets[n + 1..]{
+                if a.contains(&b.network()) || b.contains(&a.network()) {
+                    self.problems.push(format!("Subnet {} of {} overlaps subnet {} of {}", a, what, b, other));
+                }
+            }
+        }
+    }
+
+    fn duplicates(&mut self){
+        for (n, a) in self.addresses.iter().enumerate(){
+            for b in self.addresses[n + 1..].iter().filter(|b| b.addr.addr() == a.addr.addr()){
+                self.problems.push(format!("Address {} is assigned to both {} and {}", a.addr.addr(), a.owner, b.owner));
+            }
+        }
+    }
+
+    fn route(&mut self, topology: &Topology, r: &RouteSpec, advertised: bool){
+        let what = format!("Route to {} in {}", r.dst, r.namespace);
+        if !topology.namespaces.iter().any(|ns| ns.name == r.namespace) {
+            self.problems.push(format!("{}: no such namespace", what));
+            return;
+        }
+        if r.kind != RouteKind::Unicast {
+            return;
+        }
+        let v6 = match r.dst.parse::<IpNet>().or_else(|_| r.dst.parse::<IpAddr>().map(IpNet::from)){
+            Ok(dst) => dst.addr().is_ipv6(),
+            Err(e) => {
+                self.problems.push(format!("{}: invalid destination: {}", what, e));
+                return;
+            },
+        };
+        let via = r.gateways.iter().map(|g| NexthopSpec{ via: Some(g.clone()), ..Default::default() });
+        for nh in via.chain(r.nexthops.iter().cloned()){
+            let gateway = match (&nh.address, &nh.via){
+                (Some(address), _) => match address.parse::<IpAddr>(){
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        self.problems.push(format!("{}: invalid gateway {}: {}", what, address, e));
+                        continue;
+                    },
+                },
+                (None, Some(via)) => {
+                    // unknown names are reported by the build, which also
+                    // knows the overlay ends
+                    let Some(Some(addrs)) = self.interfaces.get(via) else {
+                        continue;
+                    };
+                    match addrs.iter().find(|a| a.addr().is_ipv6() == v6){
+                        Some(a) => a.addr(),
+                        None => {
+                            self.problems.push(format!("{}: gateway {} has no {} address", what, via, if v6 { "IPv6" } else { "IPv4" }));
+                            continue;
+                        },
+                    }
+                },
+                (None, None) => continue,
+            };
+            if nh.onlink || self.uncertain.contains(&r.namespace) || gateway.is_ipv6() && advertised || link_local(&gateway) {
+                continue;
+            }
+            let connected = self.addresses.iter()
+                .filter(|a| a.namespace.as_deref() == Some(r.namespace.as_str()))
+                .any(|a| a.addr.contains(&gateway) && a.addr.prefix_len() < a.addr.max_prefix_len())
+                || self.peers.iter().any(|(ns, peer)| *ns == r.namespace && *peer == gateway);
+            if !connected {
+                self.problems.push(format!("{}: nexthop {} is on none of the subnets of {}, mark it onlink if it is reachable anyway", what, gateway, r.namespace));
+            }
+        }
+    }
+}
+
+fn link_local(addr: &IpAddr) -> bool {
+    match addr{
+        IpAddr::V4(a) => a.is_link_local(),
+        IpAddr::V6(a) => a.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn problems(extra: &str) -> Vec<String> {
+        let yaml = format!("
+name: lab
+namespaces: [{{name: r1}}, {{name: r2}}, {{name: r3}}]
+links:
+- {{name: a, subnet: 10.0.0.0/30, endpoints: [r1, r2]}}
+- {{name: b, subnet: 10.0.0.4/30, endpoints: [r2, r3]}}
+{}", extra);
+        check(&serde_yaml::from_str(&yaml).unwrap())
+    }
+
+    #[test]
+    fn consistent_addressing_has_no_problems(){
+        assert!(problems("routes: [{namespace: r1, dst: 10.0.0.4/30, gateways: [r2_a]}]").is_empty());
+        assert!(problems("routes: [{namespace: r1, dst: 10.0.0.4/30, nexthops: [{address: 10.0.0.2}]}]").is_empty());
+    }
+
+    #[test]
+    fn overlapping_and_invalid_subnets_are_reported(){
+        let found = problems("bridges: [{name: c, subnet: 10.0.0.0/29, members: [r1, r3]}, {name: d, subnet: 10.0.1.0/33, members: [r2]}]");
+        assert!(found.iter().any(|p| p == "Subnet 10.0.0.4/30 of link b overlaps subnet 10.0.0.0/29 of bridge c"), "{:?}", found);
+        assert!(found.iter().any(|p| p.starts_with("Invalid subnet 10.0.1.0/33 of bridge d")), "{:?}", found);
+    }
+
+    #[test]
+    fn addresses_assigned_twice_are_reported(){
+        let found = problems("interfaces: [{name: eth9, namespace: r3, ip: 10.0.0.1/24}]");
+        assert_eq!(found, vec!["Address 10.0.0.1 is assigned to both r1_a and eth9".to_string()]);
+    }
+
+    #[test]
+    fn nexthops_off_the_namespaces_subnets_are_reported(){
+        let found = problems("routes: [{namespace: r1, dst: 10.9.0.0/24, nexthops: [{address: 10.0.0.6}]}]");
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert!(found[0].contains("nexthop 10.0.0.6 is on none of the subnets of r1"));
+        assert!(problems("routes: [{namespace: r1, dst: 10.9.0.0/24, nexthops: [{address: 10.0.0.6, onlink: true}]}]").is_empty());
+    }
+
+    #[test]
+    fn gateways_need_an_address_of_the_routes_family(){
+        let found = problems("routes: [{namespace: r1, dst: 'fd00::/64', gateways: [r2_a]}]");
+        assert_eq!(found, vec!["Route to fd00::/64 in r1: gateway r2_a has no IPv6 address".to_string()]);
+    }
+
+    #[test]
+    fn routes_of_unknown_namespaces_are_reported(){
+        let found = problems("routes: [{namespace: r9, dst: 10.9.0.0/24, gateways: [r2_a]}]");
+        assert_eq!(found, vec!["Route to 10.9.0.0/24 in r9: no such namespace".to_string()]);
+    }
+}
@@ -0,0 +1,698 @@
+//! Connectivity checks between namespaces of a running topology, so a lab
+//! can serve as CI test harness: every check pings its target from inside
+//! the source namespace, or opens a TCP connection when it names a port,
+//! and yields a pass/fail result. MTU checks send full-size UDP packets
+//! which must not be fragmented, so they only arrive if every hop on the
+//! path carries the size. A check with a latency budget fails as well when
+//! any answer takes longer, measured with the impairments of the links in
+//! place, so the lab can gate SLA regressions.
+//!
+//! Path MTU probes search the largest unfragmented packet that makes it
+//! to the target. A path carrying less than the source's egress MTU fails,
+//! and is flagged as blackhole when no ICMP "packet too big" told the
+//! source about it, which is how a hop with a smaller MTU silently drops
+//! large packets. `mtu_mismatches` compares the MTUs of the ends of links
+//! and bridges, the usual cause.
+//!
+//! A check declaring a `path` traces the route to its target instead, UDP
+//! probes with rising TTL whose ICMP answers name the hops, and passes if
+//! the namespaces they belong to are the ones declared, e.g. `p1 r1 r2 p2`.
+//! The way back is traced from the target to the source address the probes
+//! came from and has to be the same path reversed, unless the check
+//! declares a `return_path` of its own, so asymmetric routing doesn't go
+//! unnoticed.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd;
+use crate::error::{failed, Result, RouterError};
+use crate::interface;
+use crate::netns;
+use crate::state::State;
+use crate::topology::Topology;
+use crate::trace::Traced;
+use crate::Namespace;
+
+/// Check declared in a topology. `to` is an address or the name of a
+/// namespace, which stands for its first IPv4 address (IPv6 if it has none)
+/// in interface name order.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CheckSpec{
+    pub from: String,
+    pub to: String,
+    /// TCP connect to this port instead of pinging
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// send IP packets of this many bytes with DF set instead of pinging
+    #[serde(default)]
+    pub mtu: Option<u32>,
+    /// passes if the target can't be reached, e.g. behind a firewall
+    #[serde(default)]
+    pub blocked: bool,
+    /// latency budget in milliseconds: the slowest ping or connect may take
+    /// at most this long
+    #[serde(default)]
+    pub max_rtt: Option<f64>,
+    /// namespaces the packets pass, from `from` to the target's namespace,
+    /// verified by traceroute
+    #[serde(default)]
+    pub path: Vec<String>,
+    /// namespaces the answers pass back, `path` reversed if empty
+    #[serde(default)]
+    pub return_path: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct VerifyOptions{
+    /// pings, or connection attempts, per check
+    pub count: u32,
+    /// for each reply or connection
+    pub timeout: Duration,
+    /// percentage of pings or connections allowed to fail
+    pub max_loss: f64,
+    /// turn ping checks into MTU checks sized to the MTU of the interface
+    /// the source sends them out of
+    pub mtu: bool,
+    /// turn ping checks into path MTU probes
+    pub pmtu: bool,
+}
+
+impl Default for VerifyOptions{
+    fn default() -> Self {
+        VerifyOptions{
+            count: 3,
+            timeout: Duration::from_secs(1),
+            max_loss: 0.0,
+            mtu: false,
+            pmtu: false,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct CheckResult{
+    pub from: String,
+    pub to: String,
+    pub address: Option<IpAddr>,
+    pub port: Option<u16>,
+    /// packet size of an MTU check
+    pub mtu: Option<u32>,
+    pub blocked: bool,
+    pub sent: u32,
+    pub received: u32,
+    /// average round trip or connect time in milliseconds
+    pub rtt: Option<f64>,
+    /// slowest round trip or connect in milliseconds
+    pub rtt_max: Option<f64>,
+    /// latency budget of the check in milliseconds
+    pub max_rtt: Option<f64>,
+    /// largest packet found to arrive by a path MTU probe
+    pub pmtu: Option<u32>,
+    /// the path drops packets larger than `pmtu` without ICMP telling the
+    /// source
+    pub blackhole: bool,
+    /// hops traced by a path check: namespaces, the address of a hop
+    /// outside the topology or `*` for one that didn't answer
+    pub path: Vec<String>,
+    /// hops traced back from the target
+    pub return_path: Vec<String>,
+    /// declared path and return path of a path check
+    pub expected: Vec<String>,
+    pub expected_return: Vec<String>,
+    /// the way back isn't the way there reversed
+    pub asymmetric: bool,
+    pub passed: bool,
+    /// why the check couldn't run
+    pub error: Option<String>,
+}
+
+impl fmt::Display for CheckResult{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} -> {}", if self.passed { "PASS" } else { "FAIL" }, self.from, self.to)?;
+        if let Some(address) = self.address{
+            if address.to_string() != self.to {
+                write!(f, " ({})", address)?;
+            }
+        }
+        if let Some(port) = self.port{
+            write!(f, " tcp/{}", port)?;
+        }
+        if let Some(mtu) = self.mtu{
+            write!(f, " mtu {}", mtu)?;
+        }
+        if self.blocked {
+            write!(f, " expected blocked")?;
+        }
+        if let Some(error) = &self.error{
+            return write!(f, ": {}", error);
+        }
+        if let Some(pmtu) = self.pmtu{
+            write!(f, ": path mtu {}", pmtu)?;
+            if let Some(mtu) = self.mtu.filter(|m| *m != pmtu){
+                write!(f, " of {}", mtu)?;
+            }
+            if self.blackhole {
+                write!(f, ", larger packets are dropped silently")?;
+            }
+            return Ok(());
+        }
+        if !self.expected.is_empty() {
+            write!(f, ": path {}", self.path.join(" "))?;
+            if self.path != self.expected {
+                write!(f, ", expected {}", self.expected.join(" "))?;
+            }
+            if self.asymmetric || self.return_path != self.expected_return {
+                write!(f, ", returns {}", self.return_path.join(" "))?;
+            }
+            if self.return_path != self.expected_return {
+                write!(f, ", expected back {}", self.expected_return.join(" "))?;
+            }
+            return Ok(());
+        }
+        write!(f, ": {}/{} answered", self.received, self.sent)?;
+        if let Some(rtt) = self.rtt{
+            write!(f, ", avg {:.2} ms", rtt)?;
+        }
+        if let Some(budget) = self.max_rtt{
+            match self.rtt_max{
+                Some(max) if max > budget => write!(f, ", max {:.2} ms exceeds budget of {} ms", max, budget)?,
+                Some(max) => write!(f, ", max {:.2} ms within budget of {} ms", max, budget)?,
+                None => write!(f, ", budget {} ms", budget)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs the checks of `topology` one after another against its saved state.
+pub fn run(topology: &Topology, options: &VerifyOptions) -> Result<Vec<CheckResult>>{
+    let state = State::load(&topology.name)?
+        .ok_or_else(|| RouterError::TopologyNotFound(topology.name.to_string()))?;
+    Ok(topology.checks.iter().map(|c| check(&state, c, options)).collect())
+}
+
+/// Runs one check against `state`, an error is recorded in the result.
+pub(crate) fn check(state: &State, spec: &CheckSpec, options: &VerifyOptions) -> CheckResult {
+    let mut result = CheckResult{
+        from: spec.from.clone(),
+        to: spec.to.clone(),
+        port: spec.port,
+        blocked: spec.blocked,
+        max_rtt: spec.max_rtt,
+        ..Default::default()
+    };
+    let netns = Namespace::netns_name(&state.name, &spec.from);
+    let probed = resolve(state, &spec.to).and_then(|address| {
+        result.address = Some(address);
+        if spec.max_rtt.is_some() && (spec.mtu.is_some() || spec.blocked) {
+            return Err(RouterError::Invalid("max_rtt needs a ping or tcp check which is not blocked".to_string()));
+        }
+        let mtu = match (spec.mtu, spec.port){
+            (Some(_), Some(_)) => return Err(RouterError::Invalid("mtu and port exclude each other".to_string())),
+            // MTU probes measure one way only
+            (None, None) if options.mtu && spec.max_rtt.is_none() => Some(egress_mtu(&netns, address)?),
+            (mtu, _) => mtu,
+        };
+        result.mtu = mtu;
+        if !spec.path.is_empty() {
+            if spec.port.is_some() || mtu.is_some() || spec.blocked || spec.max_rtt.is_some() {
+                return Err(RouterError::Invalid("path excludes port, mtu, blocked and max_rtt".to_string()));
+            }
+            result.expected = spec.path.clone();
+            result.expected_return = if spec.return_path.is_empty() {
+                spec.path.iter().rev().cloned().collect()
+            } else {
+                spec.return_path.clone()
+            };
+            (result.path, result.return_path) = trace_paths(state, &spec.from, address, options)?;
+            result.asymmetric = result.return_path.iter().rev().ne(result.path.iter());
+            return Ok((options.count, None));
+        }
+        if options.pmtu && spec.port.is_none() && spec.mtu.is_none() && !spec.blocked && spec.max_rtt.is_none() {
+            let egress = egress_mtu(&netns, address)?;
+            let (pmtu, signalled) = path_mtu(state, &netns, address, egress, options)?;
+            (result.mtu, result.pmtu, result.blackhole) = (Some(egress), Some(pmtu), pmtu < egress && !signalled);
+            return Ok((options.count, None));
+        }
+        match (spec.port, mtu){
+            (Some(port), _) => connect(&netns, SocketAddr::new(address, port), options),
+            (None, Some(mtu)) => send_full_size(state, &netns, address, mtu, options),
+            (None, None) => ping(&netns, address, options),
+        }
+    });
+    match probed{
+        Ok((received, rtt)) => {
+            result.sent = options.count;
+            result.received = received;
+            result.rtt = rtt.map(|r| r.avg);
+            result.rtt_max = rtt.map(|r| r.max);
+            let loss = 100.0 * (options.count - received) as f64 / options.count.max(1) as f64;
+            let in_budget = match (spec.max_rtt, rtt){
+                (Some(budget), Some(rtt)) => rtt.max <= budget,
+                _ => true,
+            };
+            result.passed = match result.pmtu{
+                Some(pmtu) => result.mtu == Some(pmtu),
+                None if !result.expected.is_empty() => result.path == result.expected && result.return_path == result.expected_return,
+                None if spec.blocked => received == 0,
+                None => received > 0 && loss <= options.max_loss && in_budget,
+            };
+        },
+        Err(e) => result.error = Some(e.to_string()),
+    }
+    result
+}
+
+/// Address `to` stands for, see `CheckSpec`.
+pub(crate) fn resolve(state: &State, to: &str) -> Result<IpAddr>{
+    if let Ok(address) = to.parse() {
+        return Ok(address);
+    }
+    let netns = Namespace::netns_name(&state.name, to);
+    if !state.namespaces.iter().any(|n| n.netns == netns) {
+        return Err(failed!("{} is neither an address nor a namespace of {}", to, state.name));
+    }
+    let mut interfaces: Vec<_> = state.interfaces.iter().filter(|i| i.netns.as_deref() == Some(netns.as_str())).collect();
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    interfaces.iter().filter_map(|i| i.ip.as_ref())
+        .chain(interfaces.iter().filter_map(|i| i.ip6.as_ref()))
+        .find_map(|ip| ip.split('/').next()?.parse().ok())
+        .ok_or_else(|| failed!("Namespace {} has no address", to))
+}
+
+/// Round trips of the answered probes in milliseconds.
+#[derive(Clone, Copy, Debug)]
+struct Rtt{
+    avg: f64,
+    max: f64,
+}
+
+impl Rtt{
+    fn of(times: &[f64]) -> Option<Rtt> {
+        if times.is_empty() {
+            return None;
+        }
+        Some(Rtt{
+            avg: times.iter().sum::<f64>() / times.len() as f64,
+            max: times.iter().cloned().fold(0.0, f64::max),
+        })
+    }
+}
+
+/// Replies received and round trips of `ping` in `netns`.
+fn ping(netns: &str, address: IpAddr, options: &VerifyOptions) -> Result<(u32, Option<Rtt>)>{
+    let output = netns::command(netns, "ping")
+        .arg("-n")
+        .arg("-q")
+        .arg("-c")
+        .arg(options.count.to_string())
+        .arg("-i")
+        .arg("0.2")
+        .arg("-W")
+        .arg(options.timeout.as_secs_f64().max(0.001).to_string())
+        .arg(address.to_string())
+        .traced_output()
+        .map_err(|e| failed!("Failed to run ping: {}", e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // "3 packets transmitted, 3 received, 0% packet loss, time 405ms"
+    let received = stdout.lines()
+        .find(|l| l.contains("packets transmitted"))
+        .and_then(|l| l.split(", ").nth(1)?.split_whitespace().next()?.parse().ok())
+        .ok_or_else(|| failed!("Failed to run ping in {}: {}{}", netns, stdout.trim(), String::from_utf8_lossy(&output.stderr).trim()))?;
+    // "rtt min/avg/max/mdev = 0.041/0.052/0.068/0.011 ms"
+    let rtt = stdout.lines()
+        .find(|l| l.starts_with("rtt") || l.starts_with("round-trip"))
+        .and_then(|l| {
+            let stats: Vec<&str> = l.split(" = ").nth(1)?.split('/').collect();
+            Some(Rtt{ avg: stats.get(1)?.parse().ok()?, max: stats.get(2)?.parse().ok()? })
+        });
+    Ok((received, rtt))
+}
+
+/// MTU of the interface `netns` routes `address` out of.
+fn egress_mtu(netns: &str, address: IpAddr) -> Result<u32>{
+    let route: serde_json::Value = serde_json::from_str(&cmd::ip(Some(netns), &["-j", "route", "get", &address.to_string()])?)?;
+    let dev = route[0]["dev"].as_str()
+        .ok_or_else(|| failed!("No route to {} in {}", address, netns))?;
+    let link: serde_json::Value = serde_json::from_str(&cmd::ip(Some(netns), &["-j", "link", "show", "dev", dev])?)?;
+    link[0]["mtu"].as_u64().map(|m| m as u32)
+        .ok_or_else(|| failed!("Failed to read mtu of {} in {}", dev, netns))
+}
+
+/// Largest IP packet arriving from `netns` at `address`, searched between
+/// the minimum MTU of the family and `egress`, and whether the source was
+/// told about a smaller path MTU by ICMP.
+fn path_mtu(state: &State, netns: &str, address: IpAddr, egress: u32, options: &VerifyOptions) -> Result<(u32, bool)>{
+    let single = VerifyOptions{ count: 1, ..options.clone() };
+    let arrives = |size: u32| -> Result<bool>{
+        // a single lost probe isn't a smaller MTU yet
+        for _ in 0..options.count.max(1){
+            if send_full_size(state, netns, address, size, &single)?.0 > 0 {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    };
+    let mut low = if address.is_ipv6() { 1280 } else { 576 }.min(egress);
+    if !arrives(low)? {
+        return Err(failed!("no packet of {} bytes arrives at {}", low, address));
+    }
+    let mut high = egress;
+    if !arrives(high)? {
+        while high - low > 1 {
+            let size = low + (high - low) / 2;
+            if arrives(size)? {
+                low = size;
+            } else {
+                high = size;
+            }
+        }
+        high = low;
+    }
+    // a "packet too big" leaves the learned MTU in the route cache:
+    // "cache expires 598sec mtu 1400"
+    let route = cmd::ip(Some(netns), &["route", "get", &address.to_string()])?;
+    let tokens: Vec<&str> = route.split_whitespace().collect();
+    let signalled = tokens.windows(2).any(|w| w[0] == "mtu" && w[1].parse::<u32>().is_ok_and(|m| m < egress));
+    Ok((high, signalled))
+}
+
+/// Link or bridge whose ends disagree on the MTU.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct MtuMismatch{
+    pub segment: String,
+    /// (namespace, interface, mtu) of every end
+    pub ends: Vec<(String, String, u32)>,
+}
+
+impl fmt::Display for MtuMismatch{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ends: Vec<String> = self.ends.iter().map(|(ns, name, mtu)| format!("{} of {} has {}", name, ns, mtu)).collect();
+        write!(f, "mtu mismatch on {}: {}", self.segment, ends.join(", "))
+    }
+}
+
+/// Compares the kernel MTUs of both ends of every link and of the member
+/// ends and ports of every bridge of running `topology`.
+pub fn mtu_mismatches(topology: &Topology) -> Result<Vec<MtuMismatch>>{
+    let mut mtus: HashMap<String, HashMap<String, u32>> = HashMap::new();
+    let mut mtu = |ns: &str, name: String| -> Result<Option<(String, String, u32)>>{
+        let netns = Namespace::netns_name(&topology.name, ns);
+        if !mtus.contains_key(&netns) {
+            // namespaces on other hosts of the topology aren't here
+            let links: serde_json::Value = match cmd::ip(Some(&netns), &["-j", "link", "show"]){
+                Ok(out) => serde_json::from_str(&out)?,
+                Err(_) => serde_json::Value::Null,
+            };
+            let found = links.as_array().cloned().unwrap_or_default().iter()
+                .filter_map(|l| Some((l["ifname"].as_str()?.to_string(), l["mtu"].as_u64()? as u32)))
+                .collect();
+            mtus.insert(netns.clone(), found);
+        }
+        Ok(mtus[&netns].get(&name).map(|m| (ns.to_string(), name, *m)))
+    };
+    let mut mismatches = Vec::new();
+    for l in &topology.links{
+        let mut ends = Vec::new();
+        for ns in &l.endpoints{
+            ends.extend(mtu(ns, interface::name(ns, &l.name))?);
+        }
+        if ends.windows(2).any(|w| w[0].2 != w[1].2) {
+            mismatches.push(MtuMismatch{ segment: format!("link {}", l.name), ends });
+        }
+    }
+    for b in &topology.bridges{
+        let bridge_ns = b.namespace.clone().unwrap_or_else(|| b.name.clone());
+        let mut ends = Vec::new();
+        for m in &b.members{
+            ends.extend(mtu(m, interface::name(m, &b.name))?);
+            ends.extend(mtu(&bridge_ns, interface::name(&b.name, m))?);
+        }
+        if ends.windows(2).any(|w| w[0].2 != w[1].2) {
+            mismatches.push(MtuMismatch{ segment: format!("bridge {}", b.name), ends });
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Datagrams filling IP packets of `size` bytes sent with DF set from
+/// `netns` to `address`, counted by a receiver in the namespace owning it.
+fn send_full_size(state: &State, netns: &str, address: IpAddr, size: u32, options: &VerifyOptions) -> Result<(u32, Option<Rtt>)>{
+    let headers = if address.is_ipv6() { 40 + 8 } else { 20 + 8 };
+    let payload = (size as usize).checked_sub(headers)
+        .ok_or_else(|| failed!("mtu {} leaves no room for a UDP payload", size))?;
+    let owner = owner(state, address)
+        .ok_or_else(|| failed!("{} belongs to no namespace of {}", address, state.name))?;
+    let receiver = netns::run_in(&owner, || Ok(UdpSocket::bind(SocketAddr::new(address, 0))?))?;
+    receiver.set_read_timeout(Some(options.timeout))?;
+    let target = receiver.local_addr()?;
+    let sender = netns::run_in(netns, || {
+        let socket = UdpSocket::bind(SocketAddr::new(if address.is_ipv6() { IpAddr::V6(Ipv6Addr::UNSPECIFIED) } else { IpAddr::V4(Ipv4Addr::UNSPECIFIED) }, 0))?;
+        dont_fragment(&socket, address.is_ipv6())?;
+        Ok(socket)
+    })?;
+    let packet = vec![0u8; payload];
+    let mut buf = vec![0u8; payload + 1];
+    let mut received = 0;
+    let mut times = Vec::new();
+    for _ in 0..options.count{
+        let start = Instant::now();
+        // a hop with a smaller MTU answers with "packet too big", after
+        // which the kernel refuses the size right away
+        if sender.send_to(&packet, target).is_err() {
+            continue;
+        }
+        if let Ok(len) = receiver.recv(&mut buf) {
+            if len == payload {
+                received += 1;
+                times.push(start.elapsed().as_secs_f64() * 1000.0);
+            }
+        }
+    }
+    Ok((received, Rtt::of(&times)))
+}
+
+/// Sets DF and keeps the kernel from fragmenting locally, ignoring any
+/// path MTU it learned before, so every probe tests the full path.
+fn dont_fragment(socket: &UdpSocket, v6: bool) -> Result<()>{
+    let (level, option, value) = if v6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_PROBE)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_PROBE)
+    };
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(failed!("Failed to set DF: {}", std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Namespace of `state` owning `address`.
+fn owner(state: &State, address: IpAddr) -> Option<String> {
+    let address = address.to_string();
+    state.interfaces.iter()
+        .filter(|i| [&i.ip, &i.ip6].into_iter().flatten().any(|ip| ip.split('/').next() == Some(address.as_str())))
+        .find_map(|i| i.netns.clone())
+}
+
+/// Hops from `from` to `address` and back, by the names of their
+/// namespaces, each starting with the namespace tracing.
+fn trace_paths(state: &State, from: &str, address: IpAddr, options: &VerifyOptions) -> Result<(Vec<String>, Vec<String>)>{
+    let netns = Namespace::netns_name(&state.name, from);
+    let target = owner(state, address)
+        .ok_or_else(|| failed!("{} belongs to no namespace of {}, the way back can't be traced", address, state.name))?;
+    // answers go to the address the probes come from
+    let route: serde_json::Value = serde_json::from_str(&cmd::ip(Some(&netns), &["-j", "route", "get", &address.to_string()])?)?;
+    let source: IpAddr = route[0]["prefsrc"].as_str()
+        .and_then(|a| a.parse().ok())
+        .ok_or_else(|| failed!("No source address for {} in {}", address, netns))?;
+    let name = |netns: &str| {
+        state.namespaces.iter().find(|n| n.netns == netns).map(|n| n.name.clone()).unwrap_or_else(|| netns.to_string())
+    };
+    let hops = |netns: &str, address: IpAddr| -> Result<Vec<String>>{
+        let mut path = vec![name(netns)];
+        for hop in traceroute(netns, address, options)?{
+            path.push(match hop{
+                Some(hop) => owner(state, hop).map(|n| name(&n)).unwrap_or_else(|| hop.to_string()),
+                None => "*".to_string(),
+            });
+        }
+        Ok(path)
+    };
+    Ok((hops(&netns, address)?, hops(&target, source)?))
+}
+
+/// Most hops a trace goes before giving up.
+const MAX_HOPS: u8 = 30;
+
+/// First UDP port probes are sent to, the one traceroute uses.
+const TRACE_PORT: u16 = 33434;
+
+/// Address answering the probes of every TTL from `netns` to `address`,
+/// None where no answer came in time, up to the target itself. Probes are
+/// UDP datagrams to unused ports, the kernel queues the ICMP errors they
+/// cause on the socket, "time exceeded" from the routers on the way and
+/// "port unreachable" from the target.
+fn traceroute(netns: &str, address: IpAddr, options: &VerifyOptions) -> Result<Vec<Option<IpAddr>>>{
+    let v6 = address.is_ipv6();
+    netns::run_in(netns, || {
+        let socket = UdpSocket::bind(SocketAddr::new(if v6 { IpAddr::V6(Ipv6Addr::UNSPECIFIED) } else { IpAddr::V4(Ipv4Addr::UNSPECIFIED) }, 0))?;
+        let (level, recverr, hops) = if v6 {
+            (libc::IPPROTO_IPV6, libc::IPV6_RECVERR, libc::IPV6_UNICAST_HOPS)
+        } else {
+            (libc::IPPROTO_IP, libc::IP_RECVERR, libc::IP_TTL)
+        };
+        set_option(&socket, level, recverr, 1)?;
+        let mut path = Vec::new();
+        let mut port = TRACE_PORT;
+        for ttl in 1..=MAX_HOPS{
+            set_option(&socket, level, hops, ttl as libc::c_int)?;
+            let mut answer = None;
+            for _ in 0..options.count.max(1){
+                port = port.wrapping_add(1).max(TRACE_PORT);
+                // an error of an earlier probe may be pending on the socket
+                let _ = socket.send_to(&[0u8; 32], SocketAddr::new(address, port));
+                answer = trace_answer(&socket, port, options.timeout)?;
+                if answer.is_some() {
+                    break;
+                }
+            }
+            path.push(answer.map(|(hop, _)| hop));
+            if answer.is_some_and(|(_, reached)| reached) {
+                return Ok(path);
+            }
+        }
+        Ok(path)
+    })
+}
+
+/// Address of the hop answering the probe sent to `port` and whether it is
+/// the target, which answers "destination unreachable" rather than "time
+/// exceeded". Answers to earlier probes are skipped.
+fn trace_answer(socket: &UdpSocket, port: u16, timeout: Duration) -> Result<Option<(IpAddr, bool)>>{
+    let deadline = Instant::now() + timeout;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(None);
+        }
+        // queued errors are signalled as POLLERR
+        let mut fd = libc::pollfd{ fd: socket.as_raw_fd(), events: 0, revents: 0 };
+        let ready = unsafe { libc::poll(&mut fd, 1, left.as_millis().max(1) as libc::c_int) };
+        if ready < 0 {
+            return Err(failed!("Failed to poll for ICMP errors: {}", std::io::Error::last_os_error()));
+        }
+        if ready == 0 {
+            return Ok(None);
+        }
+        let mut name: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut data = [0u8; 64];
+        let mut control = [0u8; 512];
+        let mut iov = libc::iovec{ iov_base: data.as_mut_ptr() as *mut libc::c_void, iov_len: data.len() };
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+        let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+        if len < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::WouldBlock {
+                continue;
+            }
+            return Err(failed!("Failed to read ICMP errors: {}", e));
+        }
+        // msg_name holds where the probe causing the error went
+        let Some(probe) = sockaddr(&name as *const libc::sockaddr_storage as *const libc::sockaddr) else { continue };
+        if probe.port() != port {
+            continue;
+        }
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let header = unsafe { &*cmsg };
+            let error = (header.cmsg_level == libc::IPPROTO_IP && header.cmsg_type == libc::IP_RECVERR)
+                || (header.cmsg_level == libc::IPPROTO_IPV6 && header.cmsg_type == libc::IPV6_RECVERR);
+            if error {
+                let ee = unsafe { libc::CMSG_DATA(cmsg) } as *const libc::sock_extended_err;
+                let (origin, kind) = unsafe { ((*ee).ee_origin, (*ee).ee_type) };
+                let reached = match origin{
+                    libc::SO_EE_ORIGIN_ICMP => kind == 3,
+                    libc::SO_EE_ORIGIN_ICMP6 => kind == 1,
+                    _ => {
+                        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+                        continue;
+                    },
+                };
+                if let Some(hop) = sockaddr(unsafe { libc::SO_EE_OFFENDER(ee) }) {
+                    return Ok(Some((hop.ip(), reached)));
+                }
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+    }
+}
+
+/// IPv4 or IPv6 socket address `addr` points to.
+fn sockaddr(addr: *const libc::sockaddr) -> Option<SocketAddr> {
+    unsafe {
+        match (*addr).sa_family as libc::c_int{
+            libc::AF_INET => {
+                let a = &*(addr as *const libc::sockaddr_in);
+                Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr))), u16::from_be(a.sin_port)))
+            },
+            libc::AF_INET6 => {
+                let a = &*(addr as *const libc::sockaddr_in6);
+                Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(a.sin6_addr.s6_addr)), u16::from_be(a.sin6_port)))
+            },
+            _ => None,
+        }
+    }
+}
+
+fn set_option(socket: &UdpSocket, level: libc::c_int, option: libc::c_int, value: libc::c_int) -> Result<()>{
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(failed!("Failed to set socket option {}: {}", option, std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Connections accepted and times to connect.
+fn connect(netns: &str, target: SocketAddr, options: &VerifyOptions) -> Result<(u32, Option<Rtt>)>{
+    let timeout = options.timeout;
+    let times = netns::run_in(netns, || {
+        let mut times = Vec::new();
+        for _ in 0..options.count{
+            let start = Instant::now();
+            if TcpStream::connect_timeout(&target, timeout).is_ok() {
+                times.push(start.elapsed().as_secs_f64() * 1000.0);
+            }
+        }
+        Ok(times)
+    })?;
+    Ok((times.len() as u32, Rtt::of(&times)))
+}
@@ -0,0 +1,71 @@
+use std::sync::Arc;
+
+use crate::cmd;
+use crate::error::{Result, RouterError};
+use crate::interface;
+use crate::{Config, Namespace};
+use crate::namespace;
+
+/// VRF device inside a namespace: the interfaces bound to it are routed
+/// with its own table, so one namespace can act as a PE router with several
+/// customer VRFs whose routes overlap. Routes go into the VRF by using its
+/// table, see `RouteSpec::vrf`.
+pub struct Vrf{
+    pub name: String,
+    pub table: u32,
+    pub namespace: Arc<Namespace>,
+    /// names of the interfaces bound to the VRF
+    pub interfaces: Vec<String>,
+}
+
+impl Vrf{
+    /// Creates the VRF device `name` with routing table `table` inside
+    /// `namespace` and binds `interfaces` of the namespace to it. Their
+    /// connected routes move into the VRF's table. Interfaces are given by
+    /// kernel or logical name.
+    pub fn new(name: String, table: u32, namespace: Arc<Namespace>, interfaces: Vec<String>, config: &Config) -> Result<Arc<Vrf>>{
+        interface::check_name(&name).map_err(|e| e.context(format!("VRF {}", name)))?;
+        let interfaces: Vec<String> = interfaces.into_iter()
+            .map(|i| config.interface(&i).map(|i| i.name.clone()).unwrap_or(i))
+            .collect();
+        let others: Vec<Arc<Vrf>> = config.vrfs.iter().filter(|v| v.namespace.netns == namespace.netns).collect();
+        if others.iter().any(|v| v.name == name) {
+            return Err(RouterError::Exists{ kind: "VRF", name });
+        }
+        if let Some(other) = others.iter().find(|v| v.table == table) {
+            return Err(RouterError::Invalid(format!("Table {} of VRF {} is taken by VRF {} in {}", table, name, other.name, namespace.name)));
+        }
+        for i in &interfaces{
+            let inside = config.interface(i).and_then(|i| i.namespace.clone()).is_some_and(|n| n.netns == namespace.netns);
+            if !inside {
+                return Err(RouterError::Invalid(format!("Interface {} of VRF {} is not in {}", i, name, namespace.name)));
+            }
+            if let Some(other) = others.iter().find(|v| v.interfaces.contains(i)) {
+                return Err(RouterError::Invalid(format!("Interface {} is bound to VRF {} already", i, other.name)));
+            }
+        }
+        let v = Vrf{
+            name: name.clone(),
+            table,
+            namespace,
+            interfaces,
+        };
+        // binding takes an interface down and up again, which would drop
+        // its IPv6 addresses
+        namespace::sysctl(&v.namespace.netns, &["net.ipv6.conf.all.keep_addr_on_down=1"])
+            .map_err(|e| e.context(format!("Failed to keep addresses of VRF interfaces in {}", v.namespace.netns)))?;
+        if !(config.reconcile && cmd::ip(Some(&v.namespace.netns), &["link", "show", "dev", v.name.as_str()]).is_ok()) {
+            let table = table.to_string();
+            cmd::ip(Some(&v.namespace.netns), &["link", "add", "name", v.name.as_str(), "type", "vrf", "table", table.as_str()])
+                .map_err(|e| e.context(format!("Failed to create VRF {} in {}", name, v.namespace.name)))?;
+        }
+        cmd::ip(Some(&v.namespace.netns), &["link", "set", "dev", v.name.as_str(), "up"])?;
+        for i in &v.interfaces{
+            cmd::ip(Some(&v.namespace.netns), &["link", "set", "dev", i.as_str(), "master", v.name.as_str()])
+                .map_err(|e| e.context(format!("Failed to bind {} to VRF {}", i, name)))?;
+        }
+        let v = Arc::new(v);
+        config.vrfs.push(v.clone());
+        Ok(v)
+    }
+}
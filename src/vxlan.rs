@@ -0,0 +1,185 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::cmd;
+use crate::error::{Result, RouterError};
+use crate::interface;
+use crate::link::{endpoint_addrs, host_addr};
+use crate::transaction::Resource;
+use crate::{Config, Interface, Namespace};
+
+/// MAC address of the flood entries, the destinations of broadcast and
+/// unknown traffic.
+const FLOOD: &str = "00:00:00:00:00:00";
+
+/// Overlay segment: a VXLAN device with VNI `vni` in each endpoint
+/// namespace, named `<namespace>_<link>`, tunnelling Ethernet over UDP
+/// between the VTEP addresses of the underlay. Subnets are assigned like
+/// those of a `Link`; with two ends they are addressed like a link's,
+/// otherwise like the members of a `Bridge`. VTEPs find each other through
+/// the multicast `group`, or without one by static flood entries to every
+/// other VTEP, those outside the topology, e.g. on another host, included.
+pub struct VxlanLink{
+    pub name: String,
+    pub vni: u32,
+    pub subnet: String,
+    pub subnet6: Option<String>,
+    pub group: Option<IpAddr>,
+    /// UDP destination port
+    pub port: u16,
+}
+
+/// End of a `VxlanLink` in a namespace.
+pub struct Vtep{
+    pub namespace: Arc<Namespace>,
+    /// underlay address the tunnel is sourced from
+    pub local: IpAddr,
+    /// underlay interface carrying the tunnel, needed for `group`
+    pub dev: Option<String>,
+    /// host number of the overlay addresses instead of the position
+    pub host: Option<u32>,
+}
+
+impl VxlanLink{
+    /// IANA port of VXLAN, Linux defaults to the older 8472.
+    pub const PORT: u16 = 4789;
+
+    pub fn new(name: String, vni: u32, subnet: String, subnet6: Option<String>, group: Option<IpAddr>, port: u16, config: &Config) -> Result<Arc<VxlanLink>>{
+        if config.vxlans.contains_key(&name) || config.links.contains_key(&name) || config.bridges.contains_key(&name) || config.tunnels.contains_key(&name) || config.wireguards.contains_key(&name) {
+            return Err(RouterError::Exists{ kind: "VXLAN link", name });
+        }
+        if vni >= 1 << 24 {
+            return Err(RouterError::Invalid(format!("VNI {} of VXLAN link {} is out of range", vni, name)));
+        }
+        if group.is_some_and(|g| !g.is_multicast()) {
+            return Err(RouterError::Invalid(format!("Group of VXLAN link {} is not a multicast address", name)));
+        }
+        let (subnet, subnet6) = config.ipam().assign(subnet, subnet6)
+            .map_err(|e| e.context(format!("VXLAN link {}", name)))?;
+        let v = Arc::new(VxlanLink{
+            name: name.clone(),
+            vni,
+            subnet,
+            subnet6,
+            group,
+            port,
+        });
+        config.vxlans.insert(name, v.clone());
+        Ok(v)
+    }
+
+    /// Creates the VXLAN devices of `vteps` and addresses them. Without
+    /// `group` each floods to the other VTEPs and to `remotes`.
+    pub fn attach(&self, vteps: &[Vtep], remotes: &[IpAddr], config: &Config) -> Result<Vec<Arc<Interface>>>{
+        let underlay: Vec<IpAddr> = vteps.iter().map(|v| v.local).chain(remotes.iter().copied()).chain(self.group).collect();
+        if underlay.iter().any(|a| a.is_ipv6() != underlay[0].is_ipv6()) {
+            return Err(RouterError::Invalid(format!("VXLAN link {} mixes IPv4 and IPv6 VTEPs", self.name)));
+        }
+        let mut subnets = Vec::new();
+        for subnet in std::iter::once(&self.subnet).chain(self.subnet6.iter()){
+            let sn: ipnet::IpNet = subnet.parse()
+                .map_err(|e: ipnet::AddrParseError| RouterError::InvalidSubnet{ subnet: subnet.clone(), reason: e.to_string() })?;
+            subnets.push(sn);
+        }
+        let pair = vteps.len() == 2 && remotes.is_empty();
+        let mut interfaces = Vec::new();
+        for (n, vtep) in vteps.iter().enumerate(){
+            let name = interface::name(&vtep.namespace.name, &self.name);
+            let others: Vec<IpAddr> = match self.group{
+                Some(_) => Vec::new(),
+                None => underlay.iter().filter(|a| **a != vtep.local).copied().collect(),
+            };
+            self.setup(&name, vtep, &others, config)?;
+            interface::alias(&name, Some(&vtep.namespace.netns), &format!("{}_{}", vtep.namespace.name, self.name), config)?;
+
+            let (mut ip, mut ip6) = (None, None);
+            for sn in &subnets{
+                let addr = overlay_addr(sn, n, pair, vtep.host)?;
+                if sn.addr().is_ipv6() {
+                    ip6 = Some(addr);
+                } else {
+                    ip = Some(addr);
+                }
+            }
+            interfaces.push(Interface::new(name, Some(vtep.namespace.clone()), ip, ip6, None, config)?);
+        }
+        Ok(interfaces)
+    }
+
+    /// Creates the device `name` of `vtep` flooding to `others`. When
+    /// reconciling, a device with the same settings is kept and only its
+    /// flood entries fixed up.
+    fn setup(&self, name: &str, vtep: &Vtep, others: &[IpAddr], config: &Config) -> Result<()>{
+        let netns = vtep.namespace.netns.as_str();
+        let mut existing = None;
+        if config.reconcile {
+            if let Ok(out) = cmd::ip(Some(netns), &["-d", "-j", "link", "show", "dev", name]) {
+                let links: serde_json::Value = serde_json::from_str(&out)?;
+                let data = &links[0]["linkinfo"]["info_data"];
+                let local = data["local"].as_str().or(data["local6"].as_str());
+                let group = data["group"].as_str().or(data["group6"].as_str());
+                let same = links[0]["linkinfo"]["info_kind"] == "vxlan"
+                    && data["id"].as_u64() == Some(self.vni as u64)
+                    && data["port"].as_u64() == Some(self.port as u64)
+                    && local == Some(vtep.local.to_string().as_str())
+                    && group == self.group.map(|g| g.to_string()).as_deref();
+                if same {
+                    existing = Some(flood_entries(netns, name)?);
+                } else {
+                    cmd::ip(Some(netns), &["link", "del", "dev", name])?;
+                }
+            }
+        }
+        let installed = match existing{
+            Some(installed) => installed,
+            None => {
+                let (vni, local, port) = (self.vni.to_string(), vtep.local.to_string(), self.port.to_string());
+                let mut args = vec!["link", "add", "name", name, "type", "vxlan", "id", vni.as_str(), "local", local.as_str(), "dstport", port.as_str()];
+                let group = self.group.map(|g| g.to_string());
+                if let Some(group) = &group{
+                    let Some(dev) = &vtep.dev else {
+                        return Err(RouterError::Invalid(format!("VXLAN link {} uses a multicast group, its VTEP in {} needs an interface", self.name, vtep.namespace.name)));
+                    };
+                    args.extend(["group", group.as_str(), "dev", dev.as_str()]);
+                } else if let Some(dev) = &vtep.dev{
+                    args.extend(["dev", dev.as_str()]);
+                }
+                cmd::ip(Some(netns), &args)
+                    .map_err(|e| e.context(format!("Failed to create VXLAN link {} in {}", self.name, vtep.namespace.name)))?;
+                config.transaction().record(Resource::Device{ name: name.to_string(), netns: netns.to_string() });
+                Vec::new()
+            },
+        };
+        for stale in installed.iter().filter(|i| !others.contains(i)){
+            cmd::bridge(Some(netns), &["fdb", "del", FLOOD, "dev", name, "dst", stale.to_string().as_str()])?;
+        }
+        for other in others.iter().filter(|o| !installed.contains(o)){
+            cmd::bridge(Some(netns), &["fdb", "append", FLOOD, "dev", name, "dst", other.to_string().as_str()])
+                .map_err(|e| e.context(format!("Failed to add VTEP {} to VXLAN link {} in {}", other, self.name, vtep.namespace.name)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Address of the `n`-th end of a VXLAN link in `subnet`: host `host` if
+/// given, else that of a link's end for one of a `pair` and host `n + 1`
+/// otherwise.
+pub(crate) fn overlay_addr(subnet: &ipnet::IpNet, n: usize, pair: bool, host: Option<u32>) -> Result<String>{
+    match host{
+        Some(host) => host_addr(subnet, host as u128),
+        None if pair => {
+            let (a, b) = endpoint_addrs(subnet)?;
+            Ok(if n == 0 { a } else { b })
+        },
+        None => host_addr(subnet, n as u128 + 1),
+    }
+}
+
+/// Destinations of the flood entries of the VXLAN device `name`.
+fn flood_entries(netns: &str, name: &str) -> Result<Vec<IpAddr>>{
+    let entries: serde_json::Value = serde_json::from_str(&cmd::bridge(Some(netns), &["-j", "fdb", "show", "dev", name])?)?;
+    Ok(entries.as_array().cloned().unwrap_or_default().iter()
+        .filter(|e| e["mac"] == FLOOD)
+        .filter_map(|e| e["dst"].as_str()?.parse().ok())
+        .collect())
+}
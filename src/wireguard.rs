@@ -0,0 +1,149 @@
+//! Encrypted point-to-point links over the underlay. Each end is a
+//! WireGuard device named `<namespace>_<link>` with a keypair of its own,
+//! generated with `wg genkey` when the link is attached, and the other end
+//! as its only peer. Ends are addressed like those of a `Link` from subnets
+//! assigned the same way, so WireGuard links are gateways of routes and
+//! interfaces of the routing daemon like veths.
+//!
+//! WireGuard only takes packets from and hands packets to a peer for its
+//! allowed IPs. Those of an end are the link's subnets plus the prefixes
+//! it declares as behind it, routes over the link to anything else are
+//! dropped. Private keys go to `wg` on stdin and are not kept, a link
+//! rebuilt gets new keys.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use crate::cmd;
+use crate::error::{Result, RouterError};
+use crate::interface;
+use crate::transaction::Resource;
+use crate::vxlan::overlay_addr;
+use crate::{Config, Interface, Namespace};
+
+/// Curve25519 keypair of an end, base64 encoded as `wg` prints them.
+#[derive(Clone)]
+pub struct Keypair{
+    pub private: String,
+    pub public: String,
+}
+
+impl Keypair{
+    /// Generates a keypair with `wg genkey` and `wg pubkey`.
+    pub fn generate() -> Result<Keypair>{
+        let private = cmd::wg(None, &["genkey"], "")?;
+        let public = cmd::wg(None, &["pubkey"], &private)?;
+        Ok(Keypair{ private, public })
+    }
+}
+
+impl std::fmt::Debug for Keypair{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keypair").field("public", &self.public).finish_non_exhaustive()
+    }
+}
+
+/// WireGuard link between two namespaces.
+pub struct WireguardLink{
+    pub name: String,
+    pub subnet: String,
+    pub subnet6: Option<String>,
+    /// seconds between keepalives, so ends behind NAT stay reachable
+    pub keepalive: Option<u16>,
+}
+
+/// End of a `WireguardLink` in a namespace.
+pub struct WireguardEnd{
+    pub namespace: Arc<Namespace>,
+    /// underlay address the other end sends to
+    pub local: IpAddr,
+    /// UDP port the end listens on
+    pub port: u16,
+    /// prefixes behind the end, allowed IPs of its peer besides the subnets
+    pub allowed_ips: Vec<ipnet::IpNet>,
+    /// host number of the link addresses instead of those of a link's end
+    pub host: Option<u32>,
+}
+
+impl WireguardLink{
+    /// Port WireGuard ends listen on if not set.
+    pub const PORT: u16 = 51820;
+
+    pub fn new(name: String, subnet: String, subnet6: Option<String>, keepalive: Option<u16>, config: &Config) -> Result<Arc<WireguardLink>>{
+        if config.wireguards.contains_key(&name) || config.links.contains_key(&name) || config.bridges.contains_key(&name)
+            || config.vxlans.contains_key(&name) || config.tunnels.contains_key(&name) {
+            return Err(RouterError::Exists{ kind: "WireGuard link", name });
+        }
+        let (subnet, subnet6) = config.ipam().assign(subnet, subnet6)
+            .map_err(|e| e.context(format!("WireGuard link {}", name)))?;
+        let w = Arc::new(WireguardLink{
+            name: name.clone(),
+            subnet,
+            subnet6,
+            keepalive,
+        });
+        config.wireguards.insert(name, w.clone());
+        Ok(w)
+    }
+
+    /// Subnets of the link, IPv4 first.
+    fn subnets(&self) -> Result<Vec<ipnet::IpNet>>{
+        std::iter::once(&self.subnet).chain(self.subnet6.iter())
+            .map(|s| s.parse().map_err(|e: ipnet::AddrParseError| RouterError::InvalidSubnet{ subnet: s.clone(), reason: e.to_string() }))
+            .collect()
+    }
+
+    /// Creates the devices of both `ends` with new keypairs, makes each the
+    /// peer of the other and addresses them.
+    pub fn attach(&self, ends: &[WireguardEnd], config: &Config) -> Result<Vec<Arc<Interface>>>{
+        if ends.len() != 2 {
+            return Err(RouterError::Invalid(format!("WireGuard link {} needs two ends, got {}", self.name, ends.len())));
+        }
+        let subnets = self.subnets()?;
+        let keys = [Keypair::generate()?, Keypair::generate()?];
+        let mut interfaces = Vec::new();
+        for (n, end) in ends.iter().enumerate(){
+            let netns = end.namespace.netns.as_str();
+            let name = interface::name(&end.namespace.name, &self.name);
+            if config.reconcile && cmd::ip(Some(netns), &["link", "show", "dev", &name]).is_ok() {
+                // keys were not kept, the ends are keyed anew
+                cmd::ip(Some(netns), &["link", "del", "dev", &name])?;
+            }
+            cmd::ip(Some(netns), &["link", "add", "name", &name, "type", "wireguard"])
+                .map_err(|e| e.context(format!("Failed to create WireGuard link {} in {}", self.name, end.namespace.name)))?;
+            config.transaction().record(Resource::Device{ name: name.clone(), netns: netns.to_string() });
+            let peer = &ends[1 - n];
+            let allowed: Vec<String> = subnets.iter().chain(&peer.allowed_ips).map(|s| s.to_string()).collect();
+            let port = end.port.to_string();
+            let endpoint = SocketAddr::new(peer.local, peer.port).to_string();
+            let keepalive = self.keepalive.map(|k| k.to_string());
+            let mut args = vec![
+                "set", name.as_str(),
+                "listen-port", port.as_str(),
+                "private-key", "/dev/stdin",
+                "peer", keys[1 - n].public.as_str(),
+                "endpoint", endpoint.as_str(),
+            ];
+            let allowed = allowed.join(",");
+            args.extend(["allowed-ips", allowed.as_str()]);
+            if let Some(keepalive) = &keepalive{
+                args.extend(["persistent-keepalive", keepalive.as_str()]);
+            }
+            cmd::wg(Some(netns), &args, &keys[n].private)
+                .map_err(|e| e.context(format!("Failed to configure WireGuard link {} in {}", self.name, end.namespace.name)))?;
+            interface::alias(&name, Some(netns), &format!("{}_{}", end.namespace.name, self.name), config)?;
+
+            let (mut ip, mut ip6) = (None, None);
+            for sn in &subnets{
+                let addr = overlay_addr(sn, n, true, end.host)?;
+                if sn.addr().is_ipv6() {
+                    ip6 = Some(addr);
+                } else {
+                    ip = Some(addr);
+                }
+            }
+            interfaces.push(Interface::new(name, Some(end.namespace.clone()), ip, ip6, None, config)?);
+        }
+        Ok(interfaces)
+    }
+}
@@ -0,0 +1,492 @@
+//! AF_XDP port of the dataplane, built with the `af-xdp` feature: a small
+//! XDP program in generic mode on the port redirects every frame it
+//! receives to an AF_XDP socket bound to the port's queue 0, over a map of
+//! such sockets. The socket's frames live in a memory area (UMEM) shared
+//! with the kernel, and four rings pass their offsets back and forth:
+//!
+//! - fill: frames handed to the kernel to receive into
+//! - rx: frames received
+//! - tx: frames to send
+//! - completion: frames sent, free again
+//!
+//! Received frames are taken and refilled a batch at a time, frames to
+//! send are queued on the tx ring and sent together by the next `flush`.
+//! All of it is done with raw syscalls, no libbpf or libxdp needed.
+//!
+//! Veths run the XDP program in generic mode and the socket in copy mode,
+//! which sees the bytes of a frame but not what the sender left to
+//! offloads: frames larger than `FRAME_SIZE` are dropped and checksums
+//! left to the hardware arrive unfilled. Traffic through the port needs
+//! segmentation and checksum offloads off on the senders, e.g. with the
+//! topology's `offloads`, see `offload`.
+
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::dataplane::{self, PortIo, BATCH};
+use crate::error::{failed, Result, RouterError};
+
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x80000000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x100000000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x180000000;
+const XDP_COPY: u16 = 1 << 1;
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+const XDP_PASS: i32 = 2;
+
+const IFLA_XDP: u16 = 43;
+const IFLA_XDP_FD: u16 = 1;
+const IFLA_XDP_FLAGS: u16 = 3;
+const NLA_F_NESTED: u16 = 0x8000;
+const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+
+/// Size of a frame in the UMEM, a page, the most copy mode allows. Larger
+/// frames are dropped, links of the port need an MTU below it.
+pub const FRAME_SIZE: usize = 4096;
+/// Frames of the UMEM, half of them to receive into, half to send from.
+const FRAMES: usize = 4096;
+/// Entries of each ring.
+const RING: u32 = 2048;
+/// Kicks of the kernel per `flush`, generic mode sends 32 frames a kick.
+const KICKS: usize = 128;
+
+#[repr(C)]
+#[derive(Default)]
+struct UmemReg{
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct RingOffset{
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MmapOffsets{
+    rx: RingOffset,
+    tx: RingOffset,
+    fill: RingOffset,
+    completion: RingOffset,
+}
+
+#[repr(C)]
+struct SockaddrXdp{
+    family: u16,
+    flags: u16,
+    ifindex: u32,
+    queue_id: u32,
+    shared_umem_fd: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Desc{
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+/// One of the rings, mapped from the socket. Entries are `Desc` for rx
+/// and tx, UMEM offsets (`u64`) for fill and completion.
+struct Ring{
+    map: *mut libc::c_void,
+    len: usize,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    desc: *mut u8,
+}
+
+impl Ring{
+    fn map(fd: libc::c_int, offset: &RingOffset, pgoff: libc::off_t, entry: usize) -> std::io::Result<Ring>{
+        let len = offset.desc as usize + RING as usize * entry;
+        let map = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED | libc::MAP_POPULATE, fd, pgoff) };
+        if map == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        let at = |off: u64| unsafe { (map as *mut u8).add(off as usize) };
+        Ok(Ring{
+            map,
+            len,
+            producer: at(offset.producer) as *const AtomicU32,
+            consumer: at(offset.consumer) as *const AtomicU32,
+            desc: at(offset.desc),
+        })
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*self.consumer }
+    }
+
+    fn slot<T>(&self, index: u32) -> *mut T {
+        unsafe { (self.desc as *mut T).add((index & (RING - 1)) as usize) }
+    }
+}
+
+impl Drop for Ring{
+    fn drop(&mut self){
+        unsafe { libc::munmap(self.map, self.len) };
+    }
+}
+
+/// What the receiving thread owns.
+struct Rx{
+    rx: Ring,
+    fill: Ring,
+}
+
+/// What every thread sending out of the port shares.
+struct Tx{
+    tx: Ring,
+    completion: Ring,
+    /// UMEM offsets of the frames free to send from
+    free: Vec<u64>,
+    queued: usize,
+}
+
+/// Memory of the frames, unmapped last.
+struct Umem(*mut u8);
+
+impl Umem{
+    fn at(&self, addr: u64) -> *mut u8 {
+        unsafe { self.0.add(addr as usize) }
+    }
+}
+
+impl Drop for Umem{
+    fn drop(&mut self){
+        unsafe { libc::munmap(self.0 as *mut libc::c_void, FRAMES * FRAME_SIZE) };
+    }
+}
+
+/// A port whose frames an XDP program redirects to an AF_XDP socket.
+pub struct XskPort{
+    port: String,
+    ifindex: u32,
+    rx: Mutex<Rx>,
+    tx: Mutex<Tx>,
+    socket: OwnedFd,
+    // kept open while the program uses them
+    _map: OwnedFd,
+    _program: OwnedFd,
+    umem: Umem,
+}
+
+// the UMEM and rings are only touched under the locks or by the kernel
+unsafe impl Send for XskPort{}
+unsafe impl Sync for XskPort{}
+
+impl XskPort{
+    pub fn open(port: &str) -> Result<XskPort>{
+        let name = std::ffi::CString::new(port)
+            .map_err(|_| RouterError::InvalidName{ name: port.to_string(), reason: "contains a NUL byte".into() })?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(RouterError::NotFound{ kind: "Interface", name: port.to_string() });
+        }
+        let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(failed!("Failed to open AF_XDP socket: {}", std::io::Error::last_os_error()));
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        let size = FRAMES * FRAME_SIZE;
+        let umem = unsafe { libc::mmap(std::ptr::null_mut(), size, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0) };
+        if umem == libc::MAP_FAILED {
+            return Err(failed!("Failed to map UMEM: {}", std::io::Error::last_os_error()));
+        }
+        let umem = Umem(umem as *mut u8);
+        let fd = socket.as_raw_fd();
+        let reg = UmemReg{ addr: umem.0 as u64, len: size as u64, chunk_size: FRAME_SIZE as u32, ..Default::default() };
+        setsockopt(fd, XDP_UMEM_REG, &reg).map_err(|e| failed!("Failed to register UMEM: {}", e))?;
+        for ring in [XDP_UMEM_FILL_RING, XDP_UMEM_COMPLETION_RING, XDP_RX_RING, XDP_TX_RING]{
+            setsockopt(fd, ring, &RING).map_err(|e| failed!("Failed to size rings: {}", e))?;
+        }
+        let mut offsets = MmapOffsets::default();
+        let mut len = std::mem::size_of::<MmapOffsets>() as libc::socklen_t;
+        if unsafe { libc::getsockopt(fd, SOL_XDP, XDP_MMAP_OFFSETS, &mut offsets as *mut MmapOffsets as *mut libc::c_void, &mut len) } < 0 {
+            return Err(failed!("Failed to read ring offsets: {}", std::io::Error::last_os_error()));
+        }
+        let map_ring = |offset: &RingOffset, pgoff, entry| Ring::map(fd, offset, pgoff, entry)
+            .map_err(|e| failed!("Failed to map ring: {}", e));
+        let rx = map_ring(&offsets.rx, XDP_PGOFF_RX_RING, std::mem::size_of::<Desc>())?;
+        let tx = map_ring(&offsets.tx, XDP_PGOFF_TX_RING, std::mem::size_of::<Desc>())?;
+        let fill = map_ring(&offsets.fill, XDP_UMEM_PGOFF_FILL_RING, 8)?;
+        let completion = map_ring(&offsets.completion, XDP_UMEM_PGOFF_COMPLETION_RING, 8)?;
+        // the first half of the frames to receive into
+        let rx_frames = FRAMES / 2;
+        for n in 0..rx_frames as u32{
+            unsafe { *fill.slot::<u64>(n) = n as u64 * FRAME_SIZE as u64 };
+        }
+        fill.producer().store(rx_frames as u32, Ordering::Release);
+        let addr = SockaddrXdp{ family: AF_XDP as u16, flags: XDP_COPY, ifindex, queue_id: 0, shared_umem_fd: 0 };
+        if unsafe { libc::bind(fd, &addr as *const SockaddrXdp as *const libc::sockaddr, std::mem::size_of::<SockaddrXdp>() as libc::socklen_t) } < 0 {
+            return Err(failed!("Failed to bind AF_XDP socket to {}: {}", port, std::io::Error::last_os_error()));
+        }
+        let map = xskmap()?;
+        update(&map, 0, fd)?;
+        let program = program(&map)?;
+        attach(ifindex, program.as_raw_fd())?;
+        Ok(XskPort{
+            port: port.to_string(),
+            ifindex,
+            rx: Mutex::new(Rx{ rx, fill }),
+            tx: Mutex::new(Tx{
+                tx,
+                completion,
+                free: (rx_frames..FRAMES).map(|n| (n * FRAME_SIZE) as u64).collect(),
+                queued: 0,
+            }),
+            socket,
+            _map: map,
+            _program: program,
+            umem,
+        })
+    }
+
+    /// Frees the frames the kernel has sent.
+    fn complete(tx: &mut Tx){
+        let ring = &tx.completion;
+        let producer = ring.producer().load(Ordering::Acquire);
+        let consumer = ring.consumer().load(Ordering::Relaxed);
+        for n in consumer..producer{
+            tx.free.push(unsafe { *ring.slot::<u64>(n) });
+        }
+        ring.consumer().store(producer, Ordering::Release);
+    }
+
+    fn kick(&self) -> std::io::Result<()>{
+        if unsafe { libc::sendto(self.socket.as_raw_fd(), std::ptr::null(), 0, libc::MSG_DONTWAIT, std::ptr::null(), 0) } < 0 {
+            let e = std::io::Error::last_os_error();
+            return match e.raw_os_error(){
+                Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::ENOBUFS) => Ok(()),
+                _ => Err(e),
+            };
+        }
+        Ok(())
+    }
+}
+
+impl PortIo for XskPort{
+    fn receive(&self, timeout: Duration, handle: &mut dyn FnMut(&[u8])) -> std::io::Result<usize>{
+        let rx = self.rx.lock().unwrap();
+        let mut producer = rx.rx.producer().load(Ordering::Acquire);
+        let consumer = rx.rx.consumer().load(Ordering::Relaxed);
+        if producer == consumer {
+            if !dataplane::wait(self.socket.as_raw_fd(), timeout)? {
+                return Ok(0);
+            }
+            producer = rx.rx.producer().load(Ordering::Acquire);
+        }
+        let n = producer.wrapping_sub(consumer).min(BATCH as u32);
+        let fill = rx.fill.producer().load(Ordering::Relaxed);
+        for i in 0..n{
+            let desc = unsafe { *rx.rx.slot::<Desc>(consumer.wrapping_add(i)) };
+            let frame = unsafe { std::slice::from_raw_parts(self.umem.at(desc.addr), desc.len as usize) };
+            handle(frame);
+            // back to the kernel to receive into
+            unsafe { *rx.fill.slot::<u64>(fill.wrapping_add(i)) = desc.addr & !(FRAME_SIZE as u64 - 1) };
+        }
+        rx.rx.consumer().store(consumer.wrapping_add(n), Ordering::Release);
+        rx.fill.producer().store(fill.wrapping_add(n), Ordering::Release);
+        Ok(n as usize)
+    }
+
+    fn send(&self, frame: &[u8]) -> std::io::Result<()>{
+        if frame.len() > FRAME_SIZE {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "frame larger than a UMEM frame"));
+        }
+        let mut tx = self.tx.lock().unwrap();
+        if tx.free.is_empty() {
+            XskPort::complete(&mut tx);
+        }
+        if tx.free.is_empty() {
+            self.kick()?;
+            XskPort::complete(&mut tx);
+        }
+        let Some(addr) = tx.free.pop() else {
+            return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, "no free frame to send from"));
+        };
+        unsafe { std::ptr::copy_nonoverlapping(frame.as_ptr(), self.umem.at(addr), frame.len()) };
+        let producer = tx.tx.producer().load(Ordering::Relaxed);
+        unsafe { *tx.tx.slot::<Desc>(producer) = Desc{ addr, len: frame.len() as u32, options: 0 } };
+        tx.tx.producer().store(producer.wrapping_add(1), Ordering::Release);
+        tx.queued += 1;
+        Ok(())
+    }
+
+    fn flush(&self) -> std::io::Result<()>{
+        let mut tx = self.tx.lock().unwrap();
+        if tx.queued == 0 {
+            return Ok(());
+        }
+        tx.queued = 0;
+        let producer = tx.tx.producer().load(Ordering::Relaxed);
+        for _ in 0..KICKS{
+            self.kick()?;
+            if tx.tx.consumer().load(Ordering::Acquire) == producer {
+                break;
+            }
+        }
+        XskPort::complete(&mut tx);
+        Ok(())
+    }
+
+    fn close(&self) -> Result<()>{
+        attach(self.ifindex, -1).map_err(|e| failed!("Failed to detach XDP from {}: {}", self.port, e))
+    }
+}
+
+fn setsockopt<T>(fd: libc::c_int, option: libc::c_int, value: &T) -> std::io::Result<()>{
+    if unsafe { libc::setsockopt(fd, SOL_XDP, option, value as *const T as *const libc::c_void, std::mem::size_of::<T>() as libc::socklen_t) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// bpf(2) with `attr`, the start of a zeroed `union bpf_attr`.
+fn bpf(cmd: libc::c_long, attr: &[u8]) -> std::io::Result<libc::c_long>{
+    let mut buf = [0u8; 128];
+    buf[..attr.len()].copy_from_slice(attr);
+    let r = unsafe { libc::syscall(libc::SYS_bpf, cmd, buf.as_mut_ptr(), buf.len()) };
+    if r < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(r)
+}
+
+/// Map of queue index to AF_XDP socket.
+fn xskmap() -> Result<OwnedFd>{
+    let attr: Vec<u8> = [BPF_MAP_TYPE_XSKMAP, 4, 4, 64].iter().flat_map(|v| v.to_ne_bytes()).collect();
+    let fd = bpf(BPF_MAP_CREATE, &attr).map_err(|e| failed!("Failed to create XSKMAP: {}", e))?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) })
+}
+
+fn update(map: &OwnedFd, key: u32, socket: libc::c_int) -> Result<()>{
+    let value = socket as u32;
+    let mut attr = Vec::new();
+    attr.extend_from_slice(&(map.as_raw_fd() as u32).to_ne_bytes());
+    attr.extend_from_slice(&[0; 4]);
+    attr.extend_from_slice(&(&key as *const u32 as u64).to_ne_bytes());
+    attr.extend_from_slice(&(&value as *const u32 as u64).to_ne_bytes());
+    attr.extend_from_slice(&0u64.to_ne_bytes());
+    bpf(BPF_MAP_UPDATE_ELEM, &attr).map_err(|e| failed!("Failed to add socket to XSKMAP: {}", e))?;
+    Ok(())
+}
+
+/// One eBPF instruction.
+fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> [u8; 8] {
+    let mut i = [0u8; 8];
+    i[0] = code;
+    i[1] = (src << 4) | dst;
+    i[2..4].copy_from_slice(&off.to_ne_bytes());
+    i[4..8].copy_from_slice(&imm.to_ne_bytes());
+    i
+}
+
+/// XDP program redirecting frames to the socket of their queue in `map`,
+/// passing them to the kernel if there is none:
+///
+/// ```text
+/// r2 = ctx->rx_queue_index
+/// r1 = map
+/// r3 = XDP_PASS
+/// return bpf_redirect_map(r1, r2, r3)
+/// ```
+fn program(map: &OwnedFd) -> Result<OwnedFd>{
+    let insns: Vec<u8> = [
+        insn(0x61, 2, 1, 16, 0),
+        insn(0x18, 1, BPF_PSEUDO_MAP_FD, 0, map.as_raw_fd()),
+        insn(0, 0, 0, 0, 0),
+        insn(0xb7, 3, 0, 0, XDP_PASS),
+        insn(0x85, 0, 0, 0, BPF_FUNC_REDIRECT_MAP),
+        insn(0x95, 0, 0, 0, 0),
+    ].concat();
+    let license = c"GPL";
+    let mut log = vec![0u8; 4096];
+    let mut attr = Vec::new();
+    attr.extend_from_slice(&BPF_PROG_TYPE_XDP.to_ne_bytes());
+    attr.extend_from_slice(&((insns.len() / 8) as u32).to_ne_bytes());
+    attr.extend_from_slice(&(insns.as_ptr() as u64).to_ne_bytes());
+    attr.extend_from_slice(&(license.as_ptr() as u64).to_ne_bytes());
+    attr.extend_from_slice(&1u32.to_ne_bytes());
+    attr.extend_from_slice(&(log.len() as u32).to_ne_bytes());
+    attr.extend_from_slice(&(log.as_mut_ptr() as u64).to_ne_bytes());
+    let fd = bpf(BPF_PROG_LOAD, &attr).map_err(|e| {
+        let log = String::from_utf8_lossy(&log);
+        failed!("Failed to load XDP program: {} {}", e, log.trim_end_matches('\0').trim())
+    })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) })
+}
+
+/// Attaches the XDP program `program` to `ifindex` in generic mode over
+/// rtnetlink, detaches it if -1.
+fn attach(ifindex: u32, program: libc::c_int) -> Result<()>{
+    let attr = |kind: u16, payload: &[u8]| -> Vec<u8> {
+        let mut a = Vec::new();
+        a.extend_from_slice(&((4 + payload.len()) as u16).to_ne_bytes());
+        a.extend_from_slice(&kind.to_ne_bytes());
+        a.extend_from_slice(payload);
+        a
+    };
+    let xdp = [attr(IFLA_XDP_FD, &program.to_ne_bytes()), attr(IFLA_XDP_FLAGS, &XDP_FLAGS_SKB_MODE.to_ne_bytes())].concat();
+    let mut body = vec![libc::AF_UNSPEC as u8, 0, 0, 0];
+    body.extend_from_slice(&(ifindex as i32).to_ne_bytes());
+    body.extend_from_slice(&[0; 8]);
+    body.extend(attr(IFLA_XDP | NLA_F_NESTED, &xdp));
+    let mut msg = Vec::new();
+    msg.extend_from_slice(&((16 + body.len()) as u32).to_ne_bytes());
+    msg.extend_from_slice(&libc::RTM_SETLINK.to_ne_bytes());
+    msg.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16).to_ne_bytes());
+    msg.extend_from_slice(&1u32.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend(body);
+    let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE) };
+    if fd < 0 {
+        return Err(failed!("Failed to open netlink socket: {}", std::io::Error::last_os_error()));
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    if unsafe { libc::send(socket.as_raw_fd(), msg.as_ptr() as *const libc::c_void, msg.len(), 0) } < 0 {
+        return Err(failed!("Failed to send netlink request: {}", std::io::Error::last_os_error()));
+    }
+    let mut reply = [0u8; 4096];
+    let n = unsafe { libc::recv(socket.as_raw_fd(), reply.as_mut_ptr() as *mut libc::c_void, reply.len(), 0) };
+    if n < 20 {
+        return Err(failed!("Failed to read netlink reply: {}", std::io::Error::last_os_error()));
+    }
+    // NLMSG_ERROR with 0 acknowledges
+    let error = i32::from_ne_bytes(reply[16..20].try_into().unwrap());
+    if error != 0 {
+        return Err(failed!("{}", std::io::Error::from_raw_os_error(-error)));
+    }
+    Ok(())
+}